@@ -0,0 +1,328 @@
+//! ipset management shared by the firewall adapters
+//!
+//! iptables rules match large address and port lists through named ipsets
+//! (`-m set --match-set <name> src`), which stay O(1) per packet however
+//! many members they hold. A set is refilled by building a temporary copy
+//! with `ipset restore` and swapping it with the live one, so the rules that
+//! reference it by name never need rewriting and never see a half-filled
+//! set.
+//!
+//! Every set the agent owns starts with [`SET_PREFIX`]. The members last
+//! synced are remembered per set, so re-syncing unchanged sets is free.
+//!
+//! Members are parsed before they are written to the restore script, and
+//! ones that are not valid for the set type are dropped, so data such as a
+//! threat feed can never inject restore commands.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::Ipv4Addr;
+use std::process::Stdio;
+use std::sync::Mutex;
+
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+/// Prefix of every set name the agent owns
+pub const SET_PREFIX: &str = "ngfw_";
+
+/// Suffix of the temporary copy a set is rebuilt in
+const TEMP_SUFFIX: &str = "_t";
+
+/// Smallest `maxelem`; sets grow beyond it with their member count
+const MIN_MAXELEM: usize = 65536;
+
+/// Members of each set as last synced, by set name
+static SYNCED: Mutex<BTreeMap<String, Vec<String>>> = Mutex::new(BTreeMap::new());
+
+/// Kind of ipset, which decides what members it holds and how it matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetType {
+    /// IPv4 addresses, CIDR networks and `a-b` ranges (`hash:net`)
+    Net,
+    /// MAC addresses, matched on the source only (`hash:mac`)
+    Mac,
+    /// Ports and `a-b` port ranges (`bitmap:port`)
+    Port,
+}
+
+impl SetType {
+    fn create_args(&self, members: usize) -> String {
+        match self {
+            SetType::Net => format!("hash:net family inet maxelem {}", members.max(MIN_MAXELEM)),
+            SetType::Mac => format!("hash:mac maxelem {}", members.max(MIN_MAXELEM)),
+            SetType::Port => "bitmap:port range 0-65535".to_string(),
+        }
+    }
+
+    /// Whether `member` is something this kind of set holds
+    fn accepts(&self, member: &str) -> bool {
+        match self {
+            SetType::Net => match (member.split_once('-'), member.split_once('/')) {
+                (Some((start, end)), _) => matches!(
+                    (start.parse::<Ipv4Addr>(), end.parse::<Ipv4Addr>()),
+                    (Ok(start), Ok(end)) if start <= end
+                ),
+                (None, Some((addr, prefix))) => {
+                    addr.parse::<Ipv4Addr>().is_ok() && prefix.parse::<u8>().is_ok_and(|p| p <= 32)
+                }
+                (None, None) => member.parse::<Ipv4Addr>().is_ok(),
+            },
+            SetType::Mac => {
+                let octets: Vec<&str> = member.split(':').collect();
+                octets.len() == 6
+                    && octets
+                        .iter()
+                        .all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit()))
+            }
+            SetType::Port => match member.split_once('-') {
+                Some((start, end)) => matches!(
+                    (start.parse::<u16>(), end.parse::<u16>()),
+                    (Ok(start), Ok(end)) if start <= end
+                ),
+                None => member.parse::<u16>().is_ok(),
+            },
+        }
+    }
+}
+
+/// A named set and the members it should hold
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpSet {
+    pub name: String,
+    pub kind: SetType,
+    pub members: Vec<String>,
+}
+
+impl IpSet {
+    pub fn new(name: String, kind: SetType, mut members: Vec<String>) -> Self {
+        members.sort();
+        members.dedup();
+        Self {
+            name,
+            kind,
+            members,
+        }
+    }
+}
+
+/// iptables arguments matching packets whose `direction` (`src` or `dst`)
+/// is in the set
+pub fn match_args(name: &str, direction: &str) -> Vec<String> {
+    ["-m", "set", "--match-set", name, direction]
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// Build `ipset restore` input that rebuilds each set in a temporary copy
+/// and swaps it in, or renames the copy when the set does not exist yet.
+fn restore_script(sets: &[&IpSet], existing: &BTreeSet<String>) -> String {
+    let mut script = String::new();
+    for set in sets {
+        let temp = format!("{}{}", set.name, TEMP_SUFFIX);
+        if existing.contains(&temp) {
+            script.push_str(&format!("destroy {}\n", temp));
+        }
+        script.push_str(&format!(
+            "create {} {}\n",
+            temp,
+            set.kind.create_args(set.members.len())
+        ));
+        for member in &set.members {
+            if !set.kind.accepts(member) {
+                warn!(set = %set.name, "dropping invalid ipset member {:?}", member);
+                continue;
+            }
+            script.push_str(&format!("add {} {} -exist\n", temp, member));
+        }
+        if existing.contains(&set.name) {
+            script.push_str(&format!("swap {} {}\n", temp, set.name));
+            script.push_str(&format!("destroy {}\n", temp));
+        } else {
+            script.push_str(&format!("rename {} {}\n", temp, set.name));
+        }
+    }
+    script
+}
+
+/// Names of every agent-owned set on the router
+pub async fn existing_sets() -> Result<BTreeSet<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let output = Command::new("ipset").args(["list", "-n"]).output().await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("ipset list failed: {}", stderr.trim()).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|name| name.starts_with(SET_PREFIX))
+        .map(str::to_string)
+        .collect())
+}
+
+fn synced() -> std::sync::MutexGuard<'static, BTreeMap<String, Vec<String>>> {
+    SYNCED
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Bring `sets` on the router up to date. Sets whose members have not
+/// changed since the last sync, and that still exist, are left alone.
+/// Returns the names of the sets that were rebuilt.
+pub async fn sync(sets: &[IpSet]) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    if sets.is_empty() {
+        return Ok(Vec::new());
+    }
+    let existing = existing_sets().await?;
+    let stale: Vec<&IpSet> = {
+        let synced = synced();
+        sets.iter()
+            .filter(|set| {
+                !existing.contains(&set.name) || synced.get(&set.name) != Some(&set.members)
+            })
+            .collect()
+    };
+    if stale.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut child = Command::new("ipset")
+        .arg("restore")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(restore_script(&stale, &existing).as_bytes())
+            .await?;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("ipset restore failed: {}", stderr.trim()).into());
+    }
+
+    let mut synced = synced();
+    let mut rebuilt = Vec::new();
+    for set in stale {
+        debug!(set = %set.name, members = set.members.len(), "synced ipset");
+        synced.insert(set.name.clone(), set.members.clone());
+        rebuilt.push(set.name.clone());
+    }
+    Ok(rebuilt)
+}
+
+/// Destroy agent-owned sets starting with one of `prefixes` that are not
+/// in `keep`. Sets still referenced by a rule cannot be destroyed and are
+/// left for a later call.
+pub async fn destroy_unused(prefixes: &[&str], keep: &[IpSet]) {
+    let existing = match existing_sets().await {
+        Ok(existing) => existing,
+        Err(e) => {
+            debug!("cannot list ipsets: {}", e);
+            return;
+        }
+    };
+    for name in existing {
+        let owned = prefixes.iter().any(|p| name.starts_with(p));
+        if !owned || keep.iter().any(|set| set.name == name) {
+            continue;
+        }
+        match Command::new("ipset")
+            .args(["destroy", &name])
+            .output()
+            .await
+        {
+            Ok(output) if output.status.success() => {
+                synced().remove(&name);
+                debug!(set = %name, "destroyed unused ipset");
+            }
+            Ok(output) => warn!(
+                "ipset destroy {} failed: {}",
+                name,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(e) => warn!("ipset destroy {} failed: {}", name, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_sets_are_renamed_and_existing_ones_swapped() {
+        let nets = IpSet::new(
+            "ngfw_a1".to_string(),
+            SetType::Net,
+            vec!["10.0.0.0/8".to_string(), "192.0.2.1".to_string()],
+        );
+        let ports = IpSet::new("ngfw_p2".to_string(), SetType::Port, vec!["80".to_string()]);
+        let existing = BTreeSet::from(["ngfw_p2".to_string(), "ngfw_p2_t".to_string()]);
+
+        assert_eq!(
+            restore_script(&[&nets, &ports], &existing),
+            "create ngfw_a1_t hash:net family inet maxelem 65536\n\
+             add ngfw_a1_t 10.0.0.0/8 -exist\n\
+             add ngfw_a1_t 192.0.2.1 -exist\n\
+             rename ngfw_a1_t ngfw_a1\n\
+             destroy ngfw_p2_t\n\
+             create ngfw_p2_t bitmap:port range 0-65535\n\
+             add ngfw_p2_t 80 -exist\n\
+             swap ngfw_p2_t ngfw_p2\n\
+             destroy ngfw_p2_t\n"
+        );
+    }
+
+    #[test]
+    fn members_are_sorted_and_deduplicated() {
+        let set = IpSet::new(
+            "ngfw_m1".to_string(),
+            SetType::Mac,
+            vec![
+                "bb:00:00:00:00:02".to_string(),
+                "aa:00:00:00:00:01".to_string(),
+                "bb:00:00:00:00:02".to_string(),
+            ],
+        );
+        assert_eq!(set.members, vec!["aa:00:00:00:00:01", "bb:00:00:00:00:02"]);
+    }
+
+    #[test]
+    fn invalid_members_are_not_written() {
+        let nets = IpSet::new(
+            "ngfw_t1".to_string(),
+            SetType::Net,
+            vec![
+                "198.51.100.0/24".to_string(),
+                "192.0.2.1\ndestroy ngfw_a1".to_string(),
+                "203.0.113.0/33".to_string(),
+                "2001:db8::/32".to_string(),
+                "10.0.0.1-10.0.0.9".to_string(),
+            ],
+        );
+        let ports = IpSet::new(
+            "ngfw_p1".to_string(),
+            SetType::Port,
+            vec![
+                "443".to_string(),
+                "70000".to_string(),
+                "80 -exist".to_string(),
+            ],
+        );
+
+        assert_eq!(
+            restore_script(&[&nets, &ports], &BTreeSet::new()),
+            "create ngfw_t1_t hash:net family inet maxelem 65536\n\
+             add ngfw_t1_t 10.0.0.1-10.0.0.9 -exist\n\
+             add ngfw_t1_t 198.51.100.0/24 -exist\n\
+             rename ngfw_t1_t ngfw_t1\n\
+             create ngfw_p1_t bitmap:port range 0-65535\n\
+             add ngfw_p1_t 443 -exist\n\
+             rename ngfw_p1_t ngfw_p1\n"
+        );
+    }
+}
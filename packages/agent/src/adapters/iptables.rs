@@ -1,16 +1,1409 @@
-//! iptables (firewall) adapter stub
+//! iptables (firewall) adapter
+//!
+//! Firewall rules are installed into agent-owned chains (`NGFW_INPUT`,
+//! `NGFW_FORWARD`, `NGFW_OUTPUT`) that the built-in chains jump to, so the
+//! firmware's own rules are never rewritten. Every installed rule carries a
+//! `-m comment --comment ngfw:rule:<id>` tag with its `FirewallRule` id,
+//! which is how packet counters are attributed back to rules.
+//!
+//! The section config is an object of the form
+//! `{ "zones": [{ "id", "interfaces" }], "rules": [FirewallRule], "timezone" }`.
+//!
+//! Rules with a `RuleSchedule` are only installed while their window is
+//! open in router local time (`timezone` is a POSIX TZ string and defaults
+//! to the router's own zone). The last applied config is kept so the
+//! scheduler can re-render it as windows open and close.
+//!
+//! `address_groups` and `port_groups` are compiled to ipsets (see
+//! [`super::ipset`]) that rules reference through `source_group`,
+//! `destination_group` and `port_group`. Host name members are resolved on
+//! the router and re-resolved every `resolve_interval` seconds; membership
+//! changes swap set contents without touching the rules.
+//!
+//! `countries` carries the networks of every country that rules match
+//! through `source_countries` and `destination_countries`, compiled by the
+//! API from the device's GeoIP dataset; each becomes an `ngfw_c<code>` set.
+//! Codes must be `[A-Z]{2}` and networks IPv4 addresses or CIDRs.
+//! Every country renders its own kernel rule tagged
+//! `ngfw:rule:<id>:geo:<code>`, so hits are also counted per country.
+//!
+//! `threat_feeds` carries the aggregated networks of every subscribed IP
+//! threat feed, each loaded into an `ngfw_t<id>` set. Traffic from or to a
+//! feed through the `WAN` zone is dropped ahead of every rule by kernel
+//! rules tagged `ngfw:feed:<id>`, whose counters are reported per feed.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::Ipv4Addr;
+use std::process::Stdio;
+use std::sync::Mutex;
 
 use ngfw_protocol::rpc::ConfigSection;
-use serde_json::Value;
+use ngfw_protocol::{CountryCounters, FeedCounters, RuleCounters};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{debug, warn};
 
+use super::ipset::{self, IpSet, SetType};
 use super::{ConfigDiff, SubsystemAdapter, ValidationIssue};
+use crate::clock;
+use crate::rollback;
+use crate::schedule::{self, RuleSchedule};
+use crate::timezone::TimeZone;
+
+/// Built-in chains and the agent-owned chains they jump to.
+const CHAINS: &[(&str, &str)] = &[
+    ("INPUT", "NGFW_INPUT"),
+    ("FORWARD", "NGFW_FORWARD"),
+    ("OUTPUT", "NGFW_OUTPUT"),
+];
+
+/// Comment prefix identifying the `FirewallRule` a kernel rule belongs to.
+pub const RULE_COMMENT_PREFIX: &str = "ngfw:rule:";
+
+/// Comment prefix identifying the threat feed a drop rule belongs to.
+const FEED_COMMENT_PREFIX: &str = "ngfw:feed:";
+
+/// Zone whose interfaces threat feeds are enforced on.
+const WAN_ZONE: &str = "WAN";
+
+/// Comment on rules installed for rules without an id; never counted.
+const UNTRACKED_COMMENT: &str = "ngfw:rule";
+
+/// Most ports a single `multiport` match accepts (ranges count twice).
+const MULTIPORT_MAX: usize = 15;
+
+/// Set name prefixes of address (nets and MACs) and port groups, of
+/// countries and of threat feeds
+const RULE_SET_PREFIXES: &[&str] = &["ngfw_a", "ngfw_m", "ngfw_p", "ngfw_c", "ngfw_t"];
+
+/// Separates the rule id from the country in a rule comment
+const COUNTRY_TAG: &str = ":geo:";
+
+/// Last applied firewall config, re-rendered when schedules switch
+static APPLIED: tokio::sync::Mutex<Option<FirewallConfig>> = tokio::sync::Mutex::const_new(None);
+
+/// Addresses host name members last resolved to, with when, by host name
+static RESOLVED: Mutex<BTreeMap<String, (i64, Vec<String>)>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct FirewallConfig {
+    #[serde(default)]
+    zones: Vec<ZoneConfig>,
+    #[serde(default)]
+    rules: Vec<Value>,
+    /// POSIX TZ string schedules are evaluated in
+    #[serde(default)]
+    timezone: Option<String>,
+    #[serde(default)]
+    address_groups: Vec<AddressGroup>,
+    #[serde(default)]
+    port_groups: Vec<PortGroup>,
+    #[serde(default)]
+    countries: Vec<CountrySet>,
+    #[serde(default)]
+    threat_feeds: Vec<FeedSet>,
+}
+
+/// Networks of one country, as compiled by the API
+#[derive(Debug, Clone, Deserialize)]
+struct CountrySet {
+    code: String,
+    #[serde(default)]
+    networks: Vec<String>,
+}
+
+/// Networks of one threat feed, as compiled by the API
+#[derive(Debug, Clone, Deserialize)]
+struct FeedSet {
+    id: u32,
+    #[serde(default)]
+    networks: Vec<String>,
+}
+
+/// An `AddressGroup` as pushed by the API
+#[derive(Debug, Clone, Deserialize)]
+struct AddressGroup {
+    id: u32,
+    #[serde(default)]
+    members: Vec<String>,
+    #[serde(default = "resolve_interval_default")]
+    resolve_interval: u32,
+}
+
+fn resolve_interval_default() -> u32 {
+    300
+}
+
+/// A `PortGroup` as pushed by the API
+#[derive(Debug, Clone, Deserialize)]
+struct PortGroup {
+    id: u32,
+    #[serde(default)]
+    ports: Vec<String>,
+}
+
+/// One address group member
+#[derive(Debug, PartialEq)]
+enum GroupMember {
+    /// Address, CIDR or range, as `hash:net` takes it
+    Net(String),
+    /// MAC address, lowercase and colon-separated
+    Mac(String),
+    /// Host name resolved on the router
+    Host(String),
+}
+
+fn parse_group_member(member: &str) -> Option<GroupMember> {
+    let member = member.trim();
+    if let Some(item) = parse_address_item(member) {
+        return Some(GroupMember::Net(match item {
+            AddressItem::Net(net) | AddressItem::Range(net) => net,
+        }));
+    }
+    let octets: Vec<&str> = member.split([':', '-']).collect();
+    if octets.len() == 6
+        && octets
+            .iter()
+            .all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit()))
+    {
+        return Some(GroupMember::Mac(octets.join(":").to_ascii_lowercase()));
+    }
+    let is_host = !member.is_empty()
+        && member.len() <= 253
+        && member.chars().any(|c| c.is_ascii_alphabetic())
+        && member.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        });
+    is_host.then(|| GroupMember::Host(member.to_ascii_lowercase()))
+}
+
+pub(super) fn address_set(id: u32) -> String {
+    format!("{}a{}", ipset::SET_PREFIX, id)
+}
+
+fn mac_set(id: u32) -> String {
+    format!("{}m{}", ipset::SET_PREFIX, id)
+}
+
+pub(super) fn port_set(id: u32) -> String {
+    format!("{}p{}", ipset::SET_PREFIX, id)
+}
+
+/// Whether `code` is an uppercase ISO 3166-1 alpha-2 code (`[A-Z]{2}`)
+fn is_country_code(code: &str) -> bool {
+    code.len() == 2 && code.chars().all(|c| c.is_ascii_uppercase())
+}
+
+fn country_set(code: &str) -> String {
+    format!("{}c{}", ipset::SET_PREFIX, code.to_ascii_lowercase())
+}
+
+fn feed_set(id: u32) -> String {
+    format!("{}t{}", ipset::SET_PREFIX, id)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ZoneConfig {
+    id: String,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    interfaces: Vec<String>,
+}
+
+/// A `FirewallRule` as pushed by the API. Only the fields the kernel
+/// enforces are read; `chain` defaults to `FORWARD`.
+#[derive(Debug, Deserialize)]
+struct FirewallRule {
+    #[serde(default)]
+    id: Option<u32>,
+    #[serde(default = "enabled_default")]
+    enabled: bool,
+    #[serde(default)]
+    chain: Option<String>,
+    #[serde(default)]
+    zone_from: Option<String>,
+    #[serde(default)]
+    zone_to: Option<String>,
+    #[serde(default)]
+    source: Option<String>,
+    #[serde(default)]
+    destination: Option<String>,
+    #[serde(default)]
+    protocol: Option<String>,
+    #[serde(default)]
+    port: Option<String>,
+    action: String,
+    #[serde(default)]
+    log: bool,
+    #[serde(default)]
+    schedule: Option<RuleSchedule>,
+    #[serde(default)]
+    source_group: Option<u32>,
+    #[serde(default)]
+    destination_group: Option<u32>,
+    #[serde(default)]
+    port_group: Option<u32>,
+    #[serde(default)]
+    source_countries: Vec<String>,
+    #[serde(default)]
+    destination_countries: Vec<String>,
+}
+
+fn enabled_default() -> bool {
+    true
+}
+
+/// Whether a zone, address or port field matches anything
+pub(super) fn is_any(value: Option<&str>) -> bool {
+    value.is_none_or(|v| {
+        let v = v.trim();
+        v.is_empty() || v == "*" || v.eq_ignore_ascii_case("any")
+    })
+}
+
+/// Render a firewall config into `iptables-restore` rule lines.
+///
+/// Scheduled rules are left out unless their window is open at `minute` of
+/// the week; with no `minute` every rule is rendered. Collects every
+/// problem instead of stopping at the first, so the same function backs
+/// `validate`.
+fn render(
+    config: &FirewallConfig,
+    minute: Option<u32>,
+) -> Result<Vec<String>, Vec<ValidationIssue>> {
+    let mut lines = feed_rules(config);
+    let mut issues = group_issues(config);
+    issues.extend(country_issues(config));
+
+    for (index, raw) in config.rules.iter().enumerate() {
+        let field = format!("rules[{}]", index);
+        let rule: FirewallRule = match serde_json::from_value(raw.clone()) {
+            Ok(rule) => rule,
+            Err(e) => {
+                issues.push(issue(&field, &e.to_string()));
+                continue;
+            }
+        };
+        let scheduled_off = match (&rule.schedule, minute) {
+            (Some(schedule), Some(minute)) => !schedule.is_active(minute),
+            _ => false,
+        };
+        match render_rule(config, &rule, &field) {
+            Ok(rendered) if rule.enabled && !scheduled_off => lines.extend(rendered),
+            Ok(_) => {}
+            Err(mut errors) => issues.append(&mut errors),
+        }
+    }
+
+    if issues.is_empty() {
+        Ok(lines)
+    } else {
+        Err(issues)
+    }
+}
+
+/// Drop rules for every threat feed, for traffic arriving from or leaving
+/// through the WAN zone. Without a WAN zone every interface is covered.
+fn feed_rules(config: &FirewallConfig) -> Vec<String> {
+    let wan = |flag| {
+        zone_matches(config, Some(WAN_ZONE), flag, "threat_feeds", "zone")
+            .unwrap_or_else(|_| vec![Vec::new()])
+    };
+    let (inbound, outbound) = (wan("-i"), wan("-o"));
+    let mut lines = Vec::new();
+    for feed in &config.threat_feeds {
+        let set = feed_set(feed.id);
+        for (chain, interfaces, direction) in [
+            ("NGFW_INPUT", &inbound, "src"),
+            ("NGFW_FORWARD", &inbound, "src"),
+            ("NGFW_FORWARD", &outbound, "dst"),
+            ("NGFW_OUTPUT", &outbound, "dst"),
+        ] {
+            for interface in interfaces {
+                let mut matches = vec![format!("-A {}", chain)];
+                matches.extend(interface.iter().cloned());
+                matches.extend(ipset::match_args(&set, direction));
+                lines.push(format!(
+                    "{} -m comment --comment {}{} -j DROP",
+                    matches.join(" "),
+                    FEED_COMMENT_PREFIX,
+                    feed.id
+                ));
+            }
+        }
+    }
+    lines
+}
+
+/// Problems with group members
+fn group_issues(config: &FirewallConfig) -> Vec<ValidationIssue> {
+    let mut issues = Vec::new();
+    for (i, group) in config.address_groups.iter().enumerate() {
+        for (j, member) in group.members.iter().enumerate() {
+            if parse_group_member(member).is_none() {
+                issues.push(issue(
+                    &format!("address_groups[{}].members[{}]", i, j),
+                    &format!(
+                        "'{}' is not an IPv4 address, network, range, MAC or host name",
+                        member
+                    ),
+                ));
+            }
+        }
+    }
+    for (i, group) in config.port_groups.iter().enumerate() {
+        for (j, port) in group.ports.iter().enumerate() {
+            let valid = match port.trim().split_once('-') {
+                Some((start, end)) => matches!(
+                    (start.parse::<u16>(), end.parse::<u16>()),
+                    (Ok(start), Ok(end)) if start <= end
+                ),
+                None => port.trim().parse::<u16>().is_ok(),
+            };
+            if !valid {
+                issues.push(issue(
+                    &format!("port_groups[{}].ports[{}]", i, j),
+                    &format!("invalid port '{}'", port),
+                ));
+            }
+        }
+    }
+    issues
+}
+
+/// Problems with country set definitions
+fn country_issues(config: &FirewallConfig) -> Vec<ValidationIssue> {
+    let mut issues = Vec::new();
+    for (i, country) in config.countries.iter().enumerate() {
+        if !is_country_code(&country.code) {
+            issues.push(issue(
+                &format!("countries[{}].code", i),
+                &format!("'{}' is not an ISO 3166-1 alpha-2 code", country.code),
+            ));
+        }
+        for (j, network) in country.networks.iter().enumerate() {
+            if parse_cidr(network).is_none() {
+                issues.push(issue(
+                    &format!("countries[{}].networks[{}]", i, j),
+                    &format!("'{}' is not an IPv4 address or CIDR", network),
+                ));
+            }
+        }
+    }
+    issues
+}
+
+/// The ipsets of every group, country and feed in `config`, resolving host name
+/// members whose last resolution is older than their group's
+/// `resolve_interval`.
+///
+/// A host name that fails to resolve keeps its previous addresses.
+async fn rule_sets(config: &FirewallConfig) -> Vec<IpSet> {
+    let now = clock::now();
+    let mut sets = Vec::new();
+    for group in &config.address_groups {
+        let mut nets = Vec::new();
+        let mut macs = Vec::new();
+        for member in group.members.iter().filter_map(|m| parse_group_member(m)) {
+            match member {
+                GroupMember::Net(net) => nets.push(net),
+                GroupMember::Mac(mac) => macs.push(mac),
+                GroupMember::Host(host) => {
+                    nets.extend(resolve(&host, now, group.resolve_interval).await)
+                }
+            }
+        }
+        sets.push(IpSet::new(address_set(group.id), SetType::Net, nets));
+        if !macs.is_empty() {
+            sets.push(IpSet::new(mac_set(group.id), SetType::Mac, macs));
+        }
+    }
+    for group in &config.port_groups {
+        let ports = group.ports.iter().map(|p| p.trim().to_string()).collect();
+        sets.push(IpSet::new(port_set(group.id), SetType::Port, ports));
+    }
+    for country in config.countries.iter().filter(|c| is_country_code(&c.code)) {
+        let networks = country.networks.iter().filter_map(|n| parse_cidr(n));
+        sets.push(IpSet::new(
+            country_set(&country.code),
+            SetType::Net,
+            networks.collect(),
+        ));
+    }
+    for feed in &config.threat_feeds {
+        let networks = feed.networks.iter().map(|n| n.trim().to_string());
+        sets.push(IpSet::new(
+            feed_set(feed.id),
+            SetType::Net,
+            networks.collect(),
+        ));
+    }
+    sets
+}
+
+/// IPv4 addresses of a host name, cached for `interval` seconds
+async fn resolve(host: &str, now: i64, interval: u32) -> Vec<String> {
+    let cached = RESOLVED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(host)
+        .cloned();
+    if let Some((at, addresses)) = &cached
+        && now - at < i64::from(interval)
+    {
+        return addresses.clone();
+    }
+
+    match tokio::net::lookup_host((host, 0)).await {
+        Ok(found) => {
+            let mut addresses: Vec<String> = found
+                .filter(|a| a.is_ipv4())
+                .map(|a| a.ip().to_string())
+                .collect();
+            addresses.sort();
+            addresses.dedup();
+            RESOLVED
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(host.to_string(), (now, addresses.clone()));
+            addresses
+        }
+        Err(e) => {
+            warn!("failed to resolve address group member {}: {}", host, e);
+            cached.map(|(_, addresses)| addresses).unwrap_or_default()
+        }
+    }
+}
+
+/// Window state at `minute` of every enabled rule with an enabled schedule
+fn scheduled(config: &FirewallConfig, minute: u32) -> BTreeMap<u32, bool> {
+    config
+        .rules
+        .iter()
+        .filter_map(|raw| serde_json::from_value::<FirewallRule>(raw.clone()).ok())
+        .filter(|rule| rule.enabled)
+        .filter_map(|rule| {
+            let schedule = rule.schedule.filter(|s| s.enabled)?;
+            Some((rule.id?, schedule.is_active(minute)))
+        })
+        .collect()
+}
+
+/// Minute of the week in the config's local time
+async fn local_minute(config: &FirewallConfig) -> u32 {
+    TimeZone::router(config.timezone.as_deref())
+        .await
+        .minute_of_week(clock::now())
+}
+
+pub(super) fn issue(field: &str, message: &str) -> ValidationIssue {
+    ValidationIssue {
+        field: field.to_string(),
+        message: message.to_string(),
+    }
+}
+
+/// Render one rule, expanding zones with several interfaces and address
+/// lists containing ranges into one kernel rule per combination.
+fn render_rule(
+    config: &FirewallConfig,
+    rule: &FirewallRule,
+    field: &str,
+) -> Result<Vec<String>, Vec<ValidationIssue>> {
+    let mut issues = Vec::new();
+    let chain = keep(chain_of(rule, field), &mut issues);
+    let inputs = keep(
+        zone_matches(config, rule.zone_from.as_deref(), "-i", field, "zone_from"),
+        &mut issues,
+    );
+    let outputs = keep(
+        zone_matches(config, rule.zone_to.as_deref(), "-o", field, "zone_to"),
+        &mut issues,
+    );
+    let sources = keep(
+        address_matches(rule.source.as_deref(), "src", field, "source"),
+        &mut issues,
+    );
+    let source_groups = keep(
+        group_matches(config, rule.source_group, "src", field, "source_group"),
+        &mut issues,
+    );
+    let destinations = keep(
+        address_matches(rule.destination.as_deref(), "dst", field, "destination"),
+        &mut issues,
+    );
+    let destination_groups = keep(
+        group_matches(
+            config,
+            rule.destination_group,
+            "dst",
+            field,
+            "destination_group",
+        ),
+        &mut issues,
+    );
+    let protocol = keep(protocol_match(rule, field), &mut issues);
+    let ports = keep(port_match(rule, field), &mut issues);
+    let port_group = keep(port_group_match(config, rule, field), &mut issues);
+    let source_countries = keep(
+        country_matches(
+            config,
+            &rule.source_countries,
+            "src",
+            field,
+            "source_countries",
+        ),
+        &mut issues,
+    );
+    let destination_countries = keep(
+        country_matches(
+            config,
+            &rule.destination_countries,
+            "dst",
+            field,
+            "destination_countries",
+        ),
+        &mut issues,
+    );
+    let target = keep(target_of(&rule.action, field), &mut issues);
+
+    if chain == Some("NGFW_INPUT") && !is_any(rule.zone_to.as_deref()) {
+        issues.push(issue(
+            &format!("{}.zone_to", field),
+            "rules on the INPUT chain cannot match an outgoing zone",
+        ));
+    }
+    if chain == Some("NGFW_OUTPUT") && !is_any(rule.zone_from.as_deref()) {
+        issues.push(issue(
+            &format!("{}.zone_from", field),
+            "rules on the OUTPUT chain cannot match an incoming zone",
+        ));
+    }
+    let (
+        Some(chain),
+        Some(inputs),
+        Some(outputs),
+        Some(sources),
+        Some(source_groups),
+        Some(destinations),
+        Some(destination_groups),
+        Some(protocol),
+        Some(mut ports),
+        Some(port_group),
+        Some(source_countries),
+        Some(destination_countries),
+        Some(target),
+    ) = (
+        chain,
+        inputs,
+        outputs,
+        sources,
+        source_groups,
+        destinations,
+        destination_groups,
+        protocol,
+        ports,
+        port_group,
+        source_countries,
+        destination_countries,
+        target,
+    )
+    else {
+        return Err(issues);
+    };
+    let sources = product(&sources, &source_groups);
+    let destinations = product(&destinations, &destination_groups);
+    ports.extend(port_group);
+    // Each combination is tagged with its source country, or failing that
+    // its destination country
+    let countries: Vec<(Option<&str>, Vec<String>)> = source_countries
+        .iter()
+        .flat_map(|(src, src_args)| {
+            destination_countries.iter().map(move |(dst, dst_args)| {
+                (src.or(*dst), [src_args.as_slice(), dst_args].concat())
+            })
+        })
+        .collect();
+    if let Some(schedule) = &rule.schedule {
+        for (name, message) in schedule.issues() {
+            issues.push(issue(&format!("{}.schedule.{}", field, name), &message));
+        }
+    }
+    if !issues.is_empty() {
+        return Err(issues);
+    }
+
+    let mut lines = Vec::new();
+    for (country, geo) in &countries {
+        let (comment, prefix) = match (rule.id, country) {
+            (Some(id), Some(country)) => (
+                format!("{}{}{}{}", RULE_COMMENT_PREFIX, id, COUNTRY_TAG, country),
+                format!("ngfw:{}:{} ", id, country),
+            ),
+            (Some(id), None) => (
+                format!("{}{}", RULE_COMMENT_PREFIX, id),
+                format!("ngfw:{} ", id),
+            ),
+            (None, _) => (UNTRACKED_COMMENT.to_string(), "ngfw ".to_string()),
+        };
+        for input in &inputs {
+            for output in &outputs {
+                for source in &sources {
+                    for destination in &destinations {
+                        let mut matches = vec![format!("-A {}", chain)];
+                        for part in [input, output, source, destination, geo, &protocol, &ports] {
+                            matches.extend(part.iter().cloned());
+                        }
+                        let matches = matches.join(" ");
+
+                        if rule.log {
+                            lines.push(format!(
+                                "{} -m comment --comment {}:log -j LOG --log-prefix \"{}\"",
+                                matches, comment, prefix
+                            ));
+                        }
+
+                        let mut line = format!("{} -m comment --comment {}", matches, comment);
+                        if let Some(target) = target {
+                            line.push_str(&format!(" -j {}", target));
+                        }
+                        lines.push(line);
+                    }
+                }
+            }
+        }
+    }
+    Ok(lines)
+}
+
+/// Set match arguments, tagged with the country they match
+type CountryMatch<'a> = (Option<&'a str>, Vec<String>);
+
+/// Set matches for a rule's countries, one alternative per country with
+/// the country it matches. No countries is a single untagged alternative.
+fn country_matches<'a>(
+    config: &FirewallConfig,
+    countries: &'a [String],
+    direction: &str,
+    field: &str,
+    name: &str,
+) -> Result<Vec<CountryMatch<'a>>, ValidationIssue> {
+    if countries.is_empty() {
+        return Ok(vec![(None, Vec::new())]);
+    }
+    countries
+        .iter()
+        .enumerate()
+        .map(|(i, code)| {
+            let known = is_country_code(code) && config.countries.iter().any(|c| &c.code == code);
+            if !known {
+                return Err(issue(
+                    &format!("{}.{}[{}]", field, name, i),
+                    &format!("no networks for country '{}'", code),
+                ));
+            }
+            Ok((
+                Some(code.as_str()),
+                ipset::match_args(&country_set(code), direction),
+            ))
+        })
+        .collect()
+}
+
+/// Every alternative of `a` combined with every alternative of `b`
+fn product(a: &[Vec<String>], b: &[Vec<String>]) -> Vec<Vec<String>> {
+    a.iter()
+        .flat_map(|x| b.iter().map(move |y| [x.as_slice(), y.as_slice()].concat()))
+        .collect()
+}
+
+/// Set matches for an address group reference. A group with MAC members
+/// matches either its address set or its MAC set, so it renders two
+/// alternatives; MACs can only be matched on the source.
+fn group_matches(
+    config: &FirewallConfig,
+    group: Option<u32>,
+    direction: &str,
+    field: &str,
+    name: &str,
+) -> Result<Vec<Vec<String>>, ValidationIssue> {
+    let Some(id) = group else {
+        return Ok(vec![Vec::new()]);
+    };
+    let field = format!("{}.{}", field, name);
+    let group = config
+        .address_groups
+        .iter()
+        .find(|g| g.id == id)
+        .ok_or_else(|| issue(&field, &format!("unknown address group {}", id)))?;
+
+    let mut alternatives = vec![ipset::match_args(&address_set(id), direction)];
+    let has_macs = group
+        .members
+        .iter()
+        .any(|m| matches!(parse_group_member(m), Some(GroupMember::Mac(_))));
+    if has_macs {
+        if direction != "src" {
+            return Err(issue(
+                &field,
+                &format!(
+                    "address group {} has MAC members, which only match sources",
+                    id
+                ),
+            ));
+        }
+        alternatives.push(ipset::match_args(&mac_set(id), direction));
+    }
+    Ok(alternatives)
+}
+
+/// Set match for a port group reference; only valid for TCP and UDP rules
+fn port_group_match(
+    config: &FirewallConfig,
+    rule: &FirewallRule,
+    field: &str,
+) -> Result<Vec<String>, ValidationIssue> {
+    let Some(id) = rule.port_group else {
+        return Ok(Vec::new());
+    };
+    let field = format!("{}.port_group", field);
+    if !config.port_groups.iter().any(|g| g.id == id) {
+        return Err(issue(&field, &format!("unknown port group {}", id)));
+    }
+    let protocol = rule
+        .protocol
+        .as_deref()
+        .unwrap_or("all")
+        .to_ascii_lowercase();
+    if protocol != "tcp" && protocol != "udp" {
+        return Err(issue(&field, "ports can only be matched for tcp or udp"));
+    }
+    Ok(ipset::match_args(&port_set(id), "dst"))
+}
+
+/// Keep a successful result, or record its issue
+pub(super) fn keep<T>(
+    result: Result<T, ValidationIssue>,
+    issues: &mut Vec<ValidationIssue>,
+) -> Option<T> {
+    result.map_err(|e| issues.push(e)).ok()
+}
+
+/// Agent-owned chain a rule is installed into
+fn chain_of(rule: &FirewallRule, field: &str) -> Result<&'static str, ValidationIssue> {
+    let builtin = rule.chain.as_deref().unwrap_or("FORWARD");
+    CHAINS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(builtin))
+        .map(|(_, managed)| *managed)
+        .ok_or_else(|| {
+            issue(
+                &format!("{}.chain", field),
+                &format!(
+                    "unknown chain '{}', expected INPUT, FORWARD or OUTPUT",
+                    builtin
+                ),
+            )
+        })
+}
+
+/// Interface matches for a zone, one alternative per interface
+fn zone_matches(
+    config: &FirewallConfig,
+    zone: Option<&str>,
+    flag: &str,
+    field: &str,
+    name: &str,
+) -> Result<Vec<Vec<String>>, ValidationIssue> {
+    if is_any(zone) {
+        return Ok(vec![Vec::new()]);
+    }
+    let zone = zone.unwrap_or_default();
+    let field = format!("{}.{}", field, name);
+    let found = config
+        .zones
+        .iter()
+        .find(|z| {
+            z.id.eq_ignore_ascii_case(zone)
+                || z.name
+                    .as_deref()
+                    .is_some_and(|n| n.eq_ignore_ascii_case(zone))
+        })
+        .ok_or_else(|| issue(&field, &format!("unknown zone '{}'", zone)))?;
+
+    if found.interfaces.is_empty() {
+        return Err(issue(&field, &format!("zone '{}' has no interfaces", zone)));
+    }
+    found
+        .interfaces
+        .iter()
+        .map(|iface| {
+            if is_interface_name(iface) {
+                Ok(vec![flag.to_string(), iface.clone()])
+            } else {
+                Err(issue(
+                    &field,
+                    &format!("invalid interface name '{}'", iface),
+                ))
+            }
+        })
+        .collect()
+}
+
+pub(super) fn is_interface_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 15
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '+'))
+}
+
+/// One parsed item of an address list
+enum AddressItem {
+    /// Single address or CIDR
+    Net(String),
+    /// `a.b.c.d-e.f.g.h`
+    Range(String),
+}
+
+fn parse_address_item(item: &str) -> Option<AddressItem> {
+    if let Some((start, end)) = item.split_once('-') {
+        let start: Ipv4Addr = start.trim().parse().ok()?;
+        let end: Ipv4Addr = end.trim().parse().ok()?;
+        return (start <= end).then(|| AddressItem::Range(format!("{}-{}", start, end)));
+    }
+    match item.split_once('/') {
+        Some((addr, prefix)) => {
+            addr.parse::<Ipv4Addr>().ok()?;
+            (prefix.parse::<u8>().ok()? <= 32).then(|| AddressItem::Net(item.to_string()))
+        }
+        None => item
+            .parse::<Ipv4Addr>()
+            .ok()
+            .map(|a| AddressItem::Net(a.to_string())),
+    }
+}
+
+/// A single address or CIDR network, as country sets hold them
+fn parse_cidr(network: &str) -> Option<String> {
+    match parse_address_item(network.trim())? {
+        AddressItem::Net(net) => Some(net),
+        AddressItem::Range(_) => None,
+    }
+}
+
+/// Address matches for `source` or `destination`.
+///
+/// A plain list becomes a single `-s a,b` match; lists containing ranges
+/// are split into one alternative per item since `iprange` takes one range.
+pub(super) fn address_matches(
+    spec: Option<&str>,
+    direction: &str,
+    field: &str,
+    name: &str,
+) -> Result<Vec<Vec<String>>, ValidationIssue> {
+    if is_any(spec) {
+        return Ok(vec![Vec::new()]);
+    }
+    let field = format!("{}.{}", field, name);
+    let spec = spec.unwrap_or_default().trim();
+    let (negated, list) = match spec.strip_prefix('!') {
+        Some(rest) => (true, rest.trim()),
+        None => (false, spec),
+    };
+
+    let items = list
+        .split(',')
+        .map(|item| {
+            parse_address_item(item.trim()).ok_or_else(|| {
+                issue(
+                    &field,
+                    &format!("'{}' is not an IPv4 address, CIDR or range", item.trim()),
+                )
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if negated && items.len() > 1 {
+        return Err(issue(
+            &field,
+            "negated address lists must have a single entry",
+        ));
+    }
+
+    let flag = if direction == "src" { "-s" } else { "-d" };
+    let render = |item: &AddressItem| -> Vec<String> {
+        let mut args = Vec::new();
+        match item {
+            AddressItem::Net(net) => {
+                if negated {
+                    args.push("!".to_string());
+                }
+                args.extend([flag.to_string(), net.clone()]);
+            }
+            AddressItem::Range(range) => {
+                args.extend(["-m".to_string(), "iprange".to_string()]);
+                if negated {
+                    args.push("!".to_string());
+                }
+                args.extend([format!("--{}-range", direction), range.clone()]);
+            }
+        }
+        args
+    };
+
+    if items.iter().all(|i| matches!(i, AddressItem::Net(_))) {
+        let nets: Vec<String> = items
+            .iter()
+            .filter_map(|i| match i {
+                AddressItem::Net(net) => Some(net.clone()),
+                AddressItem::Range(_) => None,
+            })
+            .collect();
+        return Ok(vec![render(&AddressItem::Net(nets.join(",")))]);
+    }
+    Ok(items.iter().map(render).collect())
+}
+
+fn protocol_match(rule: &FirewallRule, field: &str) -> Result<Vec<String>, ValidationIssue> {
+    protocol_args(rule.protocol.as_deref(), field)
+}
+
+/// `-p` match for a protocol; `all` matches every protocol
+pub(super) fn protocol_args(
+    protocol: Option<&str>,
+    field: &str,
+) -> Result<Vec<String>, ValidationIssue> {
+    let protocol = protocol.unwrap_or("all").to_ascii_lowercase();
+    match protocol.as_str() {
+        "all" | "any" | "" => Ok(Vec::new()),
+        "tcp" | "udp" | "icmp" | "gre" | "esp" | "ah" => Ok(vec!["-p".to_string(), protocol]),
+        other => Err(issue(
+            &format!("{}.protocol", field),
+            &format!("protocol '{}' is not supported by iptables", other),
+        )),
+    }
+}
+
+/// Destination port match; only valid for TCP and UDP rules
+fn port_match(rule: &FirewallRule, field: &str) -> Result<Vec<String>, ValidationIssue> {
+    multiport_match(
+        rule.protocol.as_deref(),
+        rule.port.as_deref(),
+        "--dports",
+        &format!("{}.port", field),
+    )
+}
+
+/// `multiport` match of a port list for `option` (`--sports` or
+/// `--dports`); only valid for TCP and UDP
+pub(super) fn multiport_match(
+    protocol: Option<&str>,
+    spec: Option<&str>,
+    option: &str,
+    field: &str,
+) -> Result<Vec<String>, ValidationIssue> {
+    if is_any(spec) {
+        return Ok(Vec::new());
+    }
+    let protocol = protocol.unwrap_or("all").to_ascii_lowercase();
+    if protocol != "tcp" && protocol != "udp" {
+        return Err(issue(field, "ports can only be matched for tcp or udp"));
+    }
+
+    let spec = spec.unwrap_or_default().trim();
+    let (negated, list) = match spec.strip_prefix('!') {
+        Some(rest) => (true, rest.trim()),
+        None => (false, spec),
+    };
+
+    let mut ports = Vec::new();
+    let mut slots = 0;
+    for item in list.split(',').map(str::trim) {
+        let parsed = match item.split_once(['-', ':']) {
+            Some((start, end)) => match (start.parse::<u16>(), end.parse::<u16>()) {
+                (Ok(start), Ok(end)) if start <= end => {
+                    slots += 2;
+                    Some(format!("{}:{}", start, end))
+                }
+                _ => None,
+            },
+            None => item.parse::<u16>().ok().map(|p| {
+                slots += 1;
+                p.to_string()
+            }),
+        };
+        ports.push(parsed.ok_or_else(|| issue(field, &format!("invalid port '{}'", item)))?);
+    }
+    if slots > MULTIPORT_MAX {
+        return Err(issue(
+            field,
+            &format!(
+                "at most {} ports (ranges count twice) per rule",
+                MULTIPORT_MAX
+            ),
+        ));
+    }
+
+    let mut args = vec!["-m".to_string(), "multiport".to_string()];
+    if negated {
+        args.push("!".to_string());
+    }
+    args.extend([option.to_string(), ports.join(",")]);
+    Ok(args)
+}
+
+/// iptables target for an action.
+///
+/// `limit` and `shape` are enforced by the QoS subsystem; their rules are
+/// installed without a target so they still count matching packets.
+fn target_of(action: &str, field: &str) -> Result<Option<&'static str>, ValidationIssue> {
+    match action.to_ascii_lowercase().as_str() {
+        "accept" => Ok(Some("ACCEPT")),
+        "drop" => Ok(Some("DROP")),
+        "reject" => Ok(Some("REJECT")),
+        "limit" | "shape" => Ok(None),
+        other => Err(issue(
+            &format!("{}.action", field),
+            &format!("unknown action '{}'", other),
+        )),
+    }
+}
+
+/// Build `iptables-restore --noflush` input replacing the managed chains.
+///
+/// Declaring a user chain in noflush mode flushes it, so the whole swap is
+/// committed atomically without touching other chains.
+fn restore_script(lines: &[String]) -> String {
+    let mut script = String::from("*filter\n");
+    for (_, managed) in CHAINS {
+        script.push_str(&format!(":{} - [0:0]\n", managed));
+    }
+    for line in lines {
+        script.push_str(line);
+        script.push('\n');
+    }
+    script.push_str("COMMIT\n");
+    script
+}
+
+/// Rule id carried in an `ngfw:rule:<id>` comment
+fn rule_id_of(comment: &str) -> Option<u32> {
+    rule_tag(comment).map(|(id, _)| id)
+}
+
+/// Rule id and country of a rule comment, `ngfw:rule:<id>` or
+/// `ngfw:rule:<id>:geo:<code>`
+fn rule_tag(comment: &str) -> Option<(u32, Option<&str>)> {
+    let tag = comment
+        .trim_matches('"')
+        .strip_prefix(RULE_COMMENT_PREFIX)?;
+    match tag.split_once(COUNTRY_TAG) {
+        Some((id, country)) => {
+            is_country_code(country).then_some((id.parse().ok()?, Some(country)))
+        }
+        None => Some((tag.parse().ok()?, None)),
+    }
+}
+
+/// Counters and rule tag of every tagged kernel rule in
+/// `iptables -L <chain> -v -x -n` output. Log rules
+/// (`ngfw:rule:<id>:log`) and untagged rules are skipped.
+fn tagged_counters(output: &str) -> impl Iterator<Item = ((u32, Option<&str>), u64, u64)> {
+    output.lines().filter_map(|line| {
+        let mut fields = line.split_whitespace();
+        let packets = fields.next()?.parse::<u64>().ok()?;
+        let bytes = fields.next()?.parse::<u64>().ok()?;
+        let (_, rest) = line.split_once("/* ")?;
+        let (comment, _) = rest.split_once(" */")?;
+        Some((rule_tag(comment)?, packets, bytes))
+    })
+}
+
+/// Parse `iptables -L <chain> -v -x -n` output into counters per rule id.
+///
+/// Counters of kernel rules sharing an id (interface, address or country
+/// expansion) are summed. Log rules (`ngfw:rule:<id>:log`) and untagged
+/// rules are skipped.
+pub fn parse_rule_counters(output: &str) -> Vec<RuleCounters> {
+    let mut counters: BTreeMap<u32, (u64, u64)> = BTreeMap::new();
+    for ((rule_id, _), packets, bytes) in tagged_counters(output) {
+        let entry = counters.entry(rule_id).or_default();
+        entry.0 += packets;
+        entry.1 += bytes;
+    }
+    counters
+        .into_iter()
+        .map(|(rule_id, (packets, bytes))| RuleCounters {
+            rule_id,
+            packets,
+            bytes,
+        })
+        .collect()
+}
+
+/// Parse `iptables -L <chain> -v -x -n` output into counters per country,
+/// summed over the `ngfw:rule:<id>:geo:<code>` rules of every rule.
+pub fn parse_country_counters(output: &str) -> Vec<CountryCounters> {
+    let mut counters: BTreeMap<&str, (u64, u64)> = BTreeMap::new();
+    for ((_, country), packets, bytes) in tagged_counters(output) {
+        if let Some(country) = country {
+            let entry = counters.entry(country).or_default();
+            entry.0 += packets;
+            entry.1 += bytes;
+        }
+    }
+    counters
+        .into_iter()
+        .map(|(country, (packets, bytes))| CountryCounters {
+            country: country.to_string(),
+            packets,
+            bytes,
+        })
+        .collect()
+}
+
+/// Parse `iptables -L <chain> -v -x -n` output into counters per threat
+/// feed, summed over the `ngfw:feed:<id>` rules of every chain.
+pub fn parse_feed_counters(output: &str) -> Vec<FeedCounters> {
+    let mut counters: BTreeMap<u32, (u64, u64)> = BTreeMap::new();
+    for line in output.lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(Ok(packets)), Some(Ok(bytes))) = (
+            fields.next().map(str::parse::<u64>),
+            fields.next().map(str::parse::<u64>),
+        ) else {
+            continue;
+        };
+        let feed_id = line
+            .split_once("/* ")
+            .and_then(|(_, rest)| rest.split_once(" */"))
+            .and_then(|(comment, _)| comment.trim_matches('"').strip_prefix(FEED_COMMENT_PREFIX))
+            .and_then(|id| id.parse::<u32>().ok());
+        if let Some(feed_id) = feed_id {
+            let entry = counters.entry(feed_id).or_default();
+            entry.0 += packets;
+            entry.1 += bytes;
+        }
+    }
+    counters
+        .into_iter()
+        .map(|(feed_id, (packets, bytes))| FeedCounters {
+            feed_id,
+            packets,
+            bytes,
+        })
+        .collect()
+}
+
+/// Rule ids referenced by installed or rendered rule lines
+fn rule_ids(lines: &[String]) -> BTreeMap<u32, BTreeSet<String>> {
+    let mut ids: BTreeMap<u32, BTreeSet<String>> = BTreeMap::new();
+    for line in lines {
+        let normalized = line.replace('"', "");
+        let id = normalized
+            .split_once("--comment ")
+            .and_then(|(_, rest)| rest.split_whitespace().next())
+            .and_then(rule_id_of);
+        if let Some(id) = id {
+            ids.entry(id).or_default().insert(normalized);
+        }
+    }
+    ids
+}
+
+pub(super) async fn run(
+    program: &str,
+    args: &[&str],
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let output = Command::new(program).args(args).output().await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("{} {} failed: {}", program, args.join(" "), stderr.trim()).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Feed a script to `iptables-restore --noflush`
+pub(super) async fn restore(script: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut child = Command::new("iptables-restore")
+        .args(["-w", "--noflush"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(script.as_bytes()).await?;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("iptables-restore failed: {}", stderr.trim()).into());
+    }
+    Ok(())
+}
+
+/// Make a built-in chain of `table` jump to a managed chain first, unless
+/// it already jumps to it
+pub(super) async fn hook(
+    table: &str,
+    builtin: &str,
+    managed: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let jump = ["-w", "-t", table, "-C", builtin, "-j", managed];
+    if run("iptables", &jump).await.is_err() {
+        run(
+            "iptables",
+            &["-w", "-t", table, "-I", builtin, "1", "-j", managed],
+        )
+        .await?;
+        debug!("hooked {} into {}", managed, builtin);
+    }
+    Ok(())
+}
+
+/// Packet counters of the managed chains
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub rules: Vec<RuleCounters>,
+    pub countries: Vec<CountryCounters>,
+    pub feeds: Vec<FeedCounters>,
+}
+
+/// Managed chain rules and group sets replaced by an apply
+struct Previous {
+    rules: Vec<String>,
+    sets: Vec<IpSet>,
+}
 
 #[derive(Default)]
-pub struct IptablesAdapter;
+pub struct IptablesAdapter {
+    /// State replaced by the last apply, for rollback
+    previous: Mutex<Option<Previous>>,
+}
+
+/// The applied config, reloaded from the copy persisted on apply after an
+/// agent restart
+async fn applied_config(applied: &mut Option<FirewallConfig>) -> Option<&FirewallConfig> {
+    if applied.is_none() {
+        *applied = rollback::load_applied(&ConfigSection::Firewall)
+            .await
+            .and_then(|value| serde_json::from_value(value).ok());
+    }
+    applied.as_ref()
+}
 
 impl IptablesAdapter {
-    pub fn new() -> Self {
-        Self
+    pub const fn new() -> Self {
+        Self {
+            previous: Mutex::new(None),
+        }
+    }
+
+    /// Rules currently installed in the managed chains, as `-A` lines.
+    /// A chain that does not exist yet contributes nothing.
+    async fn installed_rules(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (_, managed) in CHAINS {
+            match run("iptables", &["-w", "-t", "filter", "-S", managed]).await {
+                Ok(output) => lines.extend(
+                    output
+                        .lines()
+                        .filter(|l| l.starts_with("-A "))
+                        .map(str::to_string),
+                ),
+                Err(e) => debug!("chain {} not readable: {}", managed, e),
+            }
+        }
+        lines
+    }
+
+    /// Replace the managed chains with `lines` and make sure the built-in
+    /// chains jump to them.
+    async fn install(
+        &self,
+        lines: &[String],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        restore(&restore_script(lines)).await?;
+        for (builtin, managed) in CHAINS {
+            hook("filter", builtin, managed).await?;
+        }
+        Ok(())
+    }
+
+    /// Re-install the last applied rules if a schedule window opened or
+    /// closed since the last install. Returns whether anything switched.
+    ///
+    /// After an agent restart the config is reloaded from the copy
+    /// persisted on apply.
+    pub async fn enforce_schedules(
+        &self,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let mut applied = APPLIED.lock().await;
+        let Some(config) = applied_config(&mut applied).await else {
+            return Ok(false);
+        };
+
+        let minute = local_minute(config).await;
+        let active = scheduled(config, minute);
+        if !schedule::differs(&active) {
+            return Ok(false);
+        }
+        let lines = render(config, Some(minute))
+            .map_err(|issues| format!("invalid firewall config: {} issue(s)", issues.len()))?;
+        self.install(&lines).await?;
+        schedule::record(&active);
+        Ok(true)
+    }
+
+    /// Re-resolve host name members of the applied address groups whose
+    /// `resolve_interval` has elapsed and refill the sets that changed.
+    /// Rules are left alone. Returns whether any set was refilled.
+    pub async fn refresh_groups(&self) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let mut applied = APPLIED.lock().await;
+        let Some(config) = applied_config(&mut applied).await else {
+            return Ok(false);
+        };
+        let rebuilt = ipset::sync(&rule_sets(config).await).await?;
+        if !rebuilt.is_empty() {
+            debug!(sets = ?rebuilt, "refreshed address groups");
+        }
+        Ok(!rebuilt.is_empty())
+    }
+
+    /// Packet counters of every tagged rule in the managed chains, per rule,
+    /// per country and per threat feed.
+    pub async fn counters(&self) -> Counters {
+        let output = self.chain_counters().await;
+        Counters {
+            rules: parse_rule_counters(&output),
+            countries: parse_country_counters(&output),
+            feeds: parse_feed_counters(&output),
+        }
+    }
+
+    /// `iptables -L -v -x -n` listing of every managed chain
+    async fn chain_counters(&self) -> String {
+        let mut output = String::new();
+        for (_, managed) in CHAINS {
+            match run(
+                "iptables",
+                &["-w", "-t", "filter", "-L", managed, "-v", "-x", "-n"],
+            )
+            .await
+            {
+                Ok(chain) => output.push_str(&chain),
+                Err(e) => debug!("chain {} not readable: {}", managed, e),
+            }
+        }
+        output
     }
 }
 
@@ -20,37 +1413,557 @@ impl SubsystemAdapter for IptablesAdapter {
         ConfigSection::Firewall
     }
 
+    /// Rules installed in the managed chains, keyed by chain.
     async fn read_config(&self) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> {
-        todo!("iptables read_config not yet implemented")
+        let mut chains = serde_json::Map::new();
+        for (_, managed) in CHAINS {
+            chains.insert(managed.to_string(), json!([]));
+        }
+        for line in self.installed_rules().await {
+            let chain = line.split_whitespace().nth(1).unwrap_or_default();
+            if let Some(Value::Array(rules)) = chains.get_mut(chain) {
+                rules.push(json!(line));
+            }
+        }
+        Ok(json!({ "chains": chains }))
     }
 
     async fn validate(
         &self,
-        _config: &Value,
+        config: &Value,
     ) -> Result<Vec<ValidationIssue>, Box<dyn std::error::Error + Send + Sync>> {
-        todo!("iptables validate not yet implemented")
+        let config: FirewallConfig = match serde_json::from_value(config.clone()) {
+            Ok(config) => config,
+            Err(e) => return Ok(vec![issue("firewall", &e.to_string())]),
+        };
+        Ok(render(&config, None).err().unwrap_or_default())
     }
 
+    /// Diff by rule id: rules only installed are removals, rules only
+    /// proposed are additions, and rules whose kernel rules differ are
+    /// changes.
     async fn diff(
         &self,
-        _proposed: &Value,
+        proposed: &Value,
     ) -> Result<ConfigDiff, Box<dyn std::error::Error + Send + Sync>> {
-        todo!("iptables diff not yet implemented")
+        let config: FirewallConfig = serde_json::from_value(proposed.clone())?;
+        let minute = local_minute(&config).await;
+        let rendered = render(&config, Some(minute)).map_err(|issues| {
+            let messages: Vec<String> = issues
+                .iter()
+                .map(|i| format!("{}: {}", i.field, i.message))
+                .collect();
+            messages.join("; ")
+        })?;
+
+        let current = rule_ids(&self.installed_rules().await);
+        let proposed = rule_ids(&rendered);
+        let key = |id: &u32| format!("rule:{}", id);
+
+        Ok(ConfigDiff {
+            section: ConfigSection::Firewall,
+            additions: proposed
+                .keys()
+                .filter(|id| !current.contains_key(id))
+                .map(key)
+                .collect(),
+            removals: current
+                .keys()
+                .filter(|id| !proposed.contains_key(id))
+                .map(key)
+                .collect(),
+            changes: proposed
+                .iter()
+                .filter_map(|(id, lines)| {
+                    let old = current.get(id)?;
+                    (old != lines).then(|| {
+                        let join =
+                            |l: &BTreeSet<String>| l.iter().cloned().collect::<Vec<_>>().join("\n");
+                        (key(id), join(old), join(lines))
+                    })
+                })
+                .collect(),
+        })
     }
 
     async fn apply(
         &self,
-        _config: &Value,
-        _version: u64,
+        config: &Value,
+        version: u64,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        todo!("iptables apply not yet implemented")
+        // Nothing to roll back to until this apply starts changing rules
+        self.previous
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+
+        let config: FirewallConfig = serde_json::from_value(config.clone())?;
+        let minute = local_minute(&config).await;
+        let lines = render(&config, Some(minute))
+            .map_err(|issues| format!("invalid firewall config: {} issue(s)", issues.len()))?;
+
+        let mut applied = APPLIED.lock().await;
+        let previous = Previous {
+            rules: self.installed_rules().await,
+            sets: match applied_config(&mut applied).await {
+                Some(old) => rule_sets(old).await,
+                None => Vec::new(),
+            },
+        };
+        *self.previous.lock().unwrap_or_else(|e| e.into_inner()) = Some(previous);
+        // Sets must exist before the rules referencing them are installed
+        let sets = rule_sets(&config).await;
+        ipset::sync(&sets).await?;
+        self.install(&lines).await?;
+        ipset::destroy_unused(RULE_SET_PREFIXES, &sets).await;
+        schedule::record(&scheduled(&config, minute));
+        *applied = Some(config);
+
+        debug!(
+            version,
+            rules = lines.len(),
+            sets = sets.len(),
+            "applied firewall rules"
+        );
+        Ok(())
     }
 
     async fn rollback(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        todo!("iptables rollback not yet implemented")
+        let previous = self
+            .previous
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+            .ok_or("no previous firewall rules to roll back to")?;
+        let restored = match ipset::sync(&previous.sets).await {
+            Ok(_) => self.install(&previous.rules).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = restored {
+            warn!("firewall rollback failed: {}", e);
+            return Err(e);
+        }
+        ipset::destroy_unused(RULE_SET_PREFIXES, &previous.sets).await;
+        // The persisted copy is reloaded on the next schedule check
+        *APPLIED.lock().await = None;
+        debug!(rules = previous.rules.len(), "rolled back firewall rules");
+        Ok(())
     }
 
+    /// Per-rule, per-country and per-feed packet and byte counters, e.g.
+    /// `{ "rules": [{ "rule_id": 3, "packets": 12, "bytes": 1008 }],
+    /// "countries": [{ "country": "NL", "packets": 4, "bytes": 240 }],
+    /// "threat_feeds": [{ "feed_id": 1, "packets": 2, "bytes": 120 }] }`.
     async fn collect_metrics(&self) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> {
-        todo!("iptables collect_metrics not yet implemented")
+        let counters = self.counters().await;
+        Ok(json!({
+            "rules": counters.rules,
+            "countries": counters.countries,
+            "threat_feeds": counters.feeds,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(value: Value) -> FirewallConfig {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn zones() -> Value {
+        json!([
+            { "id": "WAN", "interfaces": ["eth0"] },
+            { "id": "LAN", "interfaces": ["br0", "br1"] }
+        ])
+    }
+
+    #[test]
+    fn rules_are_tagged_with_their_id() {
+        let config = config(json!({
+            "zones": zones(),
+            "rules": [{
+                "id": 7, "name": "Block SSH", "enabled": true,
+                "zone_from": "WAN", "zone_to": "any",
+                "source": "203.0.113.0/24", "destination": "any",
+                "protocol": "tcp", "port": "22", "action": "drop", "log": false
+            }]
+        }));
+        assert_eq!(
+            render(&config, None).unwrap(),
+            vec![
+                "-A NGFW_FORWARD -i eth0 -s 203.0.113.0/24 -p tcp -m multiport --dports 22 \
+                 -m comment --comment ngfw:rule:7 -j DROP"
+            ]
+        );
+    }
+
+    #[test]
+    fn zones_and_ranges_expand_and_logging_adds_a_rule() {
+        let config = config(json!({
+            "zones": zones(),
+            "rules": [{
+                "id": 2, "zone_from": "LAN", "zone_to": "WAN",
+                "source": "10.0.0.1-10.0.0.9,192.168.1.0/24",
+                "protocol": "udp", "port": "53,5000-5010", "action": "accept", "log": true
+            }]
+        }));
+        let lines = render(&config, None).unwrap();
+        // 2 interfaces x 2 source items, each with a log rule
+        assert_eq!(lines.len(), 8);
+        assert!(
+            lines
+                .iter()
+                .any(|l| l.contains("-m iprange --src-range 10.0.0.1-10.0.0.9"))
+        );
+        assert!(lines.iter().any(|l| l.contains("--dports 53,5000:5010")));
+        assert!(lines.iter().all(|l| l.contains("-o eth0")));
+        assert!(
+            lines
+                .iter()
+                .any(|l| l.contains("--comment ngfw:rule:2:log -j LOG"))
+        );
+    }
+
+    #[test]
+    fn disabled_rules_are_skipped_and_bad_rules_reported() {
+        let config = config(json!({
+            "zones": zones(),
+            "rules": [
+                { "id": 1, "enabled": false, "action": "drop" },
+                { "id": 2, "zone_from": "DMZ", "action": "drop" },
+                { "id": 3, "protocol": "icmp", "port": "22", "action": "accept" },
+                { "id": 4, "source": "!1.2.3.4,5.6.7.8", "action": "bounce" }
+            ]
+        }));
+        let issues = render(&config, None).unwrap_err();
+        let fields: Vec<&str> = issues.iter().map(|i| i.field.as_str()).collect();
+        assert_eq!(
+            fields,
+            vec![
+                "rules[1].zone_from",
+                "rules[2].port",
+                "rules[3].source",
+                "rules[3].action"
+            ]
+        );
+    }
+
+    #[test]
+    fn scheduled_rules_follow_their_window() {
+        let config = config(json!({
+            "zones": zones(),
+            "rules": [
+                { "id": 1, "action": "drop" },
+                {
+                    "id": 2, "action": "drop",
+                    "schedule": { "enabled": true, "start": "22:00", "end": "06:00", "days": [5] }
+                },
+                {
+                    "id": 3, "action": "drop",
+                    "schedule": { "enabled": true, "start": "9:00", "end": "17:00", "days": [] }
+                }
+            ]
+        }));
+        let fields: Vec<String> = render(&config, None)
+            .unwrap_err()
+            .into_iter()
+            .map(|i| i.field)
+            .collect();
+        assert_eq!(
+            fields,
+            vec!["rules[2].schedule.start", "rules[2].schedule.days"]
+        );
+
+        let mut config = config;
+        config.rules.pop();
+        // Friday 23:00 and Saturday 07:00
+        let friday_night = 5 * 1440 + 23 * 60;
+        let saturday_morning = 6 * 1440 + 7 * 60;
+        assert_eq!(render(&config, Some(friday_night)).unwrap().len(), 2);
+        assert_eq!(render(&config, Some(saturday_morning)).unwrap().len(), 1);
+        assert_eq!(
+            scheduled(&config, saturday_morning),
+            BTreeMap::from([(2, false)])
+        );
+    }
+
+    #[test]
+    fn parses_counters_per_rule() {
+        let output = "\
+Chain NGFW_FORWARD (1 references)
+    pkts      bytes target     prot opt in     out     source               destination
+      12     1008 DROP       tcp  --  eth0   *       0.0.0.0/0            0.0.0.0/0            multiport dports 22 /* ngfw:rule:7 */
+       3      180 LOG        udp  --  br0    eth0    0.0.0.0/0            0.0.0.0/0            /* ngfw:rule:2:log */ LOG flags 0 level 4 prefix \"ngfw:2 \"
+       3      180 ACCEPT     udp  --  br0    eth0    0.0.0.0/0            0.0.0.0/0            /* ngfw:rule:2 */
+       1       60 ACCEPT     udp  --  br1    eth0    0.0.0.0/0            0.0.0.0/0            /* \"ngfw:rule:2\" */
+       0        0 ACCEPT     all  --  *      *       0.0.0.0/0            0.0.0.0/0            /* ngfw:rule */
+";
+        assert_eq!(
+            parse_rule_counters(output),
+            vec![
+                RuleCounters {
+                    rule_id: 2,
+                    packets: 4,
+                    bytes: 240
+                },
+                RuleCounters {
+                    rule_id: 7,
+                    packets: 12,
+                    bytes: 1008
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn countries_render_one_tagged_rule_each() {
+        let countries = config(json!({
+            "zones": zones(),
+            "countries": [
+                { "code": "NL", "hash": "a", "networks": ["198.51.100.0/24"] },
+                { "code": "DE", "hash": "b", "networks": ["203.0.113.0/24"] }
+            ],
+            "rules": [{
+                "id": 5, "zone_from": "WAN", "zone_to": "any",
+                "source_countries": ["NL", "DE"], "action": "drop", "log": true
+            }]
+        }));
+        let lines = render(&countries, None).unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                "-A NGFW_FORWARD -i eth0 -m set --match-set ngfw_cnl src \
+                 -m comment --comment ngfw:rule:5:geo:NL:log -j LOG --log-prefix \"ngfw:5:NL \"",
+                "-A NGFW_FORWARD -i eth0 -m set --match-set ngfw_cnl src \
+                 -m comment --comment ngfw:rule:5:geo:NL -j DROP",
+                "-A NGFW_FORWARD -i eth0 -m set --match-set ngfw_cde src \
+                 -m comment --comment ngfw:rule:5:geo:DE:log -j LOG --log-prefix \"ngfw:5:DE \"",
+                "-A NGFW_FORWARD -i eth0 -m set --match-set ngfw_cde src \
+                 -m comment --comment ngfw:rule:5:geo:DE -j DROP",
+            ]
+        );
+
+        let unknown = config(json!({
+            "rules": [{ "id": 6, "destination_countries": ["FR"], "action": "drop" }]
+        }));
+        let fields: Vec<String> = render(&unknown, None)
+            .unwrap_err()
+            .into_iter()
+            .map(|i| i.field)
+            .collect();
+        assert_eq!(fields, vec!["rules[0].destination_countries[0]"]);
+    }
+
+    #[test]
+    fn country_sets_are_validated() {
+        let countries = config(json!({
+            "countries": [
+                { "code": "nl", "networks": ["198.51.100.0/24"] },
+                { "code": "DE", "networks": [
+                    "203.0.113.0/24",
+                    "192.0.2.0/24\ndestroy ngfw_a1",
+                    "10.0.0.0/40",
+                    "10.0.0.1-10.0.0.9"
+                ] }
+            ]
+        }));
+        let fields: Vec<String> = render(&countries, None)
+            .unwrap_err()
+            .into_iter()
+            .map(|i| i.field)
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                "countries[0].code",
+                "countries[1].networks[1]",
+                "countries[1].networks[2]",
+                "countries[1].networks[3]",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn country_sets_hold_only_valid_networks() {
+        let countries = config(json!({
+            "countries": [
+                { "code": "N L", "networks": ["198.51.100.0/24"] },
+                { "code": "DE", "networks": [" 203.0.113.0/24 ", "192.0.2.0/24 -exist"] }
+            ]
+        }));
+        assert_eq!(
+            rule_sets(&countries).await,
+            vec![IpSet::new(
+                "ngfw_cde".to_string(),
+                SetType::Net,
+                vec!["203.0.113.0/24".to_string()],
+            )]
+        );
+    }
+
+    #[test]
+    fn parses_counters_per_country() {
+        let output = "\
+Chain NGFW_FORWARD (1 references)
+    pkts      bytes target     prot opt in     out     source               destination
+       2      120 LOG        all  --  eth0   *       0.0.0.0/0            0.0.0.0/0            match-set ngfw_cnl src /* ngfw:rule:5:geo:NL:log */
+       2      120 DROP       all  --  eth0   *       0.0.0.0/0            0.0.0.0/0            match-set ngfw_cnl src /* ngfw:rule:5:geo:NL */
+       1       40 DROP       all  --  eth0   *       0.0.0.0/0            0.0.0.0/0            match-set ngfw_cnl src /* ngfw:rule:6:geo:NL */
+       4      400 DROP       all  --  eth0   *       0.0.0.0/0            0.0.0.0/0            match-set ngfw_cde src /* ngfw:rule:5:geo:DE */
+";
+        assert_eq!(
+            parse_rule_counters(output),
+            vec![
+                RuleCounters {
+                    rule_id: 5,
+                    packets: 6,
+                    bytes: 520
+                },
+                RuleCounters {
+                    rule_id: 6,
+                    packets: 1,
+                    bytes: 40
+                },
+            ]
+        );
+        assert_eq!(
+            parse_country_counters(output),
+            vec![
+                CountryCounters {
+                    country: "DE".to_string(),
+                    packets: 4,
+                    bytes: 400
+                },
+                CountryCounters {
+                    country: "NL".to_string(),
+                    packets: 3,
+                    bytes: 160
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn threat_feeds_drop_ahead_of_rules_and_count_per_feed() {
+        let mut feeds = config(json!({
+            "zones": zones(),
+            "threat_feeds": [{ "id": 2, "hash": "a", "networks": ["192.0.2.0/24"] }],
+            "rules": [{ "id": 1, "action": "accept" }]
+        }));
+        let lines = render(&feeds, None).unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                "-A NGFW_INPUT -i eth0 -m set --match-set ngfw_t2 src \
+                 -m comment --comment ngfw:feed:2 -j DROP",
+                "-A NGFW_FORWARD -i eth0 -m set --match-set ngfw_t2 src \
+                 -m comment --comment ngfw:feed:2 -j DROP",
+                "-A NGFW_FORWARD -o eth0 -m set --match-set ngfw_t2 dst \
+                 -m comment --comment ngfw:feed:2 -j DROP",
+                "-A NGFW_OUTPUT -o eth0 -m set --match-set ngfw_t2 dst \
+                 -m comment --comment ngfw:feed:2 -j DROP",
+                "-A NGFW_FORWARD -m comment --comment ngfw:rule:1 -j ACCEPT",
+            ]
+        );
+        assert_eq!(rule_ids(&lines).keys().collect::<Vec<_>>(), vec![&1]);
+
+        // Without a WAN zone every interface is covered
+        feeds.zones.clear();
+        assert_eq!(
+            render(&feeds, None).unwrap()[0],
+            "-A NGFW_INPUT -m set --match-set ngfw_t2 src -m comment --comment ngfw:feed:2 -j DROP"
+        );
+
+        let output = "\
+Chain NGFW_FORWARD (1 references)
+    pkts      bytes target     prot opt in     out     source               destination
+       3      180 DROP       all  --  *      *       0.0.0.0/0            0.0.0.0/0            match-set ngfw_t2 src /* ngfw:feed:2 */
+       1       60 DROP       all  --  *      *       0.0.0.0/0            0.0.0.0/0            match-set ngfw_t2 dst /* ngfw:feed:2 */
+       5      300 ACCEPT     all  --  *      *       0.0.0.0/0            0.0.0.0/0            /* ngfw:rule:1 */
+";
+        assert_eq!(
+            parse_feed_counters(output),
+            vec![FeedCounters {
+                feed_id: 2,
+                packets: 4,
+                bytes: 240
+            }]
+        );
+        assert_eq!(parse_rule_counters(output).len(), 1);
+    }
+
+    #[test]
+    fn groups_render_as_set_matches() {
+        let config = config(json!({
+            "zones": zones(),
+            "address_groups": [
+                { "id": 1, "members": ["10.0.0.0/24", "AA-BB-CC-DD-EE-FF"] },
+                { "id": 2, "members": ["198.51.100.7"] }
+            ],
+            "port_groups": [{ "id": 3, "ports": ["80", "8000-8080"] }],
+            "rules": [{
+                "id": 4, "zone_from": "LAN", "zone_to": "any",
+                "source": "10.0.0.0/16", "destination": "any",
+                "source_group": 1, "destination_group": 2, "port_group": 3,
+                "protocol": "tcp", "action": "accept"
+            }]
+        }));
+        assert_eq!(
+            render(&config, None).unwrap()[..2],
+            [
+                "-A NGFW_FORWARD -i br0 -s 10.0.0.0/16 -m set --match-set ngfw_a1 src \
+                 -m set --match-set ngfw_a2 dst -p tcp -m set --match-set ngfw_p3 dst \
+                 -m comment --comment ngfw:rule:4 -j ACCEPT",
+                "-A NGFW_FORWARD -i br0 -s 10.0.0.0/16 -m set --match-set ngfw_m1 src \
+                 -m set --match-set ngfw_a2 dst -p tcp -m set --match-set ngfw_p3 dst \
+                 -m comment --comment ngfw:rule:4 -j ACCEPT",
+            ]
+        );
+        assert_eq!(
+            parse_group_member("AA-BB-CC-DD-EE-FF"),
+            Some(GroupMember::Mac("aa:bb:cc:dd:ee:ff".to_string()))
+        );
+        assert_eq!(
+            parse_group_member("api.example.com"),
+            Some(GroupMember::Host("api.example.com".to_string()))
+        );
+    }
+
+    #[test]
+    fn group_references_are_checked() {
+        let config = config(json!({
+            "address_groups": [
+                { "id": 1, "members": ["aa:bb:cc:dd:ee:ff", "not a host"] }
+            ],
+            "port_groups": [{ "id": 2, "ports": ["90-80"] }],
+            "rules": [{
+                "id": 1, "destination_group": 1, "source_group": 9,
+                "port_group": 2, "protocol": "icmp", "action": "drop"
+            }]
+        }));
+        let fields: Vec<String> = render(&config, None)
+            .unwrap_err()
+            .into_iter()
+            .map(|i| i.field)
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                "address_groups[0].members[1]",
+                "port_groups[0].ports[0]",
+                "rules[0].source_group",
+                "rules[0].destination_group",
+                "rules[0].port_group",
+            ]
+        );
+    }
+
+    #[test]
+    fn restore_script_declares_managed_chains() {
+        let script = restore_script(&["-A NGFW_INPUT -j ACCEPT".to_string()]);
+        assert!(script.starts_with("*filter\n:NGFW_INPUT - [0:0]\n"));
+        assert!(script.ends_with("-A NGFW_INPUT -j ACCEPT\nCOMMIT\n"));
     }
 }
@@ -5,7 +5,9 @@
 //! and rolling back configuration as well as collecting runtime metrics.
 
 pub mod dnsmasq;
+pub mod ipset;
 pub mod iptables;
+pub mod nat;
 pub mod nvram;
 pub mod system;
 pub mod wifi;
@@ -16,6 +18,8 @@ pub use dnsmasq::DnsmasqAdapter;
 #[allow(unused_imports)]
 pub use iptables::IptablesAdapter;
 #[allow(unused_imports)]
+pub use nat::NatAdapter;
+#[allow(unused_imports)]
 pub use nvram::NvramAdapter;
 #[allow(unused_imports)]
 pub use system::SystemAdapter;
@@ -45,6 +49,7 @@ pub struct ConfigDiff {
 }
 
 /// Uniform interface implemented by every router subsystem adapter.
+#[allow(clippy::double_must_use)]
 #[async_trait::async_trait]
 pub trait SubsystemAdapter: Send + Sync {
     /// Which configuration section this adapter owns.
@@ -0,0 +1,979 @@
+//! NAT adapter
+//!
+//! Port forwards and source NAT are installed into agent-owned chains of
+//! the `nat` table (`NGFW_PREROUTING`, `NGFW_POSTROUTING`) that the
+//! built-in chains jump to, so the firmware's own NAT rules are never
+//! rewritten. Every installed rule carries a `-m comment --comment
+//! ngfw:nat:<id>` tag with its `NatRule` id.
+//!
+//! The section config is an object of the form `{ "rules": [NatRule] }`.
+//! `dnat` rules translate the destination of packets arriving on
+//! `interface`; `snat` and `masquerade` rules translate the source of
+//! packets leaving through it. A `1:1` rule maps `destination` to
+//! `translate_to` both ways. Address and port group references match the
+//! sets the firewall adapter keeps for every group (see [`super::iptables`]);
+//! a rule referencing a group whose set does not exist yet fails validation
+//! until the firewall section defining the group is applied.
+//!
+//! Port mappings that LAN clients open through UPnP IGD or NAT-PMP are
+//! owned by miniupnpd, which installs them into its own `MINIUPNPD` chains
+//! and records them in its lease file. The adapter reports those leases and
+//! can revoke one by deleting its kernel rules and its lease.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+use ngfw_protocol::UpnpMapping;
+use ngfw_protocol::rpc::ConfigSection;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::{debug, warn};
+
+use super::iptables::{
+    address_matches, address_set, hook, is_any, is_interface_name, issue, keep, multiport_match,
+    port_set, protocol_args, restore, run,
+};
+use super::{ConfigDiff, SubsystemAdapter, ValidationIssue, ipset};
+
+/// Built-in chains of the `nat` table and the agent-owned chains they jump to.
+const CHAINS: &[(&str, &str)] = &[
+    ("PREROUTING", "NGFW_PREROUTING"),
+    ("POSTROUTING", "NGFW_POSTROUTING"),
+];
+
+/// Comment prefix identifying the `NatRule` a kernel rule belongs to.
+pub const NAT_COMMENT_PREFIX: &str = "ngfw:nat:";
+
+/// Chain miniupnpd installs its mappings into, in both the `nat` and the
+/// `filter` table.
+const UPNP_CHAIN: &str = "MINIUPNPD";
+
+/// miniupnpd's lease file (`lease_file` in miniupnpd.conf).
+const UPNP_LEASE_FILE: &str = "/tmp/upnp.leases";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct NatConfig {
+    #[serde(default)]
+    rules: Vec<Value>,
+}
+
+/// A `NatRule` as pushed by the API
+#[derive(Debug, Deserialize)]
+struct NatRule {
+    id: u32,
+    #[serde(default = "enabled_default")]
+    enabled: bool,
+    nat_type: String,
+    #[serde(default)]
+    protocol: Option<String>,
+    #[serde(default)]
+    source: Option<String>,
+    #[serde(default)]
+    source_port: Option<String>,
+    #[serde(default)]
+    destination: Option<String>,
+    #[serde(default)]
+    destination_port: Option<String>,
+    #[serde(default)]
+    translate_to: Option<String>,
+    #[serde(default)]
+    translate_port: Option<String>,
+    #[serde(default)]
+    interface: Option<String>,
+    #[serde(default)]
+    log: bool,
+    #[serde(default)]
+    source_group: Option<u32>,
+    #[serde(default)]
+    destination_group: Option<u32>,
+    #[serde(default)]
+    port_group: Option<u32>,
+}
+
+fn enabled_default() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NatType {
+    Dnat,
+    Snat,
+    Masquerade,
+    OneToOne,
+}
+
+fn nat_type_of(rule: &NatRule, field: &str) -> Result<NatType, ValidationIssue> {
+    match rule.nat_type.to_ascii_lowercase().as_str() {
+        "dnat" => Ok(NatType::Dnat),
+        "snat" => Ok(NatType::Snat),
+        "masquerade" => Ok(NatType::Masquerade),
+        "1:1" => Ok(NatType::OneToOne),
+        other => Err(issue(
+            &format!("{}.nat_type", field),
+            &format!("unknown NAT type '{}'", other),
+        )),
+    }
+}
+
+/// Render a NAT config into `iptables-restore` rule lines. Collects every
+/// problem instead of stopping at the first, so the same function backs
+/// `validate`.
+fn render(config: &NatConfig) -> Result<Vec<String>, Vec<ValidationIssue>> {
+    let mut lines = Vec::new();
+    let mut issues = Vec::new();
+
+    for (index, raw) in config.rules.iter().enumerate() {
+        let field = format!("rules[{}]", index);
+        let rule: NatRule = match serde_json::from_value(raw.clone()) {
+            Ok(rule) => rule,
+            Err(e) => {
+                issues.push(issue(&field, &e.to_string()));
+                continue;
+            }
+        };
+        match render_rule(&rule, &field) {
+            Ok(rendered) if rule.enabled => lines.extend(rendered),
+            Ok(_) => {}
+            Err(mut errors) => issues.append(&mut errors),
+        }
+    }
+
+    if issues.is_empty() {
+        Ok(lines)
+    } else {
+        Err(issues)
+    }
+}
+
+/// Group sets enabled rules match, as `(field, group kind, group id, set)`
+fn group_references(config: &NatConfig) -> Vec<(String, &'static str, u32, String)> {
+    let mut references = Vec::new();
+    for (index, raw) in config.rules.iter().enumerate() {
+        let Ok(rule) = serde_json::from_value::<NatRule>(raw.clone()) else {
+            continue;
+        };
+        if !rule.enabled {
+            continue;
+        }
+        let field = |name: &str| format!("rules[{}].{}", index, name);
+        if let Some(id) = rule.source_group {
+            references.push((field("source_group"), "address", id, address_set(id)));
+        }
+        if let Some(id) = rule.destination_group {
+            references.push((field("destination_group"), "address", id, address_set(id)));
+        }
+        if let Some(id) = rule.port_group {
+            references.push((field("port_group"), "port", id, port_set(id)));
+        }
+    }
+    references
+}
+
+/// Issues for group references whose sets do not exist on the router.
+/// Only the firewall adapter creates group sets, from the definitions in
+/// its section.
+async fn missing_groups(config: &NatConfig) -> Vec<ValidationIssue> {
+    let references = group_references(config);
+    if references.is_empty() {
+        return Vec::new();
+    }
+    let existing = match ipset::existing_sets().await {
+        Ok(existing) => existing,
+        Err(e) => return vec![issue("rules", &format!("cannot check group sets: {}", e))],
+    };
+    references
+        .into_iter()
+        .filter(|(_, _, _, set)| !existing.contains(set))
+        .map(|(field, kind, id, _)| {
+            issue(
+                &field,
+                &format!(
+                    "{} group {} is not installed; apply the firewall section defining it first",
+                    kind, id
+                ),
+            )
+        })
+        .collect()
+}
+
+/// Render one rule, expanding address lists containing ranges into one
+/// kernel rule per combination.
+fn render_rule(rule: &NatRule, field: &str) -> Result<Vec<String>, Vec<ValidationIssue>> {
+    let mut issues = Vec::new();
+    let nat_type = keep(nat_type_of(rule, field), &mut issues);
+    let interface = keep(interface_of(rule, field), &mut issues);
+    let sources = keep(
+        address_matches(rule.source.as_deref(), "src", field, "source"),
+        &mut issues,
+    );
+    let destinations = keep(
+        address_matches(rule.destination.as_deref(), "dst", field, "destination"),
+        &mut issues,
+    );
+    let protocol = keep(protocol_args(rule.protocol.as_deref(), field), &mut issues);
+    let source_ports = keep(
+        multiport_match(
+            rule.protocol.as_deref(),
+            rule.source_port.as_deref(),
+            "--sports",
+            &format!("{}.source_port", field),
+        ),
+        &mut issues,
+    );
+    let destination_ports = keep(
+        multiport_match(
+            rule.protocol.as_deref(),
+            rule.destination_port.as_deref(),
+            "--dports",
+            &format!("{}.destination_port", field),
+        ),
+        &mut issues,
+    );
+    let port_group = keep(port_group_match(rule, field), &mut issues);
+    let translate_port = keep(translate_port_of(rule, field), &mut issues);
+    // Masquerading takes the address of the outgoing interface
+    let translate_to = match nat_type {
+        Some(NatType::Masquerade) => Some(String::new()),
+        _ => keep(translate_address(rule, field), &mut issues),
+    };
+    if nat_type == Some(NatType::OneToOne) {
+        one_to_one_issues(rule, field, &mut issues);
+    }
+
+    let (
+        Some(nat_type),
+        Some(interface),
+        Some(sources),
+        Some(destinations),
+        Some(protocol),
+        Some(source_ports),
+        Some(destination_ports),
+        Some(port_group),
+        Some(translate_port),
+        Some(translate_to),
+    ) = (
+        nat_type,
+        interface,
+        sources,
+        destinations,
+        protocol,
+        source_ports,
+        destination_ports,
+        port_group,
+        translate_port,
+        translate_to,
+    )
+    else {
+        return Err(issues);
+    };
+    if !issues.is_empty() {
+        return Err(issues);
+    }
+
+    let comment = format!("{}{}", NAT_COMMENT_PREFIX, rule.id);
+    let mut groups = Vec::new();
+    if let Some(id) = rule.source_group {
+        groups.extend(ipset::match_args(&address_set(id), "src"));
+    }
+    if let Some(id) = rule.destination_group {
+        groups.extend(ipset::match_args(&address_set(id), "dst"));
+    }
+    let ports = [protocol, source_ports, destination_ports, port_group].concat();
+    let translate_port = translate_port
+        .map(|port| format!(":{}", port))
+        .unwrap_or_default();
+
+    // (chain, interface flag, target) of every kernel rule of the rule
+    let targets: Vec<(&str, &str, String)> = match nat_type {
+        NatType::Dnat => vec![(
+            "NGFW_PREROUTING",
+            "-i",
+            format!("DNAT --to-destination {}{}", translate_to, translate_port),
+        )],
+        NatType::Snat => vec![(
+            "NGFW_POSTROUTING",
+            "-o",
+            format!("SNAT --to-source {}{}", translate_to, translate_port),
+        )],
+        NatType::Masquerade => vec![(
+            "NGFW_POSTROUTING",
+            "-o",
+            match translate_port.strip_prefix(':') {
+                Some(ports) => format!("MASQUERADE --to-ports {}", ports),
+                None => "MASQUERADE".to_string(),
+            },
+        )],
+        NatType::OneToOne => vec![
+            (
+                "NGFW_PREROUTING",
+                "-i",
+                format!("DNAT --to-destination {}", translate_to),
+            ),
+            (
+                "NGFW_POSTROUTING",
+                "-o",
+                format!(
+                    "SNAT --to-source {}",
+                    rule.destination.as_deref().unwrap_or_default().trim()
+                ),
+            ),
+        ],
+    };
+
+    let mut lines = Vec::new();
+    for (chain, flag, target) in &targets {
+        let mut prefix = vec![format!("-A {}", chain)];
+        if let Some(interface) = &interface {
+            prefix.extend([flag.to_string(), interface.clone()]);
+        }
+        let alternatives: Vec<Vec<String>> =
+            if *chain == "NGFW_POSTROUTING" && nat_type == NatType::OneToOne {
+                // The reverse direction of a 1:1 mapping leaves from the
+                // internal address, whatever the rule matches on the way in
+                vec![vec!["-s".to_string(), translate_to.clone()]]
+            } else {
+                sources
+                    .iter()
+                    .flat_map(|source| {
+                        destinations
+                            .iter()
+                            .map(move |destination| [source.as_slice(), destination].concat())
+                    })
+                    .collect()
+            };
+        for addresses in alternatives {
+            let mut matches = prefix.clone();
+            matches.extend(addresses);
+            if *chain == "NGFW_PREROUTING" || nat_type != NatType::OneToOne {
+                matches.extend(groups.iter().cloned());
+                matches.extend(ports.iter().cloned());
+            }
+            let matches = matches.join(" ");
+            if rule.log {
+                lines.push(format!(
+                    "{} -m comment --comment {}:log -j LOG --log-prefix \"{} \"",
+                    matches, comment, comment
+                ));
+            }
+            lines.push(format!(
+                "{} -m comment --comment {} -j {}",
+                matches, comment, target
+            ));
+        }
+    }
+    Ok(lines)
+}
+
+/// Interface the rule is bound to, if any
+fn interface_of(rule: &NatRule, field: &str) -> Result<Option<String>, ValidationIssue> {
+    let Some(interface) = rule.interface.as_deref().map(str::trim) else {
+        return Ok(None);
+    };
+    if is_any(Some(interface)) {
+        return Ok(None);
+    }
+    if !is_interface_name(interface) {
+        return Err(issue(
+            &format!("{}.interface", field),
+            &format!("invalid interface name '{}'", interface),
+        ));
+    }
+    Ok(Some(interface.to_string()))
+}
+
+/// The single IPv4 address a rule translates to
+fn translate_address(rule: &NatRule, field: &str) -> Result<String, ValidationIssue> {
+    let value = rule.translate_to.as_deref().unwrap_or_default().trim();
+    value
+        .parse::<Ipv4Addr>()
+        .map(|address| address.to_string())
+        .map_err(|_| {
+            issue(
+                &format!("{}.translate_to", field),
+                &format!("'{}' is not an IPv4 address", value),
+            )
+        })
+}
+
+/// The port or `start-end` range a rule translates to
+fn translate_port_of(rule: &NatRule, field: &str) -> Result<Option<String>, ValidationIssue> {
+    if is_any(rule.translate_port.as_deref()) {
+        return Ok(None);
+    }
+    let field = format!("{}.translate_port", field);
+    let protocol = rule
+        .protocol
+        .as_deref()
+        .unwrap_or("all")
+        .to_ascii_lowercase();
+    if protocol != "tcp" && protocol != "udp" {
+        return Err(issue(&field, "ports can only be translated for tcp or udp"));
+    }
+    let spec = rule.translate_port.as_deref().unwrap_or_default().trim();
+    let parsed = match spec.split_once(['-', ':']) {
+        Some((start, end)) => match (start.trim().parse::<u16>(), end.trim().parse::<u16>()) {
+            (Ok(start), Ok(end)) if start <= end => Some(format!("{}-{}", start, end)),
+            _ => None,
+        },
+        None => spec.parse::<u16>().ok().map(|port| port.to_string()),
+    };
+    parsed
+        .map(Some)
+        .ok_or_else(|| issue(&field, &format!("'{}' is not a port or port range", spec)))
+}
+
+/// Set match for a port group reference; only valid for TCP and UDP rules
+fn port_group_match(rule: &NatRule, field: &str) -> Result<Vec<String>, ValidationIssue> {
+    let Some(id) = rule.port_group else {
+        return Ok(Vec::new());
+    };
+    let protocol = rule
+        .protocol
+        .as_deref()
+        .unwrap_or("all")
+        .to_ascii_lowercase();
+    if protocol != "tcp" && protocol != "udp" {
+        return Err(issue(
+            &format!("{}.port_group", field),
+            "ports can only be matched for tcp or udp",
+        ));
+    }
+    Ok(ipset::match_args(&port_set(id), "dst"))
+}
+
+/// A 1:1 rule maps one whole address to another
+fn one_to_one_issues(rule: &NatRule, field: &str, issues: &mut Vec<ValidationIssue>) {
+    let destination = rule.destination.as_deref().unwrap_or_default().trim();
+    if destination.parse::<Ipv4Addr>().is_err() {
+        issues.push(issue(
+            &format!("{}.destination", field),
+            "1:1 rules map a single external IPv4 address",
+        ));
+    }
+    let ports = [
+        ("source_port", &rule.source_port),
+        ("destination_port", &rule.destination_port),
+        ("translate_port", &rule.translate_port),
+    ];
+    for (name, value) in ports {
+        if !is_any(value.as_deref()) {
+            issues.push(issue(
+                &format!("{}.{}", field, name),
+                "1:1 rules map every port",
+            ));
+        }
+    }
+    if rule.port_group.is_some() {
+        issues.push(issue(
+            &format!("{}.port_group", field),
+            "1:1 rules map every port",
+        ));
+    }
+}
+
+/// Build `iptables-restore --noflush` input replacing the managed chains.
+fn restore_script(lines: &[String]) -> String {
+    let mut script = String::from("*nat\n");
+    for (_, managed) in CHAINS {
+        script.push_str(&format!(":{} - [0:0]\n", managed));
+    }
+    for line in lines {
+        script.push_str(line);
+        script.push('\n');
+    }
+    script.push_str("COMMIT\n");
+    script
+}
+
+/// Kernel rules of each `NatRule`, keyed by rule id
+fn rule_ids(lines: &[String]) -> BTreeMap<u32, BTreeSet<String>> {
+    let mut ids: BTreeMap<u32, BTreeSet<String>> = BTreeMap::new();
+    for line in lines {
+        let normalized = line.replace('"', "");
+        let id = normalized
+            .split_once(&format!("--comment {}", NAT_COMMENT_PREFIX))
+            .and_then(|(_, rest)| rest.split_whitespace().next())
+            .and_then(|tag| tag.split(':').next())
+            .and_then(|id| id.parse().ok());
+        if let Some(id) = id {
+            ids.entry(id).or_default().insert(normalized);
+        }
+    }
+    ids
+}
+
+/// Parse miniupnpd's lease file, one
+/// `PROTO:EXT_PORT:INT_IP:INT_PORT:EXPIRES:DESC` line per mapping.
+/// `EXPIRES` is 0 for mappings without a lease time; descriptions may
+/// contain colons.
+pub fn parse_upnp_leases(text: &str) -> Vec<UpnpMapping> {
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.trim_end().splitn(6, ':');
+            let protocol = fields.next()?.to_ascii_lowercase();
+            if protocol != "tcp" && protocol != "udp" {
+                return None;
+            }
+            let external_port = fields.next()?.parse().ok()?;
+            let internal_ip = fields.next()?.parse::<Ipv4Addr>().ok()?.to_string();
+            let internal_port = fields.next()?.parse().ok()?;
+            let expires_at = fields.next()?.parse::<i64>().ok()?;
+            Some(UpnpMapping {
+                protocol,
+                external_port,
+                internal_ip,
+                internal_port,
+                description: fields.next().unwrap_or_default().to_string(),
+                expires_at: (expires_at > 0).then_some(expires_at),
+            })
+        })
+        .collect()
+}
+
+/// The lease file without the lease of `external_port`
+fn without_lease(text: &str, protocol: &str, external_port: u16) -> String {
+    let prefix = format!("{}:{}:", protocol, external_port);
+    text.lines()
+        .filter(|line| !line.to_ascii_lowercase().starts_with(&prefix))
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+/// Value of an option in an `iptables -S` line
+fn option_value<'a>(line: &'a str, option: &str) -> Option<&'a str> {
+    let mut tokens = line.split_whitespace();
+    tokens.find(|t| *t == option)?;
+    tokens.next()
+}
+
+/// Rules of miniupnpd's chains implementing the mapping of `external_port`,
+/// as `(table, -S line)`: the DNAT rule in `nat`, and the `filter` rules
+/// accepting the traffic it forwards.
+fn upnp_rules(
+    nat: &str,
+    filter: &str,
+    protocol: &str,
+    external_port: u16,
+) -> Vec<(&'static str, String)> {
+    let external_port = external_port.to_string();
+    let mut rules = Vec::new();
+    let mut targets = Vec::new();
+    for line in nat.lines().filter(|l| l.starts_with("-A ")) {
+        if option_value(line, "-p") == Some(protocol)
+            && option_value(line, "--dport") == Some(external_port.as_str())
+        {
+            if let Some(target) = option_value(line, "--to-destination") {
+                targets.push(target.to_string());
+            }
+            rules.push(("nat", line.to_string()));
+        }
+    }
+    for line in filter.lines().filter(|l| l.starts_with("-A ")) {
+        let destination = option_value(line, "-d").map(|d| d.trim_end_matches("/32"));
+        let port = option_value(line, "--dport");
+        let forwards = targets.iter().any(|target| {
+            let (ip, target_port) = target
+                .split_once(':')
+                .unwrap_or((target.as_str(), external_port.as_str()));
+            destination == Some(ip) && port == Some(target_port)
+        });
+        if option_value(line, "-p") == Some(protocol) && forwards {
+            rules.push(("filter", line.to_string()));
+        }
+    }
+    rules
+}
+
+#[derive(Default)]
+pub struct NatAdapter {
+    /// Rules replaced by the last apply, for rollback
+    previous: Mutex<Option<Vec<String>>>,
+}
+
+impl NatAdapter {
+    pub const fn new() -> Self {
+        Self {
+            previous: Mutex::new(None),
+        }
+    }
+
+    /// Rules currently installed in the managed chains, as `-A` lines.
+    /// A chain that does not exist yet contributes nothing.
+    async fn installed_rules(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (_, managed) in CHAINS {
+            match run("iptables", &["-w", "-t", "nat", "-S", managed]).await {
+                Ok(output) => lines.extend(
+                    output
+                        .lines()
+                        .filter(|l| l.starts_with("-A "))
+                        .map(str::to_string),
+                ),
+                Err(e) => debug!("chain {} not readable: {}", managed, e),
+            }
+        }
+        lines
+    }
+
+    /// Replace the managed chains with `lines` and make sure the built-in
+    /// chains jump to them.
+    async fn install(
+        &self,
+        lines: &[String],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        restore(&restore_script(lines)).await?;
+        for (builtin, managed) in CHAINS {
+            hook("nat", builtin, managed).await?;
+        }
+        Ok(())
+    }
+
+    /// Mappings in miniupnpd's lease file, or `None` if there is no lease
+    /// file because miniupnpd is not running.
+    pub async fn upnp_leases(&self) -> Option<Vec<UpnpMapping>> {
+        let text = tokio::fs::read_to_string(UPNP_LEASE_FILE).await.ok()?;
+        Some(parse_upnp_leases(&text))
+    }
+
+    /// Remove the UPnP mapping of `external_port`: delete its rules from
+    /// miniupnpd's chains and its line from the lease file. Fails if the
+    /// router has no such mapping.
+    pub async fn revoke_upnp_lease(
+        &self,
+        protocol: &str,
+        external_port: u16,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let protocol = protocol.to_ascii_lowercase();
+        if protocol != "tcp" && protocol != "udp" {
+            return Err(format!("unknown UPnP protocol '{}'", protocol).into());
+        }
+        let listing = |table: &'static str| async move {
+            run("iptables", &["-w", "-t", table, "-S", UPNP_CHAIN])
+                .await
+                .unwrap_or_default()
+        };
+        let rules = upnp_rules(
+            &listing("nat").await,
+            &listing("filter").await,
+            &protocol,
+            external_port,
+        );
+        let leases = tokio::fs::read_to_string(UPNP_LEASE_FILE)
+            .await
+            .unwrap_or_default();
+        let remaining = without_lease(&leases, &protocol, external_port);
+        if rules.is_empty() && remaining.len() == leases.len() {
+            return Err(format!("no UPnP mapping for {} port {}", protocol, external_port).into());
+        }
+
+        for (table, line) in &rules {
+            let spec: Vec<&str> = line.split_whitespace().skip(1).collect();
+            let mut args = vec!["-w", "-t", table, "-D"];
+            args.extend(spec);
+            run("iptables", &args).await?;
+        }
+        if remaining.len() != leases.len() {
+            tokio::fs::write(UPNP_LEASE_FILE, remaining).await?;
+        }
+        debug!(
+            protocol = %protocol,
+            external_port,
+            rules = rules.len(),
+            "revoked UPnP mapping"
+        );
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl SubsystemAdapter for NatAdapter {
+    fn section(&self) -> ConfigSection {
+        ConfigSection::Nat
+    }
+
+    /// Rules installed in the managed chains, keyed by chain.
+    async fn read_config(&self) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> {
+        let mut chains = serde_json::Map::new();
+        for (_, managed) in CHAINS {
+            chains.insert(managed.to_string(), json!([]));
+        }
+        for line in self.installed_rules().await {
+            let chain = line.split_whitespace().nth(1).unwrap_or_default();
+            if let Some(Value::Array(rules)) = chains.get_mut(chain) {
+                rules.push(json!(line));
+            }
+        }
+        Ok(json!({ "chains": chains }))
+    }
+
+    async fn validate(
+        &self,
+        config: &Value,
+    ) -> Result<Vec<ValidationIssue>, Box<dyn std::error::Error + Send + Sync>> {
+        let config: NatConfig = match serde_json::from_value(config.clone()) {
+            Ok(config) => config,
+            Err(e) => return Ok(vec![issue("nat", &e.to_string())]),
+        };
+        match render(&config) {
+            Ok(_) => Ok(missing_groups(&config).await),
+            Err(issues) => Ok(issues),
+        }
+    }
+
+    /// Diff by rule id, like the firewall adapter.
+    async fn diff(
+        &self,
+        proposed: &Value,
+    ) -> Result<ConfigDiff, Box<dyn std::error::Error + Send + Sync>> {
+        let config: NatConfig = serde_json::from_value(proposed.clone())?;
+        let rendered = render(&config).map_err(|issues| {
+            let messages: Vec<String> = issues
+                .iter()
+                .map(|i| format!("{}: {}", i.field, i.message))
+                .collect();
+            messages.join("; ")
+        })?;
+
+        let current = rule_ids(&self.installed_rules().await);
+        let proposed = rule_ids(&rendered);
+        let key = |id: &u32| format!("nat:{}", id);
+
+        Ok(ConfigDiff {
+            section: ConfigSection::Nat,
+            additions: proposed
+                .keys()
+                .filter(|id| !current.contains_key(id))
+                .map(key)
+                .collect(),
+            removals: current
+                .keys()
+                .filter(|id| !proposed.contains_key(id))
+                .map(key)
+                .collect(),
+            changes: proposed
+                .iter()
+                .filter_map(|(id, lines)| {
+                    let old = current.get(id)?;
+                    (old != lines).then(|| {
+                        let join =
+                            |l: &BTreeSet<String>| l.iter().cloned().collect::<Vec<_>>().join("\n");
+                        (key(id), join(old), join(lines))
+                    })
+                })
+                .collect(),
+        })
+    }
+
+    async fn apply(
+        &self,
+        config: &Value,
+        version: u64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // Nothing to roll back to until this apply starts changing rules
+        self.previous
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+
+        let config: NatConfig = serde_json::from_value(config.clone())?;
+        let lines = render(&config)
+            .map_err(|issues| format!("invalid NAT config: {} issue(s)", issues.len()))?;
+
+        let previous = self.installed_rules().await;
+        *self.previous.lock().unwrap_or_else(|e| e.into_inner()) = Some(previous);
+        self.install(&lines).await?;
+
+        debug!(version, rules = lines.len(), "applied NAT rules");
+        Ok(())
+    }
+
+    async fn rollback(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let previous = self
+            .previous
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+            .ok_or("no previous NAT rules to roll back to")?;
+        if let Err(e) = self.install(&previous).await {
+            warn!("NAT rollback failed: {}", e);
+            return Err(e);
+        }
+        debug!(rules = previous.len(), "rolled back NAT rules");
+        Ok(())
+    }
+
+    /// UPnP leases held by miniupnpd, e.g. `{ "upnp_leases": [{ "protocol":
+    /// "tcp", "external_port": 8080, "internal_ip": "192.168.1.20",
+    /// "internal_port": 80, "description": "NAS", "expires_at": 1700003600 }] }`,
+    /// with `null` leases when miniupnpd is not running.
+    async fn collect_metrics(&self) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(json!({ "upnp_leases": self.upnp_leases().await }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(value: Value) -> NatConfig {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn port_forwards_and_masquerade_render_into_managed_chains() {
+        let lines = render(&config(json!({ "rules": [
+            {
+                "id": 1, "enabled": true, "nat_type": "dnat", "protocol": "tcp",
+                "source": "any", "destination": "any", "destination_port": "8080",
+                "translate_to": "192.168.1.20", "translate_port": "80",
+                "interface": "eth0", "log": true
+            },
+            {
+                "id": 2, "enabled": true, "nat_type": "masquerade", "protocol": "all",
+                "source": "192.168.1.0/24", "destination": "any",
+                "translate_to": "", "interface": "eth0"
+            },
+            {
+                "id": 3, "enabled": false, "nat_type": "snat", "protocol": "all",
+                "source": "10.0.0.0/8", "destination": "any", "translate_to": "203.0.113.9"
+            }
+        ]})))
+        .unwrap();
+        assert_eq!(
+            lines,
+            [
+                "-A NGFW_PREROUTING -i eth0 -p tcp -m multiport --dports 8080 \
+                 -m comment --comment ngfw:nat:1:log -j LOG --log-prefix \"ngfw:nat:1 \"",
+                "-A NGFW_PREROUTING -i eth0 -p tcp -m multiport --dports 8080 \
+                 -m comment --comment ngfw:nat:1 -j DNAT --to-destination 192.168.1.20:80",
+                "-A NGFW_POSTROUTING -o eth0 -s 192.168.1.0/24 \
+                 -m comment --comment ngfw:nat:2 -j MASQUERADE",
+            ]
+        );
+        assert_eq!(rule_ids(&lines).keys().copied().collect::<Vec<_>>(), [1, 2]);
+        assert!(restore_script(&lines).starts_with("*nat\n:NGFW_PREROUTING - [0:0]\n"));
+    }
+
+    #[test]
+    fn one_to_one_maps_both_directions() {
+        let lines = render(&config(json!({ "rules": [{
+            "id": 4, "enabled": true, "nat_type": "1:1", "protocol": "all",
+            "source": "any", "destination": "203.0.113.10",
+            "translate_to": "192.168.1.10", "interface": "eth0"
+        }]})))
+        .unwrap();
+        assert_eq!(
+            lines,
+            [
+                "-A NGFW_PREROUTING -i eth0 -d 203.0.113.10 \
+                 -m comment --comment ngfw:nat:4 -j DNAT --to-destination 192.168.1.10",
+                "-A NGFW_POSTROUTING -o eth0 -s 192.168.1.10 \
+                 -m comment --comment ngfw:nat:4 -j SNAT --to-source 203.0.113.10",
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_rules_are_reported() {
+        let issues = render(&config(json!({ "rules": [
+            {
+                "id": 1, "enabled": true, "nat_type": "dnat", "protocol": "icmp",
+                "source": "any", "destination": "any", "destination_port": "22",
+                "translate_to": "lan-host"
+            },
+            {
+                "id": 2, "enabled": false, "nat_type": "1:1", "protocol": "all",
+                "source": "any", "destination": "203.0.113.0/24",
+                "translate_to": "192.168.1.10", "interface": "bad iface"
+            }
+        ]})))
+        .unwrap_err();
+        let fields: Vec<&str> = issues.iter().map(|i| i.field.as_str()).collect();
+        assert_eq!(
+            fields,
+            [
+                "rules[0].destination_port",
+                "rules[0].translate_to",
+                "rules[1].interface",
+                "rules[1].destination",
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_miniupnpd_leases() {
+        let leases = parse_upnp_leases(
+            "TCP:51413:192.168.1.30:51413:1700003600:Transmission at 51413\n\
+             UDP:3074:192.168.1.40:3074:0:Xbox: party chat\n\
+             garbage line\n",
+        );
+        assert_eq!(leases.len(), 2);
+        assert_eq!(leases[0].protocol, "tcp");
+        assert_eq!(leases[0].expires_at, Some(1_700_003_600));
+        assert_eq!(leases[1].description, "Xbox: party chat");
+        assert_eq!(leases[1].expires_at, None);
+        assert_eq!(
+            without_lease(
+                "TCP:80:192.168.1.2:80:0:a\nUDP:80:192.168.1.2:80:0:b\n",
+                "tcp",
+                80
+            ),
+            "UDP:80:192.168.1.2:80:0:b\n"
+        );
+    }
+
+    #[test]
+    fn revoking_finds_the_mapping_rules() {
+        let nat = "-N MINIUPNPD\n\
+            -A MINIUPNPD -i eth0 -p tcp -m tcp --dport 8080 -j DNAT --to-destination 192.168.1.20:80\n\
+            -A MINIUPNPD -i eth0 -p udp -m udp --dport 8080 -j DNAT --to-destination 192.168.1.21:8080\n";
+        let filter = "-N MINIUPNPD\n\
+            -A MINIUPNPD -d 192.168.1.20/32 -i eth0 -o br0 -p tcp -m tcp --dport 80 -j ACCEPT\n\
+            -A MINIUPNPD -d 192.168.1.21/32 -i eth0 -o br0 -p udp -m udp --dport 8080 -j ACCEPT\n";
+        let rules = upnp_rules(nat, filter, "tcp", 8080);
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].0, "nat");
+        assert!(rules[0].1.contains("192.168.1.20:80"));
+        assert_eq!(rules[1].0, "filter");
+        assert!(rules[1].1.contains("-d 192.168.1.20/32"));
+        assert!(upnp_rules(nat, filter, "tcp", 9090).is_empty());
+    }
+
+    #[test]
+    fn group_references_name_the_firewall_sets() {
+        let references = group_references(&config(json!({ "rules": [
+            {
+                "id": 1, "nat_type": "dnat", "protocol": "tcp", "destination_port": "8080",
+                "translate_to": "192.168.1.20", "interface": "eth0",
+                "source_group": 3, "port_group": 4
+            },
+            {
+                "id": 2, "enabled": false, "nat_type": "masquerade", "interface": "eth0",
+                "source_group": 5
+            }
+        ]})));
+        assert_eq!(
+            references,
+            vec![
+                (
+                    "rules[0].source_group".to_string(),
+                    "address",
+                    3,
+                    address_set(3)
+                ),
+                ("rules[0].port_group".to_string(), "port", 4, port_set(4)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_apply_does_not_roll_back_to_older_rules() {
+        let adapter = NatAdapter::new();
+        *adapter.previous.lock().unwrap() = Some(vec!["-A NGFW_PREROUTING -j ACCEPT".into()]);
+
+        // Rejected before any rule changed, so there is nothing to restore
+        assert!(adapter.apply(&json!([]), 2).await.is_err());
+        assert!(adapter.rollback().await.is_err());
+    }
+}
@@ -0,0 +1,144 @@
+//! Clock skew correction against server time
+//!
+//! Routers often boot without NTP, so the local wall clock can be hours or
+//! decades off. The connection loop learns an offset (server time minus
+//! local time) from `AuthResponse.server_time` and refreshes it on every
+//! `Pong`; every timestamp the agent sends goes through [`correct`] or
+//! [`now`] so the cloud sees server-aligned times.
+
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+
+use ngfw_protocol::{AlertMessage, AlertSeverity, AlertType, MessageType, RpcMessage};
+
+/// Skew beyond which a warning alert is raised, in seconds
+pub const SKEW_WARN_SECS: i64 = 300;
+
+static OFFSET_SECS: AtomicI64 = AtomicI64::new(0);
+static SYNCED: AtomicBool = AtomicBool::new(false);
+
+/// Local wall-clock time in milliseconds since the Unix epoch
+pub fn local_now_ms() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// Local wall-clock time as a Unix timestamp, uncorrected
+pub fn local_now() -> i64 {
+    local_now_ms() / 1000
+}
+
+/// Server-aligned Unix timestamp
+pub fn now() -> i64 {
+    correct(local_now())
+}
+
+/// Convert a local Unix timestamp to server-aligned time
+pub fn correct(local_ts: i64) -> i64 {
+    local_ts + OFFSET_SECS.load(Ordering::Relaxed)
+}
+
+/// Current offset in seconds, or `None` until the first sync
+pub fn offset() -> Option<i64> {
+    SYNCED
+        .load(Ordering::Relaxed)
+        .then(|| OFFSET_SECS.load(Ordering::Relaxed))
+}
+
+/// Store a new offset, returning the previous one (if any)
+pub fn set_offset(secs: i64) -> Option<i64> {
+    let previous = offset();
+    OFFSET_SECS.store(secs, Ordering::Relaxed);
+    SYNCED.store(true, Ordering::Relaxed);
+    previous
+}
+
+/// Estimate the offset from a server timestamp received in reply to a
+/// request sent at `sent_ms` and answered at `received_ms` (local millis).
+///
+/// The server is assumed to have stamped its reply halfway through the
+/// round trip.
+pub fn estimate_offset(server_time: i64, sent_ms: i64, received_ms: i64) -> i64 {
+    let midpoint_ms = sent_ms + (received_ms - sent_ms) / 2;
+    ((server_time * 1000 - midpoint_ms) as f64 / 1000.0).round() as i64
+}
+
+/// Whether an offset is large enough to warn about
+pub fn exceeds_threshold(offset: i64) -> bool {
+    offset.abs() > SKEW_WARN_SECS
+}
+
+/// Build the warning alert raised when the local clock is badly skewed
+pub fn skew_alert(offset: i64) -> RpcMessage {
+    let direction = if offset > 0 { "behind" } else { "ahead of" };
+    let alert = AlertMessage {
+        timestamp: now(),
+        severity: AlertSeverity::Medium,
+        alert_type: AlertType::SystemAnomaly,
+        source: "agent".to_string(),
+        description: format!(
+            "Router clock is {}s {} server time; check NTP configuration",
+            offset.abs(),
+            direction
+        ),
+        details: Some(serde_json::json!({
+            "clock_offset": offset,
+            "threshold": SKEW_WARN_SECS,
+        })),
+    };
+    RpcMessage::new(
+        MessageType::Alert,
+        serde_json::to_value(alert).unwrap_or_default(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_now_reasonable() {
+        // Must be after 2023-11-14 (~1700000000)
+        assert!(local_now() > 1_700_000_000);
+    }
+
+    #[test]
+    fn estimate_uses_round_trip_midpoint() {
+        // Local clock reads 1000s, server says 1500s, 2s round trip
+        let offset = estimate_offset(1501, 1_000_000, 1_002_000);
+        assert_eq!(offset, 500);
+    }
+
+    #[test]
+    fn estimate_handles_clock_ahead() {
+        // Router thinks it is an hour in the future
+        let offset = estimate_offset(1_700_000_000, 1_700_003_600_000, 1_700_003_600_040);
+        assert_eq!(offset, -3600);
+    }
+
+    #[test]
+    fn unsynced_router_at_epoch() {
+        // Router booted at 1970 with no NTP
+        let offset = estimate_offset(1_700_000_000, 5_000, 5_100);
+        assert_eq!(5 + offset, 1_700_000_000);
+        assert!(exceeds_threshold(offset));
+    }
+
+    #[test]
+    fn threshold_is_symmetric() {
+        assert!(!exceeds_threshold(SKEW_WARN_SECS));
+        assert!(exceeds_threshold(SKEW_WARN_SECS + 1));
+        assert!(exceeds_threshold(-SKEW_WARN_SECS - 1));
+    }
+
+    #[test]
+    fn skew_alert_is_system_anomaly() {
+        let msg = skew_alert(-7200);
+        assert_eq!(msg.msg_type, MessageType::Alert);
+        let alert: AlertMessage = serde_json::from_value(msg.payload).unwrap();
+        assert_eq!(alert.alert_type, AlertType::SystemAnomaly);
+        assert!(alert.description.contains("7200s ahead of"));
+        assert_eq!(alert.details.unwrap()["clock_offset"], -7200);
+    }
+}
@@ -1,18 +1,29 @@
 //! Metrics collection loop
 //!
 //! Reads system stats from /proc and /sys on a configurable interval,
-//! computes derived values (CPU %, memory %, interface rates), and sends
-//! a `MetricsPayload` over the outbound channel to the cloud API.
+//! computes derived values (CPU %, memory %, interface rates), reads the
+//! packet counters of agent-installed firewall rules and miniupnpd's port
+//! mappings, and sends a `MetricsPayload` over the outbound channel to the
+//! cloud API.
+//!
+//! While the link is down, samples are kept in a bounded ring buffer and
+//! sent as `MetricsBatch` messages once the agent re-authenticates, so the
+//! portal's graphs have no hole for the outage.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
 use ngfw_protocol::{
-    ConnectionCounts, DnsMetrics, InterfaceRates, MessageType, MetricsPayload, RpcMessage,
+    ConnectionCounts, CountryCounters, DnsMetrics, FeedCounters, InterfaceRates, MessageType,
+    MetricsBatchPayload, MetricsPayload, RpcMessage, RuleCounters,
 };
 use tokio::sync::{mpsc, watch};
-use tracing::{debug, error, warn};
+use tracing::{debug, error, info, warn};
 
+use crate::adapters::iptables::Counters;
+use crate::adapters::{IptablesAdapter, NatAdapter};
+use crate::clock;
 use crate::config::AgentConfig;
+use crate::connection::LinkStatus;
 
 /// Interfaces to monitor for byte-rate calculations.
 const MONITORED_INTERFACES: &[&str] = &["br0", "eth0"];
@@ -21,15 +32,25 @@ const MONITORED_INTERFACES: &[&str] = &["br0", "eth0"];
 /// this far apart to compute a meaningful delta.
 const CPU_SAMPLE_MS: u64 = 100;
 
+/// Memory budget for samples buffered while disconnected.
+const HISTORY_BUDGET_BYTES: usize = 64 * 1024;
+
+/// Maximum samples per `MetricsBatch` message.
+const MAX_BATCH_SAMPLES: usize = 120;
+
 // ---------------------------------------------------------------------------
 // Public entry point
 // ---------------------------------------------------------------------------
 
 /// Long-running loop that collects system metrics and sends them to the
 /// cloud API via `outbound_tx`.  Exits cleanly when `shutdown` fires.
+///
+/// Samples taken while `link` reports the agent unauthenticated are
+/// buffered and flushed as `MetricsBatch` messages on re-authentication.
 pub async fn metrics_loop(
     config: AgentConfig,
     outbound_tx: mpsc::Sender<RpcMessage>,
+    mut link: watch::Receiver<LinkStatus>,
     mut shutdown: watch::Receiver<bool>,
 ) {
     let interval_secs = config.agent.metrics_interval_secs;
@@ -40,6 +61,14 @@ pub async fn metrics_loop(
     let mut prev_bytes: HashMap<String, (u64, u64)> = HashMap::new();
     let mut prev_ts = tokio::time::Instant::now();
 
+    let mut history = MetricsHistory::new(HISTORY_BUDGET_BYTES);
+    let mut link_open = true;
+
+    // Counters of the rules the agent installed, attributed by rule id
+    let firewall = config.adapters.iptables.then(IptablesAdapter::new);
+    // UPnP leases sit next to the NAT rules the same adapter flag covers
+    let nat = config.adapters.iptables.then(NatAdapter::new);
+
     debug!("metrics collector started (interval={}s)", interval_secs);
 
     loop {
@@ -50,6 +79,20 @@ pub async fn metrics_loop(
                     break;
                 }
             }
+            changed = link.changed(), if link_open => {
+                if changed.is_err() {
+                    // Publisher gone; keep the last known status
+                    link_open = false;
+                    continue;
+                }
+                if link.borrow_and_update().authenticated
+                    && !history.is_empty()
+                    && !flush_history(&mut history, &outbound_tx).await
+                {
+                    break;
+                }
+                continue;
+            }
             _ = interval.tick() => {}
         }
 
@@ -66,20 +109,48 @@ pub async fn metrics_loop(
         let (interfaces, new_bytes) = read_interfaces(&prev_bytes, elapsed_secs).await;
         let connections = read_connections().await;
         let dns = read_dns().await;
+        let counters = match &firewall {
+            Some(adapter) => adapter.counters().await,
+            None => Counters::default(),
+        };
 
         prev_bytes = new_bytes;
         prev_ts = now;
 
-        let payload = MetricsPayload {
-            timestamp: chrono_timestamp(),
+        // Stamped with local time; corrected to server time when sent
+        let mut payload = MetricsPayload {
+            timestamp: clock::local_now(),
             cpu,
             memory,
             temperature,
             interfaces,
             connections,
             dns,
+            firewall_rules: counters.rules,
+            countries: counters.countries,
+            threat_feeds: counters.feeds,
+            upnp_leases: None,
         };
 
+        if !link.borrow().authenticated {
+            history.push(payload);
+            debug!(
+                buffered = history.len(),
+                "link down, buffered metrics sample"
+            );
+            continue;
+        }
+
+        // Leases are live state; buffered samples go without them
+        if let Some(adapter) = &nat {
+            payload.upnp_leases = adapter.upnp_leases().await.map(|mut leases| {
+                for lease in &mut leases {
+                    lease.expires_at = lease.expires_at.map(clock::correct);
+                }
+                leases
+            });
+        }
+        payload.timestamp = clock::correct(payload.timestamp);
         let value = match serde_json::to_value(&payload) {
             Ok(v) => v,
             Err(e) => {
@@ -101,6 +172,140 @@ pub async fn metrics_loop(
     debug!("metrics collector stopped");
 }
 
+/// Send buffered samples as `MetricsBatch` messages, oldest first.
+/// Returns `false` if the outbound channel has closed.
+async fn flush_history(
+    history: &mut MetricsHistory,
+    outbound_tx: &mpsc::Sender<RpcMessage>,
+) -> bool {
+    let batches = history.drain_batches(MAX_BATCH_SAMPLES);
+    let total: usize = batches.iter().map(|b| b.samples.len()).sum();
+    info!(
+        samples = total,
+        batches = batches.len(),
+        "backfilling buffered metrics"
+    );
+
+    for mut batch in batches {
+        for sample in &mut batch.samples {
+            sample.timestamp = clock::correct(sample.timestamp);
+        }
+        let value = match serde_json::to_value(&batch) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed to serialize metrics batch: {}", e);
+                continue;
+            }
+        };
+        let msg = RpcMessage::new(MessageType::MetricsBatch, value);
+        if let Err(e) = outbound_tx.send(msg).await {
+            warn!("outbound channel closed, stopping metrics collector: {}", e);
+            return false;
+        }
+    }
+    true
+}
+
+// ---------------------------------------------------------------------------
+// History buffer
+// ---------------------------------------------------------------------------
+
+/// Ring buffer of samples collected while the link is down.
+///
+/// When a new sample would push the buffer over its byte budget, the older
+/// half is downsampled by dropping every other sample. A long outage thus
+/// keeps coarse coverage of its start and full resolution of its end.
+///
+/// Samples keep their local timestamps until flushed, so an offset learned
+/// on reconnection also corrects samples taken before the first sync.
+struct MetricsHistory {
+    samples: VecDeque<MetricsPayload>,
+    budget_bytes: usize,
+    used_bytes: usize,
+}
+
+impl MetricsHistory {
+    fn new(budget_bytes: usize) -> Self {
+        Self {
+            samples: VecDeque::new(),
+            budget_bytes,
+            used_bytes: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    fn push(&mut self, sample: MetricsPayload) {
+        self.used_bytes += sample_size(&sample);
+        self.samples.push_back(sample);
+
+        while self.used_bytes > self.budget_bytes && !self.samples.is_empty() {
+            self.downsample();
+        }
+    }
+
+    /// Drop every other sample from the older half of the buffer.
+    fn downsample(&mut self) {
+        let half = self.samples.len() / 2;
+        if half < 2 {
+            if let Some(dropped) = self.samples.pop_front() {
+                self.used_bytes -= sample_size(&dropped);
+            }
+            return;
+        }
+
+        let newer = self.samples.split_off(half);
+        let older = std::mem::take(&mut self.samples);
+        for (i, sample) in older.into_iter().enumerate() {
+            if i % 2 == 0 {
+                self.samples.push_back(sample);
+            } else {
+                self.used_bytes -= sample_size(&sample);
+            }
+        }
+        self.samples.extend(newer);
+    }
+
+    /// Take all buffered samples, chunked into batches of at most `max` samples.
+    fn drain_batches(&mut self, max: usize) -> Vec<MetricsBatchPayload> {
+        let samples: Vec<MetricsPayload> = self.samples.drain(..).collect();
+        self.used_bytes = 0;
+        samples
+            .chunks(max.max(1))
+            .map(|chunk| MetricsBatchPayload {
+                samples: chunk.to_vec(),
+            })
+            .collect()
+    }
+}
+
+/// Approximate heap + inline footprint of a sample.
+fn sample_size(sample: &MetricsPayload) -> usize {
+    std::mem::size_of::<MetricsPayload>()
+        + sample
+            .interfaces
+            .keys()
+            .map(|name| {
+                name.capacity()
+                    + std::mem::size_of::<String>()
+                    + std::mem::size_of::<InterfaceRates>()
+            })
+            .sum::<usize>()
+        + sample.firewall_rules.capacity() * std::mem::size_of::<RuleCounters>()
+        + sample
+            .countries
+            .iter()
+            .map(|c| c.country.capacity() + std::mem::size_of::<CountryCounters>())
+            .sum::<usize>()
+        + sample.threat_feeds.capacity() * std::mem::size_of::<FeedCounters>()
+}
+
 // ---------------------------------------------------------------------------
 // CPU
 // ---------------------------------------------------------------------------
@@ -395,18 +600,6 @@ async fn read_dns() -> DnsMetrics {
     }
 }
 
-// ---------------------------------------------------------------------------
-// Helpers
-// ---------------------------------------------------------------------------
-
-/// Current Unix timestamp in seconds (no chrono dependency needed).
-fn chrono_timestamp() -> i64 {
-    std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .map(|d| d.as_secs() as i64)
-        .unwrap_or(0)
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -426,15 +619,78 @@ mod tests {
         assert_eq!(parse_meminfo_kb("  8192"), Some(8192));
     }
 
-    #[test]
-    fn chrono_timestamp_reasonable() {
-        let ts = chrono_timestamp();
-        // Must be after 2023-11-14 (~1700000000)
-        assert!(
-            ts > 1_700_000_000,
-            "timestamp {} should be > 1700000000",
-            ts
+    fn sample(timestamp: i64) -> MetricsPayload {
+        let mut interfaces = HashMap::new();
+        interfaces.insert(
+            "br0".to_string(),
+            InterfaceRates {
+                rx_rate: 1,
+                tx_rate: 2,
+            },
         );
+        MetricsPayload {
+            timestamp,
+            cpu: 0.0,
+            memory: 0.0,
+            temperature: None,
+            interfaces,
+            connections: ConnectionCounts {
+                total: 0,
+                tcp: 0,
+                udp: 0,
+            },
+            dns: DnsMetrics {
+                queries: 0,
+                blocked: 0,
+                cached: 0,
+            },
+            firewall_rules: Vec::new(),
+            countries: Vec::new(),
+            threat_feeds: Vec::new(),
+            upnp_leases: None,
+        }
+    }
+
+    #[test]
+    fn history_stays_within_budget() {
+        let budget = sample_size(&sample(0)) * 50;
+        let mut history = MetricsHistory::new(budget);
+        for ts in 0..1_000 {
+            history.push(sample(ts));
+            assert!(history.used_bytes <= budget);
+        }
+        assert!(history.len() <= 50);
+    }
+
+    #[test]
+    fn history_downsampling_keeps_endpoints_and_order() {
+        let budget = sample_size(&sample(0)) * 50;
+        let mut history = MetricsHistory::new(budget);
+        for ts in 0..1_000 {
+            history.push(sample(ts));
+        }
+
+        let timestamps: Vec<i64> = history.samples.iter().map(|s| s.timestamp).collect();
+        assert_eq!(timestamps.first(), Some(&0));
+        assert_eq!(timestamps.last(), Some(&999));
+        assert!(timestamps.windows(2).all(|w| w[0] < w[1]));
+        // The newest samples keep full resolution
+        assert_eq!(timestamps[timestamps.len() - 2], 998);
+    }
+
+    #[test]
+    fn history_drains_into_chunked_batches() {
+        let mut history = MetricsHistory::new(usize::MAX);
+        for ts in 0..250 {
+            history.push(sample(ts));
+        }
+
+        let batches = history.drain_batches(MAX_BATCH_SAMPLES);
+        assert_eq!(batches.len(), 3);
+        assert_eq!(batches[0].samples.len(), MAX_BATCH_SAMPLES);
+        assert_eq!(batches[2].samples.last().unwrap().timestamp, 249);
+        assert!(history.is_empty());
+        assert_eq!(history.used_bytes, 0);
     }
 
     #[test]
@@ -1,14 +1,20 @@
 //! WebSocket client with auth handshake, reconnection, and keepalive
 
 use futures_util::{SinkExt, StreamExt};
-use ngfw_protocol::{AuthRequest, MessageType, RpcMessage, StatusPayload};
+use ngfw_protocol::{
+    AuthRequest, AuthResponse, MessageType, PongPayload, RpcMessage, StatusPayload,
+};
 use tokio::sync::{mpsc, watch};
 use tokio::time::{Duration, sleep, timeout};
 use tokio_tungstenite::tungstenite::Message;
 use tracing::{Instrument, debug, error, info, info_span, warn};
 use url::Url;
 
+use crate::clock;
 use crate::config::AgentConfig;
+use crate::outbound::{self, OutboundQueue};
+use crate::schedule;
+use crate::supervisor;
 
 /// Maximum reconnection backoff
 const MAX_BACKOFF: Duration = Duration::from_secs(60);
@@ -17,12 +23,31 @@ const PING_INTERVAL: Duration = Duration::from_secs(30);
 /// Auth handshake timeout
 const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// Link state published by the connection loop for other agent tasks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStatus {
+    /// True between a successful auth handshake and the next disconnect
+    pub authenticated: bool,
+}
+
 /// Main connection loop — connects, authenticates, and routes messages.
 /// Reconnects with exponential backoff on disconnection.
+///
+/// `outbound_rx` is drained continuously into the prioritized `outbound`
+/// queue, so producers never stall while the link is down. The queue is
+/// owned by the caller and shared across supervisor restarts, so messages
+/// still pending when a generation stops are sent by the next one.
+/// Pending alerts and logs are spooled to disk between attempts and
+/// replayed after the next successful handshake.
+///
+/// `link` is set to authenticated after each successful handshake and
+/// cleared whenever the connection drops.
 pub async fn connection_loop(
     config: AgentConfig,
-    mut outbound_rx: mpsc::Receiver<RpcMessage>,
+    outbound: OutboundQueue,
+    outbound_rx: mpsc::Receiver<RpcMessage>,
     inbound_tx: mpsc::Sender<RpcMessage>,
+    link: watch::Sender<LinkStatus>,
     mut shutdown: watch::Receiver<bool>,
 ) {
     let mut backoff = Duration::from_secs(1);
@@ -30,10 +55,13 @@ pub async fn connection_loop(
 
     let span = info_span!("connection", device_id = %config.agent.device_id);
 
+    let pump = tokio::spawn(outbound::forward(outbound_rx, outbound.clone()));
+
     async {
         loop {
             if *shutdown.borrow() {
                 info!("Connection loop shutting down");
+                spool_outbound(&outbound).await;
                 return;
             }
 
@@ -53,15 +81,20 @@ pub async fn connection_loop(
                 "Connecting to {}", config.agent.websocket_url
             );
 
-            match connect_and_run(
+            let result = connect_and_run(
                 &ws_url,
                 &config,
-                &mut outbound_rx,
+                &outbound,
                 &inbound_tx,
+                &link,
                 &mut shutdown,
             )
-            .await
-            {
+            .await;
+            link.send_replace(LinkStatus {
+                authenticated: false,
+            });
+
+            match result {
                 Ok(()) => {
                     info!("Connection closed cleanly");
                     backoff = Duration::from_secs(1); // Reset on clean close
@@ -72,6 +105,8 @@ pub async fn connection_loop(
                 }
             }
 
+            spool_outbound(&outbound).await;
+
             if *shutdown.borrow() {
                 return;
             }
@@ -79,6 +114,7 @@ pub async fn connection_loop(
             info!(
                 attempt = attempt,
                 delay_ms = backoff.as_millis() as u64,
+                pending = outbound.len(),
                 "Reconnecting"
             );
             tokio::select! {
@@ -92,13 +128,16 @@ pub async fn connection_loop(
     }
     .instrument(span)
     .await;
+
+    pump.abort();
 }
 
 async fn connect_and_run(
     ws_url: &str,
     config: &AgentConfig,
-    outbound_rx: &mut mpsc::Receiver<RpcMessage>,
+    outbound: &OutboundQueue,
     inbound_tx: &mpsc::Sender<RpcMessage>,
+    link: &watch::Sender<LinkStatus>,
     shutdown: &mut watch::Receiver<bool>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     // Validate URL format, then pass the string to connect_async
@@ -121,6 +160,7 @@ async fn connect_and_run(
         })?,
     );
     let auth_json = serde_json::to_string(&auth_msg)?;
+    let auth_sent_ms = clock::local_now_ms();
     ws_tx.send(Message::Text(auth_json.into())).await?;
 
     // Wait for AUTH_OK / AUTH_FAIL with timeout
@@ -130,7 +170,7 @@ async fn connect_and_run(
                 Ok(Message::Text(text)) => {
                     if let Ok(rpc) = serde_json::from_str::<RpcMessage>(&text) {
                         match rpc.msg_type {
-                            MessageType::AuthOk => return Ok(()),
+                            MessageType::AuthOk => return Ok(rpc.payload),
                             MessageType::AuthFail => {
                                 let err = rpc
                                     .payload
@@ -155,9 +195,16 @@ async fn connect_and_run(
     .await;
 
     match auth_result {
-        Ok(Ok(())) => {
+        Ok(Ok(payload)) => {
             debug!(device_id = %config.agent.device_id, "Auth succeeded");
             info!("Authenticated successfully");
+            match serde_json::from_value::<AuthResponse>(payload) {
+                Ok(AuthResponse {
+                    server_time: Some(server_time),
+                    ..
+                }) => sync_clock(server_time, auth_sent_ms, outbound),
+                _ => warn!("AUTH_OK carried no server time, clock offset not updated"),
+            }
         }
         Ok(Err(ref e)) => {
             debug!(device_id = %config.agent.device_id, error = %e, "Auth failed");
@@ -189,15 +236,30 @@ async fn connect_and_run(
             connections,
             wan_ip: None,
             firmware,
+            clock_offset: clock::offset(),
+            tasks: supervisor::health(),
+            schedules: schedule::states(),
         })?,
     );
     let status_json = serde_json::to_string(&status_msg)?;
     ws_tx.send(Message::Text(status_json.into())).await?;
 
+    // Replay events spooled while we were offline, ahead of newer traffic
+    if let Err(e) = outbound.replay_spool().await {
+        warn!("Failed to replay outbound spool: {}", e);
+    }
+
+    // Let the collector backfill samples buffered during the outage
+    link.send_replace(LinkStatus {
+        authenticated: true,
+    });
+
     info!("Entering message loop");
 
     let mut ping_interval = tokio::time::interval(PING_INTERVAL);
     ping_interval.tick().await; // Skip first immediate tick
+    // Outstanding keepalive (message id, local send time in ms)
+    let mut pending_ping: Option<(String, i64)> = None;
 
     // Main message loop
     loop {
@@ -207,6 +269,9 @@ async fn connect_and_run(
                 match msg {
                     Some(Ok(Message::Text(text))) => {
                         match serde_json::from_str::<RpcMessage>(&text) {
+                            Ok(rpc) if rpc.msg_type == MessageType::Pong => {
+                                handle_pong(&rpc, &mut pending_ping, outbound);
+                            }
                             Ok(rpc) => {
                                 if inbound_tx.send(rpc).await.is_err() {
                                     warn!("Dispatcher channel closed");
@@ -235,12 +300,16 @@ async fn connect_and_run(
                 }
             }
 
-            // Outbound message from agent internals (metrics, logs, etc.)
-            msg = outbound_rx.recv() => {
+            // Outbound message from agent internals, highest priority first
+            msg = outbound.recv() => {
                 match msg {
                     Some(rpc) => {
                         let json = serde_json::to_string(&rpc)?;
-                        ws_tx.send(Message::Text(json.into())).await?;
+                        if let Err(e) = ws_tx.send(Message::Text(json.into())).await {
+                            // Keep the message for the next connection
+                            outbound.requeue(rpc);
+                            return Err(e.into());
+                        }
                     }
                     None => {
                         info!("Outbound channel closed");
@@ -256,6 +325,7 @@ async fn connect_and_run(
                     serde_json::json!({}),
                 );
                 let json = serde_json::to_string(&ping_msg)?;
+                pending_ping = Some((ping_msg.id, clock::local_now_ms()));
                 ws_tx.send(Message::Text(json.into())).await?;
             }
 
@@ -271,6 +341,55 @@ async fn connect_and_run(
     }
 }
 
+/// Refresh the clock offset from the server time in a reply to our keepalive
+fn handle_pong(
+    rpc: &RpcMessage,
+    pending_ping: &mut Option<(String, i64)>,
+    outbound: &OutboundQueue,
+) {
+    // Only a reply to the outstanding ping has a known round trip
+    let sent_ms = match pending_ping.take() {
+        Some((id, sent_ms)) if id == rpc.id => sent_ms,
+        other => {
+            *pending_ping = other;
+            debug!("Ignoring unsolicited Pong");
+            return;
+        }
+    };
+
+    if let Ok(PongPayload {
+        server_time: Some(server_time),
+    }) = serde_json::from_value(rpc.payload.clone())
+    {
+        sync_clock(server_time, sent_ms, outbound);
+    }
+}
+
+/// Update the clock offset from a server timestamp, raising a warning alert
+/// when the skew first crosses [`clock::SKEW_WARN_SECS`].
+fn sync_clock(server_time: i64, sent_ms: i64, outbound: &OutboundQueue) {
+    let offset = clock::estimate_offset(server_time, sent_ms, clock::local_now_ms());
+    let previous = clock::set_offset(offset);
+    if previous != Some(offset) {
+        debug!(offset = offset, "Clock offset updated");
+    }
+
+    if clock::exceeds_threshold(offset) && !previous.is_some_and(clock::exceeds_threshold) {
+        warn!(
+            offset = offset,
+            "Local clock is skewed from server time; correcting outbound timestamps"
+        );
+        outbound.push(clock::skew_alert(offset));
+    }
+}
+
+/// Move pending alerts and logs to the on-disk spool while disconnected
+async fn spool_outbound(outbound: &OutboundQueue) {
+    if let Err(e) = outbound.spool().await {
+        warn!("Failed to spool outbound events: {}", e);
+    }
+}
+
 // ---------------------------------------------------------------------------
 // System metric helpers
 // ---------------------------------------------------------------------------
@@ -9,12 +9,18 @@ use std::time::Instant;
 use ngfw_protocol::{
     AgentMode, ConfigAck, ConfigPush, ConfigSection, ExecCommand, ExecResult, MessageType,
     ModeAckPayload, ModeConfig, ModeUpdatePayload, RpcMessage, StatusPayload, UpgradeCommand,
+    UpnpRevoke,
 };
 use tokio::sync::{mpsc, watch};
 use tracing::{debug, error, info, warn};
 
+use crate::adapters::{IptablesAdapter, NatAdapter, SubsystemAdapter};
+use crate::clock;
 use crate::config::AgentConfig;
 use crate::mode;
+use crate::rollback;
+use crate::schedule;
+use crate::supervisor;
 
 /// Commands the agent is permitted to execute, even in takeover mode.
 /// Any command not in this list is rejected outright.
@@ -40,6 +46,11 @@ const ALLOWED_COMMANDS: &[&str] = &[
     "nslookup",
 ];
 
+/// Adapters shared by config pushes and hook re-applies for the life of the
+/// agent, so a failed apply can roll back to the rules it replaced
+static FIREWALL: IptablesAdapter = IptablesAdapter::new();
+static NAT: NatAdapter = NatAdapter::new();
+
 /// Read-only diagnostic commands allowed in shadow mode
 const DIAGNOSTIC_COMMANDS: &[&str] = &[
     "cat",
@@ -115,6 +126,9 @@ pub async fn dispatcher_loop(
                     MessageType::ModeUpdate => {
                         handle_mode_update(&msg, &mode_tx).await
                     }
+                    MessageType::UpnpRevoke => {
+                        handle_upnp_revoke(&config, &msg, &current_mode).await
+                    }
                     other => {
                         debug!(msg_type = ?other, "Ignoring unhandled message type");
                         None
@@ -430,6 +444,60 @@ async fn handle_reboot(
     Some(ack)
 }
 
+/// Handle UpnpRevoke — remove a UPnP port mapping from the router
+async fn handle_upnp_revoke(
+    config: &AgentConfig,
+    msg: &RpcMessage,
+    mode_config: &ModeConfig,
+) -> Option<RpcMessage> {
+    let error = |error: String| {
+        Some(RpcMessage::with_id(
+            msg.id.clone(),
+            MessageType::Error,
+            serde_json::json!({ "error": error }),
+        ))
+    };
+
+    let mode = mode_config.effective_mode(&ConfigSection::Nat);
+    if mode != &AgentMode::Takeover {
+        warn!(mode = ?mode, "UPnP revoke denied — requires takeover mode");
+        return error(format!(
+            "Revoking UPnP leases requires takeover mode (current: {:?})",
+            mode
+        ));
+    }
+    if !config.adapters.iptables {
+        return error("The iptables adapter is disabled".to_string());
+    }
+    let revoke: UpnpRevoke = match serde_json::from_value(msg.payload.clone()) {
+        Ok(revoke) => revoke,
+        Err(e) => {
+            warn!(id = %msg.id, "Invalid UpnpRevoke payload: {}", e);
+            return error(e.to_string());
+        }
+    };
+
+    info!(
+        protocol = %revoke.protocol,
+        external_port = revoke.external_port,
+        "Revoking UPnP lease"
+    );
+    match NAT
+        .revoke_upnp_lease(&revoke.protocol, revoke.external_port)
+        .await
+    {
+        Ok(()) => Some(RpcMessage::with_id(
+            msg.id.clone(),
+            MessageType::StatusOk,
+            serde_json::json!({ "action": "upnp_revoke", "status": "revoked" }),
+        )),
+        Err(e) => {
+            warn!("Failed to revoke UPnP lease: {}", e);
+            error(e.to_string())
+        }
+    }
+}
+
 /// Handle Upgrade — download firmware, verify checksum, replace binary, restart
 async fn handle_upgrade(msg: &RpcMessage, mode_config: &ModeConfig) -> Option<RpcMessage> {
     if mode_config.mode != AgentMode::Takeover {
@@ -736,25 +804,20 @@ fn validate_config(_config: &AgentConfig, push: &ConfigPush) -> Result<(), Strin
 
     // Section-specific structural validation
     match push.section {
-        ConfigSection::Firewall => {
-            if !push.config.is_object() {
-                return Err("Firewall config must be an object".to_string());
-            }
+        ConfigSection::Firewall if !push.config.is_object() => {
+            return Err("Firewall config must be an object".to_string());
         }
-        ConfigSection::Wan | ConfigSection::Lan => {
-            if !push.config.is_object() {
-                return Err(format!("{:?} config must be an object", push.section));
-            }
+        ConfigSection::Nat if !push.config.is_object() => {
+            return Err("NAT config must be an object".to_string());
         }
-        ConfigSection::Dns => {
-            if !push.config.is_object() {
-                return Err("DNS config must be an object".to_string());
-            }
+        ConfigSection::Wan | ConfigSection::Lan if !push.config.is_object() => {
+            return Err(format!("{:?} config must be an object", push.section));
         }
-        ConfigSection::Full => {
-            if !push.config.is_object() {
-                return Err("Full config must be an object".to_string());
-            }
+        ConfigSection::Dns if !push.config.is_object() => {
+            return Err("DNS config must be an object".to_string());
+        }
+        ConfigSection::Full if !push.config.is_object() => {
+            return Err("Full config must be an object".to_string());
         }
         _ => {
             // Accept any valid JSON for other sections
@@ -765,24 +828,77 @@ fn validate_config(_config: &AgentConfig, push: &ConfigPush) -> Result<(), Strin
 }
 
 /// Apply a config push to the router (takeover mode)
-async fn apply_config(_config: &AgentConfig, push: &ConfigPush) -> Result<(), String> {
+async fn apply_config(config: &AgentConfig, push: &ConfigPush) -> Result<(), String> {
     // Validate first
-    validate_config(_config, push)?;
+    validate_config(config, push)?;
 
-    // TODO: Delegate to section-specific adapters (iptables, dnsmasq, nvram, etc.)
-    // For now, log and acknowledge. The adapter layer will be implemented in
-    // packages/agent/src/adapters/ with per-section modules.
-    info!(
-        section = ?push.section,
-        version = push.version,
-        "Config apply delegated to adapter (stub)"
-    );
+    match push.section {
+        ConfigSection::Firewall if config.adapters.iptables => apply_with(&FIREWALL, push).await,
+        ConfigSection::Nat if config.adapters.iptables => apply_with(&NAT, push).await,
+        _ => {
+            // TODO: Delegate remaining sections to their adapters (dnsmasq, nvram, etc.)
+            info!(
+                section = ?push.section,
+                version = push.version,
+                "Config apply delegated to adapter (stub)"
+            );
+            Ok(())
+        }
+    }
+}
 
+/// Validate and apply a config push through a subsystem adapter, rolling
+/// back to the rules it replaced if the apply fails part way
+async fn apply_with(adapter: &dyn SubsystemAdapter, push: &ConfigPush) -> Result<(), String> {
+    let issues = adapter
+        .validate(&push.config)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !issues.is_empty() {
+        let messages: Vec<String> = issues
+            .iter()
+            .map(|i| format!("{}: {}", i.field, i.message))
+            .collect();
+        return Err(messages.join("; "));
+    }
+    if let Err(e) = adapter.apply(&push.config, push.version).await {
+        match adapter.rollback().await {
+            Ok(()) => info!(section = ?push.section, "rolled back after failed apply"),
+            Err(rollback_err) => warn!(
+                section = ?push.section,
+                "no rollback after failed apply: {}", rollback_err
+            ),
+        }
+        return Err(e.to_string());
+    }
+    if let Err(e) = rollback::save_applied(&push.section, &push.config).await {
+        warn!("failed to persist applied {:?} config: {}", push.section, e);
+    }
+    if let Err(e) = rollback::update_version(&push.section, push.version).await {
+        warn!("failed to persist {:?} config version: {}", push.section, e);
+    }
     Ok(())
 }
 
+/// Apply the last good config for `section` again, e.g. after the firmware
+/// rebuilt what its adapter installed.
+///
+/// Returns the re-applied version, or `None` if nothing was ever applied.
+pub async fn reapply(config: &AgentConfig, section: &ConfigSection) -> Result<Option<u64>, String> {
+    let Some(applied) = rollback::load_applied(section).await else {
+        return Ok(None);
+    };
+    let push = ConfigPush {
+        section: section.clone(),
+        config: applied,
+        version: rollback::get_version(section).await.unwrap_or(0),
+    };
+    apply_config(config, &push).await?;
+    Ok(Some(push.version))
+}
+
 /// Collect current system status from procfs and system commands
-async fn collect_status(config: &AgentConfig) -> StatusPayload {
+pub async fn collect_status(config: &AgentConfig) -> StatusPayload {
     let uptime = read_uptime().await;
     let (cpu, memory) = read_cpu_memory().await;
     let temperature = read_temperature().await;
@@ -803,6 +919,9 @@ async fn collect_status(config: &AgentConfig) -> StatusPayload {
             .as_deref()
             .map(|_| "unknown".to_string())
             .unwrap_or_else(|| "unknown".to_string()),
+        clock_offset: clock::offset(),
+        tasks: supervisor::health(),
+        schedules: schedule::states(),
     }
 }
 
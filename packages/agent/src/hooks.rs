@@ -0,0 +1,475 @@
+//! Re-applying managed config after firmware service restarts
+//!
+//! asuswrt-merlin rebuilds the firewall whenever the WAN reconnects or
+//! settings are saved, wiping out the rules the adapters installed. While
+//! any section is in takeover mode, the agent keeps a managed block in the
+//! firmware's user scripts under `/jffs/scripts` (`firewall-start`,
+//! `nat-start` and `service-event`). The block appends the hook name and its arguments to
+//! a queue file and sends the agent `SIGUSR1`; user content around the
+//! block is left untouched, and the block is removed again when no section
+//! is in takeover mode any more.
+//!
+//! On a signal the agent waits for the router to settle, since
+//! `service-event` runs before the service restarts, then re-applies the
+//! last good config of every affected section that is in takeover mode.
+//! Each re-application is reported as a `config_reapplied` alert.
+
+use std::path::Path;
+use std::time::Duration;
+
+use ngfw_protocol::{
+    AgentMode, AlertMessage, AlertSeverity, AlertType, ConfigSection, MessageType, ModeConfig,
+    RpcMessage,
+};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, info, warn};
+
+use crate::clock;
+use crate::config::AgentConfig;
+use crate::dispatcher;
+
+/// Directory merlin runs user scripts from
+const SCRIPTS_DIR: &str = "/jffs/scripts";
+
+/// Hooks that get a managed block
+pub const HOOKS: &[&str] = &["firewall-start", "nat-start", "service-event"];
+
+/// Hook invocations waiting for the agent, one per line
+const QUEUE_FILE: &str = "/tmp/ngfw-agent.hooks";
+
+/// Written by the agent at startup so the hooks can signal it
+pub const PID_FILE: &str = "/tmp/ngfw-agent.pid";
+
+const BEGIN_MARKER: &str = "# BEGIN ngfw-agent managed hook (do not edit)";
+const END_MARKER: &str = "# END ngfw-agent managed hook";
+
+/// Quiet period after the last hook before re-applying
+const SETTLE: Duration = Duration::from_secs(5);
+
+/// The managed block for `hook`
+fn managed_block(hook: &str) -> String {
+    format!(
+        "{BEGIN_MARKER}\n\
+         echo \"{hook} $*\" >> {QUEUE_FILE}\n\
+         [ -f {PID_FILE} ] && kill -USR1 \"$(cat {PID_FILE})\" 2>/dev/null\n\
+         {END_MARKER}\n"
+    )
+}
+
+/// `script` without the managed block
+pub fn without_hook(script: &str) -> String {
+    let mut inside = false;
+    let mut kept = String::new();
+    for line in script.lines() {
+        if line == BEGIN_MARKER {
+            inside = true;
+        } else if line == END_MARKER && inside {
+            inside = false;
+        } else if !inside {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+    kept
+}
+
+/// `script` with the managed block for `hook` right after the shebang,
+/// so it runs even if the user's part exits early
+pub fn with_hook(script: &str, hook: &str) -> String {
+    let script = without_hook(script);
+    let block = managed_block(hook);
+    if script.trim().is_empty() {
+        return format!("#!/bin/sh\n{block}");
+    }
+    match script.split_once('\n') {
+        Some((shebang, rest)) if shebang.starts_with("#!") => format!("{shebang}\n{block}{rest}"),
+        _ => format!("{block}{script}"),
+    }
+}
+
+/// Whether nothing but a shebang is left of `script`
+fn is_empty_script(script: &str) -> bool {
+    script
+        .lines()
+        .all(|line| line.trim().is_empty() || line.starts_with("#!"))
+}
+
+/// Add or remove the managed block in every hook script
+async fn sync_hooks(enabled: bool) {
+    if !Path::new(SCRIPTS_DIR).is_dir() {
+        if enabled {
+            warn!(
+                "{} does not exist; enable JFFS custom scripts to re-apply config after service restarts",
+                SCRIPTS_DIR
+            );
+        }
+        return;
+    }
+
+    for hook in HOOKS {
+        let path = Path::new(SCRIPTS_DIR).join(hook);
+        let current = match tokio::fs::read_to_string(&path).await {
+            Ok(script) => Some(script),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                warn!("failed to read {}: {}", path.display(), e);
+                continue;
+            }
+        };
+
+        let result = if enabled {
+            let updated = with_hook(current.as_deref().unwrap_or(""), hook);
+            if current.as_deref() == Some(updated.as_str()) {
+                continue;
+            }
+            install(&path, &updated).await
+        } else {
+            let Some(current) = current else {
+                continue;
+            };
+            let updated = without_hook(&current);
+            if updated == current {
+                continue;
+            }
+            if is_empty_script(&updated) {
+                tokio::fs::remove_file(&path).await
+            } else {
+                tokio::fs::write(&path, updated).await
+            }
+        };
+        match result {
+            Ok(()) if enabled => info!("installed {} hook", hook),
+            Ok(()) => info!("removed {} hook", hook),
+            Err(e) => warn!("failed to update {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Write an executable hook script
+async fn install(path: &Path, script: &str) -> Result<(), std::io::Error> {
+    tokio::fs::write(path, script).await?;
+    let status = tokio::process::Command::new("chmod")
+        .arg("755")
+        .arg(path)
+        .status()
+        .await?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "chmod exited with {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Sections whose config a hook invocation (`<hook> [args...]`, one queue
+/// line) may have wiped
+pub fn sections_for(line: &str) -> Vec<ConfigSection> {
+    let mut words = line.split_whitespace();
+    match words.next() {
+        Some("firewall-start") => vec![ConfigSection::Firewall],
+        Some("nat-start") => vec![ConfigSection::Nat],
+        Some("service-event") => {
+            let (Some("start" | "restart"), Some(service)) = (words.next(), words.next()) else {
+                return Vec::new();
+            };
+            match service {
+                "firewall" => vec![ConfigSection::Firewall, ConfigSection::Nat],
+                _ => Vec::new(),
+            }
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Affected sections with the hooks that hit each, in first-seen order
+fn pending_sections(lines: &[String]) -> Vec<(ConfigSection, Vec<String>)> {
+    let mut pending: Vec<(ConfigSection, Vec<String>)> = Vec::new();
+    for line in lines {
+        let Some(hook) = line.split_whitespace().next() else {
+            continue;
+        };
+        for section in sections_for(line) {
+            let index = match pending.iter().position(|(s, _)| *s == section) {
+                Some(index) => index,
+                None => {
+                    pending.push((section, Vec::new()));
+                    pending.len() - 1
+                }
+            };
+            let hooks = &mut pending[index].1;
+            if !hooks.iter().any(|h| h == hook) {
+                hooks.push(hook.to_string());
+            }
+        }
+    }
+    pending
+}
+
+/// Take the queued hook invocations, leaving the queue empty
+async fn take_queue() -> Vec<String> {
+    let taken = format!("{}.taken", QUEUE_FILE);
+    if let Err(e) = tokio::fs::rename(QUEUE_FILE, &taken).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("failed to take hook queue: {}", e);
+        }
+        return Vec::new();
+    }
+    let lines = match tokio::fs::read_to_string(&taken).await {
+        Ok(data) => data.lines().map(str::to_string).collect(),
+        Err(e) => {
+            warn!("failed to read hook queue: {}", e);
+            Vec::new()
+        }
+    };
+    let _ = tokio::fs::remove_file(&taken).await;
+    lines
+}
+
+/// Alert reporting a re-application of `section` triggered by `hooks`
+pub fn reapply_alert(
+    section: &ConfigSection,
+    hooks: &[String],
+    result: &Result<u64, String>,
+) -> AlertMessage {
+    let name = serde_json::to_value(section)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_else(|| format!("{:?}", section).to_lowercase());
+    let trigger = hooks.join(", ");
+    let (severity, description, details) = match result {
+        Ok(version) => (
+            AlertSeverity::Low,
+            format!(
+                "Re-applied {} config (version {}) after {}",
+                name, version, trigger
+            ),
+            serde_json::json!({ "section": name, "hooks": hooks, "version": version }),
+        ),
+        Err(e) => (
+            AlertSeverity::High,
+            format!(
+                "Failed to re-apply {} config after {}: {}",
+                name, trigger, e
+            ),
+            serde_json::json!({ "section": name, "hooks": hooks, "error": e }),
+        ),
+    };
+    AlertMessage {
+        timestamp: clock::now(),
+        severity,
+        alert_type: AlertType::ConfigReapplied,
+        source: "agent".to_string(),
+        description,
+        details: Some(details),
+    }
+}
+
+/// Whether any section is in takeover mode
+fn any_takeover(mode: &ModeConfig) -> bool {
+    mode.mode == AgentMode::Takeover
+        || mode
+            .section_overrides
+            .values()
+            .any(|m| *m == AgentMode::Takeover)
+}
+
+/// Re-apply the sections hit by queued hooks; `false` once the outbound
+/// channel is closed
+async fn process_queue(
+    config: &AgentConfig,
+    outbound_tx: &mpsc::Sender<RpcMessage>,
+    mode_rx: &watch::Receiver<ModeConfig>,
+) -> bool {
+    for (section, hooks) in pending_sections(&take_queue().await) {
+        if mode_rx.borrow().effective_mode(&section) != &AgentMode::Takeover {
+            debug!(section = ?section, "not in takeover mode, ignoring hook");
+            continue;
+        }
+        let result = match dispatcher::reapply(config, &section).await {
+            Ok(None) => continue,
+            Ok(Some(version)) => {
+                info!(section = ?section, version, hooks = ?hooks, "Re-applied config");
+                Ok(version)
+            }
+            Err(e) => {
+                warn!(section = ?section, hooks = ?hooks, "Failed to re-apply config: {}", e);
+                Err(e)
+            }
+        };
+        let alert = reapply_alert(&section, &hooks, &result);
+        match serde_json::to_value(&alert) {
+            Ok(payload) => {
+                let msg = RpcMessage::new(MessageType::Alert, payload);
+                if outbound_tx.send(msg).await.is_err() {
+                    return false;
+                }
+            }
+            Err(e) => warn!("failed to serialize alert: {}", e),
+        }
+    }
+    true
+}
+
+/// Keep the hooks installed and re-apply config when they fire. Runs until
+/// shutdown fires.
+pub async fn hook_loop(
+    config: AgentConfig,
+    outbound_tx: mpsc::Sender<RpcMessage>,
+    mut mode_rx: watch::Receiver<ModeConfig>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!("failed to listen for hook signals: {}", e);
+            let _ = shutdown.wait_for(|stop| *stop).await;
+            return;
+        }
+    };
+
+    let mut enabled = any_takeover(&mode_rx.borrow_and_update());
+    sync_hooks(enabled).await;
+    debug!("hook listener started");
+
+    // Hooks that fired while the agent was down
+    if enabled && !process_queue(&config, &outbound_tx, &mode_rx).await {
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => {
+                if *shutdown.borrow() {
+                    break;
+                }
+            }
+            changed = mode_rx.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                let takeover = any_takeover(&mode_rx.borrow_and_update());
+                if takeover != enabled {
+                    enabled = takeover;
+                    sync_hooks(enabled).await;
+                }
+            }
+            _ = signals.recv() => {
+                // Let the firmware finish restarting, coalescing hooks
+                // that fire meanwhile
+                loop {
+                    tokio::select! {
+                        _ = signals.recv() => {}
+                        _ = tokio::time::sleep(SETTLE) => break,
+                    }
+                }
+                if !process_queue(&config, &outbound_tx, &mode_rx).await {
+                    warn!("outbound channel closed, stopping hook listener");
+                    break;
+                }
+            }
+        }
+    }
+
+    debug!("hook listener stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hook_block_goes_after_the_shebang_and_replaces_itself() {
+        let fresh = with_hook("", "nat-start");
+        assert!(fresh.starts_with("#!/bin/sh\n# BEGIN ngfw-agent"));
+        assert!(fresh.contains("echo \"nat-start $*\" >> /tmp/ngfw-agent.hooks\n"));
+        assert_eq!(with_hook(&fresh, "nat-start"), fresh);
+
+        let user = "#!/bin/sh\niptables -I INPUT -p tcp --dport 22 -j ACCEPT\nexit 0\n";
+        let hooked = with_hook(user, "firewall-start");
+        let lines: Vec<&str> = hooked.lines().collect();
+        assert_eq!(lines[0], "#!/bin/sh");
+        assert_eq!(lines[1], BEGIN_MARKER);
+        assert_eq!(lines[4], END_MARKER);
+        assert_eq!(
+            lines[5..],
+            ["iptables -I INPUT -p tcp --dport 22 -j ACCEPT", "exit 0"]
+        );
+        assert_eq!(with_hook(&hooked, "firewall-start"), hooked);
+    }
+
+    #[test]
+    fn removing_the_hook_restores_the_user_script() {
+        let user = "#!/bin/sh\nlogger firewall restarted\n";
+        assert_eq!(without_hook(&with_hook(user, "firewall-start")), user);
+
+        let only_ours = without_hook(&with_hook("", "service-event"));
+        assert!(is_empty_script(&only_ours));
+        assert!(!is_empty_script(user));
+    }
+
+    #[test]
+    fn hooks_map_to_the_sections_they_wipe() {
+        assert_eq!(
+            sections_for("firewall-start eth0"),
+            [ConfigSection::Firewall]
+        );
+        assert_eq!(sections_for("nat-start "), [ConfigSection::Nat]);
+        assert_eq!(
+            sections_for("service-event restart firewall"),
+            [ConfigSection::Firewall, ConfigSection::Nat]
+        );
+        assert!(sections_for("service-event stop firewall").is_empty());
+        assert!(sections_for("service-event restart dnsmasq").is_empty());
+        assert!(sections_for("service-event restart httpd").is_empty());
+        assert!(sections_for("services-start").is_empty());
+    }
+
+    #[test]
+    fn queued_hooks_are_grouped_by_section() {
+        let lines: Vec<String> = [
+            "service-event restart firewall",
+            "firewall-start eth0",
+            "nat-start ",
+            "firewall-start eth0",
+        ]
+        .iter()
+        .map(|l| l.to_string())
+        .collect();
+        assert_eq!(
+            pending_sections(&lines),
+            [
+                (
+                    ConfigSection::Firewall,
+                    vec!["service-event".to_string(), "firewall-start".to_string()]
+                ),
+                (
+                    ConfigSection::Nat,
+                    vec!["service-event".to_string(), "nat-start".to_string()]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn reapplications_are_reported_as_alerts() {
+        let hooks = vec!["firewall-start".to_string()];
+        let ok = reapply_alert(&ConfigSection::Firewall, &hooks, &Ok(12));
+        assert_eq!(ok.alert_type, AlertType::ConfigReapplied);
+        assert_eq!(ok.severity, AlertSeverity::Low);
+        assert_eq!(
+            ok.description,
+            "Re-applied firewall config (version 12) after firewall-start"
+        );
+        assert_eq!(ok.details.unwrap()["version"], 12);
+
+        let failed = reapply_alert(
+            &ConfigSection::Nat,
+            &hooks,
+            &Err("iptables-restore failed".to_string()),
+        );
+        assert_eq!(failed.severity, AlertSeverity::High);
+        assert_eq!(failed.details.unwrap()["error"], "iptables-restore failed");
+    }
+}
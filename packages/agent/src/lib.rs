@@ -1,9 +1,15 @@
-//! NGFW.sh Router Agent — library crate exposing modules for integration tests.
+//! NGFW.sh Router Agent — library crate shared by the daemon binary and integration tests.
 
 pub mod adapters;
+pub mod clock;
 pub mod collector;
 pub mod config;
 pub mod connection;
 pub mod dispatcher;
+pub mod hooks;
 pub mod mode;
+pub mod outbound;
 pub mod rollback;
+pub mod schedule;
+pub mod supervisor;
+pub mod timezone;
@@ -4,13 +4,9 @@
 //! via WebSocket, receives configuration and commands, reports telemetry,
 //! and manages router subsystems.
 
-mod collector;
-mod config;
-mod connection;
-mod dispatcher;
-mod mode;
-
-use config::AgentConfig;
+use ngfw_agent::config::AgentConfig;
+use ngfw_agent::{collector, connection, dispatcher, hooks, mode, outbound, schedule, supervisor};
+use ngfw_protocol::ModeConfig;
 use tracing::{error, info};
 
 #[tokio::main]
@@ -29,7 +25,6 @@ async fn main() {
         .map(|s| s.as_str())
         .unwrap_or("/jffs/ngfw/config.toml");
 
-    let daemon_mode = args.iter().any(|a| a == "--daemon");
     let check_mode = args.iter().any(|a| a == "--check");
 
     // Load configuration
@@ -74,12 +69,10 @@ async fn main() {
         config.agent.device_id
     );
 
-    // Write PID file
-    if daemon_mode {
-        let pid = std::process::id();
-        if let Err(e) = tokio::fs::write("/tmp/ngfw-agent.pid", pid.to_string()).await {
-            error!("Failed to write PID file: {}", e);
-        }
+    // Write PID file, which the firmware hooks signal
+    let pid = std::process::id();
+    if let Err(e) = tokio::fs::write(hooks::PID_FILE, pid.to_string()).await {
+        error!("Failed to write PID file: {}", e);
     }
 
     // Run the agent
@@ -90,51 +83,24 @@ async fn main() {
 }
 
 async fn run(config: AgentConfig) -> Result<(), Box<dyn std::error::Error>> {
-    let (outbound_tx, outbound_rx) = tokio::sync::mpsc::channel(256);
-    let (inbound_tx, inbound_rx) = tokio::sync::mpsc::channel(256);
     let (mode_tx, mode_rx) = tokio::sync::watch::channel(mode::load_persisted_mode().await);
-    let shutdown = tokio::sync::watch::channel(false);
-
-    // Spawn connection loop
-    let conn_config = config.clone();
-    let conn_shutdown = shutdown.1.clone();
-    let conn_handle = tokio::spawn(connection::connection_loop(
-        conn_config,
-        outbound_rx,
-        inbound_tx,
-        conn_shutdown,
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    // Shared by every generation so a restart keeps unsent messages
+    let outbound = outbound::OutboundQueue::new(outbound::SPOOL_DIR);
+
+    // Supervise connection, dispatcher, collector, scheduler and hook
+    // listener; each generation gets fresh channels and its own stop signal
+    let mut supervisor = tokio::spawn(supervisor::supervise(
+        supervisor::RestartPolicy::default(),
+        move |tasks, stop| spawn_agent_tasks(&config, &outbound, &mode_tx, &mode_rx, tasks, stop),
+        shutdown_rx,
     ));
 
-    // Spawn dispatcher
-    let disp_config = config.clone();
-    let disp_outbound = outbound_tx.clone();
-    let disp_mode_tx = mode_tx.clone();
-    let disp_mode_rx = mode_rx.clone();
-    let disp_shutdown = shutdown.1.clone();
-    let disp_handle = tokio::spawn(dispatcher::dispatcher_loop(
-        disp_config,
-        inbound_rx,
-        disp_outbound,
-        disp_mode_tx,
-        disp_mode_rx,
-        disp_shutdown,
-    ));
-
-    // Spawn metrics collector
-    let coll_config = config.clone();
-    let coll_outbound = outbound_tx.clone();
-    let coll_shutdown = shutdown.1.clone();
-    let coll_handle = tokio::spawn(collector::metrics_loop(
-        coll_config,
-        coll_outbound,
-        coll_shutdown,
-    ));
-
-    // Wait for shutdown signal
-    let shutdown_tx = shutdown.0;
-    tokio::select! {
+    // Wait for a shutdown signal, or for the supervisor to give up
+    let exited = tokio::select! {
         _ = tokio::signal::ctrl_c() => {
             info!("Received SIGINT, shutting down...");
+            None
         }
         _ = async {
             #[cfg(unix)]
@@ -150,18 +116,87 @@ async fn run(config: AgentConfig) -> Result<(), Box<dyn std::error::Error>> {
             }
         } => {
             info!("Received SIGTERM, shutting down...");
+            None
         }
-    }
-
-    // Signal all tasks to stop
-    let _ = shutdown_tx.send(true);
+        result = &mut supervisor => Some(result),
+    };
 
-    // Wait for tasks to finish
-    let _ = tokio::join!(conn_handle, disp_handle, coll_handle);
+    let result = match exited {
+        Some(result) => result,
+        None => {
+            // Signal all tasks to stop and wait for them to finish
+            let _ = shutdown_tx.send(true);
+            supervisor.await
+        }
+    };
 
     // Cleanup PID file
-    let _ = tokio::fs::remove_file("/tmp/ngfw-agent.pid").await;
+    let _ = tokio::fs::remove_file(hooks::PID_FILE).await;
 
+    result??;
     info!("ngfw-agent stopped");
     Ok(())
 }
+
+/// Spawn one generation of agent tasks, wired together by fresh channels
+fn spawn_agent_tasks(
+    config: &AgentConfig,
+    outbound: &outbound::OutboundQueue,
+    mode_tx: &tokio::sync::watch::Sender<ModeConfig>,
+    mode_rx: &tokio::sync::watch::Receiver<ModeConfig>,
+    tasks: &mut supervisor::Tasks,
+    stop: tokio::sync::watch::Receiver<bool>,
+) {
+    let (outbound_tx, outbound_rx) = tokio::sync::mpsc::channel(256);
+    let (inbound_tx, inbound_rx) = tokio::sync::mpsc::channel(256);
+    let (link_tx, link_rx) = tokio::sync::watch::channel(connection::LinkStatus::default());
+
+    tasks.spawn(
+        "connection",
+        connection::connection_loop(
+            config.clone(),
+            outbound.clone(),
+            outbound_rx,
+            inbound_tx,
+            link_tx,
+            stop.clone(),
+        ),
+    );
+
+    tasks.spawn(
+        "dispatcher",
+        dispatcher::dispatcher_loop(
+            config.clone(),
+            inbound_rx,
+            outbound_tx.clone(),
+            mode_tx.clone(),
+            mode_rx.clone(),
+            stop.clone(),
+        ),
+    );
+
+    tasks.spawn(
+        "scheduler",
+        schedule::schedule_loop(
+            config.clone(),
+            outbound_tx.clone(),
+            mode_rx.clone(),
+            stop.clone(),
+        ),
+    );
+
+    tasks.spawn(
+        "hooks",
+        hooks::hook_loop(
+            config.clone(),
+            outbound_tx.clone(),
+            mode_rx.clone(),
+            stop.clone(),
+        ),
+    );
+
+    tasks.spawn(
+        "collector",
+        collector::metrics_loop(config.clone(), outbound_tx, link_rx, stop),
+    );
+}
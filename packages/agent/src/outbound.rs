@@ -0,0 +1,800 @@
+//! Prioritized outbound queue with offline spooling
+//!
+//! Every message bound for the cloud API passes through an `OutboundQueue`,
+//! fed from the shared outbound mpsc channel by [`forward`].
+//! Messages are drained in priority order (acks/results > alerts > logs >
+//! metrics > metrics backfill) so control-plane replies never wait behind
+//! telemetry, and pushing never blocks the producer.
+//!
+//! While the link is down, alerts and logs are spooled to a bounded on-disk
+//! journal under `/jffs/ngfw/spool/`. The spool is replayed ahead of newer
+//! traffic once the connection is re-established. Metrics sampled while
+//! the link is down are buffered by the collector and arrive as backfill
+//! batches; live metrics that back up behind a slow link are coalesced into
+//! summary buckets instead of being dropped.
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use ngfw_protocol::{MessageType, MetricsPayload, RpcMessage};
+use tokio::sync::{Notify, mpsc};
+use tracing::{debug, info, warn};
+
+/// Default location of the offline spool
+pub const SPOOL_DIR: &str = "/jffs/ngfw/spool";
+
+/// Maximum queued control messages (acks, results, status) before the oldest is dropped
+const MAX_CONTROL: usize = 256;
+/// Maximum in-memory alerts or logs before the oldest is dropped
+const MAX_EVENTS: usize = 256;
+/// Maximum pending metrics entries; beyond this, neighbours are coalesced
+const MAX_METRICS: usize = 60;
+/// Maximum pending `MetricsBatch` messages before the oldest is dropped
+const MAX_BACKFILL: usize = 32;
+/// Maximum spooled entries per spool file; the oldest are discarded first
+const SPOOL_MAX_ENTRIES: usize = 1000;
+
+/// Delivery priority of an outbound message, highest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Acks, exec results, status, pongs, and errors
+    Control,
+    Alert,
+    Log,
+    Metrics,
+    /// Historical metrics batches, sent only when nothing else is pending
+    Backfill,
+}
+
+impl Priority {
+    /// Classify a message type into its delivery priority
+    pub fn of(msg_type: &MessageType) -> Self {
+        match msg_type {
+            MessageType::Alert => Priority::Alert,
+            MessageType::Log => Priority::Log,
+            MessageType::Metrics => Priority::Metrics,
+            MessageType::MetricsBatch => Priority::Backfill,
+            _ => Priority::Control,
+        }
+    }
+}
+
+/// A pending metrics bucket; `samples` counts the raw samples merged into it.
+struct MetricsBucket {
+    payload: MetricsPayload,
+    samples: u32,
+}
+
+impl MetricsBucket {
+    /// Merge a later bucket into this one, weighting gauges by sample count.
+    ///
+    /// The merged bucket keeps the earlier timestamp (bucket start). DNS,
+    /// firewall rule, country and threat feed counters are cumulative and
+    /// UPnP leases are a snapshot, so the later values are kept as-is.
+    fn absorb(&mut self, later: MetricsBucket) {
+        let a = self.samples as f32;
+        let b = later.samples as f32;
+        let avg = |x: f32, y: f32| (x * a + y * b) / (a + b);
+        let avg_u64 = |x: u64, y: u64| {
+            ((x as f64 * a as f64 + y as f64 * b as f64) / (a + b) as f64).round() as u64
+        };
+        let avg_u32 = |x: u32, y: u32| avg_u64(x as u64, y as u64) as u32;
+
+        let p = &mut self.payload;
+        let q = later.payload;
+
+        p.cpu = avg(p.cpu, q.cpu);
+        p.memory = avg(p.memory, q.memory);
+        p.temperature = match (p.temperature, q.temperature) {
+            (Some(x), Some(y)) => Some(avg(x, y)),
+            (x, y) => x.or(y),
+        };
+        for (name, rates) in q.interfaces {
+            match p.interfaces.get_mut(&name) {
+                Some(existing) => {
+                    existing.rx_rate = avg_u64(existing.rx_rate, rates.rx_rate);
+                    existing.tx_rate = avg_u64(existing.tx_rate, rates.tx_rate);
+                }
+                None => {
+                    p.interfaces.insert(name, rates);
+                }
+            }
+        }
+        p.connections.total = avg_u32(p.connections.total, q.connections.total);
+        p.connections.tcp = avg_u32(p.connections.tcp, q.connections.tcp);
+        p.connections.udp = avg_u32(p.connections.udp, q.connections.udp);
+        p.dns = q.dns;
+        p.firewall_rules = q.firewall_rules;
+        p.countries = q.countries;
+        p.threat_feeds = q.threat_feeds;
+        p.upnp_leases = q.upnp_leases;
+
+        self.samples += later.samples;
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    control: VecDeque<RpcMessage>,
+    alerts: VecDeque<RpcMessage>,
+    logs: VecDeque<RpcMessage>,
+    metrics: VecDeque<MetricsBucket>,
+    backfill: VecDeque<RpcMessage>,
+    /// The last metrics bucket popped, with its message id, so a failed
+    /// send can requeue it with its sample count
+    popped_metrics: Option<(String, MetricsBucket)>,
+    closed: bool,
+}
+
+impl QueueState {
+    fn len(&self) -> usize {
+        self.control.len()
+            + self.alerts.len()
+            + self.logs.len()
+            + self.metrics.len()
+            + self.backfill.len()
+    }
+
+    /// Coalesce the adjacent pair of buckets with the fewest combined samples
+    /// (oldest first on ties) until the metrics queue is back within bounds.
+    /// Older data therefore ends up in progressively wider buckets.
+    fn coalesce_metrics(&mut self) {
+        while self.metrics.len() > MAX_METRICS {
+            let idx = (0..self.metrics.len() - 1)
+                .min_by_key(|&i| self.metrics[i].samples + self.metrics[i + 1].samples)
+                .unwrap_or(0);
+            if let Some(later) = self.metrics.remove(idx + 1) {
+                self.metrics[idx].absorb(later);
+            }
+        }
+    }
+}
+
+struct Inner {
+    state: Mutex<QueueState>,
+    notify: Notify,
+    spool_dir: PathBuf,
+}
+
+/// Cloneable handle to the shared outbound queue.
+///
+/// Producers call [`push`](Self::push) (usually via [`forward`]); the
+/// connection loop is the single consumer via [`recv`](Self::recv).
+#[derive(Clone)]
+pub struct OutboundQueue {
+    inner: Arc<Inner>,
+}
+
+impl OutboundQueue {
+    /// Create an empty queue that spools to `spool_dir` while offline
+    pub fn new(spool_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(QueueState::default()),
+                notify: Notify::new(),
+                spool_dir: spool_dir.into(),
+            }),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, QueueState> {
+        // A poisoned lock only means a producer panicked mid-push; the
+        // queues themselves are still structurally valid.
+        self.inner
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Enqueue a message. Never blocks; bounded queues drop their oldest
+    /// entry and metrics are coalesced rather than dropped.
+    pub fn push(&self, msg: RpcMessage) {
+        {
+            let mut state = self.state();
+            match Priority::of(&msg.msg_type) {
+                Priority::Control => {
+                    state.control.push_back(msg);
+                    if state.control.len() > MAX_CONTROL {
+                        state.control.pop_front();
+                        warn!("Outbound control queue full, dropped oldest message");
+                    }
+                }
+                Priority::Alert => {
+                    state.alerts.push_back(msg);
+                    if state.alerts.len() > MAX_EVENTS {
+                        state.alerts.pop_front();
+                        warn!("Outbound alert queue full, dropped oldest alert");
+                    }
+                }
+                Priority::Log => {
+                    state.logs.push_back(msg);
+                    if state.logs.len() > MAX_EVENTS {
+                        state.logs.pop_front();
+                        debug!("Outbound log queue full, dropped oldest log");
+                    }
+                }
+                Priority::Metrics => match serde_json::from_value(msg.payload) {
+                    Ok(payload) => {
+                        state.metrics.push_back(MetricsBucket {
+                            payload,
+                            samples: 1,
+                        });
+                        state.coalesce_metrics();
+                    }
+                    Err(e) => {
+                        warn!("Dropping malformed metrics message: {}", e);
+                        return;
+                    }
+                },
+                Priority::Backfill => {
+                    state.backfill.push_back(msg);
+                    if state.backfill.len() > MAX_BACKFILL {
+                        state.backfill.pop_front();
+                        warn!("Outbound backfill queue full, dropped oldest batch");
+                    }
+                }
+            }
+        }
+        self.inner.notify.notify_one();
+    }
+
+    /// Put a message back at the head of its queue, e.g. after a failed send
+    pub fn requeue(&self, msg: RpcMessage) {
+        {
+            let mut state = self.state();
+            match Priority::of(&msg.msg_type) {
+                Priority::Control => state.control.push_front(msg),
+                Priority::Alert => state.alerts.push_front(msg),
+                Priority::Log => state.logs.push_front(msg),
+                Priority::Metrics => {
+                    let bucket = match state.popped_metrics.take() {
+                        Some((id, bucket)) if id == msg.id => bucket,
+                        _ => match serde_json::from_value(msg.payload) {
+                            Ok(payload) => MetricsBucket {
+                                payload,
+                                samples: 1,
+                            },
+                            Err(e) => {
+                                warn!("Dropping malformed requeued metrics message: {}", e);
+                                return;
+                            }
+                        },
+                    };
+                    state.metrics.push_front(bucket);
+                    state.coalesce_metrics();
+                }
+                Priority::Backfill => state.backfill.push_front(msg),
+            }
+        }
+        self.inner.notify.notify_one();
+    }
+
+    /// Pop the highest-priority pending message, if any
+    pub fn try_pop(&self) -> Option<RpcMessage> {
+        let mut state = self.state();
+        if let Some(msg) = state.control.pop_front() {
+            return Some(msg);
+        }
+        if let Some(msg) = state.alerts.pop_front() {
+            return Some(msg);
+        }
+        if let Some(msg) = state.logs.pop_front() {
+            return Some(msg);
+        }
+        let Some(bucket) = state.metrics.pop_front() else {
+            return state.backfill.pop_front();
+        };
+        if bucket.samples > 1 {
+            debug!(samples = bucket.samples, "Sending coalesced metrics bucket");
+        }
+        match serde_json::to_value(&bucket.payload) {
+            Ok(payload) => {
+                let msg = RpcMessage::new(MessageType::Metrics, payload);
+                state.popped_metrics = Some((msg.id.clone(), bucket));
+                Some(msg)
+            }
+            Err(e) => {
+                warn!("Failed to serialize metrics bucket: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Wait for the next message in priority order.
+    ///
+    /// Returns `None` once the queue is closed and drained. Cancel-safe:
+    /// a message is only removed when this future completes.
+    pub async fn recv(&self) -> Option<RpcMessage> {
+        loop {
+            if let Some(msg) = self.try_pop() {
+                return Some(msg);
+            }
+            if self.state().closed {
+                return None;
+            }
+            self.inner.notify.notified().await;
+        }
+    }
+
+    /// Close the queue; `recv` returns `None` once drained
+    #[allow(dead_code)]
+    pub fn close(&self) {
+        self.state().closed = true;
+        self.inner.notify.notify_one();
+    }
+
+    /// Number of pending messages (a coalesced bucket counts once)
+    pub fn len(&self) -> usize {
+        self.state().len()
+    }
+
+    /// Whether no messages are pending
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Move pending alerts and logs from memory to the on-disk spool.
+    ///
+    /// Called while disconnected so events survive an agent restart.
+    /// Returns the number of entries written.
+    pub async fn spool(&self) -> std::io::Result<usize> {
+        let (alerts, logs) = {
+            let mut state = self.state();
+            (
+                std::mem::take(&mut state.alerts),
+                std::mem::take(&mut state.logs),
+            )
+        };
+        if alerts.is_empty() && logs.is_empty() {
+            return Ok(0);
+        }
+
+        tokio::fs::create_dir_all(&self.inner.spool_dir).await?;
+        let count = alerts.len() + logs.len();
+        append_spool(&self.alerts_path(), alerts).await?;
+        append_spool(&self.logs_path(), logs).await?;
+
+        info!(count = count, "Spooled outbound events to disk");
+        Ok(count)
+    }
+
+    /// Load spooled alerts and logs back into the queue ahead of newer
+    /// in-memory events, then remove the spool files.
+    ///
+    /// Returns the number of entries replayed.
+    pub async fn replay_spool(&self) -> std::io::Result<usize> {
+        let alerts = read_spool(&self.alerts_path()).await?;
+        let logs = read_spool(&self.logs_path()).await?;
+        let count = alerts.len() + logs.len();
+        if count == 0 {
+            return Ok(0);
+        }
+
+        {
+            let mut state = self.state();
+            for msg in alerts.into_iter().rev() {
+                state.alerts.push_front(msg);
+            }
+            for msg in logs.into_iter().rev() {
+                state.logs.push_front(msg);
+            }
+        }
+
+        remove_if_exists(&self.alerts_path()).await?;
+        remove_if_exists(&self.logs_path()).await?;
+        self.inner.notify.notify_one();
+
+        info!(count = count, "Replaying spooled outbound events");
+        Ok(count)
+    }
+
+    fn alerts_path(&self) -> PathBuf {
+        self.inner.spool_dir.join("alerts.jsonl")
+    }
+
+    fn logs_path(&self) -> PathBuf {
+        self.inner.spool_dir.join("logs.jsonl")
+    }
+}
+
+/// Forward messages from an mpsc channel into the queue until every sender
+/// has been dropped.
+///
+/// Producers keep their `mpsc::Sender<RpcMessage>` handles; because this
+/// drains the channel regardless of link state, their sends never stall.
+/// The queue is left open: it outlives each supervisor generation, and the
+/// next generation forwards into it from a fresh channel.
+pub async fn forward(mut rx: mpsc::Receiver<RpcMessage>, queue: OutboundQueue) {
+    while let Some(msg) = rx.recv().await {
+        queue.push(msg);
+    }
+    debug!("Outbound channel closed, forwarder stopped");
+}
+
+/// Read a JSON-lines spool file, skipping corrupt lines
+async fn read_spool(path: &Path) -> std::io::Result<Vec<RpcMessage>> {
+    let data = match tokio::fs::read_to_string(path).await {
+        Ok(d) => d,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    Ok(data
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(msg) => Some(msg),
+            Err(e) => {
+                warn!("Skipping corrupt spool entry in {}: {}", path.display(), e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Append entries to a spool file, keeping only the newest `SPOOL_MAX_ENTRIES`.
+///
+/// The file is rewritten via a temp file and rename so a crash mid-write
+/// never leaves a truncated spool behind.
+async fn append_spool(path: &Path, entries: VecDeque<RpcMessage>) -> std::io::Result<()> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    let mut all = read_spool(path).await?;
+    all.extend(entries);
+    if all.len() > SPOOL_MAX_ENTRIES {
+        let excess = all.len() - SPOOL_MAX_ENTRIES;
+        warn!(
+            dropped = excess,
+            "Spool {} full, discarding oldest entries",
+            path.display()
+        );
+        all.drain(..excess);
+    }
+
+    let mut data = String::new();
+    for msg in &all {
+        data.push_str(&serde_json::to_string(msg).map_err(std::io::Error::other)?);
+        data.push('\n');
+    }
+
+    let tmp = path.with_extension("jsonl.tmp");
+    tokio::fs::write(&tmp, data).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+async fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ngfw_protocol::{
+        ConnectionCounts, CountryCounters, DnsMetrics, FeedCounters, InterfaceRates, RuleCounters,
+        UpnpMapping,
+    };
+    use std::collections::HashMap;
+
+    fn msg(msg_type: MessageType) -> RpcMessage {
+        RpcMessage::new(msg_type, serde_json::json!({}))
+    }
+
+    fn metrics(timestamp: i64, cpu: f32) -> RpcMessage {
+        let mut interfaces = HashMap::new();
+        interfaces.insert(
+            "eth0".to_string(),
+            InterfaceRates {
+                rx_rate: (cpu * 10.0) as u64,
+                tx_rate: 0,
+            },
+        );
+        let payload = MetricsPayload {
+            timestamp,
+            cpu,
+            memory: 50.0,
+            temperature: Some(40.0),
+            interfaces,
+            connections: ConnectionCounts {
+                total: 10,
+                tcp: 6,
+                udp: 4,
+            },
+            dns: DnsMetrics {
+                queries: timestamp as u64,
+                blocked: 0,
+                cached: 0,
+            },
+            firewall_rules: Vec::new(),
+            countries: Vec::new(),
+            threat_feeds: Vec::new(),
+            upnp_leases: None,
+        };
+        RpcMessage::new(MessageType::Metrics, serde_json::to_value(payload).unwrap())
+    }
+
+    #[test]
+    fn priority_classification() {
+        assert_eq!(Priority::of(&MessageType::ConfigAck), Priority::Control);
+        assert_eq!(Priority::of(&MessageType::ExecResult), Priority::Control);
+        assert_eq!(Priority::of(&MessageType::Alert), Priority::Alert);
+        assert_eq!(Priority::of(&MessageType::Log), Priority::Log);
+        assert_eq!(Priority::of(&MessageType::Metrics), Priority::Metrics);
+        assert_eq!(Priority::of(&MessageType::MetricsBatch), Priority::Backfill);
+    }
+
+    #[test]
+    fn pops_in_priority_order() {
+        let queue = OutboundQueue::new("/nonexistent");
+        queue.push(msg(MessageType::MetricsBatch));
+        queue.push(metrics(1, 10.0));
+        queue.push(msg(MessageType::Log));
+        queue.push(msg(MessageType::Alert));
+        queue.push(msg(MessageType::ConfigAck));
+
+        let order: Vec<MessageType> = std::iter::from_fn(|| queue.try_pop())
+            .map(|m| m.msg_type)
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                MessageType::ConfigAck,
+                MessageType::Alert,
+                MessageType::Log,
+                MessageType::Metrics,
+                MessageType::MetricsBatch
+            ]
+        );
+    }
+
+    #[test]
+    fn requeue_goes_to_head() {
+        let queue = OutboundQueue::new("/nonexistent");
+        let first = msg(MessageType::ExecResult);
+        let first_id = first.id.clone();
+        queue.push(msg(MessageType::ConfigAck));
+        queue.requeue(first);
+        assert_eq!(queue.try_pop().unwrap().id, first_id);
+    }
+
+    #[test]
+    fn requeued_metrics_keep_their_bucket() {
+        let queue = OutboundQueue::new("/nonexistent");
+        for i in 0..=MAX_METRICS {
+            queue.push(metrics(i as i64, i as f32));
+        }
+        let sent = queue.try_pop().unwrap();
+        let sent_payload = sent.payload.clone();
+        queue.requeue(sent);
+
+        let state = queue.state();
+        let head = state.metrics.front().unwrap();
+        assert_eq!(head.samples, 2);
+        assert_eq!(serde_json::to_value(&head.payload).unwrap(), sent_payload);
+    }
+
+    #[test]
+    fn metrics_are_coalesced_not_dropped() {
+        let queue = OutboundQueue::new("/nonexistent");
+        let total = MAX_METRICS + 20;
+        for i in 0..total {
+            queue.push(metrics(i as i64, i as f32));
+        }
+        assert_eq!(queue.len(), MAX_METRICS);
+
+        let buckets: Vec<MetricsPayload> = std::iter::from_fn(|| queue.try_pop())
+            .map(|m| serde_json::from_value(m.payload).unwrap())
+            .collect();
+
+        // First bucket starts at the first sample, last bucket holds the latest DNS counters
+        assert_eq!(buckets.first().unwrap().timestamp, 0);
+        assert_eq!(buckets.last().unwrap().dns.queries, (total - 1) as u64);
+        // Timestamps remain strictly ordered
+        assert!(buckets.windows(2).all(|w| w[0].timestamp < w[1].timestamp));
+    }
+
+    #[test]
+    fn coalesced_bucket_is_weighted_average() {
+        let mut a = MetricsBucket {
+            payload: serde_json::from_value(metrics(0, 10.0).payload).unwrap(),
+            samples: 3,
+        };
+        let b = MetricsBucket {
+            payload: serde_json::from_value(metrics(5, 50.0).payload).unwrap(),
+            samples: 1,
+        };
+        a.absorb(b);
+        assert_eq!(a.samples, 4);
+        assert_eq!(a.payload.timestamp, 0);
+        assert!((a.payload.cpu - 20.0).abs() < f32::EPSILON);
+        assert_eq!(a.payload.interfaces["eth0"].rx_rate, 200);
+        assert_eq!(a.payload.dns.queries, 5);
+    }
+
+    fn bucket(timestamp: i64) -> MetricsBucket {
+        MetricsBucket {
+            payload: serde_json::from_value(metrics(timestamp, 10.0).payload).unwrap(),
+            samples: 1,
+        }
+    }
+
+    #[test]
+    fn coalesced_bucket_keeps_later_rule_counters() {
+        let mut a = bucket(0);
+        a.payload.firewall_rules = vec![RuleCounters {
+            rule_id: 1,
+            packets: 10,
+            bytes: 1_000,
+        }];
+        let mut b = bucket(5);
+        b.payload.firewall_rules = vec![RuleCounters {
+            rule_id: 1,
+            packets: 15,
+            bytes: 1_500,
+        }];
+        let expected = b.payload.firewall_rules.clone();
+        a.absorb(b);
+        assert_eq!(a.payload.firewall_rules, expected);
+    }
+
+    #[test]
+    fn coalesced_bucket_keeps_later_country_counters() {
+        let mut a = bucket(0);
+        a.payload.countries = vec![CountryCounters {
+            country: "CN".to_string(),
+            packets: 3,
+            bytes: 300,
+        }];
+        let mut b = bucket(5);
+        b.payload.countries = vec![CountryCounters {
+            country: "CN".to_string(),
+            packets: 7,
+            bytes: 700,
+        }];
+        let expected = b.payload.countries.clone();
+        a.absorb(b);
+        assert_eq!(a.payload.countries, expected);
+    }
+
+    #[test]
+    fn coalesced_bucket_keeps_later_feed_counters() {
+        let mut a = bucket(0);
+        a.payload.threat_feeds = vec![FeedCounters {
+            feed_id: 2,
+            packets: 40,
+            bytes: 4_000,
+        }];
+        let mut b = bucket(5);
+        b.payload.threat_feeds = vec![FeedCounters {
+            feed_id: 2,
+            packets: 90,
+            bytes: 9_000,
+        }];
+        let expected = b.payload.threat_feeds.clone();
+        a.absorb(b);
+        assert_eq!(a.payload.threat_feeds, expected);
+    }
+
+    #[test]
+    fn coalesced_bucket_keeps_later_upnp_leases() {
+        let mapping = |external_port| UpnpMapping {
+            protocol: "tcp".to_string(),
+            external_port,
+            internal_ip: "192.168.1.20".to_string(),
+            internal_port: 8080,
+            description: "game".to_string(),
+            expires_at: None,
+        };
+        let mut a = bucket(0);
+        a.payload.upnp_leases = Some(vec![mapping(8080)]);
+        let mut b = bucket(5);
+        b.payload.upnp_leases = Some(vec![mapping(9090)]);
+        a.absorb(b);
+        assert_eq!(a.payload.upnp_leases, Some(vec![mapping(9090)]));
+    }
+
+    #[test]
+    fn control_queue_is_bounded() {
+        let queue = OutboundQueue::new("/nonexistent");
+        for _ in 0..MAX_CONTROL + 5 {
+            queue.push(msg(MessageType::ConfigAck));
+        }
+        assert_eq!(queue.len(), MAX_CONTROL);
+    }
+
+    #[tokio::test]
+    async fn spool_and_replay_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = OutboundQueue::new(dir.path());
+
+        let alert = msg(MessageType::Alert);
+        let log = msg(MessageType::Log);
+        let (alert_id, log_id) = (alert.id.clone(), log.id.clone());
+        queue.push(alert);
+        queue.push(log);
+        queue.push(msg(MessageType::ConfigAck));
+
+        assert_eq!(queue.spool().await.unwrap(), 2);
+        // Control messages stay in memory
+        assert_eq!(queue.len(), 1);
+        assert!(dir.path().join("alerts.jsonl").exists());
+
+        // A restarted agent replays the spool from the same directory
+        let restarted = OutboundQueue::new(dir.path());
+        let newer = msg(MessageType::Alert);
+        let newer_id = newer.id.clone();
+        restarted.push(newer);
+        assert_eq!(restarted.replay_spool().await.unwrap(), 2);
+        assert!(!dir.path().join("alerts.jsonl").exists());
+
+        let ids: Vec<String> = std::iter::from_fn(|| restarted.try_pop())
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(ids, vec![alert_id, newer_id, log_id]);
+    }
+
+    #[tokio::test]
+    async fn spool_is_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = OutboundQueue::new(dir.path());
+
+        for _ in 0..(SPOOL_MAX_ENTRIES / MAX_EVENTS + 2) {
+            for _ in 0..MAX_EVENTS {
+                queue.push(msg(MessageType::Log));
+            }
+            queue.spool().await.unwrap();
+        }
+
+        let spooled = read_spool(&dir.path().join("logs.jsonl")).await.unwrap();
+        assert_eq!(spooled.len(), SPOOL_MAX_ENTRIES);
+    }
+
+    #[tokio::test]
+    async fn recv_returns_none_when_closed_and_drained() {
+        let queue = OutboundQueue::new("/nonexistent");
+        queue.push(msg(MessageType::Pong));
+        queue.close();
+        assert!(queue.recv().await.is_some());
+        assert!(queue.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn recv_wakes_on_push() {
+        let queue = OutboundQueue::new("/nonexistent");
+        let producer = queue.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            producer.push(msg(MessageType::ConfigAck));
+        });
+        let received = tokio::time::timeout(std::time::Duration::from_secs(1), queue.recv())
+            .await
+            .expect("recv should wake");
+        assert_eq!(received.unwrap().msg_type, MessageType::ConfigAck);
+    }
+
+    #[tokio::test]
+    async fn forward_keeps_queue_open_for_next_generation() {
+        let queue = OutboundQueue::new("/nonexistent");
+
+        let (tx, rx) = mpsc::channel(4);
+        tx.send(msg(MessageType::ConfigAck)).await.unwrap();
+        drop(tx);
+        forward(rx, queue.clone()).await;
+
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(forward(rx, queue.clone()));
+        tx.send(msg(MessageType::ExecResult)).await.unwrap();
+
+        assert_eq!(queue.recv().await.unwrap().msg_type, MessageType::ConfigAck);
+        let next = tokio::time::timeout(std::time::Duration::from_secs(1), queue.recv())
+            .await
+            .expect("queue should still be open");
+        assert_eq!(next.unwrap().msg_type, MessageType::ExecResult);
+    }
+}
@@ -1,9 +1,11 @@
-//! Backup and restore of configuration state before applying changes.
+//! Persistent record of the configuration applied per section.
 //!
-//! Each config section is backed up as a standalone JSON file under
-//! `/jffs/ngfw/rollback/`. A `versions.json` file tracks the last
-//! successfully applied version number per section. Only the most
-//! recent backup per section is kept (overwritten on each backup).
+//! A `versions.json` file under `/jffs/ngfw/rollback/` tracks the last
+//! successfully applied version number per section.
+//!
+//! The config last applied successfully is also kept per section under
+//! `/jffs/ngfw/applied/`, so the agent can re-evaluate it after a restart
+//! without the cloud (e.g. firewall rule schedules).
 
 use ngfw_protocol::ConfigSection;
 use serde::{Deserialize, Serialize};
@@ -13,6 +15,8 @@ use tracing::{error, info, warn};
 
 const ROLLBACK_DIR: &str = "/jffs/ngfw/rollback";
 
+const APPLIED_DIR: &str = "/jffs/ngfw/applied";
+
 /// Tracks the last known config version per section.
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 struct VersionMap {
@@ -29,9 +33,9 @@ fn section_name(section: &ConfigSection) -> String {
         .unwrap_or_else(|| format!("{:?}", section).to_lowercase())
 }
 
-/// Path to the backup file for a given section.
-fn backup_path(section: &ConfigSection) -> PathBuf {
-    PathBuf::from(ROLLBACK_DIR).join(format!("{}.json", section_name(section)))
+/// Path to the last applied config for a given section.
+fn applied_path(section: &ConfigSection) -> PathBuf {
+    PathBuf::from(APPLIED_DIR).join(format!("{}.json", section_name(section)))
 }
 
 /// Path to the version map file.
@@ -49,57 +53,28 @@ pub async fn ensure_rollback_dir() {
     }
 }
 
-/// Save the current config state for `section` so it can be restored later.
-///
-/// Overwrites any previous backup for the same section.
-pub async fn backup(
+/// Persist the config that was just applied successfully for `section`.
+pub async fn save_applied(
     section: &ConfigSection,
     config: &serde_json::Value,
 ) -> Result<(), std::io::Error> {
-    ensure_rollback_dir().await;
-
-    let path = backup_path(section);
-    let data = serde_json::to_string_pretty(config)
+    tokio::fs::create_dir_all(APPLIED_DIR).await?;
+    let data = serde_json::to_string(config)
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-
-    tokio::fs::write(&path, data).await?;
-    info!(
-        "Backed up {} config to {}",
-        section_name(section),
-        path.display()
-    );
-    Ok(())
+    tokio::fs::write(applied_path(section), data).await
 }
 
-/// Restore the previously backed-up config for `section`.
-///
-/// Returns the JSON value read from disk. If the backup file does not
-/// exist (e.g. first run), an error is returned rather than panicking.
-pub async fn restore(
-    section: &ConfigSection,
-) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
-    let path = backup_path(section);
-
-    let data = match tokio::fs::read_to_string(&path).await {
-        Ok(d) => d,
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-            warn!(
-                "No rollback backup found for {} at {}",
-                section_name(section),
-                path.display()
-            );
-            return Err(Box::new(e));
+/// The config last applied successfully for `section`, if any.
+pub async fn load_applied(section: &ConfigSection) -> Option<serde_json::Value> {
+    let path = applied_path(section);
+    let data = tokio::fs::read_to_string(&path).await.ok()?;
+    match serde_json::from_str(&data) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            warn!("Corrupt applied config {}: {}", path.display(), e);
+            None
         }
-        Err(e) => return Err(Box::new(e)),
-    };
-
-    let value: serde_json::Value = serde_json::from_str(&data)?;
-    info!(
-        "Restored {} config from {}",
-        section_name(section),
-        path.display()
-    );
-    Ok(value)
+    }
 }
 
 /// Update the persisted version number for `section`.
@@ -160,13 +135,9 @@ mod tests {
     }
 
     #[test]
-    fn backup_path_uses_section_name() {
-        let path = backup_path(&ConfigSection::Firewall);
-        assert!(
-            path.ends_with("rollback/firewall.json"),
-            "expected path ending with rollback/firewall.json, got: {}",
-            path.display()
-        );
+    fn applied_path_uses_section_name() {
+        let path = applied_path(&ConfigSection::Firewall);
+        assert!(path.ends_with("applied/firewall.json"));
     }
 
     #[test]
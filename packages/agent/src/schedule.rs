@@ -0,0 +1,254 @@
+//! Time-based firewall rule schedules
+//!
+//! A `RuleSchedule` limits a firewall rule to a daily window on chosen
+//! weekdays, in router local time. The scheduler wakes at every minute
+//! boundary and asks the firewall adapter to re-install its rules when a
+//! window opens or closes. Everything runs from the last applied config
+//! and the router clock, so schedules keep switching while the device is
+//! offline.
+//!
+//! The current state of each scheduled rule is kept in a process-wide
+//! registry, reported in `StatusPayload.schedules`, and a fresh status is
+//! sent whenever a rule switches.
+//!
+//! The same tick re-resolves host names in firewall address groups once
+//! their resolve interval has elapsed.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use ngfw_protocol::{AgentMode, ConfigSection, MessageType, ModeConfig, RpcMessage, ScheduleState};
+use serde::Deserialize;
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, info, warn};
+
+use crate::adapters::IptablesAdapter;
+use crate::clock;
+use crate::config::AgentConfig;
+use crate::dispatcher;
+
+const MINUTES_PER_DAY: u32 = 1440;
+const MINUTES_PER_WEEK: u32 = 7 * MINUTES_PER_DAY;
+
+static STATES: Mutex<BTreeMap<u32, ScheduleState>> = Mutex::new(BTreeMap::new());
+
+/// Daily window during which a rule is enforced
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct RuleSchedule {
+    pub enabled: bool,
+    /// Window start, `HH:MM` local time
+    pub start: String,
+    /// Window end, `HH:MM`; at or before `start` means the window crosses
+    /// midnight into the next day
+    pub end: String,
+    /// Days the window starts on, 0 (Sunday) to 6
+    pub days: Vec<u8>,
+}
+
+impl RuleSchedule {
+    /// Problems with the schedule, as `(field, message)` pairs
+    pub fn issues(&self) -> Vec<(&'static str, String)> {
+        let mut issues = Vec::new();
+        if parse_minutes(&self.start).is_none() {
+            issues.push(("start", format!("'{}' is not HH:MM", self.start)));
+        }
+        if parse_minutes(&self.end).is_none() {
+            issues.push(("end", format!("'{}' is not HH:MM", self.end)));
+        }
+        if self.days.is_empty() {
+            issues.push(("days", "must not be empty".to_string()));
+        }
+        if self.days.iter().any(|d| *d > 6) {
+            issues.push(("days", "must be 0 (Sunday) to 6 (Saturday)".to_string()));
+        }
+        issues
+    }
+
+    /// Whether the window is open at `minute` of the week (from Sunday 00:00).
+    ///
+    /// A window that starts on Saturday and crosses midnight runs into
+    /// Sunday morning. Invalid schedules are never active.
+    pub fn is_active(&self, minute: u32) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        let (Some(start), Some(end)) = (parse_minutes(&self.start), parse_minutes(&self.end))
+        else {
+            return false;
+        };
+        let length = if end > start {
+            end - start
+        } else {
+            end + MINUTES_PER_DAY - start
+        };
+        self.days.iter().filter(|d| **d < 7).any(|day| {
+            let from = u32::from(*day) * MINUTES_PER_DAY + start;
+            (minute + MINUTES_PER_WEEK - from) % MINUTES_PER_WEEK < length
+        })
+    }
+}
+
+/// Minutes since midnight of `HH:MM`
+fn parse_minutes(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+/// Snapshot of scheduled rule states, ordered by rule id
+pub fn states() -> Vec<ScheduleState> {
+    registry().values().cloned().collect()
+}
+
+fn registry() -> std::sync::MutexGuard<'static, BTreeMap<u32, ScheduleState>> {
+    STATES
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Whether `active` (rule id to window open) differs from the recorded states
+pub fn differs(active: &BTreeMap<u32, bool>) -> bool {
+    let registry = registry();
+    registry.len() != active.len()
+        || active
+            .iter()
+            .any(|(id, on)| registry.get(id).is_none_or(|s| s.active != *on))
+}
+
+/// Record the states installed on the router, stamping rules that switched
+pub fn record(active: &BTreeMap<u32, bool>) {
+    let now = clock::now();
+    let mut registry = registry();
+    registry.retain(|id, _| active.contains_key(id));
+    for (id, on) in active {
+        let state = registry.entry(*id).or_insert(ScheduleState {
+            rule_id: *id,
+            active: *on,
+            since: now,
+        });
+        if state.active != *on {
+            info!(rule_id = id, active = on, "firewall rule schedule switched");
+            state.active = *on;
+            state.since = now;
+        }
+    }
+}
+
+/// Long-running loop that enforces firewall rule schedules and refreshes
+/// address groups at each minute boundary while the firewall section is in
+/// takeover mode. Exits cleanly when `shutdown` fires.
+pub async fn schedule_loop(
+    config: AgentConfig,
+    outbound_tx: mpsc::Sender<RpcMessage>,
+    mode_rx: watch::Receiver<ModeConfig>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    if !config.adapters.iptables {
+        let _ = shutdown.wait_for(|stop| *stop).await;
+        return;
+    }
+
+    let adapter = IptablesAdapter::new();
+    debug!("rule scheduler started");
+
+    loop {
+        let takeover =
+            mode_rx.borrow().effective_mode(&ConfigSection::Firewall) == &AgentMode::Takeover;
+        if takeover {
+            match adapter.enforce_schedules().await {
+                Ok(true) => {
+                    let status = dispatcher::collect_status(&config).await;
+                    match serde_json::to_value(&status) {
+                        Ok(payload) => {
+                            let msg = RpcMessage::new(MessageType::Status, payload);
+                            if outbound_tx.send(msg).await.is_err() {
+                                warn!("outbound channel closed, stopping rule scheduler");
+                                break;
+                            }
+                        }
+                        Err(e) => warn!("failed to serialize status: {}", e),
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => warn!("failed to enforce rule schedules: {}", e),
+            }
+            if let Err(e) = adapter.refresh_groups().await {
+                warn!("failed to refresh address groups: {}", e);
+            }
+        }
+
+        // Wake just after the next minute boundary
+        let wait = 60 - clock::local_now().rem_euclid(60) + 1;
+        tokio::select! {
+            _ = shutdown.changed() => {
+                if *shutdown.borrow() {
+                    break;
+                }
+            }
+            _ = tokio::time::sleep(std::time::Duration::from_secs(wait as u64)) => {}
+        }
+    }
+
+    debug!("rule scheduler stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(start: &str, end: &str, days: &[u8]) -> RuleSchedule {
+        RuleSchedule {
+            enabled: true,
+            start: start.to_string(),
+            end: end.to_string(),
+            days: days.to_vec(),
+        }
+    }
+
+    fn at(day: u32, hour: u32, minute: u32) -> u32 {
+        day * MINUTES_PER_DAY + hour * 60 + minute
+    }
+
+    #[test]
+    fn daytime_window() {
+        let office = schedule("08:00", "18:00", &[1, 2, 3, 4, 5]);
+        assert!(office.is_active(at(1, 8, 0)));
+        assert!(office.is_active(at(5, 17, 59)));
+        assert!(!office.is_active(at(5, 18, 0)));
+        assert!(!office.is_active(at(6, 12, 0)));
+    }
+
+    #[test]
+    fn window_crossing_midnight_runs_into_next_day() {
+        let night = schedule("22:00", "06:00", &[5, 6]);
+        assert!(night.is_active(at(5, 23, 0)));
+        assert!(night.is_active(at(6, 5, 59)));
+        assert!(!night.is_active(at(6, 6, 0)));
+        // Saturday night wraps into Sunday morning
+        assert!(night.is_active(at(0, 3, 0)));
+        assert!(!night.is_active(at(0, 22, 0)));
+    }
+
+    #[test]
+    fn equal_start_and_end_is_a_full_day() {
+        let day = schedule("00:00", "00:00", &[3]);
+        assert!(day.is_active(at(3, 0, 0)));
+        assert!(day.is_active(at(3, 23, 59)));
+        assert!(!day.is_active(at(4, 0, 0)));
+    }
+
+    #[test]
+    fn disabled_schedules_are_always_active_and_bad_ones_never() {
+        let mut off = schedule("08:00", "09:00", &[1]);
+        off.enabled = false;
+        assert!(off.is_active(at(0, 0, 0)));
+
+        let bad = schedule("8:00", "25:00", &[7]);
+        assert!(!bad.is_active(at(1, 8, 30)));
+        let fields: Vec<&str> = bad.issues().iter().map(|(f, _)| *f).collect();
+        assert_eq!(fields, vec!["start", "end", "days"]);
+    }
+}
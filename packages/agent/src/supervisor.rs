@@ -0,0 +1,351 @@
+//! Task supervisor — restarts the agent's background tasks when one fails
+//!
+//! The connection, dispatcher and collector tasks are wired together by
+//! channels, so they are supervised as a group: when any of them panics or
+//! returns while the agent is not shutting down, the others are stopped,
+//! and after a backoff the whole set is respawned with fresh channels.
+//!
+//! After `RestartPolicy::max_failures` failures without a stable run in
+//! between, [`supervise`] gives up and returns an error so the agent exits
+//! non-zero and the init system can take over. Per-task health is kept in
+//! a process-wide registry and reported in `StatusPayload.tasks`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::Mutex;
+
+use ngfw_protocol::{TaskHealth, TaskState};
+use tokio::sync::watch;
+use tokio::task::{self, JoinError, JoinSet};
+use tokio::time::{Duration, Instant, sleep, timeout};
+use tracing::{error, info, warn};
+
+/// Time allowed for surviving tasks to stop before they are aborted
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+static HEALTH: Mutex<BTreeMap<String, TaskHealth>> = Mutex::new(BTreeMap::new());
+
+/// Snapshot of supervised task health, ordered by task name
+pub fn health() -> Vec<TaskHealth> {
+    registry().values().cloned().collect()
+}
+
+fn registry() -> std::sync::MutexGuard<'static, BTreeMap<String, TaskHealth>> {
+    HEALTH
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn set_state(name: &str, state: TaskState, error: Option<String>) {
+    let mut registry = registry();
+    let entry = registry
+        .entry(name.to_string())
+        .or_insert_with(|| TaskHealth {
+            name: name.to_string(),
+            state,
+            restarts: 0,
+            last_error: None,
+        });
+    entry.state = state;
+    if error.is_some() {
+        entry.last_error = error;
+    }
+}
+
+fn record_restart(name: &str) {
+    if let Some(entry) = registry().get_mut(name) {
+        entry.restarts += 1;
+    }
+}
+
+/// When and how often the supervisor restarts failed tasks
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    /// Consecutive failures tolerated before giving up
+    pub max_failures: u32,
+    /// Delay before the first restart
+    pub initial_backoff: Duration,
+    /// Maximum delay between restarts
+    pub max_backoff: Duration,
+    /// A generation that runs this long resets the failure count
+    pub stable_after: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            stable_after: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Error returned when the supervisor gives up
+#[derive(Debug)]
+pub struct SupervisorError {
+    /// Task whose failure exhausted the restart budget
+    pub task: &'static str,
+    /// Reason for that task's last failure
+    pub reason: String,
+    /// Consecutive failures observed
+    pub failures: u32,
+}
+
+impl std::fmt::Display for SupervisorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "task '{}' failed {} times in a row: {}",
+            self.task, self.failures, self.reason
+        )
+    }
+}
+
+impl std::error::Error for SupervisorError {}
+
+/// A generation of named tasks spawned by the supervisor
+#[derive(Default)]
+pub struct Tasks {
+    set: JoinSet<()>,
+    names: HashMap<task::Id, &'static str>,
+}
+
+impl Tasks {
+    /// Spawn a named task into this generation
+    pub fn spawn<F>(&mut self, name: &'static str, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let handle = self.set.spawn(future);
+        self.names.insert(handle.id(), name);
+        set_state(name, TaskState::Running, None);
+    }
+
+    /// Wait for the next task to finish, returning its name and outcome
+    async fn next(&mut self) -> Option<(&'static str, Result<(), JoinError>)> {
+        let (id, result) = match self.set.join_next_with_id().await? {
+            Ok((id, ())) => (id, Ok(())),
+            Err(e) => (e.id(), Err(e)),
+        };
+        let name = self.names.remove(&id).unwrap_or("unknown");
+        Some((name, result))
+    }
+
+    /// Signal the generation to stop and wait for its tasks, aborting any
+    /// that outlive `STOP_TIMEOUT`
+    async fn stop(&mut self, stop_tx: &watch::Sender<bool>) {
+        let _ = stop_tx.send(true);
+        let drained = timeout(STOP_TIMEOUT, async {
+            while self.set.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            warn!("Tasks did not stop in time, aborting");
+            self.set.abort_all();
+            while self.set.join_next().await.is_some() {}
+        }
+        self.names.clear();
+    }
+}
+
+/// Describe why a task ended
+fn exit_reason(result: Result<(), JoinError>) -> String {
+    match result {
+        Ok(()) => "exited unexpectedly".to_string(),
+        Err(e) if e.is_panic() => {
+            let panic = e.into_panic();
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            format!("panicked: {}", message)
+        }
+        Err(_) => "cancelled".to_string(),
+    }
+}
+
+/// Resolve once shutdown is requested (or can no longer be requested)
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stop| *stop).await;
+}
+
+/// Run generations of tasks until `shutdown` fires, restarting the whole
+/// generation with exponential backoff whenever one of its tasks ends.
+///
+/// `spawn` is called once per generation with a fresh `Tasks` set and a
+/// stop signal the tasks must treat as their shutdown receiver.
+pub async fn supervise<F>(
+    policy: RestartPolicy,
+    mut spawn: F,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), SupervisorError>
+where
+    F: FnMut(&mut Tasks, watch::Receiver<bool>) + Send,
+{
+    let mut failures: u32 = 0;
+    let mut backoff = policy.initial_backoff;
+
+    loop {
+        if *shutdown.borrow() {
+            return Ok(());
+        }
+
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let mut tasks = Tasks::default();
+        spawn(&mut tasks, stop_rx);
+        let started = Instant::now();
+
+        let (name, result) = tokio::select! {
+            exited = tasks.next() => match exited {
+                Some(exited) => exited,
+                None => {
+                    warn!("Supervisor has no tasks to run");
+                    return Ok(());
+                }
+            },
+            _ = shutdown_requested(&mut shutdown) => {
+                tasks.stop(&stop_tx).await;
+                return Ok(());
+            }
+        };
+
+        let reason = exit_reason(result);
+        error!(task = name, "Task {}", reason);
+        set_state(name, TaskState::Restarting, Some(reason.clone()));
+        tasks.stop(&stop_tx).await;
+
+        if started.elapsed() >= policy.stable_after {
+            failures = 0;
+            backoff = policy.initial_backoff;
+        }
+        failures += 1;
+
+        if failures >= policy.max_failures {
+            set_state(name, TaskState::Failed, None);
+            return Err(SupervisorError {
+                task: name,
+                reason,
+                failures,
+            });
+        }
+
+        info!(
+            task = name,
+            failures = failures,
+            delay_ms = backoff.as_millis() as u64,
+            "Restarting agent tasks"
+        );
+        record_restart(name);
+        tokio::select! {
+            _ = sleep(backoff) => {}
+            _ = shutdown_requested(&mut shutdown) => return Ok(()),
+        }
+        backoff = (backoff * 2).min(policy.max_backoff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn fast_policy() -> RestartPolicy {
+        RestartPolicy {
+            max_failures: 3,
+            initial_backoff: Duration::from_millis(5),
+            max_backoff: Duration::from_millis(20),
+            stable_after: Duration::from_secs(60),
+        }
+    }
+
+    /// Task that runs until told to stop
+    async fn well_behaved(mut stop: watch::Receiver<bool>) {
+        let _ = stop.wait_for(|s| *s).await;
+    }
+
+    #[tokio::test]
+    async fn restarts_generation_after_task_exits() {
+        let generations = Arc::new(AtomicU32::new(0));
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+        let counter = generations.clone();
+        let supervisor = tokio::spawn(supervise(
+            fast_policy(),
+            move |tasks, stop| {
+                let generation = counter.fetch_add(1, Ordering::SeqCst);
+                tasks.spawn("test-steady", well_behaved(stop.clone()));
+                if generation == 0 {
+                    tasks.spawn("test-flaky", async {});
+                } else {
+                    tasks.spawn("test-flaky", well_behaved(stop));
+                }
+            },
+            shutdown_rx,
+        ));
+
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(generations.load(Ordering::SeqCst), 2);
+
+        let flaky = health()
+            .into_iter()
+            .find(|t| t.name == "test-flaky")
+            .unwrap();
+        assert_eq!(flaky.state, TaskState::Running);
+        assert_eq!(flaky.restarts, 1);
+        assert_eq!(flaky.last_error.as_deref(), Some("exited unexpectedly"));
+
+        shutdown_tx.send(true).unwrap();
+        assert!(supervisor.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_repeated_panics() {
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+
+        let result = supervise(
+            fast_policy(),
+            |tasks, stop| {
+                tasks.spawn("test-steady-2", well_behaved(stop));
+                tasks.spawn("test-panicky", async { panic!("boom") });
+            },
+            shutdown_rx,
+        )
+        .await;
+
+        let err = result.unwrap_err();
+        assert_eq!(err.task, "test-panicky");
+        assert_eq!(err.failures, 3);
+        assert_eq!(err.reason, "panicked: boom");
+
+        let panicky = health()
+            .into_iter()
+            .find(|t| t.name == "test-panicky")
+            .unwrap();
+        assert_eq!(panicky.state, TaskState::Failed);
+        assert_eq!(panicky.restarts, 2);
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_tasks_without_restart() {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let supervisor = tokio::spawn(supervise(
+            fast_policy(),
+            |tasks, stop| tasks.spawn("test-steady-3", well_behaved(stop)),
+            shutdown_rx,
+        ));
+
+        sleep(Duration::from_millis(10)).await;
+        shutdown_tx.send(true).unwrap();
+        assert!(supervisor.await.unwrap().is_ok());
+
+        let steady = health()
+            .into_iter()
+            .find(|t| t.name == "test-steady-3")
+            .unwrap();
+        assert_eq!(steady.restarts, 0);
+    }
+}
@@ -0,0 +1,341 @@
+//! POSIX TZ strings and router local time
+//!
+//! Asuswrt-Merlin keeps the router's zone in `/etc/TZ` as a POSIX TZ
+//! string (e.g. `CET-1CEST,M3.5.0,M10.5.0/3`) rather than an Olson name,
+//! and the agent ships without a zone database, so local time is derived
+//! from that string: a standard offset plus optional DST transition rules.
+
+use tracing::warn;
+
+/// Where Asuswrt-Merlin writes the configured zone
+const TZ_FILE: &str = "/etc/TZ";
+
+const SECS_PER_DAY: i64 = 86_400;
+
+/// Rule used by glibc when a zone names DST without transition dates
+const DEFAULT_DST_RULE: &str = "M3.2.0,M11.1.0";
+
+/// Day of the year a DST transition happens on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TransitionDate {
+    /// `Jn`: 1-based day of year, February 29 never counted
+    Julian(u16),
+    /// `n`: 0-based day of year, February 29 counted
+    Ordinal(u16),
+    /// `Mm.w.d`: day `d` (0 = Sunday) of week `w` (5 = last) of month `m`
+    MonthWeekDay { month: u8, week: u8, weekday: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Transition {
+    date: TransitionDate,
+    /// Local time of day of the transition, in seconds (may exceed a day)
+    time: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Dst {
+    /// Seconds east of UTC while DST is in effect
+    offset: i64,
+    start: Transition,
+    end: Transition,
+}
+
+/// A zone parsed from a POSIX TZ string
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeZone {
+    /// Seconds east of UTC outside DST
+    offset: i64,
+    dst: Option<Dst>,
+}
+
+impl TimeZone {
+    pub const UTC: TimeZone = TimeZone {
+        offset: 0,
+        dst: None,
+    };
+
+    /// Parse a POSIX TZ string such as `EST5EDT,M3.2.0,M11.1.0`.
+    ///
+    /// Offsets in the string are west of UTC (`EST5` is UTC-5).
+    pub fn parse(spec: &str) -> Option<TimeZone> {
+        let mut rest = spec.trim();
+        rest = skip_name(rest)?;
+        let (std_west, after) = parse_offset(rest)?;
+        rest = after;
+        let offset = -std_west;
+
+        if rest.is_empty() {
+            return Some(TimeZone { offset, dst: None });
+        }
+
+        rest = skip_name(rest)?;
+        let (dst_offset, after) = match parse_offset(rest) {
+            Some((west, after)) => (-west, after),
+            None => (offset + 3600, rest),
+        };
+        let rules = match after.strip_prefix(',') {
+            Some(rules) => rules,
+            None if after.is_empty() => DEFAULT_DST_RULE,
+            None => return None,
+        };
+        let (start, end) = rules.split_once(',')?;
+
+        Some(TimeZone {
+            offset,
+            dst: Some(Dst {
+                offset: dst_offset,
+                start: parse_transition(start)?,
+                end: parse_transition(end)?,
+            }),
+        })
+    }
+
+    /// The router's zone: `spec` if given, else `/etc/TZ`, else `$TZ`.
+    /// Falls back to UTC when none is set or parseable.
+    pub async fn router(spec: Option<&str>) -> TimeZone {
+        let spec = match spec {
+            Some(spec) => Some(spec.to_string()),
+            None => match tokio::fs::read_to_string(TZ_FILE).await {
+                Ok(contents) => Some(contents),
+                Err(_) => std::env::var("TZ").ok(),
+            },
+        };
+        let Some(spec) = spec.filter(|s| !s.trim().is_empty()) else {
+            return TimeZone::UTC;
+        };
+        TimeZone::parse(&spec).unwrap_or_else(|| {
+            warn!(tz = %spec.trim(), "unsupported TZ string, using UTC");
+            TimeZone::UTC
+        })
+    }
+
+    /// Seconds east of UTC in effect at Unix time `utc`
+    pub fn offset_at(&self, utc: i64) -> i64 {
+        let Some(dst) = &self.dst else {
+            return self.offset;
+        };
+        let year = civil_from_days((utc + self.offset).div_euclid(SECS_PER_DAY)).0;
+        // Start is given in standard time, end in daylight time
+        let start = transition_local(&dst.start, year) - self.offset;
+        let end = transition_local(&dst.end, year) - dst.offset;
+        let in_dst = if start < end {
+            (start..end).contains(&utc)
+        } else {
+            // Southern hemisphere: DST spans the new year
+            !(end..start).contains(&utc)
+        };
+        if in_dst { dst.offset } else { self.offset }
+    }
+
+    /// Minute of the week (from Sunday 00:00) in local time at Unix time `utc`
+    pub fn minute_of_week(&self, utc: i64) -> u32 {
+        let local = utc + self.offset_at(utc);
+        let days = local.div_euclid(SECS_PER_DAY);
+        let minute = local.rem_euclid(SECS_PER_DAY) / 60;
+        (weekday(days) * 1440 + minute) as u32
+    }
+}
+
+/// Skip a zone abbreviation: alphabetic, or `<...>` quoted
+fn skip_name(s: &str) -> Option<&str> {
+    if let Some(quoted) = s.strip_prefix('<') {
+        let end = quoted.find('>')?;
+        return (end >= 3).then(|| &quoted[end + 1..]);
+    }
+    let end = s
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(s.len());
+    (end >= 3).then(|| &s[end..])
+}
+
+/// Parse `[+-]hh[:mm[:ss]]` into seconds
+fn parse_offset(s: &str) -> Option<(i64, &str)> {
+    let (sign, s) = match s.as_bytes().first()? {
+        b'-' => (-1, &s[1..]),
+        b'+' => (1, &s[1..]),
+        _ => (1, s),
+    };
+    let end = s
+        .find(|c: char| !c.is_ascii_digit() && c != ':')
+        .unwrap_or(s.len());
+    if end == 0 {
+        return None;
+    }
+    let mut secs = 0;
+    for (i, part) in s[..end].split(':').enumerate() {
+        let value: i64 = part.parse().ok()?;
+        secs += match i {
+            0 if value <= 167 => value * 3600,
+            1 | 2 if value < 60 => value * 60_i64.pow(2 - i as u32),
+            _ => return None,
+        };
+    }
+    Some((sign * secs, &s[end..]))
+}
+
+fn parse_transition(s: &str) -> Option<Transition> {
+    let (date, time) = match s.split_once('/') {
+        Some((date, time)) => {
+            let (secs, rest) = parse_offset(time)?;
+            (date, rest.is_empty().then_some(secs)?)
+        }
+        None => (s, 2 * 3600),
+    };
+    let date = if let Some(mwd) = date.strip_prefix('M') {
+        let mut parts = mwd.split('.').map(|p| p.parse::<u8>().ok());
+        let (Some(Some(month)), Some(Some(week)), Some(Some(weekday)), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return None;
+        };
+        if !(1..=12).contains(&month) || !(1..=5).contains(&week) || weekday > 6 {
+            return None;
+        }
+        TransitionDate::MonthWeekDay {
+            month,
+            week,
+            weekday,
+        }
+    } else if let Some(day) = date.strip_prefix('J') {
+        let day: u16 = day.parse().ok()?;
+        (1..=365)
+            .contains(&day)
+            .then_some(TransitionDate::Julian(day))?
+    } else {
+        let day: u16 = date.parse().ok()?;
+        (day <= 365).then_some(TransitionDate::Ordinal(day))?
+    };
+    Some(Transition { date, time })
+}
+
+/// Local-time seconds since the epoch at which a transition happens in `year`
+fn transition_local(transition: &Transition, year: i64) -> i64 {
+    let jan1 = days_from_civil(year, 1, 1);
+    let day = match transition.date {
+        TransitionDate::Julian(n) => {
+            let leap_shift = i64::from(is_leap(year) && n >= 60);
+            jan1 + i64::from(n) - 1 + leap_shift
+        }
+        TransitionDate::Ordinal(n) => jan1 + i64::from(n),
+        TransitionDate::MonthWeekDay {
+            month,
+            week,
+            weekday: wanted,
+        } => {
+            let first = days_from_civil(year, month.into(), 1);
+            let mut day = first + (i64::from(wanted) - weekday(first)).rem_euclid(7);
+            day += 7 * (i64::from(week) - 1);
+            let next_month = if month == 12 {
+                days_from_civil(year + 1, 1, 1)
+            } else {
+                days_from_civil(year, i64::from(month) + 1, 1)
+            };
+            while day >= next_month {
+                day -= 7;
+            }
+            day
+        }
+    };
+    day * SECS_PER_DAY + transition.time
+}
+
+fn is_leap(year: i64) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+/// Day of the week, 0 = Sunday, of days since the epoch
+fn weekday(days: i64) -> i64 {
+    // 1970-01-01 was a Thursday
+    (days + 4).rem_euclid(7)
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Proleptic Gregorian (year, month, day) of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Unix time of a UTC date and time
+    fn utc(year: i64, month: i64, day: i64, hour: i64, minute: i64) -> i64 {
+        days_from_civil(year, month, day) * SECS_PER_DAY + hour * 3600 + minute * 60
+    }
+
+    #[test]
+    fn civil_dates_roundtrip() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(days_from_civil(2024, 2, 29)), (2024, 2, 29));
+        // 2026-10-18 is a Sunday
+        assert_eq!(weekday(days_from_civil(2026, 10, 18)), 0);
+    }
+
+    #[test]
+    fn parses_fixed_offsets() {
+        assert_eq!(TimeZone::parse("UTC0"), Some(TimeZone::UTC));
+        let ist = TimeZone::parse("IST-5:30").unwrap();
+        assert_eq!(ist.offset_at(0), 5 * 3600 + 1800);
+        let quoted = TimeZone::parse("<+0330>-3:30").unwrap();
+        assert_eq!(quoted.offset_at(0), 3 * 3600 + 1800);
+        assert_eq!(TimeZone::parse("Europe/Berlin"), None);
+        assert_eq!(TimeZone::parse(""), None);
+    }
+
+    #[test]
+    fn central_europe_switches_on_last_sundays() {
+        let cet = TimeZone::parse("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        // DST 2026 runs from 29 March 01:00 UTC to 25 October 01:00 UTC
+        assert_eq!(cet.offset_at(utc(2026, 3, 29, 0, 59)), 3600);
+        assert_eq!(cet.offset_at(utc(2026, 3, 29, 1, 0)), 7200);
+        assert_eq!(cet.offset_at(utc(2026, 10, 25, 0, 59)), 7200);
+        assert_eq!(cet.offset_at(utc(2026, 10, 25, 1, 0)), 3600);
+    }
+
+    #[test]
+    fn us_default_rule_applies_without_dates() {
+        let eastern = TimeZone::parse("EST5EDT").unwrap();
+        // Second Sunday of March 2026 is the 8th, 02:00 EST = 07:00 UTC
+        assert_eq!(eastern.offset_at(utc(2026, 3, 8, 6, 59)), -5 * 3600);
+        assert_eq!(eastern.offset_at(utc(2026, 3, 8, 7, 0)), -4 * 3600);
+    }
+
+    #[test]
+    fn southern_hemisphere_dst_spans_new_year() {
+        let sydney = TimeZone::parse("AEST-10AEDT,M10.1.0,M4.1.0/3").unwrap();
+        assert_eq!(sydney.offset_at(utc(2026, 1, 15, 0, 0)), 11 * 3600);
+        assert_eq!(sydney.offset_at(utc(2026, 7, 15, 0, 0)), 10 * 3600);
+    }
+
+    #[test]
+    fn minute_of_week_uses_local_time() {
+        let cet = TimeZone::parse("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        // Saturday 2026-10-17 23:30 UTC is Sunday 01:30 CEST
+        assert_eq!(cet.minute_of_week(utc(2026, 10, 17, 23, 30)), 90);
+        assert_eq!(
+            TimeZone::UTC.minute_of_week(utc(2026, 10, 17, 23, 30)),
+            6 * 1440 + 23 * 60 + 30
+        );
+    }
+}
@@ -6,6 +6,8 @@
 use ngfw_agent::config::AgentConfig;
 use ngfw_protocol::{AgentMode, MessageType, ModeConfig, RpcMessage};
 use serde_json::json;
+use std::env;
+use std::path::PathBuf;
 use std::time::Duration;
 use tokio::sync::{mpsc, watch};
 use tokio::time::timeout;
@@ -35,6 +37,21 @@ fn test_config() -> AgentConfig {
     }
 }
 
+/// Put the mock firmware binaries first on PATH so applied configs reach
+/// them instead of the host's tools
+fn setup_mock_bins() {
+    let mock_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("integration")
+        .join("mock-bins");
+
+    if let Ok(path) = env::var("PATH") {
+        unsafe {
+            env::set_var("PATH", format!("{}:{}", mock_dir.display(), path));
+        }
+    }
+}
+
 #[tokio::test]
 async fn test_dispatcher_ping_pong() {
     let config = test_config();
@@ -393,6 +410,7 @@ async fn test_dispatcher_config_push_shadow_mode_invalid() {
 
 #[tokio::test]
 async fn test_dispatcher_config_push_takeover_mode() {
+    setup_mock_bins();
     let config = test_config();
     let (inbound_tx, inbound_rx) = mpsc::channel(10);
     let (outbound_tx, mut outbound_rx) = mpsc::channel(10);
@@ -6,6 +6,8 @@
 
 use futures_util::{SinkExt, StreamExt};
 use ngfw_agent::config::{AdaptersSection, AgentConfig, AgentSection, ModeSection};
+use ngfw_agent::connection::LinkStatus;
+use ngfw_agent::outbound::{self, OutboundQueue};
 use ngfw_protocol::{AgentMode, MessageType, ModeConfig, RpcMessage};
 use serde_json::json;
 use std::net::SocketAddr;
@@ -109,13 +111,22 @@ async fn test_e2e_agent_startup_and_shutdown() {
         section_overrides: Default::default(),
     });
     let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (link_tx, link_rx) = watch::channel(LinkStatus::default());
 
     // Spawn all agent components
     let conn_task = tokio::spawn({
         let config = config.clone();
         let shutdown = shutdown_rx.clone();
         async move {
-            ngfw_agent::connection::connection_loop(config, outbound_rx, inbound_tx, shutdown).await
+            ngfw_agent::connection::connection_loop(
+                config,
+                OutboundQueue::new(outbound::SPOOL_DIR),
+                outbound_rx,
+                inbound_tx,
+                link_tx,
+                shutdown,
+            )
+            .await
         }
     });
 
@@ -139,7 +150,7 @@ async fn test_e2e_agent_startup_and_shutdown() {
     let coll_task = tokio::spawn({
         let config = config.clone();
         let shutdown = shutdown_rx.clone();
-        async move { ngfw_agent::collector::metrics_loop(config, outbound_tx, shutdown).await }
+        async move { ngfw_agent::collector::metrics_loop(config, outbound_tx, link_rx, shutdown).await }
     });
 
     // Wait for agent to connect and send initial messages
@@ -194,20 +205,29 @@ async fn test_e2e_metrics_collection_flow() {
     let (outbound_tx, outbound_rx) = mpsc::channel(256);
     let (inbound_tx, _inbound_rx) = mpsc::channel(256);
     let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (link_tx, link_rx) = watch::channel(LinkStatus::default());
 
     // Spawn connection and collector
     tokio::spawn({
         let config = config.clone();
         let shutdown = shutdown_rx.clone();
         async move {
-            ngfw_agent::connection::connection_loop(config, outbound_rx, inbound_tx, shutdown).await
+            ngfw_agent::connection::connection_loop(
+                config,
+                OutboundQueue::new(outbound::SPOOL_DIR),
+                outbound_rx,
+                inbound_tx,
+                link_tx,
+                shutdown,
+            )
+            .await
         }
     });
 
     tokio::spawn({
         let config = config.clone();
         let shutdown = shutdown_rx.clone();
-        async move { ngfw_agent::collector::metrics_loop(config, outbound_tx, shutdown).await }
+        async move { ngfw_agent::collector::metrics_loop(config, outbound_tx, link_rx, shutdown).await }
     });
 
     // Wait for metrics to be collected and sent
@@ -503,13 +523,22 @@ async fn test_e2e_concurrent_operations() {
         section_overrides: Default::default(),
     });
     let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (link_tx, link_rx) = watch::channel(LinkStatus::default());
 
     // Spawn all components
     tokio::spawn({
         let config = config.clone();
         let shutdown = shutdown_rx.clone();
         async move {
-            ngfw_agent::connection::connection_loop(config, outbound_rx, inbound_tx, shutdown).await
+            ngfw_agent::connection::connection_loop(
+                config,
+                OutboundQueue::new(outbound::SPOOL_DIR),
+                outbound_rx,
+                inbound_tx,
+                link_tx,
+                shutdown,
+            )
+            .await
         }
     });
 
@@ -533,7 +562,7 @@ async fn test_e2e_concurrent_operations() {
     tokio::spawn({
         let config = config.clone();
         let shutdown = shutdown_rx.clone();
-        async move { ngfw_agent::collector::metrics_loop(config, outbound_tx, shutdown).await }
+        async move { ngfw_agent::collector::metrics_loop(config, outbound_tx, link_rx, shutdown).await }
     });
 
     // Wait for system to stabilize
@@ -4,6 +4,7 @@
 //! proper formatting of telemetry data sent to the API.
 
 use ngfw_agent::config::AgentConfig;
+use ngfw_agent::connection::LinkStatus;
 use ngfw_protocol::{MessageType, MetricsPayload};
 use std::time::Duration;
 use tokio::sync::{mpsc, watch};
@@ -33,16 +34,26 @@ fn test_config() -> AgentConfig {
     }
 }
 
+/// Link status for a collector whose connection is up, so samples are sent
+/// live rather than buffered. Keep the sender alive for the test duration.
+fn authenticated_link() -> (watch::Sender<LinkStatus>, watch::Receiver<LinkStatus>) {
+    watch::channel(LinkStatus {
+        authenticated: true,
+    })
+}
+
 #[tokio::test]
 async fn test_metrics_loop_basic_operation() {
     let config = test_config();
     let (outbound_tx, mut outbound_rx) = mpsc::channel(10);
     let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (_link_tx, link_rx) = authenticated_link();
 
     // Spawn metrics collector
     let collector_task = tokio::spawn(ngfw_agent::collector::metrics_loop(
         config,
         outbound_tx,
+        link_rx,
         shutdown_rx,
     ));
 
@@ -79,10 +90,12 @@ async fn test_metrics_loop_interval_timing() {
     let config = test_config();
     let (outbound_tx, mut outbound_rx) = mpsc::channel(10);
     let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (_link_tx, link_rx) = authenticated_link();
 
     tokio::spawn(ngfw_agent::collector::metrics_loop(
         config,
         outbound_tx,
+        link_rx,
         shutdown_rx,
     ));
 
@@ -121,10 +134,12 @@ async fn test_metrics_cpu_percentage_valid() {
     let config = test_config();
     let (outbound_tx, mut outbound_rx) = mpsc::channel(10);
     let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (_link_tx, link_rx) = authenticated_link();
 
     tokio::spawn(ngfw_agent::collector::metrics_loop(
         config,
         outbound_tx,
+        link_rx,
         shutdown_rx,
     ));
 
@@ -153,10 +168,12 @@ async fn test_metrics_memory_percentage_valid() {
     let config = test_config();
     let (outbound_tx, mut outbound_rx) = mpsc::channel(10);
     let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (_link_tx, link_rx) = authenticated_link();
 
     tokio::spawn(ngfw_agent::collector::metrics_loop(
         config,
         outbound_tx,
+        link_rx,
         shutdown_rx,
     ));
 
@@ -181,10 +198,12 @@ async fn test_metrics_temperature_optional() {
     let config = test_config();
     let (outbound_tx, mut outbound_rx) = mpsc::channel(10);
     let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (_link_tx, link_rx) = authenticated_link();
 
     tokio::spawn(ngfw_agent::collector::metrics_loop(
         config,
         outbound_tx,
+        link_rx,
         shutdown_rx,
     ));
 
@@ -207,10 +226,12 @@ async fn test_metrics_interfaces_structure() {
     let config = test_config();
     let (outbound_tx, mut outbound_rx) = mpsc::channel(10);
     let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (_link_tx, link_rx) = authenticated_link();
 
     tokio::spawn(ngfw_agent::collector::metrics_loop(
         config,
         outbound_tx,
+        link_rx,
         shutdown_rx,
     ));
 
@@ -241,10 +262,12 @@ async fn test_metrics_connections_count() {
     let config = test_config();
     let (outbound_tx, mut outbound_rx) = mpsc::channel(10);
     let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (_link_tx, link_rx) = authenticated_link();
 
     tokio::spawn(ngfw_agent::collector::metrics_loop(
         config,
         outbound_tx,
+        link_rx,
         shutdown_rx,
     ));
 
@@ -270,10 +293,12 @@ async fn test_metrics_dns_structure() {
     let config = test_config();
     let (outbound_tx, mut outbound_rx) = mpsc::channel(10);
     let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (_link_tx, link_rx) = authenticated_link();
 
     tokio::spawn(ngfw_agent::collector::metrics_loop(
         config,
         outbound_tx,
+        link_rx,
         shutdown_rx,
     ));
 
@@ -298,10 +323,12 @@ async fn test_metrics_shutdown_cleanup() {
     let config = test_config();
     let (outbound_tx, mut outbound_rx) = mpsc::channel(10);
     let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (_link_tx, link_rx) = authenticated_link();
 
     let collector_task = tokio::spawn(ngfw_agent::collector::metrics_loop(
         config,
         outbound_tx,
+        link_rx,
         shutdown_rx,
     ));
 
@@ -321,10 +348,12 @@ async fn test_metrics_channel_closed_handling() {
     let config = test_config();
     let (outbound_tx, outbound_rx) = mpsc::channel(10);
     let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (_link_tx, link_rx) = authenticated_link();
 
     let collector_task = tokio::spawn(ngfw_agent::collector::metrics_loop(
         config,
         outbound_tx,
+        link_rx,
         shutdown_rx,
     ));
 
@@ -344,10 +373,12 @@ async fn test_metrics_multiple_collections() {
     let config = test_config();
     let (outbound_tx, mut outbound_rx) = mpsc::channel(20);
     let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (_link_tx, link_rx) = authenticated_link();
 
     tokio::spawn(ngfw_agent::collector::metrics_loop(
         config,
         outbound_tx,
+        link_rx,
         shutdown_rx,
     ));
 
@@ -392,10 +423,12 @@ async fn test_metrics_serialization_roundtrip() {
     let config = test_config();
     let (outbound_tx, mut outbound_rx) = mpsc::channel(10);
     let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (_link_tx, link_rx) = authenticated_link();
 
     tokio::spawn(ngfw_agent::collector::metrics_loop(
         config,
         outbound_tx,
+        link_rx,
         shutdown_rx,
     ));
 
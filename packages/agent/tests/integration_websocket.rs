@@ -4,6 +4,8 @@
 //! keepalive, reconnection, and protocol compliance.
 
 use futures_util::{SinkExt, StreamExt};
+use ngfw_agent::connection::LinkStatus;
+use ngfw_agent::outbound::{self, OutboundQueue};
 use ngfw_protocol::{AuthRequest, MessageType, RpcMessage};
 use serde_json::json;
 use std::net::SocketAddr;
@@ -78,6 +80,7 @@ async fn test_connection_auth_handshake_success() {
     let (_outbound_tx, outbound_rx) = mpsc::channel(10);
     let (inbound_tx, _inbound_rx) = mpsc::channel::<RpcMessage>(10);
     let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (link_tx, _link_rx) = watch::channel(LinkStatus::default());
 
     // Spawn a mock WebSocket server that accepts auth
     let server_task = tokio::spawn(async move {
@@ -116,8 +119,10 @@ async fn test_connection_auth_handshake_success() {
     // Start connection loop
     let conn_task = tokio::spawn(ngfw_agent::connection::connection_loop(
         config,
+        OutboundQueue::new(outbound::SPOOL_DIR),
         outbound_rx,
         inbound_tx,
+        link_tx,
         shutdown_rx,
     ));
 
@@ -0,0 +1,145 @@
+//! Agent config sections compiled from the API's stored sections
+//!
+//! Firewall settings are stored as several sections (`firewall_rules`,
+//! `firewall_zones`, `address_groups`, `port_groups`) so each can be edited
+//! and versioned on its own, but agents apply a single `firewall` section
+//! holding all of them (see [`ConfigSection`]). A write to any of them
+//! recompiles the whole `firewall` section and pushes that instead.
+//!
+//! The section also carries the networks of every country the rules match
+//! on, taken from the device's GeoIP dataset, and of every enabled threat
+//! feed. Feed subscriptions themselves are never pushed: agents only see
+//! them once their networks have been fetched.
+//!
+//! Likewise `nat_rules` reaches agents as the `nat` section, shaped
+//! `{ "rules": [NatRule] }`.
+//!
+//! [`ConfigSection`]: crate::models::rpc::ConfigSection
+
+use crate::models::security::{GeoIpSet, ThreatFeedSet};
+use serde::Serialize;
+use serde_json::Value;
+
+/// Stored sections the agent's `firewall` section is compiled from
+pub const FIREWALL_SOURCES: &[&str] = &[
+    "firewall_rules",
+    "firewall_zones",
+    "address_groups",
+    "port_groups",
+];
+
+/// Stored section the agent's `nat` section is compiled from
+pub const NAT_SOURCE: &str = "nat_rules";
+
+/// Stored sections that are never pushed as they are
+pub const STORED_ONLY: &[&str] = &["threat_feeds"];
+
+/// The `firewall` section as the agent's iptables adapter reads it
+#[derive(Debug, Default, Serialize)]
+pub struct FirewallSection {
+    pub zones: Vec<Value>,
+    pub rules: Vec<Value>,
+    pub address_groups: Vec<Value>,
+    pub port_groups: Vec<Value>,
+    pub countries: Vec<GeoIpSet>,
+    pub threat_feeds: Vec<ThreatFeedSet>,
+}
+
+impl FirewallSection {
+    /// Replace the part compiled from `section` with `value`, e.g. with a
+    /// write that KV may not return yet
+    pub fn set(&mut self, section: &str, value: Value) {
+        let Value::Array(value) = value else {
+            return;
+        };
+        match section {
+            "firewall_rules" => self.rules = value,
+            "firewall_zones" => self.zones = value,
+            "address_groups" => self.address_groups = value,
+            "port_groups" => self.port_groups = value,
+            _ => {}
+        }
+    }
+}
+
+/// The `nat` section as the agent's NAT adapter reads it
+#[derive(Debug, Default, Serialize)]
+pub struct NatSection {
+    pub rules: Vec<Value>,
+}
+
+/// `CONFIG_PUSH` command for the device's `AgentConnection`
+pub fn push_message<T: Serialize>(section: &str, config: &T, version: u64) -> Value {
+    serde_json::json!({
+        "type": "CONFIG_PUSH",
+        "payload": {
+            "section": section,
+            "config": config,
+            "version": version
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::rpc::{ConfigPush, ConfigSection};
+    use serde_json::json;
+
+    #[test]
+    fn test_firewall_push_parses_as_agent_config_push() {
+        let mut section = FirewallSection {
+            zones: vec![json!({ "id": "WAN", "name": "WAN", "interfaces": ["eth0"] })],
+            rules: vec![json!({ "id": 1, "action": "drop", "source_group": 2 })],
+            address_groups: vec![json!({ "id": 2, "members": ["192.0.2.0/24"] })],
+            port_groups: vec![json!({ "id": 3, "ports": ["443"] })],
+            countries: vec![GeoIpSet {
+                code: "NL".to_string(),
+                hash: "a1".to_string(),
+                networks: vec!["198.51.100.0/24".to_string()],
+            }],
+            threat_feeds: vec![ThreatFeedSet {
+                id: 5,
+                hash: "b2".to_string(),
+                networks: vec!["203.0.113.0/24".to_string()],
+            }],
+        };
+        section.set("firewall_rules", json!([{ "id": 4, "action": "accept" }]));
+
+        let message = push_message("firewall", &section, 7);
+        assert_eq!(message["type"], "CONFIG_PUSH");
+
+        let push: ConfigPush = serde_json::from_value(message["payload"].clone()).unwrap();
+        assert_eq!(push.section, ConfigSection::Firewall);
+        assert_eq!(push.version, 7);
+        assert_eq!(push.config["zones"][0]["interfaces"], json!(["eth0"]));
+        assert_eq!(
+            push.config["rules"],
+            json!([{ "id": 4, "action": "accept" }])
+        );
+        assert_eq!(push.config["address_groups"][0]["id"], 2);
+        assert_eq!(push.config["port_groups"][0]["ports"], json!(["443"]));
+        assert_eq!(push.config["countries"][0]["code"], "NL");
+        assert_eq!(
+            push.config["countries"][0]["networks"],
+            json!(["198.51.100.0/24"])
+        );
+        assert_eq!(push.config["threat_feeds"][0]["id"], 5);
+        assert_eq!(
+            push.config["threat_feeds"][0]["networks"],
+            json!(["203.0.113.0/24"])
+        );
+    }
+
+    #[test]
+    fn test_nat_push_parses_as_agent_config_push() {
+        let section = NatSection {
+            rules: vec![json!({ "id": 1, "nat_type": "dnat", "translate_to": "192.168.1.10" })],
+        };
+
+        let message = push_message("nat", &section, 2);
+        let push: ConfigPush = serde_json::from_value(message["payload"].clone()).unwrap();
+        assert_eq!(push.section, ConfigSection::Nat);
+        assert_eq!(push.config["rules"][0]["nat_type"], "dnat");
+    }
+}
@@ -0,0 +1,119 @@
+//! IPv4 network lists
+//!
+//! GeoIP datasets and threat feeds arrive as long lists of addresses,
+//! networks and ranges that overlap and abut. They are reduced to the
+//! fewest CIDR blocks that cover exactly the same addresses before being
+//! stored and pushed to agents as ipsets, and identified by a hash of that
+//! list so a list that has not changed is never rewritten or pushed again.
+
+use std::net::Ipv4Addr;
+
+/// Inclusive range of addresses covered by an IPv4 address, `a.b.c.d/n`
+/// network or `start-end` range
+pub fn parse_range(item: &str) -> Option<(u32, u32)> {
+    let item = item.trim();
+    if let Some((start, end)) = item.split_once('-') {
+        let start = u32::from(start.trim().parse::<Ipv4Addr>().ok()?);
+        let end = u32::from(end.trim().parse::<Ipv4Addr>().ok()?);
+        return (start <= end).then_some((start, end));
+    }
+    let (addr, prefix) = match item.split_once('/') {
+        Some((addr, prefix)) => (addr, prefix.parse::<u32>().ok().filter(|p| *p <= 32)?),
+        None => (item, 32),
+    };
+    let addr = u32::from(addr.parse::<Ipv4Addr>().ok()?);
+    let host_bits = u32::MAX.checked_shr(prefix).unwrap_or(0);
+    Some((addr & !host_bits, addr | host_bits))
+}
+
+/// The fewest CIDR blocks covering exactly the union of `ranges`, in
+/// address order
+pub fn aggregate(mut ranges: Vec<(u32, u32)>) -> Vec<String> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::new();
+    for (start, end) in ranges {
+        let (start, end) = (u64::from(start), u64::from(end));
+        match merged.last_mut() {
+            Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    let mut blocks = Vec::new();
+    for (mut start, end) in merged {
+        while start <= end {
+            // Largest block aligned at `start` that does not pass `end`
+            let mut size = if start == 0 {
+                1u64 << 32
+            } else {
+                1u64 << start.trailing_zeros()
+            };
+            while size > end - start + 1 {
+                size >>= 1;
+            }
+            let prefix = 32 - size.trailing_zeros();
+            blocks.push(format!("{}/{}", Ipv4Addr::from(start as u32), prefix));
+            start += size;
+        }
+    }
+    blocks
+}
+
+/// Version hash of a network list: 64-bit FNV-1a, in hex
+pub fn list_hash(networks: &[String]) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for network in networks {
+        for byte in network.bytes().chain(std::iter::once(b'\n')) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overlapping_and_adjacent_ranges_aggregate() {
+        let ranges = [
+            "10.0.0.0/25",
+            "10.0.0.128/25",
+            "10.0.1.0/24",
+            "10.0.0.7",
+            "192.168.0.1-192.168.0.6",
+        ]
+        .iter()
+        .map(|item| parse_range(item).unwrap())
+        .collect();
+        assert_eq!(
+            aggregate(ranges),
+            [
+                "10.0.0.0/23",
+                "192.168.0.1/32",
+                "192.168.0.2/31",
+                "192.168.0.4/31",
+                "192.168.0.6/32"
+            ]
+        );
+        assert_eq!(aggregate(vec![(0, u32::MAX)]), ["0.0.0.0/0"]);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("10.1.2.3/8"), Some((0x0a00_0000, 0x0aff_ffff)));
+        assert_eq!(parse_range("0.0.0.0/0"), Some((0, u32::MAX)));
+        assert_eq!(parse_range("10.0.0.9-10.0.0.1"), None);
+        assert_eq!(parse_range("2001:db8::/32"), None);
+    }
+
+    #[test]
+    fn test_list_hash_changes_with_content() {
+        let a = vec!["10.0.0.0/8".to_string()];
+        let b = vec!["10.0.0.0/9".to_string()];
+        assert_eq!(list_hash(&a), list_hash(&a.clone()));
+        assert_ne!(list_hash(&a), list_hash(&b));
+        assert_eq!(list_hash(&a).len(), 16);
+    }
+}
@@ -0,0 +1,671 @@
+//! Firewall rule shadowing and redundancy analysis
+//!
+//! Each enabled rule is turned into the space of packets it matches: zones,
+//! source and destination addresses, protocols, destination ports and the
+//! minutes of the week its schedule is active. Rules are compared pairwise
+//! in evaluation order:
+//!
+//! - **shadowed**: an earlier rule matches everything a later one does, with
+//!   a different action, so the later rule never applies
+//! - **redundant**: the same, but with the same action, or an earlier rule
+//!   that a later, broader one with the same action would handle anyway
+//! - **conflicting**: two rules overlap partly and one accepts what the
+//!   other blocks, so their order decides the outcome
+//! - **mergeable**: two rules with the same action differ in only one of
+//!   source, destination or port and could be written as one
+//!
+//! Only `accept`, `drop` and `reject` end evaluation; `limit` and `shape`
+//! rules are compared against but never shadow. Members of address and port
+//! groups and the networks of countries are not known here, so a rule
+//! referencing a group or country is taken to match some unknown part of
+//! its other fields: it can be shadowed, but is never reported as covering,
+//! overlapping or mergeable with another rule.
+//! Rules whose fields cannot be parsed are skipped. Every finding is a
+//! warning.
+
+use crate::models::lint::{LintIssue, LintSeverity};
+use crate::models::security::{FirewallRule, Protocol, RuleAction, RuleSchedule, Zone};
+use std::net::IpAddr;
+
+const MINUTES_PER_DAY: u128 = 24 * 60;
+const MINUTES_PER_WEEK: u128 = 7 * MINUTES_PER_DAY;
+
+/// A set of integers stored as sorted, disjoint, inclusive ranges
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IntervalSet(Vec<(u128, u128)>);
+
+impl IntervalSet {
+    pub fn new(mut ranges: Vec<(u128, u128)>) -> Self {
+        ranges.retain(|(start, end)| start <= end);
+        ranges.sort();
+        let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        Self(merged)
+    }
+
+    /// Every value from 0 to `max`
+    pub fn full(max: u128) -> Self {
+        Self(vec![(0, max)])
+    }
+
+    pub fn contains(&self, value: u128) -> bool {
+        self.0
+            .iter()
+            .any(|(start, end)| *start <= value && value <= *end)
+    }
+
+    pub fn is_subset(&self, other: &Self) -> bool {
+        self.0.iter().all(|(start, end)| {
+            other
+                .0
+                .iter()
+                .any(|(o_start, o_end)| o_start <= start && end <= o_end)
+        })
+    }
+
+    pub fn intersects(&self, other: &Self) -> bool {
+        self.0.iter().any(|(start, end)| {
+            other
+                .0
+                .iter()
+                .any(|(o_start, o_end)| start <= o_end && o_start <= end)
+        })
+    }
+
+    /// Values from 0 to `max` not in the set
+    pub fn complement(&self, max: u128) -> Self {
+        let mut ranges = Vec::new();
+        let mut next = 0u128;
+        for (start, end) in &self.0 {
+            if *start > next {
+                ranges.push((next, start - 1));
+            }
+            match end.checked_add(1) {
+                Some(after) => next = after,
+                None => return Self(ranges),
+            }
+        }
+        if next <= max {
+            ranges.push((next, max));
+        }
+        Self(ranges)
+    }
+}
+
+/// An address as a point in the IPv6 space, IPv4 mapped to `::ffff:0:0/96`
+pub fn address_value(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(v4) => u128::from(v4.to_ipv6_mapped()),
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+/// Parse an address match (see `models::validation::address_spec`)
+pub fn parse_addresses(spec: &str) -> Option<IntervalSet> {
+    let spec = spec.trim();
+    if matches!(spec, "" | "*" | "any") {
+        return Some(IntervalSet::full(u128::MAX));
+    }
+    let (negated, list) = match spec.strip_prefix('!') {
+        Some(list) => (true, list),
+        None => (false, spec),
+    };
+    let mut ranges = Vec::new();
+    for item in list.split(',').map(str::trim) {
+        let range = match item.split_once('-') {
+            Some((start, end)) => {
+                let start: IpAddr = start.trim().parse().ok()?;
+                let end: IpAddr = end.trim().parse().ok()?;
+                (address_value(start), address_value(end))
+            }
+            None => {
+                let (addr, prefix) = match item.split_once('/') {
+                    Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix)),
+                    None => (item.parse::<IpAddr>().ok()?, None),
+                };
+                let bits = match addr {
+                    IpAddr::V4(_) => 32,
+                    IpAddr::V6(_) => 128,
+                };
+                let prefix: u32 = match prefix {
+                    Some(prefix) => prefix.parse().ok().filter(|p| *p <= bits)?,
+                    None => bits,
+                };
+                let host_bits = bits - prefix;
+                let host_mask = u128::MAX.checked_shr(128 - host_bits).unwrap_or(0);
+                let start = address_value(addr) & !host_mask;
+                (start, start | host_mask)
+            }
+        };
+        ranges.push(range);
+    }
+    let set = IntervalSet::new(ranges);
+    Some(if negated {
+        set.complement(u128::MAX)
+    } else {
+        set
+    })
+}
+
+/// Parse a port match (see `models::validation::port_spec`)
+pub fn parse_ports(spec: &str) -> Option<IntervalSet> {
+    let spec = spec.trim();
+    if matches!(spec, "" | "*" | "any") {
+        return Some(IntervalSet::full(65535));
+    }
+    let mut ranges = Vec::new();
+    for item in spec.split(',').map(str::trim) {
+        let (start, end) = item.split_once(['-', ':']).unwrap_or((item, item));
+        ranges.push((
+            u128::from(start.trim().parse::<u16>().ok()?),
+            u128::from(end.trim().parse::<u16>().ok()?),
+        ));
+    }
+    Some(IntervalSet::new(ranges))
+}
+
+fn parse_minutes(time: &str) -> Option<u128> {
+    let (hours, minutes) = time.split_once(':')?;
+    let (hours, minutes): (u128, u128) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+/// Minutes of the week (from Sunday 00:00) a schedule is active.
+///
+/// No schedule, or a disabled one, is always active. A window ending at or
+/// before its start runs past midnight into the next day.
+pub fn schedule_minutes(schedule: Option<&RuleSchedule>) -> Option<IntervalSet> {
+    let Some(schedule) = schedule.filter(|s| s.enabled) else {
+        return Some(IntervalSet::full(MINUTES_PER_WEEK - 1));
+    };
+    let start = parse_minutes(&schedule.start)?;
+    let end = parse_minutes(&schedule.end)?;
+    let length = if end > start {
+        end - start
+    } else {
+        end + MINUTES_PER_DAY - start
+    };
+    let mut ranges = Vec::new();
+    for day in &schedule.days {
+        let from = u128::from(*day) * MINUTES_PER_DAY + start;
+        let to = from + length - 1;
+        if to < MINUTES_PER_WEEK {
+            ranges.push((from, to));
+        } else {
+            // Saturday night runs into Sunday morning
+            ranges.push((from, MINUTES_PER_WEEK - 1));
+            ranges.push((0, to - MINUTES_PER_WEEK));
+        }
+    }
+    Some(IntervalSet::new(ranges))
+}
+
+/// Minute of the week (from Sunday 00:00) of a day and `HH:MM` time
+pub fn minute_of_week(day: u8, time: &str) -> Option<u128> {
+    (day < 7).then_some(u128::from(day) * MINUTES_PER_DAY + parse_minutes(time)?)
+}
+
+/// Whether a zone matches any zone
+pub fn is_any_zone(zone: &Zone) -> bool {
+    matches!(zone, Zone::Custom(name) if name == "*" || name.eq_ignore_ascii_case("any"))
+}
+
+fn zone_subset(a: &Zone, b: &Zone) -> bool {
+    is_any_zone(b) || a == b
+}
+
+fn zones_intersect(a: &Zone, b: &Zone) -> bool {
+    is_any_zone(a) || is_any_zone(b) || a == b
+}
+
+const PROTOCOLS: [Protocol; 7] = [
+    Protocol::Tcp,
+    Protocol::Udp,
+    Protocol::Icmp,
+    Protocol::Icmpv6,
+    Protocol::Gre,
+    Protocol::Esp,
+    Protocol::Ah,
+];
+
+/// Protocols a rule matches, as a bit per entry of [`PROTOCOLS`]
+fn protocol_bits(protocol: &Protocol) -> u8 {
+    match PROTOCOLS.iter().position(|p| p == protocol) {
+        Some(i) => 1 << i,
+        None => (1 << PROTOCOLS.len()) - 1,
+    }
+}
+
+/// The packets a rule matches
+#[derive(Debug, Clone)]
+struct MatchSpace {
+    zone_from: Zone,
+    zone_to: Zone,
+    source: IntervalSet,
+    destination: IntervalSet,
+    protocols: u8,
+    ports: IntervalSet,
+    time: IntervalSet,
+    /// Narrowed further by an address or port group, or by countries
+    grouped: bool,
+}
+
+impl MatchSpace {
+    fn from_rule(rule: &FirewallRule) -> Option<Self> {
+        Some(Self {
+            zone_from: rule.zone_from.clone(),
+            zone_to: rule.zone_to.clone(),
+            source: parse_addresses(&rule.source)?,
+            destination: parse_addresses(&rule.destination)?,
+            protocols: protocol_bits(&rule.protocol),
+            ports: parse_ports(&rule.port)?,
+            time: schedule_minutes(rule.schedule.as_ref())?,
+            grouped: rule.source_group.is_some()
+                || rule.destination_group.is_some()
+                || rule.port_group.is_some()
+                || !rule.source_countries.is_empty()
+                || !rule.destination_countries.is_empty(),
+        })
+    }
+
+    fn is_subset(&self, other: &Self) -> bool {
+        zone_subset(&self.zone_from, &other.zone_from)
+            && zone_subset(&self.zone_to, &other.zone_to)
+            && self.source.is_subset(&other.source)
+            && self.destination.is_subset(&other.destination)
+            && self.protocols & !other.protocols == 0
+            && self.ports.is_subset(&other.ports)
+            && self.time.is_subset(&other.time)
+    }
+
+    fn intersects(&self, other: &Self) -> bool {
+        zones_intersect(&self.zone_from, &other.zone_from)
+            && zones_intersect(&self.zone_to, &other.zone_to)
+            && self.source.intersects(&other.source)
+            && self.destination.intersects(&other.destination)
+            && self.protocols & other.protocols != 0
+            && self.ports.intersects(&other.ports)
+            && self.time.intersects(&other.time)
+    }
+
+    /// The single field in which two spaces differ, if all others are equal
+    fn only_difference(&self, other: &Self) -> Option<&'static str> {
+        let same_rest = self.zone_from == other.zone_from
+            && self.zone_to == other.zone_to
+            && self.protocols == other.protocols
+            && self.time == other.time;
+        if !same_rest {
+            return None;
+        }
+        match (
+            self.source == other.source,
+            self.destination == other.destination,
+            self.ports == other.ports,
+        ) {
+            (false, true, true) => Some("source"),
+            (true, false, true) => Some("destination"),
+            (true, true, false) => Some("port"),
+            _ => None,
+        }
+    }
+}
+
+fn is_terminal(action: &RuleAction) -> bool {
+    matches!(
+        action,
+        RuleAction::Accept | RuleAction::Drop | RuleAction::Reject
+    )
+}
+
+fn accepts(action: &RuleAction) -> bool {
+    !matches!(action, RuleAction::Drop | RuleAction::Reject)
+}
+
+fn describe(rule: &FirewallRule) -> String {
+    format!("rule {} '{}'", rule.id, rule.name)
+}
+
+fn field_value<'a>(rule: &'a FirewallRule, field: &str) -> &'a str {
+    match field {
+        "source" => &rule.source,
+        "destination" => &rule.destination,
+        _ => &rule.port,
+    }
+}
+
+/// Analyze rules in evaluation order
+pub fn analyze(rules: &[FirewallRule]) -> Vec<LintIssue> {
+    let spaces: Vec<Option<MatchSpace>> = rules
+        .iter()
+        .map(|rule| rule.enabled.then(|| MatchSpace::from_rule(rule)).flatten())
+        .collect();
+    let warning = |code: &str, message: String, index: usize| LintIssue {
+        severity: LintSeverity::Warning,
+        code: code.to_string(),
+        message,
+        sections: vec!["firewall_rules".to_string()],
+        field: Some(format!("firewall_rules[{}]", index)),
+    };
+
+    let mut issues = Vec::new();
+    for (j, later) in rules.iter().enumerate() {
+        let Some(later_space) = &spaces[j] else {
+            continue;
+        };
+        let earlier = (0..j).filter_map(|i| Some((i, &rules[i], spaces[i].as_ref()?)));
+
+        let mut shadowed = false;
+        let mut conflict: Option<usize> = None;
+        for (i, rule, space) in earlier {
+            if !is_terminal(&rule.action) {
+                continue;
+            }
+            if !space.grouped && later_space.is_subset(space) {
+                let (code, reason) = if rule.action == later.action {
+                    ("firewall_rule_redundant", "already handles")
+                } else {
+                    ("firewall_rule_shadowed", "overrides")
+                };
+                issues.push(warning(
+                    code,
+                    format!(
+                        "{} never matches: earlier {} {} all of its traffic",
+                        describe(later),
+                        describe(rule),
+                        reason
+                    ),
+                    j,
+                ));
+                shadowed = true;
+                break;
+            }
+            if conflict.is_none()
+                && !space.grouped
+                && !later_space.grouped
+                && is_terminal(&later.action)
+                && accepts(&rule.action) != accepts(&later.action)
+                && later_space.intersects(space)
+                && !space.is_subset(later_space)
+            {
+                conflict = Some(i);
+            }
+        }
+        if shadowed {
+            continue;
+        }
+        if let Some(i) = conflict {
+            issues.push(warning(
+                "firewall_rule_conflict",
+                format!(
+                    "{} partly overlaps earlier {} with the opposite action; \
+                     traffic matching both follows {}",
+                    describe(later),
+                    describe(&rules[i]),
+                    describe(&rules[i])
+                ),
+                j,
+            ));
+        }
+    }
+
+    for (i, rule) in rules.iter().enumerate() {
+        let Some(space) = &spaces[i] else {
+            continue;
+        };
+        for (j, later) in rules.iter().enumerate().skip(i + 1) {
+            let Some(later_space) = &spaces[j] else {
+                continue;
+            };
+            if rule.action != later.action || !is_terminal(&rule.action) || later_space.grouped {
+                continue;
+            }
+            // Moving this rule's traffic to the later rule must not let a
+            // rule in between with another action see it first
+            let blocked = (i + 1..j).any(|k| {
+                rules[k].action != rule.action
+                    && spaces[k].as_ref().is_some_and(|s| s.intersects(space))
+            });
+            if blocked {
+                continue;
+            }
+            if space.is_subset(later_space) && !later_space.is_subset(space) {
+                issues.push(warning(
+                    "firewall_rule_redundant",
+                    format!(
+                        "{} is covered by later {} with the same action and can be removed",
+                        describe(rule),
+                        describe(later)
+                    ),
+                    i,
+                ));
+                break;
+            }
+            if let Some(field) = space.only_difference(later_space)
+                && !space.grouped
+                && !space.is_subset(later_space)
+                && !later_space.is_subset(space)
+                && !field_value(rule, field).starts_with('!')
+                && !field_value(later, field).starts_with('!')
+            {
+                issues.push(warning(
+                    "firewall_rules_mergeable",
+                    format!(
+                        "{} and {} differ only in {} and could be one rule with {} \"{},{}\"",
+                        describe(rule),
+                        describe(later),
+                        field,
+                        field,
+                        field_value(rule, field),
+                        field_value(later, field)
+                    ),
+                    i,
+                ));
+            }
+        }
+    }
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(id: u32, action: RuleAction, source: &str, port: &str) -> FirewallRule {
+        FirewallRule {
+            id,
+            name: format!("r{}", id),
+            enabled: true,
+            zone_from: Zone::Wan,
+            zone_to: Zone::Lan,
+            source: source.to_string(),
+            destination: "any".to_string(),
+            protocol: Protocol::Tcp,
+            port: port.to_string(),
+            action,
+            schedule: None,
+            log: false,
+            source_group: None,
+            destination_group: None,
+            port_group: None,
+            source_countries: Vec::new(),
+            destination_countries: Vec::new(),
+            hits: None,
+            last_hit: None,
+            schedule_active: None,
+        }
+    }
+
+    fn codes(issues: &[LintIssue]) -> Vec<(&str, &str)> {
+        issues
+            .iter()
+            .map(|i| (i.code.as_str(), i.field.as_deref().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_interval_sets() {
+        let set = IntervalSet::new(vec![(5, 10), (1, 3), (4, 4), (20, 30)]);
+        assert_eq!(set, IntervalSet::new(vec![(1, 10), (20, 30)]));
+        assert!(IntervalSet::new(vec![(2, 8)]).is_subset(&set));
+        assert!(!IntervalSet::new(vec![(8, 12)]).is_subset(&set));
+        assert!(IntervalSet::new(vec![(8, 12)]).intersects(&set));
+        assert_eq!(
+            set.complement(40),
+            IntervalSet::new(vec![(0, 0), (11, 19), (31, 40)])
+        );
+        assert_eq!(
+            IntervalSet::full(u128::MAX).complement(u128::MAX),
+            IntervalSet::new(vec![])
+        );
+    }
+
+    #[test]
+    fn test_address_and_port_parsing() {
+        let lan = parse_addresses("192.168.1.0/24").unwrap();
+        assert!(
+            parse_addresses("192.168.1.10-192.168.1.20")
+                .unwrap()
+                .is_subset(&lan)
+        );
+        assert!(parse_addresses("192.168.1.7").unwrap().is_subset(&lan));
+        assert!(!parse_addresses("192.168.2.7").unwrap().intersects(&lan));
+        assert!(!parse_addresses("!192.168.1.0/24").unwrap().intersects(&lan));
+        assert!(!parse_addresses("fd00::/8").unwrap().intersects(&lan));
+        assert!(parse_addresses("wan").is_none());
+        assert!(
+            parse_ports("80,443")
+                .unwrap()
+                .is_subset(&parse_ports("1-1024").unwrap())
+        );
+        assert!(parse_ports("8000:8080").unwrap().contains(8080));
+    }
+
+    #[test]
+    fn test_schedule_wraps_past_midnight() {
+        let night = RuleSchedule {
+            enabled: true,
+            start: "22:00".to_string(),
+            end: "06:00".to_string(),
+            days: vec![6],
+        };
+        let minutes = schedule_minutes(Some(&night)).unwrap();
+        assert!(minutes.contains(6 * MINUTES_PER_DAY + 23 * 60));
+        assert!(minutes.contains(5 * 60));
+        assert!(!minutes.contains(7 * 60));
+    }
+
+    #[test]
+    fn test_shadowed_and_redundant() {
+        let rules = vec![
+            rule(1, RuleAction::Drop, "10.0.0.0/8", "any"),
+            rule(2, RuleAction::Accept, "10.1.0.0/16", "22"),
+            rule(3, RuleAction::Drop, "10.2.0.0/16", "any"),
+        ];
+        assert_eq!(
+            codes(&analyze(&rules)),
+            [
+                ("firewall_rule_shadowed", "firewall_rules[1]"),
+                ("firewall_rule_redundant", "firewall_rules[2]")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exceptions_before_general_rules_are_fine() {
+        let rules = vec![
+            rule(1, RuleAction::Accept, "10.1.0.0/16", "22"),
+            rule(2, RuleAction::Drop, "10.0.0.0/8", "any"),
+        ];
+        assert!(analyze(&rules).is_empty());
+    }
+
+    #[test]
+    fn test_earlier_rule_covered_by_later() {
+        let rules = vec![
+            rule(1, RuleAction::Drop, "10.1.0.0/16", "any"),
+            rule(2, RuleAction::Drop, "10.0.0.0/8", "any"),
+        ];
+        assert_eq!(
+            codes(&analyze(&rules)),
+            [("firewall_rule_redundant", "firewall_rules[0]")]
+        );
+
+        // Not when an exception in between would then apply
+        let rules = vec![
+            rule(1, RuleAction::Drop, "10.1.0.0/16", "any"),
+            rule(2, RuleAction::Accept, "10.1.2.0/24", "22"),
+            rule(3, RuleAction::Drop, "10.0.0.0/8", "any"),
+        ];
+        assert_eq!(
+            codes(&analyze(&rules)),
+            [("firewall_rule_shadowed", "firewall_rules[1]")]
+        );
+    }
+
+    #[test]
+    fn test_conflicts_and_merges() {
+        let rules = vec![
+            rule(1, RuleAction::Accept, "10.0.0.0/24", "80"),
+            rule(2, RuleAction::Accept, "10.0.0.0/24", "443"),
+            rule(3, RuleAction::Drop, "10.0.0.128/25", "70-90"),
+        ];
+        let issues = analyze(&rules);
+        assert_eq!(
+            codes(&issues),
+            [
+                ("firewall_rule_conflict", "firewall_rules[2]"),
+                ("firewall_rules_mergeable", "firewall_rules[0]")
+            ]
+        );
+        assert!(issues[1].message.contains("port \"80,443\""));
+    }
+
+    #[test]
+    fn test_grouped_rules_can_be_shadowed_but_never_shadow() {
+        let mut grouped = rule(1, RuleAction::Drop, "any", "any");
+        grouped.source_group = Some(1);
+        let rules = vec![grouped.clone(), rule(2, RuleAction::Accept, "10.0.0.0/8", "22")];
+        assert!(analyze(&rules).is_empty());
+
+        let mut by_country = rule(1, RuleAction::Drop, "any", "any");
+        by_country.source_countries = vec!["NL".to_string()];
+        let rules = vec![by_country, rule(2, RuleAction::Accept, "10.0.0.0/8", "22")];
+        assert!(analyze(&rules).is_empty());
+
+        grouped.id = 2;
+        let rules = vec![rule(1, RuleAction::Accept, "any", "any"), grouped];
+        assert_eq!(
+            codes(&analyze(&rules)),
+            [("firewall_rule_shadowed", "firewall_rules[1]")]
+        );
+    }
+
+    #[test]
+    fn test_schedules_and_disabled_rules() {
+        let mut scheduled = rule(1, RuleAction::Drop, "any", "any");
+        scheduled.schedule = Some(RuleSchedule {
+            enabled: true,
+            start: "09:00".to_string(),
+            end: "17:00".to_string(),
+            days: vec![1, 2, 3, 4, 5],
+        });
+        let mut disabled = rule(2, RuleAction::Drop, "any", "any");
+        disabled.enabled = false;
+        let rules = vec![
+            scheduled,
+            disabled,
+            rule(3, RuleAction::Accept, "any", "22"),
+        ];
+        // Neither the scheduled nor the disabled rule shadows rule 3, but
+        // the scheduled one drops its traffic during office hours
+        assert_eq!(
+            codes(&analyze(&rules)),
+            [("firewall_rule_conflict", "firewall_rules[2]")]
+        );
+    }
+}
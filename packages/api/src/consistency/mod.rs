@@ -0,0 +1,1113 @@
+//! Cross-section configuration consistency checks
+//!
+//! Request bodies are validated on their own (see `models::validation`), so
+//! a DHCP range can still fall outside the LAN subnet it serves or a port
+//! forward can point at an address no interface reaches. The checks here
+//! load every stored section of a device and evaluate rules across them.
+//!
+//! They run in `storage::update_config` before a section is stored and
+//! pushed: errors involving the section being written reject the write,
+//! warnings are returned with it. Problems confined to other sections never
+//! block a write, so a device already in a bad state can still be fixed one
+//! section at a time. `GET /config/lint` reports everything.
+//!
+//! Firewall rule ordering is analyzed in [`firewall`]; [`trace`] walks a
+//! single packet through the same stored configuration. Rules that name an
+//! address or port group which does not exist are errors, so a group still
+//! in use cannot be deleted. Rules on countries missing from the device's
+//! GeoIP dataset only warn, since the dataset may be uploaded afterwards.
+//!
+//! Port forwards are also checked against the UPnP leases the router last
+//! reported: a lease on a port a manual forward claims only warns, since
+//! the lease is not part of the stored configuration.
+
+pub mod firewall;
+pub mod trace;
+
+use crate::models::lint::{LintIssue, LintReport, LintSeverity};
+use crate::models::network::*;
+use crate::models::security::{
+    AddressGroup, FirewallRule, GeoIpDataset, GeoIpSet, NatRule, NatType, PortGroup, Protocol,
+    UpnpLease, ZoneConfig,
+};
+use crate::models::services::VpnServerConfig;
+use crate::models::validation::is_any;
+use crate::models::{ApiError, ApiResult, ErrorCode, ErrorDetails};
+use crate::storage;
+use serde::de::DeserializeOwned;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::Ipv4Addr;
+use worker::Env;
+
+/// Sections the checks read; writes to other sections are not checked
+pub const LINTED_SECTIONS: &[&str] = &[
+    "wan",
+    "lan",
+    "vlans",
+    "wifi_networks",
+    "dhcp",
+    "dhcp_reservations",
+    "routes",
+    "nat_rules",
+    "firewall_rules",
+    "firewall_zones",
+    "address_groups",
+    "port_groups",
+    "geoip",
+    "vpn_server",
+];
+
+/// Stored configuration of a device, keyed by section
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSnapshot {
+    sections: HashMap<String, serde_json::Value>,
+}
+
+impl ConfigSnapshot {
+    /// Load every linted section of a device; missing sections are skipped.
+    /// The UPnP leases the device last reported are loaded alongside.
+    pub async fn load(device_id: &str, env: &Env) -> ApiResult<Self> {
+        let mut snapshot = Self::load_sections(device_id, LINTED_SECTIONS, env).await?;
+        if let Ok(leases) = storage::get_reported_upnp_leases(device_id, env).await {
+            snapshot.set("upnp_leases", serde_json::json!(leases));
+        }
+        Ok(snapshot)
+    }
+
+    /// Load the given sections of a device; missing sections are skipped
+    pub async fn load_sections(device_id: &str, names: &[&str], env: &Env) -> ApiResult<Self> {
+        let reads = names
+            .iter()
+            .map(|section| storage::get_config::<serde_json::Value>(device_id, section, env));
+        let mut sections = HashMap::new();
+        for (section, result) in names.iter().zip(futures::future::join_all(reads).await) {
+            match result {
+                Ok(value) => {
+                    sections.insert(section.to_string(), value);
+                }
+                Err(e) if e.error.code == ErrorCode::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Self { sections })
+    }
+
+    /// Replace a section, e.g. with a value about to be written
+    pub fn set(&mut self, section: &str, value: serde_json::Value) {
+        self.sections.insert(section.to_string(), value);
+    }
+
+    /// A single-object section, if stored and well-formed
+    fn get<T: DeserializeOwned>(&self, section: &str) -> Option<T> {
+        serde_json::from_value(self.sections.get(section)?.clone()).ok()
+    }
+
+    /// A list section, skipping malformed elements. A section stored as a
+    /// single object is read as a list of one.
+    fn list<T: DeserializeOwned>(&self, section: &str) -> Vec<T> {
+        match self.sections.get(section) {
+            Some(serde_json::Value::Array(items)) => items
+                .iter()
+                .filter_map(|item| serde_json::from_value(item.clone()).ok())
+                .collect(),
+            Some(item) => serde_json::from_value(item.clone()).into_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Check a section about to be written against the rest of the device's
+/// stored configuration.
+///
+/// Fails with `INVALID_CONFIG` listing every issue involving the section if
+/// any of them is an error; otherwise returns its warnings.
+pub async fn check_write(
+    device_id: &str,
+    section: &str,
+    value: serde_json::Value,
+    env: &Env,
+) -> ApiResult<Vec<LintIssue>> {
+    if !LINTED_SECTIONS.contains(&section) {
+        return Ok(Vec::new());
+    }
+    let mut snapshot = ConfigSnapshot::load(device_id, env).await?;
+    snapshot.set(section, value);
+
+    let issues = LintReport::new(lint(&snapshot)).for_section(section);
+    if issues.iter().any(|i| i.severity == LintSeverity::Error) {
+        return Err(
+            ApiError::invalid_config("Configuration conflicts with other sections").with_details(
+                ErrorDetails {
+                    lint_issues: Some(issues),
+                    ..Default::default()
+                },
+            ),
+        );
+    }
+    Ok(issues)
+}
+
+/// Lint a device's stored configuration
+pub async fn lint_device(device_id: &str, env: &Env) -> ApiResult<LintReport> {
+    let snapshot = ConfigSnapshot::load(device_id, env).await?;
+    Ok(LintReport::new(lint(&snapshot)))
+}
+
+/// Run every check over a snapshot
+pub fn lint(snapshot: &ConfigSnapshot) -> Vec<LintIssue> {
+    let config = Config::from_snapshot(snapshot);
+    let mut issues = Vec::new();
+    check_subnets(&config, &mut issues);
+    check_vlan_ids(&config, &mut issues);
+    check_dhcp(&config, &mut issues);
+    check_reservations(&config, &mut issues);
+    check_routes(&config, &mut issues);
+    check_port_forwards(&config, &mut issues);
+    check_upnp_conflicts(&config, &mut issues);
+    check_wifi_vlans(&config, &mut issues);
+    check_zone_interfaces(&config, &mut issues);
+    check_group_references(&config, &mut issues);
+    check_country_references(&config, &mut issues);
+    issues.extend(firewall::analyze(&config.firewall_rules));
+    issues
+}
+
+// ========== Addressing ==========
+
+/// An IPv4 network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    network: u32,
+    prefix: u8,
+}
+
+impl Subnet {
+    fn new(addr: Ipv4Addr, prefix: u8) -> Self {
+        Self {
+            network: u32::from(addr) & Self::mask(prefix),
+            prefix,
+        }
+    }
+
+    fn mask(prefix: u8) -> u32 {
+        u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0)
+    }
+
+    /// From an interface address and a dotted netmask
+    pub fn from_mask(ip: &str, netmask: &str) -> Option<Self> {
+        let mask = u32::from(netmask.parse::<Ipv4Addr>().ok()?);
+        if mask.leading_ones() + mask.trailing_zeros() != 32 {
+            return None;
+        }
+        Some(Self::new(ip.parse().ok()?, mask.leading_ones() as u8))
+    }
+
+    /// From `a.b.c.d/len`, or a bare address as a /32
+    pub fn from_cidr(value: &str) -> Option<Self> {
+        let (addr, prefix) = value.split_once('/').unwrap_or((value, "32"));
+        let prefix: u8 = prefix.parse().ok().filter(|p| *p <= 32)?;
+        Some(Self::new(addr.parse().ok()?, prefix))
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        u32::from(ip) & Self::mask(self.prefix) == self.network
+    }
+
+    pub fn overlaps(&self, other: &Subnet) -> bool {
+        let prefix = self.prefix.min(other.prefix);
+        self.network & Self::mask(prefix) == other.network & Self::mask(prefix)
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", Ipv4Addr::from(self.network), self.prefix)
+    }
+}
+
+/// A network the router is attached to, with where it is configured
+#[derive(Debug, Clone)]
+struct Network {
+    interface: String,
+    subnet: Subnet,
+    /// The router's own address on it
+    address: Ipv4Addr,
+    section: &'static str,
+    field: String,
+    internal: bool,
+}
+
+/// The parts of a snapshot the checks use
+#[derive(Debug, Default)]
+struct Config {
+    wan: Option<WanConfig>,
+    vlans: Vec<VlanConfig>,
+    wifi_networks: Vec<WifiNetwork>,
+    dhcp: Option<DhcpConfig>,
+    reservations: Vec<DhcpReservation>,
+    routes: Vec<RouteRequest>,
+    nat_rules: Vec<NatRule>,
+    /// Reported by the device rather than configured
+    upnp_leases: Vec<UpnpLease>,
+    firewall_rules: Vec<FirewallRule>,
+    zones: Vec<ZoneConfig>,
+    address_groups: Vec<AddressGroup>,
+    port_groups: Vec<PortGroup>,
+    geoip: Option<GeoIpDataset>,
+    /// Networks of the countries rules use; only loaded for traces
+    geoip_sets: Vec<GeoIpSet>,
+    networks: Vec<Network>,
+    /// Interfaces and ports named by the WAN, LAN, VLAN and VPN sections
+    interfaces: HashSet<String>,
+}
+
+impl Config {
+    fn from_snapshot(snapshot: &ConfigSnapshot) -> Self {
+        let mut config = Config {
+            wan: snapshot.get("wan"),
+            vlans: snapshot.list("vlans"),
+            wifi_networks: snapshot.list("wifi_networks"),
+            dhcp: snapshot.get("dhcp"),
+            reservations: snapshot.list("dhcp_reservations"),
+            routes: snapshot.list("routes"),
+            nat_rules: snapshot.list("nat_rules"),
+            upnp_leases: snapshot.list("upnp_leases"),
+            firewall_rules: snapshot.list("firewall_rules"),
+            zones: snapshot.list("firewall_zones"),
+            address_groups: snapshot.list("address_groups"),
+            port_groups: snapshot.list("port_groups"),
+            geoip: snapshot.get("geoip"),
+            geoip_sets: snapshot.list("geoip_sets"),
+            ..Default::default()
+        };
+        let lan: Option<LanConfig> = snapshot.get("lan");
+        let vpn: Option<VpnServerConfig> = snapshot.get("vpn_server");
+
+        if let Some(lan) = &lan {
+            config.add_network(
+                &lan.interface,
+                &lan.ip,
+                &lan.subnet_mask,
+                "lan",
+                "lan.ip",
+                true,
+            );
+            config
+                .interfaces
+                .extend(lan.bridge_interfaces.iter().cloned());
+        }
+        for (i, vlan) in config.vlans.clone().iter().enumerate() {
+            config.add_network(
+                &vlan.interface,
+                &vlan.ip,
+                &vlan.subnet_mask,
+                "vlans",
+                &format!("vlans[{}].ip", i),
+                true,
+            );
+            config.interfaces.extend(vlan.tagged_ports.iter().cloned());
+            config
+                .interfaces
+                .extend(vlan.untagged_ports.iter().cloned());
+        }
+        if let Some(vpn) = vpn.filter(|vpn| vpn.enabled) {
+            let (address, _) = vpn.address.split_once('/').unwrap_or((&vpn.address, ""));
+            if let (Some(subnet), Ok(address)) = (Subnet::from_cidr(&vpn.address), address.parse())
+            {
+                config.networks.push(Network {
+                    interface: vpn.interface.clone(),
+                    subnet,
+                    address,
+                    section: "vpn_server",
+                    field: "vpn_server.address".to_string(),
+                    internal: true,
+                });
+            }
+            config.interfaces.insert(vpn.interface);
+        }
+        if let Some(wan) = config.wan.clone() {
+            config.interfaces.insert(wan.interface.clone());
+            if let (WanType::Static, Some(static_config)) = (&wan.wan_type, &wan.static_config) {
+                config.add_network(
+                    &wan.interface,
+                    &static_config.ip,
+                    &static_config.subnet_mask,
+                    "wan",
+                    "wan.static_config.ip",
+                    false,
+                );
+            }
+        }
+        config
+    }
+
+    fn add_network(
+        &mut self,
+        interface: &str,
+        ip: &str,
+        netmask: &str,
+        section: &'static str,
+        field: &str,
+        internal: bool,
+    ) {
+        self.interfaces.insert(interface.to_string());
+        if let (Some(subnet), Ok(address)) = (Subnet::from_mask(ip, netmask), ip.parse()) {
+            self.networks.push(Network {
+                interface: interface.to_string(),
+                subnet,
+                address,
+                section,
+                field: field.to_string(),
+                internal,
+            });
+        }
+    }
+
+    fn internal_networks(&self) -> impl Iterator<Item = &Network> {
+        self.networks.iter().filter(|n| n.internal)
+    }
+
+    fn network_on(&self, interface: &str) -> Option<&Network> {
+        self.networks.iter().find(|n| n.interface == interface)
+    }
+
+    /// Whether an interface is configured anywhere, including firewall zones
+    fn has_interface(&self, interface: &str) -> bool {
+        self.interfaces.contains(interface)
+            || self
+                .zones
+                .iter()
+                .any(|zone| zone.interfaces.iter().any(|i| i == interface))
+    }
+}
+
+fn issue(
+    severity: LintSeverity,
+    code: &str,
+    message: String,
+    sections: &[&str],
+    field: impl Into<String>,
+) -> LintIssue {
+    let mut names: Vec<String> = Vec::new();
+    for section in sections {
+        if !names.iter().any(|s| s == section) {
+            names.push(section.to_string());
+        }
+    }
+    LintIssue {
+        severity,
+        code: code.to_string(),
+        message,
+        sections: names,
+        field: Some(field.into()),
+    }
+}
+
+// ========== Checks ==========
+
+/// Networks attached to the router must not overlap
+fn check_subnets(config: &Config, issues: &mut Vec<LintIssue>) {
+    for (i, a) in config.networks.iter().enumerate() {
+        for b in &config.networks[i + 1..] {
+            if a.subnet.overlaps(&b.subnet) {
+                issues.push(issue(
+                    LintSeverity::Error,
+                    "subnet_overlap",
+                    format!(
+                        "{} ({}) overlaps {} ({})",
+                        b.subnet, b.field, a.subnet, a.field
+                    ),
+                    &[a.section, b.section],
+                    b.field.clone(),
+                ));
+            }
+        }
+    }
+}
+
+/// VLAN ids must be unique, including the WAN's tag
+fn check_vlan_ids(config: &Config, issues: &mut Vec<LintIssue>) {
+    let mut seen: HashMap<u16, usize> = HashMap::new();
+    for (i, vlan) in config.vlans.iter().enumerate() {
+        if let Some(first) = seen.insert(vlan.vlan_id, i) {
+            issues.push(issue(
+                LintSeverity::Error,
+                "vlan_id_conflict",
+                format!(
+                    "VLAN id {} is used by both '{}' and '{}'",
+                    vlan.vlan_id, config.vlans[first].name, vlan.name
+                ),
+                &["vlans"],
+                format!("vlans[{}].vlan_id", i),
+            ));
+            seen.insert(vlan.vlan_id, first);
+        }
+    }
+    if let Some(id) = config.wan.as_ref().and_then(|wan| wan.vlan_id)
+        && let Some(&i) = seen.get(&id)
+    {
+        issues.push(issue(
+            LintSeverity::Error,
+            "vlan_id_conflict",
+            format!(
+                "WAN VLAN id {} is also used by VLAN '{}'",
+                id, config.vlans[i].name
+            ),
+            &["wan", "vlans"],
+            "wan.vlan_id",
+        ));
+    }
+}
+
+/// The DHCP pool must sit inside the subnet of the interface it serves
+fn check_dhcp(config: &Config, issues: &mut Vec<LintIssue>) {
+    let Some(dhcp) = config.dhcp.as_ref().filter(|dhcp| dhcp.enabled) else {
+        return;
+    };
+    let Some(network) = config
+        .internal_networks()
+        .find(|n| n.interface == dhcp.interface)
+    else {
+        issues.push(issue(
+            LintSeverity::Error,
+            "dhcp_unknown_interface",
+            format!(
+                "DHCP serves '{}', which is not a LAN, VLAN or VPN interface",
+                dhcp.interface
+            ),
+            &["dhcp", "lan", "vlans"],
+            "dhcp.interface",
+        ));
+        return;
+    };
+
+    let sections = ["dhcp", network.section];
+    let mut range = Vec::new();
+    for (field, value) in [
+        ("range_start", &dhcp.range_start),
+        ("range_end", &dhcp.range_end),
+    ] {
+        let Ok(ip) = value.parse::<Ipv4Addr>() else {
+            continue;
+        };
+        if network.subnet.contains(ip) {
+            range.push(ip);
+        } else {
+            issues.push(issue(
+                LintSeverity::Error,
+                "dhcp_range_outside_subnet",
+                format!(
+                    "{} is outside {} on {}",
+                    value, network.subnet, network.interface
+                ),
+                &sections,
+                format!("dhcp.{}", field),
+            ));
+        }
+    }
+    if let [start, end] = range[..]
+        && (start..=end).contains(&network.address)
+    {
+        issues.push(issue(
+            LintSeverity::Warning,
+            "dhcp_range_includes_router",
+            format!(
+                "The DHCP range includes the router's own address {}",
+                network.address
+            ),
+            &sections,
+            "dhcp.range_start",
+        ));
+    }
+    if let Ok(gateway) = dhcp.gateway.parse::<Ipv4Addr>()
+        && !network.subnet.contains(gateway)
+    {
+        issues.push(issue(
+            LintSeverity::Warning,
+            "dhcp_gateway_outside_subnet",
+            format!(
+                "Gateway {} is outside {}; clients will not reach it",
+                gateway, network.subnet
+            ),
+            &sections,
+            "dhcp.gateway",
+        ));
+    }
+}
+
+/// Reservations must be unique and reachable
+fn check_reservations(config: &Config, issues: &mut Vec<LintIssue>) {
+    let pool = config.dhcp.as_ref().and_then(|dhcp| {
+        Some(dhcp.range_start.parse::<Ipv4Addr>().ok()?..=dhcp.range_end.parse().ok()?)
+    });
+    let mut ips = HashSet::new();
+    let mut macs = HashSet::new();
+    for (i, reservation) in config.reservations.iter().enumerate() {
+        let field = |name: &str| format!("dhcp_reservations[{}].{}", i, name);
+        if !macs.insert(reservation.mac.to_ascii_lowercase()) {
+            issues.push(issue(
+                LintSeverity::Error,
+                "reservation_duplicate_mac",
+                format!("{} has more than one reservation", reservation.mac),
+                &["dhcp_reservations"],
+                field("mac"),
+            ));
+        }
+        if !ips.insert(reservation.ip.clone()) {
+            issues.push(issue(
+                LintSeverity::Error,
+                "reservation_duplicate_ip",
+                format!("{} is reserved more than once", reservation.ip),
+                &["dhcp_reservations"],
+                field("ip"),
+            ));
+        }
+        let Ok(ip) = reservation.ip.parse::<Ipv4Addr>() else {
+            continue;
+        };
+        if !config.internal_networks().any(|n| n.subnet.contains(ip)) {
+            issues.push(issue(
+                LintSeverity::Warning,
+                "reservation_outside_subnet",
+                format!("{} is not on any LAN or VLAN subnet", ip),
+                &["dhcp_reservations", "lan", "vlans"],
+                field("ip"),
+            ));
+        } else if pool.as_ref().is_some_and(|pool| pool.contains(&ip)) {
+            issues.push(issue(
+                LintSeverity::Warning,
+                "reservation_in_dhcp_range",
+                format!(
+                    "{} is inside the dynamic DHCP range and may be leased to another client",
+                    ip
+                ),
+                &["dhcp_reservations", "dhcp"],
+                field("ip"),
+            ));
+        }
+    }
+}
+
+/// Static routes must leave through a configured interface
+fn check_routes(config: &Config, issues: &mut Vec<LintIssue>) {
+    for (i, route) in config.routes.iter().enumerate() {
+        if route.enabled == Some(false) {
+            continue;
+        }
+        if !config.has_interface(&route.interface) {
+            issues.push(issue(
+                LintSeverity::Error,
+                "route_unknown_interface",
+                format!(
+                    "Route to {} uses interface '{}', which is not configured",
+                    route.destination, route.interface
+                ),
+                &["routes"],
+                format!("routes[{}].interface", i),
+            ));
+            continue;
+        }
+        if let (Some(network), Ok(gateway)) = (
+            config.network_on(&route.interface),
+            route.gateway.parse::<Ipv4Addr>(),
+        ) && !network.subnet.contains(gateway)
+        {
+            issues.push(issue(
+                LintSeverity::Warning,
+                "route_gateway_unreachable",
+                format!(
+                    "Gateway {} is not on {} ({})",
+                    gateway, route.interface, network.subnet
+                ),
+                &["routes", network.section],
+                format!("routes[{}].gateway", i),
+            ));
+        }
+    }
+}
+
+/// Port forwards must point at an internal address
+fn check_port_forwards(config: &Config, issues: &mut Vec<LintIssue>) {
+    for (i, rule) in config.nat_rules.iter().enumerate() {
+        if !rule.enabled || rule.nat_type != NatType::Dnat {
+            continue;
+        }
+        let Ok(target) = rule.translate_to.parse::<Ipv4Addr>() else {
+            continue;
+        };
+        if !config
+            .internal_networks()
+            .any(|n| n.subnet.contains(target))
+        {
+            issues.push(issue(
+                LintSeverity::Error,
+                "port_forward_target_not_internal",
+                format!(
+                    "Port forward '{}' targets {}, which is not on any LAN, VLAN or VPN subnet",
+                    rule.name, target
+                ),
+                &["nat_rules", "lan", "vlans"],
+                format!("nat_rules[{}].translate_to", i),
+            ));
+        }
+    }
+}
+
+/// Whether an enabled port forward claims the external port of a UPnP lease
+pub fn claims_upnp_port(rule: &NatRule, lease: &UpnpLease) -> bool {
+    rule.enabled
+        && matches!(rule.nat_type, NatType::Dnat | NatType::OneToOne)
+        && (rule.protocol == Protocol::All || rule.protocol == lease.protocol)
+        && rule.destination_port.as_deref().is_none_or(|spec| {
+            is_any(spec)
+                || firewall::parse_ports(spec)
+                    .is_some_and(|ports| ports.contains(u128::from(lease.external_port)))
+        })
+}
+
+/// Port forwards should not claim ports UPnP clients hold, since only one
+/// of them receives the traffic
+fn check_upnp_conflicts(config: &Config, issues: &mut Vec<LintIssue>) {
+    for (i, rule) in config.nat_rules.iter().enumerate() {
+        for lease in config
+            .upnp_leases
+            .iter()
+            .filter(|lease| claims_upnp_port(rule, lease))
+        {
+            let protocol = if lease.protocol == Protocol::Udp {
+                "UDP"
+            } else {
+                "TCP"
+            };
+            issues.push(issue(
+                LintSeverity::Warning,
+                "port_forward_upnp_conflict",
+                format!(
+                    "Port forward '{}' and the UPnP lease of {} ('{}') both claim {} port {}",
+                    rule.name, lease.client, lease.description, protocol, lease.external_port
+                ),
+                &["nat_rules"],
+                format!("nat_rules[{}].destination_port", i),
+            ));
+        }
+    }
+}
+
+/// WiFi networks can only be bridged to configured VLANs
+fn check_wifi_vlans(config: &Config, issues: &mut Vec<LintIssue>) {
+    for (i, network) in config.wifi_networks.iter().enumerate() {
+        if let Some(id) = network.vlan
+            && !config.vlans.iter().any(|vlan| vlan.vlan_id == id)
+        {
+            issues.push(issue(
+                LintSeverity::Warning,
+                "wifi_unknown_vlan",
+                format!(
+                    "SSID '{}' is tagged with VLAN {}, which is not configured",
+                    network.ssid, id
+                ),
+                &["wifi_networks", "vlans"],
+                format!("wifi_networks[{}].vlan", i),
+            ));
+        }
+    }
+}
+
+/// Rules can only reference address and port groups that exist
+fn check_group_references(config: &Config, issues: &mut Vec<LintIssue>) {
+    let firewall = config.firewall_rules.iter().enumerate().map(|(i, r)| {
+        let groups = [r.source_group, r.destination_group, r.port_group];
+        ("firewall_rules", i, &r.name, groups)
+    });
+    let nat = config.nat_rules.iter().enumerate().map(|(i, r)| {
+        let groups = [r.source_group, r.destination_group, r.port_group];
+        ("nat_rules", i, &r.name, groups)
+    });
+    let fields = [
+        ("source_group", "address_groups"),
+        ("destination_group", "address_groups"),
+        ("port_group", "port_groups"),
+    ];
+    for (section, i, name, groups) in firewall.chain(nat) {
+        for ((field, groups_section), id) in fields.into_iter().zip(groups) {
+            let Some(id) = id else {
+                continue;
+            };
+            let exists = match groups_section {
+                "address_groups" => config.address_groups.iter().any(|g| g.id == id),
+                _ => config.port_groups.iter().any(|g| g.id == id),
+            };
+            if !exists {
+                issues.push(issue(
+                    LintSeverity::Error,
+                    "unknown_group",
+                    format!(
+                        "Rule '{}' references {} {}, which does not exist",
+                        name, field, id
+                    ),
+                    &[section, groups_section],
+                    format!("{}[{}].{}", section, i, field),
+                ));
+            }
+        }
+    }
+}
+
+/// Countries rules match on should be in the GeoIP dataset, or the rules
+/// never match anything
+fn check_country_references(config: &Config, issues: &mut Vec<LintIssue>) {
+    let known = |code: &str| {
+        config
+            .geoip
+            .as_ref()
+            .is_some_and(|d| d.countries.iter().any(|c| c.code == code))
+    };
+    for (i, rule) in config.firewall_rules.iter().enumerate() {
+        let fields = [
+            ("source_countries", &rule.source_countries),
+            ("destination_countries", &rule.destination_countries),
+        ];
+        for (field, codes) in fields {
+            for (j, code) in codes.iter().enumerate() {
+                if known(code) {
+                    continue;
+                }
+                issues.push(issue(
+                    LintSeverity::Warning,
+                    "unknown_country",
+                    format!(
+                        "Rule '{}' matches country {}, which the GeoIP dataset does not include",
+                        rule.name, code
+                    ),
+                    &["firewall_rules", "geoip"],
+                    format!("firewall_rules[{}].{}[{}]", i, field, j),
+                ));
+            }
+        }
+    }
+}
+
+/// Zones should only list interfaces configured elsewhere
+fn check_zone_interfaces(config: &Config, issues: &mut Vec<LintIssue>) {
+    for (i, zone) in config.zones.iter().enumerate() {
+        for (j, interface) in zone.interfaces.iter().enumerate() {
+            if !config.interfaces.contains(interface) {
+                issues.push(issue(
+                    LintSeverity::Warning,
+                    "zone_unknown_interface",
+                    format!(
+                        "Zone '{}' includes '{}', which no WAN, LAN, VLAN or VPN config uses",
+                        zone.name, interface
+                    ),
+                    &["firewall_zones"],
+                    format!("firewall_zones[{}].interfaces[{}]", i, j),
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn snapshot() -> ConfigSnapshot {
+        let mut snapshot = ConfigSnapshot::default();
+        snapshot.set(
+            "lan",
+            json!({
+                "interface": "br0",
+                "ip": "192.168.1.1",
+                "subnet_mask": "255.255.255.0",
+                "bridge_interfaces": ["eth1", "eth2"],
+                "ipv6_enabled": false
+            }),
+        );
+        snapshot.set(
+            "dhcp",
+            json!({
+                "enabled": true,
+                "interface": "br0",
+                "range_start": "192.168.1.100",
+                "range_end": "192.168.1.200",
+                "lease_time": 86400,
+                "gateway": "192.168.1.1",
+                "dns": ["192.168.1.1"]
+            }),
+        );
+        snapshot
+    }
+
+    fn vlan(id: u32, vlan_id: u16, ip: &str) -> serde_json::Value {
+        json!({
+            "id": id,
+            "vlan_id": vlan_id,
+            "name": format!("vlan{}", vlan_id),
+            "interface": format!("br{}", id),
+            "ip": ip,
+            "subnet_mask": "255.255.255.0",
+            "tagged_ports": [],
+            "untagged_ports": [],
+            "enabled": true
+        })
+    }
+
+    fn codes(issues: &[LintIssue]) -> Vec<&str> {
+        issues.iter().map(|i| i.code.as_str()).collect()
+    }
+
+    #[test]
+    fn test_subnet_math() {
+        let lan = Subnet::from_mask("192.168.1.1", "255.255.255.0").unwrap();
+        assert_eq!(lan.to_string(), "192.168.1.0/24");
+        assert!(lan.contains("192.168.1.254".parse().unwrap()));
+        assert!(!lan.contains("192.168.2.1".parse().unwrap()));
+        assert!(lan.overlaps(&Subnet::from_cidr("192.168.0.0/16").unwrap()));
+        assert!(!lan.overlaps(&Subnet::from_cidr("10.0.0.0/8").unwrap()));
+        assert_eq!(
+            Subnet::from_cidr("0.0.0.0/0").unwrap().to_string(),
+            "0.0.0.0/0"
+        );
+        assert!(Subnet::from_cidr("10.0.0.0/33").is_none());
+    }
+
+    #[test]
+    fn test_consistent_config_is_clean() {
+        assert!(lint(&snapshot()).is_empty());
+    }
+
+    #[test]
+    fn test_dhcp_range_outside_lan() {
+        let mut snapshot = snapshot();
+        snapshot.set(
+            "lan",
+            json!({
+                "interface": "br0",
+                "ip": "10.0.0.1",
+                "subnet_mask": "255.255.255.0",
+                "bridge_interfaces": [],
+                "ipv6_enabled": false
+            }),
+        );
+        let issues = lint(&snapshot);
+        assert_eq!(
+            codes(&issues),
+            [
+                "dhcp_range_outside_subnet",
+                "dhcp_range_outside_subnet",
+                "dhcp_gateway_outside_subnet"
+            ]
+        );
+        assert_eq!(issues[0].sections, ["dhcp", "lan"]);
+        assert_eq!(issues[0].field.as_deref(), Some("dhcp.range_start"));
+    }
+
+    #[test]
+    fn test_vlan_conflicts() {
+        let mut snapshot = snapshot();
+        snapshot.set(
+            "vlans",
+            json!([
+                vlan(1, 10, "192.168.10.1"),
+                vlan(2, 10, "192.168.20.1"),
+                vlan(3, 30, "192.168.1.129")
+            ]),
+        );
+        let issues = lint(&snapshot);
+        assert_eq!(codes(&issues), ["subnet_overlap", "vlan_id_conflict"]);
+        assert_eq!(issues[0].sections, ["lan", "vlans"]);
+        assert_eq!(issues[1].field.as_deref(), Some("vlans[1].vlan_id"));
+    }
+
+    #[test]
+    fn test_port_forward_target() {
+        let mut snapshot = snapshot();
+        let rule = |target: &str| {
+            json!({
+                "id": 1,
+                "name": "Game server",
+                "enabled": true,
+                "nat_type": "dnat",
+                "protocol": "udp",
+                "source": "any",
+                "source_port": null,
+                "destination": "any",
+                "destination_port": "27015",
+                "translate_to": target,
+                "translate_port": null,
+                "log": false
+            })
+        };
+        snapshot.set("nat_rules", json!([rule("192.168.1.50")]));
+        assert!(lint(&snapshot).is_empty());
+
+        // A single rule stored as an object is read too
+        snapshot.set("nat_rules", rule("8.8.8.8"));
+        assert_eq!(
+            codes(&lint(&snapshot)),
+            ["port_forward_target_not_internal"]
+        );
+    }
+
+    #[test]
+    fn test_port_forwards_conflicting_with_upnp() {
+        let mut snapshot = snapshot();
+        snapshot.set(
+            "nat_rules",
+            json!([{
+                "id": 7, "name": "Game server", "enabled": true, "nat_type": "dnat",
+                "protocol": "udp", "source": "any", "source_port": null,
+                "destination": "any", "destination_port": "27000-27050",
+                "translate_to": "192.168.1.50", "translate_port": null, "log": false
+            }]),
+        );
+        let lease = |protocol: &str, port: u16| {
+            json!({
+                "id": format!("{}:{}", protocol, port), "protocol": protocol,
+                "external_port": port, "internal_ip": "192.168.1.60",
+                "internal_port": port, "description": "Steam", "created_at": 0,
+                "expires_at": null, "client": "192.168.1.60"
+            })
+        };
+        snapshot.set(
+            "upnp_leases",
+            json!([lease("udp", 27015), lease("tcp", 27015), lease("udp", 3074)]),
+        );
+        let issues = lint(&snapshot);
+        assert_eq!(codes(&issues), ["port_forward_upnp_conflict"]);
+        assert_eq!(issues[0].sections, ["nat_rules"]);
+        assert_eq!(
+            issues[0].field.as_deref(),
+            Some("nat_rules[0].destination_port")
+        );
+        assert_eq!(
+            issues[0].message,
+            "Port forward 'Game server' and the UPnP lease of 192.168.1.60 ('Steam') \
+             both claim UDP port 27015"
+        );
+    }
+
+    #[test]
+    fn test_group_references() {
+        let mut snapshot = snapshot();
+        snapshot.set(
+            "firewall_rules",
+            json!([{
+                "id": 1, "name": "Web", "enabled": true,
+                "zone_from": "WAN", "zone_to": "LAN",
+                "source": "any", "destination": "any",
+                "protocol": "tcp", "port": "any", "action": "accept", "log": false,
+                "source_group": 1, "port_group": 2
+            }]),
+        );
+        snapshot.set(
+            "address_groups",
+            json!([{ "id": 1, "name": "Partners", "members": ["203.0.113.0/24"] }]),
+        );
+        let issues = lint(&snapshot);
+        assert_eq!(codes(&issues), ["unknown_group"]);
+        assert_eq!(issues[0].sections, ["firewall_rules", "port_groups"]);
+        assert_eq!(
+            issues[0].field.as_deref(),
+            Some("firewall_rules[0].port_group")
+        );
+
+        // Deleting a group in use is reported against the groups section
+        snapshot.set("address_groups", json!([]));
+        let report = LintReport::new(lint(&snapshot));
+        assert_eq!(report.for_section("address_groups").len(), 1);
+    }
+
+    #[test]
+    fn test_country_references() {
+        let mut snapshot = snapshot();
+        snapshot.set(
+            "firewall_rules",
+            json!([{
+                "id": 1, "name": "Block", "enabled": true,
+                "zone_from": "WAN", "zone_to": "any",
+                "source": "any", "destination": "any",
+                "protocol": "all", "port": "any", "action": "drop", "log": false,
+                "source_countries": ["NL", "XK"]
+            }]),
+        );
+        snapshot.set(
+            "geoip",
+            json!({
+                "version": "0", "updated_at": 0,
+                "countries": [{ "code": "NL", "networks": 1, "hash": "0" }]
+            }),
+        );
+        let issues = lint(&snapshot);
+        assert_eq!(codes(&issues), ["unknown_country"]);
+        assert_eq!(issues[0].severity, LintSeverity::Warning);
+        assert_eq!(
+            issues[0].field.as_deref(),
+            Some("firewall_rules[0].source_countries[1]")
+        );
+    }
+
+    fn route(gateway: &str, interface: &str, enabled: bool) -> serde_json::Value {
+        json!({
+            "id": "1",
+            "destination": "10.10.0.0/16",
+            "gateway": gateway,
+            "interface": interface,
+            "enabled": enabled
+        })
+    }
+
+    #[test]
+    fn test_route_interfaces() {
+        let mut snapshot = snapshot();
+        snapshot.set(
+            "routes",
+            json!([
+                route("192.168.1.2", "br0", true),
+                route("192.168.9.2", "br0", true),
+                route("10.0.0.1", "wg9", true),
+                route("10.0.0.1", "wg9", false),
+            ]),
+        );
+        let issues = lint(&snapshot);
+        assert_eq!(
+            codes(&issues),
+            ["route_gateway_unreachable", "route_unknown_interface"]
+        );
+        assert_eq!(issues[1].severity, LintSeverity::Error);
+
+        // Interfaces placed in a firewall zone count as configured
+        snapshot.set(
+            "firewall_zones",
+            json!([{
+                "id": "vpn",
+                "name": "VPN",
+                "interfaces": ["wg9"],
+                "masquerade": false,
+                "mss_clamping": false
+            }]),
+        );
+        assert_eq!(
+            codes(&lint(&snapshot)),
+            ["route_gateway_unreachable", "zone_unknown_interface"]
+        );
+    }
+
+    #[test]
+    fn test_report_filters_by_section() {
+        let mut snapshot = snapshot();
+        snapshot.set(
+            "dhcp_reservations",
+            json!([
+                { "mac": "aa:bb:cc:dd:ee:01", "ip": "192.168.1.150" },
+                { "mac": "AA:BB:CC:DD:EE:01", "ip": "192.168.1.20" }
+            ]),
+        );
+        let report = LintReport::new(lint(&snapshot));
+        assert_eq!((report.errors, report.warnings), (1, 1));
+        assert_eq!(report.for_section("dhcp").len(), 1);
+        assert!(report.for_section("wan").is_empty());
+    }
+}
@@ -0,0 +1,853 @@
+//! Packet tracer
+//!
+//! Walks a single packet through a device's stored configuration in the
+//! order the router evaluates it:
+//!
+//! 1. the ingress zone, from the interface the packet arrives on
+//! 2. destination NAT (`dnat` and `1:1` rules), first match wins
+//! 3. the egress interface and zone, by longest prefix over the connected
+//!    networks and static routes, falling back to the WAN
+//! 4. the ordered firewall rules against the translated destination, up to
+//!    the first `accept`, `drop` or `reject`
+//! 5. the zone policy when no rule decided
+//! 6. source NAT (`snat`, `masquerade`, zone masquerading) for accepted
+//!    packets leaving through the egress interface
+//!
+//! Only addressing stored in the API is known, so traffic to the router's
+//! own addresses is reported with no egress zone, and DHCP or PPPoE WAN
+//! addresses show up as `masquerade` without a concrete source. Address
+//! groups match on their address members only; MAC and host name members
+//! are resolved on the router. Countries are looked up in the networks
+//! compiled from the device's GeoIP dataset.
+
+use super::firewall::{address_value, is_any_zone, minute_of_week, parse_addresses, parse_ports};
+use super::{Config, ConfigSnapshot, Subnet, firewall};
+use crate::models::security::*;
+use crate::models::validation::is_any;
+use crate::models::{ApiError, ApiResult};
+use crate::storage;
+use std::net::IpAddr;
+use worker::Env;
+
+/// Prefix of the command ids of live counter dumps
+const LIVE_COMMAND_PREFIX: &str = "trace-";
+
+/// Sections a trace reads
+pub const TRACED_SECTIONS: &[&str] = &[
+    "wan",
+    "lan",
+    "vlans",
+    "vpn_server",
+    "routes",
+    "nat_rules",
+    "firewall_rules",
+    "firewall_zones",
+    "zone_policies",
+    "address_groups",
+    "port_groups",
+];
+
+/// A packet as it moves through the router
+#[derive(Debug, Clone)]
+struct Packet {
+    protocol: Protocol,
+    source: IpAddr,
+    source_port: Option<u16>,
+    destination: IpAddr,
+    destination_port: Option<u16>,
+}
+
+impl Packet {
+    fn destination_endpoint(&self) -> String {
+        endpoint(self.destination, self.destination_port)
+    }
+
+    fn source_endpoint(&self) -> String {
+        endpoint(self.source, self.source_port)
+    }
+}
+
+fn endpoint(ip: IpAddr, port: Option<u16>) -> String {
+    match (ip, port) {
+        (IpAddr::V4(ip), Some(port)) => format!("{}:{}", ip, port),
+        (IpAddr::V6(ip), Some(port)) => format!("[{}]:{}", ip, port),
+        (ip, None) => ip.to_string(),
+    }
+}
+
+fn address_matches(spec: &str, ip: IpAddr) -> bool {
+    parse_addresses(spec).is_some_and(|set| set.contains(address_value(ip)))
+}
+
+/// Whether an address is in one of `countries`; no countries matches all
+fn country_matches(config: &Config, countries: &[String], ip: IpAddr) -> bool {
+    countries.is_empty()
+        || config
+            .geoip_sets
+            .iter()
+            .filter(|set| countries.contains(&set.code))
+            .any(|set| set.networks.iter().any(|n| address_matches(n, ip)))
+}
+
+/// A rule without a port match matches packets without ports too
+fn port_matches(spec: Option<&str>, port: Option<u16>) -> bool {
+    match spec {
+        None => true,
+        Some(spec) if is_any(spec) => true,
+        Some(spec) => port.is_some_and(|port| {
+            parse_ports(spec).is_some_and(|set| set.contains(u128::from(port)))
+        }),
+    }
+}
+
+/// Whether the packet is in the address and port groups a rule references,
+/// given as `[source_group, destination_group, port_group]`
+fn groups_match(config: &Config, groups: [Option<u32>; 3], packet: &Packet) -> bool {
+    let in_address_group = |id: Option<u32>, ip: IpAddr| {
+        id.is_none_or(|id| {
+            config
+                .address_groups
+                .iter()
+                .find(|g| g.id == id)
+                .is_some_and(|g| g.members.iter().any(|m| address_matches(m, ip)))
+        })
+    };
+    let [source, destination, ports] = groups;
+    in_address_group(source, packet.source)
+        && in_address_group(destination, packet.destination)
+        && ports.is_none_or(|id| {
+            config
+                .port_groups
+                .iter()
+                .find(|g| g.id == id)
+                .is_some_and(|g| {
+                    g.ports
+                        .iter()
+                        .any(|p| port_matches(Some(p), packet.destination_port))
+                })
+        })
+}
+
+fn protocol_matches(rule: &Protocol, packet: &Protocol) -> bool {
+    *rule == Protocol::All || rule == packet
+}
+
+fn zone_matches(rule: &Zone, packet: Option<&Zone>) -> bool {
+    is_any_zone(rule) || packet == Some(rule)
+}
+
+/// First port of a port match, e.g. `8000` for `8000-8010`
+fn first_port(spec: &str) -> Option<u16> {
+    let spec = spec.trim();
+    let first = spec.split([',', '-', ':']).next()?;
+    first.trim().parse().ok()
+}
+
+/// The rule zone a configured zone stands for, by its id
+fn zone_of(zone: &ZoneConfig) -> Zone {
+    match zone.id.to_ascii_lowercase().as_str() {
+        "wan" => Zone::Wan,
+        "lan" => Zone::Lan,
+        "guest" => Zone::Guest,
+        "iot" => Zone::Iot,
+        "dmz" => Zone::Dmz,
+        _ => Zone::Custom(zone.id.clone()),
+    }
+}
+
+fn zone_name(zone: &Zone) -> String {
+    match zone {
+        Zone::Custom(name) => name.clone(),
+        other => serde_json::to_value(other)
+            .ok()
+            .and_then(|v| v.as_str().map(str::to_string))
+            .unwrap_or_default(),
+    }
+}
+
+/// Zone of an interface: the firewall zone listing it, else WAN for the
+/// WAN interface and LAN for the LAN bridge
+fn interface_zone(config: &Config, interface: &str) -> Option<Zone> {
+    if let Some(zone) = config
+        .zones
+        .iter()
+        .find(|zone| zone.interfaces.iter().any(|i| i == interface))
+    {
+        return Some(zone_of(zone));
+    }
+    if config
+        .wan
+        .as_ref()
+        .is_some_and(|wan| wan.interface == interface)
+    {
+        return Some(Zone::Wan);
+    }
+    config
+        .network_on(interface)
+        .filter(|network| network.section == "lan")
+        .map(|_| Zone::Lan)
+}
+
+/// Where a packet to `destination` leaves the router
+#[derive(Debug, Clone, PartialEq)]
+enum Egress {
+    /// Addressed to the router itself
+    Local,
+    Interface(String),
+    /// No route and no WAN configured
+    Unknown,
+}
+
+fn egress(config: &Config, destination: IpAddr) -> Egress {
+    let IpAddr::V4(destination) = destination else {
+        return config.wan.as_ref().map_or(Egress::Unknown, |wan| {
+            Egress::Interface(wan.interface.clone())
+        });
+    };
+    if config.networks.iter().any(|n| n.address == destination) {
+        return Egress::Local;
+    }
+    let connected = config
+        .networks
+        .iter()
+        .map(|n| (n.subnet, n.interface.as_str()));
+    let routes = config
+        .routes
+        .iter()
+        .filter(|route| route.enabled != Some(false))
+        .filter_map(|route| {
+            Some((
+                Subnet::from_cidr(&route.destination)?,
+                route.interface.as_str(),
+            ))
+        });
+    let best = connected
+        .chain(routes)
+        .filter(|(subnet, _)| subnet.contains(destination))
+        .max_by_key(|(subnet, _)| subnet.prefix);
+    match (best, &config.wan) {
+        (Some((_, interface)), _) => Egress::Interface(interface.to_string()),
+        (None, Some(wan)) => Egress::Interface(wan.interface.clone()),
+        (None, None) => Egress::Unknown,
+    }
+}
+
+fn nat_groups(rule: &NatRule) -> [Option<u32>; 3] {
+    [rule.source_group, rule.destination_group, rule.port_group]
+}
+
+/// Apply the first matching destination NAT rule
+fn destination_nat(
+    config: &Config,
+    interface: &str,
+    packet: &mut Packet,
+) -> Option<NatTranslation> {
+    let rule = config.nat_rules.iter().find(|rule| {
+        rule.enabled
+            && matches!(rule.nat_type, NatType::Dnat | NatType::OneToOne)
+            && rule.interface.as_deref().is_none_or(|i| i == interface)
+            && protocol_matches(&rule.protocol, &packet.protocol)
+            && address_matches(&rule.source, packet.source)
+            && address_matches(&rule.destination, packet.destination)
+            && port_matches(rule.source_port.as_deref(), packet.source_port)
+            && port_matches(rule.destination_port.as_deref(), packet.destination_port)
+            && groups_match(config, nat_groups(rule), packet)
+    })?;
+    let to: IpAddr = rule.translate_to.parse().ok()?;
+
+    let from = packet.destination_endpoint();
+    packet.destination = to;
+    if let (Some(port), Some(translated)) = (
+        packet.destination_port,
+        rule.translate_port.as_deref().and_then(first_port),
+    ) {
+        // Ranges map port for port: 8000-8010 to 80 sends 8005 to 85
+        let offset = rule
+            .destination_port
+            .as_deref()
+            .and_then(first_port)
+            .map_or(0, |start| port.saturating_sub(start));
+        packet.destination_port = Some(translated.saturating_add(offset));
+    }
+    Some(NatTranslation {
+        rule_id: Some(rule.id),
+        name: rule.name.clone(),
+        nat_type: rule.nat_type.clone(),
+        from,
+        to: packet.destination_endpoint(),
+    })
+}
+
+/// Apply the first matching source NAT rule, or the egress zone's
+/// masquerading
+fn source_nat(
+    config: &Config,
+    rules: &[NatRule],
+    interface: &str,
+    zone: Option<&Zone>,
+    packet: &mut Packet,
+) -> Option<NatTranslation> {
+    let router_address = config
+        .network_on(interface)
+        .map(|network| IpAddr::V4(network.address));
+
+    let rule = rules.iter().find(|rule| {
+        rule.enabled
+            && matches!(rule.nat_type, NatType::Snat | NatType::Masquerade)
+            && rule.interface.as_deref().is_none_or(|i| i == interface)
+            && protocol_matches(&rule.protocol, &packet.protocol)
+            && address_matches(&rule.source, packet.source)
+            && address_matches(&rule.destination, packet.destination)
+            && port_matches(rule.source_port.as_deref(), packet.source_port)
+            && port_matches(rule.destination_port.as_deref(), packet.destination_port)
+            && groups_match(config, nat_groups(rule), packet)
+    });
+    let (rule_id, name, nat_type, to) = match rule {
+        Some(rule) => {
+            let to = match rule.nat_type {
+                NatType::Snat => rule.translate_to.parse().ok(),
+                _ => rule.translate_to.parse().ok().or(router_address),
+            };
+            (Some(rule.id), rule.name.clone(), rule.nat_type.clone(), to)
+        }
+        None => {
+            let masquerading = config
+                .zones
+                .iter()
+                .find(|z| z.masquerade && Some(&zone_of(z)) == zone)?;
+            (
+                None,
+                format!("zone {} masquerading", masquerading.name),
+                NatType::Masquerade,
+                router_address,
+            )
+        }
+    };
+
+    let from = packet.source_endpoint();
+    let to = match to {
+        Some(to) => {
+            packet.source = to;
+            packet.source_endpoint()
+        }
+        None => format!("address of {}", interface),
+    };
+    Some(NatTranslation {
+        rule_id,
+        name,
+        nat_type,
+        from,
+        to,
+    })
+}
+
+fn policy_for<'a>(
+    policies: &'a [ZonePolicy],
+    from: Option<&Zone>,
+    to: Option<&Zone>,
+) -> Option<&'a ZonePolicy> {
+    policies
+        .iter()
+        .find(|p| zone_matches(&p.from, from) && zone_matches(&p.to, to))
+}
+
+/// Verdict of a zone policy; `limit` and `shape` let traffic through
+fn policy_verdict(action: &RuleAction) -> RuleAction {
+    match action {
+        RuleAction::Drop => RuleAction::Drop,
+        RuleAction::Reject => RuleAction::Reject,
+        _ => RuleAction::Accept,
+    }
+}
+
+/// Trace a packet through a snapshot
+pub fn trace(snapshot: &ConfigSnapshot, request: &TraceRequest) -> ApiResult<TraceResult> {
+    let config = Config::from_snapshot(snapshot);
+    let policies: Vec<ZonePolicy> = snapshot.list("zone_policies");
+    let mut notes = Vec::new();
+
+    let (Ok(source), Ok(destination)) = (
+        request.source_ip.parse::<IpAddr>(),
+        request.destination_ip.parse::<IpAddr>(),
+    ) else {
+        return Err(ApiError::bad_request(
+            "Invalid source or destination address",
+        ));
+    };
+    let mut packet = Packet {
+        protocol: request.protocol.clone(),
+        source,
+        source_port: request.source_port,
+        destination,
+        destination_port: request.destination_port,
+    };
+
+    let ingress_zone = interface_zone(&config, &request.interface);
+    if ingress_zone.is_none() {
+        notes.push(format!(
+            "Interface '{}' is in no zone; only rules from any zone apply",
+            request.interface
+        ));
+    }
+
+    let mut nat = Vec::new();
+    nat.extend(destination_nat(&config, &request.interface, &mut packet));
+
+    let (egress_interface, egress_zone) = match egress(&config, packet.destination) {
+        Egress::Local => {
+            notes.push(format!(
+                "{} is the router itself; only rules to any zone apply",
+                packet.destination
+            ));
+            (None, None)
+        }
+        Egress::Interface(interface) => {
+            let zone = interface_zone(&config, &interface);
+            (Some(interface), zone)
+        }
+        Egress::Unknown => {
+            notes.push(format!(
+                "No route to {} and no WAN configured",
+                packet.destination
+            ));
+            (None, None)
+        }
+    };
+
+    let minute = match (request.day, request.time.as_deref()) {
+        (Some(day), Some(time)) => minute_of_week(day, time),
+        _ => None,
+    };
+
+    let mut rules = Vec::new();
+    let mut decided = None;
+    for (index, rule) in config.firewall_rules.iter().enumerate() {
+        let matches = rule.enabled
+            && zone_matches(&rule.zone_from, ingress_zone.as_ref())
+            && zone_matches(&rule.zone_to, egress_zone.as_ref())
+            && protocol_matches(&rule.protocol, &packet.protocol)
+            && address_matches(&rule.source, packet.source)
+            && address_matches(&rule.destination, packet.destination)
+            && port_matches(Some(&rule.port), packet.destination_port)
+            && groups_match(
+                &config,
+                [rule.source_group, rule.destination_group, rule.port_group],
+                &packet,
+            )
+            && country_matches(&config, &rule.source_countries, packet.source)
+            && country_matches(&config, &rule.destination_countries, packet.destination);
+        if !matches {
+            continue;
+        }
+        if let Some(schedule) = rule.schedule.as_ref().filter(|s| s.enabled) {
+            match minute {
+                Some(minute) => {
+                    let active = firewall::schedule_minutes(Some(schedule))
+                        .is_some_and(|set| set.contains(minute));
+                    if !active {
+                        continue;
+                    }
+                }
+                None => notes.push(format!(
+                    "Rule {} '{}' is scheduled; assumed active as no day and time were given",
+                    rule.id, rule.name
+                )),
+            }
+        }
+        rules.push(TraceRuleMatch {
+            index,
+            id: rule.id,
+            name: rule.name.clone(),
+            action: rule.action.clone(),
+            log: rule.log,
+        });
+        if matches!(
+            rule.action,
+            RuleAction::Accept | RuleAction::Drop | RuleAction::Reject
+        ) {
+            decided = Some(rule.action.clone());
+            break;
+        }
+    }
+
+    let mut policy = None;
+    let (verdict, decided_by) = match decided {
+        Some(action) => (action, TraceDecision::Rule),
+        None => match policy_for(&policies, ingress_zone.as_ref(), egress_zone.as_ref()) {
+            Some(p) => {
+                policy = Some(p.clone());
+                (policy_verdict(&p.action), TraceDecision::Policy)
+            }
+            None => {
+                notes.push(format!(
+                    "No rule or policy covers {} to {}; the router drops it by default",
+                    ingress_zone
+                        .as_ref()
+                        .map_or("any zone".to_string(), zone_name),
+                    egress_zone
+                        .as_ref()
+                        .map_or("any zone".to_string(), zone_name)
+                ));
+                (RuleAction::Drop, TraceDecision::Default)
+            }
+        },
+    };
+
+    if verdict == RuleAction::Accept
+        && let Some(interface) = &egress_interface
+    {
+        nat.extend(source_nat(
+            &config,
+            &config.nat_rules,
+            interface,
+            egress_zone.as_ref(),
+            &mut packet,
+        ));
+    }
+
+    Ok(TraceResult {
+        ingress_zone,
+        egress_zone,
+        egress_interface,
+        nat,
+        rules,
+        policy,
+        verdict,
+        decided_by,
+        notes,
+        live_command_id: None,
+    })
+}
+
+/// Trace a packet through a device's stored configuration.
+///
+/// With `live` set the router is also asked for an `iptables-save -c`
+/// dump, collected later with [`live_counters`].
+pub async fn trace_device(
+    device_id: &str,
+    request: &TraceRequest,
+    env: &Env,
+) -> ApiResult<TraceResult> {
+    let mut snapshot = ConfigSnapshot::load_sections(device_id, TRACED_SECTIONS, env).await?;
+    let rules: Vec<FirewallRule> = snapshot.list("firewall_rules");
+    let sets = storage::get_geoip_sets(device_id, &crate::geoip::referenced(&rules), env).await?;
+    snapshot.set("geoip_sets", serde_json::json!(sets));
+    let mut result = trace(&snapshot, request)?;
+    if request.live {
+        let command_id = format!("{}{}", LIVE_COMMAND_PREFIX, uuid::Uuid::new_v4());
+        let command = serde_json::json!({
+            "command_id": command_id,
+            "command": "iptables-save",
+            "args": ["-c"]
+        });
+        storage::send_command(device_id, "EXEC", Some(command), env).await?;
+        result.live_command_id = Some(command_id);
+    }
+    Ok(result)
+}
+
+/// Counters of a live trace, `pending` until the router has answered
+pub async fn live_counters(
+    device_id: &str,
+    command_id: &str,
+    env: &Env,
+) -> ApiResult<LiveTraceResult> {
+    if !command_id.starts_with(LIVE_COMMAND_PREFIX) {
+        return Err(ApiError::not_found("Trace"));
+    }
+    let result = storage::get_exec_result(device_id, command_id, env).await?;
+    let (status, error, counters) = match result {
+        None => ("pending", None, Vec::new()),
+        Some(r) if r.exit_code == 0 => (
+            "completed",
+            None,
+            parse_counters(r.stdout.as_deref().unwrap_or_default()),
+        ),
+        Some(r) => ("failed", r.stderr, Vec::new()),
+    };
+    Ok(LiveTraceResult {
+        command_id: command_id.to_string(),
+        status: status.to_string(),
+        error,
+        counters,
+    })
+}
+
+/// Parse `iptables-save -c` output into per-rule counters
+pub fn parse_counters(output: &str) -> Vec<RuleCounter> {
+    let mut table = String::new();
+    let mut counters = Vec::new();
+    for line in output.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix('*') {
+            table = name.to_string();
+            continue;
+        }
+        let Some(rest) = line.strip_prefix('[') else {
+            continue;
+        };
+        let Some((count, rule)) = rest.split_once(']') else {
+            continue;
+        };
+        let Some((packets, bytes)) = count.split_once(':') else {
+            continue;
+        };
+        let rule = rule.trim();
+        let Some(chain) = rule
+            .strip_prefix("-A ")
+            .and_then(|r| r.split_whitespace().next())
+        else {
+            continue;
+        };
+        if let (Ok(packets), Ok(bytes)) = (packets.parse(), bytes.parse()) {
+            counters.push(RuleCounter {
+                table: table.clone(),
+                chain: chain.to_string(),
+                packets,
+                bytes,
+                rule: rule.to_string(),
+            });
+        }
+    }
+    counters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn snapshot() -> ConfigSnapshot {
+        let mut snapshot = ConfigSnapshot::default();
+        snapshot.set(
+            "wan",
+            json!({
+                "interface": "eth0",
+                "wan_type": "static",
+                "static_config": {
+                    "ip": "203.0.113.2",
+                    "subnet_mask": "255.255.255.0",
+                    "gateway": "203.0.113.1",
+                    "dns": []
+                },
+                "mtu": 1500,
+                "ipv6_enabled": false
+            }),
+        );
+        snapshot.set(
+            "lan",
+            json!({
+                "interface": "br0",
+                "ip": "192.168.1.1",
+                "subnet_mask": "255.255.255.0",
+                "bridge_interfaces": [],
+                "ipv6_enabled": false
+            }),
+        );
+        snapshot.set(
+            "firewall_zones",
+            json!([
+                { "id": "wan", "name": "WAN", "interfaces": ["eth0"], "masquerade": true, "mss_clamping": true },
+                { "id": "lan", "name": "LAN", "interfaces": ["br0"], "masquerade": false, "mss_clamping": false }
+            ]),
+        );
+        snapshot.set(
+            "zone_policies",
+            json!([
+                { "from": "LAN", "to": "WAN", "action": "accept", "log": false },
+                { "from": "WAN", "to": "*", "action": "drop", "log": false }
+            ]),
+        );
+        snapshot.set(
+            "nat_rules",
+            json!([{
+                "id": 1,
+                "name": "console",
+                "enabled": true,
+                "nat_type": "dnat",
+                "protocol": "udp",
+                "source": "any",
+                "source_port": null,
+                "destination": "any",
+                "destination_port": "3074-3075",
+                "translate_to": "192.168.1.50",
+                "translate_port": "3074",
+                "interface": "eth0",
+                "log": false
+            }]),
+        );
+        snapshot
+    }
+
+    fn rule(id: u32, action: &str, port: &str) -> serde_json::Value {
+        json!({
+            "id": id,
+            "name": format!("r{}", id),
+            "enabled": true,
+            "zone_from": "WAN",
+            "zone_to": "LAN",
+            "source": "any",
+            "destination": "192.168.1.50",
+            "protocol": "udp",
+            "port": port,
+            "action": action,
+            "log": false
+        })
+    }
+
+    fn request(interface: &str, source: &str, destination: &str, port: u16) -> TraceRequest {
+        TraceRequest {
+            protocol: Protocol::Udp,
+            source_ip: source.to_string(),
+            source_port: Some(40000),
+            destination_ip: destination.to_string(),
+            destination_port: Some(port),
+            interface: interface.to_string(),
+            day: None,
+            time: None,
+            live: false,
+        }
+    }
+
+    #[test]
+    fn test_rules_match_group_members() {
+        let mut snapshot = snapshot();
+        let mut grouped = rule(1, "drop", "any");
+        grouped["source_group"] = json!(1);
+        grouped["port_group"] = json!(2);
+        snapshot.set("firewall_rules", json!([grouped, rule(2, "accept", "any")]));
+        snapshot.set(
+            "address_groups",
+            json!([{ "id": 1, "name": "Blocked", "members": ["198.51.100.0/24", "bad.example.com"] }]),
+        );
+        snapshot.set(
+            "port_groups",
+            json!([{ "id": 2, "name": "Games", "ports": ["3074-3075"] }]),
+        );
+
+        let blocked = trace(&snapshot, &request("eth0", "198.51.100.7", "203.0.113.2", 3074)).unwrap();
+        assert_eq!(blocked.verdict, RuleAction::Drop);
+        assert_eq!(blocked.rules[0].id, 1);
+
+        let other = trace(&snapshot, &request("eth0", "192.0.2.9", "203.0.113.2", 3074)).unwrap();
+        assert_eq!(other.verdict, RuleAction::Accept);
+        assert_eq!(other.rules[0].id, 2);
+    }
+
+    #[test]
+    fn test_rules_match_countries() {
+        let mut snapshot = snapshot();
+        let mut by_country = rule(1, "drop", "any");
+        by_country["source_countries"] = json!(["NL"]);
+        snapshot.set("firewall_rules", json!([by_country, rule(2, "accept", "any")]));
+        snapshot.set(
+            "geoip_sets",
+            json!([{ "code": "NL", "hash": "0", "networks": ["198.51.100.0/24"] }]),
+        );
+
+        let blocked = trace(&snapshot, &request("eth0", "198.51.100.7", "203.0.113.2", 3074)).unwrap();
+        assert_eq!(blocked.rules[0].id, 1);
+        let other = trace(&snapshot, &request("eth0", "192.0.2.9", "203.0.113.2", 3074)).unwrap();
+        assert_eq!(other.rules[0].id, 2);
+    }
+
+    #[test]
+    fn test_port_forward_then_rule() {
+        let mut snapshot = snapshot();
+        snapshot.set("firewall_rules", json!([rule(1, "accept", "3074-3075")]));
+
+        let result = trace(
+            &snapshot,
+            &request("eth0", "198.51.100.7", "203.0.113.2", 3075),
+        )
+        .unwrap();
+        assert_eq!(result.ingress_zone, Some(Zone::Wan));
+        assert_eq!(result.egress_zone, Some(Zone::Lan));
+        assert_eq!(result.egress_interface.as_deref(), Some("br0"));
+        assert_eq!(result.nat.len(), 1);
+        assert_eq!(result.nat[0].from, "203.0.113.2:3075");
+        assert_eq!(result.nat[0].to, "192.168.1.50:3075");
+        assert_eq!(result.rules.len(), 1);
+        assert_eq!(result.verdict, RuleAction::Accept);
+        assert_eq!(result.decided_by, TraceDecision::Rule);
+    }
+
+    #[test]
+    fn test_falls_through_to_zone_policy() {
+        let mut snapshot = snapshot();
+        snapshot.set("firewall_rules", json!([rule(1, "accept", "80")]));
+
+        let result = trace(
+            &snapshot,
+            &request("eth0", "198.51.100.7", "203.0.113.2", 3074),
+        )
+        .unwrap();
+        assert!(result.rules.is_empty());
+        assert_eq!(result.verdict, RuleAction::Drop);
+        assert_eq!(result.decided_by, TraceDecision::Policy);
+        assert_eq!(result.policy.unwrap().from, Zone::Wan);
+    }
+
+    #[test]
+    fn test_outbound_is_masqueraded() {
+        let result = trace(&snapshot(), &request("br0", "192.168.1.50", "8.8.8.8", 53)).unwrap();
+        assert_eq!(result.egress_zone, Some(Zone::Wan));
+        assert_eq!(result.verdict, RuleAction::Accept);
+        assert_eq!(result.nat.len(), 1);
+        assert_eq!(result.nat[0].nat_type, NatType::Masquerade);
+        assert_eq!(result.nat[0].to, "203.0.113.2:40000");
+    }
+
+    #[test]
+    fn test_schedules_use_the_given_time() {
+        let mut snapshot = snapshot();
+        let mut blocked = rule(1, "drop", "any");
+        blocked["schedule"] = json!({
+            "enabled": true,
+            "start": "22:00",
+            "end": "06:00",
+            "days": [1, 2, 3, 4, 5]
+        });
+        snapshot.set("firewall_rules", json!([blocked, rule(2, "accept", "any")]));
+
+        let mut at_night = request("eth0", "198.51.100.7", "203.0.113.2", 3074);
+        at_night.day = Some(2);
+        at_night.time = Some("23:30".to_string());
+        assert_eq!(
+            trace(&snapshot, &at_night).unwrap().verdict,
+            RuleAction::Drop
+        );
+
+        let mut by_day = at_night.clone();
+        by_day.time = Some("12:00".to_string());
+        let result = trace(&snapshot, &by_day).unwrap();
+        assert_eq!(result.verdict, RuleAction::Accept);
+        assert_eq!(result.rules[0].id, 2);
+
+        // Without a time the schedule is assumed active
+        let result = trace(
+            &snapshot,
+            &request("eth0", "198.51.100.7", "203.0.113.2", 3074),
+        )
+        .unwrap();
+        assert_eq!(result.verdict, RuleAction::Drop);
+        assert_eq!(result.notes.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_counters() {
+        let output = "# Generated by iptables-save\n\
+            *filter\n\
+            :FORWARD DROP [0:0]\n\
+            [12:3400] -A FORWARD -i eth0 -p udp --dport 3074 -j ACCEPT\n\
+            COMMIT\n\
+            *nat\n\
+            [3:180] -A PREROUTING -i eth0 -p udp -j DNAT --to-destination 192.168.1.50\n";
+        let counters = parse_counters(output);
+        assert_eq!(counters.len(), 2);
+        assert_eq!(counters[0].table, "filter");
+        assert_eq!(counters[0].chain, "FORWARD");
+        assert_eq!(counters[0].packets, 12);
+        assert_eq!(counters[0].bytes, 3400);
+        assert_eq!(counters[1].table, "nat");
+    }
+}
@@ -0,0 +1,259 @@
+//! GeoIP datasets for country firewall rules
+//!
+//! A dataset is uploaded per device as CSV with one network per line:
+//! `network,country_code[,country_name]`, where the network is an IPv4
+//! address, CIDR network or `start-end` range. Blank lines, `#` comments
+//! and a header row are skipped, as are IPv6 networks (agents only install
+//! IPv4 rules).
+//!
+//! Each country's networks are aggregated into the fewest CIDR blocks (see
+//! [`crate::cidr`]) and stored once per content hash. The dataset index
+//! records every country's hash, so uploading a new release only writes the
+//! countries that changed, and only devices with rules on those countries
+//! are pushed new sets.
+
+use crate::cidr;
+use crate::models::security::{FirewallRule, GeoIpCountry, GeoIpDataset, GeoStats};
+use crate::models::{ApiError, ApiResult, ValidationError};
+use crate::rpc::rule_hits::CountryHitMap;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Parse errors reported before giving up on a dataset
+const MAX_ERRORS: usize = 20;
+
+/// Aggregated networks of one country
+#[derive(Debug, Clone, PartialEq)]
+pub struct Country {
+    pub name: Option<String>,
+    pub networks: Vec<String>,
+    pub hash: String,
+}
+
+/// A parsed dataset, keyed by country code
+#[derive(Debug, Default)]
+pub struct Dataset {
+    pub countries: BTreeMap<String, Country>,
+    pub skipped_ipv6: u32,
+}
+
+impl Dataset {
+    /// Index of the dataset, as stored and returned by the API
+    pub fn index(&self, updated_at: i64) -> GeoIpDataset {
+        let hashes: Vec<String> = self
+            .countries
+            .iter()
+            .map(|(code, country)| format!("{}:{}", code, country.hash))
+            .collect();
+        GeoIpDataset {
+            version: cidr::list_hash(&hashes),
+            updated_at,
+            countries: self
+                .countries
+                .iter()
+                .map(|(code, country)| GeoIpCountry {
+                    code: code.clone(),
+                    name: country.name.clone(),
+                    networks: country.networks.len() as u32,
+                    hash: country.hash.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Parse and aggregate an uploaded dataset
+pub fn parse(text: &str) -> ApiResult<Dataset> {
+    let mut ranges: BTreeMap<String, Vec<(u32, u32)>> = BTreeMap::new();
+    let mut names: BTreeMap<String, String> = BTreeMap::new();
+    let mut skipped_ipv6 = 0;
+    let mut errors = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split(',').map(|f| f.trim().trim_matches('"'));
+        let network = fields.next().unwrap_or_default();
+        let code = fields.next().unwrap_or_default().to_ascii_uppercase();
+        let name = fields.next().filter(|n| !n.is_empty()).map(str::to_string);
+
+        if index == 0 && cidr::parse_range(network).is_none() && !network.contains(':') {
+            // Header row
+            continue;
+        }
+        if network.contains(':') {
+            skipped_ipv6 += 1;
+            continue;
+        }
+        let problem = match cidr::parse_range(network) {
+            None => Some(format!("'{}' is not an IPv4 network", network)),
+            Some(_) if code.len() != 2 || !code.chars().all(|c| c.is_ascii_alphabetic()) => {
+                Some(format!("'{}' is not a two-letter country code", code))
+            }
+            Some(range) => {
+                if let Some(name) = name {
+                    names.entry(code.clone()).or_insert(name);
+                }
+                ranges.entry(code).or_default().push(range);
+                None
+            }
+        };
+        if let Some(message) = problem {
+            errors.push(ValidationError {
+                field: "data".to_string(),
+                message: format!("line {}: {}", index + 1, message),
+                value: None,
+            });
+            if errors.len() == MAX_ERRORS {
+                break;
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(ApiError::validation_failed(errors));
+    }
+    if ranges.is_empty() {
+        return Err(ApiError::bad_request("GeoIP dataset has no IPv4 networks"));
+    }
+
+    let countries = ranges
+        .into_iter()
+        .map(|(code, ranges)| {
+            let networks = cidr::aggregate(ranges);
+            let hash = cidr::list_hash(&networks);
+            let name = names.remove(&code);
+            (
+                code,
+                Country {
+                    name,
+                    networks,
+                    hash,
+                },
+            )
+        })
+        .collect();
+    Ok(Dataset {
+        countries,
+        skipped_ipv6,
+    })
+}
+
+/// Codes of countries added, removed or with different networks
+pub fn changed(old: Option<&GeoIpDataset>, new: &GeoIpDataset) -> Vec<String> {
+    let hashes = |dataset: Option<&GeoIpDataset>| -> BTreeMap<String, String> {
+        dataset
+            .map(|d| {
+                d.countries
+                    .iter()
+                    .map(|c| (c.code.clone(), c.hash.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let (old, new) = (hashes(old), hashes(Some(new)));
+    let codes: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    codes
+        .into_iter()
+        .filter(|code| old.get(*code) != new.get(*code))
+        .cloned()
+        .collect()
+}
+
+/// Countries referenced by enabled firewall rules
+pub fn referenced(rules: &[FirewallRule]) -> BTreeSet<String> {
+    rules
+        .iter()
+        .filter(|rule| rule.enabled)
+        .flat_map(|rule| {
+            rule.source_countries
+                .iter()
+                .chain(&rule.destination_countries)
+        })
+        .cloned()
+        .collect()
+}
+
+/// Per-country traffic of country rules, busiest first. Counters are
+/// packets, so `connections` counts matched packets; for `drop` rules that
+/// is close to the number of blocked attempts.
+pub fn stats(hits: &CountryHitMap, dataset: Option<&GeoIpDataset>) -> Vec<GeoStats> {
+    let mut stats: Vec<GeoStats> = hits
+        .iter()
+        .map(|(code, hits)| GeoStats {
+            country_code: code.clone(),
+            country_name: dataset
+                .and_then(|d| d.countries.iter().find(|c| &c.code == code))
+                .and_then(|c| c.name.clone())
+                .unwrap_or_else(|| code.clone()),
+            bytes: hits.bytes,
+            connections: hits.packets,
+        })
+        .collect();
+    stats.sort_by(|a, b| {
+        b.bytes
+            .cmp(&a.bytes)
+            .then(a.country_code.cmp(&b.country_code))
+    });
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATASET: &str = "network,country_code,country_name\n\
+        # comment\n\
+        198.51.100.0/25,NL,Netherlands\n\
+        198.51.100.128/25,nl\n\
+        2001:db8::/32,NL\n\
+        203.0.113.1-203.0.113.2,DE,Germany\n";
+
+    #[test]
+    fn test_countries_are_aggregated() {
+        let dataset = parse(DATASET).unwrap();
+        assert_eq!(dataset.skipped_ipv6, 1);
+        assert_eq!(dataset.countries["NL"].networks, ["198.51.100.0/24"]);
+        assert_eq!(dataset.countries["NL"].name.as_deref(), Some("Netherlands"));
+        assert_eq!(
+            dataset.countries["DE"].networks,
+            ["203.0.113.1/32", "203.0.113.2/32"]
+        );
+    }
+
+    #[test]
+    fn test_bad_lines_are_reported() {
+        let err = parse("10.0.0.0/8,XYZ\nnot-an-ip,DE\n").unwrap_err();
+        let messages: Vec<String> = err
+            .error
+            .details
+            .and_then(|d| d.validation_errors)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.message)
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "line 1: 'XYZ' is not a two-letter country code",
+                "line 2: 'not-an-ip' is not an IPv4 network"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_only_changed_countries_are_reported() {
+        let old = parse(DATASET).unwrap().index(1);
+        let new = parse(
+            "198.51.100.0/24,NL\n\
+             203.0.113.1-203.0.113.3,DE\n\
+             192.0.2.0/24,FR\n",
+        )
+        .unwrap()
+        .index(2);
+        assert_eq!(changed(Some(&old), &new), ["DE", "FR"]);
+        assert_ne!(old.version, new.version);
+        assert_eq!(changed(None, &old), ["DE", "NL"]);
+    }
+}
@@ -1,17 +1,18 @@
 //! Router agent WebSocket handler
 
-use crate::middleware::authenticate_device;
+use crate::middleware::{RequestAuth, authenticate_device};
 use worker::*;
 
 /// GET /agent/ws - WebSocket endpoint for router agents
-pub async fn websocket_handler(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+pub async fn websocket_handler(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
     // Extract API key from query parameter or header
     let api_key = extract_api_key(&req)?;
 
     // Authenticate the device
-    let device_auth = authenticate_device(&api_key, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    let device_auth = match authenticate_device(&api_key, &ctx.env).await {
+        Ok(device_auth) => device_auth,
+        Err(e) => return e.into_response(),
+    };
 
     // Get or create the Durable Object for this device
     let namespace = ctx.env.durable_object("AGENT_CONNECTIONS")?;
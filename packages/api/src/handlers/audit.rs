@@ -0,0 +1,100 @@
+//! Audit log handlers
+//!
+//! Implements:
+//! - `GET /api/audit` - List audit entries with filtering and pagination
+//! - `POST /api/audit/export` - Export audit entries (JSON or CSV)
+//!
+//! Entries are written by the audit middleware for every successful
+//! PUT/POST/DELETE request. Within an organization, members with the read
+//! permission see the entries for its devices; otherwise users only see
+//! their own entries.
+
+use crate::middleware::{RequestAuth, authenticated, device_scope};
+use crate::models::IntoApiResponse;
+use crate::models::audit::*;
+use crate::models::orgs::Permission;
+use crate::storage;
+use worker::*;
+
+/// GET /api/audit
+///
+/// Query parameters: device_id, action, resource_type, resource_id,
+/// date_start, date_end (Unix seconds), limit (default 50, max 200),
+/// offset (default 0).
+pub async fn list_audit_logs(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+
+    let url = req.url()?;
+    let query = parse_audit_list_query(&url);
+
+    let device_id = query.filter.device_id.as_deref();
+    let scope = match device_scope(&auth, device_id, Permission::Read, &ctx.env).await {
+        Ok(scope) => scope,
+        Err(e) => return e.into_response(),
+    };
+    let result = storage::list_audit_logs(scope, &query, &ctx.env).await;
+    result.into_api_response()
+}
+
+/// POST /api/audit/export
+///
+/// Accepts a JSON body with format (json|csv) and the same optional
+/// filters as `GET /audit`. Writes the export file to R2 (REPORTS bucket)
+/// and returns a 202 response with the export_id and download URL.
+pub async fn export_audit_logs(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+
+    let export_req: AuditExportRequest =
+        req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
+
+    let device_id = export_req.filter.device_id.as_deref();
+    let scope = match device_scope(&auth, device_id, Permission::Read, &ctx.env).await {
+        Ok(scope) => scope,
+        Err(e) => return e.into_response(),
+    };
+    match storage::export_audit_logs(scope, &auth.user_id, &export_req, &ctx.env).await {
+        Ok(resp) => Ok(Response::from_json(&resp)?.with_status(202)),
+        Err(e) => e.into_response(),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Helpers
+// ---------------------------------------------------------------------------
+
+/// Parse `AuditListQuery` from URL query parameters.
+fn parse_audit_list_query(url: &Url) -> AuditListQuery {
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+
+    let get = |name: &str| -> Option<String> {
+        pairs
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.clone())
+    };
+
+    AuditListQuery {
+        filter: AuditFilter {
+            device_id: get("device_id"),
+            action: get("action"),
+            resource_type: get("resource_type"),
+            resource_id: get("resource_id"),
+            date_start: get("date_start").and_then(|v| v.parse().ok()),
+            date_end: get("date_end").and_then(|v| v.parse().ok()),
+        },
+        limit: get("limit").and_then(|v| v.parse().ok()),
+        offset: get("offset").and_then(|v| v.parse().ok()),
+    }
+}
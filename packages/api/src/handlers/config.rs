@@ -0,0 +1,31 @@
+//! Configuration consistency handlers
+//!
+//! Implements:
+//! - `GET /config/lint` - Check the device's stored sections against each other
+
+use crate::consistency;
+use crate::middleware::{RequestAuth, authenticated, check_device_access};
+use crate::models::IntoApiResponse;
+use crate::models::orgs::Permission;
+use worker::*;
+
+fn get_device_id(req: &Request) -> Result<String> {
+    req.headers()
+        .get("X-Device-ID")?
+        .ok_or_else(|| Error::from("Missing X-Device-ID header"))
+}
+
+/// GET /api/config/lint
+pub async fn lint_config(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let device_id = get_device_id(&req)?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let report = consistency::lint_device(&device_id, &ctx.env).await;
+    report.into_api_response()
+}
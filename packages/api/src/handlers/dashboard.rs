@@ -1,25 +1,25 @@
 //! Dashboard handlers (computed/aggregated views)
 
-use crate::middleware::authenticate;
+use crate::middleware::{RequestAuth, authenticated};
 use crate::models::IntoApiResponse;
 use crate::storage;
 use worker::*;
 
 /// GET /api/dashboards/
-pub async fn list_dashboards(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let _auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn list_dashboards(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    if let Err(e) = authenticated(&ctx) {
+        return e.into_response();
+    }
 
     let dashboards = storage::list_dashboards();
     dashboards.into_api_response()
 }
 
 /// GET /api/dashboards/:id
-pub async fn get_dashboard(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let _auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_dashboard(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    if let Err(e) = authenticated(&ctx) {
+        return e.into_response();
+    }
 
     let dashboard_id = ctx
         .param("id")
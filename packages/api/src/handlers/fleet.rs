@@ -1,7 +1,10 @@
 //! Fleet management handlers
 
-use crate::middleware::{authenticate, check_device_access, require_plan};
+use crate::middleware::{
+    RequestAuth, authenticated, check_device_access, require_permission, require_plan,
+};
 use crate::models::fleet::*;
+use crate::models::orgs::Permission;
 use crate::models::{ApiError, IntoApiResponse};
 use crate::storage;
 use worker::*;
@@ -9,24 +12,35 @@ use worker::*;
 // ========== Device Management Handlers ==========
 
 /// GET /api/fleet/devices
-pub async fn get_devices(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    let devices = storage::get_user_devices(&auth.user_id, &ctx.env).await;
+///
+/// Lists the active organization's devices, or the user's personal ones.
+pub async fn get_devices(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let devices = match &auth.org_id {
+        Some(org_id) => storage::get_org_devices(org_id, &ctx.env).await,
+        None => storage::get_user_devices(&auth.user_id, &ctx.env).await,
+    };
     devices.into_api_response()
 }
 
 /// POST /api/fleet/devices
-pub async fn register_device(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn register_device(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_permission(&auth, Permission::Configure) {
+        return e.into_response();
+    }
 
     // Check device limit based on plan
-    let current_devices = storage::count_user_devices(&auth.user_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    let current_devices = match storage::count_user_devices(&auth.user_id, &ctx.env).await {
+        Ok(current_devices) => current_devices,
+        Err(e) => return e.into_response(),
+    };
 
     let limit = match auth.plan.to_lowercase().as_str() {
         "starter" => 10,
@@ -44,56 +58,69 @@ pub async fn register_device(mut req: Request, ctx: RouteContext<()>) -> Result<
 
     let register: RegisterDeviceRequest =
         req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::register_device(&auth.user_id, &register, &ctx.env).await;
+    let result =
+        storage::register_device(&auth.user_id, auth.org_id.as_deref(), &register, &ctx.env).await;
     result.into_api_response()
 }
 
 /// DELETE /api/fleet/devices/:id
-pub async fn remove_device(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn remove_device(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing device ID"))?;
-    check_device_access(&auth, device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
 
     let result = storage::remove_device(device_id, &ctx.env).await;
     result.into_api_response()
 }
 
 /// GET /api/fleet/devices/:id/status
-pub async fn get_device_status(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_device_status(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing device ID"))?;
-    check_device_access(&auth, device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let status = storage::get_device_status(device_id, &ctx.env).await;
     status.into_api_response()
 }
 
 /// POST /api/fleet/devices/:id/command
-pub async fn send_command(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn send_command(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing device ID"))?;
-    check_device_access(&auth, device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-
     let command: DeviceCommand = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
 
+    // Operational commands need the operator role, disruptive ones admin
+    let permission = match command.command {
+        CommandType::Shutdown | CommandType::ApplyConfig => Permission::Configure,
+        CommandType::Reboot
+        | CommandType::RefreshStatus
+        | CommandType::RunDiagnostics
+        | CommandType::ClearCache
+        | CommandType::RestartService => Permission::Operate,
+    };
+    if let Err(e) = check_device_access(&auth, device_id, permission, &ctx.env).await {
+        return e.into_response();
+    }
+
     // Get the Durable Object for this device
     let namespace = ctx
         .env
@@ -136,24 +163,34 @@ pub async fn send_command(mut req: Request, ctx: RouteContext<()>) -> Result<Res
 // ========== Template Handlers ==========
 
 /// GET /api/fleet/templates
-pub async fn get_templates(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_templates(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["business", "business_plus"]) {
+        return e.into_response();
+    }
+    if let Err(e) = require_permission(&auth, Permission::Read) {
+        return e.into_response();
+    }
 
     let templates = storage::get_config_templates(&auth.user_id, &ctx.env).await;
     templates.into_api_response()
 }
 
 /// POST /api/fleet/templates
-pub async fn create_template(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn create_template(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["business", "business_plus"]) {
+        return e.into_response();
+    }
+    if let Err(e) = require_permission(&auth, Permission::Configure) {
+        return e.into_response();
+    }
 
     let template: CreateTemplateRequest =
         req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
@@ -162,12 +199,17 @@ pub async fn create_template(mut req: Request, ctx: RouteContext<()>) -> Result<
 }
 
 /// POST /api/fleet/templates/:id/apply
-pub async fn apply_template(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn apply_template(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["business", "business_plus"]) {
+        return e.into_response();
+    }
+    if let Err(e) = require_permission(&auth, Permission::Configure) {
+        return e.into_response();
+    }
 
     let template_id = ctx
         .param("id")
@@ -176,9 +218,10 @@ pub async fn apply_template(mut req: Request, ctx: RouteContext<()>) -> Result<R
 
     // Verify access to all devices
     for device_id in &apply.device_ids {
-        check_device_access(&auth, device_id, &ctx.env)
-            .await
-            .map_err(|e| Error::from(e.error.message))?;
+        if let Err(e) = check_device_access(&auth, device_id, Permission::Configure, &ctx.env).await
+        {
+            return e.into_response();
+        }
     }
 
     let result = storage::apply_config_template(template_id, &apply, &ctx.env).await;
@@ -4,8 +4,9 @@
 //! - `GET /api/logs/` - List logs with filtering and pagination
 //! - `POST /api/logs/export` - Export logs as an async job (JSON or CSV)
 
-use crate::middleware::authenticate;
+use crate::middleware::{RequestAuth, authenticated, check_device_access, device_scope};
 use crate::models::logs::*;
+use crate::models::orgs::Permission;
 use crate::models::IntoApiResponse;
 use crate::storage;
 use worker::*;
@@ -15,35 +16,53 @@ use worker::*;
 /// Query parameters: device_id, level, category, search, date_start, date_end,
 /// limit (default 20), offset (default 0).
 ///
-/// Authorization is enforced via the D1 query: logs are joined with the
-/// `devices` table on `owner_id` so that only logs for devices owned by
-/// the authenticated user are returned.
-pub async fn list_logs(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+/// Requires the read permission on the given device or, without a device
+/// filter, on the active organization, whose devices' logs are returned.
+pub async fn list_logs(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
 
     // Parse query parameters from the URL
     let url = req.url()?;
     let query = parse_log_list_query(&url);
 
-    let result = storage::list_logs(&auth.user_id, &query, &ctx.env).await;
+    let scope = match device_scope(
+        &auth,
+        query.device_id.as_deref(),
+        Permission::Read,
+        &ctx.env,
+    )
+    .await
+    {
+        Ok(scope) => scope,
+        Err(e) => return e.into_response(),
+    };
+    let result = storage::list_logs(scope, &query, &ctx.env).await;
     result.into_api_response()
 }
 
 /// POST /api/logs/export
 ///
 /// Accepts a JSON body with device_id, format (json|csv), optional level,
-/// category, date_start, and date_end. Verifies device ownership via D1,
-/// queries matching logs, writes the export file to R2 (REPORTS bucket),
+/// category, date_start, and date_end. Requires the read permission on the
+/// device, queries matching logs, writes the export file to R2 (REPORTS bucket),
 /// and returns a 202 response with the export_id and download URL.
-pub async fn export_logs(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn export_logs(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
 
     let export_req: LogExportRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
 
+    if let Err(e) =
+        check_device_access(&auth, &export_req.device_id, Permission::Read, &ctx.env).await
+    {
+        return e.into_response();
+    }
+
     let result = storage::export_logs(&auth.user_id, &export_req, &ctx.env).await;
 
     match result {
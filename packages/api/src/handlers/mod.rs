@@ -1,14 +1,19 @@
 //! Request handlers for the NGFW.sh API
 
 pub mod agent;
+pub mod audit;
+pub mod config;
 pub mod dashboard;
 pub mod fleet;
 pub mod logs;
 pub mod network;
 pub mod onboarding;
+pub mod orgs;
 pub mod report;
 pub mod router;
 pub mod security;
 pub mod services;
 pub mod system;
+pub mod tokens;
 pub mod user;
+pub mod webhooks;
@@ -1,8 +1,13 @@
 //! Network configuration handlers (WAN, LAN, WiFi, DHCP)
 
-use crate::middleware::{authenticate, check_device_access};
+use crate::middleware::{
+    RequestAuth, authenticated, check_device_access, precondition_response, require_if_match,
+    respond_with_etag,
+};
 use crate::models::IntoApiResponse;
 use crate::models::network::*;
+use crate::models::orgs::Permission;
+use crate::models::validation::Validate;
 use crate::storage;
 use worker::*;
 
@@ -15,71 +20,87 @@ fn get_device_id(req: &Request) -> Result<String> {
 // ========== WAN Handlers ==========
 
 /// GET /api/wan/config
-pub async fn get_wan_config(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_wan_config(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let config = storage::get_config::<WanConfig>(&device_id, "wan", &ctx.env).await;
-    config.into_api_response()
+    respond_with_etag(config, &device_id, "wan", &ctx.env).await
 }
 
 /// PUT /api/wan/config
-pub async fn update_wan_config(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_wan_config(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "wan", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let config: WanConfig = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::update_config(&device_id, "wan", &config, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = config.validate() {
+        return e.into_response();
+    }
+    let result = storage::update_config(&device_id, "wan", &config, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "wan", &ctx.env).await
 }
 
 /// POST /api/wan/renew
-pub async fn renew_dhcp(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn renew_dhcp(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Operate, &ctx.env).await {
+        return e.into_response();
+    }
 
     let result = storage::send_command(&device_id, "wan_renew", None, &ctx.env).await;
     result.into_api_response()
 }
 
 /// POST /api/wan/release
-pub async fn release_dhcp(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn release_dhcp(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Operate, &ctx.env).await {
+        return e.into_response();
+    }
 
     let result = storage::send_command(&device_id, "wan_release", None, &ctx.env).await;
     result.into_api_response()
 }
 
 /// GET /api/wan/status
-pub async fn get_wan_status(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_wan_status(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let status = storage::get_wan_status(&device_id, &ctx.env).await;
     status.into_api_response()
@@ -88,205 +109,289 @@ pub async fn get_wan_status(req: Request, ctx: RouteContext<()>) -> Result<Respo
 // ========== LAN Handlers ==========
 
 /// GET /api/lan/config
-pub async fn get_lan_config(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_lan_config(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let config = storage::get_config::<LanConfig>(&device_id, "lan", &ctx.env).await;
-    config.into_api_response()
+    respond_with_etag(config, &device_id, "lan", &ctx.env).await
 }
 
 /// PUT /api/lan/config
-pub async fn update_lan_config(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_lan_config(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "lan", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let config: LanConfig = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::update_config(&device_id, "lan", &config, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = config.validate() {
+        return e.into_response();
+    }
+    let result = storage::update_config(&device_id, "lan", &config, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "lan", &ctx.env).await
 }
 
 /// GET /api/lan/vlans
-pub async fn get_vlans(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_vlans(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let vlans = storage::get_vlans(&device_id, &ctx.env).await;
-    vlans.into_api_response()
+    respond_with_etag(vlans, &device_id, "vlans", &ctx.env).await
 }
 
 /// POST /api/lan/vlans
-pub async fn create_vlan(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn create_vlan(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "vlans", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let vlan: VlanConfig = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::create_vlan(&device_id, &vlan, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = vlan.validate() {
+        return e.into_response();
+    }
+    let result = storage::create_vlan(&device_id, &vlan, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "vlans", &ctx.env).await
 }
 
 /// PUT /api/lan/vlans/:id
-pub async fn update_vlan(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_vlan(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "vlans", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let vlan_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing VLAN ID"))?;
     let vlan: VlanConfig = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::update_vlan(&device_id, vlan_id, &vlan, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = vlan.validate() {
+        return e.into_response();
+    }
+    let result = storage::update_vlan(&device_id, vlan_id, &vlan, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "vlans", &ctx.env).await
 }
 
 /// DELETE /api/lan/vlans/:id
-pub async fn delete_vlan(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn delete_vlan(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "vlans", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let vlan_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing VLAN ID"))?;
-    let result = storage::delete_vlan(&device_id, vlan_id, &ctx.env).await;
-    result.into_api_response()
+    let result = storage::delete_vlan(&device_id, vlan_id, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "vlans", &ctx.env).await
 }
 
 // ========== WiFi Handlers ==========
 
 /// GET /api/wifi/radios
-pub async fn get_wifi_radios(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_wifi_radios(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let radios = storage::get_wifi_radios(&device_id, &ctx.env).await;
-    radios.into_api_response()
+    respond_with_etag(radios, &device_id, "wifi_radios", &ctx.env).await
 }
 
 /// PUT /api/wifi/radios/:id
-pub async fn update_wifi_radio(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_wifi_radio(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "wifi_radios", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let radio_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing radio ID"))?;
     let radio: WifiRadio = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::update_wifi_radio(&device_id, radio_id, &radio, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = radio.validate() {
+        return e.into_response();
+    }
+    let result = storage::update_wifi_radio(&device_id, radio_id, &radio, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "wifi_radios", &ctx.env).await
 }
 
 /// GET /api/wifi/networks
-pub async fn get_wifi_networks(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_wifi_networks(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let networks = storage::get_wifi_networks(&device_id, &ctx.env).await;
-    networks.into_api_response()
+    respond_with_etag(networks, &device_id, "wifi_networks", &ctx.env).await
 }
 
 /// POST /api/wifi/networks
-pub async fn create_wifi_network(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn create_wifi_network(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "wifi_networks", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let network: WifiNetwork = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::create_wifi_network(&device_id, &network, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = network.validate() {
+        return e.into_response();
+    }
+    let result = storage::create_wifi_network(&device_id, &network, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "wifi_networks", &ctx.env).await
 }
 
 /// PUT /api/wifi/networks/:id
-pub async fn update_wifi_network(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_wifi_network(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "wifi_networks", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let network_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing network ID"))?;
     let network: WifiNetwork = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::update_wifi_network(&device_id, network_id, &network, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = network.validate() {
+        return e.into_response();
+    }
+    let result =
+        storage::update_wifi_network(&device_id, network_id, &network, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "wifi_networks", &ctx.env).await
 }
 
 /// DELETE /api/wifi/networks/:id
-pub async fn delete_wifi_network(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn delete_wifi_network(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "wifi_networks", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let network_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing network ID"))?;
-    let result = storage::delete_wifi_network(&device_id, network_id, &ctx.env).await;
-    result.into_api_response()
+    let result = storage::delete_wifi_network(&device_id, network_id, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "wifi_networks", &ctx.env).await
 }
 
 /// GET /api/wifi/clients
-pub async fn get_wifi_clients(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_wifi_clients(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let clients = storage::get_wifi_clients(&device_id, &ctx.env).await;
     clients.into_api_response()
@@ -295,57 +400,72 @@ pub async fn get_wifi_clients(req: Request, ctx: RouteContext<()>) -> Result<Res
 // ========== DHCP Handlers ==========
 
 /// GET /api/dhcp/config
-pub async fn get_dhcp_config(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_dhcp_config(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let config = storage::get_config::<DhcpConfig>(&device_id, "dhcp", &ctx.env).await;
-    config.into_api_response()
+    respond_with_etag(config, &device_id, "dhcp", &ctx.env).await
 }
 
 /// PUT /api/dhcp/config
-pub async fn update_dhcp_config(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_dhcp_config(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "dhcp", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let config: DhcpConfig = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::update_config(&device_id, "dhcp", &config, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = config.validate() {
+        return e.into_response();
+    }
+    let result = storage::update_config(&device_id, "dhcp", &config, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "dhcp", &ctx.env).await
 }
 
 /// GET /api/dhcp/leases
-pub async fn get_dhcp_leases(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_dhcp_leases(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let leases = storage::get_dhcp_leases(&device_id, &ctx.env).await;
     leases.into_api_response()
 }
 
 /// DELETE /api/dhcp/leases/:ip
-pub async fn revoke_lease(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn revoke_lease(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Operate, &ctx.env).await {
+        return e.into_response();
+    }
 
     let ip = ctx
         .param("ip")
@@ -355,113 +475,158 @@ pub async fn revoke_lease(req: Request, ctx: RouteContext<()>) -> Result<Respons
 }
 
 /// GET /api/dhcp/reservations
-pub async fn get_reservations(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_reservations(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let reservations = storage::get_dhcp_reservations(&device_id, &ctx.env).await;
-    reservations.into_api_response()
+    respond_with_etag(reservations, &device_id, "dhcp_reservations", &ctx.env).await
 }
 
 /// POST /api/dhcp/reservations
-pub async fn create_reservation(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn create_reservation(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "dhcp_reservations", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let reservation: DhcpReservation = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::create_dhcp_reservation(&device_id, &reservation, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = reservation.validate() {
+        return e.into_response();
+    }
+    let result =
+        storage::create_dhcp_reservation(&device_id, &reservation, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "dhcp_reservations", &ctx.env).await
 }
 
 /// DELETE /api/dhcp/reservations/:mac
-pub async fn delete_reservation(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn delete_reservation(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "dhcp_reservations", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let mac = ctx
         .param("mac")
         .ok_or_else(|| Error::from("Missing MAC address"))?;
-    let result = storage::delete_dhcp_reservation(&device_id, mac, &ctx.env).await;
-    result.into_api_response()
+    let result = storage::delete_dhcp_reservation(&device_id, mac, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "dhcp_reservations", &ctx.env).await
 }
 
 // ========== Routing Handlers ==========
 
 /// GET /routing/routes
-pub async fn get_routes(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_routes(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let routes = storage::get_routes(&device_id, &ctx.env).await;
-    routes.into_api_response()
+    respond_with_etag(routes, &device_id, "routes", &ctx.env).await
 }
 
 /// POST /routing/routes
-pub async fn create_route(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn create_route(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "routes", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let route: RouteRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::create_route(&device_id, &route, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = route.validate() {
+        return e.into_response();
+    }
+    let result = storage::create_route(&device_id, &route, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "routes", &ctx.env).await
 }
 
 /// PUT /routing/routes/:id
-pub async fn update_route(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_route(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "routes", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let route_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing route ID"))?;
     let route: RouteRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::update_route(&device_id, route_id, &route, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = route.validate() {
+        return e.into_response();
+    }
+    let result = storage::update_route(&device_id, route_id, &route, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "routes", &ctx.env).await
 }
 
 /// DELETE /routing/routes/:id
-pub async fn delete_route(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn delete_route(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "routes", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let route_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing route ID"))?;
-    let result = storage::delete_route(&device_id, route_id, &ctx.env).await;
-    result.into_api_response()
+    let result = storage::delete_route(&device_id, route_id, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "routes", &ctx.env).await
 }
@@ -3,6 +3,7 @@
 //! These endpoints are PUBLIC (no auth required) to support
 //! the initial onboarding experience before a user has an account.
 
+use crate::middleware::RequestAuth;
 use crate::models::onboarding::*;
 use crate::models::IntoApiResponse;
 use crate::storage;
@@ -10,14 +11,14 @@ use worker::*;
 
 /// GET /api/onboarding/routers
 /// List available router options for purchase (no auth required)
-pub async fn list_routers(_req: Request, _ctx: RouteContext<()>) -> Result<Response> {
+pub async fn list_routers(_req: Request, _ctx: RouteContext<RequestAuth>) -> Result<Response> {
     let routers = storage::get_onboarding_routers();
     routers.into_api_response()
 }
 
 /// POST /api/onboarding/order
 /// Create a new router order with device configuration
-pub async fn create_order(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
+pub async fn create_order(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
     let order: OrderSubmission = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
     let result = storage::create_onboarding_order(&order, &ctx.env).await;
 
@@ -35,7 +36,7 @@ pub async fn create_order(mut req: Request, ctx: RouteContext<()>) -> Result<Res
 
 /// GET /api/onboarding/status
 /// Get onboarding progress (no auth required)
-pub async fn get_status(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+pub async fn get_status(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
     let url = req.url()?;
     let user_id = url
         .query_pairs()
@@ -0,0 +1,320 @@
+//! Organization and membership handlers
+//!
+//! Implements:
+//! - `GET /orgs` - Organizations the caller belongs to, with their role
+//! - `POST /orgs` - Create an organization (caller becomes owner)
+//! - `GET /orgs/:id` - Get an organization
+//! - `PUT /orgs/:id` - Rename an organization (admin)
+//! - `DELETE /orgs/:id` - Delete an organization without devices (owner)
+//! - `GET /orgs/:id/members` - List members
+//! - `POST /orgs/:id/members` - Add a member (admin)
+//! - `PUT /orgs/:id/members/:user_id` - Change a member's role (admin)
+//! - `DELETE /orgs/:id/members/:user_id` - Remove a member (admin, or self)
+//! - `GET /orgs/:id/devices` - Devices belonging to the organization
+//! - `POST /orgs/:id/devices` - Move a device into the organization (admin)
+//!
+//! Only owners may grant or change the owner role, and an organization
+//! always keeps at least one owner.
+
+use crate::middleware::{
+    RequestAuth, authenticated, check_device_access, require_org_permission, require_plan,
+};
+use crate::models::orgs::*;
+use crate::models::{ApiError, ApiResult, IntoApiResponse};
+use crate::storage;
+use worker::*;
+
+/// Plans that can create organizations
+const ORG_PLANS: &[&str] = &["business", "business_plus"];
+
+fn get_org_id(ctx: &RouteContext<RequestAuth>) -> Result<String> {
+    ctx.param("id")
+        .cloned()
+        .ok_or_else(|| Error::from("Missing organization ID"))
+}
+
+fn get_member_id(ctx: &RouteContext<RequestAuth>) -> Result<String> {
+    ctx.param("user_id")
+        .cloned()
+        .ok_or_else(|| Error::from("Missing user ID"))
+}
+
+fn validate_name(name: &str) -> ApiResult<()> {
+    if name.trim().is_empty() {
+        return Err(ApiError::bad_request("name must not be empty").with_field("name"));
+    }
+    Ok(())
+}
+
+/// Check that the caller may change a membership from `current` to
+/// `requested` (`None` when removing it)
+async fn check_member_change(
+    caller: Role,
+    org_id: &str,
+    current: Option<Role>,
+    requested: Option<Role>,
+    env: &Env,
+) -> ApiResult<()> {
+    let touches_owner = current == Some(Role::Owner) || requested == Some(Role::Owner);
+    if touches_owner && caller != Role::Owner {
+        return Err(ApiError::forbidden(
+            "Only owners can grant or change the owner role",
+        ));
+    }
+    if current == Some(Role::Owner)
+        && requested != Some(Role::Owner)
+        && storage::count_org_owners(org_id, env).await? <= 1
+    {
+        return Err(ApiError::bad_request(
+            "An organization must keep at least one owner",
+        ));
+    }
+    Ok(())
+}
+
+/// GET /api/orgs
+pub async fn list_orgs(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+
+    let result = storage::list_user_orgs(&auth.user_id, &ctx.env).await;
+    result.into_api_response()
+}
+
+/// POST /api/orgs
+pub async fn create_org(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, ORG_PLANS) {
+        return e.into_response();
+    }
+
+    let body: CreateOrgRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
+    if let Err(e) = validate_name(&body.name) {
+        return e.into_response();
+    }
+
+    match storage::create_org(&auth.user_id, &body, &ctx.env).await {
+        Ok(organization) => Ok(Response::from_json(&OrgMembership {
+            organization,
+            role: Role::Owner,
+        })?
+        .with_status(201)),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// GET /api/orgs/:id
+pub async fn get_org(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let org_id = get_org_id(&ctx)?;
+
+    let result = storage::get_org(&auth.user_id, &org_id, &ctx.env).await;
+    result.into_api_response()
+}
+
+/// PUT /api/orgs/:id
+pub async fn update_org(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let org_id = get_org_id(&ctx)?;
+    if let Err(e) =
+        require_org_permission(&auth, &org_id, Permission::ManageMembers, &ctx.env).await
+    {
+        return e.into_response();
+    }
+
+    let body: UpdateOrgRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
+    if let Err(e) = validate_name(&body.name) {
+        return e.into_response();
+    }
+    if let Err(e) = storage::update_org(&org_id, &body, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let result = storage::get_org(&auth.user_id, &org_id, &ctx.env).await;
+    result.into_api_response()
+}
+
+/// DELETE /api/orgs/:id
+pub async fn delete_org(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let org_id = get_org_id(&ctx)?;
+    if let Err(e) =
+        require_org_permission(&auth, &org_id, Permission::DeleteOrganization, &ctx.env).await
+    {
+        return e.into_response();
+    }
+
+    match storage::delete_org(&org_id, &ctx.env).await {
+        Ok(()) => Ok(Response::empty()?.with_status(204)),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// GET /api/orgs/:id/members
+pub async fn list_members(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let org_id = get_org_id(&ctx)?;
+    if let Err(e) = require_org_permission(&auth, &org_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let result = storage::list_org_members(&org_id, &ctx.env).await;
+    result.into_api_response()
+}
+
+/// POST /api/orgs/:id/members
+pub async fn add_member(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let org_id = get_org_id(&ctx)?;
+    let caller =
+        match require_org_permission(&auth, &org_id, Permission::ManageMembers, &ctx.env).await {
+            Ok(role) => role,
+            Err(e) => return e.into_response(),
+        };
+
+    let body: AddMemberRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
+    let current = match storage::get_org_role(&org_id, &body.user_id, &ctx.env).await {
+        Ok(role) => role,
+        Err(e) => return e.into_response(),
+    };
+    if current.is_some() {
+        return ApiError::bad_request("User is already a member")
+            .with_field("user_id")
+            .into_response();
+    }
+    if let Err(e) = check_member_change(caller, &org_id, None, Some(body.role), &ctx.env).await {
+        return e.into_response();
+    }
+
+    match storage::upsert_org_member(&org_id, &body.user_id, body.role, &ctx.env).await {
+        Ok(member) => Ok(Response::from_json(&member)?.with_status(201)),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// PUT /api/orgs/:id/members/:user_id
+pub async fn update_member(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let org_id = get_org_id(&ctx)?;
+    let member_id = get_member_id(&ctx)?;
+    let caller =
+        match require_org_permission(&auth, &org_id, Permission::ManageMembers, &ctx.env).await {
+            Ok(role) => role,
+            Err(e) => return e.into_response(),
+        };
+
+    let body: UpdateMemberRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
+    let current = match storage::get_org_role(&org_id, &member_id, &ctx.env).await {
+        Ok(Some(role)) => role,
+        Ok(None) => return ApiError::not_found("Member").into_response(),
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) =
+        check_member_change(caller, &org_id, Some(current), Some(body.role), &ctx.env).await
+    {
+        return e.into_response();
+    }
+
+    let result = storage::upsert_org_member(&org_id, &member_id, body.role, &ctx.env).await;
+    result.into_api_response()
+}
+
+/// DELETE /api/orgs/:id/members/:user_id
+///
+/// Members may always remove themselves.
+pub async fn remove_member(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let org_id = get_org_id(&ctx)?;
+    let member_id = get_member_id(&ctx)?;
+
+    let permission = if member_id == auth.user_id {
+        Permission::Read
+    } else {
+        Permission::ManageMembers
+    };
+    let caller = match require_org_permission(&auth, &org_id, permission, &ctx.env).await {
+        Ok(role) => role,
+        Err(e) => return e.into_response(),
+    };
+
+    let current = match storage::get_org_role(&org_id, &member_id, &ctx.env).await {
+        Ok(Some(role)) => role,
+        Ok(None) => return ApiError::not_found("Member").into_response(),
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = check_member_change(caller, &org_id, Some(current), None, &ctx.env).await {
+        return e.into_response();
+    }
+
+    match storage::remove_org_member(&org_id, &member_id, &ctx.env).await {
+        Ok(()) => Ok(Response::empty()?.with_status(204)),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// GET /api/orgs/:id/devices
+pub async fn list_devices(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let org_id = get_org_id(&ctx)?;
+    if let Err(e) = require_org_permission(&auth, &org_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let result = storage::get_org_devices(&org_id, &ctx.env).await;
+    result.into_api_response()
+}
+
+/// POST /api/orgs/:id/devices
+///
+/// The caller needs to be allowed to configure the device where it is now
+/// (its owner, or an admin of its current organization) and in the target
+/// organization.
+pub async fn transfer_device(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let org_id = get_org_id(&ctx)?;
+    if let Err(e) = require_org_permission(&auth, &org_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let body: TransferDeviceRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
+    if let Err(e) =
+        check_device_access(&auth, &body.device_id, Permission::Configure, &ctx.env).await
+    {
+        return e.into_response();
+    }
+
+    let result = storage::set_device_org(&body.device_id, &org_id, &ctx.env).await;
+    result.into_api_response()
+}
@@ -1,16 +1,20 @@
 //! Report management handlers
 
-use crate::middleware::authenticate;
+use crate::middleware::{
+    AuthContext, RequestAuth, authenticated, check_device_access, device_scope,
+};
+use crate::models::orgs::Permission;
 use crate::models::report::*;
-use crate::models::{ApiError, IntoApiResponse};
+use crate::models::{ApiResult, IntoApiResponse};
 use crate::storage;
 use worker::*;
 
 /// GET /api/reports/
-pub async fn list_reports(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn list_reports(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
 
     let url = req.url()?;
     let query_pairs: Vec<(String, String)> = url
@@ -49,25 +53,33 @@ pub async fn list_reports(req: Request, ctx: RouteContext<()>) -> Result<Respons
         offset,
     };
 
-    let result = storage::list_reports(&auth.user_id, &query, &ctx.env).await;
+    let scope = match device_scope(
+        &auth,
+        query.device_id.as_deref(),
+        Permission::Read,
+        &ctx.env,
+    )
+    .await
+    {
+        Ok(scope) => scope,
+        Err(e) => return e.into_response(),
+    };
+    let result = storage::list_reports(scope, &query, &ctx.env).await;
     result.into_api_response()
 }
 
 /// POST /api/reports/generate
-pub async fn generate_report(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn generate_report(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
 
     let body: GenerateReportRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
 
-    // Verify device ownership
-    let device_check = storage::check_device_ownership(&body.device_id, &auth.user_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-
-    if !device_check {
-        return ApiError::not_found("Device not found or access denied").into_response();
+    if let Err(e) = check_device_access(&auth, &body.device_id, Permission::Operate, &ctx.env).await
+    {
+        return e.into_response();
     }
 
     let result = storage::create_report(&auth.user_id, &body, &ctx.env).await;
@@ -85,29 +97,50 @@ pub async fn generate_report(mut req: Request, ctx: RouteContext<()>) -> Result<
 }
 
 /// GET /api/reports/:id
-pub async fn get_report(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_report(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
 
     let report_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing report ID"))?;
 
-    let result = storage::get_report(report_id, &auth.user_id, &ctx.env).await;
+    if let Err(e) = check_report_access(&auth, report_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let result = storage::get_report(report_id, &ctx.env).await;
     result.into_api_response()
 }
 
 /// DELETE /api/reports/:id
-pub async fn delete_report(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn delete_report(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
 
     let report_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing report ID"))?;
 
-    let result = storage::delete_report(report_id, &auth.user_id, &ctx.env).await;
+    if let Err(e) = check_report_access(&auth, report_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let result = storage::delete_report(report_id, &ctx.env).await;
     result.into_api_response()
 }
+
+/// Check that the user holds `permission` on the device a report is for
+async fn check_report_access(
+    auth: &AuthContext,
+    report_id: &str,
+    permission: Permission,
+    env: &Env,
+) -> ApiResult<()> {
+    let device_id = storage::get_report_device(report_id, env).await?;
+    check_device_access(auth, &device_id, permission, env).await
+}
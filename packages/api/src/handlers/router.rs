@@ -1,7 +1,7 @@
 //! Main API router
 
-use crate::handlers::{agent, dashboard, fleet, logs, network, onboarding, report, security, services, system, user};
-use crate::middleware::{AuthContext, cors};
+use crate::handlers::{agent, audit, config, dashboard, fleet, logs, network, onboarding, orgs, report, security, services, system, tokens, user, webhooks};
+use crate::middleware::{RequestAuth, cors};
 use crate::models::ApiError;
 use crate::openapi::ApiDoc;
 use worker::*;
@@ -11,8 +11,11 @@ use worker::*;
 /// Routes are registered WITHOUT the `/api` prefix to match the frontend
 /// client at portal-astro (which calls e.g. `/fleet/devices`, not
 /// `/api/fleet/devices`). The domain `api.ngfw.sh` already implies API.
-pub fn build_router() -> Router<'static, ()> {
-    Router::new()
+///
+/// `auth` is the outcome of authenticating the request, shared with every
+/// handler as router data.
+pub fn build_router(auth: RequestAuth) -> Router<'static, RequestAuth> {
+    Router::with_data(auth)
         // Health check (no auth required)
         .get("/health", |_, _| Response::ok("OK"))
         // ========== OpenAPI specification (no auth required) ==========
@@ -37,6 +40,9 @@ pub fn build_router() -> Router<'static, ()> {
         .post_async("/system/shutdown", system::shutdown)
         // ========== Metrics endpoints ==========
         .get_async("/metrics/latest", system::get_latest_metrics)
+        .get_async("/metrics/history", system::get_metrics_history)
+        // ========== Config consistency endpoints ==========
+        .get_async("/config/lint", config::lint_config)
         // ========== WAN endpoints ==========
         .get_async("/wan/config", network::get_wan_config)
         .put_async("/wan/config", network::update_wan_config)
@@ -77,10 +83,27 @@ pub fn build_router() -> Router<'static, ()> {
         .put_async("/firewall/rules/:id", security::update_firewall_rule)
         .delete_async("/firewall/rules/:id", security::delete_firewall_rule)
         .put_async("/firewall/rules/order", security::reorder_rules)
+        .get_async("/firewall/groups/addresses", security::get_address_groups)
+        .post_async("/firewall/groups/addresses", security::create_address_group)
+        .put_async("/firewall/groups/addresses/:id", security::update_address_group)
+        .delete_async("/firewall/groups/addresses/:id", security::delete_address_group)
+        .get_async("/firewall/groups/ports", security::get_port_groups)
+        .post_async("/firewall/groups/ports", security::create_port_group)
+        .put_async("/firewall/groups/ports/:id", security::update_port_group)
+        .delete_async("/firewall/groups/ports/:id", security::delete_port_group)
+        .get_async("/firewall/geoip", security::get_geoip_dataset)
+        .put_async("/firewall/geoip", security::upload_geoip_dataset)
+        .get_async("/firewall/feeds", security::get_threat_feeds)
+        .post_async("/firewall/feeds", security::create_threat_feed)
+        .put_async("/firewall/feeds/:id", security::update_threat_feed)
+        .delete_async("/firewall/feeds/:id", security::delete_threat_feed)
+        .post_async("/firewall/feeds/:id/update", security::update_threat_feed_now)
         .get_async("/firewall/zones", security::get_zones)
         .put_async("/firewall/zones/:id", security::update_zone)
         .get_async("/firewall/policies", security::get_policies)
         .put_async("/firewall/policies", security::update_policies)
+        .post_async("/firewall/trace", security::trace_packet)
+        .get_async("/firewall/trace/:id", security::get_live_trace)
         // ========== NAT endpoints ==========
         .get_async("/nat/rules", security::get_nat_rules)
         .post_async("/nat/rules", security::create_nat_rule)
@@ -171,6 +194,9 @@ pub fn build_router() -> Router<'static, ()> {
         // ========== Logs endpoints ==========
         .get_async("/logs", logs::list_logs)
         .post_async("/logs/export", logs::export_logs)
+        // ========== Audit log endpoints ==========
+        .get_async("/audit", audit::list_audit_logs)
+        .post_async("/audit/export", audit::export_audit_logs)
         // ========== Reports endpoints ==========
         .get_async("/reports", report::list_reports)
         .post_async("/reports/generate", report::generate_report)
@@ -188,6 +214,26 @@ pub fn build_router() -> Router<'static, ()> {
         .get_async("/fleet/templates", fleet::get_templates)
         .post_async("/fleet/templates", fleet::create_template)
         .post_async("/fleet/templates/:id/apply", fleet::apply_template)
+        // ========== Organization endpoints ==========
+        .get_async("/orgs", orgs::list_orgs)
+        .post_async("/orgs", orgs::create_org)
+        .get_async("/orgs/:id", orgs::get_org)
+        .put_async("/orgs/:id", orgs::update_org)
+        .delete_async("/orgs/:id", orgs::delete_org)
+        .get_async("/orgs/:id/members", orgs::list_members)
+        .post_async("/orgs/:id/members", orgs::add_member)
+        .put_async("/orgs/:id/members/:user_id", orgs::update_member)
+        .delete_async("/orgs/:id/members/:user_id", orgs::remove_member)
+        .get_async("/orgs/:id/devices", orgs::list_devices)
+        .post_async("/orgs/:id/devices", orgs::transfer_device)
+        // ========== Webhook endpoints (Business plans) ==========
+        .get_async("/webhooks", webhooks::list_webhooks)
+        .post_async("/webhooks", webhooks::create_webhook)
+        .get_async("/webhooks/:id", webhooks::get_webhook)
+        .put_async("/webhooks/:id", webhooks::update_webhook)
+        .delete_async("/webhooks/:id", webhooks::delete_webhook)
+        .get_async("/webhooks/:id/deliveries", webhooks::list_deliveries)
+        .post_async("/webhooks/:id/test", webhooks::send_test_event)
         // ========== User account endpoints ==========
         .get_async("/user/profile", user::get_profile)
         .put_async("/user/profile", user::update_profile)
@@ -197,6 +243,9 @@ pub fn build_router() -> Router<'static, ()> {
         .delete_async("/user/2fa", user::disable_2fa)
         .get_async("/user/sessions", user::get_sessions)
         .delete_async("/user/sessions/:id", user::revoke_session)
+        .get_async("/user/tokens", tokens::list_tokens)
+        .post_async("/user/tokens", tokens::create_token)
+        .delete_async("/user/tokens/:id", tokens::revoke_token)
         // ========== Billing endpoints ==========
         .get_async("/billing/plan", user::get_plan)
         .put_async("/billing/plan", user::change_plan)
@@ -219,11 +268,3 @@ pub fn build_router() -> Router<'static, ()> {
             ApiError::not_found("Endpoint").into_response()
         })
 }
-
-/// Helper to get authenticated context from request
-#[allow(dead_code)]
-pub async fn get_auth_context(req: &Request, env: &Env) -> Result<AuthContext> {
-    crate::middleware::authenticate(req, env)
-        .await
-        .map_err(|e| Error::from(e.error.message))
-}
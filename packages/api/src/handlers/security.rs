@@ -1,9 +1,18 @@
 //! Security handlers (Firewall, NAT, DNS, IDS, Traffic)
 
-use crate::middleware::{authenticate, check_device_access, require_plan};
+use crate::consistency::trace;
+use crate::geoip;
+use crate::middleware::{
+    RequestAuth, authenticated, check_device_access, precondition_response, require_if_match,
+    require_plan, respond_with_etag,
+};
 use crate::models::IntoApiResponse;
+use crate::models::orgs::Permission;
 use crate::models::security::*;
+use crate::models::validation::Validate;
+use crate::rpc::rule_hits;
 use crate::storage;
+use crate::threat_feeds;
 use worker::*;
 
 fn get_device_id(req: &Request) -> Result<String> {
@@ -15,234 +24,738 @@ fn get_device_id(req: &Request) -> Result<String> {
 // ========== Firewall Handlers ==========
 
 /// GET /api/firewall/rules
-pub async fn get_firewall_rules(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_firewall_rules(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-
-    let rules = storage::get_firewall_rules(&device_id, &ctx.env).await;
-    rules.into_api_response()
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
+
+    // Hits and schedule states are live, so they never change the rules' ETag
+    let rules = match storage::get_firewall_rules(&device_id, &ctx.env).await {
+        Ok(mut rules) => {
+            if let Ok(hits) = storage::get_rule_hits(&device_id, &ctx.env).await {
+                rule_hits::merge_into(&mut rules, &hits);
+            }
+            if let Ok(states) = storage::get_schedule_states(&device_id, &ctx.env).await {
+                rule_hits::merge_schedules(&mut rules, &states);
+            }
+            Ok(rules)
+        }
+        Err(e) => Err(e),
+    };
+    respond_with_etag(rules, &device_id, "firewall_rules", &ctx.env).await
 }
 
 /// POST /api/firewall/rules
-pub async fn create_firewall_rule(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn create_firewall_rule(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "firewall_rules", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let rule: FirewallRuleRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::create_firewall_rule(&device_id, &rule, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = rule.validate() {
+        return e.into_response();
+    }
+    let result = storage::create_firewall_rule(&device_id, &rule, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "firewall_rules", &ctx.env).await
 }
 
 /// PUT /api/firewall/rules/:id
-pub async fn update_firewall_rule(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_firewall_rule(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "firewall_rules", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let rule_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing rule ID"))?;
     let rule: FirewallRuleRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::update_firewall_rule(&device_id, rule_id, &rule, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = rule.validate() {
+        return e.into_response();
+    }
+    let result =
+        storage::update_firewall_rule(&device_id, rule_id, &rule, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "firewall_rules", &ctx.env).await
 }
 
 /// DELETE /api/firewall/rules/:id
-pub async fn delete_firewall_rule(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn delete_firewall_rule(
+    req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "firewall_rules", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let rule_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing rule ID"))?;
-    let result = storage::delete_firewall_rule(&device_id, rule_id, &ctx.env).await;
-    result.into_api_response()
+    let result = storage::delete_firewall_rule(&device_id, rule_id, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "firewall_rules", &ctx.env).await
 }
 
 /// PUT /api/firewall/rules/order
-pub async fn reorder_rules(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn reorder_rules(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "firewall_rules", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let order: RuleOrderRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::reorder_firewall_rules(&device_id, &order.rule_ids, &ctx.env).await;
+    let result =
+        storage::reorder_firewall_rules(&device_id, &order.rule_ids, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "firewall_rules", &ctx.env).await
+}
+
+/// GET /api/firewall/groups/addresses
+pub async fn get_address_groups(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let device_id = get_device_id(&req)?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let groups = storage::get_address_groups(&device_id, &ctx.env).await;
+    respond_with_etag(groups, &device_id, "address_groups", &ctx.env).await
+}
+
+/// POST /api/firewall/groups/addresses
+pub async fn create_address_group(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let device_id = get_device_id(&req)?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "address_groups", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
+
+    let group: AddressGroupRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
+    if let Err(e) = group.validate() {
+        return e.into_response();
+    }
+    let result = storage::create_address_group(&device_id, &group, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "address_groups", &ctx.env).await
+}
+
+/// PUT /api/firewall/groups/addresses/:id
+pub async fn update_address_group(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let device_id = get_device_id(&req)?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "address_groups", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
+
+    let group_id = ctx
+        .param("id")
+        .ok_or_else(|| Error::from("Missing group ID"))?;
+    let group: AddressGroupRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
+    if let Err(e) = group.validate() {
+        return e.into_response();
+    }
+    let result =
+        storage::update_address_group(&device_id, group_id, &group, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "address_groups", &ctx.env).await
+}
+
+/// DELETE /api/firewall/groups/addresses/:id
+pub async fn delete_address_group(
+    req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let device_id = get_device_id(&req)?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "address_groups", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
+
+    let group_id = ctx
+        .param("id")
+        .ok_or_else(|| Error::from("Missing group ID"))?;
+    let result = storage::delete_address_group(&device_id, group_id, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "address_groups", &ctx.env).await
+}
+
+/// GET /api/firewall/groups/ports
+pub async fn get_port_groups(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let device_id = get_device_id(&req)?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let groups = storage::get_port_groups(&device_id, &ctx.env).await;
+    respond_with_etag(groups, &device_id, "port_groups", &ctx.env).await
+}
+
+/// POST /api/firewall/groups/ports
+pub async fn create_port_group(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let device_id = get_device_id(&req)?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "port_groups", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
+
+    let group: PortGroupRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
+    if let Err(e) = group.validate() {
+        return e.into_response();
+    }
+    let result = storage::create_port_group(&device_id, &group, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "port_groups", &ctx.env).await
+}
+
+/// PUT /api/firewall/groups/ports/:id
+pub async fn update_port_group(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let device_id = get_device_id(&req)?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "port_groups", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
+
+    let group_id = ctx
+        .param("id")
+        .ok_or_else(|| Error::from("Missing group ID"))?;
+    let group: PortGroupRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
+    if let Err(e) = group.validate() {
+        return e.into_response();
+    }
+    let result = storage::update_port_group(&device_id, group_id, &group, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "port_groups", &ctx.env).await
+}
+
+/// DELETE /api/firewall/groups/ports/:id
+pub async fn delete_port_group(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let device_id = get_device_id(&req)?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "port_groups", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
+
+    let group_id = ctx
+        .param("id")
+        .ok_or_else(|| Error::from("Missing group ID"))?;
+    let result = storage::delete_port_group(&device_id, group_id, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "port_groups", &ctx.env).await
+}
+
+/// GET /api/firewall/geoip
+pub async fn get_geoip_dataset(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let device_id = get_device_id(&req)?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let dataset = storage::get_geoip_dataset(&device_id, &ctx.env).await;
+    dataset.into_api_response()
+}
+
+/// PUT /api/firewall/geoip (CSV body: `network,country_code[,country_name]`)
+pub async fn upload_geoip_dataset(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let device_id = get_device_id(&req)?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let text = req.text().await?;
+    let dataset = match geoip::parse(&text) {
+        Ok(dataset) => dataset,
+        Err(e) => return e.into_response(),
+    };
+    let result = storage::upload_geoip_dataset(&device_id, &dataset, &ctx.env).await;
     result.into_api_response()
 }
 
+/// GET /api/firewall/feeds
+pub async fn get_threat_feeds(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let device_id = get_device_id(&req)?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let feeds = storage::get_threat_feeds(&device_id, &ctx.env).await;
+    respond_with_etag(feeds, &device_id, "threat_feeds", &ctx.env).await
+}
+
+/// POST /api/firewall/feeds (the feed is fetched right away)
+pub async fn create_threat_feed(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let device_id = get_device_id(&req)?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "threat_feeds", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
+
+    let feed: ThreatFeedRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
+    if let Err(e) = feed.validate() {
+        return e.into_response();
+    }
+    let result = match storage::create_threat_feed(&device_id, &feed, expected, &ctx.env).await {
+        Ok(feed) if feed.enabled => threat_feeds::refresh(&device_id, &feed, &ctx.env).await,
+        result => result,
+    };
+    respond_with_etag(result, &device_id, "threat_feeds", &ctx.env).await
+}
+
+/// PUT /api/firewall/feeds/:id (refetched if its URL or format changed)
+pub async fn update_threat_feed(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let device_id = get_device_id(&req)?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "threat_feeds", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
+
+    let feed_id = ctx
+        .param("id")
+        .ok_or_else(|| Error::from("Missing feed ID"))?;
+    let feed: ThreatFeedRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
+    if let Err(e) = feed.validate() {
+        return e.into_response();
+    }
+    let result =
+        match storage::update_threat_feed(&device_id, feed_id, &feed, expected, &ctx.env).await {
+            Ok((feed, true)) if feed.enabled => {
+                threat_feeds::refresh(&device_id, &feed, &ctx.env).await
+            }
+            result => result.map(|(feed, _)| feed),
+        };
+    respond_with_etag(result, &device_id, "threat_feeds", &ctx.env).await
+}
+
+/// DELETE /api/firewall/feeds/:id
+pub async fn delete_threat_feed(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let device_id = get_device_id(&req)?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "threat_feeds", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
+
+    let feed_id = ctx
+        .param("id")
+        .ok_or_else(|| Error::from("Missing feed ID"))?;
+    let result = storage::delete_threat_feed(&device_id, feed_id, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "threat_feeds", &ctx.env).await
+}
+
+/// POST /api/firewall/feeds/:id/update
+pub async fn update_threat_feed_now(
+    req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let device_id = get_device_id(&req)?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let feed_id = ctx
+        .param("id")
+        .ok_or_else(|| Error::from("Missing feed ID"))?;
+    let result = match storage::get_threat_feed(&device_id, feed_id, &ctx.env).await {
+        Ok(feed) => threat_feeds::refresh(&device_id, &feed, &ctx.env).await,
+        Err(e) => Err(e),
+    };
+    respond_with_etag(result, &device_id, "threat_feeds", &ctx.env).await
+}
+
 /// GET /api/firewall/zones
-pub async fn get_zones(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_zones(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let zones = storage::get_firewall_zones(&device_id, &ctx.env).await;
-    zones.into_api_response()
+    respond_with_etag(zones, &device_id, "firewall_zones", &ctx.env).await
 }
 
 /// PUT /api/firewall/zones/:id
-pub async fn update_zone(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_zone(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "firewall_zones", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let zone_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing zone ID"))?;
     let zone: ZoneConfig = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::update_firewall_zone(&device_id, zone_id, &zone, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = zone.validate() {
+        return e.into_response();
+    }
+    let result =
+        storage::update_firewall_zone(&device_id, zone_id, &zone, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "firewall_zones", &ctx.env).await
 }
 
 /// GET /api/firewall/policies
-pub async fn get_policies(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_policies(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let policies = storage::get_zone_policies(&device_id, &ctx.env).await;
-    policies.into_api_response()
+    respond_with_etag(policies, &device_id, "zone_policies", &ctx.env).await
 }
 
 /// PUT /api/firewall/policies
-pub async fn update_policies(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_policies(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "zone_policies", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let policies: Vec<ZonePolicy> = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::update_zone_policies(&device_id, &policies, &ctx.env).await;
+    let result = storage::update_zone_policies(&device_id, &policies, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "zone_policies", &ctx.env).await
+}
+
+/// POST /api/firewall/trace
+pub async fn trace_packet(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let device_id = get_device_id(&req)?;
+
+    let request: TraceRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
+    if let Err(e) = request.validate() {
+        return e.into_response();
+    }
+
+    // Reading live counters runs a command on the router
+    let permission = if request.live {
+        Permission::Operate
+    } else {
+        Permission::Read
+    };
+    if let Err(e) = check_device_access(&auth, &device_id, permission, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let result = trace::trace_device(&device_id, &request, &ctx.env).await;
+    result.into_api_response()
+}
+
+/// GET /api/firewall/trace/:id
+pub async fn get_live_trace(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let device_id = get_device_id(&req)?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let command_id = ctx
+        .param("id")
+        .ok_or_else(|| Error::from("Missing trace ID"))?;
+    let result = trace::live_counters(&device_id, command_id, &ctx.env).await;
     result.into_api_response()
 }
 
 // ========== NAT Handlers ==========
 
 /// GET /api/nat/rules
-pub async fn get_nat_rules(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_nat_rules(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let rules = storage::get_nat_rules(&device_id, &ctx.env).await;
-    rules.into_api_response()
+    respond_with_etag(rules, &device_id, "nat_rules", &ctx.env).await
 }
 
 /// POST /api/nat/rules
-pub async fn create_nat_rule(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn create_nat_rule(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "nat_rules", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let rule: NatRule = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::create_nat_rule(&device_id, &rule, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = rule.validate() {
+        return e.into_response();
+    }
+    let result = storage::create_nat_rule(&device_id, &rule, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "nat_rules", &ctx.env).await
 }
 
 /// PUT /api/nat/rules/:id
-pub async fn update_nat_rule(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_nat_rule(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "nat_rules", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let rule_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing rule ID"))?;
     let rule: NatRule = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::update_nat_rule(&device_id, rule_id, &rule, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = rule.validate() {
+        return e.into_response();
+    }
+    let result = storage::update_nat_rule(&device_id, rule_id, &rule, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "nat_rules", &ctx.env).await
 }
 
 /// DELETE /api/nat/rules/:id
-pub async fn delete_nat_rule(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn delete_nat_rule(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "nat_rules", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let rule_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing rule ID"))?;
-    let result = storage::delete_nat_rule(&device_id, rule_id, &ctx.env).await;
-    result.into_api_response()
+    let result = storage::delete_nat_rule(&device_id, rule_id, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "nat_rules", &ctx.env).await
 }
 
 /// GET /api/nat/upnp
-pub async fn get_upnp_leases(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_upnp_leases(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let leases = storage::get_upnp_leases(&device_id, &ctx.env).await;
     leases.into_api_response()
 }
 
 /// DELETE /api/nat/upnp/:id
-pub async fn revoke_upnp_lease(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn revoke_upnp_lease(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Operate, &ctx.env).await {
+        return e.into_response();
+    }
 
     let lease_id = ctx
         .param("id")
@@ -254,14 +767,15 @@ pub async fn revoke_upnp_lease(req: Request, ctx: RouteContext<()>) -> Result<Re
 // ========== Traffic Logs Handlers ==========
 
 /// GET /api/traffic/logs
-pub async fn get_traffic_logs(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_traffic_logs(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let url = req.url()?;
     let logs = storage::get_traffic_logs(&device_id, url.query(), &ctx.env).await;
@@ -269,14 +783,15 @@ pub async fn get_traffic_logs(req: Request, ctx: RouteContext<()>) -> Result<Res
 }
 
 /// GET /api/traffic/logs/stream (WebSocket)
-pub async fn stream_traffic_logs(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn stream_traffic_logs(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     // Upgrade to WebSocket for real-time streaming
     let pair = WebSocketPair::new()?;
@@ -293,42 +808,48 @@ pub async fn stream_traffic_logs(req: Request, ctx: RouteContext<()>) -> Result<
 }
 
 /// GET /api/traffic/stats
-pub async fn get_traffic_stats(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_traffic_stats(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let stats = storage::get_traffic_stats(&device_id, &ctx.env).await;
     stats.into_api_response()
 }
 
 /// GET /api/traffic/top/clients
-pub async fn get_top_clients(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_top_clients(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let clients = storage::get_top_clients(&device_id, &ctx.env).await;
     clients.into_api_response()
 }
 
 /// GET /api/traffic/top/destinations
-pub async fn get_top_destinations(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_top_destinations(
+    req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let destinations = storage::get_top_destinations(&device_id, &ctx.env).await;
     destinations.into_api_response()
@@ -337,93 +858,124 @@ pub async fn get_top_destinations(req: Request, ctx: RouteContext<()>) -> Result
 // ========== DNS Filtering Handlers ==========
 
 /// GET /api/dns/config
-pub async fn get_dns_config(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_dns_config(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let config = storage::get_dns_config(&device_id, &ctx.env).await;
-    config.into_api_response()
+    respond_with_etag(config, &device_id, "dns", &ctx.env).await
 }
 
 /// PUT /api/dns/config
-pub async fn update_dns_config(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_dns_config(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "dns", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let config: DnsConfig = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::update_dns_config(&device_id, &config, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = config.validate() {
+        return e.into_response();
+    }
+    let result = storage::update_dns_config(&device_id, &config, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "dns", &ctx.env).await
 }
 
 /// GET /api/dns/blocklists
-pub async fn get_blocklists(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["starter", "pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_blocklists(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["starter", "pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let blocklists = storage::get_dns_blocklists(&device_id, &ctx.env).await;
-    blocklists.into_api_response()
+    respond_with_etag(blocklists, &device_id, "dns_blocklists", &ctx.env).await
 }
 
 /// POST /api/dns/blocklists
-pub async fn add_blocklist(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["starter", "pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn add_blocklist(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["starter", "pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "dns_blocklists", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let blocklist: DnsBlocklist = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::add_dns_blocklist(&device_id, &blocklist, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = blocklist.validate() {
+        return e.into_response();
+    }
+    let result = storage::add_dns_blocklist(&device_id, &blocklist, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "dns_blocklists", &ctx.env).await
 }
 
 /// DELETE /api/dns/blocklists/:id
-pub async fn remove_blocklist(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn remove_blocklist(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    if let Err(e) = require_if_match(&req, &device_id, "dns_blocklists", &ctx.env).await {
+        return precondition_response(e);
+    }
 
     let blocklist_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing blocklist ID"))?;
     let result = storage::remove_dns_blocklist(&device_id, blocklist_id, &ctx.env).await;
-    result.into_api_response()
+    respond_with_etag(result, &device_id, "dns_blocklists", &ctx.env).await
 }
 
 /// POST /api/dns/blocklists/:id/update
-pub async fn update_blocklist(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_blocklist(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Operate, &ctx.env).await {
+        return e.into_response();
+    }
 
     let blocklist_id = ctx
         .param("id")
@@ -433,60 +985,82 @@ pub async fn update_blocklist(req: Request, ctx: RouteContext<()>) -> Result<Res
 }
 
 /// GET /api/dns/allowlist
-pub async fn get_allowlist(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_allowlist(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let allowlist = storage::get_dns_allowlist(&device_id, &ctx.env).await;
-    allowlist.into_api_response()
+    respond_with_etag(allowlist, &device_id, "dns_allowlist", &ctx.env).await
 }
 
 /// POST /api/dns/allowlist
-pub async fn add_to_allowlist(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn add_to_allowlist(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "dns_allowlist", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let entry: DnsAllowlistEntry = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::add_to_dns_allowlist(&device_id, &entry, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = entry.validate() {
+        return e.into_response();
+    }
+    let result = storage::add_to_dns_allowlist(&device_id, &entry, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "dns_allowlist", &ctx.env).await
 }
 
 /// DELETE /api/dns/allowlist/:domain
-pub async fn remove_from_allowlist(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn remove_from_allowlist(
+    req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    if let Err(e) = require_if_match(&req, &device_id, "dns_allowlist", &ctx.env).await {
+        return precondition_response(e);
+    }
 
     let domain = ctx
         .param("domain")
         .ok_or_else(|| Error::from("Missing domain"))?;
     let result = storage::remove_from_dns_allowlist(&device_id, domain, &ctx.env).await;
-    result.into_api_response()
+    respond_with_etag(result, &device_id, "dns_allowlist", &ctx.env).await
 }
 
 /// GET /api/dns/queries
-pub async fn get_dns_queries(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_dns_queries(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let url = req.url()?;
     let queries = storage::get_dns_queries(&device_id, url.query(), &ctx.env).await;
@@ -494,14 +1068,15 @@ pub async fn get_dns_queries(req: Request, ctx: RouteContext<()>) -> Result<Resp
 }
 
 /// GET /api/dns/stats
-pub async fn get_dns_stats(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_dns_stats(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let stats = storage::get_dns_stats(&device_id, &ctx.env).await;
     stats.into_api_response()
@@ -510,137 +1085,182 @@ pub async fn get_dns_stats(req: Request, ctx: RouteContext<()>) -> Result<Respon
 // ========== IDS/IPS Handlers ==========
 
 /// GET /api/ids/config
-pub async fn get_ids_config(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_ids_config(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let config = storage::get_ids_config(&device_id, &ctx.env).await;
-    config.into_api_response()
+    respond_with_etag(config, &device_id, "ids", &ctx.env).await
 }
 
 /// PUT /api/ids/config
-pub async fn update_ids_config(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_ids_config(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "ids", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let config: IdsConfig = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::update_ids_config(&device_id, &config, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = config.validate() {
+        return e.into_response();
+    }
+    let result = storage::update_ids_config(&device_id, &config, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "ids", &ctx.env).await
 }
 
 /// GET /api/ids/categories
-pub async fn get_ids_categories(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_ids_categories(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let categories = storage::get_ids_categories(&device_id, &ctx.env).await;
-    categories.into_api_response()
+    respond_with_etag(categories, &device_id, "ids_categories", &ctx.env).await
 }
 
 /// PUT /api/ids/categories/:id
-pub async fn update_ids_category(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_ids_category(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "ids_categories", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let category_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing category ID"))?;
     let category: IdsCategory = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::update_ids_category(&device_id, category_id, &category, &ctx.env).await;
-    result.into_api_response()
+    let result =
+        storage::update_ids_category(&device_id, category_id, &category, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "ids_categories", &ctx.env).await
 }
 
 /// GET /api/ids/rules
-pub async fn get_ids_rules(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_ids_rules(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let rules = storage::get_ids_rules(&device_id, &ctx.env).await;
-    rules.into_api_response()
+    respond_with_etag(rules, &device_id, "ids_rules", &ctx.env).await
 }
 
 /// POST /api/ids/rules
-pub async fn create_ids_rule(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn create_ids_rule(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "ids_rules", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let rule: IdsRule = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::create_ids_rule(&device_id, &rule, &ctx.env).await;
-    result.into_api_response()
+    let result = storage::create_ids_rule(&device_id, &rule, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "ids_rules", &ctx.env).await
 }
 
 /// DELETE /api/ids/rules/:id
-pub async fn delete_ids_rule(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn delete_ids_rule(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    if let Err(e) = require_if_match(&req, &device_id, "ids_rules", &ctx.env).await {
+        return precondition_response(e);
+    }
 
     let rule_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing rule ID"))?;
     let result = storage::delete_ids_rule(&device_id, rule_id, &ctx.env).await;
-    result.into_api_response()
+    respond_with_etag(result, &device_id, "ids_rules", &ctx.env).await
 }
 
 /// GET /api/ids/alerts
-pub async fn get_ids_alerts(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_ids_alerts(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let url = req.url()?;
     let alerts = storage::get_ids_alerts(&device_id, url.query(), &ctx.env).await;
@@ -648,16 +1268,18 @@ pub async fn get_ids_alerts(req: Request, ctx: RouteContext<()>) -> Result<Respo
 }
 
 /// GET /api/ids/alerts/stream (WebSocket)
-pub async fn stream_ids_alerts(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
-    let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn stream_ids_alerts(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
+    let device_id = get_device_id(&req)?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let pair = WebSocketPair::new()?;
     let server = pair.server;
@@ -1,8 +1,13 @@
 //! Service handlers (VPN, QoS, DDNS)
 
-use crate::middleware::{authenticate, check_device_access, require_plan};
+use crate::middleware::{
+    RequestAuth, authenticated, check_device_access, precondition_response, require_if_match,
+    require_plan, respond_with_etag,
+};
 use crate::models::IntoApiResponse;
+use crate::models::orgs::Permission;
 use crate::models::services::*;
+use crate::models::validation::Validate;
 use crate::storage;
 use worker::*;
 
@@ -15,121 +20,169 @@ fn get_device_id(req: &Request) -> Result<String> {
 // ========== VPN Server Handlers ==========
 
 /// GET /api/vpn/server/config
-pub async fn get_vpn_server_config(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_vpn_server_config(
+    req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let config = storage::get_vpn_server_config(&device_id, &ctx.env).await;
-    config.into_api_response()
+    respond_with_etag(config, &device_id, "vpn_server", &ctx.env).await
 }
 
 /// PUT /api/vpn/server/config
-pub async fn update_vpn_server_config(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_vpn_server_config(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "vpn_server", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let config: VpnServerConfig = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::update_vpn_server_config(&device_id, &config, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = config.validate() {
+        return e.into_response();
+    }
+    let result = storage::update_vpn_server_config(&device_id, &config, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "vpn_server", &ctx.env).await
 }
 
 /// GET /api/vpn/server/peers
-pub async fn get_vpn_peers(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_vpn_peers(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let peers = storage::get_vpn_peers(&device_id, &ctx.env).await;
-    peers.into_api_response()
+    respond_with_etag(peers, &device_id, "vpn_peers", &ctx.env).await
 }
 
 /// POST /api/vpn/server/peers
-pub async fn create_vpn_peer(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn create_vpn_peer(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "vpn_peers", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let peer: VpnPeerRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::create_vpn_peer(&device_id, &peer, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = peer.validate() {
+        return e.into_response();
+    }
+    let result = storage::create_vpn_peer(&device_id, &peer, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "vpn_peers", &ctx.env).await
 }
 
 /// PUT /api/vpn/server/peers/:id
-pub async fn update_vpn_peer(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_vpn_peer(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "vpn_peers", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let peer_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing peer ID"))?;
     let peer: VpnPeerRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::update_vpn_peer(&device_id, peer_id, &peer, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = peer.validate() {
+        return e.into_response();
+    }
+    let result = storage::update_vpn_peer(&device_id, peer_id, &peer, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "vpn_peers", &ctx.env).await
 }
 
 /// DELETE /api/vpn/server/peers/:id
-pub async fn delete_vpn_peer(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn delete_vpn_peer(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    if let Err(e) = require_if_match(&req, &device_id, "vpn_peers", &ctx.env).await {
+        return precondition_response(e);
+    }
 
     let peer_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing peer ID"))?;
     let result = storage::delete_vpn_peer(&device_id, peer_id, &ctx.env).await;
-    result.into_api_response()
+    respond_with_etag(result, &device_id, "vpn_peers", &ctx.env).await
 }
 
 /// GET /api/vpn/server/peers/:id/qr
-pub async fn get_vpn_peer_qr(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_vpn_peer_qr(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
 
     let peer_id = ctx
         .param("id")
@@ -139,16 +192,21 @@ pub async fn get_vpn_peer_qr(req: Request, ctx: RouteContext<()>) -> Result<Resp
 }
 
 /// GET /api/vpn/server/status
-pub async fn get_vpn_server_status(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_vpn_server_status(
+    req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let status = storage::get_vpn_server_status(&device_id, &ctx.env).await;
     status.into_api_response()
@@ -157,79 +215,111 @@ pub async fn get_vpn_server_status(req: Request, ctx: RouteContext<()>) -> Resul
 // ========== VPN Client Handlers ==========
 
 /// GET /api/vpn/client/profiles
-pub async fn get_vpn_profiles(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_vpn_profiles(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let profiles = storage::get_vpn_client_profiles(&device_id, &ctx.env).await;
-    profiles.into_api_response()
+    respond_with_etag(profiles, &device_id, "vpn_profiles", &ctx.env).await
 }
 
 /// POST /api/vpn/client/profiles
-pub async fn create_vpn_profile(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn create_vpn_profile(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "vpn_profiles", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let profile: VpnClientProfile = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::create_vpn_client_profile(&device_id, &profile, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = profile.validate() {
+        return e.into_response();
+    }
+    let result = storage::create_vpn_client_profile(&device_id, &profile, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "vpn_profiles", &ctx.env).await
 }
 
 /// PUT /api/vpn/client/profiles/:id
-pub async fn update_vpn_profile(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_vpn_profile(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "vpn_profiles", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let profile_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing profile ID"))?;
     let profile: VpnClientProfile = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
+    if let Err(e) = profile.validate() {
+        return e.into_response();
+    }
     let result =
-        storage::update_vpn_client_profile(&device_id, profile_id, &profile, &ctx.env).await;
-    result.into_api_response()
+        storage::update_vpn_client_profile(&device_id, profile_id, &profile, expected, &ctx.env)
+            .await;
+    respond_with_etag(result, &device_id, "vpn_profiles", &ctx.env).await
 }
 
 /// DELETE /api/vpn/client/profiles/:id
-pub async fn delete_vpn_profile(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn delete_vpn_profile(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    if let Err(e) = require_if_match(&req, &device_id, "vpn_profiles", &ctx.env).await {
+        return precondition_response(e);
+    }
 
     let profile_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing profile ID"))?;
     let result = storage::delete_vpn_client_profile(&device_id, profile_id, &ctx.env).await;
-    result.into_api_response()
+    respond_with_etag(result, &device_id, "vpn_profiles", &ctx.env).await
 }
 
 /// POST /api/vpn/client/profiles/:id/connect
-pub async fn connect_vpn(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn connect_vpn(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Operate, &ctx.env).await {
+        return e.into_response();
+    }
 
     let profile_id = ctx
         .param("id")
@@ -239,14 +329,15 @@ pub async fn connect_vpn(req: Request, ctx: RouteContext<()>) -> Result<Response
 }
 
 /// POST /api/vpn/client/profiles/:id/disconnect
-pub async fn disconnect_vpn(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn disconnect_vpn(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Operate, &ctx.env).await {
+        return e.into_response();
+    }
 
     let profile_id = ctx
         .param("id")
@@ -256,14 +347,18 @@ pub async fn disconnect_vpn(req: Request, ctx: RouteContext<()>) -> Result<Respo
 }
 
 /// GET /api/vpn/client/status
-pub async fn get_vpn_client_status(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_vpn_client_status(
+    req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let status = storage::get_vpn_client_status(&device_id, &ctx.env).await;
     status.into_api_response()
@@ -272,219 +367,309 @@ pub async fn get_vpn_client_status(req: Request, ctx: RouteContext<()>) -> Resul
 // ========== QoS Handlers ==========
 
 /// GET /api/qos/config
-pub async fn get_qos_config(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_qos_config(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let config = storage::get_qos_config(&device_id, &ctx.env).await;
-    config.into_api_response()
+    respond_with_etag(config, &device_id, "qos", &ctx.env).await
 }
 
 /// PUT /api/qos/config
-pub async fn update_qos_config(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_qos_config(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "qos", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let config: QosConfig = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::update_qos_config(&device_id, &config, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = config.validate() {
+        return e.into_response();
+    }
+    let result = storage::update_qos_config(&device_id, &config, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "qos", &ctx.env).await
 }
 
 /// GET /api/qos/classes
-pub async fn get_traffic_classes(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_traffic_classes(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let classes = storage::get_traffic_classes(&device_id, &ctx.env).await;
-    classes.into_api_response()
+    respond_with_etag(classes, &device_id, "traffic_classes", &ctx.env).await
 }
 
 /// POST /api/qos/classes
-pub async fn create_traffic_class(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn create_traffic_class(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "traffic_classes", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let class: TrafficClass = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::create_traffic_class(&device_id, &class, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = class.validate() {
+        return e.into_response();
+    }
+    let result = storage::create_traffic_class(&device_id, &class, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "traffic_classes", &ctx.env).await
 }
 
 /// PUT /api/qos/classes/:id
-pub async fn update_traffic_class(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_traffic_class(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "traffic_classes", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let class_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing class ID"))?;
     let class: TrafficClass = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::update_traffic_class(&device_id, class_id, &class, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = class.validate() {
+        return e.into_response();
+    }
+    let result =
+        storage::update_traffic_class(&device_id, class_id, &class, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "traffic_classes", &ctx.env).await
 }
 
 /// DELETE /api/qos/classes/:id
-pub async fn delete_traffic_class(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn delete_traffic_class(
+    req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    if let Err(e) = require_if_match(&req, &device_id, "traffic_classes", &ctx.env).await {
+        return precondition_response(e);
+    }
 
     let class_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing class ID"))?;
     let result = storage::delete_traffic_class(&device_id, class_id, &ctx.env).await;
-    result.into_api_response()
+    respond_with_etag(result, &device_id, "traffic_classes", &ctx.env).await
 }
 
 /// GET /api/qos/device-limits
-pub async fn get_device_limits(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_device_limits(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let limits = storage::get_device_bandwidth_limits(&device_id, &ctx.env).await;
-    limits.into_api_response()
+    respond_with_etag(limits, &device_id, "device_limits", &ctx.env).await
 }
 
 /// PUT /api/qos/device-limits/:mac
-pub async fn set_device_limit(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn set_device_limit(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "device_limits", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let mac = ctx
         .param("mac")
         .ok_or_else(|| Error::from("Missing MAC address"))?;
     let limit: DeviceLimit = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::set_device_bandwidth_limit(&device_id, mac, &limit, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = limit.validate() {
+        return e.into_response();
+    }
+    let result =
+        storage::set_device_bandwidth_limit(&device_id, mac, &limit, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "device_limits", &ctx.env).await
 }
 
 /// DELETE /api/qos/device-limits/:mac
-pub async fn remove_device_limit(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-    require_plan(&auth, &["pro", "business", "business_plus"])
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn remove_device_limit(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, &["pro", "business", "business_plus"]) {
+        return e.into_response();
+    }
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    if let Err(e) = require_if_match(&req, &device_id, "device_limits", &ctx.env).await {
+        return precondition_response(e);
+    }
 
     let mac = ctx
         .param("mac")
         .ok_or_else(|| Error::from("Missing MAC address"))?;
     let result = storage::remove_device_bandwidth_limit(&device_id, mac, &ctx.env).await;
-    result.into_api_response()
+    respond_with_etag(result, &device_id, "device_limits", &ctx.env).await
 }
 
 // ========== DDNS Handlers ==========
 
 /// GET /api/ddns/config
-pub async fn get_ddns_config(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_ddns_config(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let config = storage::get_ddns_config(&device_id, &ctx.env).await;
-    config.into_api_response()
+    respond_with_etag(config, &device_id, "ddns", &ctx.env).await
 }
 
 /// PUT /api/ddns/config
-pub async fn update_ddns_config(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_ddns_config(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let expected = match require_if_match(&req, &device_id, "ddns", &ctx.env).await {
+        Ok(expected) => expected,
+        Err(e) => return precondition_response(e),
+    };
 
     let config: DdnsConfig = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
-    let result = storage::update_ddns_config(&device_id, &config, &ctx.env).await;
-    result.into_api_response()
+    if let Err(e) = config.validate() {
+        return e.into_response();
+    }
+    let result = storage::update_ddns_config(&device_id, &config, expected, &ctx.env).await;
+    respond_with_etag(result, &device_id, "ddns", &ctx.env).await
 }
 
 /// POST /api/ddns/update
-pub async fn force_ddns_update(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn force_ddns_update(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Operate, &ctx.env).await {
+        return e.into_response();
+    }
 
     let result = storage::force_ddns_update(&device_id, &ctx.env).await;
     result.into_api_response()
 }
 
 /// GET /api/ddns/status
-pub async fn get_ddns_status(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_ddns_status(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let status = storage::get_ddns_status(&device_id, &ctx.env).await;
     status.into_api_response()
@@ -1,61 +1,68 @@
 //! System management handlers
 
-use crate::middleware::{authenticate, check_device_access};
+use crate::middleware::{RequestAuth, authenticated, check_device_access};
+use crate::models::metrics::{MetricKind, MetricsHistoryQuery, MetricsHistoryResponse};
+use crate::models::orgs::Permission;
 use crate::models::{ApiError, ApiResult, IntoApiResponse};
+use crate::rpc::metrics_rollup::plan_history;
 use crate::storage;
 use worker::*;
 
 /// GET /api/system/status
-pub async fn get_status(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_status(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let status = storage::get_device_status(&device_id, &ctx.env).await;
     status.into_api_response()
 }
 
 /// GET /api/system/interfaces
-pub async fn get_interfaces(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_interfaces(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let interfaces = storage::get_interfaces(&device_id, &ctx.env).await;
     interfaces.into_api_response()
 }
 
 /// GET /api/system/hardware
-pub async fn get_hardware(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_hardware(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let hardware = storage::get_hardware_info(&device_id, &ctx.env).await;
     hardware.into_api_response()
 }
 
 /// POST /api/system/reboot
-pub async fn reboot(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn reboot(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Operate, &ctx.env).await {
+        return e.into_response();
+    }
 
     // Send reboot command to device via Durable Object
     let result = send_device_command(&device_id, "REBOOT", None, &ctx.env).await;
@@ -63,56 +70,66 @@ pub async fn reboot(req: Request, ctx: RouteContext<()>) -> Result<Response> {
 }
 
 /// POST /api/system/shutdown
-pub async fn shutdown(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn shutdown(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
 
     let result = send_device_command(&device_id, "SHUTDOWN", None, &ctx.env).await;
     result.into_api_response()
 }
 
 /// GET /api/firmware/current
-pub async fn get_current_firmware(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_current_firmware(
+    req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let firmware = storage::get_firmware_info(&device_id, &ctx.env).await;
     firmware.into_api_response()
 }
 
 /// GET /api/firmware/available
-pub async fn get_available_updates(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_available_updates(
+    req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let updates = storage::get_available_updates(&device_id, &ctx.env).await;
     updates.into_api_response()
 }
 
 /// POST /api/firmware/download
-pub async fn download_firmware(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn download_firmware(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
 
     // Trigger firmware download on the device
     let result = send_device_command(&device_id, "DOWNLOAD_FIRMWARE", None, &ctx.env).await;
@@ -120,28 +137,30 @@ pub async fn download_firmware(req: Request, ctx: RouteContext<()>) -> Result<Re
 }
 
 /// POST /api/firmware/install
-pub async fn install_firmware(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn install_firmware(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
 
     let result = send_device_command(&device_id, "INSTALL_FIRMWARE", None, &ctx.env).await;
     result.into_api_response()
 }
 
 /// POST /api/firmware/upload
-pub async fn upload_firmware(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn upload_firmware(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
 
     // Handle multipart upload to R2
     let form = req.form_data().await?;
@@ -159,28 +178,30 @@ pub async fn upload_firmware(mut req: Request, ctx: RouteContext<()>) -> Result<
 }
 
 /// GET /api/firmware/slots
-pub async fn get_boot_slots(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_boot_slots(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let slots = storage::get_boot_slots(&device_id, &ctx.env).await;
     slots.into_api_response()
 }
 
 /// POST /api/firmware/slots/:id/activate
-pub async fn activate_boot_slot(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn activate_boot_slot(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
 
     let slot_id = ctx
         .param("id")
@@ -191,42 +212,45 @@ pub async fn activate_boot_slot(req: Request, ctx: RouteContext<()>) -> Result<R
 }
 
 /// GET /api/backup/list
-pub async fn list_backups(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn list_backups(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
 
     let backups = storage::list_backups(&device_id, &ctx.env).await;
     backups.into_api_response()
 }
 
 /// POST /api/backup/create
-pub async fn create_backup(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn create_backup(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Operate, &ctx.env).await {
+        return e.into_response();
+    }
 
     let backup = storage::create_backup(&device_id, &ctx.env).await;
     backup.into_api_response()
 }
 
 /// GET /api/backup/:id/download
-pub async fn download_backup(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn download_backup(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
 
     let backup_id = ctx
         .param("id")
@@ -248,14 +272,15 @@ pub async fn download_backup(req: Request, ctx: RouteContext<()>) -> Result<Resp
 }
 
 /// POST /api/backup/restore
-pub async fn restore_backup(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn restore_backup(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
 
     let body: serde_json::Value = req.json().await?;
     let backup_id = body.get("backup_id").and_then(|v| v.as_str());
@@ -270,14 +295,15 @@ pub async fn restore_backup(mut req: Request, ctx: RouteContext<()>) -> Result<R
 }
 
 /// DELETE /api/backup/:id
-pub async fn delete_backup(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn delete_backup(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
 
     let backup_id = ctx
         .param("id")
@@ -287,24 +313,26 @@ pub async fn delete_backup(req: Request, ctx: RouteContext<()>) -> Result<Respon
 }
 
 /// POST /api/backup/factory-reset
-pub async fn factory_reset(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn factory_reset(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let device_id = get_device_id(&req)?;
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Configure, &ctx.env).await {
+        return e.into_response();
+    }
 
     let result = send_device_command(&device_id, "FACTORY_RESET", None, &ctx.env).await;
     result.into_api_response()
 }
 
 /// GET /api/metrics/latest
-pub async fn get_latest_metrics(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_latest_metrics(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
 
     let url = req.url()?;
     let device_id = url
@@ -313,31 +341,130 @@ pub async fn get_latest_metrics(req: Request, ctx: RouteContext<()>) -> Result<R
         .map(|(_, v)| v.into_owned())
         .ok_or_else(|| Error::from("Missing device_id query parameter"))?;
 
-    check_device_access(&auth, &device_id, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
-
-    let kv = ctx.env.kv("CACHE")?;
-    let prefix = format!("metrics:{}:", device_id);
-    let list_response = kv.list().prefix(prefix).execute().await?;
-
-    let latest_key = list_response.keys.iter().map(|k| k.name.clone()).max();
-
-    match latest_key {
-        Some(key) => match kv.get(&key).text().await? {
-            Some(value) => {
-                let json: serde_json::Value =
-                    serde_json::from_str(&value).map_err(|e| Error::from(e.to_string()))?;
-                Response::from_json(&json)
-            }
-            None => ApiError::not_found("Metrics").into_response(),
-        },
-        None => ApiError::not_found("Metrics").into_response(),
+    if let Err(e) = check_device_access(&auth, &device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
     }
+
+    // The device's Durable Object holds the latest raw sample
+    let namespace = ctx.env.durable_object("AGENT_CONNECTIONS")?;
+    let stub = namespace.id_from_name(&device_id)?.get_stub()?;
+    let mut response = stub
+        .fetch_with_str("http://internal/metrics/latest")
+        .await?;
+
+    if response.status_code() == 404 {
+        return ApiError::not_found("Metrics").into_response();
+    }
+
+    let json: serde_json::Value = response.json().await?;
+    Response::from_json(&json)
+}
+
+/// GET /api/metrics/history
+///
+/// Query parameters: device_id, metric, interface (for rx_rate/tx_rate),
+/// from and to (Unix seconds, default the last hour), step (seconds).
+/// The rollup resolution and actual step are chosen to stay within
+/// `MAX_HISTORY_POINTS`.
+pub async fn get_metrics_history(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+
+    let url = req.url()?;
+    let query = match parse_metrics_history_query(&url, chrono::Utc::now().timestamp()) {
+        Ok(query) => query,
+        Err(e) => return e.into_response(),
+    };
+
+    if let Err(e) = check_device_access(&auth, &query.device_id, Permission::Read, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let (resolution, step) = plan_history(query.from, query.to, query.step, now);
+
+    let result = storage::query_metrics_history(&query, resolution, step, &ctx.env)
+        .await
+        .map(|points| MetricsHistoryResponse {
+            device_id: query.device_id.clone(),
+            metric: query.metric,
+            interface: query.interface.clone(),
+            from: query.from,
+            to: query.to,
+            resolution: resolution.as_str().to_string(),
+            step,
+            points,
+        });
+    result.into_api_response()
 }
 
 // Helper functions
 
+/// Parse and validate `/metrics/history` query parameters
+fn parse_metrics_history_query(url: &Url, now: i64) -> ApiResult<MetricsHistoryQuery> {
+    let get = |name: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    };
+    let get_i64 = |name: &str| -> ApiResult<Option<i64>> {
+        get(name)
+            .map(|v| {
+                v.parse::<i64>().map_err(|_| {
+                    ApiError::bad_request(format!("{} must be an integer", name)).with_field(name)
+                })
+            })
+            .transpose()
+    };
+
+    let device_id = get("device_id").ok_or_else(|| {
+        ApiError::bad_request("Missing device_id query parameter").with_field("device_id")
+    })?;
+
+    let metric_name = get("metric").ok_or_else(|| {
+        ApiError::bad_request("Missing metric query parameter").with_field("metric")
+    })?;
+    let metric = MetricKind::parse(&metric_name).ok_or_else(|| {
+        let names: Vec<_> = MetricKind::ALL.iter().map(|m| m.as_str()).collect();
+        ApiError::bad_request(format!("metric must be one of: {}", names.join(", ")))
+            .with_field("metric")
+    })?;
+
+    let interface = get("interface");
+    if metric.is_per_interface() && interface.is_none() {
+        return Err(
+            ApiError::bad_request(format!("{} requires an interface", metric.as_str()))
+                .with_field("interface"),
+        );
+    }
+
+    let to = get_i64("to")?.unwrap_or(now);
+    let from = get_i64("from")?.unwrap_or(to - 3600);
+    if from >= to {
+        return Err(ApiError::bad_request("from must be before to").with_field("from"));
+    }
+
+    let step = get_i64("step")?;
+    if step.is_some_and(|s| s <= 0) {
+        return Err(ApiError::bad_request("step must be positive").with_field("step"));
+    }
+
+    Ok(MetricsHistoryQuery {
+        device_id,
+        metric,
+        interface: if metric.is_per_interface() {
+            interface
+        } else {
+            None
+        },
+        from,
+        to,
+        step,
+    })
+}
+
 fn get_device_id(req: &Request) -> Result<String> {
     req.headers()
         .get("X-Device-ID")?
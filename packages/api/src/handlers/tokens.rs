@@ -0,0 +1,112 @@
+//! API token handlers
+//!
+//! Implements:
+//! - `GET /user/tokens` - List the caller's tokens (without secrets)
+//! - `POST /user/tokens` - Create a token (returns the secret once)
+//! - `DELETE /user/tokens/:id` - Revoke a token
+//!
+//! Tokens created while an organization is active are service tokens for
+//! that organization. These routes need a Clerk session; API tokens are
+//! rejected before routing.
+
+use crate::middleware::{RequestAuth, api_tokens, authenticated};
+use crate::models::tokens::*;
+use crate::models::{ApiError, ApiResult, IntoApiResponse};
+use crate::storage;
+use worker::*;
+
+/// Characters of the token kept in clear for display
+const DISPLAY_PREFIX_LEN: usize = TOKEN_PREFIX.len() + 8;
+
+fn validate(body: &CreateTokenRequest) -> ApiResult<u32> {
+    if body.name.trim().is_empty() {
+        return Err(ApiError::bad_request("name must not be empty").with_field("name"));
+    }
+    if body.scopes.is_empty() {
+        return Err(ApiError::bad_request("scopes must not be empty").with_field("scopes"));
+    }
+    match body.expires_in_days.unwrap_or(DEFAULT_TOKEN_DAYS) {
+        days @ 1..=MAX_TOKEN_DAYS => Ok(days),
+        _ => Err(ApiError::bad_request(format!(
+            "expires_in_days must be between 1 and {}",
+            MAX_TOKEN_DAYS
+        ))
+        .with_field("expires_in_days")),
+    }
+}
+
+/// GET /api/user/tokens
+pub async fn list_tokens(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+
+    let result = storage::list_api_tokens(&auth.user_id, &ctx.env).await;
+    result.into_api_response()
+}
+
+/// POST /api/user/tokens
+pub async fn create_token(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+
+    let body: CreateTokenRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
+    let days = match validate(&body) {
+        Ok(days) => days,
+        Err(e) => return e.into_response(),
+    };
+    let mut scopes: Vec<TokenScope> = Vec::new();
+    for scope in body.scopes {
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+
+    let secret = api_tokens::generate_token();
+    let token_hash = match api_tokens::hash_token(&secret).await {
+        Ok(hash) => hash,
+        Err(e) => return e.into_response(),
+    };
+    let now = chrono::Utc::now().timestamp();
+    let api_token = ApiToken {
+        id: format!("tok_{}", uuid::Uuid::new_v4().simple()),
+        name: body.name,
+        token_prefix: secret[..DISPLAY_PREFIX_LEN].to_string(),
+        scopes,
+        org_id: auth.org_id.clone(),
+        created_at: now,
+        expires_at: now + i64::from(days) * 86_400,
+        last_used_at: None,
+        revoked_at: None,
+    };
+
+    match storage::create_api_token(&auth.user_id, &auth.plan, &api_token, &token_hash, &ctx.env)
+        .await
+    {
+        Ok(()) => Ok(Response::from_json(&CreateTokenResponse {
+            api_token,
+            token: secret,
+        })?
+        .with_status(201)),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// DELETE /api/user/tokens/:id
+pub async fn revoke_token(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    let token_id = ctx
+        .param("id")
+        .ok_or_else(|| Error::from("Missing token ID"))?;
+
+    match storage::revoke_api_token(&auth.user_id, token_id, &ctx.env).await {
+        Ok(()) => Ok(Response::empty()?.with_status(204)),
+        Err(e) => e.into_response(),
+    }
+}
@@ -1,7 +1,8 @@
 //! User account and billing handlers
 
-use crate::middleware::authenticate;
+use crate::middleware::{RequestAuth, authenticated, require_permission};
 use crate::models::IntoApiResponse;
+use crate::models::orgs::Permission;
 use crate::models::user::*;
 use crate::storage;
 use worker::*;
@@ -9,29 +10,32 @@ use worker::*;
 // ========== User Profile Handlers ==========
 
 /// GET /api/user/profile
-pub async fn get_profile(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_profile(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let profile = storage::get_user_profile(&auth.user_id, &ctx.env).await;
     profile.into_api_response()
 }
 
 /// PUT /api/user/profile
-pub async fn update_profile(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn update_profile(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let update: UpdateProfileRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
     let result = storage::update_user_profile(&auth.user_id, &update, &ctx.env).await;
     result.into_api_response()
 }
 
 /// PUT /api/user/password
-pub async fn change_password(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn change_password(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let change: ChangePasswordRequest =
         req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
     let result = storage::change_user_password(&auth.user_id, &change, &ctx.env).await;
@@ -41,29 +45,32 @@ pub async fn change_password(mut req: Request, ctx: RouteContext<()>) -> Result<
 // ========== 2FA Handlers ==========
 
 /// GET /api/user/2fa
-pub async fn get_2fa_status(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_2fa_status(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let status = storage::get_2fa_status(&auth.user_id, &ctx.env).await;
     status.into_api_response()
 }
 
 /// POST /api/user/2fa/enable
-pub async fn enable_2fa(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn enable_2fa(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let enable: Enable2faRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
     let result = storage::enable_2fa(&auth.user_id, &enable, &ctx.env).await;
     result.into_api_response()
 }
 
 /// DELETE /api/user/2fa
-pub async fn disable_2fa(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn disable_2fa(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let result = storage::disable_2fa(&auth.user_id, &ctx.env).await;
     result.into_api_response()
 }
@@ -71,19 +78,21 @@ pub async fn disable_2fa(req: Request, ctx: RouteContext<()>) -> Result<Response
 // ========== Session Handlers ==========
 
 /// GET /api/user/sessions
-pub async fn get_sessions(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_sessions(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let sessions = storage::get_user_sessions(&auth.user_id, &ctx.env).await;
     sessions.into_api_response()
 }
 
 /// DELETE /api/user/sessions/:id
-pub async fn revoke_session(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn revoke_session(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let session_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing session ID"))?;
@@ -94,57 +103,84 @@ pub async fn revoke_session(req: Request, ctx: RouteContext<()>) -> Result<Respo
 // ========== Billing Handlers ==========
 
 /// GET /api/billing/plan
-pub async fn get_plan(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_plan(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let plan = storage::get_subscription(&auth.user_id, &ctx.env).await;
     plan.into_api_response()
 }
 
 /// PUT /api/billing/plan
-pub async fn change_plan(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn change_plan(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_permission(&auth, Permission::ManageBilling) {
+        return e.into_response();
+    }
     let change: ChangePlanRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
     let result = storage::change_subscription(&auth.user_id, &change, &ctx.env).await;
     result.into_api_response()
 }
 
 /// GET /api/billing/usage
-pub async fn get_usage(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_usage(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
     let usage = storage::get_usage_meters(&auth.user_id, &ctx.env).await;
     usage.into_api_response()
 }
 
 /// GET /api/billing/payment-methods
-pub async fn get_payment_methods(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_payment_methods(
+    _req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_permission(&auth, Permission::ManageBilling) {
+        return e.into_response();
+    }
     let methods = storage::get_payment_methods(&auth.user_id, &ctx.env).await;
     methods.into_api_response()
 }
 
 /// POST /api/billing/payment-methods
-pub async fn add_payment_method(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn add_payment_method(
+    mut req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_permission(&auth, Permission::ManageBilling) {
+        return e.into_response();
+    }
     let add: AddPaymentMethodRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
     let result = storage::add_payment_method(&auth.user_id, &add, &ctx.env).await;
     result.into_api_response()
 }
 
 /// DELETE /api/billing/payment-methods/:id
-pub async fn remove_payment_method(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn remove_payment_method(
+    _req: Request,
+    ctx: RouteContext<RequestAuth>,
+) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_permission(&auth, Permission::ManageBilling) {
+        return e.into_response();
+    }
     let method_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing payment method ID"))?;
@@ -153,19 +189,27 @@ pub async fn remove_payment_method(req: Request, ctx: RouteContext<()>) -> Resul
 }
 
 /// GET /api/billing/invoices
-pub async fn get_invoices(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn get_invoices(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_permission(&auth, Permission::ManageBilling) {
+        return e.into_response();
+    }
     let invoices = storage::get_invoices(&auth.user_id, &ctx.env).await;
     invoices.into_api_response()
 }
 
 /// GET /api/billing/invoices/:id
-pub async fn download_invoice(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let auth = authenticate(&req, &ctx.env)
-        .await
-        .map_err(|e| Error::from(e.error.message))?;
+pub async fn download_invoice(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_permission(&auth, Permission::ManageBilling) {
+        return e.into_response();
+    }
     let invoice_id = ctx
         .param("id")
         .ok_or_else(|| Error::from("Missing invoice ID"))?;
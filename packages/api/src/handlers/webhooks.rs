@@ -0,0 +1,227 @@
+//! Webhook subscription handlers
+//!
+//! Implements:
+//! - `GET /webhooks` - List webhooks
+//! - `POST /webhooks` - Create a webhook (returns the signing secret once)
+//! - `GET /webhooks/:id` - Get a webhook
+//! - `PUT /webhooks/:id` - Update url, events or enabled flag
+//! - `DELETE /webhooks/:id` - Delete a webhook and its delivery log
+//! - `GET /webhooks/:id/deliveries` - Recent deliveries (`limit`, default 50)
+//! - `POST /webhooks/:id/test` - Send a signed test event
+
+use crate::middleware::{RequestAuth, authenticated, require_permission, require_plan};
+use crate::models::fleet::WebhookConfig;
+use crate::models::orgs::Permission;
+use crate::models::webhooks::*;
+use crate::models::{ApiError, IntoApiResponse};
+use crate::storage;
+use crate::webhooks;
+use worker::*;
+
+/// Plans that include webhooks
+const WEBHOOK_PLANS: &[&str] = &["business", "business_plus"];
+
+/// Most deliveries returned by the delivery log
+const MAX_DELIVERY_LIMIT: u32 = 200;
+
+fn get_webhook_id(ctx: &RouteContext<RequestAuth>) -> Result<String> {
+    ctx.param("id")
+        .cloned()
+        .ok_or_else(|| Error::from("Missing webhook ID"))
+}
+
+/// GET /api/webhooks
+pub async fn list_webhooks(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, WEBHOOK_PLANS) {
+        return e.into_response();
+    }
+    if let Err(e) = require_permission(&auth, Permission::Read) {
+        return e.into_response();
+    }
+
+    let result = storage::list_webhooks(&auth.user_id, &ctx.env).await;
+    result.into_api_response()
+}
+
+/// POST /api/webhooks
+pub async fn create_webhook(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, WEBHOOK_PLANS) {
+        return e.into_response();
+    }
+    if let Err(e) = require_permission(&auth, Permission::Configure) {
+        return e.into_response();
+    }
+
+    let body: CreateWebhookRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
+    if let Err(e) = webhooks::validate_url(&body.url) {
+        return e.into_response();
+    }
+    if body.events.is_empty() {
+        return ApiError::bad_request("events must not be empty")
+            .with_field("events")
+            .into_response();
+    }
+
+    let secret = body.secret.unwrap_or_else(webhooks::generate_secret);
+    let webhook = WebhookConfig {
+        id: format!("wh_{}", uuid::Uuid::new_v4().simple()),
+        url: body.url,
+        events: body.events,
+        enabled: body.enabled,
+        secret: Some(secret.clone()),
+        created_at: chrono::Utc::now().timestamp(),
+    };
+
+    match storage::create_webhook(&auth.user_id, &webhook, &ctx.env).await {
+        Ok(()) => {
+            Ok(Response::from_json(&CreateWebhookResponse { webhook, secret })?.with_status(201))
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// GET /api/webhooks/:id
+pub async fn get_webhook(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, WEBHOOK_PLANS) {
+        return e.into_response();
+    }
+    if let Err(e) = require_permission(&auth, Permission::Read) {
+        return e.into_response();
+    }
+    let webhook_id = get_webhook_id(&ctx)?;
+
+    let result = storage::get_webhook(&auth.user_id, &webhook_id, &ctx.env).await;
+    result.into_api_response()
+}
+
+/// PUT /api/webhooks/:id
+pub async fn update_webhook(mut req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, WEBHOOK_PLANS) {
+        return e.into_response();
+    }
+    if let Err(e) = require_permission(&auth, Permission::Configure) {
+        return e.into_response();
+    }
+    let webhook_id = get_webhook_id(&ctx)?;
+
+    let body: UpdateWebhookRequest = req.json().await.map_err(|_| Error::from("Invalid JSON"))?;
+    let mut webhook = match storage::get_webhook(&auth.user_id, &webhook_id, &ctx.env).await {
+        Ok(webhook) => webhook,
+        Err(e) => return e.into_response(),
+    };
+
+    if let Some(url) = body.url {
+        if let Err(e) = webhooks::validate_url(&url) {
+            return e.into_response();
+        }
+        webhook.url = url;
+    }
+    if let Some(events) = body.events {
+        if events.is_empty() {
+            return ApiError::bad_request("events must not be empty")
+                .with_field("events")
+                .into_response();
+        }
+        webhook.events = events;
+    }
+    if let Some(enabled) = body.enabled {
+        webhook.enabled = enabled;
+    }
+
+    let result = storage::update_webhook(&auth.user_id, &webhook, &ctx.env)
+        .await
+        .map(|()| webhook);
+    result.into_api_response()
+}
+
+/// DELETE /api/webhooks/:id
+pub async fn delete_webhook(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, WEBHOOK_PLANS) {
+        return e.into_response();
+    }
+    if let Err(e) = require_permission(&auth, Permission::Configure) {
+        return e.into_response();
+    }
+    let webhook_id = get_webhook_id(&ctx)?;
+
+    match storage::delete_webhook(&auth.user_id, &webhook_id, &ctx.env).await {
+        Ok(()) => Ok(Response::empty()?.with_status(204)),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// GET /api/webhooks/:id/deliveries
+pub async fn list_deliveries(req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, WEBHOOK_PLANS) {
+        return e.into_response();
+    }
+    if let Err(e) = require_permission(&auth, Permission::Read) {
+        return e.into_response();
+    }
+    let webhook_id = get_webhook_id(&ctx)?;
+
+    if let Err(e) = storage::get_webhook(&auth.user_id, &webhook_id, &ctx.env).await {
+        return e.into_response();
+    }
+
+    let limit = req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == "limit")
+        .and_then(|(_, v)| v.parse::<u32>().ok())
+        .unwrap_or(50)
+        .clamp(1, MAX_DELIVERY_LIMIT);
+
+    let result = storage::list_webhook_deliveries(&webhook_id, limit, &ctx.env).await;
+    result.into_api_response()
+}
+
+/// POST /api/webhooks/:id/test
+///
+/// Delivers a `test` event synchronously and returns the delivery record,
+/// including the receiver's status or the error.
+pub async fn send_test_event(_req: Request, ctx: RouteContext<RequestAuth>) -> Result<Response> {
+    let auth = match authenticated(&ctx) {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_plan(&auth, WEBHOOK_PLANS) {
+        return e.into_response();
+    }
+    if let Err(e) = require_permission(&auth, Permission::Operate) {
+        return e.into_response();
+    }
+    let webhook_id = get_webhook_id(&ctx)?;
+
+    let webhook = match storage::get_webhook(&auth.user_id, &webhook_id, &ctx.env).await {
+        Ok(webhook) => webhook,
+        Err(e) => return e.into_response(),
+    };
+
+    let result = webhooks::send_test(&webhook, &ctx.env).await;
+    result.into_api_response()
+}
@@ -7,24 +7,89 @@
 //! - Storage via Cloudflare KV, D1, and R2
 //! - OpenAPI 3.1 specification at `/openapi.json`
 
+mod agent_config;
+mod cidr;
+mod consistency;
+mod geoip;
 mod handlers;
 mod middleware;
 mod models;
 mod openapi;
 mod rpc;
 mod storage;
+mod threat_feeds;
+mod webhooks;
 
 use worker::*;
 
 pub use handlers::router::build_router;
+pub use middleware::rate_limit::RateLimiter;
 pub use openapi::ApiDoc;
 pub use rpc::agent_connection::AgentConnection;
 
 /// Main entry point for the Cloudflare Worker
+///
+/// Every request is authenticated once here; the outcome is passed to the
+/// handlers as router data. Requests authenticated with an API token must
+/// be within its scopes, and the token's last use is recorded.
+/// Authenticated requests are rate limited before routing. Mutating
+/// requests are also wrapped by the audit middleware, which captures the
+/// prior state before routing and records the change once the handler
+/// succeeds.
 #[event(fetch)]
-async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
+async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
     console_error_panic_hook::set_once();
 
-    let router = build_router();
-    router.run(req, env).await
+    // Unauthenticated routes (health, onboarding, agents) pass through;
+    // their handlers reject anything they require
+    let auth = middleware::authenticate(&req, &env).await;
+
+    let mut rate_limit = None;
+    let mut audit = None;
+    if let Ok(auth) = &auth {
+        if let Err(e) =
+            middleware::api_tokens::check_token_scope(auth, &req.method(), &req.path())
+        {
+            return e.into_response();
+        }
+        if let Some(token_id) = auth.token_id.clone() {
+            let env = env.clone();
+            ctx.wait_until(async move {
+                if let Err(e) = storage::touch_api_token(&token_id, &env).await {
+                    console_warn!("Failed to record API token use: {}", e.error.message);
+                }
+            });
+        }
+
+        // The limiter fails open so an outage of it cannot take down the API
+        match middleware::rate_limit::check_rate_limit(auth, &req.path(), &env).await {
+            Ok(Some(decision)) if !decision.allowed => {
+                return middleware::rate_limit::rate_limited_response(&decision);
+            }
+            Ok(decision) => rate_limit = decision,
+            Err(e) => console_warn!("Rate limit check failed: {}", e.error.message),
+        }
+        audit = middleware::audit::PendingAudit::capture(&req, auth, &env).await;
+    }
+
+    let router = build_router(auth);
+    let mut response = router.run(req, env.clone()).await?;
+
+    if let Some(audit) = audit {
+        audit.complete(&mut response, env, &ctx).await;
+    }
+    match rate_limit {
+        Some(decision) => decision.headers().apply(response),
+        None => Ok(response),
+    }
+}
+
+/// Cron trigger: retry webhook deliveries whose backoff has elapsed and
+/// refetch threat feeds that are due
+#[event(scheduled)]
+async fn scheduled(_event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    console_error_panic_hook::set_once();
+
+    webhooks::retry_due(&env).await;
+    threat_feeds::refresh_due(&env).await;
 }
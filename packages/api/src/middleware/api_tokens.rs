@@ -0,0 +1,462 @@
+//! API token authentication and scopes
+//!
+//! Requests carrying an `ngfw_pat_` bearer token are authenticated against
+//! the hashed tokens in D1 instead of Clerk. Each route needs the one
+//! [`TokenScope`] listed for it in [`route_scopes`]; unlisted routes are
+//! rejected. Account routes (`/user`, `/billing`, `/orgs`) are deliberately
+//! unlisted, so a leaked token cannot mint further tokens.
+
+use crate::middleware::AuthContext;
+use crate::models::tokens::{TOKEN_PREFIX, TokenScope};
+use crate::models::{ApiError, ApiResult};
+use crate::storage;
+use worker::*;
+
+/// Routes that take no authentication, so no scope either
+const PUBLIC_PATHS: &[&str] = &["/health", "/openapi.json", "/agent/ws"];
+const PUBLIC_PREFIXES: &[&str] = &["/onboarding/"];
+
+/// Scope each route needs when called with a token.
+///
+/// Routes missing here, including every account route, are rejected, so a
+/// new route stays closed to tokens until it is given a scope.
+fn route_scopes() -> &'static [(Method, &'static str, TokenScope)] {
+    use Method::{Delete, Get, Post, Put};
+    use TokenScope::*;
+
+    &[
+        (Get, "/system/status", ConfigRead),
+        (Get, "/system/interfaces", ConfigRead),
+        (Get, "/system/hardware", ConfigRead),
+        (Post, "/system/reboot", Exec),
+        (Post, "/system/shutdown", Exec),
+        (Get, "/metrics/latest", ConfigRead),
+        (Get, "/metrics/history", ConfigRead),
+        (Get, "/config/lint", ConfigRead),
+        (Get, "/wan/config", ConfigRead),
+        (Put, "/wan/config", ConfigWrite),
+        (Post, "/wan/renew", Exec),
+        (Post, "/wan/release", Exec),
+        (Get, "/wan/status", ConfigRead),
+        (Get, "/lan/config", ConfigRead),
+        (Put, "/lan/config", ConfigWrite),
+        (Get, "/lan/vlans", ConfigRead),
+        (Post, "/lan/vlans", ConfigWrite),
+        (Put, "/lan/vlans/:id", ConfigWrite),
+        (Delete, "/lan/vlans/:id", ConfigWrite),
+        (Get, "/wifi/radios", ConfigRead),
+        (Put, "/wifi/radios/:id", ConfigWrite),
+        (Get, "/wifi/networks", ConfigRead),
+        (Post, "/wifi/networks", ConfigWrite),
+        (Put, "/wifi/networks/:id", ConfigWrite),
+        (Delete, "/wifi/networks/:id", ConfigWrite),
+        (Get, "/wifi/clients", ConfigRead),
+        (Get, "/dhcp/config", ConfigRead),
+        (Put, "/dhcp/config", ConfigWrite),
+        (Get, "/dhcp/leases", ConfigRead),
+        (Delete, "/dhcp/leases/:ip", Exec),
+        (Get, "/dhcp/reservations", ConfigRead),
+        (Post, "/dhcp/reservations", ConfigWrite),
+        (Delete, "/dhcp/reservations/:mac", ConfigWrite),
+        (Get, "/routing/routes", ConfigRead),
+        (Post, "/routing/routes", ConfigWrite),
+        (Put, "/routing/routes/:id", ConfigWrite),
+        (Delete, "/routing/routes/:id", ConfigWrite),
+        (Get, "/firewall/rules", ConfigRead),
+        (Post, "/firewall/rules", ConfigWrite),
+        (Put, "/firewall/rules/:id", ConfigWrite),
+        (Delete, "/firewall/rules/:id", ConfigWrite),
+        (Put, "/firewall/rules/order", ConfigWrite),
+        (Get, "/firewall/groups/addresses", ConfigRead),
+        (Post, "/firewall/groups/addresses", ConfigWrite),
+        (Put, "/firewall/groups/addresses/:id", ConfigWrite),
+        (Delete, "/firewall/groups/addresses/:id", ConfigWrite),
+        (Get, "/firewall/groups/ports", ConfigRead),
+        (Post, "/firewall/groups/ports", ConfigWrite),
+        (Put, "/firewall/groups/ports/:id", ConfigWrite),
+        (Delete, "/firewall/groups/ports/:id", ConfigWrite),
+        (Get, "/firewall/geoip", ConfigRead),
+        (Put, "/firewall/geoip", ConfigWrite),
+        (Get, "/firewall/feeds", ConfigRead),
+        (Post, "/firewall/feeds", ConfigWrite),
+        (Put, "/firewall/feeds/:id", ConfigWrite),
+        (Delete, "/firewall/feeds/:id", ConfigWrite),
+        (Post, "/firewall/feeds/:id/update", Exec),
+        (Get, "/firewall/zones", ConfigRead),
+        (Put, "/firewall/zones/:id", ConfigWrite),
+        (Get, "/firewall/policies", ConfigRead),
+        (Put, "/firewall/policies", ConfigWrite),
+        (Post, "/firewall/trace", ConfigRead),
+        (Get, "/firewall/trace/:id", ConfigRead),
+        (Get, "/nat/rules", ConfigRead),
+        (Post, "/nat/rules", ConfigWrite),
+        (Put, "/nat/rules/:id", ConfigWrite),
+        (Delete, "/nat/rules/:id", ConfigWrite),
+        (Get, "/nat/upnp", ConfigRead),
+        (Delete, "/nat/upnp/:id", Exec),
+        (Get, "/traffic/logs", ConfigRead),
+        (Get, "/traffic/logs/stream", ConfigRead),
+        (Get, "/traffic/stats", ConfigRead),
+        (Get, "/traffic/top/clients", ConfigRead),
+        (Get, "/traffic/top/destinations", ConfigRead),
+        (Get, "/dns/config", ConfigRead),
+        (Put, "/dns/config", ConfigWrite),
+        (Get, "/dns/blocklists", ConfigRead),
+        (Post, "/dns/blocklists", ConfigWrite),
+        (Delete, "/dns/blocklists/:id", ConfigWrite),
+        (Post, "/dns/blocklists/:id/update", Exec),
+        (Get, "/dns/allowlist", ConfigRead),
+        (Post, "/dns/allowlist", ConfigWrite),
+        (Delete, "/dns/allowlist/:domain", ConfigWrite),
+        (Get, "/dns/queries", ConfigRead),
+        (Get, "/dns/stats", ConfigRead),
+        (Get, "/ips/config", ConfigRead),
+        (Put, "/ips/config", ConfigWrite),
+        (Get, "/ips/categories", ConfigRead),
+        (Put, "/ips/categories/:id", ConfigWrite),
+        (Get, "/ips/rules", ConfigRead),
+        (Post, "/ips/rules", ConfigWrite),
+        (Delete, "/ips/rules/:id", ConfigWrite),
+        (Get, "/ips/alerts", ConfigRead),
+        (Get, "/ips/alerts/stream", ConfigRead),
+        (Get, "/vpn/server/config", ConfigRead),
+        (Put, "/vpn/server/config", ConfigWrite),
+        (Get, "/vpn/server/peers", ConfigRead),
+        (Post, "/vpn/server/peers", ConfigWrite),
+        (Put, "/vpn/server/peers/:id", ConfigWrite),
+        (Delete, "/vpn/server/peers/:id", ConfigWrite),
+        (Get, "/vpn/server/peers/:id/qr", ConfigRead),
+        (Get, "/vpn/server/status", ConfigRead),
+        (Get, "/vpn/client/profiles", ConfigRead),
+        (Post, "/vpn/client/profiles", ConfigWrite),
+        (Put, "/vpn/client/profiles/:id", ConfigWrite),
+        (Delete, "/vpn/client/profiles/:id", ConfigWrite),
+        (Post, "/vpn/client/profiles/:id/connect", Exec),
+        (Post, "/vpn/client/profiles/:id/disconnect", Exec),
+        (Get, "/vpn/client/status", ConfigRead),
+        (Get, "/qos/config", ConfigRead),
+        (Put, "/qos/config", ConfigWrite),
+        (Get, "/qos/rules", ConfigRead),
+        (Post, "/qos/rules", ConfigWrite),
+        (Put, "/qos/rules/:id", ConfigWrite),
+        (Delete, "/qos/rules/:id", ConfigWrite),
+        (Get, "/qos/device-limits", ConfigRead),
+        (Put, "/qos/device-limits/:mac", ConfigWrite),
+        (Delete, "/qos/device-limits/:mac", ConfigWrite),
+        (Get, "/ddns/configs", ConfigRead),
+        (Post, "/ddns/configs", ConfigWrite),
+        (Put, "/ddns/configs/:id", ConfigWrite),
+        (Delete, "/ddns/configs/:id", ConfigWrite),
+        (Post, "/ddns/configs/:id/update", Exec),
+        (Get, "/ddns/config", ConfigRead),
+        (Put, "/ddns/config", ConfigWrite),
+        (Post, "/ddns/update", Exec),
+        (Get, "/ddns/status", ConfigRead),
+        (Get, "/firmware/current", ConfigRead),
+        (Get, "/firmware/available", ConfigRead),
+        (Post, "/firmware/download", Exec),
+        (Post, "/firmware/install", Exec),
+        (Post, "/firmware/upload", Exec),
+        (Get, "/firmware/slots", ConfigRead),
+        (Post, "/firmware/slots/:id/activate", Exec),
+        (Get, "/backup/list", ConfigRead),
+        (Post, "/backup/create", Exec),
+        (Get, "/backup/:id/download", ConfigRead),
+        (Post, "/backup/restore", Exec),
+        (Delete, "/backup/:id", ConfigWrite),
+        (Post, "/backup/factory-reset", Exec),
+        (Get, "/logs", ConfigRead),
+        (Post, "/logs/export", ConfigRead),
+        (Get, "/audit", ConfigRead),
+        (Post, "/audit/export", ConfigRead),
+        (Get, "/reports", ConfigRead),
+        (Post, "/reports/generate", ConfigRead),
+        (Get, "/reports/:id", ConfigRead),
+        (Delete, "/reports/:id", ConfigWrite),
+        (Get, "/dashboards", ConfigRead),
+        (Get, "/dashboards/:id", ConfigRead),
+        (Get, "/fleet/devices", FleetRead),
+        (Post, "/fleet/devices", FleetWrite),
+        (Delete, "/fleet/devices/:id", FleetWrite),
+        (Get, "/fleet/devices/:id/status", FleetRead),
+        (Post, "/fleet/devices/:id/command", Exec),
+        (Get, "/fleet/templates", FleetRead),
+        (Post, "/fleet/templates", FleetWrite),
+        (Post, "/fleet/templates/:id/apply", FleetWrite),
+        (Get, "/webhooks", ConfigRead),
+        (Post, "/webhooks", ConfigWrite),
+        (Get, "/webhooks/:id", ConfigRead),
+        (Put, "/webhooks/:id", ConfigWrite),
+        (Delete, "/webhooks/:id", ConfigWrite),
+        (Get, "/webhooks/:id/deliveries", ConfigRead),
+        (Post, "/webhooks/:id/test", ConfigWrite),
+    ]
+}
+
+/// Generate a new token
+pub fn generate_token() -> String {
+    format!(
+        "{}{}{}",
+        TOKEN_PREFIX,
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// Whether a bearer token is an API token rather than a JWT
+pub fn is_api_token(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+/// Scope a token needs for a route, `None` when tokens are not accepted
+pub fn required_scope(method: &Method, path: &str) -> Option<TokenScope> {
+    let path = path.strip_prefix("/api").unwrap_or(path);
+    route_scopes()
+        .iter()
+        .find(|(m, pattern, _)| m == method && route_matches(pattern, path))
+        .map(|(_, _, scope)| *scope)
+}
+
+/// Whether a route takes no authentication
+fn is_public(path: &str) -> bool {
+    let path = path.strip_prefix("/api").unwrap_or(path);
+    PUBLIC_PATHS.contains(&path) || PUBLIC_PREFIXES.iter().any(|p| path.starts_with(p))
+}
+
+/// Match a path against a route pattern whose `:name` segments match any
+/// single segment
+fn route_matches(pattern: &str, path: &str) -> bool {
+    let mut segments = path.split('/').filter(|s| !s.is_empty());
+    pattern.split('/').filter(|p| !p.is_empty()).all(|p| {
+        segments
+            .next()
+            .is_some_and(|s| p.starts_with(':') || p == s)
+    }) && segments.next().is_none()
+}
+
+/// Check that a token-authenticated request is within the token's scopes.
+///
+/// JWT-authenticated requests and public routes always pass.
+pub fn check_token_scope(auth: &AuthContext, method: &Method, path: &str) -> ApiResult<()> {
+    if auth.token_id.is_none() || is_public(path) {
+        return Ok(());
+    }
+    match required_scope(method, path) {
+        Some(scope) if auth.scopes.contains(&scope) => Ok(()),
+        Some(scope) => Err(ApiError::forbidden(format!(
+            "API token is missing the {} scope",
+            scope.as_str()
+        ))),
+        None => Err(ApiError::forbidden(
+            "This endpoint cannot be used with an API token",
+        )),
+    }
+}
+
+/// Authenticate an API token.
+///
+/// The caller resolves the role of a service token's organization.
+pub async fn authenticate_token(token: &str, env: &Env) -> ApiResult<AuthContext> {
+    let token_hash = hash_token(token).await?;
+    let stored = storage::find_api_token(&token_hash, env)
+        .await?
+        .ok_or_else(|| ApiError::unauthorized("Invalid API token"))?;
+
+    if stored.token.revoked_at.is_some() {
+        return Err(ApiError::unauthorized("API token revoked"));
+    }
+    if stored.token.expires_at <= chrono::Utc::now().timestamp() {
+        return Err(ApiError::unauthorized("API token expired"));
+    }
+
+    Ok(AuthContext {
+        user_id: stored.user_id,
+        org_id: stored.token.org_id,
+        role: None,
+        plan: stored.plan,
+        email: None,
+        token_id: Some(stored.token.id),
+        scopes: stored.token.scopes,
+    })
+}
+
+/// Hex SHA-256 of a token, as stored in D1
+pub async fn hash_token(token: &str) -> ApiResult<String> {
+    let digest = sha256(token.as_bytes()).await?;
+    Ok(digest.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Compute SHA-256 using the Workers Web Crypto API (SubtleCrypto)
+async fn sha256(data: &[u8]) -> ApiResult<Vec<u8>> {
+    use js_sys::{Array, Function, Promise, Reflect, Uint8Array};
+    use wasm_bindgen::JsCast;
+    use wasm_bindgen_futures::JsFuture;
+
+    let global = js_sys::global();
+    let crypto = Reflect::get(&global, &"crypto".into())
+        .map_err(|_| ApiError::internal("crypto global not available"))?;
+    let subtle = Reflect::get(&crypto, &"subtle".into())
+        .map_err(|_| ApiError::internal("crypto.subtle not available"))?;
+
+    let data_arr = Uint8Array::from(data);
+
+    // crypto.subtle.digest("SHA-256", data)
+    let digest_fn: Function = Reflect::get(&subtle, &"digest".into())
+        .map_err(|_| ApiError::internal("digest not available"))?
+        .unchecked_into();
+    let digest_args = Array::new();
+    digest_args.push(&"SHA-256".into());
+    digest_args.push(&data_arr.buffer().into());
+    let digest_promise: Promise = Reflect::apply(&digest_fn, &subtle, &digest_args)
+        .map_err(|_| ApiError::internal("digest call failed"))?
+        .unchecked_into();
+    let digest = JsFuture::from(digest_promise)
+        .await
+        .map_err(|_| ApiError::internal("Failed to hash API token"))?;
+
+    Ok(Uint8Array::new(&digest).to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reads_need_read_scopes() {
+        assert_eq!(
+            required_scope(&Method::Get, "/fleet/devices"),
+            Some(TokenScope::FleetRead)
+        );
+        assert_eq!(
+            required_scope(&Method::Get, "/api/firewall/rules"),
+            Some(TokenScope::ConfigRead)
+        );
+        assert_eq!(
+            required_scope(&Method::Post, "/logs/export"),
+            Some(TokenScope::ConfigRead)
+        );
+        assert_eq!(
+            required_scope(&Method::Post, "/firewall/trace"),
+            Some(TokenScope::ConfigRead)
+        );
+    }
+
+    #[test]
+    fn test_writes_need_write_scopes() {
+        assert_eq!(
+            required_scope(&Method::Put, "/firewall/rules/r1"),
+            Some(TokenScope::ConfigWrite)
+        );
+        assert_eq!(
+            required_scope(&Method::Delete, "/dhcp/reservations/aa:bb"),
+            Some(TokenScope::ConfigWrite)
+        );
+        assert_eq!(
+            required_scope(&Method::Post, "/fleet/devices"),
+            Some(TokenScope::FleetWrite)
+        );
+    }
+
+    #[test]
+    fn test_operational_actions_need_exec() {
+        for (method, path) in [
+            (Method::Post, "/system/reboot"),
+            (Method::Post, "/wan/renew"),
+            (Method::Post, "/fleet/devices/d1/command"),
+            (Method::Post, "/dns/blocklists/b1/update"),
+            (Method::Post, "/backup/create"),
+            (Method::Delete, "/dhcp/leases/192.168.1.10"),
+        ] {
+            assert_eq!(
+                required_scope(&method, path),
+                Some(TokenScope::Exec),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_destructive_routes_need_exec() {
+        for (method, path) in [
+            (Method::Post, "/backup/factory-reset"),
+            (Method::Post, "/backup/restore"),
+            (Method::Post, "/firmware/download"),
+            (Method::Post, "/firmware/install"),
+            (Method::Post, "/firmware/upload"),
+            (Method::Post, "/firmware/slots/b/activate"),
+            (Method::Post, "/system/shutdown"),
+        ] {
+            assert_eq!(
+                required_scope(&method, path),
+                Some(TokenScope::Exec),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_unlisted_routes_reject_tokens() {
+        assert_eq!(required_scope(&Method::Get, "/users-report"), None);
+        assert_eq!(required_scope(&Method::Post, "/backup/b1"), None);
+        assert_eq!(required_scope(&Method::Patch, "/wan/config"), None);
+        assert_eq!(required_scope(&Method::Get, "/firewall/rules/r1/x"), None);
+    }
+
+    #[test]
+    fn test_every_authenticated_route_is_scoped() {
+        let router = include_str!("../handlers/router.rs");
+        for line in router.lines() {
+            let Some((method, path)) = line.trim().strip_prefix('.').and_then(|l| {
+                let (method, rest) = l.split_once("_async(\"")?;
+                Some((method, rest.split('"').next()?))
+            }) else {
+                continue;
+            };
+            let method = match method {
+                "get" => Method::Get,
+                "post" => Method::Post,
+                "put" => Method::Put,
+                "delete" => Method::Delete,
+                // The not-found fallback
+                _ => continue,
+            };
+            let account = ["/user/", "/billing/", "/orgs"]
+                .iter()
+                .any(|p| path.starts_with(p));
+            assert_eq!(
+                required_scope(&method, path).is_some(),
+                !account && !is_public(path),
+                "{:?} {}",
+                method,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_public_routes_skip_scopes() {
+        assert!(is_public("/health"));
+        assert!(is_public("/onboarding/routers"));
+        assert!(!is_public("/onboarding"));
+        assert!(!is_public("/system/status"));
+    }
+
+    #[test]
+    fn test_account_routes_reject_tokens() {
+        assert_eq!(required_scope(&Method::Get, "/user/tokens"), None);
+        assert_eq!(required_scope(&Method::Post, "/api/user/tokens"), None);
+        assert_eq!(required_scope(&Method::Put, "/billing/plan"), None);
+        assert_eq!(required_scope(&Method::Get, "/orgs"), None);
+        assert_eq!(required_scope(&Method::Delete, "/orgs/o1"), None);
+    }
+
+    #[test]
+    fn test_generated_tokens_are_recognised() {
+        let token = generate_token();
+        assert!(is_api_token(&token));
+        assert_eq!(token.len(), TOKEN_PREFIX.len() + 64);
+        assert!(!is_api_token("eyJhbGciOiJSUzI1NiJ9.e30.sig"));
+    }
+}
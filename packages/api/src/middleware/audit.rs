@@ -0,0 +1,609 @@
+//! Audit logging for mutating API calls
+//!
+//! Successful `PUT`, `POST` and `DELETE` requests are recorded in the D1
+//! `audit_logs` table with the acting user, the target device and resource,
+//! a redacted before/after diff, and the client IP and user agent.
+//!
+//! The "before" state is read from storage before the mutation runs: the
+//! device's stored config section for device resources, narrowed to the
+//! item with the request's id inside collections, or the webhook or
+//! organization itself. The "after" state is the request body.
+
+use crate::middleware::AuthContext;
+use crate::models::fleet::AuditLogEntry;
+use crate::storage;
+use serde_json::{Map, Value, json};
+use worker::*;
+
+/// Collections whose next path segment is a resource id
+const COLLECTIONS: &[&str] = &[
+    "backup",
+    "billing/payment-methods",
+    "ddns/configs",
+    "dhcp/leases",
+    "dhcp/reservations",
+    "dns/allowlist",
+    "dns/blocklists",
+    "firewall/feeds",
+    "firewall/groups/addresses",
+    "firewall/groups/ports",
+    "firewall/rules",
+    "firewall/zones",
+    "firmware/slots",
+    "fleet/devices",
+    "fleet/templates",
+    "ips/categories",
+    "ips/rules",
+    "lan/vlans",
+    "nat/rules",
+    "nat/upnp",
+    "orgs",
+    "orgs/devices",
+    "orgs/members",
+    "qos/device-limits",
+    "qos/rules",
+    "reports",
+    "routing/routes",
+    "user/sessions",
+    "vpn/client/profiles",
+    "vpn/server/peers",
+    "webhooks",
+    "wifi/networks",
+    "wifi/radios",
+];
+
+/// Trailing path segments that name an action rather than a resource
+const ACTIONS: &[&str] = &[
+    "activate",
+    "apply",
+    "command",
+    "connect",
+    "create",
+    "disconnect",
+    "download",
+    "enable",
+    "export",
+    "factory-reset",
+    "generate",
+    "install",
+    "order",
+    "reboot",
+    "release",
+    "renew",
+    "restore",
+    "shutdown",
+    "test",
+    "trace",
+    "update",
+    "upload",
+];
+
+/// Stored config section behind each audited device resource type
+const CONFIG_SECTIONS: &[(&str, &str)] = &[
+    ("ddns.config", "ddns"),
+    ("dhcp.config", "dhcp"),
+    ("dhcp.reservations", "dhcp_reservations"),
+    ("dns.allowlist", "dns_allowlist"),
+    ("dns.blocklists", "dns_blocklists"),
+    ("dns.config", "dns"),
+    ("firewall.feeds", "threat_feeds"),
+    ("firewall.groups.addresses", "address_groups"),
+    ("firewall.groups.ports", "port_groups"),
+    ("firewall.policies", "zone_policies"),
+    ("firewall.rules", "firewall_rules"),
+    ("firewall.zones", "firewall_zones"),
+    ("ips.categories", "ids_categories"),
+    ("ips.config", "ids"),
+    ("ips.rules", "ids_rules"),
+    ("lan.config", "lan"),
+    ("lan.vlans", "vlans"),
+    ("nat.rules", "nat_rules"),
+    ("qos.config", "qos"),
+    ("qos.device_limits", "device_limits"),
+    ("qos.rules", "traffic_classes"),
+    ("routing.routes", "routes"),
+    ("vpn.client.profiles", "vpn_profiles"),
+    ("vpn.server.config", "vpn_server"),
+    ("vpn.server.peers", "vpn_peers"),
+    ("wan.config", "wan"),
+    ("wifi.networks", "wifi_networks"),
+    ("wifi.radios", "wifi_radios"),
+];
+
+/// Key fragments whose values are never written to the audit log
+const SENSITIVE_KEYS: &[&str] = &[
+    "api_key",
+    "credential",
+    "passphrase",
+    "password",
+    "private_key",
+    "psk",
+    "secret",
+    "token",
+];
+
+/// Replacement for redacted values
+const REDACTED: &str = "[REDACTED]";
+
+/// What a mutating request acts on, derived from its method and path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditTarget {
+    /// `create` / `update` / `delete`, or the trailing action segment
+    pub action: String,
+    /// Dotted resource path, e.g. `lan.vlans` or `wan.config`
+    pub resource_type: String,
+    /// Id segment following a collection, if any
+    pub resource_id: Option<String>,
+    /// Path of the resource itself, without a trailing action
+    pub resource_path: String,
+    /// Path of the parent collection when the resource has an id
+    pub collection_path: Option<String>,
+}
+
+/// Classify a request; returns `None` for methods that are not audited.
+pub fn classify(method: &Method, path: &str) -> Option<AuditTarget> {
+    let default_action = match method {
+        Method::Post => "create",
+        Method::Put => "update",
+        Method::Delete => "delete",
+        _ => return None,
+    };
+
+    let mut segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let prefix = if segments.first() == Some(&"api") {
+        segments.remove(0);
+        "/api"
+    } else {
+        ""
+    };
+    if segments.is_empty() {
+        return None;
+    }
+
+    let collection = COLLECTIONS
+        .iter()
+        .map(|c| c.split('/').collect::<Vec<_>>())
+        .filter(|c| segments.len() > c.len() && segments.starts_with(c))
+        .filter(|c| !ACTIONS.contains(&segments[c.len()]))
+        .max_by_key(|c| c.len());
+
+    // (path of the collection, resource type segments, id, action)
+    let (resource, kind, resource_id, action) = match collection {
+        Some(c) => {
+            let n = c.len();
+            let nested = segments
+                .get(n + 1)
+                .filter(|sub| COLLECTIONS.contains(&format!("{}/{}", c.join("/"), sub).as_str()));
+            match nested {
+                // A collection inside an item, e.g. /orgs/:id/members/:user_id
+                Some(sub) => {
+                    let mut kind = c.clone();
+                    kind.push(sub);
+                    let action = if segments.len() == n + 4 {
+                        Some(segments[n + 3])
+                    } else {
+                        None
+                    };
+                    (
+                        &segments[..n + 2],
+                        kind,
+                        segments.get(n + 2).copied(),
+                        action,
+                    )
+                }
+                None => {
+                    let action = if segments.len() == n + 2 {
+                        Some(segments[n + 1])
+                    } else {
+                        None
+                    };
+                    (&segments[..n], c, Some(segments[n]), action)
+                }
+            }
+        }
+        None => match segments.split_last() {
+            Some((last, rest)) if !rest.is_empty() && ACTIONS.contains(last) => {
+                (rest, rest.to_vec(), None, Some(*last))
+            }
+            _ => (&segments[..], segments.clone(), None, None),
+        },
+    };
+
+    let join = |segs: &[&str]| format!("{}/{}", prefix, segs.join("/"));
+    let resource_path = match resource_id {
+        Some(id) => format!("{}/{}", join(resource), id),
+        None => join(resource),
+    };
+
+    Some(AuditTarget {
+        action: action.unwrap_or(default_action).replace('-', "_"),
+        resource_type: kind.join(".").replace('-', "_"),
+        resource_id: resource_id.map(str::to_string),
+        resource_path,
+        collection_path: resource_id.map(|_| join(resource)),
+    })
+}
+
+/// Whether an object key holds a secret
+fn is_sensitive(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    SENSITIVE_KEYS.iter().any(|s| key.contains(s))
+}
+
+/// Replace the values of sensitive keys, at any depth, with `[REDACTED]`
+pub fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, v) in map.iter_mut() {
+                if is_sensitive(key) && !v.is_null() {
+                    *v = Value::String(REDACTED.to_string());
+                } else {
+                    redact(v);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Build the redacted `{"before": .., "after": ..}` change record.
+///
+/// When both sides are objects only the keys present in `after` that
+/// differ from `before` are kept, since a request body names the fields it
+/// sets. Secrets are diffed before redaction, so a changed password shows
+/// up as a redacted key on both sides.
+pub fn diff(before: Option<&Value>, after: Option<&Value>) -> Option<Value> {
+    let (before, after) = match (before, after) {
+        (None, None) => return None,
+        (Some(Value::Object(b)), Some(Value::Object(a))) => {
+            let changed: Vec<&String> = a.keys().filter(|k| b.get(*k) != a.get(*k)).collect();
+            let pick = |m: &Map<String, Value>| -> Map<String, Value> {
+                changed
+                    .iter()
+                    .map(|k| ((*k).clone(), m.get(*k).cloned().unwrap_or(Value::Null)))
+                    .collect()
+            };
+            (Value::Object(pick(b)), Value::Object(pick(a)))
+        }
+        (b, a) => (
+            b.cloned().unwrap_or(Value::Null),
+            a.cloned().unwrap_or(Value::Null),
+        ),
+    };
+
+    let mut changes = json!({ "before": before, "after": after });
+    redact(&mut changes);
+    Some(changes)
+}
+
+/// Stored config section holding resources of `resource_type`
+fn config_section(resource_type: &str) -> Option<&'static str> {
+    CONFIG_SECTIONS
+        .iter()
+        .find(|(kind, _)| *kind == resource_type)
+        .map(|(_, section)| *section)
+}
+
+/// Find the item with a top-level field equal to `id` in a list.
+///
+/// Accepts a bare array or an object wrapping one (e.g. `{"result": [..]}`).
+/// Numeric fields match their decimal form, since ids in paths are text.
+fn find_item<'a>(list: &'a Value, id: &str) -> Option<&'a Value> {
+    let matches = |item: &&Value| {
+        item.as_object().is_some_and(|o| {
+            o.values().any(|v| match v {
+                Value::String(s) => s == id,
+                Value::Number(n) => n.to_string() == id,
+                _ => false,
+            })
+        })
+    };
+    match list {
+        Value::Array(items) => items.iter().find(matches),
+        Value::Object(map) => map
+            .values()
+            .filter_map(Value::as_array)
+            .find_map(|items| items.iter().find(matches)),
+        _ => None,
+    }
+}
+
+/// Current state of the target resource, if it is stored
+async fn snapshot(
+    target: &AuditTarget,
+    device_id: Option<&str>,
+    auth: &AuthContext,
+    env: &Env,
+) -> Option<Value> {
+    let id = target.resource_id.as_deref();
+    match target.resource_type.as_str() {
+        "webhooks" => {
+            let webhook = storage::get_webhook(&auth.user_id, id?, env).await.ok()?;
+            serde_json::to_value(webhook).ok()
+        }
+        "orgs" => {
+            let org = storage::get_org(&auth.user_id, id?, env).await.ok()?;
+            serde_json::to_value(org).ok()
+        }
+        kind => {
+            let section = config_section(kind)?;
+            let config: Value = storage::get_config(device_id?, section, env).await.ok()?;
+            match id {
+                Some(id) => find_item(&config, id).cloned(),
+                None => Some(config),
+            }
+        }
+    }
+}
+
+/// A mutating request captured before it runs
+pub struct PendingAudit {
+    user_id: String,
+    device_id: Option<String>,
+    target: AuditTarget,
+    before: Option<Value>,
+    after: Option<Value>,
+    ip_address: String,
+    user_agent: String,
+    token_id: Option<String>,
+}
+
+impl PendingAudit {
+    /// Capture the request body and current resource state.
+    ///
+    /// Returns `None` for requests that are not audited.
+    pub async fn capture(req: &Request, auth: &AuthContext, env: &Env) -> Option<Self> {
+        let url = req.url().ok()?;
+        let target = classify(&req.method(), url.path())?;
+
+        let header = |name: &str| req.headers().get(name).ok().flatten();
+        let device_id = header("X-Device-ID")
+            .or_else(|| {
+                url.query_pairs()
+                    .find(|(k, _)| k == "device_id")
+                    .map(|(_, v)| v.into_owned())
+            })
+            .or_else(|| {
+                (target.resource_type == "fleet.devices")
+                    .then(|| target.resource_id.clone())
+                    .flatten()
+            });
+
+        let after = match req.clone() {
+            Ok(mut copy) => copy
+                .text()
+                .await
+                .ok()
+                .and_then(|body| serde_json::from_str(&body).ok()),
+            Err(_) => None,
+        };
+
+        let before = if req.method() == Method::Post && target.resource_id.is_none() {
+            None
+        } else {
+            snapshot(&target, device_id.as_deref(), auth, env).await
+        };
+
+        Some(Self {
+            user_id: auth.user_id.clone(),
+            device_id,
+            target,
+            before,
+            after,
+            ip_address: header("CF-Connecting-IP").unwrap_or_default(),
+            user_agent: header("User-Agent").unwrap_or_default(),
+            token_id: auth.token_id.clone(),
+        })
+    }
+
+    /// Record the entry once the handler has answered.
+    ///
+    /// Only 2xx responses are recorded. The D1 write runs after the
+    /// response is returned; failures are logged and never affect it.
+    pub async fn complete(self, response: &mut Response, env: Env, ctx: &Context) {
+        if !(200..300).contains(&response.status_code()) {
+            return;
+        }
+
+        // Creates get their id from the response body
+        let created_id = match response.cloned() {
+            Ok(mut copy) => copy
+                .json::<Value>()
+                .await
+                .ok()
+                .and_then(|v| v.get("id").and_then(Value::as_str).map(str::to_string)),
+            Err(_) => None,
+        };
+
+        let entry = AuditLogEntry {
+            id: uuid::Uuid::new_v4().to_string(),
+            timestamp: chrono::Utc::now().timestamp(),
+            user_id: self.user_id,
+            device_id: self.device_id,
+            action: self.target.action,
+            resource_type: self.target.resource_type,
+            resource_id: self.target.resource_id.or(created_id).unwrap_or_default(),
+            changes: diff(self.before.as_ref(), self.after.as_ref()),
+            ip_address: self.ip_address,
+            user_agent: self.user_agent,
+            token_id: self.token_id,
+        };
+
+        ctx.wait_until(async move {
+            if let Err(e) = storage::insert_audit_log(&entry, &env).await {
+                console_warn!("Failed to record audit log entry: {}", e.error.message);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(method: Method, path: &str) -> AuditTarget {
+        classify(&method, path).expect("audited")
+    }
+
+    #[test]
+    fn test_classify_skips_reads() {
+        assert!(classify(&Method::Get, "/lan/vlans").is_none());
+        assert!(classify(&Method::Options, "/lan/vlans").is_none());
+    }
+
+    #[test]
+    fn test_classify_collection_items() {
+        let t = target(Method::Put, "/lan/vlans/vlan-10");
+        assert_eq!(t.action, "update");
+        assert_eq!(t.resource_type, "lan.vlans");
+        assert_eq!(t.resource_id.as_deref(), Some("vlan-10"));
+        assert_eq!(t.resource_path, "/lan/vlans/vlan-10");
+        assert_eq!(t.collection_path.as_deref(), Some("/lan/vlans"));
+
+        let t = target(Method::Delete, "/qos/device-limits/aa:bb:cc:dd:ee:ff");
+        assert_eq!(t.action, "delete");
+        assert_eq!(t.resource_type, "qos.device_limits");
+        assert_eq!(t.resource_id.as_deref(), Some("aa:bb:cc:dd:ee:ff"));
+    }
+
+    #[test]
+    fn test_classify_creates_and_singletons() {
+        let t = target(Method::Post, "/firewall/rules");
+        assert_eq!(t.action, "create");
+        assert_eq!(t.resource_type, "firewall.rules");
+        assert_eq!(t.resource_id, None);
+        assert_eq!(t.collection_path, None);
+
+        let t = target(Method::Put, "/api/wan/config");
+        assert_eq!(t.action, "update");
+        assert_eq!(t.resource_type, "wan.config");
+        assert_eq!(t.resource_path, "/api/wan/config");
+    }
+
+    #[test]
+    fn test_classify_actions() {
+        let t = target(Method::Post, "/system/reboot");
+        assert_eq!(t.action, "reboot");
+        assert_eq!(t.resource_type, "system");
+
+        let t = target(Method::Post, "/vpn/client/profiles/p1/connect");
+        assert_eq!(t.action, "connect");
+        assert_eq!(t.resource_type, "vpn.client.profiles");
+        assert_eq!(t.resource_id.as_deref(), Some("p1"));
+        assert_eq!(t.resource_path, "/vpn/client/profiles/p1");
+
+        // An action directly after a collection is not an id
+        let t = target(Method::Post, "/backup/factory-reset");
+        assert_eq!(t.action, "factory_reset");
+        assert_eq!(t.resource_type, "backup");
+        assert_eq!(t.resource_id, None);
+
+        let t = target(Method::Post, "/firewall/trace");
+        assert_eq!(t.action, "trace");
+        assert_eq!(t.resource_type, "firewall");
+
+        let t = target(Method::Put, "/firewall/rules/order");
+        assert_eq!(t.action, "order");
+        assert_eq!(t.resource_type, "firewall.rules");
+    }
+
+    #[test]
+    fn test_classify_nested_collections() {
+        let t = target(Method::Put, "/orgs/org-1/members/user-2");
+        assert_eq!(t.action, "update");
+        assert_eq!(t.resource_type, "orgs.members");
+        assert_eq!(t.resource_id.as_deref(), Some("user-2"));
+        assert_eq!(t.resource_path, "/orgs/org-1/members/user-2");
+        assert_eq!(t.collection_path.as_deref(), Some("/orgs/org-1/members"));
+
+        let t = target(Method::Post, "/orgs/org-1/members");
+        assert_eq!(t.action, "create");
+        assert_eq!(t.resource_type, "orgs.members");
+        assert_eq!(t.resource_id, None);
+        assert_eq!(t.resource_path, "/orgs/org-1/members");
+
+        let t = target(Method::Delete, "/orgs/org-1");
+        assert_eq!(t.resource_type, "orgs");
+        assert_eq!(t.resource_id.as_deref(), Some("org-1"));
+    }
+
+    #[test]
+    fn test_redact_nested_secrets() {
+        let mut value = json!({
+            "ssid": "home",
+            "wpa_psk": "hunter22",
+            "peers": [{ "name": "laptop", "private_key": "abc" }],
+            "current_password": null
+        });
+        redact(&mut value);
+        assert_eq!(value["ssid"], "home");
+        assert_eq!(value["wpa_psk"], REDACTED);
+        assert_eq!(value["peers"][0]["private_key"], REDACTED);
+        assert_eq!(value["peers"][0]["name"], "laptop");
+        assert!(value["current_password"].is_null());
+    }
+
+    #[test]
+    fn test_diff_keeps_changed_fields() {
+        let before = json!({ "name": "guest", "vlan": 10, "enabled": true, "password": "old" });
+        let after = json!({ "name": "guest", "vlan": 20, "password": "new" });
+        let changes = diff(Some(&before), Some(&after)).unwrap();
+
+        assert_eq!(
+            changes,
+            json!({
+                "before": { "vlan": 10, "password": REDACTED },
+                "after": { "vlan": 20, "password": REDACTED }
+            })
+        );
+    }
+
+    #[test]
+    fn test_diff_create_and_delete() {
+        let item = json!({ "id": "r1", "secret": "s" });
+        assert_eq!(
+            diff(None, Some(&item)).unwrap(),
+            json!({ "before": null, "after": { "id": "r1", "secret": REDACTED } })
+        );
+        assert_eq!(
+            diff(Some(&item), None).unwrap(),
+            json!({ "before": { "id": "r1", "secret": REDACTED }, "after": null })
+        );
+        assert!(diff(None, None).is_none());
+    }
+
+    #[test]
+    fn test_find_item_in_wrapped_list() {
+        let list = json!({ "result": [{ "mac": "aa" }, { "mac": "bb", "ip": "10.0.0.2" }] });
+        assert_eq!(find_item(&list, "10.0.0.2").unwrap()["mac"], "bb");
+        assert!(find_item(&json!([{ "id": "x" }]), "y").is_none());
+    }
+
+    #[test]
+    fn test_find_item_by_numeric_id() {
+        let vlans = json!([{ "id": 10, "name": "iot" }, { "id": 20, "name": "guest" }]);
+        assert_eq!(find_item(&vlans, "20").unwrap()["name"], "guest");
+        assert!(find_item(&vlans, "2").is_none());
+    }
+
+    #[test]
+    fn test_config_section_for_audited_routes() {
+        let section = |method, path| config_section(&target(method, path).resource_type);
+        assert_eq!(section(Method::Put, "/wan/config"), Some("wan"));
+        assert_eq!(section(Method::Put, "/lan/vlans/10"), Some("vlans"));
+        assert_eq!(
+            section(Method::Delete, "/firewall/groups/ports/3"),
+            Some("port_groups")
+        );
+        assert_eq!(
+            section(Method::Put, "/qos/rules/c1"),
+            Some("traffic_classes")
+        );
+        assert_eq!(
+            section(Method::Put, "/vpn/server/config"),
+            Some("vpn_server")
+        );
+        assert_eq!(section(Method::Post, "/system/reboot"), None);
+    }
+}
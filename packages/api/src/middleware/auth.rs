@@ -2,7 +2,11 @@
 
 #![allow(dead_code)]
 
+use crate::middleware::api_tokens;
+use crate::models::orgs::{Permission, Role};
+use crate::models::tokens::TokenScope;
 use crate::models::{ApiError, ApiResult};
+use crate::storage::{self, DeviceScope};
 use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
 use serde::{Deserialize, Serialize};
 use worker::*;
@@ -51,9 +55,16 @@ impl JwtClaims {
 #[derive(Debug, Clone)]
 pub struct AuthContext {
     pub user_id: String,
+    /// Active organization, only set when the user is a member
     pub org_id: Option<String>,
+    /// The user's role in the active organization
+    pub role: Option<Role>,
     pub plan: String,
     pub email: Option<String>,
+    /// API token that authenticated the request, `None` for JWTs
+    pub token_id: Option<String>,
+    /// Scopes granted by the API token
+    pub scopes: Vec<TokenScope>,
 }
 
 impl From<JwtClaims> for AuthContext {
@@ -61,12 +72,27 @@ impl From<JwtClaims> for AuthContext {
         Self {
             user_id: claims.sub,
             org_id: claims.org_id,
+            role: None,
             plan: claims.plan.unwrap_or_else(|| "starter".to_string()),
             email: claims.email,
+            token_id: None,
+            scopes: Vec::new(),
         }
     }
 }
 
+/// Outcome of authenticating a request.
+///
+/// `main` authenticates every request once and hands the outcome to the
+/// handlers as router data, so JWT verification, token lookups and the
+/// organization role query run a single time per request.
+pub type RequestAuth = ApiResult<AuthContext>;
+
+/// The authenticated caller of a route that requires authentication
+pub fn authenticated(ctx: &RouteContext<RequestAuth>) -> ApiResult<AuthContext> {
+    ctx.data.clone()
+}
+
 /// Device authentication context (for router agents)
 #[derive(Debug, Clone)]
 pub struct DeviceAuthContext {
@@ -328,22 +354,34 @@ async fn verify_jwt(token: &str, env: &Env) -> ApiResult<JwtClaims> {
 // Public authentication entry points
 // ---------------------------------------------------------------------------
 
-/// Authenticate a user request via Clerk JWT.
+/// Authenticate a user request via Clerk JWT or API token.
 ///
-/// When `CLERK_JWKS_URL` is set (production), verifies the RS256 signature
-/// against the Clerk JWKS endpoint. Otherwise falls back to unverified
-/// decoding for local development.
+/// API tokens (`ngfw_pat_...`) are checked against their stored hashes. For
+/// JWTs, when `CLERK_JWKS_URL` is set (production), verifies the RS256
+/// signature against the Clerk JWKS endpoint. Otherwise falls back to
+/// unverified decoding for local development.
+///
+/// The `org_id` becomes the active organization only if the user is a member
+/// of it; the membership's role is attached to the context.
 pub async fn authenticate(req: &Request, env: &Env) -> ApiResult<AuthContext> {
     let token = extract_bearer_token(req)?;
 
-    if env.var("CLERK_JWKS_URL").is_ok() {
-        let claims = verify_jwt(&token, env).await?;
-        return Ok(AuthContext::from(claims));
+    let mut auth = if api_tokens::is_api_token(&token) {
+        api_tokens::authenticate_token(&token, env).await?
+    } else if env.var("CLERK_JWKS_URL").is_ok() {
+        AuthContext::from(verify_jwt(&token, env).await?)
+    } else {
+        // Dev fallback — no JWKS URL configured
+        AuthContext::from(decode_jwt_unverified(&token)?)
+    };
+
+    if let Some(org_id) = &auth.org_id {
+        auth.role = storage::get_org_role(org_id, &auth.user_id, env).await?;
+        if auth.role.is_none() {
+            auth.org_id = None;
+        }
     }
-
-    // Dev fallback — no JWKS URL configured
-    let claims = decode_jwt_unverified(&token)?;
-    Ok(AuthContext::from(claims))
+    Ok(auth)
 }
 
 /// Authenticate device API key
@@ -384,8 +422,16 @@ pub async fn authenticate_device(api_key: &str, env: &Env) -> ApiResult<DeviceAu
     })
 }
 
-/// Check if user has access to a specific device
-pub async fn check_device_access(auth: &AuthContext, device_id: &str, env: &Env) -> ApiResult<()> {
+/// Check that the user holds `permission` on a specific device.
+///
+/// Personal devices grant their owner every permission. Devices in an
+/// organization grant what the user's role there allows.
+pub async fn check_device_access(
+    auth: &AuthContext,
+    device_id: &str,
+    permission: Permission,
+    env: &Env,
+) -> ApiResult<()> {
     let kv = env
         .kv("DEVICES")
         .map_err(|_| ApiError::internal("Failed to access device store"))?;
@@ -408,15 +454,80 @@ pub async fn check_device_access(auth: &AuthContext, device_id: &str, env: &Env)
     let device: DeviceRecord = serde_json::from_str(&device_data)
         .map_err(|_| ApiError::internal("Invalid device record"))?;
 
-    // Check if user owns the device or is in the same organization
-    let has_access =
-        device.owner_id == auth.user_id || (auth.org_id.is_some() && auth.org_id == device.org_id);
+    let role = match &device.org_id {
+        Some(org_id) if auth.org_id.as_ref() == Some(org_id) => auth.role,
+        Some(org_id) => storage::get_org_role(org_id, &auth.user_id, env).await?,
+        None if device.owner_id == auth.user_id => Some(Role::Owner),
+        None => None,
+    };
+
+    match role {
+        Some(role) if role.allows(permission) => Ok(()),
+        Some(_) => Err(permission_denied(permission)),
+        None => Err(ApiError::forbidden("Access denied to this device")),
+    }
+}
 
-    if !has_access {
-        return Err(ApiError::forbidden("Access denied to this device"));
+/// Check that the user holds `permission` in the active organization.
+///
+/// Without an active organization the user acts on their own account and
+/// holds every permission.
+pub fn require_permission(auth: &AuthContext, permission: Permission) -> ApiResult<()> {
+    match (&auth.org_id, auth.role) {
+        (None, _) => Ok(()),
+        (Some(_), Some(role)) if role.allows(permission) => Ok(()),
+        _ => Err(permission_denied(permission)),
     }
+}
 
-    Ok(())
+/// Devices a read of device data (logs, reports, audit entries) covers.
+///
+/// With a `device_id` the user must hold `permission` on that device.
+/// Otherwise the read covers the active organization's devices, or the
+/// user's personal ones, like `GET /fleet/devices`.
+pub async fn device_scope<'a>(
+    auth: &'a AuthContext,
+    device_id: Option<&'a str>,
+    permission: Permission,
+    env: &Env,
+) -> ApiResult<DeviceScope<'a>> {
+    match device_id {
+        Some(device_id) => {
+            check_device_access(auth, device_id, permission, env).await?;
+            Ok(DeviceScope::Device(device_id))
+        }
+        None => {
+            require_permission(auth, permission)?;
+            Ok(match &auth.org_id {
+                Some(org_id) => DeviceScope::Org(org_id),
+                None => DeviceScope::Personal(&auth.user_id),
+            })
+        }
+    }
+}
+
+/// Check that the user holds `permission` in a specific organization,
+/// returning their role there
+pub async fn require_org_permission(
+    auth: &AuthContext,
+    org_id: &str,
+    permission: Permission,
+    env: &Env,
+) -> ApiResult<Role> {
+    let role = storage::get_org_role(org_id, &auth.user_id, env)
+        .await?
+        .ok_or_else(|| ApiError::not_found("Organization"))?;
+    if !role.allows(permission) {
+        return Err(permission_denied(permission));
+    }
+    Ok(role)
+}
+
+fn permission_denied(permission: Permission) -> ApiError {
+    ApiError::forbidden(format!(
+        "This action requires the {} role or higher",
+        permission.min_role().as_str()
+    ))
 }
 
 /// Plan-based feature gate
@@ -23,6 +23,7 @@ pub struct CorsHeaders {
     pub allow_origin: Option<String>,
     pub allow_methods: String,
     pub allow_headers: String,
+    pub expose_headers: String,
     pub allow_credentials: bool,
     pub max_age: u32,
 }
@@ -32,7 +33,8 @@ impl Default for CorsHeaders {
         Self {
             allow_origin: None,
             allow_methods: "GET, POST, PUT, DELETE, OPTIONS".to_string(),
-            allow_headers: "Authorization, Content-Type, X-Device-ID".to_string(),
+            allow_headers: "Authorization, Content-Type, X-Device-ID, If-Match".to_string(),
+            expose_headers: "ETag".to_string(),
             allow_credentials: true,
             max_age: 86400,
         }
@@ -63,6 +65,7 @@ impl CorsHeaders {
             headers.set("Access-Control-Allow-Origin", origin)?;
             headers.set("Access-Control-Allow-Methods", &self.allow_methods)?;
             headers.set("Access-Control-Allow-Headers", &self.allow_headers)?;
+            headers.set("Access-Control-Expose-Headers", &self.expose_headers)?;
             headers.set("Access-Control-Max-Age", &self.max_age.to_string())?;
             headers.set("Vary", "Origin")?;
             if self.allow_credentials {
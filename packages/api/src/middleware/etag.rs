@@ -0,0 +1,142 @@
+//! Optimistic concurrency for config sections via `ETag` / `If-Match`
+//!
+//! Every config section carries a version that `storage::update_config`
+//! bumps on each write. GET handlers expose it as an `ETag`; mutating
+//! handlers require a matching `If-Match` so that two admins editing the
+//! same section cannot silently overwrite each other.
+
+use crate::models::{ApiError, ApiResult, ErrorCode, IntoApiResponse};
+use crate::storage;
+use serde::Serialize;
+use worker::*;
+
+/// Format a section version as a strong entity tag
+pub fn etag_for(version: u64) -> String {
+    format!("\"v{}\"", version)
+}
+
+/// Check an `If-Match` header value against the current entity tag.
+///
+/// Accepts a comma-separated list of tags, compared strongly as RFC 9110
+/// requires for `If-Match`: weak tags (`W/"..."`) never match. `*` matches
+/// any version and is an explicit opt-out of the check.
+pub fn if_match_satisfied(header: &str, current: &str) -> bool {
+    header
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag == current)
+}
+
+/// Require an `If-Match` header matching the current version of `section`.
+///
+/// Returns the version the write must still find when it commits (passed
+/// on to `storage::update_config`, which re-checks it atomically), `None`
+/// for `*`, or a `PRECONDITION_FAILED` error carrying the current version
+/// when the header is missing or stale.
+pub async fn require_if_match(
+    req: &Request,
+    device_id: &str,
+    section: &str,
+    env: &Env,
+) -> ApiResult<Option<u64>> {
+    let current = storage::get_config_version(device_id, section, env).await?;
+    let header = req
+        .headers()
+        .get("If-Match")
+        .map_err(|_| ApiError::bad_request("Invalid If-Match header"))?;
+
+    match header {
+        Some(value) if if_match_satisfied(&value, &etag_for(current)) => {
+            Ok((value.trim() != "*").then_some(current))
+        }
+        Some(_) => Err(ApiError::precondition_failed(
+            "Configuration was modified by another request",
+            current,
+        )),
+        None => Err(ApiError::precondition_failed(
+            "If-Match header is required",
+            current,
+        )),
+    }
+}
+
+/// Convert a precondition error into a 412 response that carries the
+/// current `ETag`, so clients can refetch and retry.
+pub fn precondition_response(err: ApiError) -> Result<Response> {
+    let version = err.error.details.as_ref().and_then(|d| d.current_version);
+    let response = err.into_response()?;
+    match version {
+        Some(v) => with_etag(response, v),
+        None => Ok(response),
+    }
+}
+
+/// Attach the `ETag` header for a section version to a response
+pub fn with_etag(response: Response, version: u64) -> Result<Response> {
+    let headers = response.headers().clone();
+    headers.set("ETag", &etag_for(version))?;
+    Ok(response.with_headers(headers))
+}
+
+/// Build the API response for a config section result, tagging successful
+/// responses with the section's current `ETag`.
+pub async fn respond_with_etag<T: Serialize>(
+    result: ApiResult<T>,
+    device_id: &str,
+    section: &str,
+    env: &Env,
+) -> Result<Response> {
+    match result {
+        Err(e) if e.error.code == ErrorCode::PreconditionFailed => {
+            return precondition_response(e);
+        }
+        Err(_) => return result.into_api_response(),
+        Ok(_) => {}
+    }
+
+    let version = storage::get_config_version(device_id, section, env).await;
+    let response = result.into_api_response()?;
+    match version {
+        Ok(v) => with_etag(response, v),
+        Err(_) => Ok(response),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_etag_format() {
+        assert_eq!(etag_for(0), "\"v0\"");
+        assert_eq!(etag_for(42), "\"v42\"");
+    }
+
+    #[test]
+    fn test_if_match_exact() {
+        assert!(if_match_satisfied("\"v3\"", &etag_for(3)));
+        assert!(!if_match_satisfied("\"v2\"", &etag_for(3)));
+    }
+
+    #[test]
+    fn test_if_match_list() {
+        assert!(if_match_satisfied("\"v1\", \"v3\"", &etag_for(3)));
+        assert!(!if_match_satisfied("\"v1\", \"v2\"", &etag_for(3)));
+    }
+
+    #[test]
+    fn test_if_match_rejects_weak_tags() {
+        assert!(!if_match_satisfied("W/\"v3\"", &etag_for(3)));
+        assert!(!if_match_satisfied("\"v1\", W/\"v3\"", &etag_for(3)));
+    }
+
+    #[test]
+    fn test_if_match_wildcard() {
+        assert!(if_match_satisfied("*", &etag_for(7)));
+    }
+
+    #[test]
+    fn test_if_match_unquoted_does_not_match() {
+        assert!(!if_match_satisfied("v3", &etag_for(3)));
+    }
+}
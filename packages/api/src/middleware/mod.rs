@@ -1,7 +1,11 @@
 //! Middleware components for the NGFW.sh API
 
+pub mod api_tokens;
+pub mod audit;
 pub mod auth;
 pub mod cors;
+pub mod etag;
 pub mod rate_limit;
 
 pub use auth::*;
+pub use etag::{precondition_response, require_if_match, respond_with_etag};
@@ -1,15 +1,30 @@
 //! Rate limiting middleware
 //!
-//! This module is scaffolded for future implementation.
-
-#![allow(dead_code)]
+//! Authenticated requests are counted per user in fixed windows by the
+//! `RateLimiter` Durable Object, so concurrent requests served by different
+//! isolates share one counter. The general budget is the plan's
+//! `api_requests_per_minute` limit; a few expensive or sensitive routes have
+//! tighter buckets of their own.
+//!
+//! Agent telemetry is limited per device with the same [`RateCounter`],
+//! persisted in the storage of the device's `AgentConnection` object.
 
+use crate::middleware::AuthContext;
 use crate::models::{ApiError, ApiResult};
+use crate::storage;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use worker::*;
 
+/// Requests per minute when the plan's limit cannot be read
+const DEFAULT_REQUESTS_PER_MINUTE: u64 = 120;
+
 /// Rate limit configuration
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RateLimitConfig {
+    /// Counter shared by all routes with the same bucket
+    pub bucket: String,
     /// Maximum requests per window
     pub limit: u64,
     /// Window duration in seconds
@@ -17,92 +32,220 @@ pub struct RateLimitConfig {
 }
 
 impl RateLimitConfig {
-    pub fn new(limit: u64, window_secs: u64) -> Self {
-        Self { limit, window_secs }
+    pub fn new(bucket: &str, limit: u64, window_secs: u64) -> Self {
+        Self {
+            bucket: bucket.to_string(),
+            limit,
+            window_secs,
+        }
     }
 }
 
-/// Rate limit configurations for different endpoint patterns
-pub fn get_rate_limit(path: &str) -> RateLimitConfig {
-    if path.starts_with("/api/auth/") {
-        // Auth endpoints: 10/min
-        RateLimitConfig::new(10, 60)
-    } else if path.starts_with("/api/traffic/logs") {
+/// Rate limit for a route.
+///
+/// `plan_limit` is the plan's requests per minute, `None` when unlimited.
+/// Returns `None` when the route is not limited.
+pub fn get_rate_limit(path: &str, plan_limit: Option<u64>) -> Option<RateLimitConfig> {
+    let path = path.strip_prefix("/api").unwrap_or(path);
+    let capped = |limit: u64| plan_limit.map_or(limit, |plan| plan.min(limit));
+
+    if path.ends_with("/stream") {
+        // Streams hold a connection open: 5 new streams/min
+        Some(RateLimitConfig::new("stream", 5, 60))
+    } else if path.starts_with("/user/password") || path.starts_with("/user/2fa") {
+        // Credential changes: 10/min
+        Some(RateLimitConfig::new("auth", capped(10), 60))
+    } else if path.starts_with("/traffic/logs") {
         // Traffic logs: 60/min
-        RateLimitConfig::new(60, 60)
-    } else if path.contains("/stream") {
-        // WebSocket streams: 5 concurrent (handled separately)
-        RateLimitConfig::new(5, 60)
+        Some(RateLimitConfig::new("traffic_logs", capped(60), 60))
     } else {
-        // All other endpoints: 120/min
-        RateLimitConfig::new(120, 60)
+        plan_limit.map(|limit| RateLimitConfig::new("api", limit, 60))
     }
 }
 
-/// Check rate limit for a request
-/// Returns Ok if within limits, Err with rate limit error if exceeded
-pub async fn check_rate_limit(user_id: &str, path: &str, env: &Env) -> ApiResult<()> {
-    let config = get_rate_limit(path);
-    let kv = env
-        .kv("CACHE")
-        .map_err(|_| ApiError::internal("Failed to access rate limit store"))?;
+/// Outcome of counting one request against a limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: u64,
+    pub remaining: u64,
+    /// Unix timestamp when the current window ends
+    pub reset: i64,
+}
 
-    // Create a rate limit key based on user and path pattern
-    let pattern = get_path_pattern(path);
-    let window = chrono::Utc::now().timestamp() / config.window_secs as i64;
-    let key = format!("ratelimit:{}:{}:{}", user_id, pattern, window);
+impl RateLimitDecision {
+    pub fn headers(&self) -> RateLimitHeaders {
+        RateLimitHeaders {
+            limit: self.limit,
+            remaining: self.remaining,
+            reset: self.reset,
+        }
+    }
+}
 
-    // Get current count
-    let current: u64 = kv
-        .get(&key)
-        .text()
-        .await
-        .map_err(|_| ApiError::internal("Failed to check rate limit"))?
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(0);
+/// A fixed window counter
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct RateWindow {
+    start: i64,
+    count: u64,
+}
+
+/// Fixed window counters keyed by bucket.
+///
+/// Not synchronized: each counter lives in a single Durable Object, which
+/// handles one event at a time.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RateCounter {
+    windows: HashMap<String, RateWindow>,
+}
+
+impl RateCounter {
+    /// Count one hit against `config` at `now` (Unix seconds)
+    pub fn hit(&mut self, config: &RateLimitConfig, now: i64) -> RateLimitDecision {
+        let window_secs = config.window_secs.max(1) as i64;
+        let start = now - now.rem_euclid(window_secs);
 
-    if current >= config.limit {
-        let reset_at = (window + 1) * config.window_secs as i64;
-        return Err(ApiError::rate_limit(config.limit, reset_at));
+        // Drop windows that have ended so idle buckets do not accumulate
+        self.windows.retain(|_, w| w.start + window_secs > now);
+
+        let window = self
+            .windows
+            .entry(config.bucket.clone())
+            .or_insert(RateWindow { start, count: 0 });
+        if window.start != start {
+            *window = RateWindow { start, count: 0 };
+        }
+
+        let allowed = window.count < config.limit;
+        if allowed {
+            window.count += 1;
+        }
+
+        RateLimitDecision {
+            allowed,
+            limit: config.limit,
+            remaining: config.limit - window.count,
+            reset: start + window_secs,
+        }
     }
+}
 
-    // Increment counter
-    let new_count = (current + 1).to_string();
-    let ttl = config.window_secs + 1;
+/// Storage key of a user's request counters (`RateCounter`)
+const COUNTER_KEY: &str = "counter";
 
-    kv.put(&key, &new_count)
-        .map_err(|_| ApiError::internal("Failed to update rate limit"))?
-        .expiration_ttl(ttl)
-        .execute()
-        .await
-        .map_err(|_| ApiError::internal("Failed to update rate limit"))?;
+/// Durable Object holding the request counters of one user.
+///
+/// The counters live in storage so eviction does not reset them.
+#[durable_object]
+pub struct RateLimiter {
+    state: State,
+}
+
+impl DurableObject for RateLimiter {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
 
-    Ok(())
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        if req.path() != "/hit" {
+            return Response::error("Not found", 404);
+        }
+        let config: RateLimitConfig = req.json().await?;
+        let storage = self.state.storage();
+        let mut counter = storage
+            .get::<RateCounter>(COUNTER_KEY)
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        let decision = counter.hit(&config, chrono::Utc::now().timestamp());
+        storage.put(COUNTER_KEY, &counter).await?;
+        Response::from_json(&decision)
+    }
 }
 
-/// Get a pattern for grouping similar paths
-fn get_path_pattern(path: &str) -> String {
-    // Replace UUIDs and numeric IDs with placeholders
-    let parts: Vec<&str> = path.split('/').collect();
-    let normalized: Vec<String> = parts
-        .iter()
-        .map(|part| {
-            if is_uuid(part) || is_numeric(part) {
-                ":id".to_string()
-            } else {
-                (*part).to_string()
-            }
-        })
-        .collect();
-    normalized.join("/")
+/// Seconds a plan's request limit is reused before it is read again
+const PLAN_LIMIT_CACHE_SECS: i64 = 60;
+
+thread_local! {
+    /// Requests per minute by plan, with the time they were read.
+    ///
+    /// Kept for the life of the isolate so the limit is not read from
+    /// storage on every request.
+    static PLAN_LIMITS: RefCell<HashMap<String, (Option<u64>, i64)>> =
+        RefCell::new(HashMap::new());
 }
 
-fn is_uuid(s: &str) -> bool {
-    s.len() == 36 && s.chars().all(|c| c.is_ascii_hexdigit() || c == '-')
+/// The plan's requests per minute, `None` when unlimited
+async fn plan_requests_per_minute(plan: &str, env: &Env) -> Option<u64> {
+    let now = chrono::Utc::now().timestamp();
+    let cached = PLAN_LIMITS.with_borrow(|limits| limits.get(plan).copied());
+    if let Some((limit, read_at)) = cached
+        && now - read_at < PLAN_LIMIT_CACHE_SECS
+    {
+        return limit;
+    }
+
+    match storage::get_plan_limits(plan, env).await {
+        Ok(limits) => {
+            let limit = u64::try_from(limits.api_requests_per_minute).ok();
+            PLAN_LIMITS.with_borrow_mut(|limits| limits.insert(plan.to_string(), (limit, now)));
+            limit
+        }
+        // Not cached, so the next request tries again
+        Err(_) => Some(DEFAULT_REQUESTS_PER_MINUTE),
+    }
 }
 
-fn is_numeric(s: &str) -> bool {
-    !s.is_empty() && s.chars().all(|c| c.is_ascii_digit())
+/// Count a request against the user's limits.
+///
+/// Returns `None` when the route is not limited for the user's plan.
+pub async fn check_rate_limit(
+    auth: &AuthContext,
+    path: &str,
+    env: &Env,
+) -> ApiResult<Option<RateLimitDecision>> {
+    let plan_limit = plan_requests_per_minute(&auth.plan, env).await;
+    let Some(config) = get_rate_limit(path, plan_limit) else {
+        return Ok(None);
+    };
+
+    let namespace = env
+        .durable_object("RATE_LIMITER")
+        .map_err(|_| ApiError::internal("Failed to access rate limiter"))?;
+    let stub = namespace
+        .id_from_name(&auth.user_id)
+        .and_then(|id| id.get_stub())
+        .map_err(|_| ApiError::internal("Failed to get rate limiter stub"))?;
+
+    let body = serde_json::to_string(&config)?;
+    let request = Request::new_with_init(
+        "http://internal/hit",
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_body(Some(body.into())),
+    )?;
+
+    let mut response = stub
+        .fetch_with_request(request)
+        .await
+        .map_err(|_| ApiError::internal("Failed to check rate limit"))?;
+    let decision = response
+        .json()
+        .await
+        .map_err(|_| ApiError::internal("Invalid rate limiter response"))?;
+
+    Ok(Some(decision))
+}
+
+/// 429 response for a rejected request, with `Retry-After`
+pub fn rate_limited_response(decision: &RateLimitDecision) -> Result<Response> {
+    let response = ApiError::rate_limit(decision.limit, decision.reset).into_response()?;
+    let retry_after = (decision.reset - chrono::Utc::now().timestamp()).max(1);
+    let response = decision.headers().apply(response)?;
+    let headers = response.headers().clone();
+    headers.set("Retry-After", &retry_after.to_string())?;
+    Ok(response.with_headers(headers))
 }
 
 /// Rate limit headers to include in responses
@@ -121,3 +264,76 @@ impl RateLimitHeaders {
         Ok(response.with_headers(headers))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_rate_limit_buckets() {
+        let general = get_rate_limit("/firewall/rules", Some(300)).unwrap();
+        assert_eq!(general, RateLimitConfig::new("api", 300, 60));
+
+        // The /api prefix is accepted for older clients
+        let logs = get_rate_limit("/api/traffic/logs", Some(300)).unwrap();
+        assert_eq!(logs.bucket, "traffic_logs");
+        assert_eq!(logs.limit, 60);
+
+        let stream = get_rate_limit("/traffic/logs/stream", Some(300)).unwrap();
+        assert_eq!(stream.bucket, "stream");
+
+        assert_eq!(
+            get_rate_limit("/user/password", Some(300)).unwrap().limit,
+            10
+        );
+    }
+
+    #[test]
+    fn test_get_rate_limit_plan_caps_special_buckets() {
+        assert_eq!(get_rate_limit("/traffic/logs", Some(20)).unwrap().limit, 20);
+    }
+
+    #[test]
+    fn test_get_rate_limit_unlimited_plan() {
+        assert!(get_rate_limit("/firewall/rules", None).is_none());
+        // Tighter buckets still apply
+        assert_eq!(get_rate_limit("/user/2fa", None).unwrap().limit, 10);
+    }
+
+    #[test]
+    fn test_counter_rejects_over_limit() {
+        let config = RateLimitConfig::new("api", 2, 60);
+        let mut counter = RateCounter::default();
+
+        let first = counter.hit(&config, 120);
+        assert!(first.allowed);
+        assert_eq!(first.remaining, 1);
+        assert_eq!(first.reset, 180);
+
+        assert!(counter.hit(&config, 130).allowed);
+        let third = counter.hit(&config, 140);
+        assert!(!third.allowed);
+        assert_eq!(third.remaining, 0);
+    }
+
+    #[test]
+    fn test_counter_resets_each_window() {
+        let config = RateLimitConfig::new("api", 1, 60);
+        let mut counter = RateCounter::default();
+
+        assert!(counter.hit(&config, 59).allowed);
+        assert!(!counter.hit(&config, 59).allowed);
+        assert!(counter.hit(&config, 60).allowed);
+    }
+
+    #[test]
+    fn test_counter_buckets_are_independent() {
+        let mut counter = RateCounter::default();
+        let api = RateLimitConfig::new("api", 1, 60);
+        let stream = RateLimitConfig::new("stream", 1, 60);
+
+        assert!(counter.hit(&api, 0).allowed);
+        assert!(counter.hit(&stream, 0).allowed);
+        assert!(!counter.hit(&api, 0).allowed);
+    }
+}
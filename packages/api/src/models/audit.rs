@@ -0,0 +1,54 @@
+//! Audit log models for the NGFW.sh API
+//!
+//! Entries use `AuditLogEntry` from ngfw-protocol and are stored in the D1
+//! `audit_logs` table (migration 0012) by the audit middleware.
+
+#![allow(dead_code)]
+
+use super::fleet::AuditLogEntry;
+use super::logs::ExportFormat;
+use serde::{Deserialize, Serialize};
+
+/// Filters shared by `GET /audit` and `POST /audit/export`.
+///
+/// Dates are Unix seconds; `date_start` is inclusive, `date_end` exclusive.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditFilter {
+    #[serde(default)]
+    pub device_id: Option<String>,
+    #[serde(default)]
+    pub action: Option<String>,
+    #[serde(default)]
+    pub resource_type: Option<String>,
+    #[serde(default)]
+    pub resource_id: Option<String>,
+    #[serde(default)]
+    pub date_start: Option<i64>,
+    #[serde(default)]
+    pub date_end: Option<i64>,
+}
+
+/// Query parameters for `GET /audit`.
+#[derive(Debug, Clone, Default)]
+pub struct AuditListQuery {
+    pub filter: AuditFilter,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+/// Response for `GET /audit`.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditListResponse {
+    pub success: bool,
+    pub result: Vec<AuditLogEntry>,
+    /// Number of entries matching the filters
+    pub total: u64,
+}
+
+/// Request body for `POST /audit/export`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuditExportRequest {
+    pub format: ExportFormat,
+    #[serde(flatten)]
+    pub filter: AuditFilter,
+}
@@ -2,11 +2,13 @@
 
 #![allow(dead_code)]
 
+use super::lint::LintIssue;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 use worker::*;
 
 /// API Error codes matching the specification in AGENTS.md
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum ErrorCode {
     Unauthorized,
@@ -19,6 +21,10 @@ pub enum ErrorCode {
     InternalError,
     BadRequest,
     Conflict,
+    /// `If-Match` was missing or did not match the current config version
+    PreconditionFailed,
+    /// One or more request fields failed validation
+    ValidationFailed,
 }
 
 impl ErrorCode {
@@ -35,30 +41,39 @@ impl ErrorCode {
             ErrorCode::InternalError => 500,
             ErrorCode::BadRequest => 400,
             ErrorCode::Conflict => 409,
+            ErrorCode::PreconditionFailed => 412,
+            ErrorCode::ValidationFailed => 422,
         }
     }
 }
 
 /// Detailed error information
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
 pub struct ErrorDetails {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub validation_errors: Option<Vec<ValidationError>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub limit_info: Option<LimitInfo>,
+    /// Current version of the config section (set on `PRECONDITION_FAILED`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_version: Option<u64>,
+    /// Cross-section conflicts (set on `INVALID_CONFIG` from a config write)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lint_issues: Option<Vec<LintIssue>>,
 }
 
 /// Validation error for specific fields
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct ValidationError {
     pub field: String,
     pub message: String,
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Object)]
     pub value: Option<serde_json::Value>,
 }
 
 /// Rate/plan limit information
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct LimitInfo {
     pub limit: u64,
     pub current: u64,
@@ -67,20 +82,20 @@ pub struct LimitInfo {
 }
 
 /// API error response structure
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct ApiError {
     pub error: ApiErrorBody,
 }
 
 /// The body of an API error
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct ApiErrorBody {
     pub code: ErrorCode,
     pub message: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub field: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub details: Option<ErrorDetails>,
+    pub details: Option<Box<ErrorDetails>>,
 }
 
 impl ApiError {
@@ -104,7 +119,7 @@ impl ApiError {
 
     /// Create an error with details
     pub fn with_details(mut self, details: ErrorDetails) -> Self {
-        self.error.details = Some(details);
+        self.error.details = Some(Box::new(details));
         self
     }
 
@@ -147,12 +162,12 @@ impl ApiError {
 
     pub fn rate_limit(limit: u64, reset_at: i64) -> Self {
         Self::new(ErrorCode::RateLimit, "Too many requests").with_details(ErrorDetails {
-            validation_errors: None,
             limit_info: Some(LimitInfo {
                 limit,
                 current: limit,
                 reset_at: Some(reset_at),
             }),
+            ..Default::default()
         })
     }
 
@@ -165,9 +180,16 @@ impl ApiError {
     }
 
     pub fn validation_failed(errors: Vec<ValidationError>) -> Self {
-        Self::new(ErrorCode::InvalidConfig, "Validation failed").with_details(ErrorDetails {
+        Self::new(ErrorCode::ValidationFailed, "Validation failed").with_details(ErrorDetails {
             validation_errors: Some(errors),
-            limit_info: None,
+            ..Default::default()
+        })
+    }
+
+    pub fn precondition_failed(message: impl Into<String>, current_version: u64) -> Self {
+        Self::new(ErrorCode::PreconditionFailed, message).with_details(ErrorDetails {
+            current_version: Some(current_version),
+            ..Default::default()
         })
     }
 }
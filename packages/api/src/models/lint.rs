@@ -0,0 +1,64 @@
+//! Configuration lint models
+//!
+//! Findings of the cross-section consistency checks, returned by
+//! `GET /config/lint` and attached to config writes they affect.
+
+#![allow(dead_code)]
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// How serious a finding is
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LintSeverity {
+    /// The configuration is likely unintended but can be applied
+    Warning,
+    /// The configuration cannot work; writes involving it are rejected
+    Error,
+}
+
+/// A conflict between configuration sections
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct LintIssue {
+    pub severity: LintSeverity,
+    /// Stable identifier of the check, e.g. `dhcp_range_outside_subnet`
+    pub code: String,
+    pub message: String,
+    /// Sections involved, e.g. `["dhcp", "lan"]`
+    pub sections: Vec<String>,
+    /// Path of the offending field, e.g. `dhcp.range_start`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+}
+
+/// Response of `GET /config/lint`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LintReport {
+    pub errors: u32,
+    pub warnings: u32,
+    pub issues: Vec<LintIssue>,
+}
+
+impl LintReport {
+    pub fn new(issues: Vec<LintIssue>) -> Self {
+        let errors = issues
+            .iter()
+            .filter(|i| i.severity == LintSeverity::Error)
+            .count() as u32;
+        Self {
+            errors,
+            warnings: issues.len() as u32 - errors,
+            issues,
+        }
+    }
+
+    /// Issues that involve a section
+    pub fn for_section(&self, section: &str) -> Vec<LintIssue> {
+        self.issues
+            .iter()
+            .filter(|i| i.sections.iter().any(|s| s == section))
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,114 @@
+//! Metrics history models for the NGFW.sh API
+//!
+//! History is served from the D1 rollup tables (migration 0010) written by
+//! the AgentConnection Durable Object.
+
+#![allow(dead_code)]
+
+use serde::{Deserialize, Serialize};
+
+/// A metric that can be charted from the rollup tables
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricKind {
+    Cpu,
+    Memory,
+    Temperature,
+    RxRate,
+    TxRate,
+    Connections,
+    Tcp,
+    Udp,
+    DnsQueries,
+    DnsBlocked,
+    DnsCached,
+}
+
+impl MetricKind {
+    /// All metrics, in the order they are documented
+    pub const ALL: [MetricKind; 11] = [
+        MetricKind::Cpu,
+        MetricKind::Memory,
+        MetricKind::Temperature,
+        MetricKind::RxRate,
+        MetricKind::TxRate,
+        MetricKind::Connections,
+        MetricKind::Tcp,
+        MetricKind::Udp,
+        MetricKind::DnsQueries,
+        MetricKind::DnsBlocked,
+        MetricKind::DnsCached,
+    ];
+
+    /// Query-string name of the metric
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MetricKind::Cpu => "cpu",
+            MetricKind::Memory => "memory",
+            MetricKind::Temperature => "temperature",
+            MetricKind::RxRate => "rx_rate",
+            MetricKind::TxRate => "tx_rate",
+            MetricKind::Connections => "connections",
+            MetricKind::Tcp => "tcp",
+            MetricKind::Udp => "udp",
+            MetricKind::DnsQueries => "dns_queries",
+            MetricKind::DnsBlocked => "dns_blocked",
+            MetricKind::DnsCached => "dns_cached",
+        }
+    }
+
+    /// Parse a query-string metric name
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|m| m.as_str() == name)
+    }
+
+    /// Whether the metric is per interface and needs `interface=`
+    pub fn is_per_interface(self) -> bool {
+        matches!(self, MetricKind::RxRate | MetricKind::TxRate)
+    }
+}
+
+/// Query parameters for `GET /metrics/history`
+#[derive(Debug, Clone)]
+pub struct MetricsHistoryQuery {
+    pub device_id: String,
+    pub metric: MetricKind,
+    /// Interface name, required for `rx_rate` / `tx_rate`
+    pub interface: Option<String>,
+    /// Range start (Unix seconds, inclusive)
+    pub from: i64,
+    /// Range end (Unix seconds, exclusive)
+    pub to: i64,
+    /// Requested spacing between points in seconds, if any
+    pub step: Option<i64>,
+}
+
+/// One point of a metrics time series.
+///
+/// `value` is the average over the step (the latest value for cumulative
+/// DNS counters); `min`/`max` are included where the rollups track them.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TimeSeriesPoint {
+    pub timestamp: i64,
+    pub value: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+}
+
+/// Response for `GET /metrics/history`
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsHistoryResponse {
+    pub device_id: String,
+    pub metric: MetricKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    pub from: i64,
+    pub to: i64,
+    /// Rollup resolution the points were computed from (`1m`, `5m`, `1h`)
+    pub resolution: String,
+    /// Actual spacing between points in seconds
+    pub step: i64,
+    pub points: Vec<TimeSeriesPoint>,
+}
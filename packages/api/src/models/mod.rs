@@ -2,17 +2,24 @@
 //!
 //! These models represent the core data structures used throughout the API.
 
+pub mod audit;
 pub mod dashboard;
 pub mod error;
 pub mod fleet;
+pub mod lint;
 pub mod logs;
+pub mod metrics;
 pub mod network;
 pub mod onboarding;
+pub mod orgs;
 pub mod report;
 pub mod rpc;
 pub mod security;
 pub mod services;
 pub mod system;
+pub mod tokens;
 pub mod user;
+pub mod validation;
+pub mod webhooks;
 
 pub use error::*;
@@ -43,7 +43,8 @@ pub struct StaticWanConfig {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PppoeConfig {
     pub username: String,
-    #[serde(skip_serializing)]
+    /// Never serialized, so absent when read back from storage
+    #[serde(skip_serializing, default)]
     pub password: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub service_name: Option<String>,
@@ -197,7 +198,8 @@ pub enum WifiSecurityMode {
 pub struct RadiusConfig {
     pub server: String,
     pub port: u16,
-    #[serde(skip_serializing)]
+    /// Never serialized, so absent when read back from storage
+    #[serde(skip_serializing, default)]
     pub secret: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub backup_server: Option<String>,
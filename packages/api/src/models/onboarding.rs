@@ -52,20 +52,15 @@ pub enum SecurityPreset {
 }
 
 /// Subscription plan options
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum SubscriptionPlan {
+    #[default]
     Free,
     Pro,
     Enterprise,
 }
 
-impl Default for SubscriptionPlan {
-    fn default() -> Self {
-        Self::Free
-    }
-}
-
 /// Shipping address
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ShippingAddress {
@@ -0,0 +1,157 @@
+//! Organization models for the NGFW.sh API
+//!
+//! Organizations and their members are stored in D1 (migration 0014).
+//! Devices registered while an organization is active belong to it, and
+//! members reach them through their role.
+
+#![allow(dead_code)]
+
+use serde::{Deserialize, Serialize};
+
+/// A member's role within an organization, from least to most privileged
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Viewer,
+    Operator,
+    Admin,
+    Owner,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        [Role::Viewer, Role::Operator, Role::Admin, Role::Owner]
+            .into_iter()
+            .find(|r| r.as_str() == name)
+    }
+
+    /// Whether the role grants `permission`
+    pub fn allows(self, permission: Permission) -> bool {
+        self >= permission.min_role()
+    }
+}
+
+/// What a handler needs the caller to be allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Read configuration, status, metrics and logs
+    Read,
+    /// Run diagnostics and operational actions (renew DHCP, reboot, ...)
+    Operate,
+    /// Change device configuration
+    Configure,
+    /// Rename the organization and manage its members
+    ManageMembers,
+    /// Change the plan and payment methods
+    ManageBilling,
+    /// Delete the organization
+    DeleteOrganization,
+}
+
+impl Permission {
+    /// Least privileged role holding the permission
+    pub fn min_role(self) -> Role {
+        match self {
+            Permission::Read => Role::Viewer,
+            Permission::Operate => Role::Operator,
+            Permission::Configure | Permission::ManageMembers => Role::Admin,
+            Permission::ManageBilling | Permission::DeleteOrganization => Role::Owner,
+        }
+    }
+}
+
+/// An organization
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Organization {
+    pub id: String,
+    pub name: String,
+    /// User who created the organization
+    pub created_by: String,
+    pub created_at: i64,
+}
+
+/// An organization together with the caller's role in it
+#[derive(Debug, Clone, Serialize)]
+pub struct OrgMembership {
+    #[serde(flatten)]
+    pub organization: Organization,
+    pub role: Role,
+}
+
+/// A member of an organization
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrgMember {
+    pub user_id: String,
+    pub role: Role,
+    pub created_at: i64,
+}
+
+/// Body of `POST /orgs`
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateOrgRequest {
+    pub name: String,
+}
+
+/// Body of `PUT /orgs/:id`
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpdateOrgRequest {
+    pub name: String,
+}
+
+/// Body of `POST /orgs/:id/members`
+#[derive(Debug, Clone, Deserialize)]
+pub struct AddMemberRequest {
+    pub user_id: String,
+    pub role: Role,
+}
+
+/// Body of `PUT /orgs/:id/members/:user_id`
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpdateMemberRequest {
+    pub role: Role,
+}
+
+/// Body of `POST /orgs/:id/devices`
+#[derive(Debug, Clone, Deserialize)]
+pub struct TransferDeviceRequest {
+    pub device_id: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_role_permissions() {
+        assert!(Role::Viewer.allows(Permission::Read));
+        assert!(!Role::Viewer.allows(Permission::Operate));
+        assert!(Role::Operator.allows(Permission::Operate));
+        assert!(!Role::Operator.allows(Permission::Configure));
+        assert!(Role::Admin.allows(Permission::Configure));
+        assert!(Role::Admin.allows(Permission::ManageMembers));
+        assert!(!Role::Admin.allows(Permission::ManageBilling));
+        assert!(Role::Owner.allows(Permission::ManageBilling));
+        assert!(Role::Owner.allows(Permission::DeleteOrganization));
+    }
+
+    #[test]
+    fn test_role_roundtrip() {
+        for role in [Role::Viewer, Role::Operator, Role::Admin, Role::Owner] {
+            assert_eq!(Role::parse(role.as_str()), Some(role));
+            assert_eq!(
+                serde_json::to_value(role).unwrap(),
+                serde_json::json!(role.as_str())
+            );
+        }
+        assert_eq!(Role::parse("superuser"), None);
+    }
+}
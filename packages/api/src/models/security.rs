@@ -58,8 +58,31 @@ pub struct FirewallRule {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub schedule: Option<RuleSchedule>,
     pub log: bool,
+    /// Address group the source must also be in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_group: Option<u32>,
+    /// Address group the destination must also be in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination_group: Option<u32>,
+    /// Port group the destination port must also be in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port_group: Option<u32>,
+    /// ISO 3166-1 alpha-2 codes of the countries the source must be in,
+    /// looked up in the device's GeoIP dataset
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub source_countries: Vec<String>,
+    /// Countries the destination must be in
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub destination_countries: Vec<String>,
+    /// Packets matched on the router, from the agent's rule counters
     #[serde(skip_serializing_if = "Option::is_none")]
     pub hits: Option<u64>,
+    /// When the rule last matched a packet (Unix seconds)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_hit: Option<i64>,
+    /// Whether the rule's schedule window is open on the router right now
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule_active: Option<bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -85,6 +108,16 @@ pub struct FirewallRuleRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub schedule: Option<RuleSchedule>,
     pub log: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_group: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination_group: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port_group: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub source_countries: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub destination_countries: Vec<String>,
 }
 
 /// Rule order request
@@ -93,6 +126,164 @@ pub struct RuleOrderRequest {
     pub rule_ids: Vec<u32>,
 }
 
+/// Named list of addresses rules can match on, kept as an ipset on the
+/// router so members can change without rewriting rules
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddressGroup {
+    pub id: u32,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// IPv4 addresses, CIDR networks, `start-end` ranges, MAC addresses and
+    /// host names
+    pub members: Vec<String>,
+    /// Seconds between re-resolving host name members on the router
+    #[serde(default = "default_resolve_interval")]
+    pub resolve_interval: u32,
+}
+
+/// Create/update address group request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddressGroupRequest {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub members: Vec<String>,
+    #[serde(default = "default_resolve_interval")]
+    pub resolve_interval: u32,
+}
+
+fn default_resolve_interval() -> u32 {
+    300
+}
+
+/// Named list of ports rules can match on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortGroup {
+    pub id: u32,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Ports and `start-end` ranges
+    pub ports: Vec<String>,
+}
+
+/// Create/update port group request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortGroupRequest {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub ports: Vec<String>,
+}
+
+/// Summary of a device's uploaded GeoIP dataset
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GeoIpDataset {
+    /// Hash over every country's networks; changes with any of them
+    pub version: String,
+    pub updated_at: i64,
+    pub countries: Vec<GeoIpCountry>,
+}
+
+/// One country of a GeoIP dataset
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeoIpCountry {
+    /// ISO 3166-1 alpha-2 code
+    pub code: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// CIDR blocks after aggregation
+    pub networks: u32,
+    /// Hash of the country's aggregated networks
+    pub hash: String,
+}
+
+/// Networks of one country, as pushed to agents for firewall rules
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeoIpSet {
+    pub code: String,
+    pub hash: String,
+    pub networks: Vec<String>,
+}
+
+/// Result of uploading a GeoIP dataset
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeoIpUploadResult {
+    pub dataset: GeoIpDataset,
+    /// Countries added, removed or with different networks
+    pub changed: Vec<String>,
+    /// IPv6 networks, which are not supported and were skipped
+    pub skipped_ipv6: u32,
+}
+
+/// Format of an IP threat feed
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ThreatFeedFormat {
+    /// One address, CIDR network or `start-end` range per line, with `#`
+    /// comments
+    Cidr,
+    /// Spamhaus DROP/EDROP: `network ; SBL id`, with `;` comments
+    SpamhausDrop,
+    /// FireHOL netset: one address or CIDR network per line, with `#`
+    /// comments
+    Firehol,
+}
+
+/// IP threat feed subscription. Traffic from or to any of the feed's
+/// networks is dropped ahead of every firewall rule.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreatFeed {
+    pub id: u32,
+    pub name: String,
+    pub url: String,
+    pub format: ThreatFeedFormat,
+    pub enabled: bool,
+    /// Seconds between fetches of the feed
+    pub update_interval: u32,
+    /// CIDR blocks after deduplication and aggregation
+    #[serde(default)]
+    pub networks: u32,
+    /// Hash of the aggregated networks, set once the feed was fetched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_updated: Option<i64>,
+    /// Why the last fetch failed, if it did; the previous networks stay in
+    /// use
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// Create/update threat feed request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreatFeedRequest {
+    pub name: String,
+    pub url: String,
+    pub format: ThreatFeedFormat,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default = "default_feed_interval")]
+    pub update_interval: u32,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_feed_interval() -> u32 {
+    86400
+}
+
+/// Networks of one threat feed, as pushed to agents
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreatFeedSet {
+    pub id: u32,
+    pub hash: String,
+    pub networks: Vec<String>,
+}
+
 /// Zone configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ZoneConfig {
@@ -112,6 +303,105 @@ pub struct ZonePolicy {
     pub log: bool,
 }
 
+/// Packet trace request (`POST /firewall/trace`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceRequest {
+    pub protocol: Protocol,
+    pub source_ip: String,
+    #[serde(default)]
+    pub source_port: Option<u16>,
+    pub destination_ip: String,
+    #[serde(default)]
+    pub destination_port: Option<u16>,
+    /// Interface the packet arrives on, e.g. `eth0` or `br0`
+    pub interface: String,
+    /// Day of the week in router local time, 0 (Sunday) to 6; schedules
+    /// are assumed active when no day and time are given
+    #[serde(default)]
+    pub day: Option<u8>,
+    /// `HH:MM` in router local time
+    #[serde(default)]
+    pub time: Option<String>,
+    /// Also dump the live iptables counters from the router
+    #[serde(default)]
+    pub live: bool,
+}
+
+/// What decided a traced packet's fate
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceDecision {
+    Rule,
+    Policy,
+    Default,
+}
+
+/// A firewall rule a traced packet matched, in evaluation order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceRuleMatch {
+    /// Position of the rule in `/firewall/rules`
+    pub index: usize,
+    pub id: u32,
+    pub name: String,
+    pub action: RuleAction,
+    pub log: bool,
+}
+
+/// A NAT rewrite applied to a traced packet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NatTranslation {
+    /// NAT rule id; `None` for zone masquerading
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule_id: Option<u32>,
+    pub name: String,
+    pub nat_type: NatType,
+    /// `ip:port` before and after the rewrite
+    pub from: String,
+    pub to: String,
+}
+
+/// Result of a packet trace
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceResult {
+    pub ingress_zone: Option<Zone>,
+    pub egress_zone: Option<Zone>,
+    pub egress_interface: Option<String>,
+    /// Destination NAT before filtering, then source NAT after it
+    pub nat: Vec<NatTranslation>,
+    /// Matching rules up to and including the one that decided
+    pub rules: Vec<TraceRuleMatch>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub policy: Option<ZonePolicy>,
+    pub verdict: RuleAction,
+    pub decided_by: TraceDecision,
+    /// Assumptions made along the way
+    pub notes: Vec<String>,
+    /// Command id of the live counter dump, see `GET /firewall/trace/:id`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub live_command_id: Option<String>,
+}
+
+/// One `iptables-save -c` rule with its counters
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RuleCounter {
+    pub table: String,
+    pub chain: String,
+    pub packets: u64,
+    pub bytes: u64,
+    pub rule: String,
+}
+
+/// Live counter dump (`GET /firewall/trace/:id`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LiveTraceResult {
+    pub command_id: String,
+    /// `pending` until the router answers, then `completed` or `failed`
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub counters: Vec<RuleCounter>,
+}
+
 /// NAT rule type
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
@@ -140,10 +430,19 @@ pub struct NatRule {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub interface: Option<String>,
     pub log: bool,
+    /// Address group the source must also be in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_group: Option<u32>,
+    /// Address group the original destination must also be in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination_group: Option<u32>,
+    /// Port group the original destination port must also be in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port_group: Option<u32>,
 }
 
 /// UPnP lease
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct UpnpLease {
     pub id: String,
     pub protocol: Protocol,
@@ -154,6 +453,9 @@ pub struct UpnpLease {
     pub created_at: i64,
     pub expires_at: Option<i64>,
     pub client: String,
+    /// Port forward claiming the same external port
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conflicting_rule: Option<u32>,
 }
 
 /// DNS configuration
@@ -0,0 +1,119 @@
+//! API token models for the NGFW.sh API
+//!
+//! Tokens let scripts and CI call the API without a Clerk session. They are
+//! stored hashed in the D1 `api_tokens` table (migration 0015) and accepted
+//! as `Authorization: Bearer ngfw_pat_...` alongside JWTs. A token created
+//! while an organization is active is a service token acting in that
+//! organization with its creator's role.
+
+#![allow(dead_code)]
+
+use serde::{Deserialize, Serialize};
+
+/// Prefix identifying API tokens in the `Authorization` header
+pub const TOKEN_PREFIX: &str = "ngfw_pat_";
+
+/// Default lifetime of a token
+pub const DEFAULT_TOKEN_DAYS: u32 = 90;
+
+/// Longest lifetime a token can be created with
+pub const MAX_TOKEN_DAYS: u32 = 365;
+
+/// What a token may be used for
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TokenScope {
+    /// List devices, templates and device status
+    #[serde(rename = "fleet:read")]
+    FleetRead,
+    /// Register and remove devices, manage templates
+    #[serde(rename = "fleet:write")]
+    FleetWrite,
+    /// Read configuration, status, metrics and logs
+    #[serde(rename = "config:read")]
+    ConfigRead,
+    /// Change configuration
+    #[serde(rename = "config:write")]
+    ConfigWrite,
+    /// Run operational actions: reboot, DHCP renew, device commands, ...
+    #[serde(rename = "exec")]
+    Exec,
+}
+
+impl TokenScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TokenScope::FleetRead => "fleet:read",
+            TokenScope::FleetWrite => "fleet:write",
+            TokenScope::ConfigRead => "config:read",
+            TokenScope::ConfigWrite => "config:write",
+            TokenScope::Exec => "exec",
+        }
+    }
+}
+
+/// A token as listed to its owner; the secret is never returned again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    /// First characters of the token, to recognise it
+    pub token_prefix: String,
+    pub scopes: Vec<TokenScope>,
+    /// Organization a service token acts in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub org_id: Option<String>,
+    pub created_at: i64,
+    pub expires_at: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<i64>,
+}
+
+/// A token looked up for authentication, with what it acts as
+#[derive(Debug, Clone)]
+pub struct StoredToken {
+    pub user_id: String,
+    /// Creator's plan when the token was created
+    pub plan: String,
+    pub token: ApiToken,
+}
+
+/// Body of `POST /user/tokens`
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateTokenRequest {
+    pub name: String,
+    pub scopes: Vec<TokenScope>,
+    /// Lifetime in days (default 90, at most 365)
+    #[serde(default)]
+    pub expires_in_days: Option<u32>,
+}
+
+/// Response of `POST /user/tokens`, the only time the token is returned
+#[derive(Debug, Clone, Serialize)]
+pub struct CreateTokenResponse {
+    #[serde(flatten)]
+    pub api_token: ApiToken,
+    pub token: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scope_names_match_serde() {
+        for scope in [
+            TokenScope::FleetRead,
+            TokenScope::FleetWrite,
+            TokenScope::ConfigRead,
+            TokenScope::ConfigWrite,
+            TokenScope::Exec,
+        ] {
+            assert_eq!(
+                serde_json::to_value(scope).unwrap(),
+                serde_json::json!(scope.as_str())
+            );
+        }
+    }
+}
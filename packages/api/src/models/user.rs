@@ -3,6 +3,7 @@
 #![allow(dead_code)]
 
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 /// User profile
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -114,6 +115,8 @@ pub struct PlanLimits {
     pub ids_custom_rules: i32,
     pub webhook_endpoints: i32,
     pub audit_log_days: i32,
+    /// Authenticated API requests per minute
+    pub api_requests_per_minute: i32,
     pub has_ids: bool,
     pub has_ips: bool,
     pub has_qos: bool,
@@ -124,6 +127,43 @@ pub struct PlanLimits {
     pub has_reports: bool,
 }
 
+impl PlanLimits {
+    /// Build limits from `plan_limits` rows (`limit_key` -> `limit_value`).
+    ///
+    /// Numeric keys missing from the table are unlimited (-1), matching
+    /// the limits removed in migration 0003; missing features are off.
+    pub fn from_values(values: &HashMap<String, i64>) -> Self {
+        let limit = |key: &str| values.get(key).map(|v| *v as i32).unwrap_or(-1);
+        let feature = |key: &str| values.get(key).is_some_and(|v| *v != 0);
+
+        Self {
+            routers: limit("routers"),
+            users: limit("users"),
+            devices: limit("devices"),
+            firewall_rules: limit("firewall_rules"),
+            backups: limit("backups"),
+            dns_blocklists: limit("dns_blocklists"),
+            dns_log_hours: limit("dns_log_hours"),
+            traffic_log_hours: limit("traffic_log_hours"),
+            vlans: limit("vlans"),
+            vpn_peers: limit("vpn_peers"),
+            vpn_client_profiles: limit("vpn_client_profiles"),
+            ids_custom_rules: limit("ids_custom_rules"),
+            webhook_endpoints: limit("webhook_endpoints"),
+            audit_log_days: limit("audit_log_days"),
+            api_requests_per_minute: limit("api_requests_per_minute"),
+            has_ids: feature("has_ids"),
+            has_ips: feature("has_ips"),
+            has_qos: feature("has_qos"),
+            has_ddns: feature("has_ddns"),
+            has_traffic_stream: feature("has_traffic_stream"),
+            has_fleet: feature("has_fleet"),
+            has_api: feature("has_api"),
+            has_reports: feature("has_reports"),
+        }
+    }
+}
+
 /// Current subscription
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Subscription {
@@ -0,0 +1,1067 @@
+//! Field-level validation of configuration request bodies
+//!
+//! Handlers deserialize a body and then call [`Validate::validate`] on it
+//! before storing anything. Every problem found is collected with the path
+//! of its field (`static_config.dns[1]`, `schedule.start`, ...) and returned
+//! together in one `VALIDATION_FAILED` (422) response, so a client can fix
+//! a form in one round trip.
+
+use super::network::*;
+use super::security::*;
+use super::services::*;
+use super::{ApiError, ApiResult, ValidationError};
+use std::net::{IpAddr, Ipv4Addr};
+
+/// Outcome of checking a single value; the error is the message
+type Check = Result<(), String>;
+
+/// Request bodies that can be validated field by field
+pub trait Validate {
+    /// Record every problem with `self` on the validator
+    fn check(&self, v: &mut Validator);
+
+    /// Validate the whole body, failing with all errors at once
+    fn validate(&self) -> ApiResult<()> {
+        let mut v = Validator::default();
+        self.check(&mut v);
+        v.finish()
+    }
+}
+
+/// Collects validation errors under a field path
+#[derive(Debug, Default)]
+pub struct Validator {
+    prefix: Vec<String>,
+    errors: Vec<ValidationError>,
+}
+
+impl Validator {
+    fn path(&self, field: &str) -> String {
+        let mut path = self.prefix.join(".");
+        if !path.is_empty() && !field.starts_with('[') {
+            path.push('.');
+        }
+        path.push_str(field);
+        path
+    }
+
+    /// Record an error on a field
+    pub fn error(&mut self, field: &str, message: impl Into<String>) {
+        let field = self.path(field);
+        self.errors.push(ValidationError {
+            field,
+            message: message.into(),
+            value: None,
+        });
+    }
+
+    /// Record an error on a field if a check failed, with the offending value
+    pub fn check(&mut self, field: &str, value: &str, check: fn(&str) -> Check) {
+        if let Err(message) = check(value) {
+            let field = self.path(field);
+            self.errors.push(ValidationError {
+                field,
+                message,
+                value: Some(value.into()),
+            });
+        }
+    }
+
+    /// Check every element of a list, indexing the field path
+    pub fn check_each(&mut self, field: &str, values: &[String], check: fn(&str) -> Check) {
+        for (i, value) in values.iter().enumerate() {
+            self.check(&format!("{}[{}]", field, i), value, check);
+        }
+    }
+
+    /// Record an error if a string is blank
+    pub fn required(&mut self, field: &str, value: &str) {
+        if value.trim().is_empty() {
+            self.error(field, "must not be empty");
+        }
+    }
+
+    /// Record an error if a number is outside an inclusive range
+    pub fn range<T: PartialOrd + std::fmt::Display>(
+        &mut self,
+        field: &str,
+        value: T,
+        min: T,
+        max: T,
+    ) {
+        if value < min || value > max {
+            self.error(field, format!("must be between {} and {}", min, max));
+        }
+    }
+
+    /// Validate a nested value under a field
+    pub fn nested(&mut self, field: &str, value: &impl Validate) {
+        self.prefix.push(field.to_string());
+        value.check(self);
+        self.prefix.pop();
+    }
+
+    /// Fail with every recorded error, if any
+    pub fn finish(self) -> ApiResult<()> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::validation_failed(self.errors))
+        }
+    }
+}
+
+// ========== Value Checks ==========
+
+/// Valid VLAN ids (0 and 4095 are reserved)
+pub const VLAN_IDS: std::ops::RangeInclusive<u16> = 1..=4094;
+
+/// Largest DNS TTL allowed by RFC 2181
+pub const MAX_DNS_TTL: u32 = 2_147_483_647;
+
+pub fn ipv4(value: &str) -> Check {
+    value
+        .parse::<Ipv4Addr>()
+        .map(|_| ())
+        .map_err(|_| "must be an IPv4 address".to_string())
+}
+
+pub fn ip(value: &str) -> Check {
+    value
+        .parse::<IpAddr>()
+        .map(|_| ())
+        .map_err(|_| "must be an IP address".to_string())
+}
+
+/// A dotted IPv4 netmask with contiguous bits
+pub fn netmask(value: &str) -> Check {
+    let mask = u32::from(
+        value
+            .parse::<Ipv4Addr>()
+            .map_err(|_| "must be a netmask such as 255.255.255.0".to_string())?,
+    );
+    if mask.leading_ones() + mask.trailing_zeros() != 32 {
+        return Err("must be a contiguous netmask".to_string());
+    }
+    Ok(())
+}
+
+/// A network in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/64`
+pub fn cidr(value: &str) -> Check {
+    let (addr, prefix) = value
+        .split_once('/')
+        .ok_or_else(|| "must be a network in CIDR notation".to_string())?;
+    let max = match addr.parse::<IpAddr>() {
+        Ok(IpAddr::V4(_)) => 32,
+        Ok(IpAddr::V6(_)) => 128,
+        Err(_) => return Err("must be a network in CIDR notation".to_string()),
+    };
+    match prefix.parse::<u8>() {
+        Ok(len) if len <= max => Ok(()),
+        _ => Err(format!("prefix length must be between 0 and {}", max)),
+    }
+}
+
+/// An IP address or a CIDR network
+pub fn ip_or_cidr(value: &str) -> Check {
+    if value.contains('/') {
+        cidr(value)
+    } else {
+        ip(value).map_err(|_| "must be an IP address or CIDR network".to_string())
+    }
+}
+
+/// An address match for rules: `any`, or a comma-separated list of
+/// addresses, CIDR networks and `start-end` ranges, optionally negated
+/// with a leading `!`
+pub fn address_spec(value: &str) -> Check {
+    let value = value.trim();
+    if is_any(value) {
+        return Ok(());
+    }
+    let list = value.strip_prefix('!').unwrap_or(value);
+    for item in list.split(',').map(str::trim) {
+        match item.split_once('-') {
+            Some((start, end)) => ip_range(start.trim(), end.trim())?,
+            None => ip_or_cidr(item)
+                .map_err(|_| format!("'{}' is not an address, network or range", item))?,
+        }
+    }
+    Ok(())
+}
+
+fn ip_range(start: &str, end: &str) -> Check {
+    match (start.parse::<IpAddr>(), end.parse::<IpAddr>()) {
+        (Ok(IpAddr::V4(a)), Ok(IpAddr::V4(b))) if a <= b => Ok(()),
+        (Ok(IpAddr::V6(a)), Ok(IpAddr::V6(b))) if a <= b => Ok(()),
+        (Ok(_), Ok(_)) => Err(format!(
+            "range {}-{} must go from low to high within one address family",
+            start, end
+        )),
+        _ => Err(format!("'{}-{}' is not an address range", start, end)),
+    }
+}
+
+/// A port match: `any`, or a comma-separated list of ports and
+/// `start-end` (or `start:end`) ranges
+pub fn port_spec(value: &str) -> Check {
+    let value = value.trim();
+    if is_any(value) {
+        return Ok(());
+    }
+    for item in value.split(',').map(str::trim) {
+        match item.split_once(['-', ':']) {
+            Some((start, end)) => match (port(start.trim()), port(end.trim())) {
+                (Ok(a), Ok(b)) if a <= b => {}
+                (Ok(_), Ok(_)) => {
+                    return Err(format!("port range {} must go from low to high", item));
+                }
+                _ => return Err(format!("'{}' is not a port range", item)),
+            },
+            None => {
+                port(item)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn port(value: &str) -> Result<u16, String> {
+    match value.parse::<u16>() {
+        Ok(port) if port > 0 => Ok(port),
+        _ => Err(format!("'{}' is not a port between 1 and 65535", value)),
+    }
+}
+
+/// An address group member: an IPv4 address, CIDR network or `start-end`
+/// range, a MAC address, or a host name the router resolves
+pub fn group_member(value: &str) -> Check {
+    let value = value.trim();
+    let looks_numeric = value
+        .chars()
+        .all(|c| c.is_ascii_digit() || matches!(c, '.' | '/' | '-'));
+    if looks_numeric {
+        let address = match value.split_once('-') {
+            Some((start, end)) => ip_range(start, end),
+            None => ip_or_cidr(value),
+        };
+        return match address {
+            Ok(()) if !value.contains(':') => Ok(()),
+            _ => Err(format!(
+                "'{}' is not an IPv4 address, network or range",
+                value
+            )),
+        };
+    }
+    mac(value)
+        .or_else(|_| hostname(value))
+        .map_err(|_| "must be an IPv4 address, network, range, MAC address or host name".into())
+}
+
+/// A single port or `start-end` range, as held by a port group
+pub fn group_port(value: &str) -> Check {
+    if is_any(value) || value.contains(',') {
+        return Err("must be a single port or range".to_string());
+    }
+    port_spec(value)
+}
+
+/// An ISO 3166-1 alpha-2 country code, in upper case
+pub fn country_code(value: &str) -> Check {
+    if value.len() == 2 && value.chars().all(|c| c.is_ascii_uppercase()) {
+        Ok(())
+    } else {
+        Err("must be a two-letter upper case country code such as DE".to_string())
+    }
+}
+
+/// Whether an address or port match is unrestricted
+pub fn is_any(value: &str) -> bool {
+    matches!(value.trim(), "" | "*" | "any")
+}
+
+/// A MAC address as six hex octets separated by `:` or `-`
+pub fn mac(value: &str) -> Check {
+    let octets: Vec<&str> = value.split([':', '-']).collect();
+    let valid = octets.len() == 6
+        && octets
+            .iter()
+            .all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit()));
+    if valid {
+        Ok(())
+    } else {
+        Err("must be a MAC address such as aa:bb:cc:dd:ee:ff".to_string())
+    }
+}
+
+/// An SSID of 1 to 32 bytes
+pub fn ssid(value: &str) -> Check {
+    match value.len() {
+        1..=32 => Ok(()),
+        _ => Err("must be between 1 and 32 bytes".to_string()),
+    }
+}
+
+/// A WPA pre-shared key: 8 to 63 printable ASCII characters or 64 hex digits
+pub fn psk(value: &str) -> Check {
+    if value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(());
+    }
+    if !(8..=63).contains(&value.len()) {
+        return Err("must be between 8 and 63 characters".to_string());
+    }
+    if !value.chars().all(|c| c.is_ascii_graphic() || c == ' ') {
+        return Err("must contain only printable ASCII characters".to_string());
+    }
+    let first = value.chars().next();
+    if value.chars().all(|c| Some(c) == first) {
+        return Err("must not repeat a single character".to_string());
+    }
+    Ok(())
+}
+
+/// A DNS host name such as `router.example.com`
+pub fn hostname(value: &str) -> Check {
+    let name = value.strip_suffix('.').unwrap_or(value);
+    let valid = !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err("must be a valid host name".to_string())
+    }
+}
+
+/// An upstream DNS server: an address, optionally followed by `#port`
+pub fn dns_server(value: &str) -> Check {
+    let (addr, port_str) = value.split_once('#').unwrap_or((value, "53"));
+    ip(addr)?;
+    port(port_str).map(|_| ())
+}
+
+pub fn http_url(value: &str) -> Check {
+    let rest = value
+        .strip_prefix("https://")
+        .or_else(|| value.strip_prefix("http://"))
+        .ok_or_else(|| "must be an http(s) URL".to_string())?;
+    let host = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = host.rsplit_once(':').map_or(host, |(h, _)| h);
+    hostname(host)
+        .or_else(|_| ip(host.trim_matches(['[', ']'])))
+        .map_err(|_| "must be an http(s) URL with a host".to_string())
+}
+
+/// A `HH:MM` time of day
+pub fn time_of_day(value: &str) -> Check {
+    let valid = value.len() == 5
+        && matches!(
+            value.split_once(':'),
+            Some((h, m)) if h.parse::<u8>().is_ok_and(|h| h < 24)
+                && m.parse::<u8>().is_ok_and(|m| m < 60)
+        );
+    if valid {
+        Ok(())
+    } else {
+        Err("must be a time of day as HH:MM".to_string())
+    }
+}
+
+/// A WireGuard public key: 32 bytes in base64
+pub fn wireguard_key(value: &str) -> Check {
+    let valid = value.len() == 44
+        && value.ends_with('=')
+        && value[..43]
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/');
+    if valid {
+        Ok(())
+    } else {
+        Err("must be a base64-encoded WireGuard key".to_string())
+    }
+}
+
+fn vlan(v: &mut Validator, field: &str, id: u16) {
+    v.range(field, id, *VLAN_IDS.start(), *VLAN_IDS.end());
+}
+
+// ========== Network ==========
+
+impl Validate for WanConfig {
+    fn check(&self, v: &mut Validator) {
+        v.required("interface", &self.interface);
+        match (&self.wan_type, &self.static_config, &self.pppoe_config) {
+            (WanType::Static, None, _) => v.error("static_config", "is required for static WAN"),
+            (WanType::Pppoe, _, None) => v.error("pppoe_config", "is required for PPPoE WAN"),
+            _ => {}
+        }
+        if let Some(config) = &self.static_config {
+            v.nested("static_config", config);
+        }
+        if let Some(config) = &self.pppoe_config {
+            v.nested("pppoe_config", config);
+        }
+        if let Some(id) = self.vlan_id {
+            vlan(v, "vlan_id", id);
+        }
+        v.range("mtu", self.mtu, 576, 9000);
+        if let Some(mac_clone) = &self.mac_clone {
+            v.check("mac_clone", mac_clone, mac);
+        }
+        if let Some(config) = &self.ipv6_config {
+            match (&config.mode, &config.static_ip) {
+                (Ipv6Mode::Static, None) => {
+                    v.error("ipv6_config.static_ip", "is required for static IPv6")
+                }
+                (_, Some(static_ip)) => v.check("ipv6_config.static_ip", static_ip, ip_or_cidr),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Validate for StaticWanConfig {
+    fn check(&self, v: &mut Validator) {
+        v.check("ip", &self.ip, ipv4);
+        v.check("subnet_mask", &self.subnet_mask, netmask);
+        v.check("gateway", &self.gateway, ipv4);
+        v.check_each("dns", &self.dns, ip);
+    }
+}
+
+impl Validate for PppoeConfig {
+    fn check(&self, v: &mut Validator) {
+        v.required("username", &self.username);
+        v.required("password", &self.password);
+    }
+}
+
+impl Validate for LanConfig {
+    fn check(&self, v: &mut Validator) {
+        v.required("interface", &self.interface);
+        v.check("ip", &self.ip, ipv4);
+        v.check("subnet_mask", &self.subnet_mask, netmask);
+        if let Some(prefix) = &self.ipv6_prefix {
+            v.check("ipv6_prefix", prefix, cidr);
+        }
+    }
+}
+
+impl Validate for VlanConfig {
+    fn check(&self, v: &mut Validator) {
+        vlan(v, "vlan_id", self.vlan_id);
+        v.required("name", &self.name);
+        v.required("interface", &self.interface);
+        v.check("ip", &self.ip, ipv4);
+        v.check("subnet_mask", &self.subnet_mask, netmask);
+    }
+}
+
+impl Validate for WifiRadio {
+    fn check(&self, v: &mut Validator) {
+        v.range("power", self.power, 1, 100);
+        if self.country_code.len() != 2
+            || !self.country_code.chars().all(|c| c.is_ascii_uppercase())
+        {
+            v.error("country_code", "must be a two-letter ISO 3166 country code");
+        }
+    }
+}
+
+impl Validate for WifiNetwork {
+    fn check(&self, v: &mut Validator) {
+        v.check("ssid", &self.ssid, ssid);
+        v.required("radio", &self.radio);
+        if let Some(id) = self.vlan {
+            vlan(v, "vlan", id);
+        }
+        v.nested("security", &self.security);
+        if self.security.password.as_deref() == Some(self.ssid.as_str()) {
+            v.error("security.password", "must not be the same as the SSID");
+        }
+    }
+}
+
+impl Validate for WifiSecurity {
+    fn check(&self, v: &mut Validator) {
+        match self.mode {
+            WifiSecurityMode::Open => {}
+            WifiSecurityMode::Wpa2 | WifiSecurityMode::Wpa3 | WifiSecurityMode::Wpa2Wpa3 => {
+                match &self.password {
+                    Some(password) => v.check("password", password, psk),
+                    None => v.error("password", "is required for WPA personal modes"),
+                }
+            }
+            WifiSecurityMode::Wpa2Enterprise | WifiSecurityMode::Wpa3Enterprise => {
+                match &self.radius {
+                    Some(radius) => v.nested("radius", radius),
+                    None => v.error("radius", "is required for WPA enterprise modes"),
+                }
+            }
+        }
+    }
+}
+
+impl Validate for RadiusConfig {
+    fn check(&self, v: &mut Validator) {
+        v.check("server", &self.server, ip);
+        if self.port == 0 {
+            v.error("port", "must be between 1 and 65535");
+        }
+        v.required("secret", &self.secret);
+        if let Some(backup) = &self.backup_server {
+            v.check("backup_server", backup, ip);
+        }
+    }
+}
+
+impl Validate for DhcpConfig {
+    fn check(&self, v: &mut Validator) {
+        v.required("interface", &self.interface);
+        v.check("range_start", &self.range_start, ipv4);
+        v.check("range_end", &self.range_end, ipv4);
+        if let (Ok(start), Ok(end)) = (
+            self.range_start.parse::<Ipv4Addr>(),
+            self.range_end.parse::<Ipv4Addr>(),
+        ) && start > end
+        {
+            v.error("range_end", "must not be before range_start");
+        }
+        // dnsmasq refuses leases shorter than two minutes
+        if self.lease_time < 120 {
+            v.error("lease_time", "must be at least 120 seconds");
+        }
+        v.check("gateway", &self.gateway, ipv4);
+        v.check_each("dns", &self.dns, ip);
+        if let Some(domain) = &self.domain {
+            v.check("domain", domain, hostname);
+        }
+        if let Some(pxe) = &self.pxe {
+            v.check("pxe.server", &pxe.server, ip);
+            v.required("pxe.filename", &pxe.filename);
+        }
+    }
+}
+
+impl Validate for DhcpReservation {
+    fn check(&self, v: &mut Validator) {
+        v.check("mac", &self.mac, mac);
+        v.check("ip", &self.ip, ipv4);
+        if let Some(name) = &self.hostname {
+            v.check("hostname", name, hostname);
+        }
+    }
+}
+
+impl Validate for RouteRequest {
+    fn check(&self, v: &mut Validator) {
+        v.check("destination", &self.destination, ip_or_cidr);
+        v.check("gateway", &self.gateway, ip);
+        v.required("interface", &self.interface);
+    }
+}
+
+// ========== Security ==========
+
+impl Validate for FirewallRuleRequest {
+    fn check(&self, v: &mut Validator) {
+        v.required("name", &self.name);
+        v.check("source", &self.source, address_spec);
+        v.check("destination", &self.destination, address_spec);
+        v.check("port", &self.port, port_spec);
+        if !is_any(&self.port) && !matches!(self.protocol, Protocol::Tcp | Protocol::Udp) {
+            v.error("port", "ports can only be matched for tcp or udp");
+        }
+        if self.port_group.is_some() && !matches!(self.protocol, Protocol::Tcp | Protocol::Udp) {
+            v.error("port_group", "ports can only be matched for tcp or udp");
+        }
+        v.check_each("source_countries", &self.source_countries, country_code);
+        v.check_each("destination_countries", &self.destination_countries, country_code);
+        if let Some(schedule) = &self.schedule {
+            v.nested("schedule", schedule);
+        }
+    }
+}
+
+impl Validate for AddressGroupRequest {
+    fn check(&self, v: &mut Validator) {
+        v.required("name", &self.name);
+        v.check_each("members", &self.members, group_member);
+        if self.resolve_interval < 60 {
+            v.error("resolve_interval", "must be at least 60 seconds");
+        }
+    }
+}
+
+impl Validate for PortGroupRequest {
+    fn check(&self, v: &mut Validator) {
+        v.required("name", &self.name);
+        v.check_each("ports", &self.ports, group_port);
+    }
+}
+
+impl Validate for RuleSchedule {
+    fn check(&self, v: &mut Validator) {
+        v.check("start", &self.start, time_of_day);
+        v.check("end", &self.end, time_of_day);
+        if self.days.is_empty() {
+            v.error("days", "must not be empty");
+        }
+        for (i, day) in self.days.iter().enumerate() {
+            if *day > 6 {
+                v.error(
+                    &format!("days[{}]", i),
+                    "must be 0 (Sunday) to 6 (Saturday)",
+                );
+            }
+        }
+    }
+}
+
+impl Validate for TraceRequest {
+    fn check(&self, v: &mut Validator) {
+        if self.protocol == Protocol::All {
+            v.error("protocol", "must be a single protocol");
+        }
+        v.check("source_ip", &self.source_ip, ip);
+        v.check("destination_ip", &self.destination_ip, ip);
+        v.required("interface", &self.interface);
+        let ports = [
+            ("source_port", self.source_port),
+            ("destination_port", self.destination_port),
+        ];
+        for (field, value) in ports {
+            match value {
+                Some(_) if !matches!(self.protocol, Protocol::Tcp | Protocol::Udp) => {
+                    v.error(field, "ports can only be given for tcp or udp");
+                }
+                Some(0) => v.error(field, "must be between 1 and 65535"),
+                _ => {}
+            }
+        }
+        match (self.day, &self.time) {
+            (Some(day), Some(time)) => {
+                v.range("day", day, 0, 6);
+                v.check("time", time, time_of_day);
+            }
+            (Some(_), None) => v.error("time", "must be given together with day"),
+            (None, Some(_)) => v.error("day", "must be given together with time"),
+            (None, None) => {}
+        }
+    }
+}
+
+impl Validate for ZoneConfig {
+    fn check(&self, v: &mut Validator) {
+        v.required("id", &self.id);
+        v.required("name", &self.name);
+    }
+}
+
+impl Validate for NatRule {
+    fn check(&self, v: &mut Validator) {
+        v.required("name", &self.name);
+        v.check("source", &self.source, address_spec);
+        v.check("destination", &self.destination, address_spec);
+        let ports = [
+            ("source_port", &self.source_port),
+            ("destination_port", &self.destination_port),
+            ("translate_port", &self.translate_port),
+        ];
+        for (field, value) in ports {
+            if let Some(value) = value {
+                v.check(field, value, port_spec);
+                if !is_any(value) && !matches!(self.protocol, Protocol::Tcp | Protocol::Udp) {
+                    v.error(field, "ports can only be matched for tcp or udp");
+                }
+            }
+        }
+        if self.port_group.is_some() && !matches!(self.protocol, Protocol::Tcp | Protocol::Udp) {
+            v.error("port_group", "ports can only be matched for tcp or udp");
+        }
+        if self.nat_type == NatType::Masquerade {
+            if !self.translate_to.is_empty() {
+                v.check("translate_to", &self.translate_to, ip);
+            }
+        } else {
+            v.check("translate_to", &self.translate_to, ip);
+        }
+    }
+}
+
+impl Validate for DnsConfig {
+    fn check(&self, v: &mut Validator) {
+        v.check("listen_address", &self.listen_address, ip);
+        v.check_each("upstream_dns", &self.upstream_dns, dns_server);
+        v.range("max_ttl", self.max_ttl, 0, MAX_DNS_TTL);
+        if self.min_ttl > self.max_ttl {
+            v.error("min_ttl", "must not be greater than max_ttl");
+        }
+    }
+}
+
+impl Validate for DnsBlocklist {
+    fn check(&self, v: &mut Validator) {
+        v.required("name", &self.name);
+        v.check("url", &self.url, http_url);
+        if self.update_interval == 0 {
+            v.error("update_interval", "must be greater than 0");
+        }
+    }
+}
+
+impl Validate for ThreatFeedRequest {
+    fn check(&self, v: &mut Validator) {
+        v.required("name", &self.name);
+        v.check("url", &self.url, http_url);
+        if self.update_interval < 3600 {
+            v.error("update_interval", "must be at least 3600 seconds");
+        }
+    }
+}
+
+impl Validate for DnsAllowlistEntry {
+    fn check(&self, v: &mut Validator) {
+        // Allow wildcard entries such as *.example.com
+        let domain = self.domain.strip_prefix("*.").unwrap_or(&self.domain);
+        if let Err(message) = hostname(domain) {
+            v.error("domain", message);
+        }
+    }
+}
+
+impl Validate for IdsConfig {
+    fn check(&self, v: &mut Validator) {
+        if self.enabled && self.interfaces.is_empty() {
+            v.error("interfaces", "must not be empty when IDS is enabled");
+        }
+        if self.update_interval == 0 {
+            v.error("update_interval", "must be greater than 0");
+        }
+    }
+}
+
+// ========== Services ==========
+
+impl Validate for VpnServerConfig {
+    fn check(&self, v: &mut Validator) {
+        if self.listen_port == 0 {
+            v.error("listen_port", "must be between 1 and 65535");
+        }
+        v.required("interface", &self.interface);
+        v.check("address", &self.address, cidr);
+        v.check_each("dns", &self.dns, ip);
+        v.check_each("allowed_ips", &self.allowed_ips, cidr);
+        v.range("mtu", self.mtu, 1280, 1500);
+        v.range("persistent_keepalive", self.persistent_keepalive, 0, 65535);
+    }
+}
+
+impl Validate for VpnPeerRequest {
+    fn check(&self, v: &mut Validator) {
+        v.required("name", &self.name);
+        if let Some(key) = &self.public_key {
+            v.check("public_key", key, wireguard_key);
+        }
+        v.check_each("allowed_ips", &self.allowed_ips, cidr);
+        v.range("persistent_keepalive", self.persistent_keepalive, 0, 65535);
+    }
+}
+
+impl Validate for VpnClientProfile {
+    fn check(&self, v: &mut Validator) {
+        v.required("name", &self.name);
+        if let Some(split) = &self.split_tunnel {
+            v.check_each("split_tunnel.ips", &split.ips, ip_or_cidr);
+            v.check_each("split_tunnel.domains", &split.domains, hostname);
+        }
+    }
+}
+
+impl Validate for QosConfig {
+    fn check(&self, v: &mut Validator) {
+        if self.enabled {
+            if self.upload_mbps == 0 {
+                v.error("upload_mbps", "must be greater than 0 when QoS is enabled");
+            }
+            if self.download_mbps == 0 {
+                v.error(
+                    "download_mbps",
+                    "must be greater than 0 when QoS is enabled",
+                );
+            }
+        }
+    }
+}
+
+impl Validate for TrafficClass {
+    fn check(&self, v: &mut Validator) {
+        v.required("name", &self.name);
+        v.range("priority", self.priority, 0, 7);
+        if let (Some(guaranteed), Some(max)) = (self.guaranteed_mbps, self.max_mbps)
+            && guaranteed > max
+        {
+            v.error("guaranteed_mbps", "must not be greater than max_mbps");
+        }
+        for (i, dscp) in self.dscp_markings.iter().enumerate() {
+            if *dscp > 63 {
+                v.error(&format!("dscp_markings[{}]", i), "must be between 0 and 63");
+            }
+        }
+        v.check_each("ports", &self.ports, port_spec);
+    }
+}
+
+impl Validate for DeviceLimit {
+    fn check(&self, v: &mut Validator) {
+        v.check("mac", &self.mac, mac);
+        if let Some(priority) = self.priority {
+            v.range("priority", priority, 0, 7);
+        }
+    }
+}
+
+impl Validate for DdnsConfig {
+    fn check(&self, v: &mut Validator) {
+        v.check("hostname", &self.hostname, hostname);
+        if self.update_interval == 0 {
+            v.error("update_interval", "must be greater than 0");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(result: ApiResult<()>) -> Vec<String> {
+        result
+            .unwrap_err()
+            .error
+            .details
+            .unwrap()
+            .validation_errors
+            .unwrap()
+            .into_iter()
+            .map(|e| e.field)
+            .collect()
+    }
+
+    fn firewall_rule(source: &str, port: &str) -> FirewallRuleRequest {
+        FirewallRuleRequest {
+            name: "Allow SSH".to_string(),
+            enabled: true,
+            zone_from: Zone::Wan,
+            zone_to: Zone::Lan,
+            source: source.to_string(),
+            destination: "any".to_string(),
+            protocol: Protocol::Tcp,
+            port: port.to_string(),
+            action: RuleAction::Accept,
+            schedule: None,
+            log: false,
+            source_group: None,
+            destination_group: None,
+            port_group: None,
+            source_countries: Vec::new(),
+            destination_countries: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_address_specs() {
+        for ok in [
+            "any",
+            "",
+            "10.0.0.1",
+            "10.0.0.0/8",
+            "fd00::/64",
+            "10.0.0.1-10.0.0.50",
+            "!192.168.1.0/24, 10.0.0.1",
+        ] {
+            assert!(address_spec(ok).is_ok(), "{}", ok);
+        }
+        for bad in [
+            "wan",
+            "10.0.0.0/33",
+            "10.0.0.50-10.0.0.1",
+            "10.0.0.1-fd00::1",
+            "10.0.0.1,",
+        ] {
+            assert!(address_spec(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_port_specs() {
+        for ok in ["any", "22", "80,443", "8000-8080", "6881:6889"] {
+            assert!(port_spec(ok).is_ok(), "{}", ok);
+        }
+        for bad in ["0", "65536", "ssh", "90-80", "22,"] {
+            assert!(port_spec(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_value_checks() {
+        assert!(mac("aa:bb:cc:dd:ee:ff").is_ok());
+        assert!(mac("AA-BB-CC-DD-EE-FF").is_ok());
+        assert!(mac("aa:bb:cc:dd:ee").is_err());
+        assert!(netmask("255.255.255.0").is_ok());
+        assert!(netmask("255.0.255.0").is_err());
+        assert!(ssid(&"x".repeat(32)).is_ok());
+        assert!(ssid(&"x".repeat(33)).is_err());
+        assert!(ssid("").is_err());
+        assert!(psk("correct horse").is_ok());
+        assert!(psk(&"ab".repeat(32)).is_ok());
+        assert!(psk("weak").is_err());
+        assert!(psk("aaaaaaaaaa").is_err());
+        assert!(dns_server("1.1.1.1#5353").is_ok());
+        assert!(dns_server("dns.google").is_err());
+        assert!(time_of_day("23:59").is_ok());
+        assert!(time_of_day("24:00").is_err());
+        assert!(http_url("https://example.com/hosts.txt").is_ok());
+        assert!(http_url("ftp://example.com").is_err());
+    }
+
+    #[test]
+    fn test_all_errors_are_reported_with_paths() {
+        let mut rule = firewall_rule("wan", "ssh");
+        rule.schedule = Some(RuleSchedule {
+            enabled: true,
+            start: "8:00".to_string(),
+            end: "18:00".to_string(),
+            days: vec![1, 7],
+        });
+        assert_eq!(
+            fields(rule.validate()),
+            ["source", "port", "schedule.start", "schedule.days[1]"]
+        );
+        assert!(firewall_rule("192.168.1.0/24", "22").validate().is_ok());
+    }
+
+    #[test]
+    fn test_ports_need_tcp_or_udp() {
+        let mut rule = firewall_rule("any", "22");
+        rule.protocol = Protocol::Icmp;
+        assert_eq!(fields(rule.validate()), ["port"]);
+    }
+
+    #[test]
+    fn test_group_members() {
+        for ok in [
+            "10.0.0.1",
+            "10.0.0.0/8",
+            "10.0.0.1-10.0.0.9",
+            "aa:bb:cc:dd:ee:ff",
+            "AA-BB-CC-DD-EE-FF",
+            "updates.example.com",
+        ] {
+            assert!(group_member(ok).is_ok(), "{}", ok);
+        }
+        for bad in ["fd00::/64", "10.0.0.0/33", "999.1.1.1", "-bad-", ""] {
+            assert!(group_member(bad).is_err(), "{}", bad);
+        }
+
+        let group = PortGroupRequest {
+            name: "Web".to_string(),
+            description: None,
+            ports: vec!["80".to_string(), "8000-8080".to_string(), "443,8443".to_string()],
+        };
+        assert_eq!(fields(group.validate()), ["ports[2]"]);
+
+        let mut rule = firewall_rule("any", "any");
+        rule.protocol = Protocol::All;
+        rule.port_group = Some(1);
+        assert_eq!(fields(rule.validate()), ["port_group"]);
+    }
+
+    #[test]
+    fn test_country_codes() {
+        let mut rule = firewall_rule("any", "any");
+        rule.source_countries = vec!["DE".to_string(), "de".to_string()];
+        rule.destination_countries = vec!["USA".to_string()];
+        assert_eq!(
+            fields(rule.validate()),
+            ["source_countries[1]", "destination_countries[0]"]
+        );
+    }
+
+    #[test]
+    fn test_wifi_security() {
+        let network = |mode, password: Option<&str>| WifiNetwork {
+            id: 1,
+            ssid: "HomeNetwork".to_string(),
+            enabled: true,
+            hidden: false,
+            radio: "radio0".to_string(),
+            security: WifiSecurity {
+                mode,
+                password: password.map(str::to_string),
+                radius: None,
+            },
+            vlan: Some(4095),
+            isolated: false,
+            bandwidth_limit: None,
+        };
+        assert_eq!(
+            fields(network(WifiSecurityMode::Wpa2, Some("weak")).validate()),
+            ["vlan", "security.password"]
+        );
+        assert_eq!(
+            fields(network(WifiSecurityMode::Wpa3, None).validate()),
+            ["vlan", "security.password"]
+        );
+        assert_eq!(
+            fields(network(WifiSecurityMode::Wpa2Enterprise, None).validate()),
+            ["vlan", "security.radius"]
+        );
+        assert_eq!(
+            fields(network(WifiSecurityMode::Open, None).validate()),
+            ["vlan"]
+        );
+    }
+
+    #[test]
+    fn test_dhcp_range_and_ttl_bounds() {
+        let dhcp = DhcpConfig {
+            enabled: true,
+            interface: "br0".to_string(),
+            range_start: "192.168.1.200".to_string(),
+            range_end: "192.168.1.100".to_string(),
+            lease_time: 86400,
+            gateway: "192.168.1.1".to_string(),
+            dns: vec!["192.168.1.1".to_string(), "dns".to_string()],
+            domain: None,
+            ntp: None,
+            pxe: None,
+        };
+        assert_eq!(fields(dhcp.validate()), ["range_end", "dns[1]"]);
+
+        let dns = DnsConfig {
+            enabled: true,
+            listen_address: "0.0.0.0".to_string(),
+            upstream_dns: vec!["1.1.1.1".to_string()],
+            dnssec: true,
+            cache_size: 1000,
+            min_ttl: 600,
+            max_ttl: 60,
+            block_mode: DnsBlockMode::Nxdomain,
+        };
+        assert_eq!(fields(dns.validate()), ["min_ttl"]);
+    }
+
+    #[test]
+    fn test_validation_error_is_422() {
+        let err = firewall_rule("any", "0").validate().unwrap_err();
+        assert_eq!(err.error.code.status_code(), 422);
+    }
+}
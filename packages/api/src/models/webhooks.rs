@@ -0,0 +1,97 @@
+//! Webhook models for the NGFW.sh API
+//!
+//! Subscriptions use `WebhookConfig` / `WebhookEvent` from ngfw-protocol;
+//! these types cover requests and the delivery log (migration 0011).
+
+#![allow(dead_code)]
+
+use super::fleet::{WebhookConfig, WebhookEvent};
+use serde::{Deserialize, Serialize};
+
+/// Body of `POST /webhooks`
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateWebhookRequest {
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Signing secret; generated when omitted
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Body of `PUT /webhooks/:id`; omitted fields are left unchanged
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpdateWebhookRequest {
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub events: Option<Vec<WebhookEvent>>,
+    #[serde(default)]
+    pub enabled: Option<bool>,
+}
+
+/// Response of `POST /webhooks`, the only time the secret is returned
+#[derive(Debug, Clone, Serialize)]
+pub struct CreateWebhookResponse {
+    #[serde(flatten)]
+    pub webhook: WebhookConfig,
+    pub secret: String,
+}
+
+/// Delivery state in the log
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Not delivered yet; retried at `next_attempt_at`
+    Pending,
+    /// Receiver answered with a 2xx status
+    Delivered,
+    /// Retries exhausted; kept for inspection
+    DeadLetter,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::DeadLetter => "dead_letter",
+        }
+    }
+}
+
+/// One entry of a webhook's delivery log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    pub id: String,
+    pub webhook_id: String,
+    /// Event name, or `test` for test deliveries
+    pub event: String,
+    /// Signed request body
+    pub payload: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_attempt_at: Option<i64>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// JSON body POSTed to webhook receivers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookEnvelope {
+    /// Delivery id, stable across retries
+    pub id: String,
+    pub event: String,
+    pub created_at: i64,
+    pub data: serde_json::Value,
+}
@@ -11,12 +11,16 @@
 //! - Server URLs (production + local dev)
 //! - API tags for endpoint organization
 //!
+//! Config sections use optimistic concurrency: GET responses carry an `ETag`
+//! derived from the section version, and PUT/POST/DELETE require a matching
+//! `If-Match` header or fail with `412 PRECONDITION_FAILED` (see `ErrorCode`).
+//!
 //! **Paths are not yet defined.** Endpoint paths will be added incrementally via
 //! `#[utoipa::path]` annotations on handler functions. See the workers-rs routing
 //! in `handlers/` for the actual endpoint implementations.
 
-use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
 use utoipa::openapi::ServerBuilder;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
 use utoipa::{Modify, OpenApi};
 
 /// OpenAPI 3.1 specification for the NGFW.sh API
@@ -78,9 +82,19 @@ use utoipa::{Modify, OpenApi};
             ngfw_protocol::AuthRequest,
             ngfw_protocol::AuthResponse,
             ngfw_protocol::StatusPayload,
+            ngfw_protocol::PongPayload,
+            ngfw_protocol::TaskHealth,
+            ngfw_protocol::TaskState,
             ngfw_protocol::InterfaceMetrics,
             ngfw_protocol::MetricsPayload,
+            ngfw_protocol::MetricsBatchPayload,
             ngfw_protocol::InterfaceRates,
+            ngfw_protocol::RuleCounters,
+            ngfw_protocol::CountryCounters,
+            ngfw_protocol::FeedCounters,
+            ngfw_protocol::UpnpMapping,
+            ngfw_protocol::UpnpRevoke,
+            ngfw_protocol::ScheduleState,
             ngfw_protocol::ConnectionCounts,
             ngfw_protocol::DnsMetrics,
             ngfw_protocol::ConfigPush,
@@ -110,6 +124,15 @@ use utoipa::{Modify, OpenApi};
             ngfw_protocol::FirmwareUpdate,
             ngfw_protocol::BootSlot,
             ngfw_protocol::BackupInfo,
+            // Error types
+            crate::models::ErrorCode,
+            crate::models::ApiError,
+            crate::models::ApiErrorBody,
+            crate::models::ErrorDetails,
+            crate::models::ValidationError,
+            crate::models::LimitInfo,
+            crate::models::lint::LintIssue,
+            crate::models::lint::LintSeverity,
         )
     ),
     modifiers(&SecurityAddon, &ServerAddon)
@@ -209,4 +232,11 @@ mod tests {
         assert!(tags.contains(&"security"));
         assert!(tags.contains(&"services"));
     }
+
+    #[test]
+    fn test_openapi_documents_error_codes() {
+        let json = ApiDoc::to_json();
+        assert!(json.contains("ErrorCode"));
+        assert!(json.contains("PRECONDITION_FAILED"));
+    }
 }
@@ -5,16 +5,76 @@
 //! - Authentication handshake
 //! - Bidirectional message passing
 //! - Status updates and metrics collection
+//! - Firewall rule hit counters
+//! - UPnP leases reported with metrics
 //! - Command execution requests
+//!
+//! Sockets are accepted through the hibernation API, so the object may be
+//! evicted while the agent stays connected. Each socket carries its device
+//! identity as a tag and a serialized attachment; on wake-up the socket is
+//! recovered via `state.get_websockets()` and `AgentState` is reloaded
+//! lazily from storage on first use.
+//!
+//! Liveness is checked by a storage alarm rather than trusting the socket
+//! to close: while authenticated, the alarm pings the agent every
+//! `AGENT_PING_INTERVAL_SECS` and counts missed pongs. A device silent for
+//! `AGENT_OFFLINE_GRACE_SECS` is marked offline, which opens an outage
+//! interval and emits `device_offline`; re-authenticating closes the
+//! interval and emits `device_online`.
 
 #![allow(dead_code)]
 
+use crate::middleware::rate_limit::{RateCounter, RateLimitConfig};
+use crate::models::fleet::WebhookEvent;
 use crate::models::rpc::*;
+use crate::models::security::UpnpLease;
+use crate::rpc::metrics_rollup::{MetricsAggregator, MetricsRollup};
+use crate::rpc::rule_hits::{self, CountryHitMap, FeedAlert, FeedHitMap, RuleHitMap};
+use crate::rpc::upnp_leases;
+use crate::storage;
+use crate::webhooks;
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::time::Duration;
 use worker::*;
 
+/// Default interval between server-initiated pings
+const DEFAULT_PING_INTERVAL_SECS: i64 = 30;
+
+/// Default silence after which a device is marked offline
+const DEFAULT_OFFLINE_GRACE_SECS: i64 = 90;
+
+/// Minimum interval between metrics rollup flushes to D1
+const METRICS_FLUSH_SECS: i64 = 60;
+
+/// Pending rollup buckets that force an early flush (e.g. after a backfill)
+const MAX_PENDING_BUCKETS: usize = 64;
+
+/// Storage key of the most recent raw metrics sample
+const LATEST_METRICS_KEY: &str = "latest_metrics";
+
+/// Storage key of rollups not yet flushed to D1 (`Vec<MetricsRollup>`)
+const PENDING_ROLLUPS_KEY: &str = "pending_rollups";
+
+/// Storage key of the accumulated firewall rule hits
+const RULE_HITS_KEY: &str = "rule_hits";
+
+/// Storage key of the accumulated hits of country rules, per country
+const COUNTRY_HITS_KEY: &str = "country_hits";
+
+/// Storage key of accumulated threat feed drops (`FeedHitMap`)
+const FEED_HITS_KEY: &str = "feed_hits";
+
+/// Storage key of the UPnP leases last reported (`Vec<UpnpLease>`)
+const UPNP_LEASES_KEY: &str = "upnp_leases";
+
+/// Storage key of the per-device message counter (`RateCounter`)
+const MESSAGE_LIMITER_KEY: &str = "message_limiter";
+
+/// Default agent messages accepted per minute before messages are dropped
+const DEFAULT_AGENT_MESSAGES_PER_MINUTE: i64 = 600;
+
 /// State stored in the Durable Object
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 struct AgentState {
@@ -24,6 +84,159 @@ struct AgentState {
     last_status: Option<StatusPayload>,
     last_seen: Option<i64>,
     pending_commands: HashMap<String, PendingCommand>,
+    /// Identifies the socket this state belongs to
+    #[serde(default)]
+    connection_id: Option<String>,
+    /// When the heartbeat alarm last pinged the agent
+    #[serde(default)]
+    last_ping_sent: Option<i64>,
+    /// Consecutive heartbeat pings that went unanswered
+    #[serde(default)]
+    missed_pongs: u32,
+    /// Outage in progress since the device was marked offline
+    #[serde(default)]
+    outage: Option<OpenOutage>,
+}
+
+/// An outage interval that has not ended yet
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct OpenOutage {
+    /// Row id in `device_outages`, if it was recorded
+    id: Option<String>,
+    /// Last time the device was heard from
+    started_at: i64,
+}
+
+/// Webhook event raised by an agent alert, if any
+fn alert_event(alert_type: &AlertType) -> Option<WebhookEvent> {
+    match alert_type {
+        AlertType::ConfigChange => Some(WebhookEvent::ConfigChanged),
+        AlertType::SystemAnomaly | AlertType::ConfigReapplied => None,
+        _ => Some(WebhookEvent::ThreatDetected),
+    }
+}
+
+/// Alert for drops by a threat feed, named after the feed if it still
+/// exists
+fn feed_alert(alert: &FeedAlert, name: Option<&str>) -> AlertMessage {
+    let feed = name.map_or_else(|| format!("#{}", alert.feed_id), str::to_string);
+    AlertMessage {
+        timestamp: chrono::Utc::now().timestamp(),
+        severity: AlertSeverity::Medium,
+        alert_type: AlertType::ThreatFeedMatch,
+        source: feed.clone(),
+        description: format!(
+            "Threat feed '{}' dropped {} packets ({} bytes)",
+            feed, alert.packets, alert.bytes
+        ),
+        details: Some(serde_json::json!({
+            "feed_id": alert.feed_id,
+            "packets": alert.packets,
+            "bytes": alert.bytes,
+        })),
+    }
+}
+
+/// What the heartbeat alarm should do next
+#[derive(Debug, PartialEq, Eq)]
+enum Heartbeat {
+    /// Ping the agent; `missed` if the previous ping went unanswered
+    Ping { missed: bool },
+    /// Silent beyond the grace period; offline since the last message
+    Offline { since: i64 },
+    /// Not connected, nothing to check
+    Idle,
+}
+
+/// Decide the heartbeat action for the current state
+fn next_heartbeat(state: &AgentState, now: i64, grace_secs: i64) -> Heartbeat {
+    if !state.authenticated {
+        return Heartbeat::Idle;
+    }
+
+    let last_seen = state.last_seen.unwrap_or(now);
+    if now - last_seen >= grace_secs {
+        return Heartbeat::Offline { since: last_seen };
+    }
+
+    let missed = state.last_ping_sent.is_some_and(|sent| last_seen < sent);
+    Heartbeat::Ping { missed }
+}
+
+/// Identity serialized onto each accepted socket so it survives hibernation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct SocketAttachment {
+    connection_id: String,
+    device_id: Option<String>,
+    owner_id: Option<String>,
+    /// Set once the agent's credentials on this socket are verified;
+    /// `None` on sockets accepted before this was tracked
+    #[serde(default)]
+    authenticated: Option<bool>,
+}
+
+/// Hibernation tag for a device's sockets
+fn device_tag(device_id: &str) -> String {
+    format!("device:{}", device_id)
+}
+
+/// Whether a message type counts against the device's message limit.
+///
+/// Telemetry is the only traffic an agent sends unprompted in bulk; control
+/// replies (config acks, exec results, pongs) are exempt so a telemetry
+/// burst never drops them.
+fn is_rate_limited(msg_type: &MessageType) -> bool {
+    matches!(
+        msg_type,
+        MessageType::Metrics | MessageType::MetricsBatch | MessageType::Log | MessageType::Alert
+    )
+}
+
+/// Rebuild in-memory state when the object is constructed or woken.
+///
+/// Storage is authoritative; the socket attachment only fills in identity
+/// that was never persisted (e.g. eviction before the first save).
+fn restore_state(stored: Option<AgentState>, attachment: Option<&SocketAttachment>) -> AgentState {
+    let mut state = stored.unwrap_or_default();
+    if let Some(attachment) = attachment {
+        if state.device_id.is_none() {
+            state.device_id = attachment.device_id.clone();
+        }
+        if state.owner_id.is_none() {
+            state.owner_id = attachment.owner_id.clone();
+        }
+        if state.connection_id.is_none() {
+            state.connection_id = Some(attachment.connection_id.clone());
+        }
+    }
+    state
+}
+
+/// Whether a socket belongs to the connection tracked by `state`.
+///
+/// Events from a socket replaced by a newer connection are stale and must
+/// not touch state. Sockets without an attachment predate this tracking
+/// and are treated as current.
+fn is_current_connection(state: &AgentState, attachment: Option<&SocketAttachment>) -> bool {
+    match (state.connection_id.as_deref(), attachment) {
+        (Some(current), Some(attachment)) => current == attachment.connection_id,
+        _ => true,
+    }
+}
+
+/// Whether a socket may send anything other than `Auth`.
+///
+/// The attachment is checked so the answer survives hibernation; sockets
+/// that predate this tracking fall back to the stored state.
+fn is_authenticated(state: &AgentState, attachment: Option<&SocketAttachment>) -> bool {
+    attachment
+        .and_then(|attachment| attachment.authenticated)
+        .unwrap_or(state.authenticated)
+}
+
+/// Read a socket's attachment, if it has a valid one
+fn attachment_of(ws: &WebSocket) -> Option<SocketAttachment> {
+    ws.deserialize_attachment().ok().flatten()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -39,8 +252,17 @@ struct PendingCommand {
 pub struct AgentConnection {
     state: State,
     env: Env,
+    /// Cached agent socket; recovered from `state.get_websockets()` after wake
     websocket: RefCell<Option<WebSocket>>,
     agent_state: RefCell<AgentState>,
+    /// Whether `agent_state` has been loaded from storage since construction
+    state_loaded: Cell<bool>,
+    /// Metrics rollups not yet flushed to D1
+    rollups: RefCell<MetricsAggregator>,
+    /// When rollups were last flushed
+    last_flush: Cell<i64>,
+    /// Window end of the last rate limit error sent to the agent
+    limit_notice: Cell<i64>,
 }
 
 impl DurableObject for AgentConnection {
@@ -50,6 +272,10 @@ impl DurableObject for AgentConnection {
             env,
             websocket: RefCell::new(None),
             agent_state: RefCell::new(AgentState::default()),
+            state_loaded: Cell::new(false),
+            rollups: RefCell::new(MetricsAggregator::default()),
+            last_flush: Cell::new(0),
+            limit_notice: Cell::new(0),
         }
     }
 
@@ -62,15 +288,31 @@ impl DurableObject for AgentConnection {
             "/command" => self.handle_command(req).await,
             "/status" => self.handle_status_request().await,
             "/disconnect" => self.handle_disconnect().await,
+            "/metrics/latest" => self.handle_latest_metrics().await,
+            "/firewall/hits" => self.handle_rule_hits().await,
+            "/firewall/hits/countries" => self.handle_country_hits().await,
+            "/nat/upnp" => self.handle_upnp_leases().await,
             _ => Response::error("Not found", 404),
         }
     }
 
     async fn websocket_message(
         &self,
-        _ws: WebSocket,
+        ws: WebSocket,
         message: WebSocketIncomingMessage,
     ) -> Result<()> {
+        // May be the first event after waking from hibernation
+        self.load_state().await?;
+        let current = {
+            let agent_state = self.agent_state.borrow();
+            is_current_connection(&agent_state, attachment_of(&ws).as_ref())
+        };
+        if !current {
+            console_log!("Ignoring message from replaced WebSocket");
+            return Ok(());
+        }
+        *self.websocket.borrow_mut() = Some(ws);
+
         match message {
             WebSocketIncomingMessage::String(text) => self.process_message(&text).await,
             WebSocketIncomingMessage::Binary(_) => {
@@ -82,19 +324,62 @@ impl DurableObject for AgentConnection {
 
     async fn websocket_close(
         &self,
-        _ws: WebSocket,
+        ws: WebSocket,
         _code: usize,
         _reason: String,
         _was_clean: bool,
     ) -> Result<()> {
-        {
-            let mut agent_state = self.agent_state.borrow_mut();
-            agent_state.authenticated = false;
+        self.load_state().await?;
+        let current = {
+            let agent_state = self.agent_state.borrow();
+            is_current_connection(&agent_state, attachment_of(&ws).as_ref())
+        };
+        if !current {
+            // A replaced socket closing must not mark the new one offline
+            return Ok(());
         }
-        self.update_device_online_status(false).await?;
-        self.save_state().await?;
+
         *self.websocket.borrow_mut() = None;
-        Ok(())
+        self.mark_offline(chrono::Utc::now().timestamp(), "connection_closed")
+            .await
+    }
+
+    async fn alarm(&self) -> Result<Response> {
+        self.load_state().await?;
+
+        let now = chrono::Utc::now().timestamp();
+        let grace = self.config_positive("AGENT_OFFLINE_GRACE_SECS", DEFAULT_OFFLINE_GRACE_SECS);
+        let action = {
+            let agent_state = self.agent_state.borrow();
+            next_heartbeat(&agent_state, now, grace)
+        };
+
+        match action {
+            Heartbeat::Ping { missed } => {
+                {
+                    let mut agent_state = self.agent_state.borrow_mut();
+                    if missed {
+                        agent_state.missed_pongs += 1;
+                    }
+                    agent_state.last_ping_sent = Some(now);
+                }
+                self.send_message(&RpcMessage::new(MessageType::Ping, serde_json::json!({})))?;
+                self.save_state().await?;
+                self.flush_rollups(false).await;
+                self.schedule_heartbeat().await?;
+            }
+            Heartbeat::Offline { since } => {
+                console_log!("Device silent since {}, marking offline", since);
+                if let Some(ws) = self.socket() {
+                    let _ = ws.close(Some(1001), Some("Heartbeat timeout"));
+                }
+                *self.websocket.borrow_mut() = None;
+                self.mark_offline(since, "heartbeat_timeout").await?;
+            }
+            Heartbeat::Idle => {}
+        }
+
+        Response::ok("OK")
     }
 
     async fn websocket_error(&self, _ws: WebSocket, error: worker::Error) -> Result<()> {
@@ -120,19 +405,44 @@ impl AgentConnection {
             agent_state.owner_id = Some(owner_id.to_string());
         }
 
+        // A device has at most one live connection; drop any previous one
+        for old in self.state.get_websockets() {
+            let _ = old.close(Some(1000), Some("Replaced by new connection"));
+        }
+
         // Create WebSocket pair
         let pair = WebSocketPair::new()?;
         let server = pair.server;
         let client = pair.client;
 
-        // Accept the WebSocket connection with hibernation
-        server.accept()?;
+        // The new socket must authenticate before it receives commands
+        let attachment = {
+            let mut agent_state = self.agent_state.borrow_mut();
+            let connection_id = uuid::Uuid::new_v4().to_string();
+            agent_state.connection_id = Some(connection_id.clone());
+            agent_state.authenticated = false;
+            SocketAttachment {
+                connection_id,
+                device_id: agent_state.device_id.clone(),
+                owner_id: agent_state.owner_id.clone(),
+                authenticated: Some(false),
+            }
+        };
 
-        // Store the server socket
-        *self.websocket.borrow_mut() = Some(server.clone());
+        // Accept with hibernation, tagging the socket with its device so it
+        // can be found again after the object is evicted
+        match attachment.device_id.as_deref() {
+            Some(device_id) => {
+                let tag = device_tag(device_id);
+                self.state
+                    .accept_websocket_with_tags(&server, &[tag.as_str()]);
+            }
+            None => self.state.accept_web_socket(&server),
+        }
+        server.serialize_attachment(&attachment)?;
 
-        // Set up event handlers using hibernation API
-        self.state.accept_web_socket(&server);
+        // Store the server socket
+        *self.websocket.borrow_mut() = Some(server);
 
         // Save initial state
         self.save_state().await?;
@@ -151,12 +461,9 @@ impl AgentConnection {
             }
         }
 
-        // Check websocket exists before await (drop borrow before async)
-        {
-            let websocket = self.websocket.borrow();
-            if websocket.is_none() {
-                return Response::error("Device not connected", 503);
-            }
+        // Recover the socket if the object woke from hibernation
+        if self.socket().is_none() {
+            return Response::error("Device not connected", 503);
         }
 
         // Parse command (await point - no RefCell borrows held)
@@ -175,6 +482,7 @@ impl AgentConnection {
             "REBOOT" => MessageType::Reboot,
             "UPGRADE" => MessageType::Upgrade,
             "STATUS_REQUEST" => MessageType::StatusRequest,
+            "REVOKE_UPNP" => MessageType::UpnpRevoke,
             "SHUTDOWN" => MessageType::Reboot, // Map shutdown to reboot for simplicity
             _ => MessageType::Exec,
         };
@@ -195,11 +503,10 @@ impl AgentConnection {
             );
         }
 
-        // Send to device (re-acquire borrow after all await points before this)
+        // Send to device
         {
-            let websocket = self.websocket.borrow();
-            let ws = websocket
-                .as_ref()
+            let ws = self
+                .socket()
                 .ok_or_else(|| worker::Error::from("WebSocket disconnected"))?;
             let msg_str = serde_json::to_string(&message)?;
             ws.send_with_str(&msg_str)?;
@@ -222,6 +529,8 @@ impl AgentConnection {
             "device_id": agent_state.device_id,
             "authenticated": agent_state.authenticated,
             "last_seen": agent_state.last_seen,
+            "missed_pongs": agent_state.missed_pongs,
+            "offline_since": agent_state.outage.as_ref().map(|o| o.started_at),
             "status": agent_state.last_status,
         });
 
@@ -230,19 +539,15 @@ impl AgentConnection {
 
     /// Disconnect the WebSocket
     async fn handle_disconnect(&self) -> Result<Response> {
-        {
-            let websocket = self.websocket.borrow();
-            if let Some(ws) = websocket.as_ref() {
-                ws.close(Some(1000), Some("Disconnected by server"))?;
-            }
-        }
+        self.load_state().await?;
 
-        {
-            let mut agent_state = self.agent_state.borrow_mut();
-            agent_state.authenticated = false;
+        if let Some(ws) = self.socket() {
+            ws.close(Some(1000), Some("Disconnected by server"))?;
         }
+
         *self.websocket.borrow_mut() = None;
-        self.save_state().await?;
+        self.mark_offline(chrono::Utc::now().timestamp(), "disconnected_by_server")
+            .await?;
 
         Response::ok("Disconnected")
     }
@@ -251,6 +556,30 @@ impl AgentConnection {
     async fn process_message(&self, msg: &str) -> Result<()> {
         let message: RpcMessage = serde_json::from_str(msg)?;
 
+        // Nothing but credentials is accepted from an unverified agent
+        if !matches!(message.msg_type, MessageType::Auth) && !self.socket_authenticated() {
+            console_log!(
+                "Dropping {:?} message from unauthenticated agent",
+                message.msg_type
+            );
+            return Ok(());
+        }
+
+        // Any message proves the agent is alive
+        let now = chrono::Utc::now().timestamp();
+        {
+            let mut agent_state = self.agent_state.borrow_mut();
+            agent_state.last_seen = Some(now);
+            agent_state.missed_pongs = 0;
+        }
+
+        // Only telemetry counts; auth and replies to server requests are
+        // never dropped
+        if is_rate_limited(&message.msg_type) && !self.allow_message(now).await? {
+            self.save_state().await?;
+            return Ok(());
+        }
+
         match message.msg_type {
             MessageType::Auth => {
                 self.handle_auth_message(&message).await?;
@@ -273,10 +602,14 @@ impl AgentConnection {
             MessageType::Metrics => {
                 self.handle_metrics_message(&message).await?;
             }
+            MessageType::MetricsBatch => {
+                self.handle_metrics_batch_message(&message).await?;
+            }
+            MessageType::Ping => {
+                self.handle_ping_message(&message)?;
+            }
             MessageType::Pong => {
-                // Update last seen
-                let mut agent_state = self.agent_state.borrow_mut();
-                agent_state.last_seen = Some(chrono::Utc::now().timestamp());
+                // Liveness already recorded above
             }
             _ => {
                 console_log!("Unknown message type: {:?}", message.msg_type);
@@ -287,6 +620,48 @@ impl AgentConnection {
         Ok(())
     }
 
+    /// Count a message against the device's limit.
+    ///
+    /// Over the limit the message is dropped; the agent gets one `Error`
+    /// per window telling it when to resume.
+    ///
+    /// The counter lives in storage so hibernation does not reset it.
+    async fn allow_message(&self, now: i64) -> Result<bool> {
+        let limit = self.config_positive(
+            "AGENT_MESSAGES_PER_MINUTE",
+            DEFAULT_AGENT_MESSAGES_PER_MINUTE,
+        );
+        let config = RateLimitConfig::new("agent_messages", limit as u64, 60);
+        let storage = self.state.storage();
+        let mut counter = storage
+            .get::<RateCounter>(MESSAGE_LIMITER_KEY)
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        let decision = counter.hit(&config, now);
+        storage.put(MESSAGE_LIMITER_KEY, &counter).await?;
+        if decision.allowed {
+            return Ok(true);
+        }
+
+        if self.limit_notice.replace(decision.reset) != decision.reset {
+            console_warn!(
+                "Agent message rate limit exceeded, dropping until {}",
+                decision.reset
+            );
+            self.send_message(&RpcMessage::new(
+                MessageType::Error,
+                serde_json::json!({
+                    "error": "Message rate limit exceeded",
+                    "limit": decision.limit,
+                    "retry_after": decision.reset - now,
+                }),
+            ))?;
+        }
+        Ok(false)
+    }
+
     /// Handle authentication message from agent
     async fn handle_auth_message(&self, message: &RpcMessage) -> Result<()> {
         let auth: AuthRequest = serde_json::from_value(message.payload.clone())?;
@@ -318,6 +693,7 @@ impl AgentConnection {
                 agent_state.authenticated = true;
                 agent_state.last_seen = Some(chrono::Utc::now().timestamp());
             }
+            self.mark_socket_authenticated()?;
 
             RpcMessage::new(
                 MessageType::AuthOk,
@@ -341,6 +717,7 @@ impl AgentConnection {
         // Update device status in KV
         if is_valid {
             self.update_device_online_status(true).await?;
+            self.mark_online().await?;
         }
 
         Ok(())
@@ -441,62 +818,482 @@ impl AgentConnection {
         let alert: AlertMessage = serde_json::from_value(message.payload.clone())?;
 
         let device_id = self.agent_state.borrow().device_id.clone();
-
-        // Store alert in KV
         if let Some(device_id) = device_id {
-            let kv = self.env.kv("CACHE")?;
-            let alert_id = uuid::Uuid::new_v4().to_string();
-            let alert_json = serde_json::to_string(&alert)?;
+            self.store_alert(&device_id, &alert).await?;
+        }
 
-            kv.put(&format!("alert:{}:{}", device_id, alert_id), &alert_json)?
-                .expiration_ttl(86400 * 7) // 7 day TTL
-                .execute()
-                .await?;
+        Ok(())
+    }
 
-            // TODO: Trigger webhooks for business plan users
-        }
+    /// Store an alert in KV and raise its webhook event
+    async fn store_alert(&self, device_id: &str, alert: &AlertMessage) -> Result<()> {
+        let kv = self.env.kv("CACHE")?;
+        let alert_id = uuid::Uuid::new_v4().to_string();
+        let alert_json = serde_json::to_string(alert)?;
+
+        kv.put(&format!("alert:{}:{}", device_id, alert_id), &alert_json)?
+            .expiration_ttl(86400 * 7) // 7 day TTL
+            .execute()
+            .await?;
 
+        if let Some(event) = alert_event(&alert.alert_type) {
+            let payload = serde_json::json!({
+                "device_id": device_id,
+                "alert_id": alert_id,
+                "alert": alert,
+            });
+            self.emit_device_event(device_id, event, payload).await;
+        }
         Ok(())
     }
 
-    /// Handle metrics update from agent
+    /// Handle metrics update from agent.
+    ///
+    /// The sample is folded into the pending rollups and kept as the latest
+    /// raw sample in Durable Object storage for `/metrics/latest`.
     async fn handle_metrics_message(&self, message: &RpcMessage) -> Result<()> {
         let metrics: MetricsPayload = serde_json::from_value(message.payload.clone())?;
 
-        let device_id = self.agent_state.borrow().device_id.clone();
+        self.rollups.borrow_mut().add(&metrics);
+        self.state
+            .storage()
+            .put(LATEST_METRICS_KEY, &metrics)
+            .await?;
+        self.record_rule_hits(std::slice::from_ref(&metrics))
+            .await?;
+        if let Some(mappings) = &metrics.upnp_leases {
+            self.record_upnp_leases(mappings, metrics.timestamp).await?;
+        }
 
-        // Store metrics for time-series analysis
-        if let Some(device_id) = device_id {
-            let kv = self.env.kv("CACHE")?;
-            let metrics_json = serde_json::to_string(&metrics)?;
+        self.flush_rollups(false).await;
+        Ok(())
+    }
 
-            // Store with timestamp-based key for time series
-            let ts = metrics.timestamp;
-            kv.put(&format!("metrics:{}:{}", device_id, ts), &metrics_json)?
-                .expiration_ttl(86400) // 24 hour TTL
-                .execute()
+    /// Replace the stored UPnP leases with the mappings an agent reported
+    async fn record_upnp_leases(&self, mappings: &[UpnpMapping], now: i64) -> Result<()> {
+        let storage = self.state.storage();
+        let previous = storage
+            .get::<Vec<UpnpLease>>(UPNP_LEASES_KEY)
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        let leases = upnp_leases::merge(&previous, mappings, now);
+        if leases != previous {
+            storage.put(UPNP_LEASES_KEY, &leases).await?;
+        }
+        Ok(())
+    }
+
+    /// Fold firewall rule counters from samples into the stored hits
+    async fn record_rule_hits(&self, samples: &[MetricsPayload]) -> Result<()> {
+        self.record_country_hits(samples).await?;
+        self.record_feed_hits(samples).await?;
+        if samples.iter().all(|s| s.firewall_rules.is_empty()) {
+            return Ok(());
+        }
+
+        let storage = self.state.storage();
+        let mut hits = storage
+            .get::<RuleHitMap>(RULE_HITS_KEY)
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        let mut changed = false;
+        for sample in samples {
+            changed |= rule_hits::record(&mut hits, &sample.firewall_rules, sample.timestamp);
+        }
+        if changed {
+            storage.put(RULE_HITS_KEY, &hits).await?;
+        }
+        Ok(())
+    }
+
+    /// Fold per-country counters from samples into the stored country hits
+    async fn record_country_hits(&self, samples: &[MetricsPayload]) -> Result<()> {
+        if samples.iter().all(|s| s.countries.is_empty()) {
+            return Ok(());
+        }
+
+        let storage = self.state.storage();
+        let mut hits = storage
+            .get::<CountryHitMap>(COUNTRY_HITS_KEY)
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        let mut changed = false;
+        for sample in samples {
+            changed |= rule_hits::record_countries(&mut hits, &sample.countries, sample.timestamp);
+        }
+        if changed {
+            storage.put(COUNTRY_HITS_KEY, &hits).await?;
+        }
+        Ok(())
+    }
+
+    /// Fold per-feed counters from samples into the stored feed hits and
+    /// raise an alert for every feed with new drops that is due one
+    async fn record_feed_hits(&self, samples: &[MetricsPayload]) -> Result<()> {
+        if samples.iter().all(|s| s.threat_feeds.is_empty()) {
+            return Ok(());
+        }
+
+        let storage = self.state.storage();
+        let mut hits = storage
+            .get::<FeedHitMap>(FEED_HITS_KEY)
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        let mut changed = false;
+        let mut alerts = Vec::new();
+        for sample in samples {
+            let (sample_changed, mut due) =
+                rule_hits::record_feeds(&mut hits, &sample.threat_feeds, sample.timestamp);
+            changed |= sample_changed;
+            alerts.append(&mut due);
+        }
+        if changed {
+            storage.put(FEED_HITS_KEY, &hits).await?;
+        }
+
+        let device_id = self.agent_state.borrow().device_id.clone();
+        let (Some(device_id), false) = (device_id, alerts.is_empty()) else {
+            return Ok(());
+        };
+        let feeds = storage::get_threat_feeds(&device_id, &self.env)
+            .await
+            .unwrap_or_default();
+        for alert in alerts {
+            let name = feeds
+                .iter()
+                .find(|f| f.id == alert.feed_id)
+                .map(|f| f.name.clone());
+            self.store_alert(&device_id, &feed_alert(&alert, name.as_deref()))
                 .await?;
         }
+        Ok(())
+    }
+
+    /// Return the accumulated hits of country rules
+    async fn handle_country_hits(&self) -> Result<Response> {
+        let hits = self
+            .state
+            .storage()
+            .get::<CountryHitMap>(COUNTRY_HITS_KEY)
+            .await?
+            .unwrap_or_default();
+        Response::from_json(&hits)
+    }
+
+    /// Return the UPnP leases last reported
+    async fn handle_upnp_leases(&self) -> Result<Response> {
+        let leases = self
+            .state
+            .storage()
+            .get::<Vec<UpnpLease>>(UPNP_LEASES_KEY)
+            .await?
+            .unwrap_or_default();
+        Response::from_json(&leases)
+    }
 
+    /// Return the accumulated firewall rule hits
+    async fn handle_rule_hits(&self) -> Result<Response> {
+        let hits = self
+            .state
+            .storage()
+            .get::<RuleHitMap>(RULE_HITS_KEY)
+            .await?
+            .unwrap_or_default();
+        Response::from_json(&hits)
+    }
+
+    /// Answer an agent keepalive with the server time, which the agent uses
+    /// to keep its clock offset fresh
+    fn handle_ping_message(&self, message: &RpcMessage) -> Result<()> {
         {
             let mut agent_state = self.agent_state.borrow_mut();
             agent_state.last_seen = Some(chrono::Utc::now().timestamp());
         }
+
+        let pong = RpcMessage::with_id(
+            message.id.clone(),
+            MessageType::Pong,
+            serde_json::to_value(PongPayload {
+                server_time: Some(chrono::Utc::now().timestamp()),
+            })?,
+        );
+        self.send_message(&pong)
+    }
+
+    /// Handle historical metrics buffered by the agent during an outage.
+    ///
+    /// Samples carry their original timestamps, so they land in the rollup
+    /// buckets they were taken in. The latest raw sample is only replaced
+    /// if the batch contains something newer.
+    async fn handle_metrics_batch_message(&self, message: &RpcMessage) -> Result<()> {
+        let batch: MetricsBatchPayload = serde_json::from_value(message.payload.clone())?;
+
+        {
+            let mut rollups = self.rollups.borrow_mut();
+            for sample in &batch.samples {
+                rollups.add(sample);
+            }
+        }
+
+        // Samples arrive in chronological order, as counters must be folded
+        self.record_rule_hits(&batch.samples).await?;
+
+        if let Some(newest) = batch.samples.iter().max_by_key(|s| s.timestamp) {
+            let storage = self.state.storage();
+            let latest = storage
+                .get::<MetricsPayload>(LATEST_METRICS_KEY)
+                .await
+                .ok()
+                .flatten();
+            if latest.is_none_or(|l| l.timestamp < newest.timestamp) {
+                storage.put(LATEST_METRICS_KEY, newest).await?;
+            }
+        }
+
+        console_log!("Backfilled {} metrics samples", batch.samples.len());
+        self.flush_rollups(false).await;
         Ok(())
     }
 
+    /// Write pending rollups to D1 if a flush is due (or `force`d).
+    ///
+    /// On failure the rollups are put back and retried on the next flush.
+    /// Whatever is still pending afterwards is saved to storage.
+    async fn flush_rollups(&self, force: bool) {
+        let now = chrono::Utc::now().timestamp();
+        let due = {
+            let rollups = self.rollups.borrow();
+            !rollups.is_empty()
+                && (force
+                    || now - self.last_flush.get() >= METRICS_FLUSH_SECS
+                    || rollups.len() >= MAX_PENDING_BUCKETS)
+        };
+        let device_id = self.agent_state.borrow().device_id.clone();
+
+        if let (true, Some(device_id)) = (due, device_id) {
+            let pending = self.rollups.borrow_mut().drain();
+            self.last_flush.set(now);
+            if let Err(e) = storage::upsert_metrics_rollups(&device_id, &pending, &self.env).await {
+                console_log!("Failed to flush metrics rollups: {}", e.error.message);
+                self.rollups.borrow_mut().restore(pending);
+            }
+        }
+
+        self.save_rollups().await;
+    }
+
+    /// Mirror pending rollups to storage so hibernation or eviction before
+    /// the next flush does not lose them
+    async fn save_rollups(&self) {
+        let pending = self.rollups.borrow().pending();
+        let storage = self.state.storage();
+        let result = if pending.is_empty() {
+            storage.delete(PENDING_ROLLUPS_KEY).await.map(|_| ())
+        } else {
+            storage.put(PENDING_ROLLUPS_KEY, &pending).await
+        };
+        if let Err(e) = result {
+            console_log!("Failed to save pending metrics rollups: {}", e);
+        }
+    }
+
+    /// Return the most recent raw metrics sample
+    async fn handle_latest_metrics(&self) -> Result<Response> {
+        match self
+            .state
+            .storage()
+            .get::<MetricsPayload>(LATEST_METRICS_KEY)
+            .await?
+        {
+            Some(metrics) => Response::from_json(&metrics),
+            None => Response::error("No metrics reported", 404),
+        }
+    }
+
     /// Send a message to the connected WebSocket
     fn send_message(&self, message: &RpcMessage) -> Result<()> {
         let msg_str = serde_json::to_string(message)?;
 
-        let websocket = self.websocket.borrow();
-        if let Some(ws) = websocket.as_ref() {
+        if let Some(ws) = self.socket() {
             ws.send_with_str(&msg_str)?;
         }
 
         Ok(())
     }
 
+    /// The agent's socket, recovered from the runtime after hibernation.
+    ///
+    /// Only the socket whose attachment matches the tracked connection is
+    /// returned; a stale socket must never receive commands.
+    fn socket(&self) -> Option<WebSocket> {
+        if let Some(ws) = self.websocket.borrow().as_ref() {
+            return Some(ws.clone());
+        }
+
+        let sockets = self.state.get_websockets();
+        let ws = {
+            let agent_state = self.agent_state.borrow();
+            sockets
+                .iter()
+                .rev()
+                .find(|ws| {
+                    attachment_of(ws).is_some_and(|attachment| {
+                        agent_state.connection_id.as_deref() == Some(&attachment.connection_id)
+                    })
+                })
+                .cloned()
+        }?;
+
+        *self.websocket.borrow_mut() = Some(ws.clone());
+        Some(ws)
+    }
+
+    /// Whether the agent's socket has authenticated
+    fn socket_authenticated(&self) -> bool {
+        let attachment = self.socket().as_ref().and_then(attachment_of);
+        is_authenticated(&self.agent_state.borrow(), attachment.as_ref())
+    }
+
+    /// Record on the socket that its agent authenticated
+    fn mark_socket_authenticated(&self) -> Result<()> {
+        let Some(ws) = self.socket() else {
+            return Ok(());
+        };
+        if let Some(mut attachment) = attachment_of(&ws) {
+            attachment.authenticated = Some(true);
+            ws.serialize_attachment(&attachment)?;
+        }
+        Ok(())
+    }
+
+    /// Mark the device offline, e.g. after its socket closed or it went
+    /// silent. `since` is when it was last known to be alive.
+    ///
+    /// Only the transition from authenticated opens an outage and emits
+    /// `device_offline`; repeated calls just refresh the KV status.
+    async fn mark_offline(&self, since: i64, reason: &str) -> Result<()> {
+        let (was_online, device_id) = {
+            let mut agent_state = self.agent_state.borrow_mut();
+            let was_online = std::mem::replace(&mut agent_state.authenticated, false);
+            agent_state.last_ping_sent = None;
+            (was_online, agent_state.device_id.clone())
+        };
+
+        self.update_device_online_status(false).await?;
+        self.flush_rollups(true).await;
+
+        if let (true, Some(device_id)) = (was_online, device_id) {
+            let outage_id =
+                match storage::open_device_outage(&device_id, since, reason, &self.env).await {
+                    Ok(id) => Some(id),
+                    Err(e) => {
+                        console_log!("Failed to record outage: {}", e.error.message);
+                        None
+                    }
+                };
+            self.agent_state.borrow_mut().outage = Some(OpenOutage {
+                id: outage_id,
+                started_at: since,
+            });
+
+            self.emit_device_event(
+                &device_id,
+                WebhookEvent::DeviceOffline,
+                serde_json::json!({
+                    "device_id": device_id,
+                    "since": since,
+                    "reason": reason,
+                }),
+            )
+            .await;
+        }
+
+        self.save_state().await
+    }
+
+    /// Close any open outage after a successful auth, emit `device_online`,
+    /// and start the heartbeat.
+    async fn mark_online(&self) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        let (outage, device_id) = {
+            let mut agent_state = self.agent_state.borrow_mut();
+            agent_state.missed_pongs = 0;
+            agent_state.last_ping_sent = None;
+            (agent_state.outage.take(), agent_state.device_id.clone())
+        };
+
+        if let (Some(outage), Some(device_id)) = (outage, device_id) {
+            let duration = match outage.id.as_deref() {
+                Some(id) => storage::close_device_outage(id, outage.started_at, now, &self.env)
+                    .await
+                    .unwrap_or_else(|e| {
+                        console_log!("Failed to close outage: {}", e.error.message);
+                        now - outage.started_at
+                    }),
+                None => now - outage.started_at,
+            };
+
+            self.emit_device_event(
+                &device_id,
+                WebhookEvent::DeviceOnline,
+                serde_json::json!({
+                    "device_id": device_id,
+                    "offline_since": outage.started_at,
+                    "outage_secs": duration,
+                }),
+            )
+            .await;
+        }
+
+        self.save_state().await?;
+        self.schedule_heartbeat().await
+    }
+
+    /// Record a device event and deliver it to the owner's webhooks;
+    /// failures are logged, not propagated
+    async fn emit_device_event(
+        &self,
+        device_id: &str,
+        event: WebhookEvent,
+        payload: serde_json::Value,
+    ) {
+        if let Err(e) = storage::record_device_event(device_id, &event, &payload, &self.env).await {
+            console_log!("Failed to record {:?} event: {}", event, e.error.message);
+        }
+
+        let owner_id = self.agent_state.borrow().owner_id.clone();
+        if let Some(owner_id) = owner_id {
+            webhooks::dispatch(&owner_id, event, payload, &self.env).await;
+        }
+    }
+
+    /// Schedule the next heartbeat alarm
+    async fn schedule_heartbeat(&self) -> Result<()> {
+        let interval = self.config_positive("AGENT_PING_INTERVAL_SECS", DEFAULT_PING_INTERVAL_SECS);
+        self.state
+            .storage()
+            .set_alarm(Duration::from_secs(interval as u64))
+            .await
+    }
+
+    /// Read a positive integer (e.g. a duration in seconds) from an environment variable
+    fn config_positive(&self, name: &str, default: i64) -> i64 {
+        self.env
+            .var(name)
+            .ok()
+            .and_then(|v| v.to_string().parse::<i64>().ok())
+            .filter(|secs| *secs > 0)
+            .unwrap_or(default)
+    }
+
     /// Update device online status in KV
     async fn update_device_online_status(&self, online: bool) -> Result<()> {
         let device_id = self.agent_state.borrow().device_id.clone();
@@ -518,16 +1315,41 @@ impl AgentConnection {
         Ok(())
     }
 
-    /// Load state from Durable Object storage
+    /// Load state from Durable Object storage on first use after the object
+    /// is constructed (including after waking from hibernation). Later calls
+    /// are no-ops: in-memory state is authoritative while the object lives.
     async fn load_state(&self) -> Result<()> {
-        if let Some(state) = self
+        if self.state_loaded.get() {
+            return Ok(());
+        }
+
+        let stored = self
             .state
             .storage()
             .get::<AgentState>("agent_state")
+            .await?;
+        let attachment = {
+            let sockets = self.state.get_websockets();
+            let mut attachments = sockets.iter().rev().filter_map(attachment_of);
+            match stored.as_ref().and_then(|s| s.connection_id.as_deref()) {
+                Some(current) => attachments.find(|a| a.connection_id == current),
+                // Evicted before the first save; the socket is the only record
+                None => attachments.next(),
+            }
+        };
+
+        *self.agent_state.borrow_mut() = restore_state(stored, attachment.as_ref());
+
+        // Rollups saved before hibernation are flushed with the next batch
+        let pending = self
+            .state
+            .storage()
+            .get::<Vec<MetricsRollup>>(PENDING_ROLLUPS_KEY)
             .await?
-        {
-            *self.agent_state.borrow_mut() = state;
-        }
+            .unwrap_or_default();
+        self.rollups.borrow_mut().restore(pending);
+
+        self.state_loaded.set(true);
         Ok(())
     }
 
@@ -541,3 +1363,223 @@ impl AgentConnection {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attachment(connection_id: &str) -> SocketAttachment {
+        SocketAttachment {
+            connection_id: connection_id.to_string(),
+            device_id: Some("dev-1".to_string()),
+            owner_id: Some("owner-1".to_string()),
+            authenticated: Some(false),
+        }
+    }
+
+    fn stored_state() -> AgentState {
+        AgentState {
+            device_id: Some("dev-1".to_string()),
+            owner_id: Some("owner-1".to_string()),
+            authenticated: true,
+            last_seen: Some(1_700_000_000),
+            connection_id: Some("conn-a".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_wake_restores_stored_state() {
+        let state = restore_state(Some(stored_state()), Some(&attachment("conn-a")));
+        assert!(state.authenticated);
+        assert_eq!(state.device_id.as_deref(), Some("dev-1"));
+        assert_eq!(state.last_seen, Some(1_700_000_000));
+    }
+
+    #[test]
+    fn test_wake_without_storage_uses_attachment_identity() {
+        let state = restore_state(None, Some(&attachment("conn-a")));
+        assert_eq!(state.device_id.as_deref(), Some("dev-1"));
+        assert_eq!(state.owner_id.as_deref(), Some("owner-1"));
+        assert_eq!(state.connection_id.as_deref(), Some("conn-a"));
+        // Never authenticated as far as storage knows
+        assert!(!state.authenticated);
+    }
+
+    #[test]
+    fn test_storage_identity_wins_over_attachment() {
+        let mut other = attachment("conn-b");
+        other.device_id = Some("dev-2".to_string());
+        let state = restore_state(Some(stored_state()), Some(&other));
+        assert_eq!(state.device_id.as_deref(), Some("dev-1"));
+        assert_eq!(state.connection_id.as_deref(), Some("conn-a"));
+    }
+
+    #[test]
+    fn test_unverified_socket_is_not_authenticated() {
+        // Storage may still say authenticated from the previous socket
+        let state = stored_state();
+        assert!(!is_authenticated(&state, Some(&attachment("conn-a"))));
+
+        let mut verified = attachment("conn-a");
+        verified.authenticated = Some(true);
+        assert!(is_authenticated(&state, Some(&verified)));
+    }
+
+    #[test]
+    fn test_attachment_without_auth_flag_uses_state() {
+        let json = r#"{"connection_id": "conn-a", "device_id": "dev-1", "owner_id": null}"#;
+        let attachment: SocketAttachment = serde_json::from_str(json).unwrap();
+        assert!(attachment.authenticated.is_none());
+        assert!(is_authenticated(&stored_state(), Some(&attachment)));
+        let fresh = AgentState::default();
+        assert!(!is_authenticated(&fresh, Some(&attachment)));
+    }
+
+    #[test]
+    fn test_wake_with_nothing_is_default() {
+        let state = restore_state(None, None);
+        assert!(state.device_id.is_none());
+        assert!(!state.authenticated);
+    }
+
+    #[test]
+    fn test_replaced_socket_is_not_current() {
+        let state = stored_state();
+        assert!(is_current_connection(&state, Some(&attachment("conn-a"))));
+        assert!(!is_current_connection(
+            &state,
+            Some(&attachment("conn-old"))
+        ));
+    }
+
+    #[test]
+    fn test_untracked_sockets_are_current() {
+        // Sockets accepted before attachments existed
+        assert!(is_current_connection(&stored_state(), None));
+        // State saved before connection ids existed
+        let legacy = AgentState {
+            connection_id: None,
+            ..stored_state()
+        };
+        assert!(is_current_connection(&legacy, Some(&attachment("conn-a"))));
+    }
+
+    #[test]
+    fn test_state_without_connection_id_deserializes() {
+        let json = r#"{
+            "device_id": "dev-1",
+            "owner_id": "owner-1",
+            "authenticated": true,
+            "last_status": null,
+            "last_seen": 1700000000,
+            "pending_commands": {}
+        }"#;
+        let state: AgentState = serde_json::from_str(json).unwrap();
+        assert!(state.connection_id.is_none());
+        assert!(state.authenticated);
+    }
+
+    fn online_state(last_seen: i64, last_ping_sent: Option<i64>) -> AgentState {
+        AgentState {
+            authenticated: true,
+            last_seen: Some(last_seen),
+            last_ping_sent,
+            ..stored_state()
+        }
+    }
+
+    #[test]
+    fn test_heartbeat_pings_live_device() {
+        let state = online_state(1_000, None);
+        assert_eq!(
+            next_heartbeat(&state, 1_030, 90),
+            Heartbeat::Ping { missed: false }
+        );
+    }
+
+    #[test]
+    fn test_heartbeat_counts_unanswered_ping() {
+        // Pinged at 1030, nothing heard since 1000
+        let state = online_state(1_000, Some(1_030));
+        assert_eq!(
+            next_heartbeat(&state, 1_060, 90),
+            Heartbeat::Ping { missed: true }
+        );
+
+        // Pong arrived after the ping
+        let state = online_state(1_031, Some(1_030));
+        assert_eq!(
+            next_heartbeat(&state, 1_060, 90),
+            Heartbeat::Ping { missed: false }
+        );
+    }
+
+    #[test]
+    fn test_heartbeat_marks_silent_device_offline() {
+        let state = online_state(1_000, Some(1_060));
+        assert_eq!(
+            next_heartbeat(&state, 1_090, 90),
+            Heartbeat::Offline { since: 1_000 }
+        );
+    }
+
+    #[test]
+    fn test_heartbeat_idle_when_not_connected() {
+        let state = AgentState {
+            authenticated: false,
+            ..online_state(0, None)
+        };
+        assert_eq!(next_heartbeat(&state, 10_000, 90), Heartbeat::Idle);
+    }
+
+    #[test]
+    fn test_open_outage_survives_state_roundtrip() {
+        let state = AgentState {
+            outage: Some(OpenOutage {
+                id: Some("outage-1".to_string()),
+                started_at: 1_000,
+            }),
+            ..stored_state()
+        };
+        let json = serde_json::to_string(&state).unwrap();
+        let restored: AgentState = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.outage, state.outage);
+    }
+
+    #[test]
+    fn test_alert_event_mapping() {
+        assert_eq!(
+            alert_event(&AlertType::PortScan),
+            Some(WebhookEvent::ThreatDetected)
+        );
+        assert_eq!(
+            alert_event(&AlertType::ConfigChange),
+            Some(WebhookEvent::ConfigChanged)
+        );
+        assert_eq!(
+            alert_event(&AlertType::ThreatFeedMatch),
+            Some(WebhookEvent::ThreatDetected)
+        );
+        assert_eq!(alert_event(&AlertType::SystemAnomaly), None);
+        assert_eq!(alert_event(&AlertType::ConfigReapplied), None);
+    }
+
+    #[test]
+    fn test_device_tag() {
+        assert_eq!(device_tag("dev-1"), "device:dev-1");
+    }
+
+    #[test]
+    fn test_only_telemetry_is_rate_limited() {
+        assert!(is_rate_limited(&MessageType::Metrics));
+        assert!(is_rate_limited(&MessageType::MetricsBatch));
+        assert!(is_rate_limited(&MessageType::Log));
+        assert!(is_rate_limited(&MessageType::Alert));
+        assert!(!is_rate_limited(&MessageType::Auth));
+        assert!(!is_rate_limited(&MessageType::ConfigAck));
+        assert!(!is_rate_limited(&MessageType::ConfigFail));
+        assert!(!is_rate_limited(&MessageType::ExecResult));
+        assert!(!is_rate_limited(&MessageType::Pong));
+    }
+}
@@ -0,0 +1,456 @@
+//! In-memory metrics rollups for the AgentConnection Durable Object
+//!
+//! Agents report a sample every few seconds. Rather than persisting each
+//! one, the Durable Object folds samples into 1m/5m/1h buckets and
+//! periodically drains them to D1. A drained rollup only covers the
+//! samples seen since the previous drain, so storage merges it into any
+//! existing row for the same bucket (sums and counts add, min/max widen).
+//! Pending buckets are mirrored to Durable Object storage between drains
+//! so they survive hibernation.
+
+use crate::models::rpc::MetricsPayload;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Rollup bucket width
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Resolution {
+    Minute,
+    FiveMinutes,
+    Hour,
+}
+
+impl Resolution {
+    /// All resolutions, finest first
+    pub const ALL: [Resolution; 3] = [
+        Resolution::Minute,
+        Resolution::FiveMinutes,
+        Resolution::Hour,
+    ];
+
+    /// Bucket width in seconds
+    pub fn secs(self) -> i64 {
+        match self {
+            Resolution::Minute => 60,
+            Resolution::FiveMinutes => 300,
+            Resolution::Hour => 3600,
+        }
+    }
+
+    /// How long buckets of this width are kept in D1
+    pub fn retention_secs(self) -> i64 {
+        match self {
+            Resolution::Minute => 2 * 86400,
+            Resolution::FiveMinutes => 14 * 86400,
+            Resolution::Hour => 90 * 86400,
+        }
+    }
+
+    /// Label stored in the `resolution` column
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Resolution::Minute => "1m",
+            Resolution::FiveMinutes => "5m",
+            Resolution::Hour => "1h",
+        }
+    }
+
+    /// Start of the bucket containing `timestamp`
+    pub fn bucket_start(self, timestamp: i64) -> i64 {
+        timestamp - timestamp.rem_euclid(self.secs())
+    }
+}
+
+/// Most points a history query returns
+pub const MAX_HISTORY_POINTS: i64 = 500;
+
+/// Choose the rollup resolution and point spacing for a history query.
+///
+/// Picks the coarsest resolution no wider than the requested step among
+/// those still retaining `from`, falling back to the finest such one (or
+/// hourly if the range predates every retention window). The step is then
+/// widened to a multiple of that resolution and to keep the range within
+/// `MAX_HISTORY_POINTS`.
+pub fn plan_history(
+    from: i64,
+    to: i64,
+    requested_step: Option<i64>,
+    now: i64,
+) -> (Resolution, i64) {
+    let span = (to - from).max(1);
+    let min_step = requested_step
+        .unwrap_or(0)
+        .max((span + MAX_HISTORY_POINTS - 1) / MAX_HISTORY_POINTS);
+
+    let retained: Vec<Resolution> = Resolution::ALL
+        .into_iter()
+        .filter(|r| from >= now - r.retention_secs())
+        .collect();
+    let resolution = retained
+        .iter()
+        .rev()
+        .find(|r| r.secs() <= min_step)
+        .or(retained.first())
+        .copied()
+        .unwrap_or(Resolution::Hour);
+
+    let width = resolution.secs();
+    let step = (min_step.max(width) + width - 1) / width * width;
+    (resolution, step)
+}
+
+/// Min/max/sum of a gauge over a bucket
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Stat {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub sum: f64,
+    pub count: u32,
+}
+
+impl Stat {
+    /// Fold one value into the statistic
+    pub fn add(&mut self, value: f64) {
+        self.min = Some(self.min.map_or(value, |m| m.min(value)));
+        self.max = Some(self.max.map_or(value, |m| m.max(value)));
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Combine with a statistic over other values in the same bucket
+    fn merge(&mut self, other: Stat) {
+        self.min = match (self.min, other.min) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.max = match (self.max, other.max) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+}
+
+/// Per-interface transfer rate sums over a bucket
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct InterfaceRollup {
+    pub samples: u32,
+    pub rx_rate_sum: u64,
+    pub rx_rate_max: u64,
+    pub tx_rate_sum: u64,
+    pub tx_rate_max: u64,
+}
+
+/// Aggregated metrics for one device over one bucket
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MetricsRollup {
+    pub resolution: Resolution,
+    pub bucket_start: i64,
+    pub samples: u32,
+    pub cpu: Stat,
+    pub memory: Stat,
+    /// Only counts samples that reported a temperature
+    pub temperature: Stat,
+    pub connections: Stat,
+    pub tcp_sum: u64,
+    pub udp_sum: u64,
+    /// DNS counters are cumulative, so the bucket keeps the highest value
+    pub dns_queries: u64,
+    pub dns_blocked: u64,
+    pub dns_cached: u64,
+    pub interfaces: BTreeMap<String, InterfaceRollup>,
+}
+
+impl MetricsRollup {
+    fn new(resolution: Resolution, bucket_start: i64) -> Self {
+        Self {
+            resolution,
+            bucket_start,
+            samples: 0,
+            cpu: Stat::default(),
+            memory: Stat::default(),
+            temperature: Stat::default(),
+            connections: Stat::default(),
+            tcp_sum: 0,
+            udp_sum: 0,
+            dns_queries: 0,
+            dns_blocked: 0,
+            dns_cached: 0,
+            interfaces: BTreeMap::new(),
+        }
+    }
+
+    fn add(&mut self, sample: &MetricsPayload) {
+        self.samples += 1;
+        self.cpu.add(sample.cpu as f64);
+        self.memory.add(sample.memory as f64);
+        if let Some(temperature) = sample.temperature {
+            self.temperature.add(temperature as f64);
+        }
+        self.connections.add(sample.connections.total as f64);
+        self.tcp_sum += sample.connections.tcp as u64;
+        self.udp_sum += sample.connections.udp as u64;
+        self.dns_queries = self.dns_queries.max(sample.dns.queries);
+        self.dns_blocked = self.dns_blocked.max(sample.dns.blocked);
+        self.dns_cached = self.dns_cached.max(sample.dns.cached);
+
+        for (name, rates) in &sample.interfaces {
+            let iface = self.interfaces.entry(name.clone()).or_default();
+            iface.samples += 1;
+            iface.rx_rate_sum += rates.rx_rate;
+            iface.rx_rate_max = iface.rx_rate_max.max(rates.rx_rate);
+            iface.tx_rate_sum += rates.tx_rate;
+            iface.tx_rate_max = iface.tx_rate_max.max(rates.tx_rate);
+        }
+    }
+
+    /// Combine with a rollup of other samples in the same bucket
+    fn merge(&mut self, other: MetricsRollup) {
+        self.samples += other.samples;
+        self.cpu.merge(other.cpu);
+        self.memory.merge(other.memory);
+        self.temperature.merge(other.temperature);
+        self.connections.merge(other.connections);
+        self.tcp_sum += other.tcp_sum;
+        self.udp_sum += other.udp_sum;
+        self.dns_queries = self.dns_queries.max(other.dns_queries);
+        self.dns_blocked = self.dns_blocked.max(other.dns_blocked);
+        self.dns_cached = self.dns_cached.max(other.dns_cached);
+
+        for (name, theirs) in other.interfaces {
+            let ours = self.interfaces.entry(name).or_default();
+            ours.samples += theirs.samples;
+            ours.rx_rate_sum += theirs.rx_rate_sum;
+            ours.rx_rate_max = ours.rx_rate_max.max(theirs.rx_rate_max);
+            ours.tx_rate_sum += theirs.tx_rate_sum;
+            ours.tx_rate_max = ours.tx_rate_max.max(theirs.tx_rate_max);
+        }
+    }
+}
+
+/// Pending rollups not yet written to D1
+#[derive(Debug, Default)]
+pub struct MetricsAggregator {
+    buckets: BTreeMap<(Resolution, i64), MetricsRollup>,
+}
+
+impl MetricsAggregator {
+    /// Fold a sample into every resolution's bucket
+    pub fn add(&mut self, sample: &MetricsPayload) {
+        for resolution in Resolution::ALL {
+            let start = resolution.bucket_start(sample.timestamp);
+            self.buckets
+                .entry((resolution, start))
+                .or_insert_with(|| MetricsRollup::new(resolution, start))
+                .add(sample);
+        }
+    }
+
+    /// Number of pending buckets
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// Copy of all pending rollups, e.g. to persist them
+    pub fn pending(&self) -> Vec<MetricsRollup> {
+        self.buckets.values().cloned().collect()
+    }
+
+    /// Take all pending rollups, leaving the aggregator empty
+    pub fn drain(&mut self) -> Vec<MetricsRollup> {
+        std::mem::take(&mut self.buckets).into_values().collect()
+    }
+
+    /// Put rollups back after a failed write so they are retried
+    pub fn restore(&mut self, rollups: Vec<MetricsRollup>) {
+        for rollup in rollups {
+            let key = (rollup.resolution, rollup.bucket_start);
+            match self.buckets.get_mut(&key) {
+                Some(existing) => existing.merge(rollup),
+                None => {
+                    self.buckets.insert(key, rollup);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::rpc::{ConnectionCounts, DnsMetrics, InterfaceRates};
+    use std::collections::HashMap;
+
+    fn sample(timestamp: i64, cpu: f32, rx_rate: u64) -> MetricsPayload {
+        let mut interfaces = HashMap::new();
+        interfaces.insert(
+            "eth0".to_string(),
+            InterfaceRates {
+                rx_rate,
+                tx_rate: rx_rate / 2,
+            },
+        );
+        MetricsPayload {
+            timestamp,
+            cpu,
+            memory: 40.0,
+            temperature: None,
+            interfaces,
+            connections: ConnectionCounts {
+                total: 10,
+                tcp: 7,
+                udp: 3,
+            },
+            dns: DnsMetrics {
+                queries: timestamp as u64,
+                blocked: 0,
+                cached: 0,
+            },
+            firewall_rules: Vec::new(),
+            countries: Vec::new(),
+            threat_feeds: Vec::new(),
+            upnp_leases: None,
+        }
+    }
+
+    fn find(rollups: &[MetricsRollup], resolution: Resolution, start: i64) -> &MetricsRollup {
+        rollups
+            .iter()
+            .find(|r| r.resolution == resolution && r.bucket_start == start)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_plan_history_uses_finest_resolution_for_short_ranges() {
+        let now = 1_000_000;
+        assert_eq!(
+            plan_history(now - 3_600, now, None, now),
+            (Resolution::Minute, 60)
+        );
+    }
+
+    #[test]
+    fn test_plan_history_honours_requested_step() {
+        let now = 1_000_000;
+        assert_eq!(
+            plan_history(now - 3_600, now, Some(600), now),
+            (Resolution::FiveMinutes, 600)
+        );
+        // Rounded up to a whole number of buckets
+        assert_eq!(
+            plan_history(now - 3_600, now, Some(90), now),
+            (Resolution::Minute, 120)
+        );
+    }
+
+    #[test]
+    fn test_plan_history_caps_point_count() {
+        let now = 10_000_000;
+        let from = now - 86_400;
+        let (resolution, step) = plan_history(from, now, Some(60), now);
+        assert!((now - from) / step <= MAX_HISTORY_POINTS);
+        assert_eq!(resolution, Resolution::Minute);
+        assert_eq!(step, 180);
+    }
+
+    #[test]
+    fn test_plan_history_skips_expired_resolutions() {
+        let now = 10_000_000;
+        // Older than the 1m retention but within 5m
+        let from = now - 3 * 86_400;
+        let (resolution, _) = plan_history(from, from + 3_600, None, now);
+        assert_eq!(resolution, Resolution::FiveMinutes);
+
+        // Older than every retention window
+        let from = now - 365 * 86_400;
+        let (resolution, step) = plan_history(from, from + 3_600, None, now);
+        assert_eq!((resolution, step), (Resolution::Hour, 3_600));
+    }
+
+    #[test]
+    fn test_bucket_start_aligns_to_width() {
+        assert_eq!(Resolution::Minute.bucket_start(3_725), 3_720);
+        assert_eq!(Resolution::FiveMinutes.bucket_start(3_725), 3_600);
+        assert_eq!(Resolution::Hour.bucket_start(3_725), 3_600);
+        assert_eq!(Resolution::Minute.bucket_start(-1), -60);
+    }
+
+    #[test]
+    fn test_samples_fold_into_every_resolution() {
+        let mut agg = MetricsAggregator::default();
+        agg.add(&sample(3_600, 10.0, 100));
+        agg.add(&sample(3_605, 30.0, 300));
+        agg.add(&sample(3_665, 20.0, 200));
+
+        let rollups = agg.drain();
+        assert!(agg.is_empty());
+
+        let minute = find(&rollups, Resolution::Minute, 3_600);
+        assert_eq!(minute.samples, 2);
+        assert_eq!(minute.cpu.min, Some(10.0));
+        assert_eq!(minute.cpu.max, Some(30.0));
+        assert_eq!(minute.cpu.sum, 40.0);
+        assert_eq!(minute.interfaces["eth0"].rx_rate_sum, 400);
+        assert_eq!(minute.interfaces["eth0"].rx_rate_max, 300);
+
+        let hour = find(&rollups, Resolution::Hour, 3_600);
+        assert_eq!(hour.samples, 3);
+        assert_eq!(hour.tcp_sum, 21);
+        assert_eq!(hour.dns_queries, 3_665);
+
+        // Two 1m buckets, one 5m, one 1h
+        assert_eq!(rollups.len(), 4);
+    }
+
+    #[test]
+    fn test_missing_temperature_does_not_skew_average() {
+        let mut agg = MetricsAggregator::default();
+        let mut hot = sample(0, 0.0, 0);
+        hot.temperature = Some(60.0);
+        agg.add(&hot);
+        agg.add(&sample(5, 0.0, 0));
+
+        let rollups = agg.drain();
+        let minute = find(&rollups, Resolution::Minute, 0);
+        assert_eq!(minute.samples, 2);
+        assert_eq!(minute.temperature.count, 1);
+        assert_eq!(minute.temperature.sum, 60.0);
+    }
+
+    #[test]
+    fn test_restore_merges_with_new_samples() {
+        let mut agg = MetricsAggregator::default();
+        agg.add(&sample(0, 50.0, 100));
+        let failed = agg.drain();
+
+        agg.add(&sample(10, 10.0, 300));
+        agg.restore(failed);
+
+        let rollups = agg.drain();
+        let minute = find(&rollups, Resolution::Minute, 0);
+        assert_eq!(minute.samples, 2);
+        assert_eq!(minute.cpu.min, Some(10.0));
+        assert_eq!(minute.cpu.max, Some(50.0));
+        assert_eq!(minute.interfaces["eth0"].samples, 2);
+        assert_eq!(minute.interfaces["eth0"].rx_rate_max, 300);
+    }
+
+    #[test]
+    fn test_pending_rollups_survive_serialization() {
+        let mut agg = MetricsAggregator::default();
+        agg.add(&sample(0, 50.0, 100));
+        agg.add(&sample(70, 10.0, 300));
+
+        let stored = serde_json::to_string(&agg.pending()).unwrap();
+        assert_eq!(agg.len(), 4);
+
+        let mut woken = MetricsAggregator::default();
+        woken.restore(serde_json::from_str(&stored).unwrap());
+        assert_eq!(woken.drain(), agg.drain());
+    }
+}
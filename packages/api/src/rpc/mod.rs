@@ -1,3 +1,6 @@
 //! RPC module for router agent communication
 
 pub mod agent_connection;
+pub mod metrics_rollup;
+pub mod rule_hits;
+pub mod upnp_leases;
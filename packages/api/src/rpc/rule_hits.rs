@@ -0,0 +1,313 @@
+//! Firewall rule hit tracking for the AgentConnection Durable Object
+//!
+//! Agents report the kernel packet counters of every firewall rule they
+//! installed with each metrics sample. Those counters restart whenever
+//! the rules are reinstalled, so the Durable Object keeps a running total
+//! per rule and records when it last grew, which is the rule's last hit.
+//!
+//! Scheduled rules are switched on the router itself, which reports each
+//! rule's window state in its status; that is merged the same way.
+//!
+//! Rules matching countries are also counted per country, and kept the same
+//! way for the `by_geo` breakdown of `/traffic/stats`.
+//!
+//! Drops by threat feeds are counted per feed. New drops raise an alert,
+//! at most one per feed every `FEED_ALERT_INTERVAL` seconds, covering every
+//! drop since the previous one.
+
+use crate::models::rpc::{CountryCounters, FeedCounters, RuleCounters, ScheduleState};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Accumulated hits of one firewall rule
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RuleHits {
+    /// Packets matched across reinstalls
+    pub packets: u64,
+    /// Bytes matched across reinstalls
+    pub bytes: u64,
+    /// Timestamp of the first sample in which the counters grew last
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_hit: Option<i64>,
+    /// Raw kernel counters from the previous sample
+    last_packets: u64,
+    last_bytes: u64,
+}
+
+/// Hits keyed by `FirewallRule` id
+pub type RuleHitMap = BTreeMap<u32, RuleHits>;
+
+/// Hits of country rules keyed by country code
+pub type CountryHitMap = BTreeMap<String, RuleHits>;
+
+/// Minimum seconds between two alerts of the same threat feed
+pub const FEED_ALERT_INTERVAL: i64 = 900;
+
+/// Accumulated drops of one threat feed and how many were alerted on
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeedHits {
+    #[serde(flatten)]
+    pub hits: RuleHits,
+    /// Totals when the last alert was raised
+    alerted_packets: u64,
+    alerted_bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alerted_at: Option<i64>,
+}
+
+/// Hits of threat feed drop rules keyed by `ThreatFeed` id
+pub type FeedHitMap = BTreeMap<u32, FeedHits>;
+
+/// Drops of a threat feed since its previous alert
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedAlert {
+    pub feed_id: u32,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// Fold one sample's counters into `hits`. Returns whether anything changed.
+///
+/// A counter lower than the previous sample means the rule was reinstalled
+/// and counting restarted from zero.
+pub fn record(hits: &mut RuleHitMap, counters: &[RuleCounters], timestamp: i64) -> bool {
+    counters.iter().fold(false, |changed, counter| {
+        let entry = hits.entry(counter.rule_id).or_default();
+        entry.fold(counter.packets, counter.bytes, timestamp) || changed
+    })
+}
+
+/// Fold one sample's per-country counters into `hits`, like [`record`]
+pub fn record_countries(
+    hits: &mut CountryHitMap,
+    counters: &[CountryCounters],
+    timestamp: i64,
+) -> bool {
+    counters.iter().fold(false, |changed, counter| {
+        let entry = hits.entry(counter.country.clone()).or_default();
+        entry.fold(counter.packets, counter.bytes, timestamp) || changed
+    })
+}
+
+/// Fold one sample's per-feed counters into `hits`, like [`record`].
+/// Returns whether anything changed and the feeds due for an alert.
+pub fn record_feeds(
+    hits: &mut FeedHitMap,
+    counters: &[FeedCounters],
+    timestamp: i64,
+) -> (bool, Vec<FeedAlert>) {
+    let mut changed = false;
+    for counter in counters {
+        let entry = hits.entry(counter.feed_id).or_default();
+        changed |= entry.hits.fold(counter.packets, counter.bytes, timestamp);
+    }
+
+    let mut alerts = Vec::new();
+    for (feed_id, feed) in hits.iter_mut() {
+        let quiet = feed
+            .alerted_at
+            .is_none_or(|at| timestamp - at >= FEED_ALERT_INTERVAL);
+        if feed.hits.packets > feed.alerted_packets && quiet {
+            alerts.push(FeedAlert {
+                feed_id: *feed_id,
+                packets: feed.hits.packets - feed.alerted_packets,
+                bytes: feed.hits.bytes.saturating_sub(feed.alerted_bytes),
+            });
+            feed.alerted_packets = feed.hits.packets;
+            feed.alerted_bytes = feed.hits.bytes;
+            feed.alerted_at = Some(timestamp);
+            changed = true;
+        }
+    }
+    (changed, alerts)
+}
+
+impl RuleHits {
+    /// Fold raw kernel counters into the totals
+    fn fold(&mut self, packets: u64, bytes: u64, timestamp: i64) -> bool {
+        let mut changed = false;
+        let (new_packets, new_bytes) = if packets >= self.last_packets {
+            (
+                packets - self.last_packets,
+                bytes.saturating_sub(self.last_bytes),
+            )
+        } else {
+            (packets, bytes)
+        };
+
+        if new_packets > 0 {
+            self.packets += new_packets;
+            self.bytes += new_bytes;
+            self.last_hit = Some(self.last_hit.map_or(timestamp, |t| t.max(timestamp)));
+            changed = true;
+        }
+        if self.last_packets != packets || self.last_bytes != bytes {
+            self.last_packets = packets;
+            self.last_bytes = bytes;
+            changed = true;
+        }
+        changed
+    }
+}
+
+/// Set `hits` and `last_hit` on stored firewall rules that have counters
+pub fn merge_into(rules: &mut [serde_json::Value], hits: &RuleHitMap) {
+    for rule in rules {
+        let Some(rule_hits) = rule
+            .get("id")
+            .and_then(|id| id.as_u64())
+            .and_then(|id| u32::try_from(id).ok())
+            .and_then(|id| hits.get(&id))
+        else {
+            continue;
+        };
+        rule["hits"] = serde_json::json!(rule_hits.packets);
+        if let Some(last_hit) = rule_hits.last_hit {
+            rule["last_hit"] = serde_json::json!(last_hit);
+        }
+    }
+}
+
+/// Set `schedule_active` on stored firewall rules the agent is scheduling
+pub fn merge_schedules(rules: &mut [serde_json::Value], states: &[ScheduleState]) {
+    for rule in rules {
+        let Some(state) = rule
+            .get("id")
+            .and_then(|id| id.as_u64())
+            .and_then(|id| states.iter().find(|s| u64::from(s.rule_id) == id))
+        else {
+            continue;
+        };
+        rule["schedule_active"] = serde_json::json!(state.active);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn counters(rule_id: u32, packets: u64) -> RuleCounters {
+        RuleCounters {
+            rule_id,
+            packets,
+            bytes: packets * 100,
+        }
+    }
+
+    #[test]
+    fn last_hit_moves_only_when_counters_grow() {
+        let mut hits = RuleHitMap::new();
+        assert!(record(&mut hits, &[counters(1, 5), counters(2, 0)], 100));
+        assert_eq!(hits[&1].packets, 5);
+        assert_eq!(hits[&1].last_hit, Some(100));
+        assert_eq!(hits[&2].last_hit, None);
+
+        // No new packets: nothing to persist
+        assert!(!record(&mut hits, &[counters(1, 5), counters(2, 0)], 105));
+        assert_eq!(hits[&1].last_hit, Some(100));
+
+        record(&mut hits, &[counters(1, 8)], 110);
+        assert_eq!(hits[&1].packets, 8);
+        assert_eq!(hits[&1].bytes, 800);
+        assert_eq!(hits[&1].last_hit, Some(110));
+    }
+
+    #[test]
+    fn reinstalled_rules_keep_their_total() {
+        let mut hits = RuleHitMap::new();
+        record(&mut hits, &[counters(1, 50)], 100);
+        // Counters restart after the rules are reinstalled
+        record(&mut hits, &[counters(1, 0)], 105);
+        assert_eq!(hits[&1].packets, 50);
+        record(&mut hits, &[counters(1, 3)], 110);
+        assert_eq!(hits[&1].packets, 53);
+        assert_eq!(hits[&1].last_hit, Some(110));
+    }
+
+    #[test]
+    fn countries_are_counted_like_rules() {
+        let counter = |packets| CountryCounters {
+            country: "NL".to_string(),
+            packets,
+            bytes: packets * 60,
+        };
+        let mut hits = CountryHitMap::new();
+        assert!(record_countries(&mut hits, &[counter(4)], 100));
+        record_countries(&mut hits, &[counter(1)], 105);
+        assert_eq!(hits["NL"].packets, 5);
+        assert_eq!(hits["NL"].bytes, 300);
+        assert!(!record_countries(&mut hits, &[counter(1)], 110));
+    }
+
+    #[test]
+    fn feed_drops_alert_at_most_once_per_interval() {
+        let counter = |packets| FeedCounters {
+            feed_id: 3,
+            packets,
+            bytes: packets * 40,
+        };
+        let mut hits = FeedHitMap::new();
+        assert!(record_feeds(&mut hits, &[counter(0)], 100).1.is_empty());
+
+        let (_, alerts) = record_feeds(&mut hits, &[counter(5)], 160);
+        assert_eq!(
+            alerts,
+            [FeedAlert {
+                feed_id: 3,
+                packets: 5,
+                bytes: 200
+            }]
+        );
+
+        // Further drops wait for the interval, then are reported together
+        let (_, alerts) = record_feeds(&mut hits, &[counter(7)], 220);
+        assert!(alerts.is_empty());
+        let (_, alerts) = record_feeds(&mut hits, &[counter(2)], 160 + FEED_ALERT_INTERVAL);
+        assert_eq!(alerts[0].packets, 4);
+        assert_eq!(hits[&3].hits.packets, 9);
+
+        let stored: FeedHitMap =
+            serde_json::from_value(serde_json::to_value(&hits).unwrap()).unwrap();
+        assert_eq!(stored, hits);
+    }
+
+    #[test]
+    fn hits_survive_a_storage_roundtrip() {
+        let mut hits = RuleHitMap::new();
+        record(&mut hits, &[counters(4, 2)], 100);
+        let stored: RuleHitMap =
+            serde_json::from_value(serde_json::to_value(&hits).unwrap()).unwrap();
+        assert_eq!(stored, hits);
+    }
+
+    #[test]
+    fn merges_hits_into_rules() {
+        let mut hits = RuleHitMap::new();
+        record(&mut hits, &[counters(1, 7), counters(2, 0)], 100);
+        let mut rules = vec![
+            json!({ "id": 1, "name": "a" }),
+            json!({ "id": 2, "name": "b" }),
+            json!({ "id": 3, "name": "c" }),
+        ];
+        merge_into(&mut rules, &hits);
+        assert_eq!(rules[0]["hits"], 7);
+        assert_eq!(rules[0]["last_hit"], 100);
+        assert_eq!(rules[1]["hits"], 0);
+        assert!(rules[1].get("last_hit").is_none());
+        assert!(rules[2].get("hits").is_none());
+    }
+
+    #[test]
+    fn merges_schedule_states_into_rules() {
+        let states = vec![ScheduleState {
+            rule_id: 2,
+            active: false,
+            since: 100,
+        }];
+        let mut rules = vec![json!({ "id": 1 }), json!({ "id": 2 })];
+        merge_schedules(&mut rules, &states);
+        assert!(rules[0].get("schedule_active").is_none());
+        assert_eq!(rules[1]["schedule_active"], false);
+    }
+}
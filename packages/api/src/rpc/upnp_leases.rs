@@ -0,0 +1,105 @@
+//! UPnP leases reported by agents
+//!
+//! Agents send the port mappings miniupnpd holds with every live metrics
+//! sample. The Durable Object keeps the latest list as [`UpnpLease`]s,
+//! remembering when each lease was first reported since miniupnpd does not
+//! record it. A lease is identified by `<protocol>:<external_port>`, the
+//! key miniupnpd itself uses, so the same id revokes it on the router.
+
+use crate::models::rpc::{UpnpMapping, UpnpRevoke};
+use crate::models::security::{Protocol, UpnpLease};
+
+/// Id of the lease of `external_port`, e.g. `tcp:8080`
+pub fn lease_id(protocol: &str, external_port: u16) -> String {
+    format!("{}:{}", protocol.to_ascii_lowercase(), external_port)
+}
+
+/// The mapping a lease id names
+pub fn parse_lease_id(id: &str) -> Option<UpnpRevoke> {
+    let (protocol, port) = id.split_once(':')?;
+    let protocol = protocol.to_ascii_lowercase();
+    if protocol != "tcp" && protocol != "udp" {
+        return None;
+    }
+    Some(UpnpRevoke {
+        protocol,
+        external_port: port.parse().ok()?,
+    })
+}
+
+/// Leases for the mappings an agent reported at `now`. Leases reported
+/// before keep their `created_at`; leases no longer reported are gone.
+pub fn merge(previous: &[UpnpLease], mappings: &[UpnpMapping], now: i64) -> Vec<UpnpLease> {
+    mappings
+        .iter()
+        .filter_map(|mapping| {
+            let protocol = match mapping.protocol.to_ascii_lowercase().as_str() {
+                "tcp" => Protocol::Tcp,
+                "udp" => Protocol::Udp,
+                _ => return None,
+            };
+            let id = lease_id(&mapping.protocol, mapping.external_port);
+            let created_at = previous
+                .iter()
+                .find(|lease| lease.id == id)
+                .map_or(now, |lease| lease.created_at);
+            Some(UpnpLease {
+                id,
+                protocol,
+                external_port: mapping.external_port,
+                internal_ip: mapping.internal_ip.clone(),
+                internal_port: mapping.internal_port,
+                description: mapping.description.clone(),
+                created_at,
+                expires_at: mapping.expires_at,
+                client: mapping.internal_ip.clone(),
+                conflicting_rule: None,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(protocol: &str, external_port: u16) -> UpnpMapping {
+        UpnpMapping {
+            protocol: protocol.to_string(),
+            external_port,
+            internal_ip: "192.168.1.20".to_string(),
+            internal_port: external_port,
+            description: "game console".to_string(),
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn test_leases_keep_when_they_were_first_reported() {
+        let first = merge(&[], &[mapping("tcp", 3074)], 100);
+        assert_eq!(first[0].id, "tcp:3074");
+        assert_eq!(first[0].created_at, 100);
+
+        let second = merge(&first, &[mapping("tcp", 3074), mapping("udp", 3074)], 160);
+        assert_eq!(second.len(), 2);
+        assert_eq!(second[0].created_at, 100);
+        assert_eq!(second[1].created_at, 160);
+        assert_eq!(second[1].protocol, Protocol::Udp);
+
+        assert!(merge(&second, &[], 220).is_empty());
+    }
+
+    #[test]
+    fn test_lease_ids_name_protocol_and_port() {
+        assert_eq!(
+            parse_lease_id("TCP:8080"),
+            Some(UpnpRevoke {
+                protocol: "tcp".to_string(),
+                external_port: 8080
+            })
+        );
+        assert_eq!(parse_lease_id("icmp:1"), None);
+        assert_eq!(parse_lease_id("tcp:99999"), None);
+        assert_eq!(parse_lease_id("8080"), None);
+    }
+}
@@ -3,6 +3,9 @@
 //! This module provides a unified interface for all storage operations.
 
 use crate::models::*;
+use crate::models::rpc::ScheduleState;
+use crate::rpc::metrics_rollup::{MAX_HISTORY_POINTS, MetricsRollup, Resolution};
+use crate::rpc::rule_hits::{CountryHitMap, RuleHitMap};
 use serde::{Serialize, de::DeserializeOwned};
 use worker::*;
 
@@ -29,11 +32,17 @@ pub async fn get_config<T: DeserializeOwned>(
     serde_json::from_str(&data).map_err(|_| ApiError::internal("Invalid config format"))
 }
 
-/// Update a configuration section for a device
+/// Update a configuration section for a device.
+///
+/// The section is first checked against the device's other sections (see
+/// `consistency`): conflicts reject the write, warnings are returned with
+/// the result. With an `expected` version (from `If-Match`) the write only
+/// goes through if the section is still at that version.
 pub async fn update_config<T: Serialize>(
     device_id: &str,
     section: &str,
     config: &T,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
     let kv = env
@@ -41,8 +50,12 @@ pub async fn update_config<T: Serialize>(
         .map_err(|_| ApiError::internal("Failed to access config store"))?;
 
     let key = format!("config:{}:{}", device_id, section);
-    let data = serde_json::to_string(config)
-        .map_err(|_| ApiError::internal("Failed to serialize config"))?;
+    let value =
+        serde_json::to_value(config).map_err(|_| ApiError::internal("Failed to serialize config"))?;
+    let warnings = crate::consistency::check_write(device_id, section, value.clone(), env).await?;
+    let data = value.to_string();
+
+    let version = bump_config_version(device_id, section, expected, env).await?;
 
     kv.put(&key, &data)
         .map_err(|_| ApiError::internal("Failed to store config"))?
@@ -50,10 +63,111 @@ pub async fn update_config<T: Serialize>(
         .await
         .map_err(|_| ApiError::internal("Failed to save config"))?;
 
-    // Push config to device
-    push_config_to_device(device_id, section, config, env).await?;
+    // Push config to device; firewall and NAT sections reach it compiled
+    if crate::agent_config::FIREWALL_SOURCES.contains(&section) {
+        push_firewall(device_id, Some((section, value)), env).await?;
+    } else if section == crate::agent_config::NAT_SOURCE {
+        let nat = crate::agent_config::NatSection {
+            rules: serde_json::from_value(value).unwrap_or_default(),
+        };
+        push_compiled_section(device_id, "nat", &nat, env).await?;
+    } else if !crate::agent_config::STORED_ONLY.contains(&section) {
+        push_config_to_device(device_id, section, config, version, env).await?;
+    }
+
+    let mut result = serde_json::json!({ "status": "updated", "version": version });
+    if !warnings.is_empty() {
+        result["warnings"] = serde_json::json!(warnings);
+    }
+    Ok(result)
+}
+
+/// Claim the next version of a configuration section.
+///
+/// The version check and the bump are a single D1 statement, so of two
+/// writers expecting the same version only one gets through; the other
+/// fails with `PRECONDITION_FAILED`. Without an `expected` version the
+/// section is bumped unconditionally.
+async fn bump_config_version(
+    device_id: &str,
+    section: &str,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<u64> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+    let now = (chrono::Utc::now().timestamp() as f64).into();
+
+    let stmt = match expected {
+        None => db
+            .prepare(
+                "INSERT INTO config_versions (device_id, section, version, updated_at) \
+                 VALUES (?, ?, 1, ?) \
+                 ON CONFLICT (device_id, section) DO UPDATE SET \
+                 version = version + 1, updated_at = excluded.updated_at \
+                 RETURNING version",
+            )
+            .bind(&[device_id.into(), section.into(), now]),
+        Some(0) => db
+            .prepare(
+                "INSERT INTO config_versions (device_id, section, version, updated_at) \
+                 VALUES (?, ?, 1, ?) \
+                 ON CONFLICT (device_id, section) DO NOTHING \
+                 RETURNING version",
+            )
+            .bind(&[device_id.into(), section.into(), now]),
+        Some(version) => db
+            .prepare(
+                "UPDATE config_versions SET version = version + 1, updated_at = ? \
+                 WHERE device_id = ? AND section = ? AND version = ? \
+                 RETURNING version",
+            )
+            .bind(&[
+                now,
+                device_id.into(),
+                section.into(),
+                (version as f64).into(),
+            ]),
+    }
+    .map_err(|_| ApiError::internal("Failed to prepare version update"))?;
+
+    let row = stmt
+        .first::<serde_json::Value>(None)
+        .await
+        .map_err(|_| ApiError::internal("Failed to update config version"))?;
+
+    match row.and_then(|r| r.get("version").and_then(|v| v.as_u64())) {
+        Some(version) => Ok(version),
+        None => {
+            let current = get_config_version(device_id, section, env).await?;
+            Err(ApiError::precondition_failed(
+                "Configuration was modified by another request",
+                current,
+            ))
+        }
+    }
+}
+
+/// Get the version of a configuration section, bumped on every write.
+///
+/// Sections that have never been written are at version 0.
+pub async fn get_config_version(device_id: &str, section: &str, env: &Env) -> ApiResult<u64> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let row = db
+        .prepare("SELECT version FROM config_versions WHERE device_id = ? AND section = ?")
+        .bind(&[device_id.into(), section.into()])
+        .map_err(|_| ApiError::internal("Failed to prepare query"))?
+        .first::<serde_json::Value>(None)
+        .await
+        .map_err(|_| ApiError::internal("Failed to read config version"))?;
 
-    Ok(serde_json::json!({ "status": "updated" }))
+    Ok(row
+        .and_then(|r| r.get("version").and_then(|v| v.as_u64()))
+        .unwrap_or(0))
 }
 
 /// Push configuration update to connected device
@@ -61,6 +175,7 @@ async fn push_config_to_device<T: Serialize>(
     device_id: &str,
     section: &str,
     config: &T,
+    version: u64,
     env: &Env,
 ) -> ApiResult<()> {
     let namespace = env
@@ -75,13 +190,7 @@ async fn push_config_to_device<T: Serialize>(
         .get_stub()
         .map_err(|_| ApiError::internal("Failed to get DO stub"))?;
 
-    let payload = serde_json::json!({
-        "type": "CONFIG_PUSH",
-        "payload": {
-            "section": section,
-            "config": config
-        }
-    });
+    let payload = crate::agent_config::push_message(section, config, version);
 
     let request = Request::new_with_init(
         "http://internal/command",
@@ -141,6 +250,72 @@ pub async fn send_command(
     Ok(serde_json::json!({ "status": "sent" }))
 }
 
+/// Result of an `EXEC` command, once the device has answered
+pub async fn get_exec_result(
+    device_id: &str,
+    command_id: &str,
+    env: &Env,
+) -> ApiResult<Option<ngfw_protocol::ExecResult>> {
+    let kv = env
+        .kv("CACHE")
+        .map_err(|_| ApiError::internal("Failed to access cache"))?;
+
+    let key = format!("exec_result:{}:{}", device_id, command_id);
+    let data = kv
+        .get(&key)
+        .text()
+        .await
+        .map_err(|_| ApiError::internal("Failed to read command result"))?;
+
+    data.map(|d| serde_json::from_str(&d))
+        .transpose()
+        .map_err(|_| ApiError::internal("Invalid command result format"))
+}
+
+/// Accumulated firewall rule hits reported by the device's agent
+pub async fn get_rule_hits(device_id: &str, env: &Env) -> ApiResult<RuleHitMap> {
+    let namespace = env
+        .durable_object("AGENT_CONNECTIONS")
+        .map_err(|_| ApiError::internal("Failed to access agent connections"))?;
+
+    let stub = namespace
+        .id_from_name(device_id)
+        .and_then(|id| id.get_stub())
+        .map_err(|_| ApiError::internal("Failed to get DO stub"))?;
+
+    let mut response = stub
+        .fetch_with_str("http://internal/firewall/hits")
+        .await
+        .map_err(|_| ApiError::internal("Failed to read rule hits"))?;
+
+    response
+        .json()
+        .await
+        .map_err(|_| ApiError::internal("Invalid rule hits format"))
+}
+
+/// Accumulated hits of the device's country rules, per country
+pub async fn get_country_hits(device_id: &str, env: &Env) -> ApiResult<CountryHitMap> {
+    let namespace = env
+        .durable_object("AGENT_CONNECTIONS")
+        .map_err(|_| ApiError::internal("Failed to access agent connections"))?;
+
+    let stub = namespace
+        .id_from_name(device_id)
+        .and_then(|id| id.get_stub())
+        .map_err(|_| ApiError::internal("Failed to get DO stub"))?;
+
+    let mut response = stub
+        .fetch_with_str("http://internal/firewall/hits/countries")
+        .await
+        .map_err(|_| ApiError::internal("Failed to read country hits"))?;
+
+    response
+        .json()
+        .await
+        .map_err(|_| ApiError::internal("Invalid country hits format"))
+}
+
 // ========== System Functions ==========
 
 pub async fn get_device_status(device_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
@@ -163,6 +338,16 @@ pub async fn get_device_status(device_id: &str, env: &Env) -> ApiResult<serde_js
     }
 }
 
+/// Firewall rule schedule states from the device's last reported status
+pub async fn get_schedule_states(device_id: &str, env: &Env) -> ApiResult<Vec<ScheduleState>> {
+    let status = get_device_status(device_id, env).await?;
+    Ok(status
+        .get("schedules")
+        .cloned()
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default())
+}
+
 pub async fn get_interfaces(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
     let status = get_device_status(device_id, env).await?;
     status
@@ -375,13 +560,14 @@ pub async fn get_vlans(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::
 pub async fn create_vlan(
     device_id: &str,
     vlan: &network::VlanConfig,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
     let mut vlans: Vec<serde_json::Value> = get_vlans(device_id, env).await.unwrap_or_default();
     vlans.push(
         serde_json::to_value(vlan).map_err(|_| ApiError::internal("Failed to serialize VLAN"))?,
     );
-    update_config(device_id, "vlans", &vlans, env).await?;
+    update_config(device_id, "vlans", &vlans, expected, env).await?;
     Ok(serde_json::json!({ "id": vlan.id, "status": "created" }))
 }
 
@@ -389,6 +575,7 @@ pub async fn update_vlan(
     device_id: &str,
     vlan_id: &str,
     vlan: &network::VlanConfig,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
     let mut vlans: Vec<serde_json::Value> = get_vlans(device_id, env).await?;
@@ -402,12 +589,13 @@ pub async fn update_vlan(
         *v = serde_json::to_value(vlan)
             .map_err(|_| ApiError::internal("Failed to serialize VLAN"))?;
     }
-    update_config(device_id, "vlans", &vlans, env).await
+    update_config(device_id, "vlans", &vlans, expected, env).await
 }
 
 pub async fn delete_vlan(
     device_id: &str,
     vlan_id: &str,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
     let mut vlans: Vec<serde_json::Value> = get_vlans(device_id, env).await?;
@@ -415,7 +603,7 @@ pub async fn delete_vlan(
         .parse()
         .map_err(|_| ApiError::bad_request("Invalid VLAN ID"))?;
     vlans.retain(|v| v.get("id").and_then(|x| x.as_u64()) != Some(id as u64));
-    update_config(device_id, "vlans", &vlans, env).await?;
+    update_config(device_id, "vlans", &vlans, expected, env).await?;
     Ok(serde_json::json!({ "status": "deleted" }))
 }
 
@@ -429,6 +617,7 @@ pub async fn update_wifi_radio(
     device_id: &str,
     _radio_id: &str,
     radio: &network::WifiRadio,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
     let mut radios: Vec<serde_json::Value> = get_wifi_radios(device_id, env).await?;
@@ -439,7 +628,7 @@ pub async fn update_wifi_radio(
         *r = serde_json::to_value(radio)
             .map_err(|_| ApiError::internal("Failed to serialize radio"))?;
     }
-    update_config(device_id, "wifi_radios", &radios, env).await
+    update_config(device_id, "wifi_radios", &radios, expected, env).await
 }
 
 pub async fn get_wifi_networks(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
@@ -449,6 +638,7 @@ pub async fn get_wifi_networks(device_id: &str, env: &Env) -> ApiResult<Vec<serd
 pub async fn create_wifi_network(
     device_id: &str,
     network_config: &network::WifiNetwork,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
     let mut networks: Vec<serde_json::Value> =
@@ -457,7 +647,7 @@ pub async fn create_wifi_network(
         serde_json::to_value(network_config)
             .map_err(|_| ApiError::internal("Failed to serialize network"))?,
     );
-    update_config(device_id, "wifi_networks", &networks, env).await?;
+    update_config(device_id, "wifi_networks", &networks, expected, env).await?;
     Ok(serde_json::json!({ "id": network_config.id, "status": "created" }))
 }
 
@@ -465,6 +655,7 @@ pub async fn update_wifi_network(
     device_id: &str,
     network_id: &str,
     network_config: &network::WifiNetwork,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
     let mut networks: Vec<serde_json::Value> = get_wifi_networks(device_id, env).await?;
@@ -478,12 +669,13 @@ pub async fn update_wifi_network(
         *n = serde_json::to_value(network_config)
             .map_err(|_| ApiError::internal("Failed to serialize network"))?;
     }
-    update_config(device_id, "wifi_networks", &networks, env).await
+    update_config(device_id, "wifi_networks", &networks, expected, env).await
 }
 
 pub async fn delete_wifi_network(
     device_id: &str,
     network_id: &str,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
     let mut networks: Vec<serde_json::Value> = get_wifi_networks(device_id, env).await?;
@@ -491,7 +683,7 @@ pub async fn delete_wifi_network(
         .parse()
         .map_err(|_| ApiError::bad_request("Invalid network ID"))?;
     networks.retain(|n| n.get("id").and_then(|x| x.as_u64()) != Some(id as u64));
-    update_config(device_id, "wifi_networks", &networks, env).await?;
+    update_config(device_id, "wifi_networks", &networks, expected, env).await?;
     Ok(serde_json::json!({ "status": "deleted" }))
 }
 
@@ -553,6 +745,7 @@ pub async fn get_dhcp_reservations(
 pub async fn create_dhcp_reservation(
     device_id: &str,
     reservation: &network::DhcpReservation,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
     let mut reservations: Vec<serde_json::Value> = get_dhcp_reservations(device_id, env)
@@ -562,18 +755,19 @@ pub async fn create_dhcp_reservation(
         serde_json::to_value(reservation)
             .map_err(|_| ApiError::internal("Failed to serialize reservation"))?,
     );
-    update_config(device_id, "dhcp_reservations", &reservations, env).await?;
+    update_config(device_id, "dhcp_reservations", &reservations, expected, env).await?;
     Ok(serde_json::json!({ "mac": reservation.mac, "status": "created" }))
 }
 
 pub async fn delete_dhcp_reservation(
     device_id: &str,
     mac: &str,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
     let mut reservations: Vec<serde_json::Value> = get_dhcp_reservations(device_id, env).await?;
     reservations.retain(|r| r.get("mac").and_then(|x| x.as_str()) != Some(mac));
-    update_config(device_id, "dhcp_reservations", &reservations, env).await?;
+    update_config(device_id, "dhcp_reservations", &reservations, expected, env).await?;
     Ok(serde_json::json!({ "status": "deleted" }))
 }
 
@@ -584,6 +778,7 @@ pub async fn get_routes(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json:
 pub async fn create_route(
     device_id: &str,
     route: &network::RouteRequest,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
     let mut routes: Vec<serde_json::Value> = get_routes(device_id, env).await.unwrap_or_default();
@@ -591,13 +786,14 @@ pub async fn create_route(
     let mut route_json = serde_json::to_value(route).unwrap();
     route_json["id"] = serde_json::json!(new_id.to_string());
     routes.push(route_json);
-    update_config(device_id, "routes", &routes, env).await?;
+    update_config(device_id, "routes", &routes, expected, env).await?;
     Ok(serde_json::json!({ "id": new_id, "status": "created" }))
 }
 pub async fn update_route(
     device_id: &str,
     route_id: &str,
     route: &network::RouteRequest,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
     let mut routes: Vec<serde_json::Value> = get_routes(device_id, env).await?;
@@ -608,16 +804,17 @@ pub async fn update_route(
         *r = serde_json::to_value(route).unwrap();
         r["id"] = serde_json::json!(route_id);
     }
-    update_config(device_id, "routes", &routes, env).await
+    update_config(device_id, "routes", &routes, expected, env).await
 }
 pub async fn delete_route(
     device_id: &str,
     route_id: &str,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
     let mut routes: Vec<serde_json::Value> = get_routes(device_id, env).await?;
     routes.retain(|r| r.get("id").and_then(|v| v.as_str()) != Some(route_id));
-    update_config(device_id, "routes", &routes, env).await?;
+    update_config(device_id, "routes", &routes, expected, env).await?;
     Ok(serde_json::json!({ "status": "deleted" }))
 }
 
@@ -629,22 +826,34 @@ pub async fn get_firewall_rules(device_id: &str, env: &Env) -> ApiResult<Vec<ser
 pub async fn create_firewall_rule(
     device_id: &str,
     rule: &security::FirewallRuleRequest,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
     let mut rules: Vec<serde_json::Value> =
         get_firewall_rules(device_id, env).await.unwrap_or_default();
-    let new_id = rules.len() as u32 + 1;
+    // Continue from the highest id so gaps left by deleted rules are not refilled
+    let new_id = rules
+        .iter()
+        .filter_map(|r| r.get("id").and_then(|v| v.as_u64()))
+        .max()
+        .unwrap_or(0) as u32
+        + 1;
     let mut rule_json = serde_json::to_value(rule).unwrap();
     rule_json["id"] = serde_json::json!(new_id);
     rules.push(rule_json);
-    update_config(device_id, "firewall_rules", &rules, env).await?;
-    Ok(serde_json::json!({ "id": new_id, "status": "created" }))
+    let result = update_config(device_id, "firewall_rules", &rules, expected, env).await?;
+    let mut response = serde_json::json!({ "id": new_id, "status": "created" });
+    if let Some(warnings) = result.get("warnings") {
+        response["warnings"] = warnings.clone();
+    }
+    Ok(response)
 }
 
 pub async fn update_firewall_rule(
     device_id: &str,
     rule_id: &str,
     rule: &security::FirewallRuleRequest,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
     let mut rules: Vec<serde_json::Value> = get_firewall_rules(device_id, env).await?;
@@ -658,12 +867,13 @@ pub async fn update_firewall_rule(
         *r = serde_json::to_value(rule).unwrap();
         r["id"] = serde_json::json!(id);
     }
-    update_config(device_id, "firewall_rules", &rules, env).await
+    update_config(device_id, "firewall_rules", &rules, expected, env).await
 }
 
 pub async fn delete_firewall_rule(
     device_id: &str,
     rule_id: &str,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
     let mut rules: Vec<serde_json::Value> = get_firewall_rules(device_id, env).await?;
@@ -671,13 +881,14 @@ pub async fn delete_firewall_rule(
         .parse()
         .map_err(|_| ApiError::bad_request("Invalid rule ID"))?;
     rules.retain(|r| r.get("id").and_then(|v| v.as_u64()) != Some(id as u64));
-    update_config(device_id, "firewall_rules", &rules, env).await?;
+    update_config(device_id, "firewall_rules", &rules, expected, env).await?;
     Ok(serde_json::json!({ "status": "deleted" }))
 }
 
 pub async fn reorder_firewall_rules(
     device_id: &str,
     order: &[u32],
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
     let rules: Vec<serde_json::Value> = get_firewall_rules(device_id, env).await?;
@@ -690,7 +901,7 @@ pub async fn reorder_firewall_rules(
             new_rules.push(rule.clone());
         }
     }
-    update_config(device_id, "firewall_rules", &new_rules, env).await
+    update_config(device_id, "firewall_rules", &new_rules, expected, env).await
 }
 
 pub async fn get_firewall_zones(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
@@ -701,6 +912,7 @@ pub async fn update_firewall_zone(
     device_id: &str,
     zone_id: &str,
     zone: &security::ZoneConfig,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
     let mut zones: Vec<serde_json::Value> = get_firewall_zones(device_id, env).await?;
@@ -711,7 +923,7 @@ pub async fn update_firewall_zone(
         *z = serde_json::to_value(zone)
             .map_err(|_| ApiError::internal("Failed to serialize zone"))?;
     }
-    update_config(device_id, "firewall_zones", &zones, env).await
+    update_config(device_id, "firewall_zones", &zones, expected, env).await
 }
 
 pub async fn get_zone_policies(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
@@ -721,1786 +933,4056 @@ pub async fn get_zone_policies(device_id: &str, env: &Env) -> ApiResult<Vec<serd
 pub async fn update_zone_policies(
     device_id: &str,
     policies: &[security::ZonePolicy],
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
     let policies_vec: Vec<serde_json::Value> = policies
         .iter()
         .filter_map(|p| serde_json::to_value(p).ok())
         .collect();
-    update_config(device_id, "zone_policies", &policies_vec, env).await
+    update_config(device_id, "zone_policies", &policies_vec, expected, env).await
 }
 
-// NAT - similar pattern
-pub async fn get_nat_rules(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
-    get_config(device_id, "nat_rules", env).await
-}
-pub async fn create_nat_rule(
-    device_id: &str,
-    rule: &security::NatRule,
-    env: &Env,
-) -> ApiResult<serde_json::Value> {
-    update_config(device_id, "nat_rules", rule, env).await
+// Object groups
+pub async fn get_address_groups(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
+    get_config(device_id, "address_groups", env).await
 }
-pub async fn update_nat_rule(
+
+pub async fn create_address_group(
     device_id: &str,
-    _rule_id: &str,
-    rule: &security::NatRule,
+    group: &security::AddressGroupRequest,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
-    update_config(device_id, "nat_rules", rule, env).await
-}
-pub async fn delete_nat_rule(
-    _device_id: &str,
-    _rule_id: &str,
-    _env: &Env,
-) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"status": "deleted"}))
+    let groups = get_address_groups(device_id, env).await.unwrap_or_default();
+    create_group(device_id, "address_groups", groups, group, expected, env).await
 }
-pub async fn get_upnp_leases(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
-    get_config(device_id, "upnp_leases", env).await
-}
-pub async fn revoke_upnp_lease(
+
+pub async fn update_address_group(
     device_id: &str,
-    lease_id: &str,
+    group_id: &str,
+    group: &security::AddressGroupRequest,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
-    send_command(
+    let groups = get_address_groups(device_id, env).await?;
+    update_group(
         device_id,
-        "REVOKE_UPNP",
-        Some(serde_json::json!({"id": lease_id})),
+        "address_groups",
+        groups,
+        group_id,
+        group,
+        expected,
         env,
     )
     .await
 }
 
-// Traffic
-pub async fn get_traffic_logs(
-    _device_id: &str,
-    _query: Option<&str>,
-    _env: &Env,
-) -> ApiResult<Vec<serde_json::Value>> {
-    Ok(vec![])
-}
-pub async fn get_traffic_stats(_device_id: &str, _env: &Env) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({}))
-}
-pub async fn get_top_clients(_device_id: &str, _env: &Env) -> ApiResult<Vec<serde_json::Value>> {
-    Ok(vec![])
-}
-pub async fn get_top_destinations(
-    _device_id: &str,
-    _env: &Env,
-) -> ApiResult<Vec<serde_json::Value>> {
-    Ok(vec![])
-}
-
-// DNS
-pub async fn get_dns_config(device_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
-    get_config(device_id, "dns", env).await
-}
-pub async fn update_dns_config(
+pub async fn delete_address_group(
     device_id: &str,
-    config: &security::DnsConfig,
+    group_id: &str,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
-    update_config(device_id, "dns", config, env).await
+    let groups = get_address_groups(device_id, env).await?;
+    delete_group(device_id, "address_groups", groups, group_id, expected, env).await
 }
-pub async fn get_dns_blocklists(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
-    get_config(device_id, "dns_blocklists", env).await
+
+pub async fn get_port_groups(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
+    get_config(device_id, "port_groups", env).await
 }
-pub async fn add_dns_blocklist(
+
+pub async fn create_port_group(
     device_id: &str,
-    blocklist: &security::DnsBlocklist,
+    group: &security::PortGroupRequest,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
-    update_config(device_id, "dns_blocklists", blocklist, env).await
-}
-pub async fn remove_dns_blocklist(
-    _device_id: &str,
-    _id: &str,
-    _env: &Env,
-) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"status": "removed"}))
+    let groups = get_port_groups(device_id, env).await.unwrap_or_default();
+    create_group(device_id, "port_groups", groups, group, expected, env).await
 }
-pub async fn force_blocklist_update(
+
+pub async fn update_port_group(
     device_id: &str,
-    id: &str,
+    group_id: &str,
+    group: &security::PortGroupRequest,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
-    send_command(
+    let groups = get_port_groups(device_id, env).await?;
+    update_group(
         device_id,
-        "UPDATE_BLOCKLIST",
-        Some(serde_json::json!({"id": id})),
+        "port_groups",
+        groups,
+        group_id,
+        group,
+        expected,
         env,
     )
     .await
 }
-pub async fn get_dns_allowlist(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
-    get_config(device_id, "dns_allowlist", env).await
-}
-pub async fn add_to_dns_allowlist(
+
+pub async fn delete_port_group(
     device_id: &str,
-    entry: &security::DnsAllowlistEntry,
+    group_id: &str,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
-    update_config(device_id, "dns_allowlist", entry, env).await
+    let groups = get_port_groups(device_id, env).await?;
+    delete_group(device_id, "port_groups", groups, group_id, expected, env).await
 }
-pub async fn remove_from_dns_allowlist(
-    _device_id: &str,
-    _domain: &str,
-    _env: &Env,
+
+/// Rules reference groups by id, so ids are never reused after a delete
+async fn create_group<T: Serialize>(
+    device_id: &str,
+    section: &str,
+    mut groups: Vec<serde_json::Value>,
+    group: &T,
+    expected: Option<u64>,
+    env: &Env,
 ) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"status": "removed"}))
-}
-pub async fn get_dns_queries(
-    _device_id: &str,
-    _query: Option<&str>,
-    _env: &Env,
-) -> ApiResult<Vec<serde_json::Value>> {
-    Ok(vec![])
-}
-pub async fn get_dns_stats(_device_id: &str, _env: &Env) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({}))
+    let new_id = groups
+        .iter()
+        .filter_map(|g| g.get("id").and_then(|v| v.as_u64()))
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let mut group_json = serde_json::to_value(group)
+        .map_err(|_| ApiError::internal("Failed to serialize group"))?;
+    group_json["id"] = serde_json::json!(new_id);
+    groups.push(group_json);
+    update_config(device_id, section, &groups, expected, env).await?;
+    Ok(serde_json::json!({ "id": new_id, "status": "created" }))
 }
 
-// IDS
-pub async fn get_ids_config(device_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
-    get_config(device_id, "ids", env).await
-}
-pub async fn update_ids_config(
+async fn update_group<T: Serialize>(
     device_id: &str,
-    config: &security::IdsConfig,
+    section: &str,
+    mut groups: Vec<serde_json::Value>,
+    group_id: &str,
+    group: &T,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
-    update_config(device_id, "ids", config, env).await
-}
-pub async fn get_ids_categories(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
-    get_config(device_id, "ids_categories", env).await
+    let id: u32 = group_id
+        .parse()
+        .map_err(|_| ApiError::bad_request("Invalid group ID"))?;
+    let Some(g) = groups
+        .iter_mut()
+        .find(|g| g.get("id").and_then(|v| v.as_u64()) == Some(id as u64))
+    else {
+        return Err(ApiError::not_found("Group"));
+    };
+    *g = serde_json::to_value(group)
+        .map_err(|_| ApiError::internal("Failed to serialize group"))?;
+    g["id"] = serde_json::json!(id);
+    update_config(device_id, section, &groups, expected, env).await
 }
-pub async fn update_ids_category(
+
+async fn delete_group(
     device_id: &str,
-    _id: &str,
-    category: &security::IdsCategory,
+    section: &str,
+    mut groups: Vec<serde_json::Value>,
+    group_id: &str,
+    expected: Option<u64>,
     env: &Env,
 ) -> ApiResult<serde_json::Value> {
-    update_config(device_id, "ids_categories", category, env).await
+    let id: u32 = group_id
+        .parse()
+        .map_err(|_| ApiError::bad_request("Invalid group ID"))?;
+    groups.retain(|g| g.get("id").and_then(|v| v.as_u64()) != Some(id as u64));
+    update_config(device_id, section, &groups, expected, env).await?;
+    Ok(serde_json::json!({ "status": "deleted" }))
 }
-pub async fn get_ids_rules(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
-    get_config(device_id, "ids_rules", env).await
+
+// GeoIP
+pub async fn get_geoip_dataset(
+    device_id: &str,
+    env: &Env,
+) -> ApiResult<security::GeoIpDataset> {
+    get_config(device_id, "geoip", env).await
 }
-pub async fn create_ids_rule(
+
+fn geoip_networks_key(hash: &str) -> String {
+    format!("geoip_networks:{}", hash)
+}
+
+/// Store an uploaded dataset. Networks are stored by content hash, so only
+/// countries whose networks changed are written; devices are pushed a new
+/// `firewall` section only if their rules use one of them.
+pub async fn upload_geoip_dataset(
     device_id: &str,
-    rule: &security::IdsRule,
+    dataset: &crate::geoip::Dataset,
     env: &Env,
-) -> ApiResult<serde_json::Value> {
-    update_config(device_id, "ids_rules", rule, env).await
+) -> ApiResult<security::GeoIpUploadResult> {
+    let kv = env
+        .kv("CONFIGS")
+        .map_err(|_| ApiError::internal("Failed to access config store"))?;
+
+    let old = get_geoip_dataset(device_id, env).await.ok();
+    let index = dataset.index(chrono::Utc::now().timestamp());
+    let changed = crate::geoip::changed(old.as_ref(), &index);
+
+    for code in &changed {
+        let Some(country) = dataset.countries.get(code) else {
+            continue;
+        };
+        let networks = serde_json::to_string(&country.networks)
+            .map_err(|_| ApiError::internal("Failed to serialize networks"))?;
+        kv.put(&geoip_networks_key(&country.hash), networks)
+            .map_err(|_| ApiError::internal("Failed to store networks"))?
+            .execute()
+            .await
+            .map_err(|_| ApiError::internal("Failed to save networks"))?;
+    }
+
+    let data = serde_json::to_string(&index)
+        .map_err(|_| ApiError::internal("Failed to serialize dataset"))?;
+    kv.put(&format!("config:{}:geoip", device_id), data)
+        .map_err(|_| ApiError::internal("Failed to store dataset"))?
+        .execute()
+        .await
+        .map_err(|_| ApiError::internal("Failed to save dataset"))?;
+
+    let rules: Vec<security::FirewallRule> = get_config(device_id, "firewall_rules", env)
+        .await
+        .unwrap_or_default();
+    if crate::geoip::referenced(&rules)
+        .iter()
+        .any(|code| changed.contains(code))
+    {
+        push_firewall(device_id, None, env).await?;
+    }
+
+    Ok(security::GeoIpUploadResult {
+        dataset: index,
+        changed,
+        skipped_ipv6: dataset.skipped_ipv6,
+    })
 }
-pub async fn delete_ids_rule(
-    _device_id: &str,
-    _id: &str,
-    _env: &Env,
-) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"status": "deleted"}))
+
+/// Networks of the given countries from the device's dataset; countries
+/// not in the dataset are left out
+pub async fn get_geoip_sets(
+    device_id: &str,
+    codes: &std::collections::BTreeSet<String>,
+    env: &Env,
+) -> ApiResult<Vec<security::GeoIpSet>> {
+    if codes.is_empty() {
+        return Ok(Vec::new());
+    }
+    let dataset = match get_geoip_dataset(device_id, env).await {
+        Ok(dataset) => dataset,
+        Err(e) if e.error.code == ErrorCode::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let kv = env
+        .kv("CONFIGS")
+        .map_err(|_| ApiError::internal("Failed to access config store"))?;
+
+    let mut sets = Vec::new();
+    for country in dataset.countries.iter().filter(|c| codes.contains(&c.code)) {
+        let networks = kv
+            .get(&geoip_networks_key(&country.hash))
+            .text()
+            .await
+            .map_err(|_| ApiError::internal("Failed to read networks"))?
+            .ok_or_else(|| ApiError::internal("GeoIP networks missing"))?;
+        sets.push(security::GeoIpSet {
+            code: country.code.clone(),
+            hash: country.hash.clone(),
+            networks: serde_json::from_str(&networks)
+                .map_err(|_| ApiError::internal("Invalid networks format"))?,
+        });
+    }
+    Ok(sets)
 }
-pub async fn get_ids_alerts(
-    _device_id: &str,
-    _query: Option<&str>,
-    _env: &Env,
-) -> ApiResult<Vec<serde_json::Value>> {
-    Ok(vec![])
+
+/// Compile the device's `firewall` section from its stored sections and
+/// push it, with the networks of every country its enabled rules use and
+/// of every enabled threat feed. `written` is a section just written, used
+/// in place of what KV returns for it.
+pub async fn push_firewall(
+    device_id: &str,
+    written: Option<(&str, serde_json::Value)>,
+    env: &Env,
+) -> ApiResult<()> {
+    let mut firewall = crate::agent_config::FirewallSection {
+        zones: get_firewall_zones(device_id, env).await.unwrap_or_default(),
+        rules: get_firewall_rules(device_id, env).await.unwrap_or_default(),
+        address_groups: get_address_groups(device_id, env).await.unwrap_or_default(),
+        port_groups: get_port_groups(device_id, env).await.unwrap_or_default(),
+        countries: Vec::new(),
+        threat_feeds: Vec::new(),
+    };
+    let mut feeds = None;
+    if let Some((section, value)) = written {
+        if section == "threat_feeds" {
+            feeds = serde_json::from_value(value).ok();
+        } else {
+            firewall.set(section, value);
+        }
+    }
+
+    let rules: Vec<security::FirewallRule> =
+        serde_json::from_value(serde_json::Value::Array(firewall.rules.clone()))
+            .unwrap_or_default();
+    firewall.countries = get_geoip_sets(device_id, &crate::geoip::referenced(&rules), env).await?;
+
+    let feeds = match feeds {
+        Some(feeds) => feeds,
+        None => get_threat_feeds(device_id, env).await.unwrap_or_default(),
+    };
+    firewall.threat_feeds = threat_feed_sets(&feeds, env).await?;
+    push_compiled_section(device_id, "firewall", &firewall, env).await
 }
 
-// Dashboards
-pub fn list_dashboards() -> ApiResult<Vec<serde_json::Value>> {
-    Ok(vec![
-        serde_json::json!({
-            "id": "network-overview",
-            "category": "network-overview",
-            "name": "Network Overview",
-            "description": "Real-time network status, connected devices, and bandwidth usage",
-            "icon": "network",
-            "default_view": true
-        }),
-        serde_json::json!({
-            "id": "security-events",
-            "category": "security-events",
-            "name": "Security Events",
-            "description": "IPS alerts, blocked threats, and security policy violations",
-            "icon": "shield",
-            "default_view": false
-        }),
-        serde_json::json!({
-            "id": "dns-analytics",
-            "category": "dns-analytics",
-            "name": "DNS Analytics",
-            "description": "DNS queries, top domains, blocked queries, and filtering statistics",
-            "icon": "dns",
-            "default_view": false
-        }),
-        serde_json::json!({
-            "id": "wifi-performance",
-            "category": "wifi-performance",
-            "name": "WiFi Performance",
-            "description": "WiFi signal strength, channel utilization, and client connections",
-            "icon": "wifi",
-            "default_view": false
-        }),
-        serde_json::json!({
-            "id": "wan-health",
-            "category": "wan-health",
-            "name": "WAN Health",
-            "description": "WAN uptime, latency, packet loss, and connection quality metrics",
-            "icon": "globe",
-            "default_view": false
-        }),
-        serde_json::json!({
-            "id": "vpn-metrics",
-            "category": "vpn-metrics",
-            "name": "VPN Metrics",
-            "description": "VPN tunnel status, throughput, connected clients, and data transfer",
-            "icon": "vpn",
-            "default_view": false
-        }),
-        serde_json::json!({
-            "id": "system-resources",
-            "category": "system-resources",
-            "name": "System Resources",
-            "description": "CPU usage, memory utilization, storage, and temperature monitoring",
-            "icon": "cpu",
-            "default_view": false
-        }),
-        serde_json::json!({
-            "id": "traffic-analysis",
-            "category": "traffic-analysis",
-            "name": "Traffic Analysis",
-            "description": "Protocol breakdown, application usage, and traffic patterns",
-            "icon": "chart",
-            "default_view": false
-        }),
-        serde_json::json!({
-            "id": "firewall-rules",
-            "category": "firewall-rules",
-            "name": "Firewall Rules",
-            "description": "Rule hit counts, blocked connections, and policy effectiveness",
-            "icon": "firewall",
-            "default_view": false
-        }),
-        serde_json::json!({
-            "id": "qos-metrics",
-            "category": "qos-metrics",
-            "name": "QoS Metrics",
-            "description": "Traffic shaping stats, bandwidth allocation, and queue performance",
-            "icon": "priority",
-            "default_view": false
-        }),
-    ])
+/// Bump the version of a section compiled from other sections and push it.
+/// Compiled sections are not stored; agents get them whole on every push.
+async fn push_compiled_section<T: Serialize>(
+    device_id: &str,
+    section: &str,
+    config: &T,
+    env: &Env,
+) -> ApiResult<()> {
+    let version = bump_config_version(device_id, section, None, env).await?;
+    push_config_to_device(device_id, section, config, version, env).await
 }
 
-pub fn get_dashboard(id: &str) -> ApiResult<serde_json::Value> {
-    let dashboards = get_all_dashboards_with_widgets();
-    dashboards
-        .get(id)
-        .cloned()
-        .ok_or_else(|| ApiError::not_found("Dashboard"))
+// Threat feeds
+pub async fn get_threat_feeds(
+    device_id: &str,
+    env: &Env,
+) -> ApiResult<Vec<security::ThreatFeed>> {
+    get_config(device_id, "threat_feeds", env).await
 }
 
-fn get_all_dashboards_with_widgets() -> std::collections::HashMap<&'static str, serde_json::Value> {
-    let mut map = std::collections::HashMap::new();
+pub async fn get_threat_feed(
+    device_id: &str,
+    feed_id: &str,
+    env: &Env,
+) -> ApiResult<security::ThreatFeed> {
+    let id: u32 = feed_id
+        .parse()
+        .map_err(|_| ApiError::bad_request("Invalid feed ID"))?;
+    get_threat_feeds(device_id, env)
+        .await?
+        .into_iter()
+        .find(|f| f.id == id)
+        .ok_or_else(|| ApiError::not_found("Threat feed"))
+}
 
-    map.insert("network-overview", serde_json::json!({
-        "id": "network-overview",
-        "category": "network-overview",
-        "name": "Network Overview",
-        "description": "Real-time network status, connected devices, and bandwidth usage",
-        "icon": "network",
-        "default_view": true,
-        "widgets": [
-            {
-                "id": "wan-status",
-                "type": "gauge",
-                "title": "WAN Status",
-                "data_source": "/api/network/wan/status",
-                "refresh_interval": 5000,
-                "config": { "unit": "Mbps", "max": 1000 }
-            },
-            {
-                "id": "connected-devices",
-                "type": "counter",
-                "title": "Connected Devices",
-                "data_source": "/api/network/devices",
-                "refresh_interval": 10000,
-                "config": { "icon": "devices" }
-            },
-            {
-                "id": "bandwidth-chart",
-                "type": "line-chart",
-                "title": "Bandwidth Usage (24h)",
-                "data_source": "/api/network/bandwidth/history",
-                "refresh_interval": 30000,
-                "config": { "timeRange": "24h", "yAxisUnit": "Mbps" }
-            },
-            {
-                "id": "top-talkers",
-                "type": "table",
-                "title": "Top Bandwidth Consumers",
-                "data_source": "/api/network/top-talkers",
-                "refresh_interval": 15000,
-                "config": { "columns": ["device", "ip", "bandwidth"], "limit": 10 }
-            }
-        ]
-    }));
+fn threat_feed_networks_key(hash: &str) -> String {
+    format!("threat_feed_networks:{}", hash)
+}
 
-    map.insert("security-events", serde_json::json!({
-        "id": "security-events",
-        "category": "security-events",
-        "name": "Security Events",
-        "description": "IPS alerts, blocked threats, and security policy violations",
-        "icon": "shield",
-        "default_view": false,
-        "widgets": [
-            {
-                "id": "threat-counter",
-                "type": "counter",
-                "title": "Threats Blocked (24h)",
-                "data_source": "/api/security/threats/count",
-                "refresh_interval": 10000,
-                "config": { "icon": "shield", "color": "red" }
-            },
-            {
-                "id": "threat-types",
-                "type": "pie-chart",
-                "title": "Threat Types",
-                "data_source": "/api/security/threats/by-type",
-                "refresh_interval": 30000,
-                "config": {}
-            },
-            {
-                "id": "recent-events",
-                "type": "table",
-                "title": "Recent Security Events",
-                "data_source": "/api/security/events/recent",
-                "refresh_interval": 5000,
-                "config": {
-                    "columns": ["timestamp", "type", "source", "action"],
-                    "limit": 20
-                }
-            },
-            {
-                "id": "attack-heatmap",
-                "type": "heatmap",
-                "title": "Attack Sources (Geo)",
-                "data_source": "/api/security/attacks/geo",
-                "refresh_interval": 60000,
-                "config": {}
-            }
-        ]
-    }));
+fn threat_feed_device_key(device_id: &str) -> String {
+    format!("threat_feed_device:{}", device_id)
+}
 
-    map.insert("dns-analytics", serde_json::json!({
-        "id": "dns-analytics",
-        "category": "dns-analytics",
-        "name": "DNS Analytics",
-        "description": "DNS queries, top domains, blocked queries, and filtering statistics",
-        "icon": "dns",
-        "default_view": false,
-        "widgets": [
-            {
-                "id": "dns-queries",
-                "type": "counter",
-                "title": "DNS Queries (24h)",
-                "data_source": "/api/dns/queries/count",
-                "refresh_interval": 10000,
-                "config": { "icon": "dns" }
-            },
-            {
-                "id": "blocked-queries",
-                "type": "counter",
-                "title": "Blocked Queries",
-                "data_source": "/api/dns/blocked/count",
-                "refresh_interval": 10000,
-                "config": { "icon": "block", "color": "red" }
-            },
-            {
-                "id": "top-domains",
-                "type": "bar-chart",
-                "title": "Top Queried Domains",
-                "data_source": "/api/dns/top-domains",
-                "refresh_interval": 30000,
-                "config": { "limit": 10 }
-            },
-            {
-                "id": "query-types",
-                "type": "pie-chart",
-                "title": "Query Types",
-                "data_source": "/api/dns/query-types",
-                "refresh_interval": 30000,
-                "config": {}
-            }
-        ]
-    }));
+/// Feeds reference their networks by content hash, so ids are never
+/// reused after a delete
+pub async fn create_threat_feed(
+    device_id: &str,
+    request: &security::ThreatFeedRequest,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<security::ThreatFeed> {
+    let mut feeds = get_threat_feeds(device_id, env).await.unwrap_or_default();
+    let feed = security::ThreatFeed {
+        id: feeds.iter().map(|f| f.id).max().unwrap_or(0) + 1,
+        name: request.name.clone(),
+        url: request.url.clone(),
+        format: request.format,
+        enabled: request.enabled,
+        update_interval: request.update_interval,
+        networks: 0,
+        version: None,
+        last_updated: None,
+        last_error: None,
+    };
+    feeds.push(feed.clone());
+    update_config(device_id, "threat_feeds", &feeds, expected, env).await?;
 
-    map.insert("wifi-performance", serde_json::json!({
-        "id": "wifi-performance",
-        "category": "wifi-performance",
-        "name": "WiFi Performance",
-        "description": "WiFi signal strength, channel utilization, and client connections",
-        "icon": "wifi",
-        "default_view": false,
-        "widgets": [
-            {
-                "id": "wifi-clients",
-                "type": "counter",
-                "title": "Connected Clients",
-                "data_source": "/api/wifi/clients/count",
-                "refresh_interval": 5000,
-                "config": { "icon": "wifi" }
-            },
-            {
-                "id": "channel-util",
-                "type": "gauge",
-                "title": "Channel Utilization",
-                "data_source": "/api/wifi/channel/utilization",
-                "refresh_interval": 10000,
-                "config": { "unit": "%", "max": 100 }
-            },
-            {
-                "id": "signal-strength",
-                "type": "bar-chart",
-                "title": "Signal Strength Distribution",
-                "data_source": "/api/wifi/signal/distribution",
-                "refresh_interval": 30000,
-                "config": {}
-            },
-            {
-                "id": "client-table",
-                "type": "table",
-                "title": "WiFi Clients",
-                "data_source": "/api/wifi/clients",
-                "refresh_interval": 10000,
-                "config": {
-                    "columns": ["device", "mac", "signal", "bandwidth"],
-                    "limit": 20
-                }
-            }
-        ]
-    }));
+    let kv = env
+        .kv("CONFIGS")
+        .map_err(|_| ApiError::internal("Failed to access config store"))?;
+    kv.put(&threat_feed_device_key(device_id), "1")
+        .map_err(|_| ApiError::internal("Failed to store feed index"))?
+        .execute()
+        .await
+        .map_err(|_| ApiError::internal("Failed to save feed index"))?;
+    Ok(feed)
+}
 
-    map.insert("wan-health", serde_json::json!({
-        "id": "wan-health",
-        "category": "wan-health",
-        "name": "WAN Health",
-        "description": "WAN uptime, latency, packet loss, and connection quality metrics",
-        "icon": "globe",
-        "default_view": false,
-        "widgets": [
-            {
-                "id": "wan-uptime",
-                "type": "gauge",
-                "title": "WAN Uptime",
-                "data_source": "/api/wan/uptime",
-                "refresh_interval": 30000,
-                "config": { "unit": "%", "max": 100 }
-            },
-            {
-                "id": "latency",
-                "type": "line-chart",
-                "title": "Latency (24h)",
-                "data_source": "/api/wan/latency/history",
-                "refresh_interval": 30000,
-                "config": { "yAxisUnit": "ms" }
-            },
-            {
-                "id": "packet-loss",
-                "type": "line-chart",
-                "title": "Packet Loss",
-                "data_source": "/api/wan/packet-loss",
-                "refresh_interval": 30000,
-                "config": { "yAxisUnit": "%" }
-            },
-            {
-                "id": "speed-test",
-                "type": "gauge",
-                "title": "Current Speed",
-                "data_source": "/api/wan/speed",
-                "refresh_interval": 60000,
-                "config": { "unit": "Mbps", "max": 1000 }
-            }
-        ]
-    }));
+/// Update a feed's subscription settings. Returns the feed and whether its
+/// source changed, in which case its networks are stale.
+pub async fn update_threat_feed(
+    device_id: &str,
+    feed_id: &str,
+    request: &security::ThreatFeedRequest,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<(security::ThreatFeed, bool)> {
+    let id: u32 = feed_id
+        .parse()
+        .map_err(|_| ApiError::bad_request("Invalid feed ID"))?;
+    let mut feeds = get_threat_feeds(device_id, env).await?;
+    let Some(feed) = feeds.iter_mut().find(|f| f.id == id) else {
+        return Err(ApiError::not_found("Threat feed"));
+    };
+    let source_changed = feed.url != request.url || feed.format != request.format;
+    let enabled_changed = feed.enabled != request.enabled;
+    feed.name = request.name.clone();
+    feed.url = request.url.clone();
+    feed.format = request.format;
+    feed.enabled = request.enabled;
+    feed.update_interval = request.update_interval;
+    let feed = feed.clone();
+    update_config(device_id, "threat_feeds", &feeds, expected, env).await?;
+    if enabled_changed {
+        push_threat_feeds(device_id, &feeds, env).await?;
+    }
+    Ok((feed, source_changed))
+}
 
-    map.insert("vpn-metrics", serde_json::json!({
-        "id": "vpn-metrics",
-        "category": "vpn-metrics",
-        "name": "VPN Metrics",
-        "description": "VPN tunnel status, throughput, connected clients, and data transfer",
-        "icon": "vpn",
-        "default_view": false,
-        "widgets": [
-            {
-                "id": "vpn-clients",
-                "type": "counter",
-                "title": "Connected VPN Clients",
-                "data_source": "/api/vpn/clients/count",
-                "refresh_interval": 10000,
-                "config": { "icon": "vpn" }
-            },
-            {
-                "id": "vpn-throughput",
-                "type": "line-chart",
-                "title": "VPN Throughput",
-                "data_source": "/api/vpn/throughput",
-                "refresh_interval": 10000,
-                "config": { "yAxisUnit": "Mbps" }
-            },
-            {
-                "id": "vpn-clients-table",
-                "type": "table",
-                "title": "VPN Clients",
-                "data_source": "/api/vpn/clients",
-                "refresh_interval": 15000,
-                "config": {
-                    "columns": ["user", "ip", "connected_at", "data_sent", "data_received"],
-                    "limit": 20
-                }
-            }
-        ]
-    }));
+pub async fn delete_threat_feed(
+    device_id: &str,
+    feed_id: &str,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    let id: u32 = feed_id
+        .parse()
+        .map_err(|_| ApiError::bad_request("Invalid feed ID"))?;
+    let mut feeds = get_threat_feeds(device_id, env).await?;
+    feeds.retain(|f| f.id != id);
+    update_config(device_id, "threat_feeds", &feeds, expected, env).await?;
+    push_threat_feeds(device_id, &feeds, env).await?;
 
-    map.insert("system-resources", serde_json::json!({
-        "id": "system-resources",
-        "category": "system-resources",
-        "name": "System Resources",
-        "description": "CPU usage, memory utilization, storage, and temperature monitoring",
-        "icon": "cpu",
-        "default_view": false,
-        "widgets": [
-            {
-                "id": "cpu-usage",
-                "type": "gauge",
-                "title": "CPU Usage",
-                "data_source": "/api/system/cpu",
-                "refresh_interval": 5000,
-                "config": { "unit": "%", "max": 100 }
-            },
-            {
-                "id": "memory-usage",
-                "type": "gauge",
-                "title": "Memory Usage",
-                "data_source": "/api/system/memory",
-                "refresh_interval": 5000,
-                "config": { "unit": "%", "max": 100 }
-            },
-            {
-                "id": "temperature",
-                "type": "gauge",
-                "title": "Temperature",
-                "data_source": "/api/system/temperature",
-                "refresh_interval": 10000,
-                "config": { "unit": "\u{00b0}C", "max": 100 }
-            },
-            {
-                "id": "storage",
-                "type": "gauge",
-                "title": "Storage Usage",
-                "data_source": "/api/system/storage",
-                "refresh_interval": 60000,
-                "config": { "unit": "%", "max": 100 }
-            }
-        ]
-    }));
+    if feeds.is_empty() {
+        let kv = env
+            .kv("CONFIGS")
+            .map_err(|_| ApiError::internal("Failed to access config store"))?;
+        let _ = kv.delete(&threat_feed_device_key(device_id)).await;
+    }
+    Ok(serde_json::json!({ "status": "deleted" }))
+}
 
-    map.insert("traffic-analysis", serde_json::json!({
-        "id": "traffic-analysis",
-        "category": "traffic-analysis",
-        "name": "Traffic Analysis",
-        "description": "Protocol breakdown, application usage, and traffic patterns",
-        "icon": "chart",
-        "default_view": false,
-        "widgets": [
-            {
-                "id": "protocol-breakdown",
-                "type": "pie-chart",
-                "title": "Protocol Breakdown",
-                "data_source": "/api/traffic/protocols",
-                "refresh_interval": 30000,
-                "config": {}
-            },
-            {
-                "id": "app-usage",
-                "type": "bar-chart",
-                "title": "Top Applications",
-                "data_source": "/api/traffic/applications",
-                "refresh_interval": 30000,
-                "config": { "limit": 10 }
-            },
-            {
-                "id": "traffic-history",
-                "type": "line-chart",
-                "title": "Traffic History (7d)",
-                "data_source": "/api/traffic/history",
-                "refresh_interval": 60000,
-                "config": { "timeRange": "7d", "yAxisUnit": "GB" }
-            }
-        ]
-    }));
+/// Record the outcome of fetching a feed: its new networks, or the error
+/// that kept the previous ones in use. Networks are stored by content hash
+/// and devices are only pushed new sets when the hash changed.
+pub async fn record_threat_feed_fetch(
+    device_id: &str,
+    feed_id: u32,
+    fetched: Result<crate::threat_feeds::Compiled, String>,
+    env: &Env,
+) -> ApiResult<security::ThreatFeed> {
+    let mut feeds = get_threat_feeds(device_id, env).await?;
+    let Some(feed) = feeds.iter_mut().find(|f| f.id == feed_id) else {
+        return Err(ApiError::not_found("Threat feed"));
+    };
 
-    map.insert("firewall-rules", serde_json::json!({
-        "id": "firewall-rules",
-        "category": "firewall-rules",
-        "name": "Firewall Rules",
-        "description": "Rule hit counts, blocked connections, and policy effectiveness",
-        "icon": "firewall",
-        "default_view": false,
-        "widgets": [
-            {
-                "id": "blocked-connections",
-                "type": "counter",
-                "title": "Blocked Connections (24h)",
-                "data_source": "/api/firewall/blocked/count",
-                "refresh_interval": 10000,
-                "config": { "icon": "block", "color": "red" }
-            },
-            {
-                "id": "rule-hits",
-                "type": "table",
-                "title": "Top Firewall Rules",
-                "data_source": "/api/firewall/rules/hits",
-                "refresh_interval": 30000,
-                "config": { "columns": ["rule", "hits", "action"], "limit": 20 }
-            },
-            {
-                "id": "block-reasons",
-                "type": "pie-chart",
-                "title": "Block Reasons",
-                "data_source": "/api/firewall/block-reasons",
-                "refresh_interval": 30000,
-                "config": {}
+    let mut changed = false;
+    match fetched {
+        Ok(compiled) => {
+            if feed.version.as_deref() != Some(compiled.hash.as_str()) {
+                let kv = env
+                    .kv("CONFIGS")
+                    .map_err(|_| ApiError::internal("Failed to access config store"))?;
+                let networks = serde_json::to_string(&compiled.networks)
+                    .map_err(|_| ApiError::internal("Failed to serialize networks"))?;
+                kv.put(&threat_feed_networks_key(&compiled.hash), networks)
+                    .map_err(|_| ApiError::internal("Failed to store networks"))?
+                    .execute()
+                    .await
+                    .map_err(|_| ApiError::internal("Failed to save networks"))?;
+                changed = true;
             }
-        ]
-    }));
+            feed.networks = compiled.networks.len() as u32;
+            feed.version = Some(compiled.hash);
+            feed.last_error = None;
+        }
+        Err(e) => feed.last_error = Some(e),
+    }
+    feed.last_updated = Some(chrono::Utc::now().timestamp());
+    let feed = feed.clone();
+    update_config(device_id, "threat_feeds", &feeds, None, env).await?;
+    if changed && feed.enabled {
+        push_threat_feeds(device_id, &feeds, env).await?;
+    }
+    Ok(feed)
+}
 
-    map.insert("qos-metrics", serde_json::json!({
-        "id": "qos-metrics",
-        "category": "qos-metrics",
-        "name": "QoS Metrics",
-        "description": "Traffic shaping stats, bandwidth allocation, and queue performance",
-        "icon": "priority",
-        "default_view": false,
-        "widgets": [
-            {
-                "id": "bandwidth-allocation",
-                "type": "pie-chart",
-                "title": "Bandwidth Allocation",
-                "data_source": "/api/qos/bandwidth",
+/// Networks of the enabled feeds that have been fetched
+async fn threat_feed_sets(
+    feeds: &[security::ThreatFeed],
+    env: &Env,
+) -> ApiResult<Vec<security::ThreatFeedSet>> {
+    let kv = env
+        .kv("CONFIGS")
+        .map_err(|_| ApiError::internal("Failed to access config store"))?;
+
+    let mut sets = Vec::new();
+    for feed in feeds.iter().filter(|f| f.enabled) {
+        let Some(hash) = &feed.version else {
+            continue;
+        };
+        let networks = kv
+            .get(&threat_feed_networks_key(hash))
+            .text()
+            .await
+            .map_err(|_| ApiError::internal("Failed to read networks"))?
+            .ok_or_else(|| ApiError::internal("Threat feed networks missing"))?;
+        sets.push(security::ThreatFeedSet {
+            id: feed.id,
+            hash: hash.clone(),
+            networks: serde_json::from_str(&networks)
+                .map_err(|_| ApiError::internal("Invalid networks format"))?,
+        });
+    }
+    Ok(sets)
+}
+
+/// Push the `firewall` section with the networks of the device's feeds
+/// as just written
+async fn push_threat_feeds(
+    device_id: &str,
+    feeds: &[security::ThreatFeed],
+    env: &Env,
+) -> ApiResult<()> {
+    let feeds = serde_json::to_value(feeds)
+        .map_err(|_| ApiError::internal("Failed to serialize threat feeds"))?;
+    push_firewall(device_id, Some(("threat_feeds", feeds)), env).await
+}
+
+/// Devices subscribed to at least one threat feed
+pub async fn list_threat_feed_devices(env: &Env) -> ApiResult<Vec<String>> {
+    let kv = env
+        .kv("CONFIGS")
+        .map_err(|_| ApiError::internal("Failed to access config store"))?;
+    let prefix = threat_feed_device_key("");
+    let list = kv
+        .list()
+        .prefix(prefix.clone())
+        .execute()
+        .await
+        .map_err(|_| ApiError::internal("Failed to list threat feed devices"))?;
+    Ok(list
+        .keys
+        .into_iter()
+        .map(|key| key.name[prefix.len()..].to_string())
+        .collect())
+}
+
+// NAT
+pub async fn get_nat_rules(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
+    get_config(device_id, "nat_rules", env).await
+}
+
+pub async fn create_nat_rule(
+    device_id: &str,
+    rule: &security::NatRule,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    let mut rules = get_nat_rules(device_id, env).await.unwrap_or_default();
+    let new_id = rules
+        .iter()
+        .filter_map(|r| r.get("id").and_then(|v| v.as_u64()))
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let mut rule_json = serde_json::to_value(rule).unwrap();
+    rule_json["id"] = serde_json::json!(new_id);
+    rules.push(rule_json);
+    let result = update_config(device_id, "nat_rules", &rules, expected, env).await?;
+    let mut response = serde_json::json!({ "id": new_id, "status": "created" });
+    if let Some(warnings) = result.get("warnings") {
+        response["warnings"] = warnings.clone();
+    }
+    Ok(response)
+}
+
+pub async fn update_nat_rule(
+    device_id: &str,
+    rule_id: &str,
+    rule: &security::NatRule,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    let mut rules = get_nat_rules(device_id, env).await?;
+    let id: u32 = rule_id
+        .parse()
+        .map_err(|_| ApiError::bad_request("Invalid rule ID"))?;
+    let Some(r) = rules
+        .iter_mut()
+        .find(|r| r.get("id").and_then(|v| v.as_u64()) == Some(id as u64))
+    else {
+        return Err(ApiError::not_found("NAT rule"));
+    };
+    *r = serde_json::to_value(rule).unwrap();
+    r["id"] = serde_json::json!(id);
+    update_config(device_id, "nat_rules", &rules, expected, env).await
+}
+
+pub async fn delete_nat_rule(
+    device_id: &str,
+    rule_id: &str,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    let mut rules = get_nat_rules(device_id, env).await?;
+    let id: u32 = rule_id
+        .parse()
+        .map_err(|_| ApiError::bad_request("Invalid rule ID"))?;
+    rules.retain(|r| r.get("id").and_then(|v| v.as_u64()) != Some(id as u64));
+    update_config(device_id, "nat_rules", &rules, expected, env).await?;
+    Ok(serde_json::json!({ "status": "deleted" }))
+}
+
+/// UPnP leases the device reported with its last metrics
+pub async fn get_reported_upnp_leases(
+    device_id: &str,
+    env: &Env,
+) -> ApiResult<Vec<security::UpnpLease>> {
+    let namespace = env
+        .durable_object("AGENT_CONNECTIONS")
+        .map_err(|_| ApiError::internal("Failed to access agent connections"))?;
+
+    let stub = namespace
+        .id_from_name(device_id)
+        .and_then(|id| id.get_stub())
+        .map_err(|_| ApiError::internal("Failed to get DO stub"))?;
+
+    let mut response = stub
+        .fetch_with_str("http://internal/nat/upnp")
+        .await
+        .map_err(|_| ApiError::internal("Failed to read UPnP leases"))?;
+
+    response
+        .json()
+        .await
+        .map_err(|_| ApiError::internal("Invalid UPnP leases format"))
+}
+
+/// Reported UPnP leases, each with the port forward claiming its port
+pub async fn get_upnp_leases(device_id: &str, env: &Env) -> ApiResult<Vec<security::UpnpLease>> {
+    let mut leases = get_reported_upnp_leases(device_id, env).await?;
+    let rules: Vec<security::NatRule> = get_config(device_id, "nat_rules", env)
+        .await
+        .unwrap_or_default();
+    for lease in &mut leases {
+        lease.conflicting_rule = rules
+            .iter()
+            .find(|rule| crate::consistency::claims_upnp_port(rule, lease))
+            .map(|rule| rule.id);
+    }
+    Ok(leases)
+}
+
+/// Revoke a UPnP lease on the device; the lease disappears from the list
+/// with the device's next metrics
+pub async fn revoke_upnp_lease(
+    device_id: &str,
+    lease_id: &str,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    let revoke = crate::rpc::upnp_leases::parse_lease_id(lease_id)
+        .ok_or_else(|| ApiError::bad_request("Invalid UPnP lease ID"))?;
+    let leases = get_reported_upnp_leases(device_id, env).await?;
+    if !leases.iter().any(|lease| lease.id == lease_id.to_ascii_lowercase()) {
+        return Err(ApiError::not_found("UPnP lease"));
+    }
+    send_command(
+        device_id,
+        "REVOKE_UPNP",
+        Some(serde_json::json!(revoke)),
+        env,
+    )
+    .await
+}
+
+// Traffic
+pub async fn get_traffic_logs(
+    _device_id: &str,
+    _query: Option<&str>,
+    _env: &Env,
+) -> ApiResult<Vec<serde_json::Value>> {
+    Ok(vec![])
+}
+/// Traffic statistics. Only the `by_geo` breakdown is tracked so far, from
+/// the hits of country rules.
+pub async fn get_traffic_stats(device_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
+    let hits = get_country_hits(device_id, env).await?;
+    let dataset = get_geoip_dataset(device_id, env).await.ok();
+    Ok(serde_json::json!({
+        "by_geo": crate::geoip::stats(&hits, dataset.as_ref())
+    }))
+}
+pub async fn get_top_clients(_device_id: &str, _env: &Env) -> ApiResult<Vec<serde_json::Value>> {
+    Ok(vec![])
+}
+pub async fn get_top_destinations(
+    _device_id: &str,
+    _env: &Env,
+) -> ApiResult<Vec<serde_json::Value>> {
+    Ok(vec![])
+}
+
+// DNS
+pub async fn get_dns_config(device_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
+    get_config(device_id, "dns", env).await
+}
+pub async fn update_dns_config(
+    device_id: &str,
+    config: &security::DnsConfig,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    update_config(device_id, "dns", config, expected, env).await
+}
+pub async fn get_dns_blocklists(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
+    get_config(device_id, "dns_blocklists", env).await
+}
+pub async fn add_dns_blocklist(
+    device_id: &str,
+    blocklist: &security::DnsBlocklist,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    update_config(device_id, "dns_blocklists", blocklist, expected, env).await
+}
+pub async fn remove_dns_blocklist(
+    _device_id: &str,
+    _id: &str,
+    _env: &Env,
+) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"status": "removed"}))
+}
+pub async fn force_blocklist_update(
+    device_id: &str,
+    id: &str,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    send_command(
+        device_id,
+        "UPDATE_BLOCKLIST",
+        Some(serde_json::json!({"id": id})),
+        env,
+    )
+    .await
+}
+pub async fn get_dns_allowlist(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
+    get_config(device_id, "dns_allowlist", env).await
+}
+pub async fn add_to_dns_allowlist(
+    device_id: &str,
+    entry: &security::DnsAllowlistEntry,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    update_config(device_id, "dns_allowlist", entry, expected, env).await
+}
+pub async fn remove_from_dns_allowlist(
+    _device_id: &str,
+    _domain: &str,
+    _env: &Env,
+) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"status": "removed"}))
+}
+pub async fn get_dns_queries(
+    _device_id: &str,
+    _query: Option<&str>,
+    _env: &Env,
+) -> ApiResult<Vec<serde_json::Value>> {
+    Ok(vec![])
+}
+pub async fn get_dns_stats(_device_id: &str, _env: &Env) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({}))
+}
+
+// IDS
+pub async fn get_ids_config(device_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
+    get_config(device_id, "ids", env).await
+}
+pub async fn update_ids_config(
+    device_id: &str,
+    config: &security::IdsConfig,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    update_config(device_id, "ids", config, expected, env).await
+}
+pub async fn get_ids_categories(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
+    get_config(device_id, "ids_categories", env).await
+}
+pub async fn update_ids_category(
+    device_id: &str,
+    _id: &str,
+    category: &security::IdsCategory,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    update_config(device_id, "ids_categories", category, expected, env).await
+}
+pub async fn get_ids_rules(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
+    get_config(device_id, "ids_rules", env).await
+}
+pub async fn create_ids_rule(
+    device_id: &str,
+    rule: &security::IdsRule,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    update_config(device_id, "ids_rules", rule, expected, env).await
+}
+pub async fn delete_ids_rule(
+    _device_id: &str,
+    _id: &str,
+    _env: &Env,
+) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"status": "deleted"}))
+}
+pub async fn get_ids_alerts(
+    _device_id: &str,
+    _query: Option<&str>,
+    _env: &Env,
+) -> ApiResult<Vec<serde_json::Value>> {
+    Ok(vec![])
+}
+
+// Dashboards
+pub fn list_dashboards() -> ApiResult<Vec<serde_json::Value>> {
+    Ok(vec![
+        serde_json::json!({
+            "id": "network-overview",
+            "category": "network-overview",
+            "name": "Network Overview",
+            "description": "Real-time network status, connected devices, and bandwidth usage",
+            "icon": "network",
+            "default_view": true
+        }),
+        serde_json::json!({
+            "id": "security-events",
+            "category": "security-events",
+            "name": "Security Events",
+            "description": "IPS alerts, blocked threats, and security policy violations",
+            "icon": "shield",
+            "default_view": false
+        }),
+        serde_json::json!({
+            "id": "dns-analytics",
+            "category": "dns-analytics",
+            "name": "DNS Analytics",
+            "description": "DNS queries, top domains, blocked queries, and filtering statistics",
+            "icon": "dns",
+            "default_view": false
+        }),
+        serde_json::json!({
+            "id": "wifi-performance",
+            "category": "wifi-performance",
+            "name": "WiFi Performance",
+            "description": "WiFi signal strength, channel utilization, and client connections",
+            "icon": "wifi",
+            "default_view": false
+        }),
+        serde_json::json!({
+            "id": "wan-health",
+            "category": "wan-health",
+            "name": "WAN Health",
+            "description": "WAN uptime, latency, packet loss, and connection quality metrics",
+            "icon": "globe",
+            "default_view": false
+        }),
+        serde_json::json!({
+            "id": "vpn-metrics",
+            "category": "vpn-metrics",
+            "name": "VPN Metrics",
+            "description": "VPN tunnel status, throughput, connected clients, and data transfer",
+            "icon": "vpn",
+            "default_view": false
+        }),
+        serde_json::json!({
+            "id": "system-resources",
+            "category": "system-resources",
+            "name": "System Resources",
+            "description": "CPU usage, memory utilization, storage, and temperature monitoring",
+            "icon": "cpu",
+            "default_view": false
+        }),
+        serde_json::json!({
+            "id": "traffic-analysis",
+            "category": "traffic-analysis",
+            "name": "Traffic Analysis",
+            "description": "Protocol breakdown, application usage, and traffic patterns",
+            "icon": "chart",
+            "default_view": false
+        }),
+        serde_json::json!({
+            "id": "firewall-rules",
+            "category": "firewall-rules",
+            "name": "Firewall Rules",
+            "description": "Rule hit counts, blocked connections, and policy effectiveness",
+            "icon": "firewall",
+            "default_view": false
+        }),
+        serde_json::json!({
+            "id": "qos-metrics",
+            "category": "qos-metrics",
+            "name": "QoS Metrics",
+            "description": "Traffic shaping stats, bandwidth allocation, and queue performance",
+            "icon": "priority",
+            "default_view": false
+        }),
+    ])
+}
+
+pub fn get_dashboard(id: &str) -> ApiResult<serde_json::Value> {
+    let dashboards = get_all_dashboards_with_widgets();
+    dashboards
+        .get(id)
+        .cloned()
+        .ok_or_else(|| ApiError::not_found("Dashboard"))
+}
+
+fn get_all_dashboards_with_widgets() -> std::collections::HashMap<&'static str, serde_json::Value> {
+    let mut map = std::collections::HashMap::new();
+
+    map.insert("network-overview", serde_json::json!({
+        "id": "network-overview",
+        "category": "network-overview",
+        "name": "Network Overview",
+        "description": "Real-time network status, connected devices, and bandwidth usage",
+        "icon": "network",
+        "default_view": true,
+        "widgets": [
+            {
+                "id": "wan-status",
+                "type": "gauge",
+                "title": "WAN Status",
+                "data_source": "/api/network/wan/status",
+                "refresh_interval": 5000,
+                "config": { "unit": "Mbps", "max": 1000 }
+            },
+            {
+                "id": "connected-devices",
+                "type": "counter",
+                "title": "Connected Devices",
+                "data_source": "/api/network/devices",
+                "refresh_interval": 10000,
+                "config": { "icon": "devices" }
+            },
+            {
+                "id": "bandwidth-chart",
+                "type": "line-chart",
+                "title": "Bandwidth Usage (24h)",
+                "data_source": "/api/network/bandwidth/history",
+                "refresh_interval": 30000,
+                "config": { "timeRange": "24h", "yAxisUnit": "Mbps" }
+            },
+            {
+                "id": "top-talkers",
+                "type": "table",
+                "title": "Top Bandwidth Consumers",
+                "data_source": "/api/network/top-talkers",
+                "refresh_interval": 15000,
+                "config": { "columns": ["device", "ip", "bandwidth"], "limit": 10 }
+            }
+        ]
+    }));
+
+    map.insert("security-events", serde_json::json!({
+        "id": "security-events",
+        "category": "security-events",
+        "name": "Security Events",
+        "description": "IPS alerts, blocked threats, and security policy violations",
+        "icon": "shield",
+        "default_view": false,
+        "widgets": [
+            {
+                "id": "threat-counter",
+                "type": "counter",
+                "title": "Threats Blocked (24h)",
+                "data_source": "/api/security/threats/count",
+                "refresh_interval": 10000,
+                "config": { "icon": "shield", "color": "red" }
+            },
+            {
+                "id": "threat-types",
+                "type": "pie-chart",
+                "title": "Threat Types",
+                "data_source": "/api/security/threats/by-type",
+                "refresh_interval": 30000,
+                "config": {}
+            },
+            {
+                "id": "recent-events",
+                "type": "table",
+                "title": "Recent Security Events",
+                "data_source": "/api/security/events/recent",
+                "refresh_interval": 5000,
+                "config": {
+                    "columns": ["timestamp", "type", "source", "action"],
+                    "limit": 20
+                }
+            },
+            {
+                "id": "attack-heatmap",
+                "type": "heatmap",
+                "title": "Attack Sources (Geo)",
+                "data_source": "/api/security/attacks/geo",
+                "refresh_interval": 60000,
+                "config": {}
+            }
+        ]
+    }));
+
+    map.insert("dns-analytics", serde_json::json!({
+        "id": "dns-analytics",
+        "category": "dns-analytics",
+        "name": "DNS Analytics",
+        "description": "DNS queries, top domains, blocked queries, and filtering statistics",
+        "icon": "dns",
+        "default_view": false,
+        "widgets": [
+            {
+                "id": "dns-queries",
+                "type": "counter",
+                "title": "DNS Queries (24h)",
+                "data_source": "/api/dns/queries/count",
+                "refresh_interval": 10000,
+                "config": { "icon": "dns" }
+            },
+            {
+                "id": "blocked-queries",
+                "type": "counter",
+                "title": "Blocked Queries",
+                "data_source": "/api/dns/blocked/count",
+                "refresh_interval": 10000,
+                "config": { "icon": "block", "color": "red" }
+            },
+            {
+                "id": "top-domains",
+                "type": "bar-chart",
+                "title": "Top Queried Domains",
+                "data_source": "/api/dns/top-domains",
+                "refresh_interval": 30000,
+                "config": { "limit": 10 }
+            },
+            {
+                "id": "query-types",
+                "type": "pie-chart",
+                "title": "Query Types",
+                "data_source": "/api/dns/query-types",
+                "refresh_interval": 30000,
+                "config": {}
+            }
+        ]
+    }));
+
+    map.insert("wifi-performance", serde_json::json!({
+        "id": "wifi-performance",
+        "category": "wifi-performance",
+        "name": "WiFi Performance",
+        "description": "WiFi signal strength, channel utilization, and client connections",
+        "icon": "wifi",
+        "default_view": false,
+        "widgets": [
+            {
+                "id": "wifi-clients",
+                "type": "counter",
+                "title": "Connected Clients",
+                "data_source": "/api/wifi/clients/count",
+                "refresh_interval": 5000,
+                "config": { "icon": "wifi" }
+            },
+            {
+                "id": "channel-util",
+                "type": "gauge",
+                "title": "Channel Utilization",
+                "data_source": "/api/wifi/channel/utilization",
+                "refresh_interval": 10000,
+                "config": { "unit": "%", "max": 100 }
+            },
+            {
+                "id": "signal-strength",
+                "type": "bar-chart",
+                "title": "Signal Strength Distribution",
+                "data_source": "/api/wifi/signal/distribution",
+                "refresh_interval": 30000,
+                "config": {}
+            },
+            {
+                "id": "client-table",
+                "type": "table",
+                "title": "WiFi Clients",
+                "data_source": "/api/wifi/clients",
+                "refresh_interval": 10000,
+                "config": {
+                    "columns": ["device", "mac", "signal", "bandwidth"],
+                    "limit": 20
+                }
+            }
+        ]
+    }));
+
+    map.insert("wan-health", serde_json::json!({
+        "id": "wan-health",
+        "category": "wan-health",
+        "name": "WAN Health",
+        "description": "WAN uptime, latency, packet loss, and connection quality metrics",
+        "icon": "globe",
+        "default_view": false,
+        "widgets": [
+            {
+                "id": "wan-uptime",
+                "type": "gauge",
+                "title": "WAN Uptime",
+                "data_source": "/api/wan/uptime",
+                "refresh_interval": 30000,
+                "config": { "unit": "%", "max": 100 }
+            },
+            {
+                "id": "latency",
+                "type": "line-chart",
+                "title": "Latency (24h)",
+                "data_source": "/api/wan/latency/history",
+                "refresh_interval": 30000,
+                "config": { "yAxisUnit": "ms" }
+            },
+            {
+                "id": "packet-loss",
+                "type": "line-chart",
+                "title": "Packet Loss",
+                "data_source": "/api/wan/packet-loss",
+                "refresh_interval": 30000,
+                "config": { "yAxisUnit": "%" }
+            },
+            {
+                "id": "speed-test",
+                "type": "gauge",
+                "title": "Current Speed",
+                "data_source": "/api/wan/speed",
+                "refresh_interval": 60000,
+                "config": { "unit": "Mbps", "max": 1000 }
+            }
+        ]
+    }));
+
+    map.insert("vpn-metrics", serde_json::json!({
+        "id": "vpn-metrics",
+        "category": "vpn-metrics",
+        "name": "VPN Metrics",
+        "description": "VPN tunnel status, throughput, connected clients, and data transfer",
+        "icon": "vpn",
+        "default_view": false,
+        "widgets": [
+            {
+                "id": "vpn-clients",
+                "type": "counter",
+                "title": "Connected VPN Clients",
+                "data_source": "/api/vpn/clients/count",
+                "refresh_interval": 10000,
+                "config": { "icon": "vpn" }
+            },
+            {
+                "id": "vpn-throughput",
+                "type": "line-chart",
+                "title": "VPN Throughput",
+                "data_source": "/api/vpn/throughput",
+                "refresh_interval": 10000,
+                "config": { "yAxisUnit": "Mbps" }
+            },
+            {
+                "id": "vpn-clients-table",
+                "type": "table",
+                "title": "VPN Clients",
+                "data_source": "/api/vpn/clients",
+                "refresh_interval": 15000,
+                "config": {
+                    "columns": ["user", "ip", "connected_at", "data_sent", "data_received"],
+                    "limit": 20
+                }
+            }
+        ]
+    }));
+
+    map.insert("system-resources", serde_json::json!({
+        "id": "system-resources",
+        "category": "system-resources",
+        "name": "System Resources",
+        "description": "CPU usage, memory utilization, storage, and temperature monitoring",
+        "icon": "cpu",
+        "default_view": false,
+        "widgets": [
+            {
+                "id": "cpu-usage",
+                "type": "gauge",
+                "title": "CPU Usage",
+                "data_source": "/api/system/cpu",
+                "refresh_interval": 5000,
+                "config": { "unit": "%", "max": 100 }
+            },
+            {
+                "id": "memory-usage",
+                "type": "gauge",
+                "title": "Memory Usage",
+                "data_source": "/api/system/memory",
+                "refresh_interval": 5000,
+                "config": { "unit": "%", "max": 100 }
+            },
+            {
+                "id": "temperature",
+                "type": "gauge",
+                "title": "Temperature",
+                "data_source": "/api/system/temperature",
+                "refresh_interval": 10000,
+                "config": { "unit": "\u{00b0}C", "max": 100 }
+            },
+            {
+                "id": "storage",
+                "type": "gauge",
+                "title": "Storage Usage",
+                "data_source": "/api/system/storage",
+                "refresh_interval": 60000,
+                "config": { "unit": "%", "max": 100 }
+            }
+        ]
+    }));
+
+    map.insert("traffic-analysis", serde_json::json!({
+        "id": "traffic-analysis",
+        "category": "traffic-analysis",
+        "name": "Traffic Analysis",
+        "description": "Protocol breakdown, application usage, and traffic patterns",
+        "icon": "chart",
+        "default_view": false,
+        "widgets": [
+            {
+                "id": "protocol-breakdown",
+                "type": "pie-chart",
+                "title": "Protocol Breakdown",
+                "data_source": "/api/traffic/protocols",
+                "refresh_interval": 30000,
+                "config": {}
+            },
+            {
+                "id": "app-usage",
+                "type": "bar-chart",
+                "title": "Top Applications",
+                "data_source": "/api/traffic/applications",
+                "refresh_interval": 30000,
+                "config": { "limit": 10 }
+            },
+            {
+                "id": "traffic-history",
+                "type": "line-chart",
+                "title": "Traffic History (7d)",
+                "data_source": "/api/traffic/history",
+                "refresh_interval": 60000,
+                "config": { "timeRange": "7d", "yAxisUnit": "GB" }
+            }
+        ]
+    }));
+
+    map.insert("firewall-rules", serde_json::json!({
+        "id": "firewall-rules",
+        "category": "firewall-rules",
+        "name": "Firewall Rules",
+        "description": "Rule hit counts, blocked connections, and policy effectiveness",
+        "icon": "firewall",
+        "default_view": false,
+        "widgets": [
+            {
+                "id": "blocked-connections",
+                "type": "counter",
+                "title": "Blocked Connections (24h)",
+                "data_source": "/api/firewall/blocked/count",
+                "refresh_interval": 10000,
+                "config": { "icon": "block", "color": "red" }
+            },
+            {
+                "id": "rule-hits",
+                "type": "table",
+                "title": "Top Firewall Rules",
+                "data_source": "/api/firewall/rules/hits",
+                "refresh_interval": 30000,
+                "config": { "columns": ["rule", "hits", "action"], "limit": 20 }
+            },
+            {
+                "id": "block-reasons",
+                "type": "pie-chart",
+                "title": "Block Reasons",
+                "data_source": "/api/firewall/block-reasons",
+                "refresh_interval": 30000,
+                "config": {}
+            }
+        ]
+    }));
+
+    map.insert("qos-metrics", serde_json::json!({
+        "id": "qos-metrics",
+        "category": "qos-metrics",
+        "name": "QoS Metrics",
+        "description": "Traffic shaping stats, bandwidth allocation, and queue performance",
+        "icon": "priority",
+        "default_view": false,
+        "widgets": [
+            {
+                "id": "bandwidth-allocation",
+                "type": "pie-chart",
+                "title": "Bandwidth Allocation",
+                "data_source": "/api/qos/bandwidth",
+                "refresh_interval": 10000,
+                "config": {}
+            },
+            {
+                "id": "queue-stats",
+                "type": "bar-chart",
+                "title": "Queue Statistics",
+                "data_source": "/api/qos/queues",
                 "refresh_interval": 10000,
                 "config": {}
             },
-            {
-                "id": "queue-stats",
-                "type": "bar-chart",
-                "title": "Queue Statistics",
-                "data_source": "/api/qos/queues",
-                "refresh_interval": 10000,
-                "config": {}
+            {
+                "id": "priority-traffic",
+                "type": "line-chart",
+                "title": "Priority Traffic",
+                "data_source": "/api/qos/priority",
+                "refresh_interval": 15000,
+                "config": { "yAxisUnit": "Mbps" }
+            }
+        ]
+    }));
+
+    map
+}
+
+// VPN Server
+pub async fn get_vpn_server_config(device_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
+    get_config(device_id, "vpn_server", env).await
+}
+pub async fn update_vpn_server_config(
+    device_id: &str,
+    config: &services::VpnServerConfig,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    update_config(device_id, "vpn_server", config, expected, env).await
+}
+pub async fn get_vpn_peers(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
+    get_config(device_id, "vpn_peers", env).await
+}
+pub async fn create_vpn_peer(
+    device_id: &str,
+    peer: &services::VpnPeerRequest,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    update_config(device_id, "vpn_peers", peer, expected, env).await
+}
+pub async fn update_vpn_peer(
+    device_id: &str,
+    _id: &str,
+    peer: &services::VpnPeerRequest,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    update_config(device_id, "vpn_peers", peer, expected, env).await
+}
+pub async fn delete_vpn_peer(
+    _device_id: &str,
+    _id: &str,
+    _env: &Env,
+) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"status": "deleted"}))
+}
+pub async fn get_vpn_peer_qr(
+    _device_id: &str,
+    _id: &str,
+    _env: &Env,
+) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({}))
+}
+pub async fn get_vpn_server_status(device_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
+    get_device_status(device_id, env).await
+}
+
+// VPN Client
+pub async fn get_vpn_client_profiles(
+    device_id: &str,
+    env: &Env,
+) -> ApiResult<Vec<serde_json::Value>> {
+    get_config(device_id, "vpn_profiles", env).await
+}
+pub async fn create_vpn_client_profile(
+    device_id: &str,
+    profile: &services::VpnClientProfile,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    update_config(device_id, "vpn_profiles", profile, expected, env).await
+}
+pub async fn update_vpn_client_profile(
+    device_id: &str,
+    _id: &str,
+    profile: &services::VpnClientProfile,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    update_config(device_id, "vpn_profiles", profile, expected, env).await
+}
+pub async fn delete_vpn_client_profile(
+    _device_id: &str,
+    _id: &str,
+    _env: &Env,
+) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"status": "deleted"}))
+}
+pub async fn connect_vpn_client(
+    device_id: &str,
+    id: &str,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    send_command(
+        device_id,
+        "VPN_CONNECT",
+        Some(serde_json::json!({"profile_id": id})),
+        env,
+    )
+    .await
+}
+pub async fn disconnect_vpn_client(
+    device_id: &str,
+    id: &str,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    send_command(
+        device_id,
+        "VPN_DISCONNECT",
+        Some(serde_json::json!({"profile_id": id})),
+        env,
+    )
+    .await
+}
+pub async fn get_vpn_client_status(_device_id: &str, _env: &Env) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"connected": false}))
+}
+
+// QoS
+pub async fn get_qos_config(device_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
+    get_config(device_id, "qos", env).await
+}
+pub async fn update_qos_config(
+    device_id: &str,
+    config: &services::QosConfig,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    update_config(device_id, "qos", config, expected, env).await
+}
+pub async fn get_traffic_classes(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
+    get_config(device_id, "traffic_classes", env).await
+}
+pub async fn create_traffic_class(
+    device_id: &str,
+    class: &services::TrafficClass,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    update_config(device_id, "traffic_classes", class, expected, env).await
+}
+pub async fn update_traffic_class(
+    device_id: &str,
+    _id: &str,
+    class: &services::TrafficClass,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    update_config(device_id, "traffic_classes", class, expected, env).await
+}
+pub async fn delete_traffic_class(
+    _device_id: &str,
+    _id: &str,
+    _env: &Env,
+) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"status": "deleted"}))
+}
+pub async fn get_device_bandwidth_limits(
+    device_id: &str,
+    env: &Env,
+) -> ApiResult<Vec<serde_json::Value>> {
+    get_config(device_id, "device_limits", env).await
+}
+pub async fn set_device_bandwidth_limit(
+    device_id: &str,
+    _mac: &str,
+    limit: &services::DeviceLimit,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    update_config(device_id, "device_limits", limit, expected, env).await
+}
+pub async fn remove_device_bandwidth_limit(
+    _device_id: &str,
+    _mac: &str,
+    _env: &Env,
+) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"status": "removed"}))
+}
+
+// DDNS
+pub async fn get_ddns_config(device_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
+    get_config(device_id, "ddns", env).await
+}
+pub async fn update_ddns_config(
+    device_id: &str,
+    config: &services::DdnsConfig,
+    expected: Option<u64>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    update_config(device_id, "ddns", config, expected, env).await
+}
+pub async fn force_ddns_update(device_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
+    send_command(device_id, "DDNS_UPDATE", None, env).await
+}
+pub async fn get_ddns_status(_device_id: &str, _env: &Env) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"status": "unknown"}))
+}
+
+// User
+pub async fn get_user_profile(user_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
+    let kv = env
+        .kv("SESSIONS")
+        .map_err(|_| ApiError::internal("Failed to access sessions"))?;
+    let data = kv
+        .get(&format!("user:{}", user_id))
+        .text()
+        .await
+        .map_err(|_| ApiError::internal("Failed to read user"))?;
+    data.map(|d| serde_json::from_str(&d).unwrap_or_default())
+        .ok_or_else(|| ApiError::not_found("User"))
+}
+pub async fn update_user_profile(
+    _user_id: &str,
+    _update: &user::UpdateProfileRequest,
+    _env: &Env,
+) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"status": "updated"}))
+}
+pub async fn change_user_password(
+    _user_id: &str,
+    _change: &user::ChangePasswordRequest,
+    _env: &Env,
+) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"status": "updated"}))
+}
+pub async fn get_2fa_status(_user_id: &str, _env: &Env) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"enabled": false}))
+}
+pub async fn enable_2fa(
+    _user_id: &str,
+    _request: &user::Enable2faRequest,
+    _env: &Env,
+) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"backup_codes": []}))
+}
+pub async fn disable_2fa(_user_id: &str, _env: &Env) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"status": "disabled"}))
+}
+pub async fn get_user_sessions(_user_id: &str, _env: &Env) -> ApiResult<Vec<serde_json::Value>> {
+    Ok(vec![])
+}
+pub async fn revoke_user_session(
+    _user_id: &str,
+    _session_id: &str,
+    _env: &Env,
+) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"status": "revoked"}))
+}
+
+// Billing
+pub async fn get_subscription(_user_id: &str, _env: &Env) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"plan": "starter"}))
+}
+pub async fn change_subscription(
+    _user_id: &str,
+    _change: &user::ChangePlanRequest,
+    _env: &Env,
+) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"status": "updated"}))
+}
+pub async fn get_usage_meters(_user_id: &str, _env: &Env) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({}))
+}
+pub async fn get_payment_methods(_user_id: &str, _env: &Env) -> ApiResult<Vec<serde_json::Value>> {
+    Ok(vec![])
+}
+pub async fn add_payment_method(
+    _user_id: &str,
+    _add: &user::AddPaymentMethodRequest,
+    _env: &Env,
+) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"status": "added"}))
+}
+pub async fn remove_payment_method(
+    _user_id: &str,
+    _id: &str,
+    _env: &Env,
+) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"status": "removed"}))
+}
+pub async fn get_invoices(_user_id: &str, _env: &Env) -> ApiResult<Vec<serde_json::Value>> {
+    Ok(vec![])
+}
+pub async fn get_invoice_pdf(_user_id: &str, _id: &str, _env: &Env) -> ApiResult<Vec<u8>> {
+    Ok(vec![])
+}
+
+// Fleet
+
+/// Device records listed under a KV index prefix (`owner:{user}:` or
+/// `org:{org}:`, keyed by device id)
+async fn list_indexed_devices(prefix: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
+    let kv = env
+        .kv("DEVICES")
+        .map_err(|_| ApiError::internal("Failed to access devices"))?;
+    let list = kv
+        .list()
+        .prefix(prefix.to_string())
+        .execute()
+        .await
+        .map_err(|_| ApiError::internal("Failed to list devices"))?;
+    let mut devices = Vec::new();
+    for key in list.keys {
+        let device_id = &key.name[prefix.len()..];
+        if let Some(data) = kv
+            .get(&format!("device:{}", device_id))
+            .text()
+            .await
+            .ok()
+            .flatten()
+            && let Ok(device) = serde_json::from_str::<serde_json::Value>(&data)
+        {
+            devices.push(device);
+        }
+    }
+    Ok(devices)
+}
+
+/// A user's personal devices
+pub async fn get_user_devices(user_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
+    list_indexed_devices(&format!("owner:{}:", user_id), env).await
+}
+
+/// Devices belonging to an organization
+pub async fn get_org_devices(org_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
+    list_indexed_devices(&format!("org:{}:", org_id), env).await
+}
+
+pub async fn count_user_devices(user_id: &str, env: &Env) -> ApiResult<u32> {
+    let devices = get_user_devices(user_id, env).await?;
+    Ok(devices.len() as u32)
+}
+
+/// KV index key listing a device under its organization or personal owner
+fn device_index_key(device: &serde_json::Value, device_id: &str) -> Option<String> {
+    match device.get("org_id").and_then(|v| v.as_str()) {
+        Some(org_id) => Some(format!("org:{}:{}", org_id, device_id)),
+        None => device
+            .get("owner_id")
+            .and_then(|v| v.as_str())
+            .map(|owner_id| format!("owner:{}:{}", owner_id, device_id)),
+    }
+}
+
+/// Register a device for a user, inside `org_id` when an organization is
+/// active
+pub async fn register_device(
+    user_id: &str,
+    org_id: Option<&str>,
+    request: &fleet::RegisterDeviceRequest,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    let kv = env
+        .kv("DEVICES")
+        .map_err(|_| ApiError::internal("Failed to access devices"))?;
+    let device_id = uuid::Uuid::new_v4().to_string();
+    let api_key = uuid::Uuid::new_v4().to_string();
+
+    let device = serde_json::json!({
+        "id": device_id,
+        "name": request.name,
+        "owner_id": user_id,
+        "org_id": org_id,
+        "status": "provisioning",
+        "created_at": chrono::Utc::now().timestamp()
+    });
+
+    kv.put(
+        &format!("device:{}", device_id),
+        serde_json::to_string(&device).unwrap(),
+    )
+    .map_err(|_| ApiError::internal("Failed to store device"))?
+    .execute()
+    .await
+    .map_err(|_| ApiError::internal("Failed to save device"))?;
+
+    kv.put(&format!("apikey:{}", api_key), &device_id)
+        .map_err(|_| ApiError::internal("Failed to store API key"))?
+        .execute()
+        .await
+        .map_err(|_| ApiError::internal("Failed to save API key"))?;
+
+    if let Some(index_key) = device_index_key(&device, &device_id) {
+        kv.put(&index_key, &device_id)
+            .map_err(|_| ApiError::internal("Failed to store owner mapping"))?
+            .execute()
+            .await
+            .map_err(|_| ApiError::internal("Failed to save owner mapping"))?;
+    }
+
+    Ok(serde_json::json!({
+        "device_id": device_id,
+        "api_key": api_key,
+        "websocket_url": "wss://api.ngfw.sh/agent/ws"
+    }))
+}
+
+pub async fn remove_device(device_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
+    let kv = env
+        .kv("DEVICES")
+        .map_err(|_| ApiError::internal("Failed to access devices"))?;
+
+    let key = format!("device:{}", device_id);
+    if let Some(device) = kv
+        .get(&key)
+        .text()
+        .await
+        .ok()
+        .flatten()
+        .and_then(|data| serde_json::from_str::<serde_json::Value>(&data).ok())
+        && let Some(index_key) = device_index_key(&device, device_id)
+    {
+        let _ = kv.delete(&index_key).await;
+    }
+
+    kv.delete(&key)
+        .await
+        .map_err(|_| ApiError::internal("Failed to delete device"))?;
+    Ok(serde_json::json!({"status": "removed"}))
+}
+
+/// Move a device into an organization.
+///
+/// Updates the KV record and index, and the D1 `devices` row used by
+/// log and report queries.
+pub async fn set_device_org(
+    device_id: &str,
+    org_id: &str,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    let kv = env
+        .kv("DEVICES")
+        .map_err(|_| ApiError::internal("Failed to access devices"))?;
+
+    let key = format!("device:{}", device_id);
+    let mut device: serde_json::Value = kv
+        .get(&key)
+        .text()
+        .await
+        .map_err(|_| ApiError::internal("Failed to lookup device"))?
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .ok_or_else(|| ApiError::not_found("Device"))?;
+
+    if let Some(old_index) = device_index_key(&device, device_id) {
+        let _ = kv.delete(&old_index).await;
+    }
+    device["org_id"] = serde_json::Value::String(org_id.to_string());
+
+    kv.put(&key, device.to_string())
+        .map_err(|_| ApiError::internal("Failed to store device"))?
+        .execute()
+        .await
+        .map_err(|_| ApiError::internal("Failed to save device"))?;
+    if let Some(index_key) = device_index_key(&device, device_id) {
+        kv.put(&index_key, device_id)
+            .map_err(|_| ApiError::internal("Failed to store org mapping"))?
+            .execute()
+            .await
+            .map_err(|_| ApiError::internal("Failed to save org mapping"))?;
+    }
+
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+    db.prepare("UPDATE devices SET org_id = ? WHERE id = ?")
+        .bind(&[org_id.into(), device_id.into()])
+        .map_err(|_| ApiError::internal("Failed to prepare update"))?
+        .run()
+        .await
+        .map_err(|_| ApiError::internal("Failed to update device organization"))?;
+
+    Ok(device)
+}
+
+pub async fn get_config_templates(user_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
+    let kv = env
+        .kv("CONFIGS")
+        .map_err(|_| ApiError::internal("Failed to access configs"))?;
+    let data = kv
+        .get(&format!("templates:{}", user_id))
+        .text()
+        .await
+        .map_err(|_| ApiError::internal("Failed to read templates"))?;
+    Ok(data
+        .map(|d| serde_json::from_str(&d).unwrap_or_default())
+        .unwrap_or_default())
+}
+
+pub async fn create_config_template(
+    _user_id: &str,
+    _template: &fleet::CreateTemplateRequest,
+    _env: &Env,
+) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"id": uuid::Uuid::new_v4().to_string(), "status": "created"}))
+}
+
+pub async fn apply_config_template(
+    _template_id: &str,
+    _apply: &fleet::ApplyTemplateRequest,
+    _env: &Env,
+) -> ApiResult<serde_json::Value> {
+    Ok(serde_json::json!({"status": "applied"}))
+}
+
+// ========== Onboarding Functions ==========
+
+/// Get the list of available routers for purchase
+pub fn get_onboarding_routers() -> ApiResult<serde_json::Value> {
+    let routers = vec![
+        serde_json::json!({
+            "id": "asus-rt-ax92u",
+            "name": "RT-AX92U",
+            "manufacturer": "ASUS",
+            "firmware": "Merlin NG",
+            "price": 299,
+            "specs": {
+                "cpu": "Quad-core 1.8GHz",
+                "ram": "512MB",
+                "storage": "256MB NAND",
+                "wan_ports": "1x Gigabit",
+                "lan_ports": "4x Gigabit",
+                "wifi": "WiFi 6 (AX6100) Tri-band",
+                "max_devices": 75
+            },
+            "features": [
+                "WiFi 6 (802.11ax) tri-band",
+                "AiMesh support for mesh networking",
+                "AiProtection Pro security",
+                "Advanced QoS and traffic management",
+                "USB 3.0 port for network storage",
+                "ASUSWRT-Merlin custom firmware"
+            ],
+            "image": "https://placehold.co/400x300/1e293b/60a5fa?text=ASUS+RT-AX92U",
+            "recommended": false,
+            "in_stock": true
+        }),
+        serde_json::json!({
+            "id": "gl-inet-flint-2",
+            "name": "Flint 2 (GL-MT6000)",
+            "manufacturer": "GL.iNet",
+            "firmware": "OpenWrt",
+            "price": 199,
+            "specs": {
+                "cpu": "Quad-core 2.0GHz ARM Cortex-A53",
+                "ram": "1GB DDR4",
+                "storage": "8GB eMMC + 128MB NAND",
+                "wan_ports": "1x 2.5Gb",
+                "lan_ports": "4x Gigabit",
+                "wifi": "WiFi 6 (AX6000) Dual-band",
+                "max_devices": 100
+            },
+            "features": [
+                "WiFi 6 dual-band with 160MHz support",
+                "OpenWrt native for maximum flexibility",
+                "2.5Gb WAN port for multi-gig connections",
+                "Pre-configured VPN client support",
+                "Open-source firmware with active community",
+                "Best price-to-performance ratio"
+            ],
+            "image": "https://placehold.co/400x300/1e293b/10b981?text=GL.iNet+Flint+2",
+            "recommended": true,
+            "in_stock": true
+        }),
+        serde_json::json!({
+            "id": "linksys-wrt3200acm",
+            "name": "WRT3200ACM",
+            "manufacturer": "Linksys",
+            "firmware": "OpenWrt",
+            "price": 179,
+            "specs": {
+                "cpu": "Dual-core 1.8GHz ARM",
+                "ram": "512MB DDR3",
+                "storage": "256MB NAND",
+                "wan_ports": "1x Gigabit",
+                "lan_ports": "4x Gigabit",
+                "wifi": "AC3200 Tri-Stream",
+                "max_devices": 60
+            },
+            "features": [
+                "OpenWrt champion with excellent support",
+                "Dual-core 1.8GHz for strong performance",
+                "eSATA + USB 3.0 + USB 2.0 ports",
+                "Proven reliability and stability",
+                "Large community and documentation",
+                "Budget-friendly OpenWrt option"
+            ],
+            "image": "https://placehold.co/400x300/1e293b/8b5cf6?text=Linksys+WRT3200ACM",
+            "recommended": false,
+            "in_stock": true
+        }),
+        serde_json::json!({
+            "id": "gl-inet-flint-3",
+            "name": "Flint 3",
+            "manufacturer": "GL.iNet",
+            "firmware": "OpenWrt",
+            "price": 299,
+            "specs": {
+                "cpu": "Quad-core 2.2GHz",
+                "ram": "2GB DDR4",
+                "storage": "8GB eMMC",
+                "wan_ports": "1x 2.5Gb",
+                "lan_ports": "4x 2.5Gb",
+                "wifi": "WiFi 7 (BE11000)",
+                "max_devices": 150
+            },
+            "features": [
+                "WiFi 7 (802.11be) cutting-edge",
+                "5x 2.5Gb ports for multi-gig network",
+                "2GB RAM for advanced workloads",
+                "Built-in WireGuard and Tailscale",
+                "Future-proof connectivity",
+                "Premium OpenWrt experience"
+            ],
+            "image": "https://placehold.co/400x300/1e293b/f59e0b?text=GL.iNet+Flint+3",
+            "recommended": false,
+            "in_stock": true
+        }),
+    ];
+
+    Ok(serde_json::json!({
+        "success": true,
+        "result": routers
+    }))
+}
+
+/// Create a new router order with pre-configuration
+pub async fn create_onboarding_order(
+    order: &onboarding::OrderSubmission,
+    env: &Env,
+) -> ApiResult<onboarding::OrderResponse> {
+    // Generate order ID
+    let order_id = format!("ORD-{}", uuid::Uuid::new_v4().to_string().replace('-', "")[..12].to_uppercase());
+
+    // Generate device ID (pre-provision)
+    let device_id = format!("DEV-{}", uuid::Uuid::new_v4().to_string().replace('-', "")[..9].to_uppercase());
+
+    // Calculate estimated delivery (9 days from now)
+    let now = chrono::Utc::now();
+    let estimated_delivery = now + chrono::Duration::days(9);
+
+    let response = onboarding::OrderResponse {
+        order_id: order_id.clone(),
+        device_id: device_id.clone(),
+        estimated_delivery: estimated_delivery.to_rfc3339(),
+        tracking_url: None,
+        setup_instructions: "https://docs.ngfw.sh/setup/quick-start".to_string(),
+        status: onboarding::OrderStatus::Pending,
+        created_at: now.to_rfc3339(),
+    };
+
+    // Store order in KV for later retrieval
+    let kv = env
+        .kv("CONFIGS")
+        .map_err(|_| ApiError::internal("Failed to access config store"))?;
+
+    let order_data = serde_json::json!({
+        "order_id": order_id,
+        "device_id": device_id,
+        "router_id": order.router_id,
+        "subscription_plan": order.subscription_plan,
+        "status": "pending",
+        "created_at": now.to_rfc3339()
+    });
+
+    kv.put(
+        &format!("onboarding:order:{}", order_id),
+        serde_json::to_string(&order_data)
+            .map_err(|_| ApiError::internal("Failed to serialize order"))?,
+    )
+    .map_err(|_| ApiError::internal("Failed to store order"))?
+    .execute()
+    .await
+    .map_err(|_| ApiError::internal("Failed to save order"))?;
+
+    // Store device pre-provisioning record
+    let device_data = serde_json::json!({
+        "device_id": device_id,
+        "order_id": order_id,
+        "device_name": order.config.device_name,
+        "status": "provisioning",
+        "created_at": now.to_rfc3339()
+    });
+
+    kv.put(
+        &format!("onboarding:device:{}", device_id),
+        serde_json::to_string(&device_data)
+            .map_err(|_| ApiError::internal("Failed to serialize device"))?,
+    )
+    .map_err(|_| ApiError::internal("Failed to store device"))?
+    .execute()
+    .await
+    .map_err(|_| ApiError::internal("Failed to save device"))?;
+
+    Ok(response)
+}
+
+/// Get the onboarding status for a user
+pub async fn get_onboarding_status(
+    user_id: Option<&str>,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    let now = chrono::Utc::now().to_rfc3339();
+
+    // If a user_id is provided, look up their onboarding state
+    if let Some(uid) = user_id {
+        let kv = env
+            .kv("CONFIGS")
+            .map_err(|_| ApiError::internal("Failed to access config store"))?;
+
+        let key = format!("onboarding:status:{}", uid);
+        if let Some(data) = kv
+            .get(&key)
+            .text()
+            .await
+            .map_err(|_| ApiError::internal("Failed to read onboarding status"))?
+        {
+            let status: serde_json::Value = serde_json::from_str(&data)
+                .map_err(|_| ApiError::internal("Invalid onboarding status format"))?;
+            return Ok(serde_json::json!({
+                "success": true,
+                "result": status
+            }));
+        }
+    }
+
+    // Default: not started
+    Ok(serde_json::json!({
+        "success": true,
+        "result": {
+            "completed": false,
+            "current_step": "router_selection",
+            "last_updated": now
+        }
+    }))
+}
+
+// ========== Report Functions ==========
+
+/// Devices a listing of device data covers
+#[derive(Debug, Clone, Copy)]
+pub enum DeviceScope<'a> {
+    /// One device the caller was checked against
+    Device(&'a str),
+    /// Every device of an organization
+    Org(&'a str),
+    /// A user's personal devices
+    Personal(&'a str),
+}
+
+impl DeviceScope<'_> {
+    /// SQL condition limiting the device id in `column` to the scope, and
+    /// its parameter
+    fn condition(&self, column: &str) -> (String, String) {
+        match *self {
+            DeviceScope::Device(device_id) => (format!("{} = ?", column), device_id.to_string()),
+            DeviceScope::Org(org_id) => (
+                format!("{} IN (SELECT id FROM devices WHERE org_id = ?)", column),
+                org_id.to_string(),
+            ),
+            DeviceScope::Personal(user_id) => (
+                format!(
+                    "{} IN (SELECT id FROM devices WHERE org_id IS NULL AND owner_id = ?)",
+                    column
+                ),
+                user_id.to_string(),
+            ),
+        }
+    }
+}
+
+/// List the reports of the devices in `scope` with optional filters
+pub async fn list_reports(
+    scope: DeviceScope<'_>,
+    query: &report::ReportListQuery,
+    env: &Env,
+) -> ApiResult<serde_json::Value> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let (condition, device_param) = scope.condition("device_id");
+    let mut sql = format!("SELECT * FROM reports WHERE {}", condition);
+    let mut params: Vec<wasm_bindgen::JsValue> = vec![device_param.as_str().into()];
+
+    if let Some(ref report_type) = query.report_type {
+        sql.push_str(" AND type = ?");
+        params.push(report_type.as_str().into());
+    }
+    if let Some(ref status) = query.status {
+        sql.push_str(" AND status = ?");
+        params.push(status.as_str().into());
+    }
+
+    // Count total before applying LIMIT/OFFSET
+    let count_sql = sql.replace("SELECT *", "SELECT COUNT(*) as total");
+    let count_stmt = db
+        .prepare(&count_sql)
+        .bind(&params)
+        .map_err(|_| ApiError::internal("Failed to prepare count query"))?;
+
+    let count_result = count_stmt
+        .first::<serde_json::Value>(None)
+        .await
+        .map_err(|_| ApiError::internal("Failed to count reports"))?;
+
+    let total = count_result
+        .and_then(|v| v.get("total").and_then(|t| t.as_i64()))
+        .unwrap_or(0);
+
+    sql.push_str(" ORDER BY created_at DESC LIMIT ? OFFSET ?");
+    params.push((query.limit as f64).into());
+    params.push((query.offset as f64).into());
+
+    let stmt = db
+        .prepare(&sql)
+        .bind(&params)
+        .map_err(|_| ApiError::internal("Failed to prepare query"))?;
+
+    let results = stmt
+        .all()
+        .await
+        .map_err(|_| ApiError::internal("Failed to list reports"))?;
+
+    let rows = results
+        .results::<serde_json::Value>()
+        .map_err(|_| ApiError::internal("Failed to parse report rows"))?;
+
+    Ok(serde_json::json!({
+        "success": true,
+        "result": rows,
+        "total": total
+    }))
+}
+
+/// Create a new report record in D1
+pub async fn create_report(
+    owner_id: &str,
+    body: &report::GenerateReportRequest,
+    env: &Env,
+) -> ApiResult<report::Report> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let now = chrono::Utc::now().to_rfc3339();
+
+    let stmt = db
+        .prepare(
+            "INSERT INTO reports (id, device_id, owner_id, type, format, status, title, date_start, date_end, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&[
+            id.as_str().into(),
+            body.device_id.as_str().into(),
+            owner_id.into(),
+            body.report_type.as_str().into(),
+            body.format.as_str().into(),
+            "pending".into(),
+            body.title.as_str().into(),
+            body.date_start.as_str().into(),
+            body.date_end.as_str().into(),
+            now.as_str().into(),
+        ])
+        .map_err(|_| ApiError::internal("Failed to prepare insert"))?;
+
+    stmt.run()
+        .await
+        .map_err(|_| ApiError::internal("Failed to insert report"))?;
+
+    Ok(report::Report {
+        id,
+        device_id: body.device_id.clone(),
+        owner_id: owner_id.to_string(),
+        report_type: body.report_type.clone(),
+        format: body.format.clone(),
+        status: "pending".to_string(),
+        title: body.title.clone(),
+        date_start: body.date_start.clone(),
+        date_end: body.date_end.clone(),
+        r2_key: None,
+        file_size: None,
+        created_at: now,
+        completed_at: None,
+        error_message: None,
+    })
+}
+
+/// Device a report was generated for
+pub async fn get_report_device(report_id: &str, env: &Env) -> ApiResult<String> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let stmt = db
+        .prepare("SELECT device_id FROM reports WHERE id = ?")
+        .bind(&[report_id.into()])
+        .map_err(|_| ApiError::internal("Failed to prepare query"))?;
+
+    #[derive(serde::Deserialize)]
+    struct ReportDeviceRow {
+        device_id: String,
+    }
+
+    stmt.first::<ReportDeviceRow>(None)
+        .await
+        .map_err(|_| ApiError::internal("Failed to query report"))?
+        .map(|row| row.device_id)
+        .ok_or_else(|| ApiError::not_found("Report"))
+}
+
+/// Get a single report by ID
+pub async fn get_report(report_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let stmt = db
+        .prepare("SELECT * FROM reports WHERE id = ?")
+        .bind(&[report_id.into()])
+        .map_err(|_| ApiError::internal("Failed to prepare query"))?;
+
+    let row = stmt
+        .first::<serde_json::Value>(None)
+        .await
+        .map_err(|_| ApiError::internal("Failed to query report"))?
+        .ok_or_else(|| ApiError::not_found("Report"))?;
+
+    let download_url = if row.get("status").and_then(|s| s.as_str()) == Some("completed") {
+        row.get("r2_key")
+            .and_then(|k| k.as_str())
+            .map(|k| format!("https://reports.ngfw.sh/{}", k))
+    } else {
+        None
+    };
+
+    let mut result = row.clone();
+    if let Some(obj) = result.as_object_mut() {
+        obj.insert(
+            "download_url".to_string(),
+            match download_url {
+                Some(url) => serde_json::Value::String(url),
+                None => serde_json::Value::Null,
             },
-            {
-                "id": "priority-traffic",
-                "type": "line-chart",
-                "title": "Priority Traffic",
-                "data_source": "/api/qos/priority",
-                "refresh_interval": 15000,
-                "config": { "yAxisUnit": "Mbps" }
-            }
-        ]
-    }));
+        );
+    }
 
-    map
+    Ok(serde_json::json!({
+        "success": true,
+        "result": result
+    }))
 }
 
-// VPN Server
-pub async fn get_vpn_server_config(device_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
-    get_config(device_id, "vpn_server", env).await
-}
-pub async fn update_vpn_server_config(
-    device_id: &str,
-    config: &services::VpnServerConfig,
-    env: &Env,
-) -> ApiResult<serde_json::Value> {
-    update_config(device_id, "vpn_server", config, env).await
-}
-pub async fn get_vpn_peers(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
-    get_config(device_id, "vpn_peers", env).await
+/// Delete a report by ID. Also removes the R2 object if present.
+pub async fn delete_report(report_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    // Fetch the report to get the r2_key before deleting
+    let stmt = db
+        .prepare("SELECT * FROM reports WHERE id = ?")
+        .bind(&[report_id.into()])
+        .map_err(|_| ApiError::internal("Failed to prepare query"))?;
+
+    let row = stmt
+        .first::<serde_json::Value>(None)
+        .await
+        .map_err(|_| ApiError::internal("Failed to query report"))?
+        .ok_or_else(|| ApiError::not_found("Report"))?;
+
+    // Delete the R2 object if it exists
+    if let Some(r2_key) = row.get("r2_key").and_then(|k| k.as_str())
+        && !r2_key.is_empty()
+    {
+        let r2 = env
+            .bucket("REPORTS")
+            .map_err(|_| ApiError::internal("Failed to access report storage"))?;
+        let _ = r2.delete(r2_key).await;
+    }
+
+    // Delete from D1
+    let delete_stmt = db
+        .prepare("DELETE FROM reports WHERE id = ?")
+        .bind(&[report_id.into()])
+        .map_err(|_| ApiError::internal("Failed to prepare delete"))?;
+
+    delete_stmt
+        .run()
+        .await
+        .map_err(|_| ApiError::internal("Failed to delete report"))?;
+
+    Ok(serde_json::json!({
+        "success": true,
+        "message": "Report deleted successfully"
+    }))
 }
-pub async fn create_vpn_peer(
-    device_id: &str,
-    peer: &services::VpnPeerRequest,
+
+// ========== Log Functions (D1) ==========
+
+/// List logs from D1 for the devices in `scope`.
+///
+/// Uses the `idx_logs_device_id_timestamp` index for efficient ordering.
+pub async fn list_logs(
+    scope: DeviceScope<'_>,
+    query: &logs::LogListQuery,
     env: &Env,
-) -> ApiResult<serde_json::Value> {
-    update_config(device_id, "vpn_peers", peer, env).await
+) -> ApiResult<logs::LogListResponse> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access D1 database"))?;
+
+    let limit = query.limit.unwrap_or(20).min(1000);
+    let offset = query.offset.unwrap_or(0);
+
+    // -- Build the dynamic WHERE clause --
+    // We accumulate owned Strings so that D1Type::Text can borrow them.
+    let (condition, device_param) = scope.condition("l.device_id");
+    let mut sql = format!(
+        "SELECT l.id, l.device_id, l.timestamp, l.level, l.category, \
+         l.message, l.source, l.metadata \
+         FROM logs l WHERE {}",
+        condition
+    );
+    let mut string_params: Vec<String> = vec![device_param.clone()];
+
+    if let Some(ref level) = query.level {
+        sql.push_str(" AND l.level = ?");
+        string_params.push(level.clone());
+    }
+    if let Some(ref category) = query.category {
+        sql.push_str(" AND l.category = ?");
+        string_params.push(category.clone());
+    }
+    if let Some(ref search) = query.search {
+        sql.push_str(" AND (l.message LIKE ? OR l.source LIKE ?)");
+        let pattern = format!("%{}%", search);
+        string_params.push(pattern.clone());
+        string_params.push(pattern);
+    }
+    if let Some(ref date_start) = query.date_start {
+        sql.push_str(" AND l.timestamp >= ?");
+        string_params.push(date_start.clone());
+    }
+    if let Some(ref date_end) = query.date_end {
+        sql.push_str(" AND l.timestamp <= ?");
+        string_params.push(date_end.clone());
+    }
+
+    sql.push_str(" ORDER BY l.timestamp DESC LIMIT ? OFFSET ?");
+
+    // Build params: text params first, then numeric limit/offset
+    let mut params: Vec<wasm_bindgen::JsValue> = string_params
+        .iter()
+        .map(|s| s.as_str().into())
+        .collect();
+    params.push((limit as f64).into());
+    params.push((offset as f64).into());
+
+    let stmt = db
+        .prepare(&sql)
+        .bind(&params)
+        .map_err(|_| ApiError::internal("Failed to bind log query parameters"))?;
+
+    let result = stmt
+        .all()
+        .await
+        .map_err(|e| ApiError::internal(format!("D1 query failed: {}", e)))?;
+
+    let rows: Vec<logs::LogEntry> = result
+        .results()
+        .map_err(|e| ApiError::internal(format!("Failed to deserialize log rows: {}", e)))?;
+
+    // -- Total count (for pagination) --
+    let count_stmt = db
+        .prepare(format!("SELECT COUNT(*) as total FROM logs l WHERE {}", condition))
+        .bind(&[device_param.as_str().into()])
+        .map_err(|_| ApiError::internal("Failed to bind count parameters"))?;
+
+    #[derive(serde::Deserialize)]
+    struct CountRow {
+        total: u64,
+    }
+
+    let total = count_stmt
+        .first::<CountRow>(None)
+        .await
+        .map_err(|e| ApiError::internal(format!("D1 count query failed: {}", e)))?
+        .map(|r| r.total)
+        .unwrap_or(0);
+
+    Ok(logs::LogListResponse {
+        success: true,
+        result: rows,
+        total,
+    })
 }
-pub async fn update_vpn_peer(
-    device_id: &str,
-    _id: &str,
-    peer: &services::VpnPeerRequest,
+
+/// Export a device's logs to R2 as a JSON or CSV file.
+///
+/// The caller must already have been checked against the device.
+///
+/// 1. Query matching log rows from D1.
+/// 2. Format as JSON or CSV.
+/// 3. Write to the REPORTS R2 bucket.
+/// 4. Return a 202-style response with the export_id.
+pub async fn export_logs(
+    user_id: &str,
+    req: &logs::LogExportRequest,
     env: &Env,
-) -> ApiResult<serde_json::Value> {
-    update_config(device_id, "vpn_peers", peer, env).await
-}
-pub async fn delete_vpn_peer(
-    _device_id: &str,
-    _id: &str,
-    _env: &Env,
-) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"status": "deleted"}))
-}
-pub async fn get_vpn_peer_qr(
-    _device_id: &str,
-    _id: &str,
-    _env: &Env,
-) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({}))
-}
-pub async fn get_vpn_server_status(device_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
-    get_device_status(device_id, env).await
-}
+) -> ApiResult<logs::LogExportResponse> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access D1 database"))?;
+
+    // -- Build export query --
+    let mut sql = String::from("SELECT * FROM logs WHERE device_id = ?");
+    let mut string_params: Vec<String> = vec![req.device_id.clone()];
+
+    if let Some(ref level) = req.level {
+        sql.push_str(" AND level = ?");
+        string_params.push(level.clone());
+    }
+    if let Some(ref category) = req.category {
+        sql.push_str(" AND category = ?");
+        string_params.push(category.clone());
+    }
+    sql.push_str(" AND timestamp >= ?");
+    string_params.push(req.date_start.clone());
+    sql.push_str(" AND timestamp <= ?");
+    string_params.push(req.date_end.clone());
+    sql.push_str(" ORDER BY timestamp DESC");
+
+    let params: Vec<wasm_bindgen::JsValue> = string_params
+        .iter()
+        .map(|s| s.as_str().into())
+        .collect();
+
+    let stmt = db
+        .prepare(&sql)
+        .bind(&params)
+        .map_err(|_| ApiError::internal("Failed to bind export query parameters"))?;
+
+    let result = stmt
+        .all()
+        .await
+        .map_err(|e| ApiError::internal(format!("D1 export query failed: {}", e)))?;
+
+    let rows: Vec<logs::LogEntry> = result
+        .results()
+        .map_err(|e| ApiError::internal(format!("Failed to deserialize export rows: {}", e)))?;
+
+    // -- Format the export --
+    let export_id = uuid::Uuid::new_v4().to_string();
+
+    let (file_content, extension) = match req.format {
+        logs::ExportFormat::Json => {
+            let json = serde_json::to_string_pretty(&rows)
+                .map_err(|_| ApiError::internal("Failed to serialize export JSON"))?;
+            (json, "json")
+        }
+        logs::ExportFormat::Csv => {
+            let headers = "id,device_id,timestamp,level,category,message,source";
+            let mut csv = String::from(headers);
+            for row in &rows {
+                csv.push('\n');
+                csv.push_str(&format!(
+                    "{},{},{},{},{},{},{}",
+                    csv_escape(&row.id),
+                    csv_escape(&row.device_id),
+                    csv_escape(&row.timestamp),
+                    csv_escape(&row.level),
+                    csv_escape(&row.category),
+                    csv_escape(&row.message),
+                    csv_escape(&row.source),
+                ));
+            }
+            (csv, "csv")
+        }
+    };
+
+    // -- Write to R2 --
+    let r2 = env
+        .bucket("REPORTS")
+        .map_err(|_| ApiError::internal("Failed to access reports storage"))?;
 
-// VPN Client
-pub async fn get_vpn_client_profiles(
-    device_id: &str,
-    env: &Env,
-) -> ApiResult<Vec<serde_json::Value>> {
-    get_config(device_id, "vpn_profiles", env).await
-}
-pub async fn create_vpn_client_profile(
-    device_id: &str,
-    profile: &services::VpnClientProfile,
-    env: &Env,
-) -> ApiResult<serde_json::Value> {
-    update_config(device_id, "vpn_profiles", profile, env).await
-}
-pub async fn update_vpn_client_profile(
-    device_id: &str,
-    _id: &str,
-    profile: &services::VpnClientProfile,
-    env: &Env,
-) -> ApiResult<serde_json::Value> {
-    update_config(device_id, "vpn_profiles", profile, env).await
-}
-pub async fn delete_vpn_client_profile(
-    _device_id: &str,
-    _id: &str,
-    _env: &Env,
-) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"status": "deleted"}))
-}
-pub async fn connect_vpn_client(
-    device_id: &str,
-    id: &str,
-    env: &Env,
-) -> ApiResult<serde_json::Value> {
-    send_command(
-        device_id,
-        "VPN_CONNECT",
-        Some(serde_json::json!({"profile_id": id})),
-        env,
-    )
-    .await
-}
-pub async fn disconnect_vpn_client(
-    device_id: &str,
-    id: &str,
-    env: &Env,
-) -> ApiResult<serde_json::Value> {
-    send_command(
-        device_id,
-        "VPN_DISCONNECT",
-        Some(serde_json::json!({"profile_id": id})),
-        env,
-    )
-    .await
-}
-pub async fn get_vpn_client_status(_device_id: &str, _env: &Env) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"connected": false}))
-}
+    let r2_key = format!("logs/{}/{}.{}", user_id, export_id, extension);
+    r2.put(&r2_key, file_content.as_bytes().to_vec())
+        .execute()
+        .await
+        .map_err(|_| ApiError::internal("Failed to write export to R2"))?;
 
-// QoS
-pub async fn get_qos_config(device_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
-    get_config(device_id, "qos", env).await
-}
-pub async fn update_qos_config(
-    device_id: &str,
-    config: &services::QosConfig,
-    env: &Env,
-) -> ApiResult<serde_json::Value> {
-    update_config(device_id, "qos", config, env).await
-}
-pub async fn get_traffic_classes(device_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
-    get_config(device_id, "traffic_classes", env).await
-}
-pub async fn create_traffic_class(
-    device_id: &str,
-    class: &services::TrafficClass,
-    env: &Env,
-) -> ApiResult<serde_json::Value> {
-    update_config(device_id, "traffic_classes", class, env).await
-}
-pub async fn update_traffic_class(
-    device_id: &str,
-    _id: &str,
-    class: &services::TrafficClass,
-    env: &Env,
-) -> ApiResult<serde_json::Value> {
-    update_config(device_id, "traffic_classes", class, env).await
+    Ok(logs::LogExportResponse {
+        success: true,
+        message: "Log export completed".to_string(),
+        export_id,
+        download_url: Some(format!("https://reports.ngfw.sh/{}", r2_key)),
+    })
 }
-pub async fn delete_traffic_class(
-    _device_id: &str,
-    _id: &str,
-    _env: &Env,
-) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"status": "deleted"}))
+
+/// Escape a value for CSV output (wraps in double quotes, escaping inner quotes).
+fn csv_escape(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
 }
-pub async fn get_device_bandwidth_limits(
+
+// ========== Device Liveness Functions (D1) ==========
+
+/// Open an outage interval for a device that went offline.
+///
+/// Returns the outage id, used to close the interval when the device
+/// comes back.
+pub async fn open_device_outage(
     device_id: &str,
+    started_at: i64,
+    reason: &str,
     env: &Env,
-) -> ApiResult<Vec<serde_json::Value>> {
-    get_config(device_id, "device_limits", env).await
+) -> ApiResult<String> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let stmt = db
+        .prepare(
+            "INSERT INTO device_outages (id, device_id, started_at, reason) VALUES (?, ?, ?, ?)",
+        )
+        .bind(&[
+            id.as_str().into(),
+            device_id.into(),
+            (started_at as f64).into(),
+            reason.into(),
+        ])
+        .map_err(|_| ApiError::internal("Failed to prepare insert"))?;
+
+    stmt.run()
+        .await
+        .map_err(|_| ApiError::internal("Failed to record device outage"))?;
+
+    Ok(id)
 }
-pub async fn set_device_bandwidth_limit(
-    device_id: &str,
-    _mac: &str,
-    limit: &services::DeviceLimit,
+
+/// Close an open outage interval, returning its duration in seconds
+pub async fn close_device_outage(
+    outage_id: &str,
+    started_at: i64,
+    ended_at: i64,
     env: &Env,
-) -> ApiResult<serde_json::Value> {
-    update_config(device_id, "device_limits", limit, env).await
-}
-pub async fn remove_device_bandwidth_limit(
-    _device_id: &str,
-    _mac: &str,
-    _env: &Env,
-) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"status": "removed"}))
-}
+) -> ApiResult<i64> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
 
-// DDNS
-pub async fn get_ddns_config(device_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
-    get_config(device_id, "ddns", env).await
+    let duration = (ended_at - started_at).max(0);
+    let stmt = db
+        .prepare("UPDATE device_outages SET ended_at = ?, duration_secs = ? WHERE id = ?")
+        .bind(&[
+            (ended_at as f64).into(),
+            (duration as f64).into(),
+            outage_id.into(),
+        ])
+        .map_err(|_| ApiError::internal("Failed to prepare update"))?;
+
+    stmt.run()
+        .await
+        .map_err(|_| ApiError::internal("Failed to close device outage"))?;
+
+    Ok(duration)
 }
-pub async fn update_ddns_config(
+
+/// Record a device lifecycle event (e.g. `device_offline`) for subscribers
+pub async fn record_device_event(
     device_id: &str,
-    config: &services::DdnsConfig,
+    event: &fleet::WebhookEvent,
+    payload: &serde_json::Value,
     env: &Env,
-) -> ApiResult<serde_json::Value> {
-    update_config(device_id, "ddns", config, env).await
-}
-pub async fn force_ddns_update(device_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
-    send_command(device_id, "DDNS_UPDATE", None, env).await
-}
-pub async fn get_ddns_status(_device_id: &str, _env: &Env) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"status": "unknown"}))
-}
+) -> ApiResult<()> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
 
-// User
-pub async fn get_user_profile(user_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
-    let kv = env
-        .kv("SESSIONS")
-        .map_err(|_| ApiError::internal("Failed to access sessions"))?;
-    let data = kv
-        .get(&format!("user:{}", user_id))
-        .text()
+    let event_name = serde_json::to_value(event)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_string))
+        .ok_or_else(|| ApiError::internal("Failed to serialize event type"))?;
+    let payload_json = serde_json::to_string(payload)
+        .map_err(|_| ApiError::internal("Failed to serialize event payload"))?;
+
+    let stmt = db
+        .prepare(
+            "INSERT INTO device_events (id, device_id, event, payload, created_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(&[
+            uuid::Uuid::new_v4().to_string().into(),
+            device_id.into(),
+            event_name.into(),
+            payload_json.into(),
+            (chrono::Utc::now().timestamp() as f64).into(),
+        ])
+        .map_err(|_| ApiError::internal("Failed to prepare insert"))?;
+
+    stmt.run()
         .await
-        .map_err(|_| ApiError::internal("Failed to read user"))?;
-    data.map(|d| serde_json::from_str(&d).unwrap_or_default())
-        .ok_or_else(|| ApiError::not_found("User"))
-}
-pub async fn update_user_profile(
-    _user_id: &str,
-    _update: &user::UpdateProfileRequest,
-    _env: &Env,
-) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"status": "updated"}))
-}
-pub async fn change_user_password(
-    _user_id: &str,
-    _change: &user::ChangePasswordRequest,
-    _env: &Env,
-) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"status": "updated"}))
-}
-pub async fn get_2fa_status(_user_id: &str, _env: &Env) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"enabled": false}))
-}
-pub async fn enable_2fa(
-    _user_id: &str,
-    _request: &user::Enable2faRequest,
-    _env: &Env,
-) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"backup_codes": []}))
-}
-pub async fn disable_2fa(_user_id: &str, _env: &Env) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"status": "disabled"}))
-}
-pub async fn get_user_sessions(_user_id: &str, _env: &Env) -> ApiResult<Vec<serde_json::Value>> {
-    Ok(vec![])
-}
-pub async fn revoke_user_session(
-    _user_id: &str,
-    _session_id: &str,
-    _env: &Env,
-) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"status": "revoked"}))
-}
+        .map_err(|_| ApiError::internal("Failed to record device event"))?;
 
-// Billing
-pub async fn get_subscription(_user_id: &str, _env: &Env) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"plan": "starter"}))
-}
-pub async fn change_subscription(
-    _user_id: &str,
-    _change: &user::ChangePlanRequest,
-    _env: &Env,
-) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"status": "updated"}))
-}
-pub async fn get_usage_meters(_user_id: &str, _env: &Env) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({}))
-}
-pub async fn get_payment_methods(_user_id: &str, _env: &Env) -> ApiResult<Vec<serde_json::Value>> {
-    Ok(vec![])
-}
-pub async fn add_payment_method(
-    _user_id: &str,
-    _add: &user::AddPaymentMethodRequest,
-    _env: &Env,
-) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"status": "added"}))
+    Ok(())
 }
-pub async fn remove_payment_method(
-    _user_id: &str,
-    _id: &str,
-    _env: &Env,
-) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"status": "removed"}))
+
+// ========== Metrics Rollup Functions (D1) ==========
+
+/// Merge SQL for a gauge stored as `{col}_min`, `{col}_max`, `{col}_sum`.
+/// Multi-argument MIN/MAX return NULL if any argument is NULL, hence the
+/// COALESCE on both sides.
+fn merge_stat_sql(col: &str) -> String {
+    format!(
+        "{c}_min = MIN(COALESCE({c}_min, excluded.{c}_min), COALESCE(excluded.{c}_min, {c}_min)), \
+         {c}_max = MAX(COALESCE({c}_max, excluded.{c}_max), COALESCE(excluded.{c}_max, {c}_max)), \
+         {c}_sum = {c}_sum + excluded.{c}_sum",
+        c = col
+    )
 }
-pub async fn get_invoices(_user_id: &str, _env: &Env) -> ApiResult<Vec<serde_json::Value>> {
-    Ok(vec![])
+
+fn optional_f64(value: Option<f64>) -> wasm_bindgen::JsValue {
+    value.map_or(wasm_bindgen::JsValue::NULL, |v| v.into())
 }
-pub async fn get_invoice_pdf(_user_id: &str, _id: &str, _env: &Env) -> ApiResult<Vec<u8>> {
-    Ok(vec![])
+
+/// Merge drained rollups into D1 and prune buckets past their retention.
+///
+/// Each rollup only covers samples since the previous flush, so existing
+/// rows are widened rather than replaced. All statements run in one batch.
+pub async fn upsert_metrics_rollups(
+    device_id: &str,
+    rollups: &[MetricsRollup],
+    env: &Env,
+) -> ApiResult<()> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let now = chrono::Utc::now().timestamp();
+    let rollup_sql = format!(
+        "INSERT INTO metrics_rollups (device_id, resolution, bucket_start, samples, \
+         cpu_min, cpu_max, cpu_sum, memory_min, memory_max, memory_sum, \
+         temperature_min, temperature_max, temperature_sum, temperature_samples, \
+         connections_min, connections_max, connections_sum, tcp_sum, udp_sum, \
+         dns_queries, dns_blocked, dns_cached, updated_at) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) \
+         ON CONFLICT (device_id, resolution, bucket_start) DO UPDATE SET \
+         samples = samples + excluded.samples, {}, {}, {}, \
+         temperature_samples = temperature_samples + excluded.temperature_samples, {}, \
+         tcp_sum = tcp_sum + excluded.tcp_sum, udp_sum = udp_sum + excluded.udp_sum, \
+         dns_queries = MAX(dns_queries, excluded.dns_queries), \
+         dns_blocked = MAX(dns_blocked, excluded.dns_blocked), \
+         dns_cached = MAX(dns_cached, excluded.dns_cached), \
+         updated_at = excluded.updated_at",
+        merge_stat_sql("cpu"),
+        merge_stat_sql("memory"),
+        merge_stat_sql("temperature"),
+        merge_stat_sql("connections"),
+    );
+    let interface_sql = "INSERT INTO metrics_interface_rollups (device_id, resolution, bucket_start, interface, \
+         samples, rx_rate_sum, rx_rate_max, tx_rate_sum, tx_rate_max) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?) \
+         ON CONFLICT (device_id, resolution, bucket_start, interface) DO UPDATE SET \
+         samples = samples + excluded.samples, \
+         rx_rate_sum = rx_rate_sum + excluded.rx_rate_sum, \
+         rx_rate_max = MAX(rx_rate_max, excluded.rx_rate_max), \
+         tx_rate_sum = tx_rate_sum + excluded.tx_rate_sum, \
+         tx_rate_max = MAX(tx_rate_max, excluded.tx_rate_max)";
+
+    let mut statements = Vec::new();
+    for rollup in rollups {
+        let resolution = rollup.resolution.as_str();
+        let bucket_start = rollup.bucket_start as f64;
+
+        statements.push(
+            db.prepare(&rollup_sql)
+                .bind(&[
+                    device_id.into(),
+                    resolution.into(),
+                    bucket_start.into(),
+                    (rollup.samples as f64).into(),
+                    optional_f64(rollup.cpu.min),
+                    optional_f64(rollup.cpu.max),
+                    rollup.cpu.sum.into(),
+                    optional_f64(rollup.memory.min),
+                    optional_f64(rollup.memory.max),
+                    rollup.memory.sum.into(),
+                    optional_f64(rollup.temperature.min),
+                    optional_f64(rollup.temperature.max),
+                    rollup.temperature.sum.into(),
+                    (rollup.temperature.count as f64).into(),
+                    optional_f64(rollup.connections.min),
+                    optional_f64(rollup.connections.max),
+                    rollup.connections.sum.into(),
+                    (rollup.tcp_sum as f64).into(),
+                    (rollup.udp_sum as f64).into(),
+                    (rollup.dns_queries as f64).into(),
+                    (rollup.dns_blocked as f64).into(),
+                    (rollup.dns_cached as f64).into(),
+                    (now as f64).into(),
+                ])
+                .map_err(|_| ApiError::internal("Failed to prepare rollup upsert"))?,
+        );
+
+        for (name, iface) in &rollup.interfaces {
+            statements.push(
+                db.prepare(interface_sql)
+                    .bind(&[
+                        device_id.into(),
+                        resolution.into(),
+                        bucket_start.into(),
+                        name.as_str().into(),
+                        (iface.samples as f64).into(),
+                        (iface.rx_rate_sum as f64).into(),
+                        (iface.rx_rate_max as f64).into(),
+                        (iface.tx_rate_sum as f64).into(),
+                        (iface.tx_rate_max as f64).into(),
+                    ])
+                    .map_err(|_| ApiError::internal("Failed to prepare rollup upsert"))?,
+            );
+        }
+    }
+
+    for resolution in Resolution::ALL {
+        let cutoff = (now - resolution.retention_secs()) as f64;
+        for table in ["metrics_rollups", "metrics_interface_rollups"] {
+            let sql = format!(
+                "DELETE FROM {} WHERE device_id = ? AND resolution = ? AND bucket_start < ?",
+                table
+            );
+            statements.push(
+                db.prepare(&sql)
+                    .bind(&[device_id.into(), resolution.as_str().into(), cutoff.into()])
+                    .map_err(|_| ApiError::internal("Failed to prepare rollup prune"))?,
+            );
+        }
+    }
+
+    db.batch(statements)
+        .await
+        .map_err(|_| ApiError::internal("Failed to write metrics rollups"))?;
+
+    Ok(())
 }
 
-// Fleet
-pub async fn get_user_devices(user_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
-    let kv = env
-        .kv("DEVICES")
-        .map_err(|_| ApiError::internal("Failed to access devices"))?;
-    let list = kv
-        .list()
-        .prefix(format!("owner:{}:", user_id))
-        .execute()
+/// Columns charted for a metric: table, value, min and max expressions
+fn metric_columns(
+    metric: metrics::MetricKind,
+) -> (&'static str, &'static str, &'static str, &'static str) {
+    use metrics::MetricKind::*;
+    match metric {
+        Cpu => (
+            "metrics_rollups",
+            "SUM(cpu_sum) / SUM(samples)",
+            "MIN(cpu_min)",
+            "MAX(cpu_max)",
+        ),
+        Memory => (
+            "metrics_rollups",
+            "SUM(memory_sum) / SUM(samples)",
+            "MIN(memory_min)",
+            "MAX(memory_max)",
+        ),
+        Temperature => (
+            "metrics_rollups",
+            "SUM(temperature_sum) / NULLIF(SUM(temperature_samples), 0)",
+            "MIN(temperature_min)",
+            "MAX(temperature_max)",
+        ),
+        Connections => (
+            "metrics_rollups",
+            "SUM(connections_sum) / SUM(samples)",
+            "MIN(connections_min)",
+            "MAX(connections_max)",
+        ),
+        Tcp => (
+            "metrics_rollups",
+            "SUM(tcp_sum) * 1.0 / SUM(samples)",
+            "NULL",
+            "NULL",
+        ),
+        Udp => (
+            "metrics_rollups",
+            "SUM(udp_sum) * 1.0 / SUM(samples)",
+            "NULL",
+            "NULL",
+        ),
+        DnsQueries => ("metrics_rollups", "MAX(dns_queries)", "NULL", "NULL"),
+        DnsBlocked => ("metrics_rollups", "MAX(dns_blocked)", "NULL", "NULL"),
+        DnsCached => ("metrics_rollups", "MAX(dns_cached)", "NULL", "NULL"),
+        RxRate => (
+            "metrics_interface_rollups",
+            "SUM(rx_rate_sum) * 1.0 / SUM(samples)",
+            "NULL",
+            "MAX(rx_rate_max)",
+        ),
+        TxRate => (
+            "metrics_interface_rollups",
+            "SUM(tx_rate_sum) * 1.0 / SUM(samples)",
+            "NULL",
+            "MAX(tx_rate_max)",
+        ),
+    }
+}
+
+/// Read a metric's time series from the rollups at `resolution`, merging
+/// buckets into points `step` seconds apart
+pub async fn query_metrics_history(
+    query: &metrics::MetricsHistoryQuery,
+    resolution: Resolution,
+    step: i64,
+    env: &Env,
+) -> ApiResult<Vec<metrics::TimeSeriesPoint>> {
+    #[derive(serde::Deserialize)]
+    struct Row {
+        timestamp: f64,
+        value: Option<f64>,
+        min: Option<f64>,
+        max: Option<f64>,
+    }
+
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let (table, value, min, max) = metric_columns(query.metric);
+    let aligned_from = query.from - query.from.rem_euclid(step);
+
+    let mut sql = format!(
+        "SELECT (bucket_start / CAST(? AS INTEGER)) * CAST(? AS INTEGER) AS timestamp, \
+         {} AS value, {} AS min, {} AS max FROM {} \
+         WHERE device_id = ? AND resolution = ? AND bucket_start >= ? AND bucket_start < ?",
+        value, min, max, table
+    );
+    let mut params: Vec<wasm_bindgen::JsValue> = vec![
+        (step as f64).into(),
+        (step as f64).into(),
+        query.device_id.as_str().into(),
+        resolution.as_str().into(),
+        (aligned_from as f64).into(),
+        (query.to as f64).into(),
+    ];
+    if let Some(ref interface) = query.interface {
+        sql.push_str(" AND interface = ?");
+        params.push(interface.as_str().into());
+    }
+    // Newest first so the cap drops the oldest (possibly partial) step
+    sql.push_str(" GROUP BY 1 ORDER BY 1 DESC LIMIT ?");
+    params.push((MAX_HISTORY_POINTS as f64).into());
+
+    let stmt = db
+        .prepare(&sql)
+        .bind(&params)
+        .map_err(|_| ApiError::internal("Failed to prepare query"))?;
+
+    let results = stmt
+        .all()
         .await
-        .map_err(|_| ApiError::internal("Failed to list devices"))?;
-    let mut devices = Vec::new();
-    for key in list.keys {
-        if let Some(data) = kv.get(&key.name).text().await.ok().flatten()
-            && let Ok(device) = serde_json::from_str::<serde_json::Value>(&data)
-        {
-            devices.push(device);
+        .map_err(|_| ApiError::internal("Failed to query metrics history"))?;
+
+    let rows = results
+        .results::<Row>()
+        .map_err(|_| ApiError::internal("Failed to parse metrics rows"))?;
+
+    Ok(rows
+        .into_iter()
+        .rev()
+        .filter_map(|row| {
+            Some(metrics::TimeSeriesPoint {
+                timestamp: row.timestamp as i64,
+                value: row.value?,
+                min: row.min,
+                max: row.max,
+            })
+        })
+        .collect())
+}
+
+// ========== Webhook Functions (D1) ==========
+
+/// Webhook row as stored in D1
+#[derive(serde::Deserialize)]
+struct WebhookRow {
+    id: String,
+    url: String,
+    events: String,
+    enabled: i64,
+    secret: String,
+    created_at: i64,
+}
+
+impl From<WebhookRow> for fleet::WebhookConfig {
+    fn from(row: WebhookRow) -> Self {
+        Self {
+            id: row.id,
+            url: row.url,
+            events: serde_json::from_str(&row.events).unwrap_or_default(),
+            enabled: row.enabled != 0,
+            secret: Some(row.secret),
+            created_at: row.created_at,
         }
     }
-    Ok(devices)
 }
 
-pub async fn count_user_devices(user_id: &str, env: &Env) -> ApiResult<u32> {
-    let devices = get_user_devices(user_id, env).await?;
-    Ok(devices.len() as u32)
+const WEBHOOK_COLUMNS: &str = "id, url, events, enabled, secret, created_at";
+
+/// List a user's webhooks, oldest first
+pub async fn list_webhooks(owner_id: &str, env: &Env) -> ApiResult<Vec<fleet::WebhookConfig>> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let sql = format!(
+        "SELECT {} FROM webhooks WHERE owner_id = ? ORDER BY created_at",
+        WEBHOOK_COLUMNS
+    );
+    let stmt = db
+        .prepare(&sql)
+        .bind(&[owner_id.into()])
+        .map_err(|_| ApiError::internal("Failed to prepare query"))?;
+
+    let results = stmt
+        .all()
+        .await
+        .map_err(|_| ApiError::internal("Failed to list webhooks"))?;
+
+    let rows = results
+        .results::<WebhookRow>()
+        .map_err(|_| ApiError::internal("Failed to parse webhook rows"))?;
+
+    Ok(rows.into_iter().map(Into::into).collect())
 }
 
-pub async fn register_device(
-    user_id: &str,
-    request: &fleet::RegisterDeviceRequest,
+/// Get one of a user's webhooks
+pub async fn get_webhook(
+    owner_id: &str,
+    webhook_id: &str,
     env: &Env,
-) -> ApiResult<serde_json::Value> {
-    let kv = env
-        .kv("DEVICES")
-        .map_err(|_| ApiError::internal("Failed to access devices"))?;
-    let device_id = uuid::Uuid::new_v4().to_string();
-    let api_key = uuid::Uuid::new_v4().to_string();
+) -> ApiResult<fleet::WebhookConfig> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
 
-    let device = serde_json::json!({
-        "id": device_id,
-        "name": request.name,
-        "owner_id": user_id,
-        "status": "provisioning",
-        "created_at": chrono::Utc::now().timestamp()
-    });
+    let sql = format!(
+        "SELECT {} FROM webhooks WHERE id = ? AND owner_id = ?",
+        WEBHOOK_COLUMNS
+    );
+    let stmt = db
+        .prepare(&sql)
+        .bind(&[webhook_id.into(), owner_id.into()])
+        .map_err(|_| ApiError::internal("Failed to prepare query"))?;
 
-    kv.put(
-        &format!("device:{}", device_id),
-        serde_json::to_string(&device).unwrap(),
-    )
-    .map_err(|_| ApiError::internal("Failed to store device"))?
-    .execute()
-    .await
-    .map_err(|_| ApiError::internal("Failed to save device"))?;
+    stmt.first::<WebhookRow>(None)
+        .await
+        .map_err(|_| ApiError::internal("Failed to query webhooks"))?
+        .map(Into::into)
+        .ok_or_else(|| ApiError::not_found("Webhook"))
+}
 
-    kv.put(&format!("apikey:{}", api_key), &device_id)
-        .map_err(|_| ApiError::internal("Failed to store API key"))?
-        .execute()
+/// Look up a webhook by id regardless of owner (used by retries)
+pub async fn find_webhook(webhook_id: &str, env: &Env) -> ApiResult<Option<fleet::WebhookConfig>> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let sql = format!("SELECT {} FROM webhooks WHERE id = ?", WEBHOOK_COLUMNS);
+    let stmt = db
+        .prepare(&sql)
+        .bind(&[webhook_id.into()])
+        .map_err(|_| ApiError::internal("Failed to prepare query"))?;
+
+    Ok(stmt
+        .first::<WebhookRow>(None)
         .await
-        .map_err(|_| ApiError::internal("Failed to save API key"))?;
+        .map_err(|_| ApiError::internal("Failed to query webhooks"))?
+        .map(Into::into))
+}
 
-    kv.put(&format!("owner:{}:{}", user_id, device_id), &device_id)
-        .map_err(|_| ApiError::internal("Failed to store owner mapping"))?
-        .execute()
+/// Insert a webhook; `webhook.secret` must be set
+pub async fn create_webhook(
+    owner_id: &str,
+    webhook: &fleet::WebhookConfig,
+    env: &Env,
+) -> ApiResult<()> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let events = serde_json::to_string(&webhook.events)
+        .map_err(|_| ApiError::internal("Failed to serialize events"))?;
+    let stmt = db
+        .prepare(
+            "INSERT INTO webhooks (id, owner_id, url, events, enabled, secret, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&[
+            webhook.id.as_str().into(),
+            owner_id.into(),
+            webhook.url.as_str().into(),
+            events.into(),
+            (webhook.enabled as i32 as f64).into(),
+            webhook.secret.as_deref().unwrap_or_default().into(),
+            (webhook.created_at as f64).into(),
+            (webhook.created_at as f64).into(),
+        ])
+        .map_err(|_| ApiError::internal("Failed to prepare insert"))?;
+
+    stmt.run()
         .await
-        .map_err(|_| ApiError::internal("Failed to save owner mapping"))?;
+        .map_err(|_| ApiError::internal("Failed to create webhook"))?;
 
-    Ok(serde_json::json!({
-        "device_id": device_id,
-        "api_key": api_key,
-        "websocket_url": "wss://api.ngfw.sh/agent/ws"
-    }))
+    Ok(())
 }
 
-pub async fn remove_device(device_id: &str, env: &Env) -> ApiResult<serde_json::Value> {
-    let kv = env
-        .kv("DEVICES")
-        .map_err(|_| ApiError::internal("Failed to access devices"))?;
-    kv.delete(&format!("device:{}", device_id))
+/// Update a webhook's url, events and enabled flag
+pub async fn update_webhook(
+    owner_id: &str,
+    webhook: &fleet::WebhookConfig,
+    env: &Env,
+) -> ApiResult<()> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let events = serde_json::to_string(&webhook.events)
+        .map_err(|_| ApiError::internal("Failed to serialize events"))?;
+    let stmt = db
+        .prepare(
+            "UPDATE webhooks SET url = ?, events = ?, enabled = ?, updated_at = ? WHERE id = ? AND owner_id = ?",
+        )
+        .bind(&[
+            webhook.url.as_str().into(),
+            events.into(),
+            (webhook.enabled as i32 as f64).into(),
+            (chrono::Utc::now().timestamp() as f64).into(),
+            webhook.id.as_str().into(),
+            owner_id.into(),
+        ])
+        .map_err(|_| ApiError::internal("Failed to prepare update"))?;
+
+    stmt.run()
         .await
-        .map_err(|_| ApiError::internal("Failed to delete device"))?;
-    Ok(serde_json::json!({"status": "removed"}))
+        .map_err(|_| ApiError::internal("Failed to update webhook"))?;
+
+    Ok(())
 }
 
-pub async fn get_config_templates(user_id: &str, env: &Env) -> ApiResult<Vec<serde_json::Value>> {
-    let kv = env
-        .kv("CONFIGS")
-        .map_err(|_| ApiError::internal("Failed to access configs"))?;
-    let data = kv
-        .get(&format!("templates:{}", user_id))
-        .text()
+/// Delete a webhook and its delivery log
+pub async fn delete_webhook(owner_id: &str, webhook_id: &str, env: &Env) -> ApiResult<()> {
+    // Ensure it exists and belongs to the user
+    get_webhook(owner_id, webhook_id, env).await?;
+
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let statements = vec![
+        db.prepare("DELETE FROM webhook_deliveries WHERE webhook_id = ?")
+            .bind(&[webhook_id.into()])
+            .map_err(|_| ApiError::internal("Failed to prepare delete"))?,
+        db.prepare("DELETE FROM webhooks WHERE id = ? AND owner_id = ?")
+            .bind(&[webhook_id.into(), owner_id.into()])
+            .map_err(|_| ApiError::internal("Failed to prepare delete"))?,
+    ];
+
+    db.batch(statements)
         .await
-        .map_err(|_| ApiError::internal("Failed to read templates"))?;
-    Ok(data
-        .map(|d| serde_json::from_str(&d).unwrap_or_default())
-        .unwrap_or_default())
+        .map_err(|_| ApiError::internal("Failed to delete webhook"))?;
+
+    Ok(())
 }
 
-pub async fn create_config_template(
-    _user_id: &str,
-    _template: &fleet::CreateTemplateRequest,
-    _env: &Env,
-) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"id": uuid::Uuid::new_v4().to_string(), "status": "created"}))
+fn optional_i64(value: Option<i64>) -> wasm_bindgen::JsValue {
+    value.map_or(wasm_bindgen::JsValue::NULL, |v| (v as f64).into())
+}
+
+/// Record a new delivery in the log
+pub async fn insert_webhook_delivery(
+    delivery: &webhooks::WebhookDelivery,
+    env: &Env,
+) -> ApiResult<()> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let stmt = db
+        .prepare(
+            "INSERT INTO webhook_deliveries (id, webhook_id, event, payload, status, attempts, response_status, last_error, next_attempt_at, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&[
+            delivery.id.as_str().into(),
+            delivery.webhook_id.as_str().into(),
+            delivery.event.as_str().into(),
+            delivery.payload.as_str().into(),
+            delivery.status.as_str().into(),
+            (delivery.attempts as f64).into(),
+            optional_i64(delivery.response_status.map(i64::from)),
+            delivery
+                .last_error
+                .as_deref()
+                .map_or(wasm_bindgen::JsValue::NULL, Into::into),
+            optional_i64(delivery.next_attempt_at),
+            (delivery.created_at as f64).into(),
+            (delivery.updated_at as f64).into(),
+        ])
+        .map_err(|_| ApiError::internal("Failed to prepare insert"))?;
+
+    stmt.run()
+        .await
+        .map_err(|_| ApiError::internal("Failed to record webhook delivery"))?;
+
+    Ok(())
+}
+
+/// Persist the outcome of a delivery attempt
+pub async fn update_webhook_delivery(
+    delivery: &webhooks::WebhookDelivery,
+    env: &Env,
+) -> ApiResult<()> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let stmt = db
+        .prepare(
+            "UPDATE webhook_deliveries SET status = ?, attempts = ?, response_status = ?, last_error = ?, next_attempt_at = ?, updated_at = ? WHERE id = ?",
+        )
+        .bind(&[
+            delivery.status.as_str().into(),
+            (delivery.attempts as f64).into(),
+            optional_i64(delivery.response_status.map(i64::from)),
+            delivery
+                .last_error
+                .as_deref()
+                .map_or(wasm_bindgen::JsValue::NULL, Into::into),
+            optional_i64(delivery.next_attempt_at),
+            (delivery.updated_at as f64).into(),
+            delivery.id.as_str().into(),
+        ])
+        .map_err(|_| ApiError::internal("Failed to prepare update"))?;
+
+    stmt.run()
+        .await
+        .map_err(|_| ApiError::internal("Failed to update webhook delivery"))?;
+
+    Ok(())
+}
+
+/// A webhook's most recent deliveries, newest first
+pub async fn list_webhook_deliveries(
+    webhook_id: &str,
+    limit: u32,
+    env: &Env,
+) -> ApiResult<Vec<webhooks::WebhookDelivery>> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let stmt = db
+        .prepare(
+            "SELECT * FROM webhook_deliveries WHERE webhook_id = ? ORDER BY created_at DESC LIMIT ?",
+        )
+        .bind(&[webhook_id.into(), (limit as f64).into()])
+        .map_err(|_| ApiError::internal("Failed to prepare query"))?;
+
+    let results = stmt
+        .all()
+        .await
+        .map_err(|_| ApiError::internal("Failed to list webhook deliveries"))?;
+
+    results
+        .results::<webhooks::WebhookDelivery>()
+        .map_err(|_| ApiError::internal("Failed to parse delivery rows"))
 }
 
-pub async fn apply_config_template(
-    _template_id: &str,
-    _apply: &fleet::ApplyTemplateRequest,
-    _env: &Env,
-) -> ApiResult<serde_json::Value> {
-    Ok(serde_json::json!({"status": "applied"}))
+/// Pending deliveries whose next attempt is due
+pub async fn list_due_webhook_deliveries(
+    now: i64,
+    limit: u32,
+    env: &Env,
+) -> ApiResult<Vec<webhooks::WebhookDelivery>> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let stmt = db
+        .prepare(
+            "SELECT * FROM webhook_deliveries WHERE status = 'pending' AND next_attempt_at <= ? ORDER BY next_attempt_at LIMIT ?",
+        )
+        .bind(&[(now as f64).into(), (limit as f64).into()])
+        .map_err(|_| ApiError::internal("Failed to prepare query"))?;
+
+    let results = stmt
+        .all()
+        .await
+        .map_err(|_| ApiError::internal("Failed to list due deliveries"))?;
+
+    results
+        .results::<webhooks::WebhookDelivery>()
+        .map_err(|_| ApiError::internal("Failed to parse delivery rows"))
+}
+
+// ========== Audit Log Functions (D1) ==========
+
+/// Most rows written by a single audit export
+const MAX_AUDIT_EXPORT_ROWS: u32 = 10_000;
+
+/// Audit log row as stored in D1
+#[derive(serde::Deserialize)]
+struct AuditRow {
+    id: String,
+    timestamp: i64,
+    user_id: String,
+    device_id: Option<String>,
+    action: String,
+    resource_type: String,
+    resource_id: String,
+    changes: Option<String>,
+    ip_address: String,
+    user_agent: String,
+    token_id: Option<String>,
+}
+
+impl From<AuditRow> for fleet::AuditLogEntry {
+    fn from(row: AuditRow) -> Self {
+        Self {
+            id: row.id,
+            timestamp: row.timestamp,
+            user_id: row.user_id,
+            device_id: row.device_id,
+            action: row.action,
+            resource_type: row.resource_type,
+            resource_id: row.resource_id,
+            changes: row.changes.and_then(|c| serde_json::from_str(&c).ok()),
+            ip_address: row.ip_address,
+            user_agent: row.user_agent,
+            token_id: row.token_id,
+        }
+    }
 }
 
-// ========== Onboarding Functions ==========
+/// Build the WHERE clause and parameters for the audit entries in `scope`.
+///
+/// Personal scopes cover every entry of the user's own, including those
+/// without a device; other scopes cover the entries of their devices.
+fn audit_filter_sql(
+    scope: DeviceScope<'_>,
+    filter: &audit::AuditFilter,
+) -> (String, Vec<wasm_bindgen::JsValue>) {
+    let (condition, param) = match scope {
+        DeviceScope::Personal(user_id) => ("user_id = ?".to_string(), user_id.to_string()),
+        scope => scope.condition("device_id"),
+    };
+    let mut sql = format!(" WHERE {}", condition);
+    let mut params: Vec<wasm_bindgen::JsValue> = vec![param.as_str().into()];
 
-/// Get the list of available routers for purchase
-pub fn get_onboarding_routers() -> ApiResult<serde_json::Value> {
-    let routers = vec![
-        serde_json::json!({
-            "id": "asus-rt-ax92u",
-            "name": "RT-AX92U",
-            "manufacturer": "ASUS",
-            "firmware": "Merlin NG",
-            "price": 299,
-            "specs": {
-                "cpu": "Quad-core 1.8GHz",
-                "ram": "512MB",
-                "storage": "256MB NAND",
-                "wan_ports": "1x Gigabit",
-                "lan_ports": "4x Gigabit",
-                "wifi": "WiFi 6 (AX6100) Tri-band",
-                "max_devices": 75
-            },
-            "features": [
-                "WiFi 6 (802.11ax) tri-band",
-                "AiMesh support for mesh networking",
-                "AiProtection Pro security",
-                "Advanced QoS and traffic management",
-                "USB 3.0 port for network storage",
-                "ASUSWRT-Merlin custom firmware"
-            ],
-            "image": "https://placehold.co/400x300/1e293b/60a5fa?text=ASUS+RT-AX92U",
-            "recommended": false,
-            "in_stock": true
-        }),
-        serde_json::json!({
-            "id": "gl-inet-flint-2",
-            "name": "Flint 2 (GL-MT6000)",
-            "manufacturer": "GL.iNet",
-            "firmware": "OpenWrt",
-            "price": 199,
-            "specs": {
-                "cpu": "Quad-core 2.0GHz ARM Cortex-A53",
-                "ram": "1GB DDR4",
-                "storage": "8GB eMMC + 128MB NAND",
-                "wan_ports": "1x 2.5Gb",
-                "lan_ports": "4x Gigabit",
-                "wifi": "WiFi 6 (AX6000) Dual-band",
-                "max_devices": 100
-            },
-            "features": [
-                "WiFi 6 dual-band with 160MHz support",
-                "OpenWrt native for maximum flexibility",
-                "2.5Gb WAN port for multi-gig connections",
-                "Pre-configured VPN client support",
-                "Open-source firmware with active community",
-                "Best price-to-performance ratio"
-            ],
-            "image": "https://placehold.co/400x300/1e293b/10b981?text=GL.iNet+Flint+2",
-            "recommended": true,
-            "in_stock": true
-        }),
-        serde_json::json!({
-            "id": "linksys-wrt3200acm",
-            "name": "WRT3200ACM",
-            "manufacturer": "Linksys",
-            "firmware": "OpenWrt",
-            "price": 179,
-            "specs": {
-                "cpu": "Dual-core 1.8GHz ARM",
-                "ram": "512MB DDR3",
-                "storage": "256MB NAND",
-                "wan_ports": "1x Gigabit",
-                "lan_ports": "4x Gigabit",
-                "wifi": "AC3200 Tri-Stream",
-                "max_devices": 60
-            },
-            "features": [
-                "OpenWrt champion with excellent support",
-                "Dual-core 1.8GHz for strong performance",
-                "eSATA + USB 3.0 + USB 2.0 ports",
-                "Proven reliability and stability",
-                "Large community and documentation",
-                "Budget-friendly OpenWrt option"
-            ],
-            "image": "https://placehold.co/400x300/1e293b/8b5cf6?text=Linksys+WRT3200ACM",
-            "recommended": false,
-            "in_stock": true
-        }),
-        serde_json::json!({
-            "id": "gl-inet-flint-3",
-            "name": "Flint 3",
-            "manufacturer": "GL.iNet",
-            "firmware": "OpenWrt",
-            "price": 299,
-            "specs": {
-                "cpu": "Quad-core 2.2GHz",
-                "ram": "2GB DDR4",
-                "storage": "8GB eMMC",
-                "wan_ports": "1x 2.5Gb",
-                "lan_ports": "4x 2.5Gb",
-                "wifi": "WiFi 7 (BE11000)",
-                "max_devices": 150
-            },
-            "features": [
-                "WiFi 7 (802.11be) cutting-edge",
-                "5x 2.5Gb ports for multi-gig network",
-                "2GB RAM for advanced workloads",
-                "Built-in WireGuard and Tailscale",
-                "Future-proof connectivity",
-                "Premium OpenWrt experience"
-            ],
-            "image": "https://placehold.co/400x300/1e293b/f59e0b?text=GL.iNet+Flint+3",
-            "recommended": false,
-            "in_stock": true
-        }),
+    let text_filters = [
+        ("action", &filter.action),
+        ("resource_type", &filter.resource_type),
+        ("resource_id", &filter.resource_id),
     ];
+    for (column, value) in text_filters {
+        if let Some(value) = value {
+            sql.push_str(&format!(" AND {} = ?", column));
+            params.push(value.as_str().into());
+        }
+    }
+    if let Some(date_start) = filter.date_start {
+        sql.push_str(" AND timestamp >= ?");
+        params.push((date_start as f64).into());
+    }
+    if let Some(date_end) = filter.date_end {
+        sql.push_str(" AND timestamp < ?");
+        params.push((date_end as f64).into());
+    }
 
-    Ok(serde_json::json!({
-        "success": true,
-        "result": routers
-    }))
+    (sql, params)
 }
 
-/// Create a new router order with pre-configuration
-pub async fn create_onboarding_order(
-    order: &onboarding::OrderSubmission,
+/// Query the audit entries in `scope`, newest first
+async fn query_audit_logs(
+    scope: DeviceScope<'_>,
+    filter: &audit::AuditFilter,
+    limit: u32,
+    offset: u32,
     env: &Env,
-) -> ApiResult<onboarding::OrderResponse> {
-    // Generate order ID
-    let order_id = format!("ORD-{}", uuid::Uuid::new_v4().to_string().replace('-', "")[..12].to_uppercase());
+) -> ApiResult<Vec<fleet::AuditLogEntry>> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
 
-    // Generate device ID (pre-provision)
-    let device_id = format!("DEV-{}", uuid::Uuid::new_v4().to_string().replace('-', "")[..9].to_uppercase());
+    let (where_sql, mut params) = audit_filter_sql(scope, filter);
+    let sql = format!(
+        "SELECT * FROM audit_logs{} ORDER BY timestamp DESC LIMIT ? OFFSET ?",
+        where_sql
+    );
+    params.push((limit as f64).into());
+    params.push((offset as f64).into());
 
-    // Calculate estimated delivery (9 days from now)
-    let now = chrono::Utc::now();
-    let estimated_delivery = now + chrono::Duration::days(9);
+    let stmt = db
+        .prepare(&sql)
+        .bind(&params)
+        .map_err(|_| ApiError::internal("Failed to bind audit query parameters"))?;
 
-    let response = onboarding::OrderResponse {
-        order_id: order_id.clone(),
-        device_id: device_id.clone(),
-        estimated_delivery: estimated_delivery.to_rfc3339(),
-        tracking_url: None,
-        setup_instructions: "https://docs.ngfw.sh/setup/quick-start".to_string(),
-        status: onboarding::OrderStatus::Pending,
-        created_at: now.to_rfc3339(),
+    let results = stmt
+        .all()
+        .await
+        .map_err(|e| ApiError::internal(format!("D1 audit query failed: {}", e)))?;
+
+    let rows: Vec<AuditRow> = results
+        .results()
+        .map_err(|_| ApiError::internal("Failed to parse audit rows"))?;
+
+    Ok(rows.into_iter().map(Into::into).collect())
+}
+
+/// Record an audit entry
+pub async fn insert_audit_log(entry: &fleet::AuditLogEntry, env: &Env) -> ApiResult<()> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let changes = entry
+        .changes
+        .as_ref()
+        .map(|c| wasm_bindgen::JsValue::from(c.to_string()))
+        .unwrap_or(wasm_bindgen::JsValue::NULL);
+    let optional_str = |value: &Option<String>| {
+        value
+            .as_deref()
+            .map(wasm_bindgen::JsValue::from)
+            .unwrap_or(wasm_bindgen::JsValue::NULL)
     };
 
-    // Store order in KV for later retrieval
-    let kv = env
-        .kv("CONFIGS")
-        .map_err(|_| ApiError::internal("Failed to access config store"))?;
+    let stmt = db
+        .prepare(
+            "INSERT INTO audit_logs (id, timestamp, user_id, device_id, action, resource_type, resource_id, changes, ip_address, user_agent, token_id) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&[
+            entry.id.as_str().into(),
+            (entry.timestamp as f64).into(),
+            entry.user_id.as_str().into(),
+            optional_str(&entry.device_id),
+            entry.action.as_str().into(),
+            entry.resource_type.as_str().into(),
+            entry.resource_id.as_str().into(),
+            changes,
+            entry.ip_address.as_str().into(),
+            entry.user_agent.as_str().into(),
+            optional_str(&entry.token_id),
+        ])
+        .map_err(|_| ApiError::internal("Failed to prepare insert"))?;
 
-    let order_data = serde_json::json!({
-        "order_id": order_id,
-        "device_id": device_id,
-        "router_id": order.router_id,
-        "subscription_plan": order.subscription_plan,
-        "status": "pending",
-        "created_at": now.to_rfc3339()
-    });
+    stmt.run()
+        .await
+        .map_err(|_| ApiError::internal("Failed to record audit log entry"))?;
 
-    kv.put(
-        &format!("onboarding:order:{}", order_id),
-        serde_json::to_string(&order_data)
-            .map_err(|_| ApiError::internal("Failed to serialize order"))?,
-    )
-    .map_err(|_| ApiError::internal("Failed to store order"))?
-    .execute()
-    .await
-    .map_err(|_| ApiError::internal("Failed to save order"))?;
+    Ok(())
+}
 
-    // Store device pre-provisioning record
-    let device_data = serde_json::json!({
-        "device_id": device_id,
-        "order_id": order_id,
-        "device_name": order.config.device_name,
-        "status": "provisioning",
-        "created_at": now.to_rfc3339()
-    });
+/// List the audit entries in `scope` with filtering and pagination
+pub async fn list_audit_logs(
+    scope: DeviceScope<'_>,
+    query: &audit::AuditListQuery,
+    env: &Env,
+) -> ApiResult<audit::AuditListResponse> {
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+    let offset = query.offset.unwrap_or(0);
 
-    kv.put(
-        &format!("onboarding:device:{}", device_id),
-        serde_json::to_string(&device_data)
-            .map_err(|_| ApiError::internal("Failed to serialize device"))?,
-    )
-    .map_err(|_| ApiError::internal("Failed to store device"))?
-    .execute()
-    .await
-    .map_err(|_| ApiError::internal("Failed to save device"))?;
+    let result = query_audit_logs(scope, &query.filter, limit, offset, env).await?;
+
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+    let (where_sql, params) = audit_filter_sql(scope, &query.filter);
+    let count_stmt = db
+        .prepare(format!("SELECT COUNT(*) as total FROM audit_logs{}", where_sql))
+        .bind(&params)
+        .map_err(|_| ApiError::internal("Failed to bind count parameters"))?;
+
+    #[derive(serde::Deserialize)]
+    struct CountRow {
+        total: u64,
+    }
+
+    let total = count_stmt
+        .first::<CountRow>(None)
+        .await
+        .map_err(|e| ApiError::internal(format!("D1 count query failed: {}", e)))?
+        .map(|r| r.total)
+        .unwrap_or(0);
 
-    Ok(response)
+    Ok(audit::AuditListResponse {
+        success: true,
+        result,
+        total,
+    })
 }
 
-/// Get the onboarding status for a user
-pub async fn get_onboarding_status(
-    user_id: Option<&str>,
+/// Export the audit entries in `scope` to R2 as a JSON or CSV file,
+/// stored under the exporting user.
+///
+/// Writes at most `MAX_AUDIT_EXPORT_ROWS` entries, newest first, and
+/// returns the export id and download URL like `export_logs`.
+pub async fn export_audit_logs(
+    scope: DeviceScope<'_>,
+    user_id: &str,
+    req: &audit::AuditExportRequest,
     env: &Env,
-) -> ApiResult<serde_json::Value> {
-    let now = chrono::Utc::now().to_rfc3339();
+) -> ApiResult<logs::LogExportResponse> {
+    let rows = query_audit_logs(scope, &req.filter, MAX_AUDIT_EXPORT_ROWS, 0, env).await?;
 
-    // If a user_id is provided, look up their onboarding state
-    if let Some(uid) = user_id {
-        let kv = env
-            .kv("CONFIGS")
-            .map_err(|_| ApiError::internal("Failed to access config store"))?;
+    let export_id = uuid::Uuid::new_v4().to_string();
 
-        let key = format!("onboarding:status:{}", uid);
-        if let Some(data) = kv
-            .get(&key)
-            .text()
-            .await
-            .map_err(|_| ApiError::internal("Failed to read onboarding status"))?
-        {
-            let status: serde_json::Value = serde_json::from_str(&data)
-                .map_err(|_| ApiError::internal("Invalid onboarding status format"))?;
-            return Ok(serde_json::json!({
-                "success": true,
-                "result": status
-            }));
+    let (file_content, extension) = match req.format {
+        logs::ExportFormat::Json => {
+            let json = serde_json::to_string_pretty(&rows)
+                .map_err(|_| ApiError::internal("Failed to serialize export JSON"))?;
+            (json, "json")
         }
-    }
-
-    // Default: not started
-    Ok(serde_json::json!({
-        "success": true,
-        "result": {
-            "completed": false,
-            "current_step": "router_selection",
-            "last_updated": now
+        logs::ExportFormat::Csv => {
+            let headers = "id,timestamp,user_id,device_id,action,resource_type,resource_id,changes,ip_address,user_agent,token_id";
+            let mut csv = String::from(headers);
+            for row in &rows {
+                let changes = row
+                    .changes
+                    .as_ref()
+                    .map(|c| c.to_string())
+                    .unwrap_or_default();
+                csv.push('\n');
+                csv.push_str(&format!(
+                    "{},{},{},{},{},{},{},{},{},{},{}",
+                    csv_escape(&row.id),
+                    row.timestamp,
+                    csv_escape(&row.user_id),
+                    csv_escape(row.device_id.as_deref().unwrap_or("")),
+                    csv_escape(&row.action),
+                    csv_escape(&row.resource_type),
+                    csv_escape(&row.resource_id),
+                    csv_escape(&changes),
+                    csv_escape(&row.ip_address),
+                    csv_escape(&row.user_agent),
+                    csv_escape(row.token_id.as_deref().unwrap_or("")),
+                ));
+            }
+            (csv, "csv")
         }
-    }))
+    };
+
+    let r2 = env
+        .bucket("REPORTS")
+        .map_err(|_| ApiError::internal("Failed to access reports storage"))?;
+
+    let r2_key = format!("audit/{}/{}.{}", user_id, export_id, extension);
+    r2.put(&r2_key, file_content.as_bytes().to_vec())
+        .execute()
+        .await
+        .map_err(|_| ApiError::internal("Failed to write export to R2"))?;
+
+    Ok(logs::LogExportResponse {
+        success: true,
+        message: format!("Exported {} audit log entries", rows.len()),
+        export_id,
+        download_url: Some(format!("https://reports.ngfw.sh/{}", r2_key)),
+    })
 }
+// ========== Plan Functions (D1) ==========
 
-// ========== Report Functions ==========
+/// How long plan limits are cached in KV
+const PLAN_LIMITS_CACHE_TTL_SECS: u64 = 300;
+
+/// Limits of a billing plan from the `plan_limits` table, cached in KV
+pub async fn get_plan_limits(plan_id: &str, env: &Env) -> ApiResult<user::PlanLimits> {
+    let cache_key = format!("plan_limits:{}", plan_id);
+    if let Ok(cache) = env.kv("CACHE")
+        && let Ok(Some(cached)) = cache.get(&cache_key).text().await
+        && let Ok(limits) = serde_json::from_str::<user::PlanLimits>(&cached)
+    {
+        return Ok(limits);
+    }
 
-/// Check if a device belongs to a specific owner via D1
-pub async fn check_device_ownership(
-    device_id: &str,
-    owner_id: &str,
-    env: &Env,
-) -> ApiResult<bool> {
     let db = env
         .d1("DB")
         .map_err(|_| ApiError::internal("Failed to access database"))?;
 
     let stmt = db
-        .prepare("SELECT id FROM devices WHERE id = ? AND owner_id = ?")
-        .bind(&[device_id.into(), owner_id.into()])
+        .prepare("SELECT limit_key, limit_value FROM plan_limits WHERE plan_id = ?")
+        .bind(&[plan_id.into()])
         .map_err(|_| ApiError::internal("Failed to prepare query"))?;
 
-    let result = stmt
-        .first::<serde_json::Value>(None)
+    #[derive(serde::Deserialize)]
+    struct LimitRow {
+        limit_key: String,
+        limit_value: i64,
+    }
+
+    let rows: Vec<LimitRow> = stmt
+        .all()
         .await
-        .map_err(|_| ApiError::internal("Failed to query devices"))?;
+        .map_err(|_| ApiError::internal("Failed to query plan limits"))?
+        .results()
+        .map_err(|_| ApiError::internal("Failed to parse plan limits"))?;
+    if rows.is_empty() {
+        return Err(ApiError::not_found("Plan"));
+    }
+
+    let values = rows
+        .into_iter()
+        .map(|row| (row.limit_key, row.limit_value))
+        .collect();
+    let limits = user::PlanLimits::from_values(&values);
 
-    Ok(result.is_some())
+    // Best-effort cache write
+    if let Ok(cache) = env.kv("CACHE")
+        && let Ok(json) = serde_json::to_string(&limits)
+        && let Ok(builder) = cache.put(&cache_key, json)
+    {
+        let _ = builder
+            .expiration_ttl(PLAN_LIMITS_CACHE_TTL_SECS)
+            .execute()
+            .await;
+    }
+
+    Ok(limits)
 }
+// ========== Organization Functions (D1) ==========
 
-/// List reports for a user with optional filters
-pub async fn list_reports(
-    owner_id: &str,
-    query: &report::ReportListQuery,
+const ORG_COLUMNS: &str = "o.id, o.name, o.created_by, o.created_at";
+
+/// Organization row joined with the caller's membership
+#[derive(serde::Deserialize)]
+struct OrgMembershipRow {
+    id: String,
+    name: String,
+    created_by: String,
+    created_at: i64,
+    role: String,
+}
+
+impl OrgMembershipRow {
+    fn into_membership(self) -> Option<orgs::OrgMembership> {
+        Some(orgs::OrgMembership {
+            role: orgs::Role::parse(&self.role)?,
+            organization: orgs::Organization {
+                id: self.id,
+                name: self.name,
+                created_by: self.created_by,
+                created_at: self.created_at,
+            },
+        })
+    }
+}
+
+/// Member row as stored in D1
+#[derive(serde::Deserialize)]
+struct OrgMemberRow {
+    user_id: String,
+    role: String,
+    created_at: i64,
+}
+
+/// Create an organization with `user_id` as its first owner
+pub async fn create_org(
+    user_id: &str,
+    request: &orgs::CreateOrgRequest,
     env: &Env,
-) -> ApiResult<serde_json::Value> {
+) -> ApiResult<orgs::Organization> {
     let db = env
         .d1("DB")
         .map_err(|_| ApiError::internal("Failed to access database"))?;
 
-    let mut sql = String::from("SELECT * FROM reports WHERE owner_id = ?");
-    let mut params: Vec<wasm_bindgen::JsValue> = vec![owner_id.into()];
-
-    if let Some(ref device_id) = query.device_id {
-        sql.push_str(" AND device_id = ?");
-        params.push(device_id.as_str().into());
-    }
-    if let Some(ref report_type) = query.report_type {
-        sql.push_str(" AND type = ?");
-        params.push(report_type.as_str().into());
-    }
-    if let Some(ref status) = query.status {
-        sql.push_str(" AND status = ?");
-        params.push(status.as_str().into());
-    }
+    let org = orgs::Organization {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: request.name.clone(),
+        created_by: user_id.to_string(),
+        created_at: chrono::Utc::now().timestamp(),
+    };
+    let now: wasm_bindgen::JsValue = (org.created_at as f64).into();
 
-    // Count total before applying LIMIT/OFFSET
-    let count_sql = sql.replace("SELECT *", "SELECT COUNT(*) as total");
-    let count_stmt = db
-        .prepare(&count_sql)
-        .bind(&params)
-        .map_err(|_| ApiError::internal("Failed to prepare count query"))?;
+    let statements = vec![
+        db.prepare(
+            "INSERT INTO organizations (id, name, created_by, created_at, updated_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(&[
+            org.id.as_str().into(),
+            org.name.as_str().into(),
+            user_id.into(),
+            now.clone(),
+            now.clone(),
+        ])
+        .map_err(|_| ApiError::internal("Failed to prepare insert"))?,
+        db.prepare(
+            "INSERT INTO org_members (org_id, user_id, role, created_at) VALUES (?, ?, 'owner', ?)",
+        )
+        .bind(&[org.id.as_str().into(), user_id.into(), now])
+        .map_err(|_| ApiError::internal("Failed to prepare insert"))?,
+    ];
 
-    let count_result = count_stmt
-        .first::<serde_json::Value>(None)
+    db.batch(statements)
         .await
-        .map_err(|_| ApiError::internal("Failed to count reports"))?;
+        .map_err(|_| ApiError::internal("Failed to create organization"))?;
 
-    let total = count_result
-        .and_then(|v| v.get("total").and_then(|t| t.as_i64()))
-        .unwrap_or(0);
+    Ok(org)
+}
 
-    sql.push_str(" ORDER BY created_at DESC LIMIT ? OFFSET ?");
-    params.push((query.limit as f64).into());
-    params.push((query.offset as f64).into());
+/// Organizations the user belongs to, with their role in each
+pub async fn list_user_orgs(user_id: &str, env: &Env) -> ApiResult<Vec<orgs::OrgMembership>> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
 
+    let sql = format!(
+        "SELECT {}, m.role FROM organizations o JOIN org_members m ON m.org_id = o.id \
+         WHERE m.user_id = ? ORDER BY o.created_at",
+        ORG_COLUMNS
+    );
     let stmt = db
         .prepare(&sql)
-        .bind(&params)
+        .bind(&[user_id.into()])
         .map_err(|_| ApiError::internal("Failed to prepare query"))?;
 
-    let results = stmt
+    let rows = stmt
         .all()
         .await
-        .map_err(|_| ApiError::internal("Failed to list reports"))?;
+        .map_err(|_| ApiError::internal("Failed to list organizations"))?
+        .results::<OrgMembershipRow>()
+        .map_err(|_| ApiError::internal("Failed to parse organization rows"))?;
 
-    let rows = results
-        .results::<serde_json::Value>()
-        .map_err(|_| ApiError::internal("Failed to parse report rows"))?;
+    Ok(rows
+        .into_iter()
+        .filter_map(OrgMembershipRow::into_membership)
+        .collect())
+}
 
-    Ok(serde_json::json!({
-        "success": true,
-        "result": rows,
-        "total": total
-    }))
+/// Get an organization the user belongs to
+pub async fn get_org(user_id: &str, org_id: &str, env: &Env) -> ApiResult<orgs::OrgMembership> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let sql = format!(
+        "SELECT {}, m.role FROM organizations o JOIN org_members m ON m.org_id = o.id \
+         WHERE o.id = ? AND m.user_id = ?",
+        ORG_COLUMNS
+    );
+    let stmt = db
+        .prepare(&sql)
+        .bind(&[org_id.into(), user_id.into()])
+        .map_err(|_| ApiError::internal("Failed to prepare query"))?;
+
+    stmt.first::<OrgMembershipRow>(None)
+        .await
+        .map_err(|_| ApiError::internal("Failed to query organizations"))?
+        .and_then(OrgMembershipRow::into_membership)
+        .ok_or_else(|| ApiError::not_found("Organization"))
 }
 
-/// Create a new report record in D1
-pub async fn create_report(
-    owner_id: &str,
-    body: &report::GenerateReportRequest,
+/// Rename an organization
+pub async fn update_org(
+    org_id: &str,
+    request: &orgs::UpdateOrgRequest,
     env: &Env,
-) -> ApiResult<report::Report> {
+) -> ApiResult<()> {
     let db = env
         .d1("DB")
         .map_err(|_| ApiError::internal("Failed to access database"))?;
 
-    let id = uuid::Uuid::new_v4().to_string();
-    let now = chrono::Utc::now().to_rfc3339();
-
-    let stmt = db
-        .prepare(
-            "INSERT INTO reports (id, device_id, owner_id, type, format, status, title, date_start, date_end, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-        )
+    let now = chrono::Utc::now().timestamp();
+    db.prepare("UPDATE organizations SET name = ?, updated_at = ? WHERE id = ?")
         .bind(&[
-            id.as_str().into(),
-            body.device_id.as_str().into(),
-            owner_id.into(),
-            body.report_type.as_str().into(),
-            body.format.as_str().into(),
-            "pending".into(),
-            body.title.as_str().into(),
-            body.date_start.as_str().into(),
-            body.date_end.as_str().into(),
-            now.as_str().into(),
+            request.name.as_str().into(),
+            (now as f64).into(),
+            org_id.into(),
         ])
-        .map_err(|_| ApiError::internal("Failed to prepare insert"))?;
+        .map_err(|_| ApiError::internal("Failed to prepare update"))?
+        .run()
+        .await
+        .map_err(|_| ApiError::internal("Failed to update organization"))?;
 
-    stmt.run()
+    Ok(())
+}
+
+/// Delete an organization and its memberships.
+///
+/// Refused while devices still belong to it.
+pub async fn delete_org(org_id: &str, env: &Env) -> ApiResult<()> {
+    if !get_org_devices(org_id, env).await?.is_empty() {
+        return Err(ApiError::bad_request(
+            "Remove the organization's devices before deleting it",
+        ));
+    }
+
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    let statements = vec![
+        db.prepare("DELETE FROM org_members WHERE org_id = ?")
+            .bind(&[org_id.into()])
+            .map_err(|_| ApiError::internal("Failed to prepare delete"))?,
+        db.prepare("DELETE FROM organizations WHERE id = ?")
+            .bind(&[org_id.into()])
+            .map_err(|_| ApiError::internal("Failed to prepare delete"))?,
+    ];
+
+    db.batch(statements)
         .await
-        .map_err(|_| ApiError::internal("Failed to insert report"))?;
+        .map_err(|_| ApiError::internal("Failed to delete organization"))?;
 
-    Ok(report::Report {
-        id,
-        device_id: body.device_id.clone(),
-        owner_id: owner_id.to_string(),
-        report_type: body.report_type.clone(),
-        format: body.format.clone(),
-        status: "pending".to_string(),
-        title: body.title.clone(),
-        date_start: body.date_start.clone(),
-        date_end: body.date_end.clone(),
-        r2_key: None,
-        file_size: None,
-        created_at: now,
-        completed_at: None,
-        error_message: None,
-    })
+    Ok(())
 }
 
-/// Get a single report by ID, scoped to owner
-pub async fn get_report(
-    report_id: &str,
-    owner_id: &str,
-    env: &Env,
-) -> ApiResult<serde_json::Value> {
+/// The user's role in an organization, `None` when not a member
+pub async fn get_org_role(org_id: &str, user_id: &str, env: &Env) -> ApiResult<Option<orgs::Role>> {
     let db = env
         .d1("DB")
         .map_err(|_| ApiError::internal("Failed to access database"))?;
 
     let stmt = db
-        .prepare("SELECT * FROM reports WHERE id = ? AND owner_id = ?")
-        .bind(&[report_id.into(), owner_id.into()])
+        .prepare("SELECT role FROM org_members WHERE org_id = ? AND user_id = ?")
+        .bind(&[org_id.into(), user_id.into()])
         .map_err(|_| ApiError::internal("Failed to prepare query"))?;
 
-    let row = stmt
-        .first::<serde_json::Value>(None)
+    let role = stmt
+        .first::<String>(Some("role"))
         .await
-        .map_err(|_| ApiError::internal("Failed to query report"))?
-        .ok_or_else(|| ApiError::not_found("Report"))?;
-
-    let download_url = if row.get("status").and_then(|s| s.as_str()) == Some("completed") {
-        row.get("r2_key")
-            .and_then(|k| k.as_str())
-            .map(|k| format!("https://reports.ngfw.sh/{}", k))
-    } else {
-        None
-    };
-
-    let mut result = row.clone();
-    if let Some(obj) = result.as_object_mut() {
-        obj.insert(
-            "download_url".to_string(),
-            match download_url {
-                Some(url) => serde_json::Value::String(url),
-                None => serde_json::Value::Null,
-            },
-        );
-    }
+        .map_err(|_| ApiError::internal("Failed to query membership"))?;
 
-    Ok(serde_json::json!({
-        "success": true,
-        "result": result
-    }))
+    Ok(role.as_deref().and_then(orgs::Role::parse))
 }
-
-/// Delete a report by ID, scoped to owner. Also removes the R2 object if present.
-pub async fn delete_report(
-    report_id: &str,
-    owner_id: &str,
-    env: &Env,
-) -> ApiResult<serde_json::Value> {
+
+/// Members of an organization, oldest first
+pub async fn list_org_members(org_id: &str, env: &Env) -> ApiResult<Vec<orgs::OrgMember>> {
     let db = env
         .d1("DB")
         .map_err(|_| ApiError::internal("Failed to access database"))?;
 
-    // Fetch the report to get the r2_key before deleting
     let stmt = db
-        .prepare("SELECT * FROM reports WHERE id = ? AND owner_id = ?")
-        .bind(&[report_id.into(), owner_id.into()])
+        .prepare(
+            "SELECT user_id, role, created_at FROM org_members WHERE org_id = ? ORDER BY created_at",
+        )
+        .bind(&[org_id.into()])
         .map_err(|_| ApiError::internal("Failed to prepare query"))?;
 
-    let row = stmt
-        .first::<serde_json::Value>(None)
+    let rows = stmt
+        .all()
         .await
-        .map_err(|_| ApiError::internal("Failed to query report"))?
-        .ok_or_else(|| ApiError::not_found("Report"))?;
+        .map_err(|_| ApiError::internal("Failed to list members"))?
+        .results::<OrgMemberRow>()
+        .map_err(|_| ApiError::internal("Failed to parse member rows"))?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(orgs::OrgMember {
+                role: orgs::Role::parse(&row.role)?,
+                user_id: row.user_id,
+                created_at: row.created_at,
+            })
+        })
+        .collect())
+}
 
-    // Delete the R2 object if it exists
-    if let Some(r2_key) = row.get("r2_key").and_then(|k| k.as_str()) {
-        if !r2_key.is_empty() {
-            let r2 = env
-                .bucket("REPORTS")
-                .map_err(|_| ApiError::internal("Failed to access report storage"))?;
-            let _ = r2.delete(r2_key).await;
-        }
-    }
+/// Add a member, or change the role of an existing one
+pub async fn upsert_org_member(
+    org_id: &str,
+    user_id: &str,
+    role: orgs::Role,
+    env: &Env,
+) -> ApiResult<orgs::OrgMember> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
 
-    // Delete from D1
-    let delete_stmt = db
-        .prepare("DELETE FROM reports WHERE id = ? AND owner_id = ?")
-        .bind(&[report_id.into(), owner_id.into()])
-        .map_err(|_| ApiError::internal("Failed to prepare delete"))?;
+    let now = chrono::Utc::now().timestamp();
+    db.prepare(
+        "INSERT INTO org_members (org_id, user_id, role, created_at) VALUES (?, ?, ?, ?) \
+         ON CONFLICT (org_id, user_id) DO UPDATE SET role = excluded.role",
+    )
+    .bind(&[
+        org_id.into(),
+        user_id.into(),
+        role.as_str().into(),
+        (now as f64).into(),
+    ])
+    .map_err(|_| ApiError::internal("Failed to prepare insert"))?
+    .run()
+    .await
+    .map_err(|_| ApiError::internal("Failed to save member"))?;
 
-    delete_stmt
+    Ok(orgs::OrgMember {
+        user_id: user_id.to_string(),
+        role,
+        created_at: now,
+    })
+}
+
+/// Remove a member from an organization
+pub async fn remove_org_member(org_id: &str, user_id: &str, env: &Env) -> ApiResult<()> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
+
+    db.prepare("DELETE FROM org_members WHERE org_id = ? AND user_id = ?")
+        .bind(&[org_id.into(), user_id.into()])
+        .map_err(|_| ApiError::internal("Failed to prepare delete"))?
         .run()
         .await
-        .map_err(|_| ApiError::internal("Failed to delete report"))?;
+        .map_err(|_| ApiError::internal("Failed to remove member"))?;
 
-    Ok(serde_json::json!({
-        "success": true,
-        "message": "Report deleted successfully"
-    }))
+    Ok(())
 }
 
-// ========== Log Functions (D1) ==========
-
-/// List logs from D1, filtered by the authenticated user's devices.
-///
-/// The query joins `logs` with `devices` on `device_id` and filters
-/// by `owner_id` so that only logs belonging to the caller's devices
-/// are returned.  Uses the `idx_logs_device_id_timestamp` index for
-/// efficient ordering.
-pub async fn list_logs(
-    user_id: &str,
-    query: &logs::LogListQuery,
-    env: &Env,
-) -> ApiResult<logs::LogListResponse> {
+/// Number of owners of an organization
+pub async fn count_org_owners(org_id: &str, env: &Env) -> ApiResult<u64> {
     let db = env
         .d1("DB")
-        .map_err(|_| ApiError::internal("Failed to access D1 database"))?;
-
-    let limit = query.limit.unwrap_or(20).min(1000);
-    let offset = query.offset.unwrap_or(0);
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
 
-    // -- Build the dynamic WHERE clause --
-    // We accumulate owned Strings so that D1Type::Text can borrow them.
-    let mut sql = String::from(
-        "SELECT l.id, l.device_id, l.timestamp, l.level, l.category, \
-         l.message, l.source, l.metadata \
-         FROM logs l INNER JOIN devices d ON l.device_id = d.id \
-         WHERE d.owner_id = ?",
-    );
-    let mut string_params: Vec<String> = vec![user_id.to_string()];
+    let stmt = db
+        .prepare("SELECT COUNT(*) as total FROM org_members WHERE org_id = ? AND role = 'owner'")
+        .bind(&[org_id.into()])
+        .map_err(|_| ApiError::internal("Failed to prepare query"))?;
 
-    if let Some(ref device_id) = query.device_id {
-        sql.push_str(" AND l.device_id = ?");
-        string_params.push(device_id.clone());
-    }
-    if let Some(ref level) = query.level {
-        sql.push_str(" AND l.level = ?");
-        string_params.push(level.clone());
-    }
-    if let Some(ref category) = query.category {
-        sql.push_str(" AND l.category = ?");
-        string_params.push(category.clone());
-    }
-    if let Some(ref search) = query.search {
-        sql.push_str(" AND (l.message LIKE ? OR l.source LIKE ?)");
-        let pattern = format!("%{}%", search);
-        string_params.push(pattern.clone());
-        string_params.push(pattern);
-    }
-    if let Some(ref date_start) = query.date_start {
-        sql.push_str(" AND l.timestamp >= ?");
-        string_params.push(date_start.clone());
+    #[derive(serde::Deserialize)]
+    struct CountRow {
+        total: u64,
     }
-    if let Some(ref date_end) = query.date_end {
-        sql.push_str(" AND l.timestamp <= ?");
-        string_params.push(date_end.clone());
+
+    Ok(stmt
+        .first::<CountRow>(None)
+        .await
+        .map_err(|_| ApiError::internal("Failed to count owners"))?
+        .map(|r| r.total)
+        .unwrap_or(0))
+}
+// ========== API Token Functions (D1) ==========
+
+/// API token row as stored in D1
+#[derive(serde::Deserialize)]
+struct ApiTokenRow {
+    id: String,
+    user_id: String,
+    org_id: Option<String>,
+    name: String,
+    token_prefix: String,
+    scopes: String,
+    plan: String,
+    created_at: i64,
+    expires_at: i64,
+    last_used_at: Option<i64>,
+    revoked_at: Option<i64>,
+}
+
+impl From<ApiTokenRow> for tokens::StoredToken {
+    fn from(row: ApiTokenRow) -> Self {
+        Self {
+            user_id: row.user_id,
+            plan: row.plan,
+            token: tokens::ApiToken {
+                id: row.id,
+                name: row.name,
+                token_prefix: row.token_prefix,
+                scopes: serde_json::from_str(&row.scopes).unwrap_or_default(),
+                org_id: row.org_id,
+                created_at: row.created_at,
+                expires_at: row.expires_at,
+                last_used_at: row.last_used_at,
+                revoked_at: row.revoked_at,
+            },
+        }
     }
+}
 
-    sql.push_str(" ORDER BY l.timestamp DESC LIMIT ? OFFSET ?");
+const API_TOKEN_COLUMNS: &str = "id, user_id, org_id, name, token_prefix, scopes, plan, created_at, expires_at, last_used_at, revoked_at";
 
-    // Build params: text params first, then numeric limit/offset
-    let mut params: Vec<wasm_bindgen::JsValue> = string_params
-        .iter()
-        .map(|s| s.as_str().into())
-        .collect();
-    params.push((limit as f64).into());
-    params.push((offset as f64).into());
+/// Look up a token by the hash of its secret
+pub async fn find_api_token(token_hash: &str, env: &Env) -> ApiResult<Option<tokens::StoredToken>> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
 
+    let sql = format!(
+        "SELECT {} FROM api_tokens WHERE token_hash = ?",
+        API_TOKEN_COLUMNS
+    );
     let stmt = db
         .prepare(&sql)
-        .bind(&params)
-        .map_err(|_| ApiError::internal("Failed to bind log query parameters"))?;
+        .bind(&[token_hash.into()])
+        .map_err(|_| ApiError::internal("Failed to prepare query"))?;
 
-    let result = stmt
-        .all()
+    Ok(stmt
+        .first::<ApiTokenRow>(None)
         .await
-        .map_err(|e| ApiError::internal(format!("D1 query failed: {}", e)))?;
+        .map_err(|_| ApiError::internal("Failed to query API tokens"))?
+        .map(Into::into))
+}
 
-    let rows: Vec<logs::LogEntry> = result
-        .results()
-        .map_err(|e| ApiError::internal(format!("Failed to deserialize log rows: {}", e)))?;
+/// List a user's tokens, newest first
+pub async fn list_api_tokens(user_id: &str, env: &Env) -> ApiResult<Vec<tokens::ApiToken>> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
 
-    // -- Total count (for pagination) --
-    let count_stmt = db
-        .prepare(
-            "SELECT COUNT(*) as total FROM logs l \
-             INNER JOIN devices d ON l.device_id = d.id \
-             WHERE d.owner_id = ?",
-        )
+    let sql = format!(
+        "SELECT {} FROM api_tokens WHERE user_id = ? ORDER BY created_at DESC",
+        API_TOKEN_COLUMNS
+    );
+    let stmt = db
+        .prepare(&sql)
         .bind(&[user_id.into()])
-        .map_err(|_| ApiError::internal("Failed to bind count parameters"))?;
-
-    #[derive(serde::Deserialize)]
-    struct CountRow {
-        total: u64,
-    }
+        .map_err(|_| ApiError::internal("Failed to prepare query"))?;
 
-    let total = count_stmt
-        .first::<CountRow>(None)
+    let rows = stmt
+        .all()
         .await
-        .map_err(|e| ApiError::internal(format!("D1 count query failed: {}", e)))?
-        .map(|r| r.total)
-        .unwrap_or(0);
+        .map_err(|_| ApiError::internal("Failed to list API tokens"))?
+        .results::<ApiTokenRow>()
+        .map_err(|_| ApiError::internal("Failed to parse API token rows"))?;
 
-    Ok(logs::LogListResponse {
-        success: true,
-        result: rows,
-        total,
-    })
+    Ok(rows
+        .into_iter()
+        .map(|row| tokens::StoredToken::from(row).token)
+        .collect())
 }
 
-/// Export logs to R2 as a JSON or CSV file.
-///
-/// 1. Verify the requesting user owns the target device (via D1).
-/// 2. Query matching log rows from D1.
-/// 3. Format as JSON or CSV.
-/// 4. Write to the REPORTS R2 bucket.
-/// 5. Return a 202-style response with the export_id.
-pub async fn export_logs(
+/// Get one of a user's tokens
+pub async fn get_api_token(
     user_id: &str,
-    req: &logs::LogExportRequest,
+    token_id: &str,
     env: &Env,
-) -> ApiResult<logs::LogExportResponse> {
+) -> ApiResult<tokens::ApiToken> {
     let db = env
         .d1("DB")
-        .map_err(|_| ApiError::internal("Failed to access D1 database"))?;
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
 
-    // -- Verify device ownership --
-    let check_stmt = db
-        .prepare("SELECT id FROM devices WHERE id = ? AND owner_id = ?")
-        .bind(&[req.device_id.as_str().into(), user_id.into()])
-        .map_err(|_| ApiError::internal("Failed to bind device check parameters"))?;
+    let sql = format!(
+        "SELECT {} FROM api_tokens WHERE id = ? AND user_id = ?",
+        API_TOKEN_COLUMNS
+    );
+    let stmt = db
+        .prepare(&sql)
+        .bind(&[token_id.into(), user_id.into()])
+        .map_err(|_| ApiError::internal("Failed to prepare query"))?;
 
-    let device_check: Option<serde_json::Value> = check_stmt
-        .first(None)
+    stmt.first::<ApiTokenRow>(None)
         .await
-        .map_err(|e| ApiError::internal(format!("Device check failed: {}", e)))?;
-
-    if device_check.is_none() {
-        return Err(ApiError::not_found("Device not found or access denied"));
-    }
-
-    // -- Build export query --
-    let mut sql = String::from("SELECT * FROM logs WHERE device_id = ?");
-    let mut string_params: Vec<String> = vec![req.device_id.clone()];
+        .map_err(|_| ApiError::internal("Failed to query API tokens"))?
+        .map(|row| tokens::StoredToken::from(row).token)
+        .ok_or_else(|| ApiError::not_found("API token"))
+}
 
-    if let Some(ref level) = req.level {
-        sql.push_str(" AND level = ?");
-        string_params.push(level.clone());
-    }
-    if let Some(ref category) = req.category {
-        sql.push_str(" AND category = ?");
-        string_params.push(category.clone());
-    }
-    sql.push_str(" AND timestamp >= ?");
-    string_params.push(req.date_start.clone());
-    sql.push_str(" AND timestamp <= ?");
-    string_params.push(req.date_end.clone());
-    sql.push_str(" ORDER BY timestamp DESC");
+/// Insert a token; only the hash of its secret is stored
+pub async fn create_api_token(
+    user_id: &str,
+    plan: &str,
+    token: &tokens::ApiToken,
+    token_hash: &str,
+    env: &Env,
+) -> ApiResult<()> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
 
-    let params: Vec<wasm_bindgen::JsValue> = string_params
-        .iter()
-        .map(|s| s.as_str().into())
-        .collect();
+    let scopes = serde_json::to_string(&token.scopes)
+        .map_err(|_| ApiError::internal("Failed to serialize scopes"))?;
+    let org_id = token
+        .org_id
+        .as_deref()
+        .map(wasm_bindgen::JsValue::from)
+        .unwrap_or(wasm_bindgen::JsValue::NULL);
+
+    db.prepare(
+        "INSERT INTO api_tokens (id, user_id, org_id, name, token_hash, token_prefix, scopes, plan, created_at, expires_at) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&[
+        token.id.as_str().into(),
+        user_id.into(),
+        org_id,
+        token.name.as_str().into(),
+        token_hash.into(),
+        token.token_prefix.as_str().into(),
+        scopes.into(),
+        plan.into(),
+        (token.created_at as f64).into(),
+        (token.expires_at as f64).into(),
+    ])
+    .map_err(|_| ApiError::internal("Failed to prepare insert"))?
+    .run()
+    .await
+    .map_err(|_| ApiError::internal("Failed to create API token"))?;
 
-    let stmt = db
-        .prepare(&sql)
-        .bind(&params)
-        .map_err(|_| ApiError::internal("Failed to bind export query parameters"))?;
+    Ok(())
+}
 
-    let result = stmt
-        .all()
-        .await
-        .map_err(|e| ApiError::internal(format!("D1 export query failed: {}", e)))?;
+/// Revoke one of a user's tokens
+pub async fn revoke_api_token(user_id: &str, token_id: &str, env: &Env) -> ApiResult<()> {
+    // Ensure it exists and belongs to the user
+    get_api_token(user_id, token_id, env).await?;
 
-    let rows: Vec<logs::LogEntry> = result
-        .results()
-        .map_err(|e| ApiError::internal(format!("Failed to deserialize export rows: {}", e)))?;
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
 
-    // -- Format the export --
-    let export_id = uuid::Uuid::new_v4().to_string();
+    let now = chrono::Utc::now().timestamp();
+    db.prepare(
+        "UPDATE api_tokens SET revoked_at = COALESCE(revoked_at, ?) WHERE id = ? AND user_id = ?",
+    )
+    .bind(&[(now as f64).into(), token_id.into(), user_id.into()])
+    .map_err(|_| ApiError::internal("Failed to prepare update"))?
+    .run()
+    .await
+    .map_err(|_| ApiError::internal("Failed to revoke API token"))?;
 
-    let (file_content, extension) = match req.format {
-        logs::ExportFormat::Json => {
-            let json = serde_json::to_string_pretty(&rows)
-                .map_err(|_| ApiError::internal("Failed to serialize export JSON"))?;
-            (json, "json")
-        }
-        logs::ExportFormat::Csv => {
-            let headers = "id,device_id,timestamp,level,category,message,source";
-            let mut csv = String::from(headers);
-            for row in &rows {
-                csv.push('\n');
-                csv.push_str(&format!(
-                    "{},{},{},{},{},{},{}",
-                    csv_escape(&row.id),
-                    csv_escape(&row.device_id),
-                    csv_escape(&row.timestamp),
-                    csv_escape(&row.level),
-                    csv_escape(&row.category),
-                    csv_escape(&row.message),
-                    csv_escape(&row.source),
-                ));
-            }
-            (csv, "csv")
-        }
-    };
+    Ok(())
+}
 
-    // -- Write to R2 --
-    let r2 = env
-        .bucket("REPORTS")
-        .map_err(|_| ApiError::internal("Failed to access reports storage"))?;
+/// Record that a token was just used
+pub async fn touch_api_token(token_id: &str, env: &Env) -> ApiResult<()> {
+    let db = env
+        .d1("DB")
+        .map_err(|_| ApiError::internal("Failed to access database"))?;
 
-    let r2_key = format!("logs/{}/{}.{}", user_id, export_id, extension);
-    r2.put(&r2_key, file_content.as_bytes().to_vec())
-        .execute()
+    let now = chrono::Utc::now().timestamp();
+    db.prepare("UPDATE api_tokens SET last_used_at = ? WHERE id = ?")
+        .bind(&[(now as f64).into(), token_id.into()])
+        .map_err(|_| ApiError::internal("Failed to prepare update"))?
+        .run()
         .await
-        .map_err(|_| ApiError::internal("Failed to write export to R2"))?;
-
-    Ok(logs::LogExportResponse {
-        success: true,
-        message: "Log export completed".to_string(),
-        export_id,
-        download_url: Some(format!("https://reports.ngfw.sh/{}", r2_key)),
-    })
-}
+        .map_err(|_| ApiError::internal("Failed to update API token"))?;
 
-/// Escape a value for CSV output (wraps in double quotes, escaping inner quotes).
-fn csv_escape(value: &str) -> String {
-    format!("\"{}\"", value.replace('"', "\"\""))
+    Ok(())
 }
@@ -0,0 +1,210 @@
+//! IP threat feed subscriptions
+//!
+//! A feed is a published list of hostile networks, fetched by the API in
+//! one of three formats (see [`ThreatFeedFormat`]). Entries are
+//! deduplicated and aggregated into the fewest CIDR blocks (see
+//! [`crate::cidr`]) and identified by a hash of the result, so a feed that
+//! was republished unchanged is neither rewritten nor pushed again.
+//!
+//! Agents load each enabled feed into an ipset behind a drop rule that is
+//! evaluated ahead of every firewall rule. Feeds are refetched by the
+//! scheduled handler once their `update_interval` has elapsed, or on demand.
+//! A failed fetch keeps the previous networks in use and is recorded on the
+//! feed.
+
+use crate::cidr;
+use crate::models::security::{ThreatFeed, ThreatFeedFormat};
+use crate::models::{ApiError, ApiResult};
+use crate::storage;
+use futures::future::{Either, select};
+use std::time::Duration;
+use worker::*;
+
+/// Time allowed for a feed to download
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Feeds refetched per scheduled run, to stay within subrequest limits
+const REFRESH_BATCH: usize = 5;
+
+/// Networks of a fetched feed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compiled {
+    /// Fewest CIDR blocks covering every entry
+    pub networks: Vec<String>,
+    /// Version hash of `networks`
+    pub hash: String,
+}
+
+/// Entry of one feed line, without its comment. Blank and comment-only
+/// lines have none.
+fn entry(format: ThreatFeedFormat, line: &str) -> Option<&str> {
+    let marker = match format {
+        ThreatFeedFormat::SpamhausDrop => ';',
+        ThreatFeedFormat::Cidr | ThreatFeedFormat::Firehol => '#',
+    };
+    let line = line.split(marker).next().unwrap_or_default();
+    line.split_whitespace().next()
+}
+
+/// Parse and aggregate a feed. IPv6 entries and lines that are not an
+/// entry of the format are skipped, but a feed without a single IPv4
+/// network is rejected so an error page never empties a blocklist.
+pub fn parse(format: ThreatFeedFormat, text: &str) -> ApiResult<Compiled> {
+    let ranges: Vec<(u32, u32)> = text
+        .lines()
+        .filter_map(|line| entry(format, line))
+        // Only plain lists carry `start-end` ranges
+        .filter(|item| format == ThreatFeedFormat::Cidr || !item.contains('-'))
+        .filter_map(cidr::parse_range)
+        .collect();
+    if ranges.is_empty() {
+        return Err(ApiError::bad_request("Threat feed has no IPv4 networks"));
+    }
+    let networks = cidr::aggregate(ranges);
+    let hash = cidr::list_hash(&networks);
+    Ok(Compiled { networks, hash })
+}
+
+/// Whether a feed is enabled and was last fetched at least
+/// `update_interval` seconds before `now`
+pub fn is_due(feed: &ThreatFeed, now: i64) -> bool {
+    feed.enabled
+        && feed
+            .last_updated
+            .is_none_or(|at| now - at >= i64::from(feed.update_interval))
+}
+
+/// Download a feed's body
+async fn fetch(url: &str) -> std::result::Result<String, String> {
+    let request = Request::new(url, Method::Get).map_err(|e| format!("Invalid URL: {e}"))?;
+    let controller = AbortController::default();
+    let signal = controller.signal();
+    let fetch = Fetch::Request(request);
+    let response = Box::pin(fetch.send_with_signal(&signal));
+    let timeout = Box::pin(Delay::from(FETCH_TIMEOUT));
+
+    match select(response, timeout).await {
+        Either::Left((Ok(mut resp), _)) => {
+            let status = resp.status_code();
+            if !(200..300).contains(&status) {
+                return Err(format!("Feed returned HTTP {status}"));
+            }
+            resp.text()
+                .await
+                .map_err(|e| format!("Failed to read feed: {e}"))
+        }
+        Either::Left((Err(e), _)) => Err(format!("Request failed: {e}")),
+        Either::Right(_) => {
+            controller.abort();
+            Err("Request timed out".to_string())
+        }
+    }
+}
+
+/// Fetch a feed now and record the outcome on it
+pub async fn refresh(device_id: &str, feed: &ThreatFeed, env: &Env) -> ApiResult<ThreatFeed> {
+    let fetched = match fetch(&feed.url).await {
+        Ok(text) => parse(feed.format, &text).map_err(|e| e.error.message),
+        Err(e) => Err(e),
+    };
+    storage::record_threat_feed_fetch(device_id, feed.id, fetched, env).await
+}
+
+/// Cron trigger: refetch feeds whose update interval has elapsed
+pub async fn refresh_due(env: &Env) {
+    let now = chrono::Utc::now().timestamp();
+    let devices = match storage::list_threat_feed_devices(env).await {
+        Ok(devices) => devices,
+        Err(e) => {
+            console_log!("Failed to list threat feed devices: {}", e.error.message);
+            return;
+        }
+    };
+
+    let mut refreshed = 0;
+    for device_id in devices {
+        let feeds = storage::get_threat_feeds(&device_id, env)
+            .await
+            .unwrap_or_default();
+        for feed in feeds.iter().filter(|f| is_due(f, now)) {
+            if refreshed == REFRESH_BATCH {
+                return;
+            }
+            refreshed += 1;
+            if let Err(e) = refresh(&device_id, feed, env).await {
+                console_log!(
+                    "Failed to refresh threat feed {} of {}: {}",
+                    feed.id,
+                    device_id,
+                    e.error.message
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats_are_parsed_and_aggregated() {
+        let plain = "# blocklist\n192.0.2.0/25\n192.0.2.128/25 # second half\n\
+                     198.51.100.1-198.51.100.2\n192.0.2.7\n2001:db8::/32\n";
+        assert_eq!(
+            parse(ThreatFeedFormat::Cidr, plain).unwrap().networks,
+            ["192.0.2.0/24", "198.51.100.1/32", "198.51.100.2/32"]
+        );
+
+        let drop = "; Spamhaus DROP List\n; Last-Modified: today\n\
+                    203.0.113.0/25 ; SBL123\n203.0.113.128/25 ; SBL124\n";
+        assert_eq!(
+            parse(ThreatFeedFormat::SpamhausDrop, drop)
+                .unwrap()
+                .networks,
+            ["203.0.113.0/24"]
+        );
+
+        let netset = "#\n# firehol_level1\n#\n198.51.100.0/24\n198.51.100.9\n\
+                      198.51.100.1-198.51.100.2\n";
+        assert_eq!(
+            parse(ThreatFeedFormat::Firehol, netset).unwrap().networks,
+            ["198.51.100.0/24"]
+        );
+    }
+
+    #[test]
+    fn test_feeds_without_networks_are_rejected() {
+        assert!(parse(ThreatFeedFormat::Cidr, "<html>Not Found</html>").is_err());
+        assert!(parse(ThreatFeedFormat::SpamhausDrop, "; empty\n").is_err());
+    }
+
+    #[test]
+    fn test_unchanged_feeds_keep_their_version() {
+        let a = parse(ThreatFeedFormat::Cidr, "10.0.0.0/9\n10.128.0.0/9\n").unwrap();
+        let b = parse(ThreatFeedFormat::Cidr, "10.0.0.0/8\n10.1.2.3\n").unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_feeds_are_due_after_their_interval() {
+        let mut feed = ThreatFeed {
+            id: 1,
+            name: "DROP".to_string(),
+            url: "https://www.spamhaus.org/drop/drop.txt".to_string(),
+            format: ThreatFeedFormat::SpamhausDrop,
+            enabled: true,
+            update_interval: 3600,
+            networks: 0,
+            version: None,
+            last_updated: None,
+            last_error: None,
+        };
+        assert!(is_due(&feed, 100));
+        feed.last_updated = Some(100);
+        assert!(!is_due(&feed, 3699));
+        assert!(is_due(&feed, 3700));
+        feed.enabled = false;
+        assert!(!is_due(&feed, 3700));
+    }
+}
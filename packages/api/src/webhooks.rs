@@ -0,0 +1,436 @@
+//! Webhook delivery
+//!
+//! Events are POSTed as a JSON [`WebhookEnvelope`] to every enabled webhook
+//! of the owner that subscribes to them. Each request is signed with the
+//! webhook secret:
+//!
+//! ```text
+//! X-NGFW-Signature: t=<unix seconds>,v1=<hex HMAC-SHA256 of "<t>.<body>">
+//! ```
+//!
+//! Receivers should recompute the HMAC and reject stale timestamps. The
+//! first attempt is made immediately; failures are retried by the
+//! scheduled handler with exponential backoff, and after `MAX_ATTEMPTS`
+//! the delivery is kept as a dead letter in the delivery log.
+
+use crate::models::fleet::{WebhookConfig, WebhookEvent};
+use crate::models::webhooks::{DeliveryStatus, WebhookDelivery, WebhookEnvelope};
+use crate::models::{ApiError, ApiResult};
+use crate::storage;
+use futures::future::{Either, select};
+use std::time::Duration;
+use worker::*;
+
+/// Attempts before a delivery is dead-lettered
+pub const MAX_ATTEMPTS: u32 = 6;
+
+/// Delay before the first retry
+const INITIAL_RETRY_SECS: i64 = 30;
+
+/// Upper bound on the delay between retries
+const MAX_RETRY_SECS: i64 = 3600;
+
+/// Time allowed for a receiver to answer
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Due deliveries retried per scheduled run
+const RETRY_BATCH: u32 = 50;
+
+/// Event name used for test deliveries
+pub const TEST_EVENT: &str = "test";
+
+pub const SIGNATURE_HEADER: &str = "X-NGFW-Signature";
+pub const EVENT_HEADER: &str = "X-NGFW-Event";
+pub const DELIVERY_HEADER: &str = "X-NGFW-Delivery";
+
+/// Wire name of an event, e.g. `device_online`
+pub fn event_name(event: &WebhookEvent) -> String {
+    serde_json::to_value(event)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// Delay before the retry that follows attempt number `attempts`
+pub fn retry_delay(attempts: u32) -> i64 {
+    let exponent = attempts.saturating_sub(1).min(16);
+    (INITIAL_RETRY_SECS << exponent).min(MAX_RETRY_SECS)
+}
+
+/// Record the outcome of an attempt on a delivery
+pub fn record_attempt(
+    delivery: &mut WebhookDelivery,
+    response_status: Option<u16>,
+    error: Option<String>,
+    now: i64,
+) {
+    delivery.attempts += 1;
+    delivery.response_status = response_status;
+    delivery.updated_at = now;
+
+    if error.is_none() {
+        delivery.status = DeliveryStatus::Delivered;
+        delivery.last_error = None;
+        delivery.next_attempt_at = None;
+    } else if delivery.attempts >= MAX_ATTEMPTS {
+        delivery.status = DeliveryStatus::DeadLetter;
+        delivery.last_error = error;
+        delivery.next_attempt_at = None;
+    } else {
+        delivery.status = DeliveryStatus::Pending;
+        delivery.last_error = error;
+        delivery.next_attempt_at = Some(now + retry_delay(delivery.attempts));
+    }
+}
+
+/// Bytes covered by the signature
+fn signing_input(timestamp: i64, body: &str) -> String {
+    format!("{}.{}", timestamp, body)
+}
+
+/// Format the signature header value
+fn signature_header(timestamp: i64, mac: &[u8]) -> String {
+    let hex: String = mac.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("t={},v1={}", timestamp, hex)
+}
+
+/// Generate a signing secret for a new webhook
+pub fn generate_secret() -> String {
+    format!(
+        "whsec_{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// Check that a webhook URL is an absolute http(s) URL
+pub fn validate_url(url: &str) -> ApiResult<()> {
+    match Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.host().is_some() => {
+            Ok(())
+        }
+        _ => Err(ApiError::bad_request("url must be an absolute http(s) URL").with_field("url")),
+    }
+}
+
+/// Compute HMAC-SHA256 using the Workers Web Crypto API (SubtleCrypto)
+async fn hmac_sha256(secret: &str, data: &str) -> ApiResult<Vec<u8>> {
+    use js_sys::{Array, Function, Object, Promise, Reflect, Uint8Array};
+    use wasm_bindgen::JsCast;
+    use wasm_bindgen_futures::JsFuture;
+
+    let global = js_sys::global();
+    let crypto = Reflect::get(&global, &"crypto".into())
+        .map_err(|_| ApiError::internal("crypto global not available"))?;
+    let subtle = Reflect::get(&crypto, &"subtle".into())
+        .map_err(|_| ApiError::internal("crypto.subtle not available"))?;
+
+    // Algorithm: { name: "HMAC", hash: { name: "SHA-256" } }
+    let hash = Object::new();
+    let _ = Reflect::set(&hash, &"name".into(), &"SHA-256".into());
+    let algo = Object::new();
+    let _ = Reflect::set(&algo, &"name".into(), &"HMAC".into());
+    let _ = Reflect::set(&algo, &"hash".into(), &hash.into());
+
+    let usages = Array::new();
+    usages.push(&"sign".into());
+
+    let key_arr = Uint8Array::from(secret.as_bytes());
+    let data_arr = Uint8Array::from(data.as_bytes());
+
+    // crypto.subtle.importKey("raw", secret, algo, false, ["sign"])
+    let import_fn: Function = Reflect::get(&subtle, &"importKey".into())
+        .map_err(|_| ApiError::internal("importKey not available"))?
+        .unchecked_into();
+    let import_args = Array::new();
+    import_args.push(&"raw".into());
+    import_args.push(&key_arr.buffer().into());
+    import_args.push(&algo.into());
+    import_args.push(&false.into());
+    import_args.push(&usages.into());
+    let import_promise: Promise = Reflect::apply(&import_fn, &subtle, &import_args)
+        .map_err(|_| ApiError::internal("importKey call failed"))?
+        .unchecked_into();
+    let key = JsFuture::from(import_promise)
+        .await
+        .map_err(|_| ApiError::internal("Failed to import webhook secret"))?;
+
+    // crypto.subtle.sign("HMAC", key, data)
+    let sign_fn: Function = Reflect::get(&subtle, &"sign".into())
+        .map_err(|_| ApiError::internal("sign not available"))?
+        .unchecked_into();
+    let sign_args = Array::new();
+    sign_args.push(&"HMAC".into());
+    sign_args.push(&key);
+    sign_args.push(&data_arr.buffer().into());
+    let sign_promise: Promise = Reflect::apply(&sign_fn, &subtle, &sign_args)
+        .map_err(|_| ApiError::internal("sign call failed"))?
+        .unchecked_into();
+    let mac = JsFuture::from(sign_promise)
+        .await
+        .map_err(|_| ApiError::internal("Failed to sign webhook payload"))?;
+
+    Ok(Uint8Array::new(&mac).to_vec())
+}
+
+/// Build a pending delivery of `event` to a webhook
+fn new_delivery(webhook_id: &str, event: &str, data: serde_json::Value) -> WebhookDelivery {
+    let now = chrono::Utc::now().timestamp();
+    let id = uuid::Uuid::new_v4().to_string();
+    let envelope = WebhookEnvelope {
+        id: id.clone(),
+        event: event.to_string(),
+        created_at: now,
+        data,
+    };
+
+    WebhookDelivery {
+        id,
+        webhook_id: webhook_id.to_string(),
+        event: event.to_string(),
+        payload: serde_json::to_string(&envelope).unwrap_or_default(),
+        status: DeliveryStatus::Pending,
+        attempts: 0,
+        response_status: None,
+        last_error: None,
+        next_attempt_at: Some(now),
+        created_at: now,
+        updated_at: now,
+    }
+}
+
+/// POST a delivery's payload, returning the response status and an error
+/// if it was not accepted
+async fn send(
+    webhook: &WebhookConfig,
+    delivery: &WebhookDelivery,
+) -> (Option<u16>, Option<String>) {
+    let secret = webhook.secret.as_deref().unwrap_or_default();
+    let timestamp = chrono::Utc::now().timestamp();
+    let mac = match hmac_sha256(secret, &signing_input(timestamp, &delivery.payload)).await {
+        Ok(mac) => mac,
+        Err(e) => return (None, Some(e.error.message)),
+    };
+
+    let headers = Headers::new();
+    let _ = headers.set("Content-Type", "application/json");
+    let _ = headers.set("User-Agent", "NGFW.sh-Webhooks/1.0");
+    let _ = headers.set(SIGNATURE_HEADER, &signature_header(timestamp, &mac));
+    let _ = headers.set(EVENT_HEADER, &delivery.event);
+    let _ = headers.set(DELIVERY_HEADER, &delivery.id);
+
+    let request = match Request::new_with_init(
+        &webhook.url,
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_headers(headers)
+            .with_body(Some(delivery.payload.clone().into())),
+    ) {
+        Ok(request) => request,
+        Err(e) => return (None, Some(format!("Invalid request: {e}"))),
+    };
+
+    let controller = AbortController::default();
+    let signal = controller.signal();
+    let fetch = Fetch::Request(request);
+    let response = Box::pin(fetch.send_with_signal(&signal));
+    let timeout = Box::pin(Delay::from(DELIVERY_TIMEOUT));
+
+    match select(response, timeout).await {
+        Either::Left((Ok(resp), _)) => {
+            let status = resp.status_code();
+            if (200..300).contains(&status) {
+                (Some(status), None)
+            } else {
+                (
+                    Some(status),
+                    Some(format!("Receiver returned HTTP {status}")),
+                )
+            }
+        }
+        Either::Left((Err(e), _)) => (None, Some(format!("Request failed: {e}"))),
+        Either::Right(_) => {
+            controller.abort();
+            (None, Some("Request timed out".to_string()))
+        }
+    }
+}
+
+/// Make one attempt at a delivery and persist the outcome
+async fn attempt(webhook: &WebhookConfig, delivery: &mut WebhookDelivery, env: &Env) {
+    let (status, error) = send(webhook, delivery).await;
+    record_attempt(delivery, status, error, chrono::Utc::now().timestamp());
+
+    if let Err(e) = storage::update_webhook_delivery(delivery, env).await {
+        console_log!(
+            "Failed to update delivery {}: {}",
+            delivery.id,
+            e.error.message
+        );
+    }
+}
+
+/// Send an event to every enabled webhook of `owner_id` subscribed to it.
+///
+/// Failures are logged and left for the retry schedule; this never fails
+/// the caller.
+pub async fn dispatch(owner_id: &str, event: WebhookEvent, data: serde_json::Value, env: &Env) {
+    let webhooks = match storage::list_webhooks(owner_id, env).await {
+        Ok(webhooks) => webhooks,
+        Err(e) => {
+            console_log!("Failed to list webhooks: {}", e.error.message);
+            return;
+        }
+    };
+
+    let name = event_name(&event);
+    for webhook in webhooks
+        .iter()
+        .filter(|w| w.enabled && w.events.contains(&event))
+    {
+        let mut delivery = new_delivery(&webhook.id, &name, data.clone());
+        if let Err(e) = storage::insert_webhook_delivery(&delivery, env).await {
+            console_log!("Failed to record delivery: {}", e.error.message);
+            continue;
+        }
+        attempt(webhook, &mut delivery, env).await;
+    }
+}
+
+/// Send a test event to a webhook regardless of its subscriptions.
+///
+/// Failed test deliveries are retried like any other.
+pub async fn send_test(webhook: &WebhookConfig, env: &Env) -> ApiResult<WebhookDelivery> {
+    let data = serde_json::json!({
+        "webhook_id": webhook.id,
+        "message": "Test event from NGFW.sh",
+    });
+    let mut delivery = new_delivery(&webhook.id, TEST_EVENT, data);
+    storage::insert_webhook_delivery(&delivery, env).await?;
+    attempt(webhook, &mut delivery, env).await;
+    Ok(delivery)
+}
+
+/// Retry deliveries whose backoff has elapsed (run from the cron trigger)
+pub async fn retry_due(env: &Env) {
+    let now = chrono::Utc::now().timestamp();
+    let due = match storage::list_due_webhook_deliveries(now, RETRY_BATCH, env).await {
+        Ok(due) => due,
+        Err(e) => {
+            console_log!("Failed to list due deliveries: {}", e.error.message);
+            return;
+        }
+    };
+
+    for mut delivery in due {
+        match storage::find_webhook(&delivery.webhook_id, env).await {
+            Ok(Some(webhook)) if webhook.enabled => attempt(&webhook, &mut delivery, env).await,
+            Ok(_) => {
+                // Disabled since: stop retrying but keep the record
+                delivery.status = DeliveryStatus::DeadLetter;
+                delivery.last_error = Some("Webhook disabled".to_string());
+                delivery.next_attempt_at = None;
+                delivery.updated_at = now;
+                if let Err(e) = storage::update_webhook_delivery(&delivery, env).await {
+                    console_log!(
+                        "Failed to update delivery {}: {}",
+                        delivery.id,
+                        e.error.message
+                    );
+                }
+            }
+            Err(e) => console_log!("Failed to load webhook: {}", e.error.message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending() -> WebhookDelivery {
+        new_delivery("wh_1", "device_offline", serde_json::json!({}))
+    }
+
+    #[test]
+    fn test_retry_delay_backs_off_exponentially() {
+        assert_eq!(retry_delay(1), 30);
+        assert_eq!(retry_delay(2), 60);
+        assert_eq!(retry_delay(3), 120);
+        assert_eq!(retry_delay(20), MAX_RETRY_SECS);
+    }
+
+    #[test]
+    fn test_failed_attempt_schedules_retry() {
+        let mut delivery = pending();
+        record_attempt(&mut delivery, Some(500), Some("HTTP 500".into()), 1_000);
+
+        assert_eq!(delivery.status, DeliveryStatus::Pending);
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.response_status, Some(500));
+        assert_eq!(delivery.next_attempt_at, Some(1_030));
+    }
+
+    #[test]
+    fn test_exhausted_retries_dead_letter() {
+        let mut delivery = pending();
+        for i in 0..MAX_ATTEMPTS {
+            record_attempt(&mut delivery, None, Some("timeout".into()), i as i64);
+        }
+
+        assert_eq!(delivery.status, DeliveryStatus::DeadLetter);
+        assert_eq!(delivery.attempts, MAX_ATTEMPTS);
+        assert_eq!(delivery.next_attempt_at, None);
+        assert_eq!(delivery.last_error.as_deref(), Some("timeout"));
+    }
+
+    #[test]
+    fn test_success_clears_retry() {
+        let mut delivery = pending();
+        record_attempt(&mut delivery, None, Some("timeout".into()), 1_000);
+        record_attempt(&mut delivery, Some(204), None, 1_030);
+
+        assert_eq!(delivery.status, DeliveryStatus::Delivered);
+        assert_eq!(delivery.last_error, None);
+        assert_eq!(delivery.next_attempt_at, None);
+    }
+
+    #[test]
+    fn test_envelope_carries_delivery_id() {
+        let delivery = pending();
+        let envelope: WebhookEnvelope = serde_json::from_str(&delivery.payload).unwrap();
+        assert_eq!(envelope.id, delivery.id);
+        assert_eq!(envelope.event, "device_offline");
+    }
+
+    #[test]
+    fn test_signature_header_format() {
+        assert_eq!(signing_input(1_700_000_000, "{}"), "1700000000.{}");
+        assert_eq!(
+            signature_header(1_700_000_000, &[0x00, 0xab, 0xff]),
+            "t=1700000000,v1=00abff"
+        );
+    }
+
+    #[test]
+    fn test_event_name_matches_serde() {
+        assert_eq!(event_name(&WebhookEvent::DeviceOnline), "device_online");
+        assert_eq!(event_name(&WebhookEvent::ThreatDetected), "threat_detected");
+    }
+
+    #[test]
+    fn test_validate_url() {
+        assert!(validate_url("https://example.com/hook").is_ok());
+        assert!(validate_url("http://127.0.0.1:9000/hook").is_ok());
+        assert!(validate_url("ftp://example.com").is_err());
+        assert!(validate_url("/relative").is_err());
+    }
+
+    #[test]
+    fn test_generated_secret_is_unique() {
+        let a = generate_secret();
+        assert!(a.starts_with("whsec_"));
+        assert_eq!(a.len(), "whsec_".len() + 64);
+        assert_ne!(a, generate_secret());
+    }
+}
@@ -0,0 +1,142 @@
+// E2E tests for the audit log
+//
+// Tests cover:
+// - Mutating calls are recorded with action, resource and redacted changes
+// - GET /audit filtering and pagination
+// - POST /audit/export (JSON and CSV)
+//
+// Entries are written after the response is sent, so lookups poll briefly.
+
+mod common;
+
+use common::{TestConfig, auth::*, client::*, fixtures::*};
+use reqwest::StatusCode;
+use serde_json::json;
+use std::time::Duration;
+
+fn pro_client(config: &TestConfig) -> ApiClient {
+    let token_gen = TokenGenerator::new(&config.clerk_secret);
+    let token = token_gen.generate_valid_token(&config.test_user_id, Some("pro".to_string()));
+    ApiClient::new(
+        config.api_base_url.clone(),
+        token,
+        config.test_device_id.clone(),
+    )
+}
+
+/// Poll `GET /audit?{query}` until it returns at least one entry
+async fn wait_for_entries(client: &ApiClient, query: &str) -> serde_json::Value {
+    for _ in 0..20 {
+        let response = client
+            .get(&format!("/audit?{}", query))
+            .await
+            .expect("Request failed");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.expect("Failed to parse JSON");
+        if body["result"].as_array().is_some_and(|r| !r.is_empty()) {
+            return body;
+        }
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+    panic!("No audit entries for {}", query);
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_create_is_audited_with_redacted_secrets() {
+    let config = TestConfig::from_env();
+    let client = pro_client(&config);
+
+    let network = WiFiNetworkFixture::custom("AuditTest", "AuditSecret123!");
+    let response = client
+        .post("/wifi/networks", &network)
+        .await
+        .expect("Request failed");
+    assert!(response.status().is_success());
+
+    let body = wait_for_entries(
+        &client,
+        &format!(
+            "resource_type=wifi.networks&action=create&device_id={}",
+            config.test_device_id
+        ),
+    )
+    .await;
+    let entry = &body["result"][0];
+
+    assert_eq!(entry["user_id"], config.test_user_id.as_str());
+    assert_eq!(entry["device_id"], config.test_device_id.as_str());
+    assert_eq!(entry["changes"]["after"]["ssid"], "AuditTest");
+    assert_eq!(entry["changes"]["after"]["password"], "[REDACTED]");
+    assert!(
+        !entry.to_string().contains("AuditSecret123!"),
+        "Secret leaked into the audit log"
+    );
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_update_records_before_and_after() {
+    let config = TestConfig::from_env();
+    let client = pro_client(&config);
+
+    let response = client
+        .put("/wan/config", &WanConfigFixture::dhcp())
+        .await
+        .expect("Request failed");
+    assert!(response.status().is_success());
+
+    let body = wait_for_entries(&client, "resource_type=wan.config&action=update").await;
+    let changes = &body["result"][0]["changes"];
+    assert!(changes.get("before").is_some());
+    assert!(changes.get("after").is_some());
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_audit_pagination() {
+    let config = TestConfig::from_env();
+    let client = pro_client(&config);
+
+    let response = client
+        .get("/audit?limit=1&offset=0")
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body["result"].as_array().unwrap().len() <= 1);
+    assert!(body["total"].as_u64().is_some());
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_audit_export() {
+    let config = TestConfig::from_env();
+    let client = pro_client(&config);
+
+    for format in ["json", "csv"] {
+        let response = client
+            .post("/audit/export", &json!({ "format": format }))
+            .await
+            .expect("Request failed");
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert!(body["export_id"].as_str().is_some());
+        assert!(
+            body["download_url"]
+                .as_str()
+                .unwrap()
+                .ends_with(&format!(".{}", format))
+        );
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_audit_requires_auth() {
+    let config = TestConfig::from_env();
+    let client = ApiClient::new_unauthenticated(config.api_base_url.clone());
+
+    let response = client.get("/audit").await.expect("Request failed");
+    assert_ne!(response.status(), StatusCode::OK);
+}
@@ -14,6 +14,8 @@ pub struct Claims {
     pub iss: String,        // Issuer
     #[serde(skip_serializing_if = "Option::is_none")]
     pub plan: Option<String>, // Subscription plan
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub org_id: Option<String>, // Active organization
 }
 
 impl Claims {
@@ -29,6 +31,7 @@ impl Claims {
             iat: now.timestamp() as usize,
             iss: "https://tough-unicorn-25.clerk.accounts.dev".to_string(),
             plan,
+            org_id: None,
         }
     }
 
@@ -44,6 +47,7 @@ impl Claims {
             iat: (now - Duration::hours(2)).timestamp() as usize,
             iss: "https://tough-unicorn-25.clerk.accounts.dev".to_string(),
             plan: None,
+            org_id: None,
         }
     }
 }
@@ -70,6 +74,16 @@ impl TokenGenerator {
             .expect("Failed to encode JWT token")
     }
 
+    /// Generate a valid JWT token with an active organization
+    pub fn generate_org_token(&self, user_id: &str, plan: Option<String>, org_id: &str) -> String {
+        let mut claims = Claims::new_test(user_id, plan);
+        claims.org_id = Some(org_id.to_string());
+        let header = Header::new(Algorithm::HS256);
+
+        encode(&header, &claims, &self.encoding_key)
+            .expect("Failed to encode JWT token")
+    }
+
     /// Generate an expired JWT token for testing
     pub fn generate_expired_token(&self, user_id: &str) -> String {
         let claims = Claims::new_expired(user_id);
@@ -134,7 +148,8 @@ mod tests {
     #[test]
     fn test_expired_claims() {
         let claims = Claims::new_expired("test_user");
-        assert!(claims.exp < claims.iat);
+        assert!(claims.exp > claims.iat);
+        assert!(claims.exp < Utc::now().timestamp() as usize);
     }
 
     #[test]
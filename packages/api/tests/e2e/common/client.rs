@@ -2,7 +2,6 @@
 
 use reqwest::{Client, Response, StatusCode};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
 use std::time::Duration;
 
 /// API client for E2E testing
@@ -146,13 +145,14 @@ impl ResponseAssertion {
     /// Assert response status code
     pub async fn assert_status(self, expected: StatusCode) -> Self {
         let actual = self.response.status();
-        assert_eq!(
-            actual, expected,
-            "Expected status {}, got {}. Response body: {:?}",
-            expected,
-            actual,
-            self.response.text().await.unwrap_or_default()
-        );
+        if actual != expected {
+            panic!(
+                "Expected status {}, got {}. Response body: {:?}",
+                expected,
+                actual,
+                self.response.text().await.unwrap_or_default()
+            );
+        }
         self
     }
 
@@ -166,14 +166,15 @@ impl ResponseAssertion {
         self
     }
 
-    /// Assert response contains JSON
-    pub async fn assert_json<T: for<'de> Deserialize<'de>>(mut self) -> (Self, T) {
+    /// Parse the JSON body, returning it with the response status
+    pub async fn assert_json<T: for<'de> Deserialize<'de>>(self) -> (StatusCode, T) {
+        let status = self.response.status();
         let json = self
             .response
             .json::<T>()
             .await
             .expect("Failed to parse JSON response");
-        (self, json)
+        (status, json)
     }
 
     /// Get the response for further processing
@@ -1,5 +1,6 @@
 // Test data factories and fixtures
 
+use base64::{Engine, engine::general_purpose::STANDARD};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -312,7 +313,7 @@ impl VpnPeerFixture {
     pub fn custom(name: &str, allowed_ip: &str) -> Self {
         Self {
             name: name.to_string(),
-            public_key: base64::encode(rand::random::<[u8; 32]>()),
+            public_key: STANDARD.encode(rand::random::<[u8; 32]>()),
             allowed_ips: vec![allowed_ip.to_string()],
             persistent_keepalive: Some(25),
         }
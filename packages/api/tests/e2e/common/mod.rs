@@ -1,5 +1,8 @@
 // Common test utilities for E2E API tests
 
+// Each suite compiles this module and uses only part of it
+#![allow(dead_code)]
+
 pub mod auth;
 pub mod client;
 pub mod fixtures;
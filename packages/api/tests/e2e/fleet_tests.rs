@@ -1,11 +1,11 @@
 // E2E tests for Fleet Management API endpoints
 //
 // Tests cover:
-// - GET /api/fleet/devices
-// - POST /api/fleet/devices (registration)
-// - DELETE /api/fleet/devices/:id
-// - GET /api/fleet/devices/:id/status
-// - POST /api/fleet/devices/:id/command
+// - GET /fleet/devices
+// - POST /fleet/devices (registration)
+// - DELETE /fleet/devices/:id
+// - GET /fleet/devices/:id/status
+// - POST /fleet/devices/:id/command
 // - Fleet templates (Business plan only)
 
 mod common;
@@ -15,6 +15,7 @@ use reqwest::StatusCode;
 use serde_json::json;
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_fleet_devices_list() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -27,7 +28,7 @@ async fn test_fleet_devices_list() {
     );
 
     let response = client
-        .get("/api/fleet/devices")
+        .get("/fleet/devices")
         .await
         .expect("Request failed");
 
@@ -38,6 +39,7 @@ async fn test_fleet_devices_list() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_fleet_device_register() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -52,7 +54,7 @@ async fn test_fleet_device_register() {
     let device = DeviceRegistrationFixture::rt_ax92u();
 
     let response = client
-        .post("/api/fleet/devices", &device)
+        .post("/fleet/devices", &device)
         .await
         .expect("Request failed");
 
@@ -73,6 +75,7 @@ async fn test_fleet_device_register() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_fleet_device_register_starter_limit() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -90,7 +93,7 @@ async fn test_fleet_device_register_starter_limit() {
     let device = DeviceRegistrationFixture::rt_ax92u();
 
     let response = client
-        .post("/api/fleet/devices", &device)
+        .post("/fleet/devices", &device)
         .await
         .expect("Request failed");
 
@@ -105,6 +108,7 @@ async fn test_fleet_device_register_starter_limit() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_fleet_device_status() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -117,7 +121,7 @@ async fn test_fleet_device_status() {
     );
 
     let response = client
-        .get(&format!("/api/fleet/devices/{}/status", config.test_device_id))
+        .get(&format!("/fleet/devices/{}/status", config.test_device_id))
         .await
         .expect("Request failed");
 
@@ -128,6 +132,7 @@ async fn test_fleet_device_status() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_fleet_device_command_send() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -146,7 +151,7 @@ async fn test_fleet_device_command_send() {
 
     let response = client
         .post(
-            &format!("/api/fleet/devices/{}/command", config.test_device_id),
+            &format!("/fleet/devices/{}/command", config.test_device_id),
             &command,
         )
         .await
@@ -163,6 +168,7 @@ async fn test_fleet_device_command_send() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_fleet_device_remove_not_owned() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -176,7 +182,7 @@ async fn test_fleet_device_remove_not_owned() {
 
     // Try to delete a device that doesn't belong to this user
     let response = client
-        .delete("/api/fleet/devices/not_owned_device_id")
+        .delete("/fleet/devices/not_owned_device_id")
         .await
         .expect("Request failed");
 
@@ -190,6 +196,7 @@ async fn test_fleet_device_remove_not_owned() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_fleet_templates_list_business_plan() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -202,7 +209,7 @@ async fn test_fleet_templates_list_business_plan() {
     );
 
     let response = client
-        .get("/api/fleet/templates")
+        .get("/fleet/templates")
         .await
         .expect("Request failed");
 
@@ -214,6 +221,7 @@ async fn test_fleet_templates_list_business_plan() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_fleet_templates_list_starter_plan() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -226,7 +234,7 @@ async fn test_fleet_templates_list_starter_plan() {
     );
 
     let response = client
-        .get("/api/fleet/templates")
+        .get("/fleet/templates")
         .await
         .expect("Request failed");
 
@@ -239,6 +247,7 @@ async fn test_fleet_templates_list_starter_plan() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_fleet_template_create_business_plan() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -262,7 +271,7 @@ async fn test_fleet_template_create_business_plan() {
     });
 
     let response = client
-        .post("/api/fleet/templates", &template)
+        .post("/fleet/templates", &template)
         .await
         .expect("Request failed");
 
@@ -274,6 +283,7 @@ async fn test_fleet_template_create_business_plan() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_fleet_template_apply() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -292,7 +302,7 @@ async fn test_fleet_template_apply() {
 
     let response = client
         .post(
-            &format!("/api/fleet/templates/{}/apply", template_id),
+            &format!("/fleet/templates/{}/apply", template_id),
             &apply_request,
         )
         .await
@@ -0,0 +1,194 @@
+// E2E tests for cross-section config consistency
+//
+// Tests cover:
+// - GET /config/lint report shape
+// - Writes conflicting with other sections rejected with INVALID_CONFIG
+// - Shadowed firewall rules reported as warnings on create
+// - Packet traces through the stored firewall configuration
+//
+// Config writes require If-Match, so writes here send `If-Match: *`.
+
+mod common;
+
+use common::{TestConfig, auth::*, client::*};
+use reqwest::StatusCode;
+use serde_json::{Value, json};
+
+fn pro_token(config: &TestConfig) -> String {
+    TokenGenerator::new(&config.clerk_secret)
+        .generate_valid_token(&config.test_user_id, Some("pro".to_string()))
+}
+
+async fn put(config: &TestConfig, path: &str, body: &Value) -> reqwest::Response {
+    send(config, reqwest::Method::PUT, path, body).await
+}
+
+async fn send(
+    config: &TestConfig,
+    method: reqwest::Method,
+    path: &str,
+    body: &Value,
+) -> reqwest::Response {
+    reqwest::Client::new()
+        .request(method, format!("{}{}", config.api_base_url, path))
+        .header("Authorization", format!("Bearer {}", pro_token(config)))
+        .header("X-Device-ID", &config.test_device_id)
+        .header("If-Match", "*")
+        .json(body)
+        .send()
+        .await
+        .expect("Request failed")
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_lint_report() {
+    let config = TestConfig::from_env();
+    let client = ApiClient::new(
+        config.api_base_url.clone(),
+        pro_token(&config),
+        config.test_device_id.clone(),
+    );
+
+    let response = client
+        .get("/config/lint")
+        .await
+        .expect("Request failed");
+    let (_, report) = ResponseAssertion::new(response)
+        .assert_success()
+        .await
+        .assert_json::<Value>()
+        .await;
+    assert!(report["errors"].is_u64());
+    assert!(report["warnings"].is_u64());
+    assert!(report["issues"].is_array());
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_dhcp_range_outside_lan_is_rejected() {
+    let config = TestConfig::from_env();
+    let lan = json!({
+        "interface": "br0",
+        "ip": "192.168.1.1",
+        "subnet_mask": "255.255.255.0",
+        "bridge_interfaces": [],
+        "ipv6_enabled": false
+    });
+    assert!(
+        put(&config, "/lan/config", &lan)
+            .await
+            .status()
+            .is_success()
+    );
+
+    let dhcp = json!({
+        "enabled": true,
+        "interface": "br0",
+        "range_start": "10.0.0.100",
+        "range_end": "10.0.0.200",
+        "lease_time": 86400,
+        "gateway": "192.168.1.1",
+        "dns": ["192.168.1.1"]
+    });
+    let response = put(&config, "/dhcp/config", &dhcp).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let body: Value = response.json().await.expect("Failed to parse JSON");
+    assert_eq!(body["error"]["code"], "INVALID_CONFIG");
+    let issues = body["error"]["details"]["lint_issues"].as_array().unwrap();
+    assert!(
+        issues
+            .iter()
+            .any(|i| i["code"] == "dhcp_range_outside_subnet" && i["field"] == "dhcp.range_start")
+    );
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_shadowed_firewall_rule_warns() {
+    let config = TestConfig::from_env();
+    let rule = |name: &str, source: &str| {
+        json!({
+            "name": name,
+            "enabled": true,
+            "zone_from": "WAN",
+            "zone_to": "LAN",
+            "source": source,
+            "destination": "any",
+            "protocol": "tcp",
+            "port": "22",
+            "action": "drop",
+            "log": false
+        })
+    };
+
+    let broad = rule("Block SSH", "any");
+    let response = send(
+        &config,
+        reqwest::Method::POST,
+        "/firewall/rules",
+        &broad,
+    )
+    .await;
+    assert!(response.status().is_success());
+
+    let narrow = rule("Block scanner SSH", "203.0.113.10");
+    let response = send(
+        &config,
+        reqwest::Method::POST,
+        "/firewall/rules",
+        &narrow,
+    )
+    .await;
+    assert!(response.status().is_success());
+
+    let body: Value = response.json().await.expect("Failed to parse JSON");
+    let warnings = body["warnings"].as_array().expect("Missing warnings");
+    assert!(
+        warnings.iter().any(
+            |w| w["code"] == "firewall_rule_redundant" || w["code"] == "firewall_rule_shadowed"
+        )
+    );
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_packet_trace() {
+    let config = TestConfig::from_env();
+    let packet = json!({
+        "protocol": "tcp",
+        "source_ip": "198.51.100.7",
+        "source_port": 40000,
+        "destination_ip": "192.168.1.50",
+        "destination_port": 22,
+        "interface": "eth0",
+        "day": 2,
+        "time": "12:00"
+    });
+    let response = send(
+        &config,
+        reqwest::Method::POST,
+        "/firewall/trace",
+        &packet,
+    )
+    .await;
+    assert!(response.status().is_success());
+
+    let body: Value = response.json().await.expect("Failed to parse JSON");
+    assert!(body["verdict"].is_string());
+    assert!(body["decided_by"].is_string());
+    assert!(body["rules"].is_array());
+    assert!(body["nat"].is_array());
+
+    // Ports cannot be traced for ICMP
+    let icmp = json!({
+        "protocol": "icmp",
+        "source_ip": "198.51.100.7",
+        "destination_ip": "192.168.1.50",
+        "destination_port": 22,
+        "interface": "eth0"
+    });
+    let response = send(&config, reqwest::Method::POST, "/firewall/trace", &icmp).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
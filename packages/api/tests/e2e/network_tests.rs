@@ -1,9 +1,9 @@
 // E2E tests for Network API endpoints
 //
 // Tests cover:
-// - WAN configuration (GET/PUT /api/wan/config)
-// - LAN configuration (GET/PUT /api/lan/config)
-// - VLAN management (GET/POST/PUT/DELETE /api/lan/vlans)
+// - WAN configuration (GET/PUT /wan/config)
+// - LAN configuration (GET/PUT /lan/config)
+// - VLAN management (GET/POST/PUT/DELETE /lan/vlans)
 // - WiFi configuration (radios, networks, clients)
 // - DHCP configuration (config, leases, reservations)
 
@@ -15,6 +15,7 @@ use reqwest::StatusCode;
 // ========== WAN Tests ==========
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_wan_config_read() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -26,7 +27,7 @@ async fn test_wan_config_read() {
         config.test_device_id.clone(),
     );
 
-    let response = client.get("/api/wan/config").await.expect("Request failed");
+    let response = client.get("/wan/config").await.expect("Request failed");
 
     assert_eq!(response.status(), StatusCode::OK);
 
@@ -35,6 +36,7 @@ async fn test_wan_config_read() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_wan_config_update_dhcp() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -49,7 +51,7 @@ async fn test_wan_config_update_dhcp() {
     let wan_config = WanConfigFixture::dhcp();
 
     let response = client
-        .put("/api/wan/config", &wan_config)
+        .put("/wan/config", &wan_config)
         .await
         .expect("Request failed");
 
@@ -61,6 +63,7 @@ async fn test_wan_config_update_dhcp() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_wan_config_update_static() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -75,7 +78,7 @@ async fn test_wan_config_update_static() {
     let wan_config = WanConfigFixture::static_ip();
 
     let response = client
-        .put("/api/wan/config", &wan_config)
+        .put("/wan/config", &wan_config)
         .await
         .expect("Request failed");
 
@@ -87,6 +90,7 @@ async fn test_wan_config_update_static() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_wan_status_info() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -98,7 +102,7 @@ async fn test_wan_status_info() {
         config.test_device_id.clone(),
     );
 
-    let response = client.get("/api/wan/status").await.expect("Request failed");
+    let response = client.get("/wan/status").await.expect("Request failed");
 
     assert_eq!(response.status(), StatusCode::OK);
 
@@ -109,6 +113,7 @@ async fn test_wan_status_info() {
 // ========== LAN Tests ==========
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_lan_config_read() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -120,7 +125,7 @@ async fn test_lan_config_read() {
         config.test_device_id.clone(),
     );
 
-    let response = client.get("/api/lan/config").await.expect("Request failed");
+    let response = client.get("/lan/config").await.expect("Request failed");
 
     assert_eq!(response.status(), StatusCode::OK);
 
@@ -130,6 +135,7 @@ async fn test_lan_config_read() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_lan_config_update() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -144,7 +150,7 @@ async fn test_lan_config_update() {
     let lan_config = LanConfigFixture::default();
 
     let response = client
-        .put("/api/lan/config", &lan_config)
+        .put("/lan/config", &lan_config)
         .await
         .expect("Request failed");
 
@@ -158,6 +164,7 @@ async fn test_lan_config_update() {
 // ========== VLAN Tests ==========
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_lan_vlans_list() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -169,7 +176,7 @@ async fn test_lan_vlans_list() {
         config.test_device_id.clone(),
     );
 
-    let response = client.get("/api/lan/vlans").await.expect("Request failed");
+    let response = client.get("/lan/vlans").await.expect("Request failed");
 
     assert_eq!(response.status(), StatusCode::OK);
 
@@ -178,6 +185,7 @@ async fn test_lan_vlans_list() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_lan_vlan_create() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -192,7 +200,7 @@ async fn test_lan_vlan_create() {
     let vlan = VlanFixture::guest_network();
 
     let response = client
-        .post("/api/lan/vlans", &vlan)
+        .post("/lan/vlans", &vlan)
         .await
         .expect("Request failed");
 
@@ -204,6 +212,7 @@ async fn test_lan_vlan_create() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_lan_vlan_create_invalid_id() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -219,7 +228,7 @@ async fn test_lan_vlan_create_invalid_id() {
     let vlan = VlanFixture::custom(0, "invalid", "192.168.99.0");
 
     let response = client
-        .post("/api/lan/vlans", &vlan)
+        .post("/lan/vlans", &vlan)
         .await
         .expect("Request failed");
 
@@ -233,6 +242,7 @@ async fn test_lan_vlan_create_invalid_id() {
 // ========== WiFi Tests ==========
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_wifi_radios_list() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -245,7 +255,7 @@ async fn test_wifi_radios_list() {
     );
 
     let response = client
-        .get("/api/wifi/radios")
+        .get("/wifi/radios")
         .await
         .expect("Request failed");
 
@@ -256,6 +266,7 @@ async fn test_wifi_radios_list() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_wifi_networks_list() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -268,7 +279,7 @@ async fn test_wifi_networks_list() {
     );
 
     let response = client
-        .get("/api/wifi/networks")
+        .get("/wifi/networks")
         .await
         .expect("Request failed");
 
@@ -279,6 +290,7 @@ async fn test_wifi_networks_list() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_wifi_network_create() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -293,7 +305,7 @@ async fn test_wifi_network_create() {
     let network = WiFiNetworkFixture::home_network();
 
     let response = client
-        .post("/api/wifi/networks", &network)
+        .post("/wifi/networks", &network)
         .await
         .expect("Request failed");
 
@@ -305,6 +317,7 @@ async fn test_wifi_network_create() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_wifi_network_create_weak_password() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -320,7 +333,7 @@ async fn test_wifi_network_create_weak_password() {
     let network = WiFiNetworkFixture::custom("TestNet", "weak");
 
     let response = client
-        .post("/api/wifi/networks", &network)
+        .post("/wifi/networks", &network)
         .await
         .expect("Request failed");
 
@@ -332,6 +345,7 @@ async fn test_wifi_network_create_weak_password() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_wifi_clients_list() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -344,7 +358,7 @@ async fn test_wifi_clients_list() {
     );
 
     let response = client
-        .get("/api/wifi/clients")
+        .get("/wifi/clients")
         .await
         .expect("Request failed");
 
@@ -357,6 +371,7 @@ async fn test_wifi_clients_list() {
 // ========== DHCP Tests ==========
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_dhcp_config_read() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -369,7 +384,7 @@ async fn test_dhcp_config_read() {
     );
 
     let response = client
-        .get("/api/dhcp/config")
+        .get("/dhcp/config")
         .await
         .expect("Request failed");
 
@@ -380,6 +395,7 @@ async fn test_dhcp_config_read() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_dhcp_leases_list() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -392,7 +408,7 @@ async fn test_dhcp_leases_list() {
     );
 
     let response = client
-        .get("/api/dhcp/leases")
+        .get("/dhcp/leases")
         .await
         .expect("Request failed");
 
@@ -403,6 +419,7 @@ async fn test_dhcp_leases_list() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_dhcp_reservations_list() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -415,7 +432,7 @@ async fn test_dhcp_reservations_list() {
     );
 
     let response = client
-        .get("/api/dhcp/reservations")
+        .get("/dhcp/reservations")
         .await
         .expect("Request failed");
 
@@ -426,6 +443,7 @@ async fn test_dhcp_reservations_list() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_dhcp_reservation_create() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -440,7 +458,7 @@ async fn test_dhcp_reservation_create() {
     let reservation = DhcpReservationFixture::server();
 
     let response = client
-        .post("/api/dhcp/reservations", &reservation)
+        .post("/dhcp/reservations", &reservation)
         .await
         .expect("Request failed");
 
@@ -452,6 +470,7 @@ async fn test_dhcp_reservation_create() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_dhcp_reservation_create_invalid_mac() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -467,7 +486,7 @@ async fn test_dhcp_reservation_create_invalid_mac() {
     let reservation = DhcpReservationFixture::custom("invalid-mac", "192.168.1.50", None);
 
     let response = client
-        .post("/api/dhcp/reservations", &reservation)
+        .post("/dhcp/reservations", &reservation)
         .await
         .expect("Request failed");
 
@@ -0,0 +1,293 @@
+// E2E tests for organizations and role-based access
+//
+// Tests cover:
+// - POST/GET/PUT/DELETE /orgs (Business plan gating)
+// - Member management and owner protection
+// - Viewer/operator/admin permissions on org devices
+// - Billing restricted to owners
+//
+// Each test uses fresh user ids so memberships from other runs do not
+// interfere.
+
+mod common;
+
+use common::{TestConfig, auth::*, client::*};
+use reqwest::StatusCode;
+use serde_json::{Value, json};
+
+fn user_id(label: &str) -> String {
+    format!("user_org_{}_{}", label, uuid::Uuid::new_v4().simple())
+}
+
+fn personal_client(config: &TestConfig, user_id: &str) -> ApiClient {
+    let token = TokenGenerator::new(&config.clerk_secret)
+        .generate_valid_token(user_id, Some("business".to_string()));
+    ApiClient::new(
+        config.api_base_url.clone(),
+        token,
+        config.test_device_id.clone(),
+    )
+}
+
+fn org_client(config: &TestConfig, user_id: &str, org_id: &str, device_id: &str) -> ApiClient {
+    let token = TokenGenerator::new(&config.clerk_secret).generate_org_token(
+        user_id,
+        Some("business".to_string()),
+        org_id,
+    );
+    ApiClient::new(config.api_base_url.clone(), token, device_id.to_string())
+}
+
+/// Create an organization owned by the client's user and return its id
+async fn create_org(client: &ApiClient) -> String {
+    let response = client
+        .post("/orgs", &json!({ "name": "E2E Org" }))
+        .await
+        .expect("Request failed");
+    let (_, org) = ResponseAssertion::new(response)
+        .assert_status(StatusCode::CREATED)
+        .await
+        .assert_json::<Value>()
+        .await;
+    assert_eq!(org["role"], "owner");
+    org["id"].as_str().unwrap().to_string()
+}
+
+async fn add_member(client: &ApiClient, org_id: &str, user_id: &str, role: &str) -> StatusCode {
+    client
+        .post(
+            &format!("/orgs/{}/members", org_id),
+            &json!({ "user_id": user_id, "role": role }),
+        )
+        .await
+        .expect("Request failed")
+        .status()
+}
+
+/// Register a device in the organization and return its id
+async fn register_org_device(client: &ApiClient) -> String {
+    let response = client
+        .post("/fleet/devices", &json!({ "name": "org-router" }))
+        .await
+        .expect("Request failed");
+    let (_, body) = ResponseAssertion::new(response)
+        .assert_success()
+        .await
+        .assert_json::<Value>()
+        .await;
+    body["device_id"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_create_org_requires_business_plan() {
+    let config = TestConfig::from_env();
+    let token = TokenGenerator::new(&config.clerk_secret)
+        .generate_valid_token(&user_id("starter"), Some("starter".to_string()));
+    let client = ApiClient::new(
+        config.api_base_url.clone(),
+        token,
+        config.test_device_id.clone(),
+    );
+
+    let response = client
+        .post("/orgs", &json!({ "name": "Nope" }))
+        .await
+        .expect("Request failed");
+    assert!(!response.status().is_success());
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_org_crud() {
+    let config = TestConfig::from_env();
+    let owner = user_id("owner");
+    let client = personal_client(&config, &owner);
+    let org_id = create_org(&client).await;
+
+    let response = client.get("/orgs").await.expect("Request failed");
+    let (_, orgs) = ResponseAssertion::new(response)
+        .assert_success()
+        .await
+        .assert_json::<Value>()
+        .await;
+    assert!(orgs["result"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|o| o["id"] == org_id.as_str()));
+
+    let response = client
+        .put(&format!("/orgs/{}", org_id), &json!({ "name": "Renamed" }))
+        .await
+        .expect("Request failed");
+    let (_, org) = ResponseAssertion::new(response)
+        .assert_success()
+        .await
+        .assert_json::<Value>()
+        .await;
+    assert_eq!(org["result"]["name"], "Renamed");
+
+    let response = client
+        .delete(&format!("/orgs/{}", org_id))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = client
+        .get(&format!("/orgs/{}", org_id))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_non_member_cannot_see_org() {
+    let config = TestConfig::from_env();
+    let owner = personal_client(&config, &user_id("owner"));
+    let org_id = create_org(&owner).await;
+
+    let outsider = personal_client(&config, &user_id("outsider"));
+    let response = outsider
+        .get(&format!("/orgs/{}/members", org_id))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_last_owner_cannot_leave() {
+    let config = TestConfig::from_env();
+    let owner = user_id("owner");
+    let client = personal_client(&config, &owner);
+    let org_id = create_org(&client).await;
+
+    let response = client
+        .delete(&format!("/orgs/{}/members/{}", org_id, owner))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_admin_cannot_grant_owner() {
+    let config = TestConfig::from_env();
+    let owner = personal_client(&config, &user_id("owner"));
+    let org_id = create_org(&owner).await;
+
+    let admin = user_id("admin");
+    assert_eq!(
+        add_member(&owner, &org_id, &admin, "admin").await,
+        StatusCode::CREATED
+    );
+
+    let admin_client = personal_client(&config, &admin);
+    assert_eq!(
+        add_member(&admin_client, &org_id, &user_id("new"), "owner").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        add_member(&admin_client, &org_id, &user_id("new"), "operator").await,
+        StatusCode::CREATED
+    );
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_device_permissions_follow_role() {
+    let config = TestConfig::from_env();
+    let owner = user_id("owner");
+    let org_id = create_org(&personal_client(&config, &owner)).await;
+
+    let owner_client = org_client(&config, &owner, &org_id, &config.test_device_id);
+    let device_id = register_org_device(&owner_client).await;
+
+    let viewer = user_id("viewer");
+    let operator = user_id("operator");
+    assert_eq!(
+        add_member(&owner_client, &org_id, &viewer, "viewer").await,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        add_member(&owner_client, &org_id, &operator, "operator").await,
+        StatusCode::CREATED
+    );
+
+    // Viewers read but cannot operate
+    let viewer_client = org_client(&config, &viewer, &org_id, &device_id);
+    let response = viewer_client
+        .get("/wan/config")
+        .await
+        .expect("Request failed");
+    assert_ne!(response.status(), StatusCode::FORBIDDEN);
+    let response = viewer_client
+        .post("/wan/renew", &json!({}))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // Operators operate but cannot configure
+    let operator_client = org_client(&config, &operator, &org_id, &device_id);
+    let response = operator_client
+        .post("/wan/renew", &json!({}))
+        .await
+        .expect("Request failed");
+    assert_ne!(response.status(), StatusCode::FORBIDDEN);
+    let response = operator_client
+        .put("/wan/config", &json!({ "connection_type": "dhcp" }))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // Members read the logs and reports of org devices they don't own
+    let response = viewer_client
+        .get(&format!("/logs?device_id={}", device_id))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = viewer_client
+        .get(&format!("/reports?device_id={}", device_id))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Org devices are listed for every member
+    let response = viewer_client
+        .get("/fleet/devices")
+        .await
+        .expect("Request failed");
+    let (_, devices) = ResponseAssertion::new(response)
+        .assert_success()
+        .await
+        .assert_json::<Value>()
+        .await;
+    assert!(devices["result"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|d| d["id"] == device_id.as_str()));
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_billing_requires_owner() {
+    let config = TestConfig::from_env();
+    let owner = personal_client(&config, &user_id("owner"));
+    let org_id = create_org(&owner).await;
+
+    let admin = user_id("admin");
+    assert_eq!(
+        add_member(&owner, &org_id, &admin, "admin").await,
+        StatusCode::CREATED
+    );
+
+    let admin_client = org_client(&config, &admin, &org_id, &config.test_device_id);
+    let response = admin_client
+        .get("/billing/payment-methods")
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
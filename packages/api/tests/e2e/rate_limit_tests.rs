@@ -0,0 +1,79 @@
+// E2E tests for API rate limiting
+//
+// Tests cover:
+// - X-RateLimit-* headers on authenticated responses
+// - Unauthenticated routes are not limited
+// - 429 with Retry-After once a bucket is exhausted
+//
+// Each test uses a fresh user id so counters from other runs do not interfere.
+
+mod common;
+
+use common::{TestConfig, auth::*, client::*};
+use reqwest::StatusCode;
+
+fn fresh_client(config: &TestConfig, plan: &str) -> ApiClient {
+    let token_gen = TokenGenerator::new(&config.clerk_secret);
+    let user_id = format!("user_ratelimit_{}", uuid::Uuid::new_v4().simple());
+    let token = token_gen.generate_valid_token(&user_id, Some(plan.to_string()));
+    ApiClient::new(
+        config.api_base_url.clone(),
+        token,
+        config.test_device_id.clone(),
+    )
+}
+
+fn header_u64(response: &reqwest::Response, name: &str) -> u64 {
+    response
+        .headers()
+        .get(name)
+        .unwrap_or_else(|| panic!("Missing {} header", name))
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_rate_limit_headers_present() {
+    let config = TestConfig::from_env();
+    let client = fresh_client(&config, "pro");
+
+    let response = client.get("/system/status").await.expect("Request failed");
+    let limit = header_u64(&response, "X-RateLimit-Limit");
+    let remaining = header_u64(&response, "X-RateLimit-Remaining");
+
+    assert!(limit > 0);
+    assert_eq!(remaining, limit - 1);
+    assert!(response.headers().get("X-RateLimit-Reset").is_some());
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_health_check_not_rate_limited() {
+    let config = TestConfig::from_env();
+    let client = ApiClient::new_unauthenticated(config.api_base_url.clone());
+
+    let response = client.get("/health").await.expect("Request failed");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("X-RateLimit-Limit").is_none());
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_traffic_logs_bucket_exhausted() {
+    let config = TestConfig::from_env();
+    let client = fresh_client(&config, "pro");
+
+    // Traffic logs allow 60 requests per minute
+    let mut last = None;
+    for _ in 0..61 {
+        last = Some(client.get("/traffic/logs").await.expect("Request failed"));
+    }
+    let response = last.unwrap();
+
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(header_u64(&response, "X-RateLimit-Remaining"), 0);
+    assert!(header_u64(&response, "Retry-After") >= 1);
+}
@@ -1,20 +1,21 @@
 // E2E tests for System API endpoints
 //
 // Tests cover:
-// - GET /api/system/status
-// - GET /api/system/interfaces
-// - GET /api/system/hardware
-// - POST /api/system/reboot
-// - POST /api/system/shutdown
-// - GET /api/metrics/latest
+// - GET /system/status
+// - GET /system/interfaces
+// - GET /system/hardware
+// - POST /system/reboot
+// - POST /system/shutdown
+// - GET /metrics/latest
 
 mod common;
 
-use common::{auth::*, client::*, fixtures::*, TestConfig};
+use common::{auth::*, client::*, TestConfig};
 use reqwest::StatusCode;
 use serde_json::json;
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_health_check_no_auth() {
     let config = TestConfig::from_env();
     let client = ApiClient::new_unauthenticated(config.api_base_url.clone());
@@ -27,6 +28,7 @@ async fn test_health_check_no_auth() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_system_status_authorized() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -39,7 +41,7 @@ async fn test_system_status_authorized() {
     );
 
     let response = client
-        .get("/api/system/status")
+        .get("/system/status")
         .await
         .expect("Request failed");
 
@@ -55,6 +57,7 @@ async fn test_system_status_authorized() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_system_status_missing_device_id() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -64,7 +67,7 @@ async fn test_system_status_missing_device_id() {
     let client = ApiClient::new(config.api_base_url.clone(), token, String::new());
 
     let response = client
-        .get("/api/system/status")
+        .get("/system/status")
         .await
         .expect("Request failed");
 
@@ -73,6 +76,7 @@ async fn test_system_status_missing_device_id() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_system_status_unauthorized() {
     let config = TestConfig::from_env();
 
@@ -80,7 +84,7 @@ async fn test_system_status_unauthorized() {
     let client = ApiClient::new_unauthenticated(config.api_base_url.clone());
 
     let response = client
-        .get("/api/system/status")
+        .get("/system/status")
         .await
         .expect("Request failed");
 
@@ -89,6 +93,7 @@ async fn test_system_status_unauthorized() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_system_status_expired_token() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -101,7 +106,7 @@ async fn test_system_status_expired_token() {
     );
 
     let response = client
-        .get("/api/system/status")
+        .get("/system/status")
         .await
         .expect("Request failed");
 
@@ -110,6 +115,7 @@ async fn test_system_status_expired_token() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_system_interfaces_list() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -122,7 +128,7 @@ async fn test_system_interfaces_list() {
     );
 
     let response = client
-        .get("/api/system/interfaces")
+        .get("/system/interfaces")
         .await
         .expect("Request failed");
 
@@ -133,18 +139,16 @@ async fn test_system_interfaces_list() {
     // Should return an array of interfaces
     assert!(body.is_array(), "Expected array of interfaces");
 
-    if let Some(interfaces) = body.as_array() {
-        if !interfaces.is_empty() {
-            let first = &interfaces[0];
-            // Verify interface structure
-            assert!(first.get("name").is_some(), "Missing interface name");
-            assert!(first.get("mac").is_some(), "Missing MAC address");
-            assert!(first.get("status").is_some(), "Missing status");
-        }
+    if let Some(first) = body.as_array().and_then(|interfaces| interfaces.first()) {
+        // Verify interface structure
+        assert!(first.get("name").is_some(), "Missing interface name");
+        assert!(first.get("mac").is_some(), "Missing MAC address");
+        assert!(first.get("status").is_some(), "Missing status");
     }
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_system_hardware_info() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -157,7 +161,7 @@ async fn test_system_hardware_info() {
     );
 
     let response = client
-        .get("/api/system/hardware")
+        .get("/system/hardware")
         .await
         .expect("Request failed");
 
@@ -172,6 +176,7 @@ async fn test_system_hardware_info() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_system_reboot_command() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -184,7 +189,7 @@ async fn test_system_reboot_command() {
     );
 
     let response = client
-        .post("/api/system/reboot", &json!({}))
+        .post("/system/reboot", &json!({}))
         .await
         .expect("Request failed");
 
@@ -199,6 +204,7 @@ async fn test_system_reboot_command() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_system_shutdown_command() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -211,7 +217,7 @@ async fn test_system_shutdown_command() {
     );
 
     let response = client
-        .post("/api/system/shutdown", &json!({}))
+        .post("/system/shutdown", &json!({}))
         .await
         .expect("Request failed");
 
@@ -226,6 +232,7 @@ async fn test_system_shutdown_command() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_metrics_latest_polling() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -238,7 +245,7 @@ async fn test_metrics_latest_polling() {
     );
 
     let response = client
-        .get("/api/metrics/latest")
+        .get("/metrics/latest")
         .await
         .expect("Request failed");
 
@@ -266,6 +273,7 @@ async fn test_metrics_latest_polling() {
 }
 
 #[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
 async fn test_system_status_device_not_owned() {
     let config = TestConfig::from_env();
     let token_gen = TokenGenerator::new(&config.clerk_secret);
@@ -279,7 +287,7 @@ async fn test_system_status_device_not_owned() {
     );
 
     let response = client
-        .get("/api/system/status")
+        .get("/system/status")
         .await
         .expect("Request failed");
 
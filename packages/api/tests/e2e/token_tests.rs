@@ -0,0 +1,165 @@
+// E2E tests for API tokens
+//
+// Tests cover:
+// - POST/GET/DELETE /user/tokens
+// - Token authentication and scope enforcement
+// - Account routes rejected for tokens
+// - Revoked tokens rejected
+//
+// Each test uses a fresh user id so tokens from other runs do not interfere.
+
+mod common;
+
+use common::{TestConfig, auth::*, client::*};
+use reqwest::StatusCode;
+use serde_json::{Value, json};
+
+fn session_client(config: &TestConfig) -> ApiClient {
+    let user_id = format!("user_tokens_{}", uuid::Uuid::new_v4().simple());
+    let token = TokenGenerator::new(&config.clerk_secret)
+        .generate_valid_token(&user_id, Some("pro".to_string()));
+    ApiClient::new(
+        config.api_base_url.clone(),
+        token,
+        config.test_device_id.clone(),
+    )
+}
+
+/// Create a token with `scopes` and return its id and secret
+async fn create_token(client: &ApiClient, scopes: &[&str]) -> (String, String) {
+    let response = client
+        .post(
+            "/user/tokens",
+            &json!({ "name": "ci", "scopes": scopes, "expires_in_days": 1 }),
+        )
+        .await
+        .expect("Request failed");
+    let (_, body) = ResponseAssertion::new(response)
+        .assert_status(StatusCode::CREATED)
+        .await
+        .assert_json::<Value>()
+        .await;
+    let secret = body["token"].as_str().unwrap().to_string();
+    assert!(secret.starts_with("ngfw_pat_"));
+    assert!(secret.starts_with(body["token_prefix"].as_str().unwrap()));
+    (body["id"].as_str().unwrap().to_string(), secret)
+}
+
+fn token_client(config: &TestConfig, secret: &str) -> ApiClient {
+    ApiClient::new(
+        config.api_base_url.clone(),
+        secret.to_string(),
+        config.test_device_id.clone(),
+    )
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_token_listed_without_secret() {
+    let config = TestConfig::from_env();
+    let client = session_client(&config);
+    let (token_id, _) = create_token(&client, &["config:read"]).await;
+
+    let response = client.get("/user/tokens").await.expect("Request failed");
+    let (_, body) = ResponseAssertion::new(response)
+        .assert_success()
+        .await
+        .assert_json::<Value>()
+        .await;
+    let listed = body["result"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|t| t["id"] == token_id.as_str())
+        .expect("token listed")
+        .clone();
+    assert!(listed.get("token").is_none());
+    assert_eq!(listed["scopes"], json!(["config:read"]));
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_token_scopes_enforced() {
+    let config = TestConfig::from_env();
+    let client = session_client(&config);
+    let (_, secret) = create_token(&client, &["config:read"]).await;
+    let token = token_client(&config, &secret);
+
+    let response = token.get("/wan/config").await.expect("Request failed");
+    assert_ne!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_ne!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = token
+        .post("/system/reboot", &json!({}))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = token
+        .put("/wan/config", &json!({ "connection_type": "dhcp" }))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // Destructive actions need the exec scope
+    let response = token
+        .post("/firmware/install", &json!({}))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_token_cannot_manage_tokens() {
+    let config = TestConfig::from_env();
+    let client = session_client(&config);
+    let (_, secret) = create_token(
+        &client,
+        &["fleet:read", "fleet:write", "config:read", "config:write", "exec"],
+    )
+    .await;
+    let token = token_client(&config, &secret);
+
+    let response = token.get("/user/tokens").await.expect("Request failed");
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_revoked_token_rejected() {
+    let config = TestConfig::from_env();
+    let client = session_client(&config);
+    let (token_id, secret) = create_token(&client, &["config:read"]).await;
+
+    let response = client
+        .delete(&format!("/user/tokens/{}", token_id))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = token_client(&config, &secret)
+        .get("/system/status")
+        .await
+        .expect("Request failed");
+    assert!(!response.status().is_success());
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_create_token_validation() {
+    let config = TestConfig::from_env();
+    let client = session_client(&config);
+
+    for body in [
+        json!({ "name": "ci", "scopes": [] }),
+        json!({ "name": "", "scopes": ["exec"] }),
+        json!({ "name": "ci", "scopes": ["exec"], "expires_in_days": 1000 }),
+    ] {
+        let response = client
+            .post("/user/tokens", &body)
+            .await
+            .expect("Request failed");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", body);
+    }
+}
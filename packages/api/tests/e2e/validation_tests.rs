@@ -0,0 +1,155 @@
+// E2E tests for request validation
+//
+// Tests cover:
+// - 422 VALIDATION_FAILED with every field error in one response
+// - Field paths for nested values and list elements
+// - Valid bodies still accepted
+//
+// Config writes require If-Match, so requests here send `If-Match: *`.
+
+mod common;
+
+use common::{TestConfig, auth::*};
+use reqwest::StatusCode;
+use serde_json::{Value, json};
+
+async fn send(
+    config: &TestConfig,
+    method: reqwest::Method,
+    path: &str,
+    body: &Value,
+) -> reqwest::Response {
+    let token = TokenGenerator::new(&config.clerk_secret)
+        .generate_valid_token(&config.test_user_id, Some("pro".to_string()));
+    reqwest::Client::new()
+        .request(method, format!("{}{}", config.api_base_url, path))
+        .header("Authorization", format!("Bearer {}", token))
+        .header("X-Device-ID", &config.test_device_id)
+        .header("If-Match", "*")
+        .json(body)
+        .send()
+        .await
+        .expect("Request failed")
+}
+
+/// Field paths reported by a 422 response
+async fn error_fields(response: reqwest::Response) -> Vec<String> {
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = response.json().await.expect("Failed to parse JSON");
+    assert_eq!(body["error"]["code"], "VALIDATION_FAILED");
+    body["error"]["details"]["validation_errors"]
+        .as_array()
+        .expect("Missing validation_errors")
+        .iter()
+        .map(|e| e["field"].as_str().unwrap().to_string())
+        .collect()
+}
+
+fn firewall_rule(source: &str, port: &str) -> Value {
+    json!({
+        "name": "Allow SSH",
+        "enabled": true,
+        "zone_from": "WAN",
+        "zone_to": "LAN",
+        "source": source,
+        "destination": "any",
+        "protocol": "tcp",
+        "port": port,
+        "action": "accept",
+        "log": false
+    })
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_firewall_rule_reports_all_errors() {
+    let config = TestConfig::from_env();
+    let mut rule = firewall_rule("10.0.0.0/33", "70000");
+    rule["schedule"] = json!({
+        "enabled": true,
+        "start": "25:00",
+        "end": "18:00",
+        "days": [1, 9]
+    });
+
+    let response = send(&config, reqwest::Method::POST, "/firewall/rules", &rule).await;
+    assert_eq!(
+        error_fields(response).await,
+        ["source", "port", "schedule.start", "schedule.days[1]"]
+    );
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_valid_firewall_rule_is_accepted() {
+    let config = TestConfig::from_env();
+    let rule = firewall_rule("192.168.1.0/24", "22,8000-8080");
+
+    let response = send(&config, reqwest::Method::POST, "/firewall/rules", &rule).await;
+    assert_ne!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_wifi_network_weak_psk() {
+    let config = TestConfig::from_env();
+    let network = json!({
+        "id": 0,
+        "ssid": "this-ssid-is-far-too-long-for-802.11",
+        "enabled": true,
+        "hidden": false,
+        "radio": "radio0",
+        "security": { "mode": "wpa2", "password": "weak" },
+        "vlan": 4095,
+        "isolated": false
+    });
+
+    let response = send(
+        &config,
+        reqwest::Method::POST,
+        "/wifi/networks",
+        &network,
+    )
+    .await;
+    assert_eq!(
+        error_fields(response).await,
+        ["ssid", "vlan", "security.password"]
+    );
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_dhcp_range_and_dns_list() {
+    let config = TestConfig::from_env();
+    let dhcp = json!({
+        "enabled": true,
+        "interface": "br0",
+        "range_start": "192.168.1.200",
+        "range_end": "192.168.1.100",
+        "lease_time": 86400,
+        "gateway": "192.168.1.1",
+        "dns": ["192.168.1.1", "not-an-ip"]
+    });
+
+    let response = send(&config, reqwest::Method::PUT, "/dhcp/config", &dhcp).await;
+    assert_eq!(error_fields(response).await, ["range_end", "dns[1]"]);
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_dns_ttl_bounds() {
+    let config = TestConfig::from_env();
+    let dns = json!({
+        "enabled": true,
+        "listen_address": "0.0.0.0",
+        "upstream_dns": ["1.1.1.1", "9.9.9.9#5353"],
+        "dnssec": true,
+        "cache_size": 1000,
+        "min_ttl": 3600,
+        "max_ttl": 60,
+        "block_mode": "nxdomain"
+    });
+
+    let response = send(&config, reqwest::Method::PUT, "/dns/config", &dns).await;
+    assert_eq!(error_fields(response).await, ["min_ttl"]);
+}
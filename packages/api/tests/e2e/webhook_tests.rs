@@ -0,0 +1,251 @@
+// E2E tests for Webhook API endpoints
+//
+// Tests cover:
+// - POST/GET/PUT/DELETE /webhooks
+// - POST /webhooks/:id/test (delivered to a local HTTP receiver)
+// - GET /webhooks/:id/deliveries
+// - Business plan gating
+//
+// The receiver listens on 127.0.0.1, so the API server must run locally.
+
+mod common;
+
+use common::{TestConfig, auth::*, client::*};
+use reqwest::StatusCode;
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// A request captured by the local receiver
+#[derive(Debug)]
+struct ReceivedRequest {
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl ReceivedRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Start a minimal HTTP receiver answering every request with `status`.
+/// Returns its URL and a channel of captured requests.
+async fn start_receiver(status: u16) -> (String, mpsc::UnboundedReceiver<ReceivedRequest>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut buf = Vec::new();
+            let mut chunk = [0u8; 4096];
+
+            // Read headers, then the body by Content-Length
+            let header_end = loop {
+                let n = stream.read(&mut chunk).await.unwrap_or(0);
+                if n == 0 {
+                    break None;
+                }
+                buf.extend_from_slice(&chunk[..n]);
+                if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break Some(pos + 4);
+                }
+            };
+            let Some(header_end) = header_end else { continue };
+
+            let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+            let headers: Vec<(String, String)> = head
+                .lines()
+                .skip(1)
+                .filter_map(|line| line.split_once(':'))
+                .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+                .collect();
+            let content_length = headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, v)| v.parse::<usize>().ok())
+                .unwrap_or(0);
+            while buf.len() < header_end + content_length {
+                let n = stream.read(&mut chunk).await.unwrap_or(0);
+                if n == 0 {
+                    break;
+                }
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            let body = String::from_utf8_lossy(&buf[header_end..]).to_string();
+
+            let response = format!(
+                "HTTP/1.1 {} Test\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = tx.send(ReceivedRequest { headers, body });
+        }
+    });
+
+    (url, rx)
+}
+
+fn business_client(config: &TestConfig) -> ApiClient {
+    let token_gen = TokenGenerator::new(&config.clerk_secret);
+    let token = token_gen.generate_valid_token(&config.test_user_id, Some("business".to_string()));
+    ApiClient::new(
+        config.api_base_url.clone(),
+        token,
+        config.test_device_id.clone(),
+    )
+}
+
+async fn create_webhook(client: &ApiClient, url: &str) -> serde_json::Value {
+    let response = client
+        .post(
+            "/webhooks",
+            &json!({ "url": url, "events": ["device_offline", "threat_detected"] }),
+        )
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::CREATED);
+    response.json().await.expect("Failed to parse JSON")
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_webhook_crud() {
+    let config = TestConfig::from_env();
+    let client = business_client(&config);
+
+    let created = create_webhook(&client, "https://example.com/hook").await;
+    let id = created["id"].as_str().expect("Missing webhook id").to_string();
+    assert!(
+        created["secret"].as_str().unwrap().starts_with("whsec_"),
+        "Secret should be returned on creation"
+    );
+
+    let response = client
+        .get(&format!("/webhooks/{}", id))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::OK);
+    let fetched: serde_json::Value = response.json().await.unwrap();
+    assert!(fetched.get("secret").is_none(), "Secret must not be returned");
+
+    let response = client
+        .put(&format!("/webhooks/{}", id), &json!({ "enabled": false }))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::OK);
+    let updated: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(updated["enabled"], false);
+
+    let response = client
+        .delete(&format!("/webhooks/{}", id))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = client
+        .get(&format!("/webhooks/{}", id))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_webhook_rejects_invalid_url() {
+    let config = TestConfig::from_env();
+    let client = business_client(&config);
+
+    let response = client
+        .post(
+            "/webhooks",
+            &json!({ "url": "ftp://example.com", "events": ["device_online"] }),
+        )
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_webhook_test_event_is_signed() {
+    let config = TestConfig::from_env();
+    let client = business_client(&config);
+    let (url, mut received) = start_receiver(200).await;
+
+    let created = create_webhook(&client, &url).await;
+    let id = created["id"].as_str().unwrap();
+
+    let response = client
+        .post(&format!("/webhooks/{}/test", id), &json!({}))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), StatusCode::OK);
+    let delivery: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(delivery["status"], "delivered");
+    assert_eq!(delivery["response_status"], 200);
+
+    let request = received.recv().await.expect("Receiver got nothing");
+    let signature = request.header("X-NGFW-Signature").expect("Missing signature");
+    assert!(signature.starts_with("t=") && signature.contains(",v1="));
+    assert_eq!(request.header("X-NGFW-Event"), Some("test"));
+
+    let envelope: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+    assert_eq!(envelope["event"], "test");
+    assert_eq!(envelope["id"], delivery["id"]);
+
+    let response = client
+        .get(&format!("/webhooks/{}/deliveries", id))
+        .await
+        .expect("Request failed");
+    let log: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(log[0]["id"], delivery["id"]);
+
+    let _ = client.delete(&format!("/webhooks/{}", id)).await;
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_webhook_failed_delivery_is_scheduled_for_retry() {
+    let config = TestConfig::from_env();
+    let client = business_client(&config);
+    let (url, mut received) = start_receiver(500).await;
+
+    let created = create_webhook(&client, &url).await;
+    let id = created["id"].as_str().unwrap();
+
+    let response = client
+        .post(&format!("/webhooks/{}/test", id), &json!({}))
+        .await
+        .expect("Request failed");
+    let delivery: serde_json::Value = response.json().await.unwrap();
+    assert!(received.recv().await.is_some());
+
+    assert_eq!(delivery["status"], "pending");
+    assert_eq!(delivery["attempts"], 1);
+    assert_eq!(delivery["response_status"], 500);
+    assert!(delivery["next_attempt_at"].as_i64().is_some());
+
+    let _ = client.delete(&format!("/webhooks/{}", id)).await;
+}
+
+#[tokio::test]
+#[ignore = "requires a running API server (API_BASE_URL)"]
+async fn test_webhooks_require_business_plan() {
+    let config = TestConfig::from_env();
+    let token_gen = TokenGenerator::new(&config.clerk_secret);
+    let token = token_gen.generate_valid_token(&config.test_user_id, Some("pro".to_string()));
+    let client = ApiClient::new(
+        config.api_base_url.clone(),
+        token,
+        config.test_device_id.clone(),
+    );
+
+    let response = client.get("/webhooks").await.expect("Request failed");
+    assert_ne!(response.status(), StatusCode::OK);
+}
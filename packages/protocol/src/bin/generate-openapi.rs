@@ -5,8 +5,8 @@
 //! This binary extracts the OpenAPI spec at build time, enabling
 //! downstream TypeScript client generation via openapi-zod-client.
 
-use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
 use utoipa::openapi::ServerBuilder;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
 use utoipa::{Modify, OpenApi};
 
 /// OpenAPI 3.1 specification for the NGFW.sh API
@@ -71,9 +71,19 @@ use utoipa::{Modify, OpenApi};
             ngfw_protocol::AuthRequest,
             ngfw_protocol::AuthResponse,
             ngfw_protocol::StatusPayload,
+            ngfw_protocol::PongPayload,
+            ngfw_protocol::TaskHealth,
+            ngfw_protocol::TaskState,
             ngfw_protocol::InterfaceMetrics,
             ngfw_protocol::MetricsPayload,
+            ngfw_protocol::MetricsBatchPayload,
             ngfw_protocol::InterfaceRates,
+            ngfw_protocol::RuleCounters,
+            ngfw_protocol::CountryCounters,
+            ngfw_protocol::FeedCounters,
+            ngfw_protocol::UpnpMapping,
+            ngfw_protocol::UpnpRevoke,
+            ngfw_protocol::ScheduleState,
             ngfw_protocol::ConnectionCounts,
             ngfw_protocol::DnsMetrics,
             ngfw_protocol::ConfigPush,
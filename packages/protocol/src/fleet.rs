@@ -370,4 +370,7 @@ pub struct AuditLogEntry {
     pub ip_address: String,
     /// User agent string
     pub user_agent: String,
+    /// API token that authenticated the request, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
 }
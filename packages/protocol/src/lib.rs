@@ -22,7 +22,7 @@ pub use utoipa;
 #[cfg(test)]
 mod tests {
     use super::*;
-    use serde_json::{json, Value};
+    use serde_json::{Value, json};
     use std::collections::HashMap;
 
     // ─── 1. RpcMessage serialization roundtrip ───────────────────────────
@@ -70,6 +70,7 @@ mod tests {
             (MessageType::StatusRequest, "\"STATUS_REQUEST\""),
             (MessageType::Ping, "\"PING\""),
             (MessageType::ModeUpdate, "\"MODE_UPDATE\""),
+            (MessageType::UpnpRevoke, "\"UPNP_REVOKE\""),
             (MessageType::Auth, "\"AUTH\""),
             (MessageType::AuthOk, "\"AUTH_OK\""),
             (MessageType::AuthFail, "\"AUTH_FAIL\""),
@@ -81,6 +82,7 @@ mod tests {
             (MessageType::Log, "\"LOG\""),
             (MessageType::Alert, "\"ALERT\""),
             (MessageType::Metrics, "\"METRICS\""),
+            (MessageType::MetricsBatch, "\"METRICS_BATCH\""),
             (MessageType::Pong, "\"PONG\""),
             (MessageType::ModeAck, "\"MODE_ACK\""),
             (MessageType::Error, "\"ERROR\""),
@@ -267,6 +269,10 @@ mod tests {
                 blocked: 350,
                 cached: 4_200,
             },
+            firewall_rules: Vec::new(),
+            countries: Vec::new(),
+            threat_feeds: Vec::new(),
+            upnp_leases: None,
         };
 
         let serialized = serde_json::to_string(&payload).unwrap();
@@ -305,6 +311,10 @@ mod tests {
                 blocked: 0,
                 cached: 0,
             },
+            firewall_rules: Vec::new(),
+            countries: Vec::new(),
+            threat_feeds: Vec::new(),
+            upnp_leases: None,
         };
         let v: Value = serde_json::to_value(&payload).unwrap();
         assert!(
@@ -313,6 +323,87 @@ mod tests {
         );
     }
 
+    #[test]
+    fn metrics_batch_preserves_sample_order_and_timestamps() {
+        let sample = |timestamp: i64| MetricsPayload {
+            timestamp,
+            cpu: 1.0,
+            memory: 2.0,
+            temperature: None,
+            interfaces: HashMap::new(),
+            connections: ConnectionCounts {
+                total: 0,
+                tcp: 0,
+                udp: 0,
+            },
+            dns: DnsMetrics {
+                queries: 0,
+                blocked: 0,
+                cached: 0,
+            },
+            firewall_rules: Vec::new(),
+            countries: Vec::new(),
+            threat_feeds: Vec::new(),
+            upnp_leases: None,
+        };
+        let batch = MetricsBatchPayload {
+            samples: vec![sample(100), sample(160), sample(220)],
+        };
+
+        let serialized = serde_json::to_string(&batch).unwrap();
+        let deserialized: MetricsBatchPayload = serde_json::from_str(&serialized).unwrap();
+
+        let timestamps: Vec<i64> = deserialized.samples.iter().map(|s| s.timestamp).collect();
+        assert_eq!(timestamps, vec![100, 160, 220]);
+    }
+
+    #[test]
+    fn status_payload_clock_offset_optional() {
+        // Older agents do not report a clock offset
+        let json_str = r#"{
+            "uptime": 60,
+            "cpu": 1.0,
+            "memory": 2.0,
+            "load": [0.1, 0.2, 0.3],
+            "interfaces": [],
+            "connections": 4,
+            "firmware": "1.0.0"
+        }"#;
+        let status: StatusPayload = serde_json::from_str(json_str).unwrap();
+        assert_eq!(status.clock_offset, None);
+        assert!(status.tasks.is_empty());
+
+        let v = serde_json::to_value(&status).unwrap();
+        assert!(v.get("clock_offset").is_none());
+        assert!(v.get("tasks").is_none());
+    }
+
+    #[test]
+    fn pong_payload_server_time_optional() {
+        // Agent pongs carry an empty payload
+        let empty: PongPayload = serde_json::from_str("{}").unwrap();
+        assert_eq!(empty.server_time, None);
+
+        let pong: PongPayload = serde_json::from_str(r#"{"server_time":1700000000}"#).unwrap();
+        assert_eq!(pong.server_time, Some(1_700_000_000));
+    }
+
+    #[test]
+    fn task_health_serde() {
+        let health = TaskHealth {
+            name: "dispatcher".to_string(),
+            state: TaskState::Restarting,
+            restarts: 2,
+            last_error: None,
+        };
+        let v = serde_json::to_value(&health).unwrap();
+        assert_eq!(v["state"], "restarting");
+        assert!(v.get("last_error").is_none());
+
+        let roundtrip: TaskHealth = serde_json::from_value(v).unwrap();
+        assert_eq!(roundtrip, health);
+    }
+
     // ─── 9. AlertType snake_case variants ────────────────────────────────
 
     #[test]
@@ -326,6 +417,8 @@ mod tests {
             (AlertType::PolicyViolation, "\"policy_violation\""),
             (AlertType::ConfigChange, "\"config_change\""),
             (AlertType::SystemAnomaly, "\"system_anomaly\""),
+            (AlertType::ThreatFeedMatch, "\"threat_feed_match\""),
+            (AlertType::ConfigReapplied, "\"config_reapplied\""),
         ];
 
         for (variant, expected_json) in &cases {
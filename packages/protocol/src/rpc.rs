@@ -68,6 +68,8 @@ pub enum MessageType {
     Ping,
     /// Update agent operating mode
     ModeUpdate,
+    /// Remove a UPnP port mapping from the device
+    UpnpRevoke,
 
     // Agent to server
     /// Authentication request from agent
@@ -92,6 +94,8 @@ pub enum MessageType {
     Alert,
     /// Periodic metrics from agent
     Metrics,
+    /// Historical metrics buffered while the agent was disconnected
+    MetricsBatch,
     /// Pong response to ping
     Pong,
     /// Mode change acknowledged
@@ -153,6 +157,68 @@ pub struct StatusPayload {
     pub wan_ip: Option<String>,
     /// Current firmware version
     pub firmware: String,
+    /// Clock correction applied to agent timestamps, in seconds
+    /// (server time minus local time). Absent until first synced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock_offset: Option<i64>,
+    /// Health of the agent's supervised background tasks
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tasks: Vec<TaskHealth>,
+    /// Enforcement state of scheduled firewall rules
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedules: Vec<ScheduleState>,
+}
+
+/// Whether a scheduled firewall rule is currently enforced on the router.
+///
+/// The agent evaluates schedules in router local time, so rules keep
+/// switching while the device is offline.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ScheduleState {
+    /// `FirewallRule` id
+    pub rule_id: u32,
+    /// Whether the rule is installed right now
+    pub active: bool,
+    /// When the agent last switched the rule (Unix seconds, server-aligned)
+    pub since: i64,
+}
+
+/// Health of a supervised agent task.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct TaskHealth {
+    /// Task name (e.g., connection, dispatcher, collector)
+    pub name: String,
+    /// Current task state
+    pub state: TaskState,
+    /// Number of times the task has been restarted since agent start
+    pub restarts: u32,
+    /// Reason for the most recent failure
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// Supervised task states.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[schema(example = "running")]
+pub enum TaskState {
+    /// Task is running normally
+    Running,
+    /// Task failed and is waiting to be restarted
+    Restarting,
+    /// Task failed too many times; the agent is exiting
+    Failed,
+}
+
+/// Keepalive reply.
+///
+/// The server answers agent pings with its current time so the agent can
+/// keep its clock offset fresh between reconnections.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct PongPayload {
+    /// Server time for clock sync (Unix timestamp)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_time: Option<i64>,
 }
 
 /// Network interface metrics.
@@ -195,6 +261,100 @@ pub struct MetricsPayload {
     pub connections: ConnectionCounts,
     /// DNS resolver metrics
     pub dns: DnsMetrics,
+    /// Counters of agent-installed firewall rules, summed per rule
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub firewall_rules: Vec<RuleCounters>,
+    /// Counters of country firewall rules, summed per country
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub countries: Vec<CountryCounters>,
+    /// Counters of threat feed drop rules, per feed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub threat_feeds: Vec<FeedCounters>,
+    /// Port mappings miniupnpd currently holds. Absent when the agent does
+    /// not read UPnP leases; empty when there are none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upnp_leases: Option<Vec<UpnpMapping>>,
+}
+
+/// Packet counters of one firewall rule installed by the agent.
+///
+/// Counters reset when the rule is reinstalled, so consumers should treat
+/// a decrease as a reset rather than a negative delta.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct RuleCounters {
+    /// `FirewallRule` id carried in the iptables rule comment
+    pub rule_id: u32,
+    /// Packets matched since the rule was installed
+    pub packets: u64,
+    /// Bytes matched since the rule was installed
+    pub bytes: u64,
+}
+
+/// Packet counters of the firewall rules matching one country.
+///
+/// Like [`RuleCounters`], these reset when the rules are reinstalled.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct CountryCounters {
+    /// ISO 3166-1 alpha-2 code the rules matched on
+    pub country: String,
+    /// Packets matched since the rules were installed
+    pub packets: u64,
+    /// Bytes matched since the rules were installed
+    pub bytes: u64,
+}
+
+/// Packet counters of the drop rules of one threat feed, summed over
+/// directions and chains.
+///
+/// Like [`RuleCounters`], these reset when the rules are reinstalled.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct FeedCounters {
+    /// `ThreatFeed` id carried in the iptables rule comment
+    pub feed_id: u32,
+    /// Packets dropped since the rules were installed
+    pub packets: u64,
+    /// Bytes dropped since the rules were installed
+    pub bytes: u64,
+}
+
+/// A port mapping a LAN client opened through UPnP IGD or NAT-PMP, as
+/// recorded in miniupnpd's lease file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct UpnpMapping {
+    /// `tcp` or `udp`
+    pub protocol: String,
+    /// Port opened on the WAN address
+    pub external_port: u16,
+    /// LAN client the port is forwarded to
+    pub internal_ip: String,
+    /// Port on the LAN client
+    pub internal_port: u16,
+    /// Description the client gave the mapping
+    pub description: String,
+    /// When the mapping expires (Unix seconds, server-aligned); `None` if
+    /// it was requested without a lease time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+}
+
+/// Request to remove a UPnP port mapping, identified like miniupnpd
+/// identifies it: by protocol and external port.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct UpnpRevoke {
+    /// `tcp` or `udp`
+    pub protocol: String,
+    /// Port opened on the WAN address
+    pub external_port: u16,
+}
+
+/// Batch of historical metrics samples from agent.
+///
+/// Sent after re-authentication to backfill samples collected while the
+/// connection was down. Each sample carries its original timestamp.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct MetricsBatchPayload {
+    /// Samples in chronological order
+    pub samples: Vec<MetricsPayload>,
 }
 
 /// Network interface transfer rates.
@@ -413,6 +573,10 @@ pub enum AlertType {
     ConfigChange,
     /// System anomaly detected
     SystemAnomaly,
+    /// Traffic dropped by a subscribed IP threat feed
+    ThreatFeedMatch,
+    /// Managed config re-applied after the firmware rebuilt its subsystem
+    ConfigReapplied,
 }
 
 /// Firmware upgrade command.
@@ -0,0 +1,29 @@
+-- Migration number: 0009   2026-10-18T00:00:00.000Z
+-- Add device outage intervals and device lifecycle events
+
+-- Outage intervals detected by the AgentConnection Durable Object.
+-- ended_at and duration_secs stay NULL while the device is still offline.
+CREATE TABLE IF NOT EXISTS device_outages (
+    id TEXT PRIMARY KEY,
+    device_id TEXT NOT NULL,
+    started_at INTEGER NOT NULL,
+    ended_at INTEGER,
+    duration_secs INTEGER,
+    reason TEXT NOT NULL,
+    FOREIGN KEY (device_id) REFERENCES devices(id) ON DELETE CASCADE
+);
+
+-- Device lifecycle events (device_online, device_offline, ...)
+CREATE TABLE IF NOT EXISTS device_events (
+    id TEXT PRIMARY KEY,
+    device_id TEXT NOT NULL,
+    event TEXT NOT NULL,
+    payload TEXT,
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY (device_id) REFERENCES devices(id) ON DELETE CASCADE
+);
+
+-- Performance indexes for common query patterns
+CREATE INDEX IF NOT EXISTS idx_device_outages_device_started ON device_outages(device_id, started_at DESC);
+CREATE INDEX IF NOT EXISTS idx_device_events_device_created ON device_events(device_id, created_at DESC);
+CREATE INDEX IF NOT EXISTS idx_device_events_event ON device_events(event);
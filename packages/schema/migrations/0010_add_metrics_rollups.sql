@@ -0,0 +1,48 @@
+-- Migration number: 0010   2026-10-18T00:00:00.000Z
+-- Add metrics rollups aggregated by the AgentConnection Durable Object
+
+-- Device metrics aggregated into 1m/5m/1h buckets.
+-- Gauges keep min/max/sum so averages are sum / samples; rows are merged
+-- additively as the Durable Object flushes partial buckets.
+CREATE TABLE IF NOT EXISTS metrics_rollups (
+    device_id TEXT NOT NULL,
+    resolution TEXT NOT NULL,
+    bucket_start INTEGER NOT NULL,
+    samples INTEGER NOT NULL,
+    cpu_min REAL,
+    cpu_max REAL,
+    cpu_sum REAL NOT NULL DEFAULT 0,
+    memory_min REAL,
+    memory_max REAL,
+    memory_sum REAL NOT NULL DEFAULT 0,
+    temperature_min REAL,
+    temperature_max REAL,
+    temperature_sum REAL NOT NULL DEFAULT 0,
+    temperature_samples INTEGER NOT NULL DEFAULT 0,
+    connections_min REAL,
+    connections_max REAL,
+    connections_sum REAL NOT NULL DEFAULT 0,
+    tcp_sum INTEGER NOT NULL DEFAULT 0,
+    udp_sum INTEGER NOT NULL DEFAULT 0,
+    dns_queries INTEGER NOT NULL DEFAULT 0,
+    dns_blocked INTEGER NOT NULL DEFAULT 0,
+    dns_cached INTEGER NOT NULL DEFAULT 0,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (device_id, resolution, bucket_start),
+    FOREIGN KEY (device_id) REFERENCES devices(id) ON DELETE CASCADE
+);
+
+-- Per-interface transfer rate sums for the same buckets
+CREATE TABLE IF NOT EXISTS metrics_interface_rollups (
+    device_id TEXT NOT NULL,
+    resolution TEXT NOT NULL,
+    bucket_start INTEGER NOT NULL,
+    interface TEXT NOT NULL,
+    samples INTEGER NOT NULL,
+    rx_rate_sum INTEGER NOT NULL DEFAULT 0,
+    rx_rate_max INTEGER NOT NULL DEFAULT 0,
+    tx_rate_sum INTEGER NOT NULL DEFAULT 0,
+    tx_rate_max INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (device_id, resolution, bucket_start, interface),
+    FOREIGN KEY (device_id) REFERENCES devices(id) ON DELETE CASCADE
+);
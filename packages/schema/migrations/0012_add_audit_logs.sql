@@ -0,0 +1,23 @@
+-- Migration number: 0012   2026-10-18T00:00:00.000Z
+-- Add audit log of mutating API calls
+
+-- One row per successful PUT/POST/DELETE request.
+-- changes is a JSON object {"before": ..., "after": ...} with secrets redacted.
+-- resource_id is empty for singleton resources (e.g. wan.config).
+CREATE TABLE IF NOT EXISTS audit_logs (
+    id TEXT PRIMARY KEY,
+    timestamp INTEGER NOT NULL,
+    user_id TEXT NOT NULL,
+    device_id TEXT,
+    action TEXT NOT NULL,
+    resource_type TEXT NOT NULL,
+    resource_id TEXT NOT NULL,
+    changes TEXT,
+    ip_address TEXT NOT NULL,
+    user_agent TEXT NOT NULL
+);
+
+-- Performance indexes for common query patterns
+CREATE INDEX IF NOT EXISTS idx_audit_logs_user_timestamp ON audit_logs(user_id, timestamp DESC);
+CREATE INDEX IF NOT EXISTS idx_audit_logs_device_timestamp ON audit_logs(device_id, timestamp DESC);
+CREATE INDEX IF NOT EXISTS idx_audit_logs_resource ON audit_logs(resource_type, resource_id);
@@ -0,0 +1,8 @@
+-- Migration number: 0013   2026-10-18T00:00:00.000Z
+-- Add per-plan API rate limits (requests per minute, -1 = unlimited)
+
+INSERT OR REPLACE INTO plan_limits (plan_id, limit_key, limit_value) VALUES
+    ('starter', 'api_requests_per_minute', 120),
+    ('pro', 'api_requests_per_minute', 300),
+    ('business', 'api_requests_per_minute', 600),
+    ('business_plus', 'api_requests_per_minute', 1200);
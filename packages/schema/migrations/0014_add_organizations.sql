@@ -0,0 +1,29 @@
+-- Migration number: 0014   2026-10-18T00:00:00.000Z
+-- Add organizations with role-based membership, and device ownership by org
+
+CREATE TABLE IF NOT EXISTS organizations (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    created_by TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+
+-- role: owner (billing, delete org), admin (config, members),
+-- operator (diagnostics and operational actions), viewer (read only)
+CREATE TABLE IF NOT EXISTS org_members (
+    org_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    role TEXT NOT NULL CHECK (role IN ('owner', 'admin', 'operator', 'viewer')),
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (org_id, user_id),
+    FOREIGN KEY (org_id) REFERENCES organizations(id) ON DELETE CASCADE
+);
+
+-- Devices registered in an organization belong to it rather than to the
+-- registering user
+ALTER TABLE devices ADD COLUMN org_id TEXT;
+
+-- Performance indexes for common query patterns
+CREATE INDEX IF NOT EXISTS idx_org_members_user_id ON org_members(user_id);
+CREATE INDEX IF NOT EXISTS idx_devices_org_id ON devices(org_id);
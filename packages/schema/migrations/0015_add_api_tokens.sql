@@ -0,0 +1,26 @@
+-- Migration number: 0015   2026-10-18T00:00:00.000Z
+-- Add scoped API tokens for automation, and record the authenticating token
+-- on audit log entries
+
+-- Tokens are stored as SHA-256 hashes; only the prefix is kept in clear for
+-- display. org_id is set for service tokens created inside an organization.
+CREATE TABLE IF NOT EXISTS api_tokens (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    org_id TEXT,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    token_prefix TEXT NOT NULL,
+    scopes TEXT NOT NULL, -- JSON array of scope names
+    plan TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    last_used_at INTEGER,
+    revoked_at INTEGER
+);
+
+ALTER TABLE audit_logs ADD COLUMN token_id TEXT;
+
+-- Performance indexes for common query patterns
+CREATE INDEX IF NOT EXISTS idx_api_tokens_user_id ON api_tokens(user_id);
+CREATE INDEX IF NOT EXISTS idx_audit_logs_token_id ON audit_logs(token_id);
@@ -0,0 +1,13 @@
+-- Migration number: 0016   2026-10-18T00:00:00.000Z
+-- Track config section versions in D1 so If-Match writes can compare and
+-- bump them in a single statement
+
+-- Versions previously kept in KV are not carried over; every section starts
+-- again at version 0 and clients holding an old ETag refetch once.
+CREATE TABLE IF NOT EXISTS config_versions (
+    device_id TEXT NOT NULL,
+    section TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (device_id, section)
+);